@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(
+    |input: (String, u32, u32, u32, u64, u64, Vec<(String, Vec<u8>)>)| {
+        let (path, mode, uid, gid, mtime, mtime_clamp, xattrs) = input;
+        chunkah::fuzzing::tar_dir_header(&path, mode, uid, gid, mtime, mtime_clamp, &xattrs);
+    }
+);
@@ -15,6 +15,8 @@ Source1:        %{url}/releases/download/v%{version}/%{crate}-%{version}-vendor.
 BuildRequires:  cargo-rpm-macros >= 26
 BuildRequires:  openssl-devel
 BuildRequires:  zlib-devel
+BuildRequires:  xz-devel
+BuildRequires:  bzip2-devel
 
 %description
 chunkah is an OCI building tool that takes a flat rootfs and outputs a
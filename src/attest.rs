@@ -0,0 +1,160 @@
+//! Per-layer digests of the layer contents.
+//!
+//! The inputs digest of a layer only covers the paths in the layer and their
+//! content, not their metadata (mtimes, ownership, ...) or their order. This
+//! lets a verifier check that a layer corresponds exactly to a set of files
+//! without rebuilding the image: extract the layer, list each path with its
+//! digest, and compare the hash of that list to the annotation.
+//!
+//! The list has one `<path>\t<digest>\n` line per entry, sorted by path. Paths
+//! are absolute without a trailing slash. The digest is `sha256:<hex>` of the
+//! content for regular files (including hardlinks), `symlink:<target>` for
+//! symlinks and `dir` for directories.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use anyhow::{Context, Result};
+use ocidir::oci_spec::image as oci_image;
+use openssl::hash::{Hasher, MessageDigest};
+
+/// Layer annotation holding the inputs digest.
+pub const INPUTS_DIGEST_ANNOTATION: &str = "org.chunkah.inputs-digest";
+
+/// Compute the inputs digest of a layer in the OCI directory.
+pub fn layer_inputs_digest(
+    oci_dir: &ocidir::OciDir,
+    layer: &oci_image::Descriptor,
+) -> Result<String> {
+    let reader = crate::tar::read_layer(oci_dir, layer)?;
+    inputs_digest(reader)
+}
+
+/// Compute the inputs digest of an uncompressed tarball.
+fn inputs_digest<R: Read>(reader: R) -> Result<String> {
+    let mut archive = tar::Archive::new(reader);
+    let mut digests: BTreeMap<String, String> = BTreeMap::new();
+    for entry in archive.entries().context("reading layer")? {
+        let mut entry = entry.context("reading layer entry")?;
+        let path = entry_path(entry.path_bytes().as_ref())?;
+
+        let digest = match entry.header().entry_type() {
+            tar::EntryType::Directory => "dir".to_string(),
+            tar::EntryType::Symlink => {
+                let target = entry
+                    .link_name_bytes()
+                    .with_context(|| format!("symlink {path} has no target"))?;
+                let target = std::str::from_utf8(&target)
+                    .with_context(|| format!("symlink {path} target is not valid UTF-8"))?;
+                format!("symlink:{target}")
+            }
+            tar::EntryType::Link => {
+                let target = entry
+                    .link_name_bytes()
+                    .with_context(|| format!("hardlink {path} has no target"))?;
+                let target = entry_path(&target)?;
+                // hardlinks always come after the file they point to
+                digests
+                    .get(&target)
+                    .with_context(|| format!("hardlink {path} points to unknown {target}"))?
+                    .clone()
+            }
+            _ => {
+                let mut hasher = Hasher::new(MessageDigest::sha256())?;
+                std::io::copy(&mut entry, &mut hasher)
+                    .with_context(|| format!("reading {path}"))?;
+                format!("sha256:{}", hex::encode(hasher.finish()?))
+            }
+        };
+        digests.insert(path, digest);
+    }
+
+    let mut hasher = Hasher::new(MessageDigest::sha256())?;
+    for (path, digest) in &digests {
+        hasher.update(format!("{path}\t{digest}\n").as_bytes())?;
+    }
+    Ok(format!("sha256:{}", hex::encode(hasher.finish()?)))
+}
+
+/// Normalize a tar entry path to an absolute path without a trailing slash.
+fn entry_path(path: &[u8]) -> Result<String> {
+    let path = std::str::from_utf8(path).context("path is not valid UTF-8")?;
+    Ok(format!("/{}", path.trim_matches('/')))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn append(
+        builder: &mut tar::Builder<Vec<u8>>,
+        entry_type: tar::EntryType,
+        path: &str,
+        data: &[u8],
+    ) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, path, data).unwrap();
+    }
+
+    fn append_link(
+        builder: &mut tar::Builder<Vec<u8>>,
+        entry_type: tar::EntryType,
+        path: &str,
+        target: &str,
+    ) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(0);
+        builder.append_link(&mut header, path, target).unwrap();
+    }
+
+    #[test]
+    fn test_inputs_digest() {
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, tar::EntryType::Directory, "usr/", b"");
+        append(&mut builder, tar::EntryType::Regular, "usr/a", b"hello");
+        append_link(&mut builder, tar::EntryType::Link, "usr/b", "usr/a");
+        append_link(&mut builder, tar::EntryType::Symlink, "usr/c", "a");
+        let layer = builder.into_inner().unwrap();
+
+        // the same files in a different order, with different metadata
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, tar::EntryType::Directory, "usr/", b"");
+        append_link(&mut builder, tar::EntryType::Symlink, "usr/c", "a");
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(5);
+        header.set_mode(0o755);
+        header.set_mtime(1000);
+        builder
+            .append_data(&mut header, "usr/a", &b"hello"[..])
+            .unwrap();
+        append(&mut builder, tar::EntryType::Regular, "usr/b", b"hello");
+        let reordered = builder.into_inner().unwrap();
+
+        let digest = inputs_digest(layer.as_slice()).unwrap();
+        assert_eq!(digest, inputs_digest(reordered.as_slice()).unwrap());
+
+        // matches the documented list format
+        let mut hasher = Hasher::new(MessageDigest::sha256()).unwrap();
+        let hello = "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let list = format!("/usr\tdir\n/usr/a\t{hello}\n/usr/b\t{hello}\n/usr/c\tsymlink:a\n");
+        hasher.update(list.as_bytes()).unwrap();
+        assert_eq!(
+            digest,
+            format!("sha256:{}", hex::encode(hasher.finish().unwrap()))
+        );
+
+        // but content changes are caught
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, tar::EntryType::Directory, "usr/", b"");
+        append(&mut builder, tar::EntryType::Regular, "usr/a", b"world");
+        append_link(&mut builder, tar::EntryType::Link, "usr/b", "usr/a");
+        append_link(&mut builder, tar::EntryType::Symlink, "usr/c", "a");
+        let changed = builder.into_inner().unwrap();
+        assert_ne!(digest, inputs_digest(changed.as_slice()).unwrap());
+    }
+}
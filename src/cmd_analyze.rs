@@ -0,0 +1,265 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use clap::Parser;
+use serde::Serialize;
+
+use crate::cmd_build::{self, BuildArgs, Telemetry};
+use crate::components::Component;
+
+#[derive(Parser)]
+pub struct AnalyzeArgs {
+    /// Output the components and layers as JSON
+    #[arg(long)]
+    json: bool,
+
+    #[command(flatten)]
+    build: BuildArgs,
+}
+
+/// A component of the rootfs.
+#[derive(Debug, PartialEq, Serialize)]
+struct ComponentRow {
+    name: String,
+    size: u64,
+    files: usize,
+    stability: f64,
+    /// The layers holding its files; several if it was split.
+    layers: Vec<usize>,
+}
+
+/// A layer of the predicted image.
+#[derive(Debug, PartialEq, Serialize)]
+struct LayerRow {
+    name: String,
+    size: u64,
+    files: usize,
+    stability: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct Analysis {
+    components: Vec<ComponentRow>,
+    layers: Vec<LayerRow>,
+}
+
+pub fn run(args: &AnalyzeArgs) -> Result<()> {
+    cmd_build::run_prepared(&args.build, |build_args, telemetry| {
+        let analysis = analyze_rootfs(build_args, telemetry)?;
+        if args.json {
+            let json = serde_json::to_string_pretty(&analysis).context("serializing analysis")?;
+            println!("{json}");
+        } else {
+            print!("{}", format_analysis(&analysis));
+        }
+        Ok(())
+    })
+}
+
+/// Claim and pack the rootfs like a build would, without writing anything.
+fn analyze_rootfs(args: &BuildArgs, telemetry: &Telemetry) -> Result<Analysis> {
+    let created_epoch = args.created_epoch()?;
+    let base_layers = cmd_build::open_base_image(args)?.map_or(0, |base| base.layer_count());
+    // this also keeps the unpacked rootfs image around, if any
+    let claimed = cmd_build::plan_components(args, &[], created_epoch, &telemetry.bundle)?;
+    let components: Vec<(String, Component)> = claimed
+        .components
+        .iter()
+        .map(|(name, component)| (name.clone(), component.clone()))
+        .collect();
+    let layers = cmd_build::pack_components(args, claimed.components, base_layers)
+        .context("packing components")?;
+    Ok(analyze(&components, &layers))
+}
+
+fn analyze(components: &[(String, Component)], layers: &[(String, Component)]) -> Analysis {
+    let layer_of: HashMap<&Utf8Path, usize> = layers
+        .iter()
+        .enumerate()
+        .flat_map(|(i, (_, layer))| layer.files.keys().map(move |path| (path.as_path(), i)))
+        .collect();
+    let mut component_rows: Vec<ComponentRow> = components
+        .iter()
+        .map(|(name, component)| ComponentRow {
+            name: name.clone(),
+            size: component_size(component),
+            files: component.files.len(),
+            stability: component.stability,
+            layers: component
+                .files
+                .keys()
+                .filter_map(|path| layer_of.get(path.as_path()).copied())
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect(),
+        })
+        .collect();
+    component_rows.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Analysis {
+        components: component_rows,
+        layers: layer_rows(layers),
+    }
+}
+
+fn layer_rows(layers: &[(String, Component)]) -> Vec<LayerRow> {
+    layers
+        .iter()
+        .map(|(name, layer)| LayerRow {
+            name: name.clone(),
+            size: component_size(layer),
+            files: layer.files.len(),
+            stability: layer.stability,
+        })
+        .collect()
+}
+
+fn component_size(component: &Component) -> u64 {
+    component.files.values().map(|f| f.size).sum()
+}
+
+/// Format the layers about to be built, e.g. for `build --dry-run`.
+pub(crate) fn format_layers(layers: &[(String, Component)]) -> String {
+    format_layer_rows(&layer_rows(layers))
+}
+
+fn format_analysis(analysis: &Analysis) -> String {
+    let width = analysis
+        .components
+        .iter()
+        .map(|c| c.name.len())
+        .chain(["COMPONENT".len()])
+        .max()
+        .unwrap_or_default();
+    let mut out = format!(
+        "{:<width$} {:>12} {:>7} {:>9}  LAYERS\n",
+        "COMPONENT", "SIZE", "FILES", "STABILITY"
+    );
+    for component in &analysis.components {
+        let layers: Vec<String> = component.layers.iter().map(|i| i.to_string()).collect();
+        out.push_str(&format!(
+            "{:<width$} {:>12} {:>7} {:>9.3}  {}\n",
+            component.name,
+            component.size,
+            component.files,
+            component.stability,
+            layers.join(","),
+        ));
+    }
+
+    out.push('\n');
+    out.push_str(&format_layer_rows(&analysis.layers));
+    out
+}
+
+/// Format the layers with their uncompressed size, file count, stability and
+/// components.
+fn format_layer_rows(layers: &[LayerRow]) -> String {
+    let mut out = String::new();
+    for (i, layer) in layers.iter().enumerate() {
+        out.push_str(&format!(
+            "{i:>3} {:>12} {:>7} {:>9.3}  {}\n",
+            layer.size, layer.files, layer.stability, layer.name,
+        ));
+    }
+    let total: u64 = layers.iter().map(|layer| layer.size).sum();
+    out.push_str(&format!("{} layers, {total} bytes\n", layers.len()));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+
+    use super::*;
+    use crate::components::{FileInfo, FileMap, FileType};
+
+    fn component(paths: &[(&str, u64)], stability: f64) -> Component {
+        let files: FileMap = paths
+            .iter()
+            .map(|(p, size)| {
+                let info = FileInfo::test_file(FileType::File, *size, 1);
+                (Utf8PathBuf::from(*p), info)
+            })
+            .collect();
+        Component {
+            mtime_clamp: 1,
+            stability,
+            files,
+            packages: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_analyze() {
+        let components = vec![
+            (
+                "rpm/kernel".to_string(),
+                component(
+                    &[("/usr/lib/modules/a", 300), ("/usr/lib/modules/b", 200)],
+                    0.5,
+                ),
+            ),
+            (
+                "rpm/bash".to_string(),
+                component(&[("/usr/bin/bash", 100)], 0.9),
+            ),
+            (
+                "rpm/glibc".to_string(),
+                component(&[("/usr/lib64/libc.so.6", 50)], 0.8),
+            ),
+        ];
+        // the kernel was split, and bash and glibc packed together
+        let layers = vec![
+            (
+                "rpm/bash rpm/glibc".to_string(),
+                component(&[("/usr/bin/bash", 100), ("/usr/lib64/libc.so.6", 50)], 0.8),
+            ),
+            (
+                "rpm/kernel#1".to_string(),
+                component(&[("/usr/lib/modules/a", 300)], 0.5),
+            ),
+            (
+                "rpm/kernel#2".to_string(),
+                component(&[("/usr/lib/modules/b", 200)], 0.5),
+            ),
+        ];
+
+        let analysis = analyze(&components, &layers);
+        assert_eq!(
+            analysis.components,
+            [
+                ComponentRow {
+                    name: "rpm/bash".into(),
+                    size: 100,
+                    files: 1,
+                    stability: 0.9,
+                    layers: vec![0],
+                },
+                ComponentRow {
+                    name: "rpm/glibc".into(),
+                    size: 50,
+                    files: 1,
+                    stability: 0.8,
+                    layers: vec![0],
+                },
+                ComponentRow {
+                    name: "rpm/kernel".into(),
+                    size: 500,
+                    files: 2,
+                    stability: 0.5,
+                    layers: vec![1, 2],
+                },
+            ]
+        );
+        assert_eq!(analysis.layers[0].size, 150);
+        assert_eq!(analysis.layers[0].files, 2);
+
+        let out = format_analysis(&analysis);
+        assert!(out.starts_with("COMPONENT          SIZE   FILES STABILITY  LAYERS\n"));
+        assert!(out.contains("rpm/kernel          500       2     0.500  1,2\n"));
+        assert!(out.contains("  0          150       2     0.800  rpm/bash rpm/glibc\n"));
+        assert!(out.ends_with("3 layers, 650 bytes\n"));
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::BTreeMap;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::{Parser, ValueEnum};
+use ocidir::oci_spec::image as oci_image;
+use serde::Deserialize;
+
+use crate::cmd_build::{self, BuildArgs};
+use crate::ocibuilder::BaseImage;
+use crate::profile::Profile;
+
+#[derive(Parser)]
+pub struct BakeArgs {
+    /// Path to the bake spec
+    #[arg(default_value = "chunkah.yaml")]
+    spec: Utf8PathBuf,
+
+    /// Output file path, overriding the one in the spec (defaults to stdout)
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<Utf8PathBuf>,
+
+    /// Directory for temporary files [default: $TMPDIR]
+    ///
+    /// This holds the unpacked base image and the OCI image before it's
+    /// written out, so it needs about twice as much space as the rootfs.
+    #[arg(long, value_name = "DIR")]
+    workdir: Option<Utf8PathBuf>,
+}
+
+/// A declarative spec of an image derived from a base image.
+///
+/// Relative paths are relative to the directory of the spec.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Spec {
+    /// OCI image layout of the base image.
+    base: Utf8PathBuf,
+    /// Directory copied over the rootfs of the base image.
+    overlay: Option<Utf8PathBuf>,
+    /// Output file path.
+    output: Option<Utf8PathBuf>,
+    #[serde(default)]
+    config: ConfigOverrides,
+    #[serde(default)]
+    chunking: Chunking,
+}
+
+/// Overrides of the config of the base image.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigOverrides {
+    entrypoint: Option<Vec<String>>,
+    cmd: Option<Vec<String>>,
+    user: Option<String>,
+    working_dir: Option<String>,
+    /// Environment variables, added to or replacing those of the base image.
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+}
+
+/// Chunking policy, with the same meaning as the `build` options of the same
+/// name.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Chunking {
+    profile: Option<Profile>,
+    max_layers: Option<usize>,
+    reserve_layers: Option<usize>,
+    #[serde(default)]
+    pins: Vec<String>,
+    #[serde(default)]
+    prune: Vec<Utf8PathBuf>,
+    components_manifest: Option<Utf8PathBuf>,
+    compressed: Option<bool>,
+}
+
+impl Spec {
+    fn load(path: &Utf8Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path).with_context(|| format!("reading {path}"))?;
+        serde_yaml::from_str(&content).with_context(|| format!("parsing {path}"))
+    }
+
+    /// Returns the `build` arguments equivalent to the spec, for the rootfs at
+    /// `rootfs` with the base image config `config`.
+    fn build_args(
+        &self,
+        spec_dir: &Utf8Path,
+        rootfs: &Utf8Path,
+        config: &oci_image::ImageConfiguration,
+    ) -> Result<Vec<String>> {
+        let mut args = vec![
+            "--rootfs".to_string(),
+            rootfs.to_string(),
+            "--original-image".to_string(),
+            spec_dir.join(&self.base).to_string(),
+            "--config-str".to_string(),
+            self.config.apply(config)?,
+        ];
+        if let Some(output) = &self.output {
+            args.extend(["--output".to_string(), spec_dir.join(output).to_string()]);
+        }
+        for (key, value) in &self.config.labels {
+            args.extend(["--label".to_string(), format!("{key}={value}")]);
+        }
+        for (key, value) in &self.config.annotations {
+            args.extend(["--annotation".to_string(), format!("{key}={value}")]);
+        }
+
+        let chunking = &self.chunking;
+        if let Some(profile) = chunking.profile {
+            // SAFETY: none of the profiles are skipped
+            let value = profile.to_possible_value().unwrap();
+            args.push(format!("--profile={}", value.get_name()));
+        }
+        if let Some(n) = chunking.max_layers {
+            args.push(format!("--max-layers={n}"));
+        }
+        if let Some(n) = chunking.reserve_layers {
+            args.push(format!("--reserve-layers={n}"));
+        }
+        for pin in &chunking.pins {
+            args.extend(["--pin".to_string(), pin.clone()]);
+        }
+        for path in &chunking.prune {
+            args.extend(["--prune".to_string(), path.to_string()]);
+        }
+        if let Some(path) = &chunking.components_manifest {
+            args.extend([
+                "--components-manifest".to_string(),
+                spec_dir.join(path).to_string(),
+            ]);
+        }
+        if let Some(compressed) = chunking.compressed {
+            args.push(format!("--compressed={compressed}"));
+        }
+        Ok(args)
+    }
+}
+
+impl ConfigOverrides {
+    /// Apply the overrides to the base image config, returning it in the
+    /// format of `--config-str`.
+    fn apply(&self, base: &oci_image::ImageConfiguration) -> Result<String> {
+        let mut config = base.config().clone().unwrap_or_default();
+        if let Some(entrypoint) = &self.entrypoint {
+            config.set_entrypoint(Some(entrypoint.clone()));
+        }
+        if let Some(cmd) = &self.cmd {
+            config.set_cmd(Some(cmd.clone()));
+        }
+        if let Some(user) = &self.user {
+            config.set_user(Some(user.clone()));
+        }
+        if let Some(dir) = &self.working_dir {
+            config.set_working_dir(Some(dir.clone()));
+        }
+        if !self.env.is_empty() {
+            let mut env: Vec<String> = config
+                .env()
+                .iter()
+                .flatten()
+                .filter(|var| {
+                    let key = var.split_once('=').map_or(var.as_str(), |(key, _)| key);
+                    !self.env.contains_key(key)
+                })
+                .cloned()
+                .collect();
+            env.extend(self.env.iter().map(|(key, value)| format!("{key}={value}")));
+            config.set_env(Some(env));
+        }
+        let inspect = serde_json::json!({
+            "Config": config,
+            "Architecture": base.architecture().to_string(),
+        });
+        serde_json::to_string(&inspect).context("serializing config")
+    }
+}
+
+pub fn run(args: &BakeArgs) -> Result<()> {
+    let spec = Spec::load(&args.spec).context("loading spec")?;
+    let spec_dir = args.spec.parent().unwrap_or(Utf8Path::new(""));
+
+    let base_path = spec_dir.join(&spec.base);
+    let base =
+        BaseImage::open(&base_path).with_context(|| format!("opening base image {base_path}"))?;
+
+    let mut builder = tempfile::Builder::new();
+    builder.prefix("chunkah-bake-");
+    let tmpdir = match &args.workdir {
+        Some(dir) => builder.tempdir_in(dir),
+        None => builder.tempdir(),
+    }
+    .context("creating temporary directory")?;
+    let tmp_path =
+        Utf8Path::from_path(tmpdir.path()).context("temporary directory path is not UTF-8")?;
+    // the temporary directory itself is only accessible to us
+    let rootfs = &tmp_path.join("rootfs");
+    std::fs::create_dir(rootfs).with_context(|| format!("creating {rootfs}"))?;
+    base.unpack(rootfs)
+        .with_context(|| format!("unpacking base image {base_path}"))?;
+    if let Some(overlay) = &spec.overlay {
+        copy_overlay(&spec_dir.join(overlay), rootfs)?;
+    }
+
+    let mut build_args = spec
+        .build_args(spec_dir, rootfs, base.config())
+        .context("converting spec to build options")?;
+    if let Some(output) = &args.output {
+        build_args.extend(["--output".to_string(), output.to_string()]);
+    }
+    if let Some(workdir) = &args.workdir {
+        build_args.extend(["--workdir".to_string(), workdir.to_string()]);
+    }
+    let build_args =
+        BuildArgs::try_parse_from(std::iter::once("chunkah build".to_string()).chain(build_args))
+            .context("parsing build options from spec")?;
+    cmd_build::run(&build_args)
+}
+
+/// Copy the contents of the directory `overlay` over `rootfs`.
+///
+/// This uses `cp` to preserve all the metadata of files.
+fn copy_overlay(overlay: &Utf8Path, rootfs: &Utf8Path) -> Result<()> {
+    anyhow::ensure!(overlay.is_dir(), "overlay {overlay} is not a directory");
+    let status = Command::new("cp")
+        .arg("-a")
+        .arg(overlay.join("."))
+        .arg(rootfs)
+        // the OCI archive may be going to our stdout
+        .stdout(std::io::stderr())
+        .status()
+        .context("spawning cp")?;
+    anyhow::ensure!(
+        status.success(),
+        "copying overlay {overlay} failed: {status}"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: &str = r#"
+base: base-image
+overlay: overlay
+output: /out/image.ociarchive
+config:
+  cmd: ["/app", "--serve"]
+  env:
+    PATH: /app/bin:/usr/bin
+    DEBUG: "1"
+  labels:
+    version: "2"
+chunking:
+  profile: webapp
+  max-layers: 48
+  pins: [rpm/glibc]
+  prune: [/var/cache/]
+  components-manifest: components.toml
+"#;
+
+    #[test]
+    fn test_parse_spec() {
+        let spec: Spec = serde_yaml::from_str(SPEC).unwrap();
+        assert_eq!(spec.base, "base-image");
+        assert_eq!(spec.overlay.as_deref(), Some(Utf8Path::new("overlay")));
+        assert_eq!(spec.chunking.profile, Some(Profile::Webapp));
+        assert_eq!(spec.chunking.max_layers, Some(48));
+        assert_eq!(spec.config.env.len(), 2);
+
+        // typos shouldn't be silently ignored
+        assert!(serde_yaml::from_str::<Spec>("base: x\nmax-layers: 48\n").is_err());
+        assert!(serde_yaml::from_str::<Spec>("base: x\nchunking:\n  max_layers: 48\n").is_err());
+        assert!(serde_yaml::from_str::<Spec>("overlay: x\n").is_err());
+    }
+
+    #[test]
+    fn test_build_args() {
+        let spec: Spec = serde_yaml::from_str(SPEC).unwrap();
+        let base: oci_image::ImageConfiguration = serde_json::from_str(
+            r#"{
+                "architecture": "arm64",
+                "os": "linux",
+                "config": {"Env": ["PATH=/usr/bin", "LANG=C"], "Cmd": ["/bin/sh"]},
+                "rootfs": {"type": "layers", "diff_ids": []}
+            }"#,
+        )
+        .unwrap();
+
+        let args = spec
+            .build_args(Utf8Path::new("/src"), Utf8Path::new("/tmp/rootfs"), &base)
+            .unwrap();
+        // they must be valid build options
+        BuildArgs::try_parse_from(std::iter::once("chunkah build".to_string()).chain(args.clone()))
+            .unwrap();
+
+        let value_of = |flag: &str| {
+            let i = args.iter().position(|a| a == flag).unwrap();
+            args[i + 1].as_str()
+        };
+        assert_eq!(value_of("--rootfs"), "/tmp/rootfs");
+        assert_eq!(value_of("--original-image"), "/src/base-image");
+        assert_eq!(value_of("--output"), "/out/image.ociarchive");
+        assert_eq!(value_of("--label"), "version=2");
+        assert_eq!(value_of("--pin"), "rpm/glibc");
+        assert_eq!(value_of("--components-manifest"), "/src/components.toml");
+        assert!(args.contains(&"--profile=webapp".to_string()));
+        assert!(args.contains(&"--max-layers=48".to_string()));
+
+        let config: serde_json::Value = serde_json::from_str(value_of("--config-str")).unwrap();
+        assert_eq!(config["Architecture"], "arm64");
+        assert_eq!(
+            config["Config"]["Env"],
+            serde_json::json!(["LANG=C", "DEBUG=1", "PATH=/app/bin:/usr/bin"])
+        );
+        assert_eq!(
+            config["Config"]["Cmd"],
+            serde_json::json!(["/app", "--serve"])
+        );
+    }
+}
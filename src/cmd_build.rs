@@ -1,31 +1,215 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
 
 use anyhow::{Context, Result};
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 use cap_std_ext::cap_std::ambient_authority;
 use cap_std_ext::cap_std::fs::Dir;
 use clap::Parser;
 use ocidir::oci_spec::image as oci_image;
 use serde::Deserialize;
 
-use crate::components::{Component, ComponentsRepos, FileMap};
-use crate::ocibuilder::{Builder, Compression};
-use crate::packing::{PackItem, calculate_packing};
+use crate::components::{
+    BigfilesGroupBy, ChurnState, ClaimCache, ClaimPolicy, Component, ComponentsRepos, FileMap,
+    FileType, RepoConfig, RepoLoader, RetentionRules, RpmConfigFiles, RpmGroupBy,
+    StabilityOverrides, StabilitySnapshot, UNCLAIMED_COMPONENT, UpdateInfo,
+};
+use crate::constraints::LayerConstraints;
+use crate::debug_bundle::{self, DebugBundle};
+use crate::diagnostics;
+use crate::expected::ExpectedPaths;
+use crate::idmap::{IdMap, IdRange};
+use crate::ignore::IgnoreRules;
+use crate::metrics::Metrics;
+use crate::normalize::Normalizer;
+use crate::ocibuilder::{ArchiveFormat, BaseImage, Builder, Compression, LayerMediaType};
+use crate::overlay::{AddedFile, Overlay};
+use crate::owners::OwnerNames;
+use crate::packing::{
+    PackGroup, PackItem, PackOptions, PackingAlgorithm, calculate_packing, calculate_packing_exact,
+    pins_fit, relax_pins,
+};
+use crate::plan::Plan;
+use crate::profile::{Profile, ProfileDefaults};
+use crate::rewrite::{PathRewrite, PathRewrites};
+use crate::rootfs_image::{ImageFormat, UnpackedImage};
+use crate::scan::NonUtf8Paths;
+use crate::selftest::{Extractor, SelfTest};
+use crate::tar::{EntryOrder, TarFormat};
+use crate::trace::{self, LogFormat, Tracing};
+use crate::transform::{Chmod, Chown};
+use crate::user_config::UserConfig;
 use crate::utils;
 
-#[derive(Parser, Default)]
+/// Maximum number of directories listed when reporting unclaimed files.
+const MAX_REPORTED_UNCLAIMED_DIRS: usize = 10;
+
+#[derive(Parser, Default, Clone)]
 pub struct BuildArgs {
     /// Path to the rootfs to build from
-    #[arg(long, env = "CHUNKAH_ROOTFS", hide_env_values = true)]
-    rootfs: Utf8PathBuf,
+    ///
+    /// This is a directory, or a squashfs or erofs image. Images are read
+    /// without mounting them and their files are extracted in --workdir for
+    /// the package databases to be read, but their ownership, modes, mtimes,
+    /// device numbers and xattrs come from the image, so no privileges are
+    /// needed to preserve them.
+    #[arg(
+        long,
+        env = "CHUNKAH_ROOTFS",
+        hide_env_values = true,
+        required_unless_present = "rootfs_tar"
+    )]
+    rootfs: Option<Utf8PathBuf>,
+
+    /// Build from this tarball of the rootfs instead
+    ///
+    /// The tarball may be compressed with gzip, xz or bzip2. Use `-` to read it
+    /// from stdin, e.g. from `podman export`. Its files are extracted in
+    /// --workdir for the package databases to be read, but their ownership,
+    /// modes, mtimes, device numbers and xattrs come from the tar headers, so
+    /// no privileges are needed to preserve them. This takes precedence over
+    /// --rootfs, which the chunkah image sets through $CHUNKAH_ROOTFS.
+    #[arg(long, value_name = "PATH")]
+    rootfs_tar: Option<Utf8PathBuf>,
+
+    /// Apply this uncompressed tarball on top of the rootfs
+    ///
+    /// Its files are read straight from the tarball, without extracting it,
+    /// and go into an `overlay/<name>` component named after the tarball
+    /// (e.g. `overlay/app` for `app.tar`). Directories which exist in the
+    /// rootfs keep their metadata. Can be specified multiple times; later
+    /// tarballs override earlier ones.
+    #[arg(long = "apply-tar", value_name = "PATH")]
+    apply_tars: Vec<Utf8PathBuf>,
+
+    /// Add a file to the image
+    ///
+    /// SRC is a file of the host, or `inline:TEXT` for the given content, and
+    /// DEST its absolute path in the image, e.g. `version.txt:/etc/app-version`.
+    /// Added files are root-owned, have mode MODE (octal, 0644 by default)
+    /// and go into the `overlay/added` component. They're applied after the
+    /// --apply-tar tarballs. Can be specified multiple times.
+    #[arg(long = "add", value_name = "SRC:DEST[:MODE]", value_parser = AddedFile::parse)]
+    adds: Vec<AddedFile>,
+
+    /// Move the paths under FROM to TO in the image
+    ///
+    /// Given as `FROM=TO` with absolute paths, e.g. `/build/stage=/opt/app`,
+    /// to ship a staged tree elsewhere without copying it. Absolute symlink
+    /// targets under FROM are rewritten too. Missing parent directories of TO
+    /// are added as root-owned 0755 directories, and parent directories of
+    /// FROM left empty are dropped. Can be specified multiple times; the first
+    /// matching rule applies.
+    #[arg(long = "transform", value_name = "FROM=TO", value_parser = PathRewrite::parse)]
+    rewrites: Vec<PathRewrite>,
 
     /// Output file path (defaults to stdout)
     #[arg(short, long, value_name = "PATH")]
     output: Option<Utf8PathBuf>,
 
-    /// Maximum number of layers to output
-    #[arg(long, default_value_t = 64)]
-    max_layers: usize,
+    /// Directory for temporary files [default: $TMPDIR]
+    ///
+    /// This holds the OCI image before it's written out and unpacked rootfs
+    /// images, so it needs about as much space as the rootfs.
+    #[arg(long, value_name = "DIR")]
+    workdir: Option<Utf8PathBuf>,
+
+    /// Don't read defaults from ~/.config/chunkah/config.toml
+    ///
+    /// See the README for the options which can be set there. Use this, e.g.
+    /// in CI, so that builds don't depend on the user running them.
+    #[arg(
+        long,
+        env = "CHUNKAH_NO_USER_CONFIG",
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    no_user_config: bool,
+
+    /// Preset of defaults for a class of images
+    ///
+    /// Sets defaults for --max-layers, --compressed, --entry-order,
+    /// --bigfile-threshold, --component-xattr and --packing, and adds prune
+    /// rules appropriate for the image class. Options passed explicitly take
+    /// precedence.
+    #[arg(long, value_name = "PROFILE", value_enum, env = "CHUNKAH_PROFILE")]
+    profile: Option<Profile>,
+
+    /// Maximum number of layers to output [default: 64]
+    #[arg(long)]
+    max_layers: Option<usize>,
+
+    /// Number of layers to leave free for derived images
+    ///
+    /// Packing targets --max-layers minus this value, so that images built
+    /// `FROM` the output can add their own layers without hitting runtime
+    /// layer limits.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    reserve_layers: usize,
+
+    /// Keep a component in a layer of its own
+    ///
+    /// Pinned components are never packed together with other components.
+    /// Can be specified multiple times.
+    #[arg(long = "pin", value_name = "COMPONENT")]
+    pins: Vec<String>,
+
+    /// Unpin components if the pins don't fit in the layer budget
+    ///
+    /// By default, chunkah fails if there are more pinned components than
+    /// available layers. With this flag, the pinned components worth the
+    /// least are packed normally instead.
+    #[arg(long)]
+    relax_pins: bool,
+
+    /// Algorithm used to pack components into layers [default: greedy]
+    ///
+    /// `greedy` merges the components whose merge loses the least reuse until
+    /// they fit in the layers. `exact` searches for the packing with the best
+    /// reuse, which is only practical for up to a few dozen components; if
+    /// the search takes too long, the best packing found so far is used with
+    /// a warning.
+    #[arg(long, value_name = "ALGORITHM", value_enum)]
+    packing: Option<PackingAlgorithm>,
+
+    /// Fixed cost of each layer, in bytes, for the packing
+    ///
+    /// Pulling a layer costs requests and round trips besides its size, which
+    /// some registries and CDNs make expensive for many small blobs. With an
+    /// overhead, components are packed together, even below --max-layers,
+    /// whenever the reuse lost is worth less than the overhead saved. SIZE
+    /// accepts the K, M and G suffixes (powers of 1024).
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "0")]
+    layer_overhead_bytes: u64,
+
+    /// OCI image layout of a previous build, whose packing to favor
+    ///
+    /// Components packed in the same layer in that image are packed together
+    /// again, unless another packing is clearly better, so that unchanged
+    /// layers keep their digest across rebuilds. Only its manifest is read,
+    /// so the directory doesn't need the layer blobs. A rebuild with the same
+    /// layers and only a different config is pointed out.
+    #[arg(long, value_name = "PATH")]
+    prev_image: Option<Utf8PathBuf>,
+
+    /// Read constraints on the packing of components from a TOML file
+    ///
+    /// The file lists components which must be packed together, components
+    /// which must each be in a different layer, and components which must get
+    /// a layer of their own at a given index among the component layers.
+    /// Other components are packed freely around them. See the README for
+    /// the format.
+    #[arg(long, value_name = "PATH")]
+    pack_constraints: Option<Utf8PathBuf>,
+
+    /// Maximum size of a layer
+    ///
+    /// Components larger than SIZE are split into several layers along their
+    /// directory trees, and components are only packed together if the layer
+    /// stays within SIZE. Some registries and CDNs behave badly with layers
+    /// of several gigabytes. Files larger than SIZE still get a layer of their
+    /// own. SIZE accepts the K, M and G suffixes (powers of 1024).
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_layer_size: Option<u64>,
 
     /// Read image config from a JSON file
     ///
@@ -45,6 +229,274 @@ pub struct BuildArgs {
     )]
     config_str: Option<String>,
 
+    /// OCI image layout of the image the rootfs comes from
+    ///
+    /// When resplitting an existing image, the original layers are used as
+    /// components for files no other component repo claims, rather than
+    /// putting them all in one layer. The directory must contain a single
+    /// image, e.g. as created by `skopeo copy ... oci:PATH`.
+    #[arg(long, value_name = "PATH")]
+    original_image: Option<Utf8PathBuf>,
+
+    /// Only emit the layers of components matching this glob pattern
+    ///
+    /// Builds a thin image, e.g. of an app, on top of the layers of
+    /// --base-image, which are referenced by digest but not copied. Files of
+    /// the other components are expected to be in the base image and are left
+    /// out. Can be specified multiple times.
+    #[arg(
+        long = "only-components",
+        value_name = "GLOB",
+        requires = "base_image",
+        conflicts_with = "validate"
+    )]
+    only_components: Vec<String>,
+
+    /// OCI image layout of the base image for --only-components
+    ///
+    /// Only its manifest and config are read, so the directory doesn't need
+    /// the layer blobs. Its config is used unless --config or --config-str is
+    /// given. The output can only be pushed to registries which already have
+    /// the base layers.
+    #[arg(long, value_name = "PATH", requires = "only_components")]
+    base_image: Option<Utf8PathBuf>,
+
+    /// Registry credentials file for the external claimers
+    ///
+    /// Passed to the executables given with --claimer-exec as
+    /// $REGISTRY_AUTH_FILE, e.g. for a claimer looking up images in a
+    /// registry; see containers-auth.json(5) for the format.
+    #[arg(long, value_name = "PATH", env = "REGISTRY_AUTH_FILE")]
+    registry_auth_file: Option<Utf8PathBuf>,
+
+    /// Run an external executable to claim files
+    ///
+    /// The executable gets the list of files on stdin and writes the
+    /// components claiming them on stdout, both as JSON (see the README for
+    /// the format). Can be specified multiple times.
+    #[arg(long = "claimer-exec", value_name = "PATH")]
+    claimer_execs: Vec<Utf8PathBuf>,
+
+    /// Keep paths in their component from a previous build
+    ///
+    /// Paths that no component repo claims anymore are assigned to the
+    /// component they were in in the previous build, which keeps layers
+    /// stable across rebuilds. PATH is either the components JSON of that
+    /// build or an OCI image layout of the image, which must have been built
+    /// with --embed-components.
+    #[arg(long, value_name = "PATH")]
+    claims_from: Option<Utf8PathBuf>,
+
+    /// Assign files to components using a TOML manifest
+    ///
+    /// The manifest maps glob patterns to component names, with optional
+    /// stability and mtime clamps. It takes precedence over all other sources
+    /// except the `user.component` xattr. Its `retention` hints are added to
+    /// layers as the `org.chunkah.retention` annotation.
+    #[arg(long, value_name = "PATH")]
+    components_manifest: Option<Utf8PathBuf>,
+
+    /// Override the priority of a component repo
+    ///
+    /// Repos with lower values claim paths first. For example, `rpm=0` makes
+    /// the rpm database win over `user.component` xattrs. See the README for
+    /// the repo names and their default priorities. Can be specified multiple
+    /// times.
+    #[arg(long = "repo-priority", value_name = "NAME=N")]
+    repo_priorities: Vec<String>,
+
+    /// Don't use a component repo
+    ///
+    /// Files it would have claimed are claimed by the next repo in priority
+    /// order instead. Can be specified multiple times.
+    #[arg(long = "disable-repo", value_name = "NAME")]
+    disabled_repos: Vec<String>,
+
+    /// How to resolve paths claimed by more than one component repo
+    ///
+    /// `first-wins` gives the path to the highest priority repo. `union`
+    /// gives it to every repo claiming it, e.g. to let repos co-claim shared
+    /// directories. `error-on-conflict` fails if repos claim a file or
+    /// symlink for different components. Fallback repos (`previous`,
+    /// `layers`, `bigfiles`) only get paths no other repo claims.
+    #[arg(long, value_name = "POLICY", value_enum, default_value_t)]
+    claim_policy: ClaimPolicy,
+
+    /// What to group rpm files into components by
+    ///
+    /// `srpm` merges all the subpackages of a source RPM (e.g. `-devel`,
+    /// `-docs`) into one component. `package` keeps them apart, for finer
+    /// grained layers; the packer still merges them if needed to stay within
+    /// --max-layers.
+    #[arg(long, value_name = "GROUP", value_enum, default_value_t)]
+    rpm_group_by: RpmGroupBy,
+
+    /// Where to put rpm `%config(noreplace)` files
+    ///
+    /// These are often modified after installation, e.g. in derived images.
+    /// `split` moves them to a `<name>-config` component per rpm component, and
+    /// `shared` to a single `rpm/config` component, so that changing them
+    /// doesn't invalidate the layers with the rest of the packages.
+    #[arg(long, value_name = "WHERE", value_enum, default_value_t)]
+    rpm_config_files: RpmConfigFiles,
+
+    /// Check rpm files against the digests in the rpmdb
+    ///
+    /// Files modified after installation, e.g. by a later build step, are
+    /// moved to an `rpm/modified` component instead of staying with their
+    /// package. This reads every file owned by a package whose size matches,
+    /// so it makes the build noticeably slower.
+    #[arg(long)]
+    rpm_verify: bool,
+
+    /// Check dpkg files against the digests in their package's `.md5sums`
+    ///
+    /// Files modified after installation are moved to a `dpkg/modified`
+    /// component instead of staying with their package, like with
+    /// --rpm-verify. This reads every file with a digest, so it makes the
+    /// build noticeably slower.
+    #[arg(long)]
+    dpkg_verify: bool,
+
+    /// Also estimate rpm stability from the updates in this updateinfo.xml
+    ///
+    /// A path or an http(s) URL to the `updateinfo.xml` of the distribution's
+    /// update repos, optionally compressed. Changelogs undercount updates for
+    /// packages which rarely document them; with this, the stability of a
+    /// component reflects how often updates of it were actually published.
+    /// Can be specified multiple times.
+    #[arg(long, value_name = "LOCATION")]
+    rpm_updateinfo: Vec<String>,
+
+    /// Override the stability of components from a TOML file
+    ///
+    /// The file maps component names or glob patterns over them to a
+    /// stability between 0 (exclusive) and 1, e.g. `"rpm/internal-*" = 0.05`.
+    /// Overrides are applied after the repos computed their stabilities; an
+    /// exact name wins over patterns, and the lowest matching pattern wins
+    /// over the others.
+    #[arg(long, value_name = "FILE")]
+    stability_overrides: Option<Utf8PathBuf>,
+
+    /// Record the stability of every component to this file
+    ///
+    /// Stabilities depend on the time of the build, so two builds of the same
+    /// rootfs can be packed differently. Pass the file to --stability-in in a
+    /// later build to compare or bisect builds.
+    #[arg(long, value_name = "FILE")]
+    stability_out: Option<Utf8PathBuf>,
+
+    /// Use the stabilities recorded with --stability-out
+    ///
+    /// Components which aren't in the file keep their computed stability.
+    #[arg(long, value_name = "FILE")]
+    stability_in: Option<Utf8PathBuf>,
+
+    /// Record the layers of the image and the paths they hold to this file
+    ///
+    /// Claiming and packing depend on e.g. the time of the build and the
+    /// available repos. Pass the file to --plan in a later build to get the
+    /// same layers.
+    #[arg(long, value_name = "FILE")]
+    plan_out: Option<Utf8PathBuf>,
+
+    /// Build the layers recorded with --plan-out
+    ///
+    /// Files aren't claimed and components aren't packed, so the options
+    /// about those are ignored. The files of the rootfs must be exactly those
+    /// of the plan.
+    #[arg(long, value_name = "FILE", conflicts_with = "only_components")]
+    plan: Option<Utf8PathBuf>,
+
+    /// Do everything but write the layers, and print them instead
+    ///
+    /// Each layer is printed with its uncompressed size, file count,
+    /// stability and components. Nothing is written to the output.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Learn the stability of components from previous builds in this directory
+    ///
+    /// Each build records which components changed since the previous one,
+    /// and blends how often they were observed to change into their computed
+    /// stability, with more weight the longer they've been observed. Builds
+    /// of the same image should share the directory, e.g. through a CI cache.
+    #[arg(long, value_name = "DIR")]
+    state_dir: Option<Utf8PathBuf>,
+
+    /// Number of threads listing directories while scanning (0: one per CPU)
+    /// [default: 1]
+    ///
+    /// On large rootfs, reading the metadata of each file dominates the scan.
+    /// With more threads, directories are listed in parallel; the files found
+    /// are the same.
+    #[arg(long, value_name = "N")]
+    scan_threads: Option<usize>,
+
+    /// Hash the content of regular files while scanning the rootfs
+    ///
+    /// This reads every file of the rootfs once more, but lets --state-dir
+    /// spot components whose content changed while their size and mtime
+    /// didn't (e.g. rebuilt with a clamped mtime).
+    #[arg(long)]
+    hash_contents: bool,
+
+    /// Put docs, translations, man pages and icons in layers of their own
+    ///
+    /// Files under e.g. /usr/share/doc, /usr/share/locale and /usr/share/man
+    /// are taken out of the components of their packages and grouped into
+    /// `content/doc`, `content/locale`, `content/man` and `content/icons`.
+    /// These trees change with most package updates, so this lets the layers
+    /// of the packages be reused more often.
+    #[arg(long)]
+    content_classes: bool,
+
+    /// Put each kernel, with its modules and initramfs, in a layer of its own
+    ///
+    /// `/usr/lib/modules/<version>` and the files of the same version in
+    /// /boot are claimed into a `kernel/<version>` component, which is pinned
+    /// so that it's never packed with other components. This matches what
+    /// bootc and rpm-ostree expect, and avoids redownloading userspace
+    /// packages on kernel updates.
+    #[arg(long)]
+    kernel_layer: bool,
+
+    /// Put unclaimed files of at least this size in components of their own
+    /// [default: 1M]
+    ///
+    /// Such files can then be packed into layers of their own, e.g. model
+    /// files, instead of going to the layer of all the unclaimed files. SIZE
+    /// accepts the K, M and G suffixes (powers of 1024). Use `--disable-repo
+    /// bigfiles` to leave all unclaimed files together.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    bigfile_threshold: Option<u64>,
+
+    /// Group big files into components by file, extension or directory
+    ///
+    /// With `extension` or `directory`, related big files (e.g. all `*.ko`
+    /// files, or all the files of a directory) share a component, and so a
+    /// layer.
+    #[arg(long, value_name = "GROUP", value_enum, default_value_t)]
+    bigfile_group_by: BigfilesGroupBy,
+
+    /// Read the component of paths from this xattr [default: user.component]
+    ///
+    /// For build systems which already stamp files with their own attribute.
+    /// The stability and mtime overrides are read from the same name with a
+    /// `.stability` or `.mtime` suffix. Must be in the `user` or `trusted`
+    /// namespace, e.g. `trusted.chunkah.component`; `trusted` xattrs are only
+    /// visible when running as root.
+    #[arg(long, value_name = "NAME", value_parser = parse_component_xattr)]
+    component_xattr: Option<String>,
+
+    /// Cache parsed package databases in this directory
+    ///
+    /// Entries are keyed by a digest of the rpm or dpkg database, so repeated
+    /// builds of rootfses with an unchanged database skip parsing it. Entries
+    /// are never removed; the directory can be deleted at any time.
+    #[arg(long, value_name = "DIR", env = "CHUNKAH_CLAIM_CACHE")]
+    claim_cache: Option<Utf8PathBuf>,
+
     /// Add or remove a label from the image
     ///
     /// Format: KEY=VALUE to set, KEY- to remove, or - to clear all.
@@ -71,16 +523,225 @@ pub struct BuildArgs {
     /// Compress layers and the OCI archive with gzip
     ///
     /// By default, layers and the OCI archive are uncompressed. This flag
-    /// enables gzip compression for both.
-    #[arg(long)]
-    compressed: bool,
+    /// enables gzip compression for both. Use `--compressed=false` to
+    /// override a profile enabling it.
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true")]
+    compressed: Option<bool>,
 
     /// Gzip compression level (0-9, default: 6)
     ///
     /// Level 0 is no compression (fastest), 9 is maximum compression (slowest).
-    /// Only applies when --compressed is specified.
-    #[arg(long, value_name = "LEVEL", default_value_t = 6, value_parser = clap::value_parser!(u32).range(0..=9))]
-    compression_level: u32,
+    /// Only applies when --compressed or --archive-compression is specified.
+    #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(u32).range(0..=9))]
+    compression_level: Option<u32>,
+
+    /// Number of threads compressing each layer (0: one per CPU)
+    ///
+    /// By default, each layer is compressed on a single thread. With more,
+    /// the layer is cut into blocks compressed in parallel, like pigz does.
+    /// The layers then differ from those compressed on a single thread, but
+    /// not depending on the number of threads.
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    compression_threads: usize,
+
+    /// Number of layers written in parallel (0: one per CPU) [default: 1]
+    ///
+    /// By default, the layers are written one after the other. With more
+    /// threads, several layers are written at once, but they're still added
+    /// to the image in the same order, so the image doesn't depend on the
+    /// number of threads. Each of them may use --compression-threads.
+    #[arg(long, value_name = "N")]
+    build_threads: Option<usize>,
+
+    /// Number of threads reading files ahead of the layer writer (0: none)
+    ///
+    /// By default, each file is read when it's written to its layer. On
+    /// storage with a high latency (e.g. a rootfs on NFS), reading the next
+    /// files on a few threads while the previous ones are compressed can
+    /// speed up the build a lot. At most 64 MiB are read ahead of the writer
+    /// of each layer. The layers are the same either way.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    read_ahead: usize,
+
+    /// Lower the compression of layers of already compressed files
+    ///
+    /// The start of the largest files of each component is checked for the
+    /// magic numbers of compressed formats (e.g. jars, PNGs, zstd firmware).
+    /// Layers made mostly (90%) of such files aren't compressed, and layers
+    /// made of at least half of them are compressed at level 1. Only applies
+    /// with --compressed.
+    #[arg(long)]
+    adaptive_compression: bool,
+
+    /// Also write the merged rootfs to PATH as a single uncompressed tarball
+    ///
+    /// The tarball has the same content, metadata and mtime clamping as the
+    /// layers of the image, for tools which need the exact same files (e.g.
+    /// `ostree commit` or `mkfs.erofs --tar`).
+    #[arg(long, value_name = "PATH")]
+    also_emit_rootfs_tar: Option<Utf8PathBuf>,
+
+    /// Compression of the OCI archive
+    ///
+    /// By default, the OCI archive is compressed with gzip if the layers are
+    /// (see --compressed), and uncompressed otherwise. This only changes the
+    /// outer archive, e.g. to publish it as a `.tar.xz` release tarball; layers
+    /// stay gzip or uncompressed. Uses --compression-level.
+    #[arg(long, value_name = "FORMAT", value_enum)]
+    archive_compression: Option<ArchiveFormat>,
+
+    /// Order of entries within each layer
+    ///
+    /// `path` sorts entries by path. `size` writes directories first, then
+    /// all other entries from smallest to largest, which can help streaming
+    /// extractors and lazy pullers. [default: path]
+    #[arg(long, value_name = "ORDER", value_enum)]
+    entry_order: Option<EntryOrder>,
+
+    /// Format of the tar headers of layer entries
+    ///
+    /// `gnu` writes GNU headers, with GNU long name entries for paths and
+    /// symlink targets longer than 100 bytes. `pax` writes POSIX ustar headers
+    /// and stores long paths, symlink targets and xattrs in a single PAX
+    /// extended header per entry, for extractors which don't support GNU
+    /// extensions.
+    #[arg(long, value_name = "FORMAT", value_enum, default_value_t)]
+    tar_format: TarFormat,
+
+    /// Set the user and group names of layer entries
+    ///
+    /// By default, entries only carry numeric owners. This also sets their
+    /// user and group names, resolved against the rootfs's own /etc/passwd
+    /// and /etc/group (never the host's), for tools and humans relying on
+    /// symbolic ownership. IDs without a name stay numeric only.
+    #[arg(long)]
+    owner_names: bool,
+
+    /// Map user IDs of the rootfs to other IDs in the image
+    ///
+    /// For rootfs trees created inside a user namespace, whose IDs are
+    /// shifted on disk. `FROM:TO:COUNT` maps the COUNT IDs from FROM to
+    /// the ones from TO (e.g. `100000:0:65536`), and `FROM:TO` shifts all IDs
+    /// from FROM up. The first matching map applies and IDs outside of all
+    /// maps are kept. Can be specified multiple times.
+    #[arg(long = "uidmap", value_name = "MAP", value_parser = IdRange::parse)]
+    uid_maps: Vec<IdRange>,
+
+    /// Map group IDs of the rootfs to other IDs in the image
+    ///
+    /// Same as --uidmap, for group IDs.
+    #[arg(long = "gidmap", value_name = "MAP", value_parser = IdRange::parse)]
+    gid_maps: Vec<IdRange>,
+
+    /// Set the owner of paths in the image
+    ///
+    /// `UID:GID` sets the owner of all paths, and `UID:GID:PATHGLOB` that of
+    /// the absolute paths matching PATHGLOB (`*` and `?` don't match `/`,
+    /// `**` does), e.g. `0:0:/usr/**`. Applies after --uidmap and --gidmap.
+    /// Can be specified multiple times; the last matching one wins.
+    #[arg(long, value_name = "UID:GID[:PATHGLOB]", value_parser = Chown::parse)]
+    chown: Vec<Chown>,
+
+    /// Set the permission bits of paths in the image
+    ///
+    /// `MODE:PATHGLOB` sets the permission bits of the absolute paths matching
+    /// PATHGLOB to the octal MODE, e.g. `0644:/etc/app/*.conf`. Symlinks are
+    /// left alone. Can be specified multiple times; the last matching one
+    /// wins.
+    #[arg(long, value_name = "MODE:PATHGLOB", value_parser = Chmod::parse)]
+    chmod: Vec<Chmod>,
+
+    /// Family of media types to use for layers
+    ///
+    /// `oci` uses the OCI layer media types. `docker` uses the Docker ones,
+    /// which some older runtimes and registries require. The manifest itself
+    /// is always an OCI manifest.
+    #[arg(long, value_name = "TYPE", value_enum, default_value_t)]
+    layer_media_type: LayerMediaType,
+
+    /// Normalize embedded timestamps in known file formats
+    ///
+    /// This modifies file content so it's opt-in. Embedded timestamps are
+    /// clamped the same way file mtimes are. Can be specified multiple times.
+    #[arg(long = "normalize", value_name = "FORMAT", value_enum)]
+    normalizers: Vec<Normalizer>,
+
+    /// Embed the component to path mapping in the image
+    ///
+    /// Writes /usr/share/chunkah/components.json in an extra final layer so
+    /// that tools running in the container can see how the image was chunked.
+    /// This layer counts towards --max-layers.
+    #[arg(long)]
+    embed_components: bool,
+
+    /// Validate the image against the OCI image-spec before writing it
+    ///
+    /// Checks required fields, digests, sizes, media types and that the
+    /// manifest stays within registry size limits. This re-reads all layers.
+    #[arg(long)]
+    validate: bool,
+
+    /// Annotate each layer with a digest of its inputs
+    ///
+    /// The digest covers the sorted list of paths in the layer and their
+    /// content digests, but not their metadata. This allows verifying that a
+    /// layer matches a set of files without rebuilding the image.
+    #[arg(long)]
+    inputs_digests: bool,
+
+    /// Annotate each layer with the packages it contains
+    ///
+    /// The `org.chunkah.packages` annotation lists the packages of the
+    /// components in the layer, separated by spaces, e.g. rpm NEVRAs. This
+    /// lets scanners and update planners map packages to layers without
+    /// pulling them.
+    #[arg(long)]
+    package_annotations: bool,
+
+    /// Extract each layer after writing it and check its files' metadata
+    ///
+    /// The tar headers and the files extracted with the tar crate into a
+    /// scratch directory must have the same type, mode, ownership and mtime as
+    /// in the rootfs (after mtime clamping). Ownership and setuid/setgid bits
+    /// of extracted files are only checked when running as root.
+    #[arg(long)]
+    self_test: bool,
+
+    /// Also extract layers with GNU tar and bsdtar in the self-test
+    ///
+    /// Both run with --numeric-owner, like container runtimes. Those which
+    /// aren't installed are skipped. Implies --self-test.
+    #[arg(long)]
+    self_test_system_tar: bool,
+
+    /// Fail if more than this percentage of bytes is unclaimed
+    ///
+    /// A high ratio usually means the package database wasn't detected, in
+    /// which case chunking does little of use.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(0..=100))]
+    max_unclaimed_percent: Option<u8>,
+
+    /// Fail if any file is unclaimed
+    ///
+    /// The error lists where the unclaimed files are. In strict pipelines,
+    /// unclaimed files usually mean a misconfigured rootfs (e.g. files
+    /// installed outside of the package manager). Directories don't count.
+    #[arg(long, conflicts_with = "warn_unclaimed")]
+    fail_on_unclaimed: bool,
+
+    /// Warn if at least SIZE bytes are unclaimed
+    ///
+    /// Without SIZE, warns about any unclaimed file. SIZE accepts the K, M and
+    /// G suffixes (powers of 1024). This also makes --max-unclaimed-percent
+    /// only warn instead of failing.
+    #[arg(
+        long,
+        value_name = "SIZE",
+        num_args = 0..=1,
+        default_missing_value = "0",
+        value_parser = parse_size
+    )]
+    warn_unclaimed: Option<u64>,
 
     /// Target architecture for the output image
     ///
@@ -91,11 +752,97 @@ pub struct BuildArgs {
 
     /// Skip special files (sockets, FIFOs, block/char devices)
     ///
-    /// By default, chunkah fails when encountering special file types.
-    /// This flag causes them to be silently skipped instead.
+    /// By default, FIFOs and block/char devices are included in the layers,
+    /// and chunkah fails when encountering sockets, which tar can't
+    /// represent. This flag causes them all to be silently skipped instead.
     #[arg(long)]
     skip_special_files: bool,
 
+    /// Don't descend into directories on other filesystems than the rootfs
+    ///
+    /// Mount points, e.g. `/proc` or bind mounts when scanning a live host,
+    /// are kept as empty directories.
+    #[arg(long)]
+    one_file_system: bool,
+
+    /// Skip the paths which can't be scanned instead of failing
+    ///
+    /// Files which can't be read, e.g. because of permissions or because
+    /// they vanished during the scan, are left out, and directories which
+    /// can't be listed are kept empty. A warning lists them at the end.
+    #[arg(long)]
+    ignore_scan_errors: bool,
+
+    /// Capture a live system, e.g. with `--rootfs /`
+    ///
+    /// Prunes the contents of /proc, /sys, /dev, /run and /tmp, and implies
+    /// --one-file-system and --skip-special-files.
+    #[arg(long)]
+    live_root: bool,
+
+    /// What to do with paths which aren't valid UTF-8
+    ///
+    /// `error` fails the build. `skip` leaves them out, along with the
+    /// contents of such directories. `lossy` keeps them, with the invalid
+    /// sequences replaced by U+FFFD in the layers. Both warn with the list of
+    /// paths.
+    #[arg(long, value_name = "POLICY", value_enum, default_value_t)]
+    non_utf8_paths: NonUtf8Paths,
+
+    /// Include the root directory in the first layer
+    ///
+    /// By default, layers have no `./` entry, since container runtimes
+    /// ignore it. With this flag, the first layer gets one with the mode,
+    /// owner and xattrs of the root of the rootfs, for consumers such as
+    /// bootable containers which read them.
+    #[arg(long)]
+    include_root: bool,
+
+    /// Increase verbosity; -v logs the steps of the build with their
+    /// duration, -vv each layer, and -vvv each claim and packing decision
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Format of the logs, progress and warnings on stderr
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        value_enum,
+        default_value_t,
+        env = "CHUNKAH_LOG_FORMAT"
+    )]
+    log_format: LogFormat,
+
+    /// Print the progress of the scan and of writing the layers to stderr
+    ///
+    /// On large rootfs, these steps can take minutes. The number of files
+    /// scanned is printed every few seconds, and each layer once written.
+    #[arg(long)]
+    progress: bool,
+
+    /// Print how long each step took and the bytes in and out of each layer
+    /// to stderr at the end, in the --log-format
+    #[arg(long)]
+    metrics: bool,
+
+    /// Write trace events to a file instead of stderr
+    ///
+    /// Implies -vvv. Unlike on stderr, the number of events isn't limited.
+    #[arg(long, value_name = "PATH")]
+    trace_out: Option<Utf8PathBuf>,
+
+    /// Only log one out of every N trace events of each kind
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    trace_sample: u64,
+
+    /// Write the state of the build to this file if it fails
+    ///
+    /// The JSON bundle holds a summary of the result of each phase reached
+    /// (scanned files, claimed components, packed layers, written layer
+    /// blobs) and the error, to attach to bug reports.
+    #[arg(long, value_name = "PATH")]
+    debug_bundle: Option<Utf8PathBuf>,
+
     /// Paths to exclude from the rootfs
     ///
     /// If a directory ends with `/`, its contents are excluded but not the
@@ -103,9 +850,232 @@ pub struct BuildArgs {
     /// absolute.
     #[arg(long = "prune", value_name = "PATH")]
     prune: Vec<Utf8PathBuf>,
+
+    /// Glob patterns of paths to exclude from the rootfs
+    ///
+    /// Patterns match paths relative to the rootfs, `*` and `?` don't match
+    /// `/` but `**` does, e.g. `**/*.pyc` or `**/__pycache__`. Excluded
+    /// directories are left out with their contents. Can be specified
+    /// multiple times.
+    #[arg(long = "exclude", value_name = "GLOB")]
+    excludes: Vec<String>,
+
+    /// Exclude paths matching the patterns of this ignore file
+    ///
+    /// The file uses the syntax of `.dockerignore` and `.containerignore`, with
+    /// patterns relative to the rootfs, e.g. to build from a working directory
+    /// with the ignore file of its build context.
+    #[arg(long, value_name = "PATH")]
+    ignore_file: Option<Utf8PathBuf>,
+
+    /// Fail if the rootfs doesn't match the paths listed in this file
+    ///
+    /// The file lists one absolute path per line. Parent directories don't
+    /// need to be listed. The build fails if a path not listed is found (after
+    /// pruning), or if a listed path is missing.
+    #[arg(long, value_name = "PATH")]
+    expected_manifest: Option<Utf8PathBuf>,
 }
 
+/// Pseudo-filesystems and runtime state of a live system, whose contents
+/// `--live-root` leaves out.
+const LIVE_ROOT_PRUNE: &[&str] = &["/proc/", "/sys/", "/dev/", "/run/", "/tmp/"];
+
 impl BuildArgs {
+    /// Returns the defaults from the selected profile, if any.
+    fn profile_defaults(&self) -> ProfileDefaults {
+        self.profile.map(Profile::defaults).unwrap_or_default()
+    }
+
+    pub(crate) fn max_layers(&self) -> usize {
+        self.max_layers
+            .unwrap_or_else(|| self.profile_defaults().max_layers)
+    }
+
+    /// Returns a copy of the options with another `--max-layers`.
+    pub(crate) fn with_max_layers(&self, max_layers: usize) -> Self {
+        Self {
+            max_layers: Some(max_layers),
+            ..self.clone()
+        }
+    }
+
+    /// Returns whether the component `name` should get a layer of its own.
+    fn is_pinned(&self, name: &str) -> bool {
+        self.pins.iter().any(|pin| pin == name)
+            || (self.kernel_layer && name.starts_with("kernel/"))
+    }
+
+    /// Returns the image creation time, which is also the mtime clamp for
+    /// files without a known build time.
+    pub(crate) fn created_epoch(&self) -> Result<u64> {
+        self.source_date_epoch
+            .map_or_else(utils::get_current_epoch, Ok)
+    }
+
+    /// Open the output file, or stdout if there's none.
+    pub(crate) fn open_output(&self) -> Result<Box<dyn std::io::Write>> {
+        match &self.output {
+            Some(path) => {
+                let file = std::fs::File::create(path)
+                    .with_context(|| format!("creating output file {path}"))?;
+                Ok(Box::new(file))
+            }
+            None => Ok(Box::new(std::io::stdout().lock())),
+        }
+    }
+
+    /// Returns whether embedded timestamps are normalized, which changes
+    /// the content of files.
+    pub(crate) fn normalizes(&self) -> bool {
+        !self.normalizers.is_empty()
+    }
+
+    fn compressed(&self) -> bool {
+        self.compressed
+            .unwrap_or_else(|| self.profile_defaults().compressed)
+    }
+
+    /// Returns the number of threads compressing each layer.
+    fn compression_threads(&self) -> Result<usize> {
+        if self.compression_threads > 0 {
+            return Ok(self.compression_threads);
+        }
+        let cpus = std::thread::available_parallelism().context("getting number of CPUs")?;
+        Ok(cpus.get())
+    }
+
+    /// Returns the number of threads scanning the rootfs.
+    fn scan_threads(&self) -> Result<usize> {
+        let threads = self.scan_threads.unwrap_or(1);
+        if threads > 0 {
+            return Ok(threads);
+        }
+        let cpus = std::thread::available_parallelism().context("getting number of CPUs")?;
+        Ok(cpus.get())
+    }
+
+    /// Returns the number of layers written in parallel.
+    fn build_threads(&self) -> Result<usize> {
+        let threads = self.build_threads.unwrap_or(1);
+        if threads > 0 {
+            return Ok(threads);
+        }
+        let cpus = std::thread::available_parallelism().context("getting number of CPUs")?;
+        Ok(cpus.get())
+    }
+
+    fn compression_level(&self) -> u32 {
+        self.compression_level.unwrap_or(6)
+    }
+
+    fn entry_order(&self) -> EntryOrder {
+        self.entry_order
+            .unwrap_or_else(|| self.profile_defaults().entry_order)
+    }
+
+    fn packing(&self) -> PackingAlgorithm {
+        self.packing
+            .unwrap_or_else(|| self.profile_defaults().packing)
+    }
+
+    fn bigfile_threshold(&self) -> Option<u64> {
+        self.bigfile_threshold
+            .or(self.profile_defaults().bigfile_threshold)
+    }
+
+    fn component_xattr(&self) -> Option<&str> {
+        self.component_xattr
+            .as_deref()
+            .or(self.profile_defaults().component_xattr)
+    }
+
+    /// Returns the prune paths from the profile and `--live-root` followed by
+    /// those from the CLI.
+    fn prune(&self) -> Vec<Utf8PathBuf> {
+        let live_root: &[&str] = if self.live_root { LIVE_ROOT_PRUNE } else { &[] };
+        self.profile_defaults()
+            .prune
+            .iter()
+            .chain(live_root)
+            .map(Utf8PathBuf::from)
+            .chain(self.prune.iter().cloned())
+            .collect()
+    }
+
+    fn skip_special_files(&self) -> bool {
+        self.skip_special_files || self.live_root
+    }
+
+    fn one_file_system(&self) -> bool {
+        self.one_file_system || self.live_root
+    }
+
+    /// Whether to read the user config.
+    fn wants_user_config(&self) -> bool {
+        !self.no_user_config
+    }
+
+    /// Fill in the options not set on the command line from the user config.
+    fn apply_user_config(&mut self, config: UserConfig) {
+        self.profile = self.profile.or(config.profile);
+        self.max_layers = self.max_layers.or(config.max_layers);
+        self.compressed = self.compressed.or(config.compressed);
+        self.compression_level = self.compression_level.or(config.compression_level);
+        self.archive_compression = self.archive_compression.or(config.archive_compression);
+        self.entry_order = self.entry_order.or(config.entry_order);
+        self.claim_cache = self.claim_cache.take().or(config.claim_cache);
+        self.workdir = self.workdir.take().or(config.workdir);
+        self.registry_auth_file = self.registry_auth_file.take().or(config.registry_auth_file);
+        self.scan_threads = self.scan_threads.or(config.jobs);
+        self.build_threads = self.build_threads.or(config.jobs);
+    }
+
+    /// Build the component repo overrides from the CLI.
+    fn repo_config(&self) -> Result<RepoConfig> {
+        let mut config = RepoConfig::new();
+        for (name, priority) in parse_repo_priorities(&self.repo_priorities)? {
+            config = config.priority(name, priority);
+        }
+        for name in &self.disabled_repos {
+            config = config.disable(name);
+        }
+        config = config
+            .claim_policy(self.claim_policy)
+            .rpm_group_by(self.rpm_group_by)
+            .rpm_config_files(self.rpm_config_files)
+            .rpm_verify(self.rpm_verify)
+            .dpkg_verify(self.dpkg_verify)
+            .content_classes(self.content_classes)
+            .kernel_layer(self.kernel_layer)
+            .bigfile_group_by(self.bigfile_group_by);
+        if let Some(name) = self.component_xattr() {
+            config = config.component_xattr(name);
+        }
+        if let Some(size) = self.bigfile_threshold() {
+            anyhow::ensure!(size > 0, "--bigfile-threshold must be greater than 0");
+            config = config.bigfile_threshold(size);
+        }
+        if let Some(dir) = &self.claim_cache {
+            config = config.claim_cache(ClaimCache::new(dir.clone()));
+        }
+        if !self.rpm_updateinfo.is_empty() {
+            let mut updateinfo = UpdateInfo::new();
+            for location in &self.rpm_updateinfo {
+                updateinfo
+                    .load(location)
+                    .with_context(|| format!("loading updateinfo {location}"))?;
+            }
+            config = config.rpm_updateinfo(updateinfo);
+        }
+        if let Some(path) = &self.stability_overrides {
+            let overrides =
+                StabilityOverrides::load(path).context("loading stability overrides")?;
+            config = config.stability_overrides(overrides);
+        }
+        Ok(config)
+    }
+
     /// Apply CLI overrides to an OCI config, returning a new config.
     fn apply_to_config(&self, config: oci_image::Config) -> Result<oci_image::Config> {
         let mut builder = oci_image::ConfigBuilder::default();
@@ -145,17 +1115,126 @@ impl BuildArgs {
 }
 
 pub fn run(args: &BuildArgs) -> Result<()> {
-    let created_epoch = args
-        .source_date_epoch
-        .map_or_else(utils::get_current_epoch, Ok)?;
+    run_with_repos(args, &[])
+}
+
+/// Like [`run`], but also load the components repos from `loaders` in
+/// addition to the built-in ones.
+pub fn run_with_repos(args: &BuildArgs, loaders: &[RepoLoader]) -> Result<()> {
+    run_prepared(args, |args, telemetry| build(args, loaders, telemetry))
+}
+
+/// Where a build records its `--debug-bundle` checkpoints and its
+/// `--metrics`.
+#[derive(Clone, Default)]
+pub(crate) struct Telemetry {
+    pub(crate) bundle: DebugBundle,
+    pub(crate) metrics: Metrics,
+}
+
+/// Run `f` with the user config applied to `args` and tracing set up for the
+/// duration of the build.
+pub(crate) fn run_prepared<F>(args: &BuildArgs, f: F) -> Result<()>
+where
+    F: FnOnce(&BuildArgs, &Telemetry) -> Result<()>,
+{
+    let mut args = args.clone();
+    let mut user_config = None;
+    if args.wants_user_config()
+        && let Some(path) = UserConfig::path()
+        && let Some(config) = UserConfig::load_from(&path).context("loading user config")?
+    {
+        args.apply_user_config(config);
+        user_config = Some(path);
+    }
+
+    let telemetry = Telemetry {
+        bundle: DebugBundle::new(args.debug_bundle.as_deref()),
+        metrics: Metrics::new(args.metrics),
+    };
+    let tracing = Tracing::new(
+        args.verbose,
+        args.log_format,
+        args.trace_out.as_deref(),
+        args.trace_sample,
+        telemetry.metrics.clone(),
+    )
+    .context("setting up tracing")?;
+    if let Some(path) = &user_config {
+        diagnostics::report(&[diagnostics::Diagnostic::info(format!(
+            "using defaults from {path}; pass --no-user-config to ignore them"
+        ))]);
+    }
+    let result = tracing.in_scope(|| f(&args, &telemetry));
+    tracing.flush()?;
+    if result.is_ok() {
+        telemetry.metrics.report()?;
+    }
+    if let Err(err) = &result
+        && let Err(bundle_err) = telemetry.bundle.write(err)
+    {
+        // the build error is what matters
+        diagnostics::report(&[diagnostics::Diagnostic::warning(format!("{bundle_err:#}"))]);
+    }
+    result
+}
 
-    // load base config from file, string, or use empty default
+/// A rootfs with its files assigned to components.
+pub(crate) struct ClaimedRootfs {
+    /// Keeps the unpacked rootfs image around, if `--rootfs` is one or
+    /// `--rootfs-tar` is given.
+    pub(crate) unpacked: Option<UnpackedImage>,
+    pub(crate) rootfs: Dir,
+    /// Tarballs applied on top of the rootfs, if any.
+    pub(crate) overlay: Option<Arc<Overlay>>,
+    /// Rewrites of the paths of the rootfs, if any.
+    pub(crate) rewrites: Option<Arc<PathRewrites>>,
+    pub(crate) components: HashMap<String, Component>,
+    /// What to record for later builds once the image is written.
+    pub(crate) records: BuildRecords,
+}
+
+/// What a build records for later builds: the `--state-dir` state and the
+/// `--stability-out` snapshot. Claiming only prepares them, so that dry runs
+/// and the commands which don't write an image leave no trace.
+#[derive(Default)]
+pub(crate) struct BuildRecords {
+    /// The build state, with the components of this build observed.
+    churn: Option<ChurnState>,
+    stability: Option<(Utf8PathBuf, StabilitySnapshot)>,
+}
+
+impl BuildRecords {
+    /// Write the records.
+    pub(crate) fn save(&self) -> Result<()> {
+        if let Some(churn) = &self.churn {
+            churn.save().context("saving build state")?;
+        }
+        if let Some((path, snapshot)) = &self.stability {
+            snapshot.write(path).context("writing stability snapshot")?;
+        }
+        Ok(())
+    }
+}
+
+fn build(args: &BuildArgs, loaders: &[RepoLoader], telemetry: &Telemetry) -> Result<()> {
+    let created_epoch = args.created_epoch()?;
+
+    let base_image = open_base_image(args)?;
+
+    // load base config from file, string, base image, or use empty default
     let parsed = if let Some(path) = &args.config {
         let content = std::fs::read_to_string(path)
             .with_context(|| format!("failed to read config file: {}", path))?;
         parse_config(&content).with_context(|| format!("failed to parse config file: {}", path))?
     } else if let Some(config_str) = &args.config_str {
         parse_config(config_str).context("failed to parse config string")?
+    } else if let Some(base) = &base_image {
+        ParsedConfig {
+            config: base.config().config().clone().unwrap_or_default(),
+            annotations: HashMap::new(),
+            architecture: Some(base.config().architecture().to_string()),
+        }
     } else {
         ParsedConfig {
             config: oci_image::Config::default(),
@@ -176,46 +1255,540 @@ pub fn run(args: &BuildArgs) -> Result<()> {
     let image_config = build_image_config(args, parsed.config, created_epoch, architecture)
         .context("building image config")?;
 
-    let rootfs = Dir::open_ambient_dir(args.rootfs.as_std_path(), ambient_authority())
-        .with_context(|| format!("opening rootfs {}", args.rootfs))?;
+    if let Some(config) = image_config.config() {
+        diagnostics::report(&diagnostics::lint_config(config, created_epoch));
+    }
+
+    // only claiming prepares records for later builds
+    let mut records = BuildRecords::default();
+    let (unpacked, rootfs, overlay, rewrites, components, components_json) = match &args.plan {
+        Some(path) => {
+            let plan = Plan::load(path).context("loading plan")?;
+            let ScannedRootfs {
+                unpacked,
+                rootfs,
+                mut files,
+                overlay,
+                rewrites,
+                overlay_components,
+                ..
+            } = scan_rootfs(args, created_epoch, &telemetry.bundle)?;
+            for (_, component) in overlay_components {
+                files.extend(component.files);
+            }
+            let components = plan
+                .layers(files)
+                .with_context(|| format!("applying plan {path}"))?;
+            let components_json = if args.embed_components {
+                let content = plan
+                    .components_json()
+                    .with_context(|| format!("{path} has no components to embed"))?;
+                Some(content.to_vec())
+            } else {
+                None
+            };
+            (
+                unpacked,
+                rootfs,
+                overlay.map(Arc::new),
+                rewrites,
+                components,
+                components_json,
+            )
+        }
+        None => {
+            let ClaimedRootfs {
+                unpacked,
+                rootfs,
+                overlay,
+                rewrites,
+                components,
+                records: claimed_records,
+            } = plan_components(args, loaders, created_epoch, &telemetry.bundle)?;
+            records = claimed_records;
+
+            // this needs to be computed before packing merges components together
+            let components_json = if args.embed_components {
+                Some(
+                    crate::components::components_json(&components)
+                        .context("serializing components")?,
+                )
+            } else {
+                None
+            };
+
+            // pack components down to max layers
+            let base_layers = base_image.as_ref().map_or(0, |base| base.layer_count());
+            let components =
+                pack_components(args, components, base_layers).context("packing components")?;
+            (
+                unpacked,
+                rootfs,
+                overlay,
+                rewrites,
+                components,
+                components_json,
+            )
+        }
+    };
+    telemetry.bundle.checkpoint("pack", || {
+        debug_bundle::components_summary(components.iter().map(|(name, c)| (name, c)))
+    });
+
+    if let Some(path) = &args.plan_out
+        && !args.dry_run
+    {
+        Plan::new(&components, components_json.as_deref())?
+            .write(path)
+            .context("writing plan")?;
+    }
+
+    // the builder still checks the layer options in a dry run
+    let dry_run_layers = args
+        .dry_run
+        .then(|| crate::cmd_analyze::format_layers(&components));
+    let rootfs_metadata = unpacked.as_ref().and_then(UnpackedImage::metadata).cloned();
+    let mut builder = new_builder(
+        args,
+        &rootfs,
+        overlay,
+        rewrites,
+        rootfs_metadata,
+        components,
+    )?
+    .annotations(annotations)
+    .config(image_config)
+    .metrics(telemetry.metrics.clone())
+    .debug_bundle(telemetry.bundle.clone());
+    if let Some(content) = components_json {
+        builder = builder.components_json(content, created_epoch);
+    }
+    if let Some(layers) = dry_run_layers {
+        print!("{layers}");
+        return Ok(());
+    }
 
-    let files = crate::scan::Scanner::new(&rootfs)
-        .skip_special_files(args.skip_special_files)
-        .prune(&args.prune)?
+    if let Some(path) = &args.also_emit_rootfs_tar {
+        let mut file = std::fs::File::create(path)
+            .with_context(|| format!("creating rootfs tarball {path}"))?;
+        builder
+            .write_rootfs_tar(&mut file)
+            .with_context(|| format!("writing rootfs tarball {path}"))?;
+    }
+
+    let _span = tracing::info_span!("write").entered();
+    let image_config = match &base_image {
+        Some(base) => builder.build_on(base, &mut args.open_output()?)?,
+        None => builder.build(&mut args.open_output()?)?,
+    };
+    if let Some(path) = &args.prev_image {
+        let previous =
+            BaseImage::open(path).with_context(|| format!("opening previous image {path}"))?;
+        diagnostics::report(&diagnostics::lint_rebuild(previous.config(), &image_config));
+    }
+    records.save()
+}
+
+/// Keep only the components whose name matches one of `patterns`.
+fn select_components(
+    components: HashMap<String, Component>,
+    patterns: &[String],
+) -> Result<HashMap<String, Component>> {
+    let patterns = patterns
+        .iter()
+        .map(|p| glob::Pattern::new(p).with_context(|| format!("invalid pattern {p}")))
+        .collect::<Result<Vec<_>>>()?;
+    let selected: HashMap<_, _> = components
+        .into_iter()
+        .filter(|(name, _)| {
+            patterns
+                .iter()
+                .any(|p| p.matches_with(name, utils::GLOB_MATCH_OPTIONS))
+        })
+        .collect();
+    anyhow::ensure!(
+        !selected.is_empty(),
+        "no component matches --only-components"
+    );
+    Ok(selected)
+}
+
+/// A scanned rootfs, with the tarballs applied on top of it.
+pub(crate) struct ScannedRootfs {
+    /// Keeps the unpacked rootfs image around, if `--rootfs` is one or
+    /// `--rootfs-tar` is given.
+    pub(crate) unpacked: Option<UnpackedImage>,
+    pub(crate) rootfs: Dir,
+    pub(crate) rootfs_path: Utf8PathBuf,
+    /// The files of the rootfs, except those from tarballs.
+    pub(crate) files: FileMap,
+    pub(crate) overlay: Option<Overlay>,
+    /// Rewrites of the paths of `files`, if any.
+    pub(crate) rewrites: Option<Arc<PathRewrites>>,
+    /// The components of the files from tarballs.
+    pub(crate) overlay_components: Vec<(String, Component)>,
+}
+
+/// Open the rootfs, scan it and apply the tarballs on top of it.
+pub(crate) fn scan_rootfs(
+    args: &BuildArgs,
+    created_epoch: u64,
+    bundle: &DebugBundle,
+) -> Result<ScannedRootfs> {
+    let _span = tracing::info_span!("scan").entered();
+    // keep the unpacked image around until the build is done
+    let (source, unpacked) = match (&args.rootfs, &args.rootfs_tar) {
+        (_, Some(tar)) => {
+            let unpacked = crate::rootfs_image::unpack_tar(tar, args.workdir.as_deref())
+                .with_context(|| format!("extracting {tar}"))?;
+            (tar, Some(unpacked))
+        }
+        (Some(path), None) => {
+            let unpacked = unpack_rootfs_image(path, args.workdir.as_deref())?;
+            (path, unpacked)
+        }
+        (None, None) => anyhow::bail!("--rootfs or --rootfs-tar is required"),
+    };
+    let rootfs_path = unpacked
+        .as_ref()
+        .map_or(source.as_path(), |unpacked| unpacked.path())
+        .to_owned();
+    let rootfs = Dir::open_ambient_dir(rootfs_path.as_std_path(), ambient_authority())
+        .with_context(|| format!("opening rootfs {source}"))?;
+
+    let ignore = args
+        .ignore_file
+        .as_deref()
+        .map(IgnoreRules::load)
+        .transpose()
+        .context("loading ignore file")?;
+    let mut scanner = crate::scan::Scanner::new(&rootfs)
+        .skip_special_files(args.skip_special_files())
+        .one_file_system(args.one_file_system())?
+        .ignore_errors(args.ignore_scan_errors)
+        .non_utf8_paths(args.non_utf8_paths)
+        .hash_contents(args.hash_contents)
+        .threads(args.scan_threads()?)
+        .progress(args.progress)
+        .prune(&args.prune())?
+        .exclude(&args.excludes)?;
+    if let Some(ignore) = &ignore {
+        scanner = scanner.ignore(ignore);
+    }
+    let mut files = scanner
         .scan()
-        .with_context(|| format!("scanning {} for files", args.rootfs))?;
+        .with_context(|| format!("scanning {source} for files"))?;
+    if let Some(unpacked) = &unpacked {
+        unpacked
+            .restore_metadata(&mut files, args.skip_special_files())
+            .with_context(|| format!("reading metadata from {source}"))?;
+    }
+    bundle.checkpoint("scan", || debug_bundle::files_summary(&files));
+
+    let rewrites = if args.rewrites.is_empty() {
+        None
+    } else {
+        let mut rewrites = PathRewrites::new(args.rewrites.clone());
+        rewrites.apply(&mut files).context("rewriting paths")?;
+        Some(Arc::new(rewrites))
+    };
 
-    let repos =
-        ComponentsRepos::load(&rootfs, &files, created_epoch).context("loading components")?;
+    // files from tarballs don't go through the repos, which would look for
+    // them in the rootfs
+    let overlay = if args.apply_tars.is_empty() && args.adds.is_empty() {
+        None
+    } else {
+        let mut overlay = Overlay::load(&args.apply_tars).context("loading tarballs")?;
+        if !args.adds.is_empty() {
+            overlay
+                .add_files(&args.adds, created_epoch)
+                .context("adding files")?;
+        }
+        overlay
+            .apply(&mut files)
+            .context("applying tarballs to rootfs")?;
+        Some(overlay)
+    };
+    let overlay_components = overlay
+        .as_ref()
+        .map(|overlay| overlay.components(created_epoch))
+        .unwrap_or_default();
+
+    if let Some(path) = &args.expected_manifest {
+        let all_paths: BTreeSet<&Utf8Path> = files
+            .keys()
+            .chain(overlay_components.iter().flat_map(|(_, c)| c.files.keys()))
+            .map(Utf8PathBuf::as_path)
+            .collect();
+        ExpectedPaths::load(path)
+            .context("loading expected manifest")?
+            .check(&all_paths)
+            .context("checking expected manifest")?;
+    }
+
+    Ok(ScannedRootfs {
+        unpacked,
+        rootfs,
+        rootfs_path,
+        files,
+        overlay,
+        rewrites,
+        overlay_components,
+    })
+}
+
+/// Open the rootfs, scan it and assign its files to components.
+pub(crate) fn claim_rootfs(
+    args: &BuildArgs,
+    loaders: &[RepoLoader],
+    created_epoch: u64,
+    bundle: &DebugBundle,
+) -> Result<ClaimedRootfs> {
+    let ScannedRootfs {
+        unpacked,
+        rootfs,
+        rootfs_path,
+        files,
+        overlay,
+        rewrites,
+        overlay_components,
+    } = scan_rootfs(args, created_epoch, bundle)?;
+    let _span = tracing::info_span!("claim").entered();
+
+    let mut repos =
+        ComponentsRepos::load_with_config(&rootfs, &files, created_epoch, args.repo_config()?)
+            .context("loading components")?;
+    if let Some(path) = &args.original_image {
+        repos
+            .load_original_layers(path)
+            .with_context(|| format!("loading original layers from {path}"))?;
+    }
+    for exec in &args.claimer_execs {
+        repos
+            .load_external_claimer(
+                exec,
+                &rootfs_path,
+                &files,
+                args.registry_auth_file.as_deref(),
+            )
+            .with_context(|| format!("running claimer {exec}"))?;
+    }
+    if let Some(path) = &args.claims_from {
+        repos
+            .load_previous_claims(path)
+            .with_context(|| format!("loading previous claims from {path}"))?;
+    }
+    if let Some(path) = &args.components_manifest {
+        repos
+            .load_components_manifest(path, &files)
+            .with_context(|| format!("loading components manifest {path}"))?;
+    }
+    for loader in loaders {
+        if let Some(repo) =
+            loader(&rootfs, &files, created_epoch).context("loading registered repo")?
+        {
+            repos.register(repo);
+        }
+    }
+    let unknown: Vec<_> = repos
+        .unknown_config_names()
+        .into_iter()
+        .map(|name| diagnostics::Diagnostic::warning(format!("unknown component repo {name}")))
+        .collect();
+    diagnostics::report(&unknown);
     if repos.is_empty() {
         anyhow::bail!("no supported component repo found in rootfs");
     }
 
-    let components = repos.into_components(files);
+    let mut components = repos
+        .into_components(files)
+        .context("assigning files to components")?;
+    components.extend(overlay_components);
+
+    let mut records = BuildRecords::default();
+    if let Some(dir) = &args.state_dir {
+        let now = utils::get_current_epoch()?;
+        let mut state = ChurnState::load(dir).context("loading build state")?;
+        state.blend(&mut components, now);
+        state
+            .observe(&components, now)
+            .context("recording components in build state")?;
+        records.churn = Some(state);
+    }
+    if let Some(path) = &args.stability_in {
+        let snapshot = StabilitySnapshot::load(path).context("loading stability snapshot")?;
+        let missing = snapshot.replay(&mut components);
+        if !missing.is_empty() {
+            diagnostics::report(&[diagnostics::Diagnostic::warning(format!(
+                "{} component(s) not in {path} keep their computed stability: {}",
+                missing.len(),
+                missing.join(", ")
+            ))]);
+        }
+    }
+    if let Some(path) = &args.stability_out {
+        records.stability = Some((path.clone(), StabilitySnapshot::new(&components)));
+    }
+    bundle.checkpoint("claim", || debug_bundle::components_summary(&components));
+
+    Ok(ClaimedRootfs {
+        unpacked,
+        rootfs,
+        overlay: overlay.map(Arc::new),
+        rewrites,
+        components,
+        records,
+    })
+}
+
+/// Claim the rootfs and select the components to build, checking the
+/// unclaimed files, like `build` does. Nothing is written, so that e.g.
+/// `analyze` plans the same layers without side effects.
+pub(crate) fn plan_components(
+    args: &BuildArgs,
+    loaders: &[RepoLoader],
+    created_epoch: u64,
+    bundle: &DebugBundle,
+) -> Result<ClaimedRootfs> {
+    let mut claimed = claim_rootfs(args, loaders, created_epoch, bundle)?;
+    if let Some(max_percent) = args.max_unclaimed_percent {
+        check_unclaimed(
+            &claimed.components,
+            max_percent,
+            args.warn_unclaimed.is_some(),
+        )?;
+    }
+    if args.fail_on_unclaimed || args.warn_unclaimed.is_some() {
+        report_unclaimed(
+            &claimed.components,
+            args.fail_on_unclaimed,
+            args.warn_unclaimed,
+        )?;
+    }
+    if !args.only_components.is_empty() {
+        claimed.components = select_components(claimed.components, &args.only_components)?;
+    }
+    Ok(claimed)
+}
 
-    // pack components down to max layers
-    let components = pack_components(args, components).context("packing components")?;
+/// Open the `--base` image, if any.
+pub(crate) fn open_base_image(args: &BuildArgs) -> Result<Option<BaseImage>> {
+    args.base_image
+        .as_deref()
+        .map(|path| BaseImage::open(path).with_context(|| format!("opening base image {path}")))
+        .transpose()
+}
 
-    // build the OCI image
-    let compression = if args.compressed {
-        Compression::Gzip(args.compression_level)
+/// Create an image builder for `components` with the layer and archive
+/// options from the CLI.
+pub(crate) fn new_builder(
+    args: &BuildArgs,
+    rootfs: &Dir,
+    overlay: Option<Arc<Overlay>>,
+    rewrites: Option<Arc<PathRewrites>>,
+    rootfs_metadata: Option<Arc<FileMap>>,
+    mut components: Vec<(String, Component)>,
+) -> Result<Builder> {
+    let compression = if args.compressed() {
+        Compression::Gzip(args.compression_level())
     } else {
         Compression::None
     };
+    diagnostics::report(&diagnostics::lint_layer_media_type(
+        args.layer_media_type,
+        compression,
+    ));
+
+    if args.include_root
+        && let Some((_, first)) = components.iter_mut().find(|(_, c)| !c.files.is_empty())
+    {
+        let root = match rootfs_metadata
+            .as_ref()
+            .and_then(|m| m.get(Utf8Path::new("/")))
+        {
+            Some(root) => root.clone(),
+            None => crate::scan::root_info(rootfs).context("reading root directory")?,
+        };
+        first.files.insert(Utf8PathBuf::from("/"), root);
+    }
 
-    let builder = Builder::new(&rootfs, components)
+    let builder = match &args.workdir {
+        Some(dir) => {
+            let workdir = Dir::open_ambient_dir(dir, ambient_authority())
+                .with_context(|| format!("opening workdir {dir}"))?;
+            Builder::new_in(rootfs, components, &workdir)
+        }
+        None => Builder::new(rootfs, components),
+    };
+    let mut builder = builder
         .context("creating builder")?
         .compression(compression)
-        .annotations(annotations)
-        .config(image_config);
+        .layer_media_type(args.layer_media_type)
+        .entry_order(args.entry_order())
+        .tar_format(args.tar_format)
+        .id_maps(
+            IdMap::new(args.uid_maps.clone()),
+            IdMap::new(args.gid_maps.clone()),
+        )
+        .transforms(args.chown.clone(), args.chmod.clone())
+        .normalizers(args.normalizers.clone())
+        .validate(args.validate)
+        .inputs_digests(args.inputs_digests)
+        .package_annotations(args.package_annotations)
+        .adaptive_compression(args.adaptive_compression)
+        .compression_threads(args.compression_threads()?)
+        .build_threads(args.build_threads()?)
+        .read_ahead(args.read_ahead)
+        .progress(args.progress);
+    if args.self_test || args.self_test_system_tar {
+        builder = builder.self_test(SelfTest {
+            extractors: Extractor::available(args.self_test_system_tar),
+            workdir: args.workdir.clone(),
+        });
+    }
+    if args.owner_names {
+        let names = OwnerNames::load(rootfs, overlay.as_deref()).context("loading owner names")?;
+        builder = builder.owner_names(names);
+    }
+    if let Some(overlay) = overlay {
+        builder = builder.overlay(overlay);
+    }
+    if let Some(rewrites) = rewrites {
+        builder = builder.rewrites(rewrites);
+    }
+    if let Some(metadata) = rootfs_metadata {
+        builder = builder.rootfs_metadata(metadata);
+    }
+    if let Some(path) = &args.components_manifest {
+        let rules = RetentionRules::load(path).context("loading retention rules")?;
+        builder = builder.retention(rules);
+    }
+    if let Some(format) = args.archive_compression {
+        builder = builder.archive_compression(format.compression(args.compression_level()));
+    }
+    Ok(builder)
+}
 
-    if let Some(output_path) = &args.output {
-        let mut file = std::fs::File::create(output_path)
-            .with_context(|| format!("creating output file {}", output_path))?;
-        builder.build(&mut file)
-    } else {
-        builder.build(&mut std::io::stdout().lock())
+/// Extract the rootfs at `path` in `workdir` if it's a squashfs or erofs
+/// image, or return `None` if it's a directory.
+fn unpack_rootfs_image(
+    path: &Utf8Path,
+    workdir: Option<&Utf8Path>,
+) -> Result<Option<UnpackedImage>> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    if let Some(format) =
+        ImageFormat::detect(path).with_context(|| format!("detecting format of {path}"))?
+    {
+        let unpacked = crate::rootfs_image::unpack_image(path, format, workdir)
+            .with_context(|| format!("extracting {format} image {path}"))?;
+        return Ok(Some(unpacked));
+    }
+    if crate::rootfs_image::is_tarball(path)? {
+        anyhow::bail!("{path} is a tarball; pass it with --rootfs-tar");
     }
+    anyhow::bail!("{path} is not a directory");
 }
 
 /// Parse config from a JSON string.
@@ -224,7 +1797,7 @@ pub fn run(args: &BuildArgs) -> Result<()> {
 /// 1. Direct OCI config (e.g., `{"Entrypoint": [...]}`)
 /// 2. podman/docker inspect output array (e.g., `[{"Config": {...}}]`)
 /// 3. Single inspect output object (e.g., `{"Config": {...}}`)
-fn parse_config(json_str: &str) -> Result<ParsedConfig> {
+pub(crate) fn parse_config(json_str: &str) -> Result<ParsedConfig> {
     let input: ConfigInput =
         serde_json::from_str(json_str).context("failed to parse config JSON")?;
     match input {
@@ -243,7 +1816,7 @@ fn parse_config(json_str: &str) -> Result<ParsedConfig> {
 /// Parsed config data from either OCI config or podman/docker inspect format.
 /// The serde renames allow this to deserialize from inspect format (with "Config" key).
 #[derive(Deserialize)]
-struct ParsedConfig {
+pub(crate) struct ParsedConfig {
     #[serde(rename = "Config")]
     config: oci_image::Config,
     #[serde(rename = "Annotations", default)]
@@ -300,6 +1873,23 @@ fn build_image_config(
     Ok(image_config)
 }
 
+/// Parse NAME=N repo priority overrides.
+fn parse_repo_priorities(pairs: &[String]) -> Result<Vec<(String, usize)>> {
+    pairs
+        .iter()
+        .map(|pair| {
+            let (name, priority) = pair
+                .split_once('=')
+                .with_context(|| format!("repo priority must be in NAME=N format: {pair}"))?;
+            anyhow::ensure!(!name.is_empty(), "repo name cannot be empty: {pair}");
+            let priority = priority
+                .parse()
+                .with_context(|| format!("parsing repo priority: {pair}"))?;
+            Ok((name.to_string(), priority))
+        })
+        .collect()
+}
+
 /// Parse KEY=VALUE pairs and merge into an existing map.
 ///
 /// Supports three formats:
@@ -321,39 +1911,474 @@ fn parse_key_value_pairs(
                 map.remove(k);
             }
         } else {
-            anyhow::bail!("label must be in KEY=VALUE or KEY- format: {pair}");
+            anyhow::bail!("label must be in KEY=VALUE or KEY- format: {pair}");
+        }
+    }
+    Ok(map)
+}
+
+/// Check that no more than `max_percent` of bytes are unclaimed.
+///
+/// If `warn_only` is set, a warning is printed instead of failing.
+fn check_unclaimed(
+    components: &HashMap<String, Component>,
+    max_percent: u8,
+    warn_only: bool,
+) -> Result<()> {
+    let bytes = |component: &Component| -> u64 {
+        component
+            .files
+            .values()
+            .filter(|f| f.file_type == FileType::File)
+            .map(|f| f.size)
+            .sum()
+    };
+    let total: u64 = components.values().map(bytes).sum();
+    let unclaimed = components.get(UNCLAIMED_COMPONENT).map_or(0, bytes);
+    if total == 0 || unclaimed * 100 <= total * u64::from(max_percent) {
+        return Ok(());
+    }
+
+    let message = format!(
+        "{:.1}% of bytes ({unclaimed} of {total}) are unclaimed, more than the {max_percent}% allowed; \
+         is the package database missing from the rootfs?",
+        unclaimed as f64 * 100.0 / total as f64
+    );
+    if warn_only {
+        diagnostics::report(&[diagnostics::Diagnostic::warning(message)]);
+        Ok(())
+    } else {
+        anyhow::bail!(message)
+    }
+}
+
+/// Report the files that ended up unclaimed, grouped by directory.
+///
+/// Fails if `fail` is set and there are any, or warns if they add up to at
+/// least `warn_size` bytes.
+fn report_unclaimed(
+    components: &HashMap<String, Component>,
+    fail: bool,
+    warn_size: Option<u64>,
+) -> Result<()> {
+    let Some(unclaimed) = components.get(UNCLAIMED_COMPONENT) else {
+        return Ok(());
+    };
+
+    // group by the first two levels of directories, which is usually enough
+    // to tell where the files come from
+    let mut dirs: BTreeMap<Utf8PathBuf, (usize, u64)> = BTreeMap::new();
+    for (path, info) in &unclaimed.files {
+        if info.file_type == FileType::Directory {
+            continue;
+        }
+        let dir = path.parent().unwrap_or(path).components().take(3).collect();
+        let (count, bytes) = dirs.entry(dir).or_default();
+        *count += 1;
+        if info.file_type == FileType::File {
+            *bytes += info.size;
+        }
+    }
+    let count: usize = dirs.values().map(|(count, _)| count).sum();
+    let bytes: u64 = dirs.values().map(|(_, bytes)| bytes).sum();
+    if count == 0 || (!fail && warn_size.is_some_and(|size| bytes < size)) {
+        return Ok(());
+    }
+
+    let mut dirs: Vec<_> = dirs.into_iter().collect();
+    // stable sort, so equal sizes stay in path order
+    dirs.sort_by_key(|(_, (_, bytes))| std::cmp::Reverse(*bytes));
+    let mut message =
+        format!("{count} file(s) ({bytes} bytes) are unclaimed and in {UNCLAIMED_COMPONENT}:");
+    for (dir, (count, bytes)) in dirs.iter().take(MAX_REPORTED_UNCLAIMED_DIRS) {
+        message.push_str(&format!("\n  {dir}: {count} file(s), {bytes} bytes"));
+    }
+    if dirs.len() > MAX_REPORTED_UNCLAIMED_DIRS {
+        message.push_str(&format!(
+            "\n  ... and {} more",
+            dirs.len() - MAX_REPORTED_UNCLAIMED_DIRS
+        ));
+    }
+    if fail {
+        anyhow::bail!(message)
+    }
+    diagnostics::report(&[diagnostics::Diagnostic::warning(message)]);
+    Ok(())
+}
+
+/// Validate the name given to --component-xattr.
+fn parse_component_xattr(name: &str) -> Result<String> {
+    let attr = name
+        .strip_prefix("user.")
+        .or_else(|| name.strip_prefix("trusted."))
+        .with_context(|| format!("{name} is not in the user or trusted xattr namespace"))?;
+    anyhow::ensure!(!attr.is_empty(), "missing xattr name after {name}");
+    Ok(name.to_string())
+}
+
+/// Parse a size in bytes, with an optional K, M or G suffix (powers of 1024).
+fn parse_size(size: &str) -> Result<u64> {
+    let (digits, multiplier) = match size.strip_suffix(['K', 'M', 'G']) {
+        Some(digits) => {
+            let shift = match &size[digits.len()..] {
+                "K" => 10,
+                "M" => 20,
+                _ => 30,
+            };
+            (digits, 1u64 << shift)
+        }
+        None => (size, 1),
+    };
+    let n: u64 = digits
+        .parse()
+        .with_context(|| format!("invalid size: {size}"))?;
+    n.checked_mul(multiplier)
+        .with_context(|| format!("size too large: {size}"))
+}
+
+/// Returns the number of layers available for components.
+///
+/// This is --max-layers minus the reserved layers and the metadata layer, if
+/// enabled.
+fn layer_budget(args: &BuildArgs, base_layers: usize) -> Result<usize> {
+    let reserved = args.reserve_layers + usize::from(args.embed_components) + base_layers;
+    anyhow::ensure!(
+        reserved < args.max_layers(),
+        "--max-layers {} leaves no room for components after reserving {reserved} layer(s)",
+        args.max_layers()
+    );
+    Ok(args.max_layers() - reserved)
+}
+
+/// Describe why the pinned components don't fit in the layer budget.
+fn infeasible_pins_report(
+    args: &BuildArgs,
+    names: &[String],
+    items: &[PackItem],
+    max_layers: usize,
+) -> String {
+    let pinned: Vec<&str> = names
+        .iter()
+        .zip(items)
+        .filter(|(_, item)| item.pinned)
+        .map(|(name, _)| name.as_str())
+        .collect();
+    let unpinned = items.len() - pinned.len();
+
+    let mut report = format!(
+        "{} pinned component(s) don't fit in {max_layers} layer(s)",
+        pinned.len()
+    );
+    if unpinned > 0 {
+        report.push_str(&format!(
+            " with 1 layer needed for the {unpinned} unpinned component(s)"
+        ));
+    }
+    report.push_str(&format!(
+        "\n  --max-layers: {}\n  --reserve-layers: {}",
+        args.max_layers(),
+        args.reserve_layers
+    ));
+    if args.embed_components {
+        report.push_str("\n  --embed-components: 1");
+    }
+    report.push_str(&format!("\n  pinned: {}", pinned.join(", ")));
+    report
+}
+
+/// Returns the index of the layer each component was in in the image at
+/// `path`.
+fn previous_layers(path: &Utf8Path) -> Result<HashMap<String, usize>> {
+    let image = BaseImage::open(path)?;
+    let mut layers = HashMap::new();
+    for (idx, name) in image.layer_names().into_iter().enumerate() {
+        for component in name.into_iter().flat_map(|name| name.split(' ')) {
+            layers.insert(component.to_string(), idx);
+        }
+    }
+    Ok(layers)
+}
+
+/// Moves the groups of the units with a layer index to that index, keeping
+/// the order of the other groups.
+fn place_layers(groups: Vec<PackGroup>, indices: &[Option<usize>]) -> Result<Vec<PackGroup>> {
+    let group_index = |group: &PackGroup| group.indices.iter().find_map(|&u| indices[u]);
+    let mut placed: Vec<(usize, PackGroup)> = Vec::new();
+    let mut rest = Vec::with_capacity(groups.len());
+    for group in groups {
+        match group_index(&group) {
+            Some(index) => placed.push((index, group)),
+            None => rest.push(group),
+        }
+    }
+    placed.sort_by_key(|(index, _)| *index);
+
+    let total = placed.len() + rest.len();
+    for (index, group) in placed {
+        anyhow::ensure!(
+            index < total,
+            "layer index {index} is out of range with {total} component layer(s)"
+        );
+        rest.insert(index, group);
+    }
+    Ok(rest)
+}
+
+/// Splits a component larger than `max_size` into parts of at most `max_size`,
+/// keeping directory trees together where possible. The parts are named
+/// `<name>#<n>`; a file larger than `max_size` gets a part of its own.
+fn split_component(name: String, component: Component, max_size: u64) -> Vec<(String, Component)> {
+    let ranges = {
+        let sizes: Vec<(&Utf8Path, u64)> = component
+            .files
+            .iter()
+            .map(|(path, info)| (path.as_path(), info.size))
+            .collect();
+        split_subtrees(&sizes, 0, max_size)
+    };
+    if ranges.len() <= 1 {
+        return vec![(name, component)];
+    }
+
+    tracing::trace!(
+        target: trace::PACK,
+        "{name}: split into {} parts of at most {max_size} bytes",
+        ranges.len()
+    );
+    // the files are in path order, like the ranges
+    let mut files = component.files.into_iter();
+    ranges
+        .iter()
+        .enumerate()
+        .map(|(i, range)| {
+            let part = Component {
+                mtime_clamp: component.mtime_clamp,
+                stability: component.stability,
+                files: files.by_ref().take(range.len()).collect(),
+                packages: component.packages.clone(),
+            };
+            (format!("{name}#{}", i + 1), part)
+        })
+        .collect()
+}
+
+/// Splits `sizes`, paths in order with their size which share their first
+/// `depth` path components, into ranges of at most `max_size` bytes, along
+/// the subtrees at `depth`.
+fn split_subtrees(
+    sizes: &[(&Utf8Path, u64)],
+    depth: usize,
+    max_size: u64,
+) -> Vec<std::ops::Range<usize>> {
+    let total = |range: &std::ops::Range<usize>| -> u64 {
+        sizes[range.clone()].iter().map(|(_, size)| size).sum()
+    };
+    let whole = 0..sizes.len();
+    if sizes.len() <= 1 || total(&whole) <= max_size {
+        return vec![whole];
+    }
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < sizes.len() {
+        // paths sort by component, so each subtree is contiguous
+        let key = sizes[start].0.components().nth(depth);
+        let mut end = start + 1;
+        while end < sizes.len() && sizes[end].0.components().nth(depth) == key {
+            end += 1;
+        }
+        for range in split_subtrees(&sizes[start..end], depth + 1, max_size) {
+            chunks.push(range.start + start..range.end + start);
+        }
+        start = end;
+    }
+
+    // put neighboring subtrees back together as long as they fit
+    let mut ranges: Vec<std::ops::Range<usize>> = Vec::new();
+    for chunk in chunks {
+        if let Some(last) = ranges.last_mut()
+            && total(last) + total(&chunk) <= max_size
+        {
+            last.end = chunk.end;
+        } else {
+            ranges.push(chunk);
         }
     }
-    Ok(map)
+    ranges
 }
 
-/// Packs components into layers according to max_layers constraint.
-fn pack_components(
+/// Packs components into layers according to max_layers constraint, on top of
+/// `base_layers` layers of a base image.
+pub(crate) fn pack_components(
     args: &BuildArgs,
     components: HashMap<String, Component>,
+    base_layers: usize,
 ) -> Result<Vec<(String, Component)>> {
-    let max_layers = args.max_layers;
+    let _span = tracing::info_span!("pack").entered();
+    let max_layers = layer_budget(args, base_layers)?;
+    anyhow::ensure!(
+        args.max_layer_size != Some(0),
+        "--max-layer-size must be greater than 0"
+    );
+
+    for pin in &args.pins {
+        if !components.contains_key(pin) {
+            diagnostics::report(&[diagnostics::Diagnostic::warning(format!(
+                "pinned component {pin} not found"
+            ))]);
+        }
+    }
 
-    let mut entries: Vec<Option<(String, Component)>> = components.into_iter().map(Some).collect();
+    let mut sorted: Vec<(String, Component)> = components.into_iter().collect();
     // sort by component name for deterministic inputs to the packing algorithm
-    entries.sort_by(|a, b| a.as_ref().unwrap().0.cmp(&b.as_ref().unwrap().0));
+    sorted.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut entries: Vec<Option<(String, Component)>> = Vec::with_capacity(sorted.len());
+    // the name of the component each entry comes from, for the constraints
+    let mut origins: Vec<String> = Vec::with_capacity(sorted.len());
+    for (name, comp) in sorted {
+        let parts = match args.max_layer_size {
+            Some(max_size) => split_component(name.clone(), comp, max_size),
+            None => vec![(name.clone(), comp)],
+        };
+        for part in parts {
+            entries.push(Some(part));
+            origins.push(name.clone());
+        }
+    }
 
-    let items: Vec<PackItem> = entries
-        .iter()
-        .map(|entry| {
-            let (_, comp) = entry.as_ref().unwrap();
-            PackItem {
-                size: comp.files.values().map(|f| f.size).sum(),
-                stability: comp.stability,
+    let constraints = match &args.pack_constraints {
+        Some(path) => LayerConstraints::load(path)?,
+        None => LayerConstraints::default(),
+    };
+    let origins: Vec<&str> = origins.iter().map(String::as_str).collect();
+    for name in constraints.missing(&origins) {
+        diagnostics::report(&[diagnostics::Diagnostic::warning(format!(
+            "constrained component {name} not found"
+        ))]);
+    }
+    let resolved = constraints.resolve(&origins);
+
+    // the components of a unit are packed as one, pinned if any of them is
+    let mut unit_names = Vec::with_capacity(resolved.units.len());
+    let mut items: Vec<PackItem> = Vec::with_capacity(resolved.units.len());
+    for (unit, index) in resolved.units.iter().zip(&resolved.indices) {
+        let unit_entries = || unit.iter().map(|&idx| entries[idx].as_ref().unwrap());
+        unit_names.push(
+            unit_entries()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+                .join(" "),
+        );
+        items.push(PackItem {
+            size: unit_entries()
+                .flat_map(|(_, comp)| comp.files.values())
+                .map(|f| f.size)
+                .sum(),
+            stability: unit_entries().map(|(_, comp)| comp.stability).product(),
+            pinned: index.is_some() || unit.iter().any(|&idx| args.is_pinned(origins[idx])),
+        });
+    }
+
+    if !pins_fit(&items, max_layers) {
+        let report = infeasible_pins_report(args, &unit_names, &items, max_layers);
+        anyhow::ensure!(
+            args.relax_pins,
+            "{report}\nuse --relax-pins to unpin some of them"
+        );
+        for idx in relax_pins(&mut items, max_layers) {
+            let name = &unit_names[idx];
+            diagnostics::report(&[diagnostics::Diagnostic::warning(format!(
+                "unpinning component {name} to fit in {max_layers} layer(s)"
+            ))]);
+        }
+    }
+
+    let previous = match &args.prev_image {
+        Some(path) => {
+            let layers = previous_layers(path)
+                .with_context(|| format!("reading previous packing from {path}"))?;
+            resolved
+                .units
+                .iter()
+                .map(|unit| {
+                    let mut layer_idx = unit.iter().map(|&idx| {
+                        let (name, _) = entries[idx].as_ref().unwrap();
+                        layers.get(name).copied()
+                    });
+                    let first = layer_idx.next().flatten();
+                    layer_idx.all(|idx| idx == first).then_some(first).flatten()
+                })
+                .collect()
+        }
+        None => Vec::new(),
+    };
+
+    let mut apart = resolved.apart.clone();
+    // so that relaxed pins with a layer index don't end up in the same layer
+    apart.push(
+        (0..items.len())
+            .filter(|&u| resolved.indices[u].is_some())
+            .collect(),
+    );
+    let pack_options = PackOptions {
+        overhead: args.layer_overhead_bytes,
+        previous,
+        max_size: args.max_layer_size,
+        apart,
+    };
+    let packed_groups = match args.packing() {
+        PackingAlgorithm::Greedy => calculate_packing(&items, max_layers, &pack_options),
+        PackingAlgorithm::Exact => {
+            let (groups, optimal) = calculate_packing_exact(&items, max_layers, &pack_options);
+            if !optimal {
+                diagnostics::report(&[diagnostics::Diagnostic::warning(
+                    "the exact packing search was cut short; the packing may not be optimal",
+                )]);
             }
+            groups
+        }
+    };
+    if packed_groups.len() > max_layers {
+        let mut report = format!("components don't fit in {max_layers} layer(s)");
+        if let Some(max_size) = args.max_layer_size {
+            report.push_str(&format!("\n  --max-layer-size: {max_size}"));
+        }
+        if let Some(path) = &args.pack_constraints {
+            report.push_str(&format!("\n  --pack-constraints: {path}"));
+        }
+        anyhow::bail!("{report}\nraise --max-layers or relax the constraints");
+    }
+    let packed_groups = place_layers(packed_groups, &resolved.indices)?;
+    // from units back to entries
+    let packed_groups: Vec<PackGroup> = packed_groups
+        .into_iter()
+        .map(|group| PackGroup {
+            indices: group
+                .indices
+                .iter()
+                .flat_map(|&u| resolved.units[u].iter().copied())
+                .collect(),
+            ..group
         })
         .collect();
 
-    let packed_groups = calculate_packing(&items, max_layers);
-
     let mut result = Vec::with_capacity(packed_groups.len());
 
-    for group in packed_groups {
+    for (layer_idx, group) in packed_groups.into_iter().enumerate() {
+        for &idx in &group.indices {
+            let (name, comp) = entries[idx]
+                .as_ref()
+                .expect("packing returned invalid index");
+            tracing::trace!(
+                target: trace::PACK,
+                "{name} (stability {:.3}) -> layer {layer_idx} with {} component(s), stability {:.3}",
+                comp.stability,
+                group.indices.len(),
+                group.stability
+            );
+        }
+
         if group.indices.len() == 1 {
             // single component group
             let idx = group.indices[0];
@@ -363,6 +2388,7 @@ fn pack_components(
             // merged group - combine components
             let mut names = Vec::with_capacity(group.indices.len());
             let mut merged_files = FileMap::new();
+            let mut merged_packages = BTreeSet::new();
             let mut max_mtime_clamp = 0u64;
 
             for &idx in &group.indices {
@@ -374,6 +2400,7 @@ fn pack_components(
                 // scriptlet-created files.
                 max_mtime_clamp = max_mtime_clamp.max(comp.mtime_clamp);
                 merged_files.extend(comp.files);
+                merged_packages.extend(comp.packages);
             }
 
             // this becomes history/annotation values; sort for reproducibility
@@ -385,6 +2412,7 @@ fn pack_components(
                     mtime_clamp: max_mtime_clamp,
                     stability: group.stability,
                     files: merged_files,
+                    packages: merged_packages,
                 },
             ));
         }
@@ -395,6 +2423,8 @@ fn pack_components(
 
 #[cfg(test)]
 mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
     use super::*;
 
     const CONFIG_FIXTURE: &str = include_str!("../tests/fixtures/empty.image-config.json");
@@ -408,7 +2438,7 @@ mod tests {
         let rootfs_dir = tempfile::tempdir().unwrap();
 
         let args = BuildArgs {
-            rootfs: Utf8PathBuf::try_from(rootfs_dir.path().to_path_buf()).unwrap(),
+            rootfs: Some(Utf8PathBuf::try_from(rootfs_dir.path().to_path_buf()).unwrap()),
             source_date_epoch: Some(1),
             ..Default::default()
         };
@@ -426,6 +2456,7 @@ mod tests {
                 mtime_clamp: 1,
                 stability: 0.0,
                 files: Default::default(),
+                packages: Default::default(),
             },
         )];
 
@@ -477,6 +2508,79 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_dry_run_writes_nothing() {
+        let rootfs_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(rootfs_dir.path().join("app")).unwrap();
+        std::fs::write(rootfs_dir.path().join("app/main"), "main").unwrap();
+        let work_dir = tempfile::tempdir().unwrap();
+        let work = Utf8Path::from_path(work_dir.path()).unwrap();
+        std::fs::write(
+            work.join("components.toml"),
+            "[[component]]\nname = \"app\"\npaths = [\"/app\", \"/app/**\"]\n",
+        )
+        .unwrap();
+
+        let args = BuildArgs {
+            rootfs: Some(Utf8PathBuf::try_from(rootfs_dir.path().to_path_buf()).unwrap()),
+            source_date_epoch: Some(1),
+            components_manifest: Some(work.join("components.toml")),
+            state_dir: Some(work.join("state")),
+            stability_out: Some(work.join("stability.json")),
+            plan_out: Some(work.join("plan.json")),
+            dry_run: true,
+            ..Default::default()
+        };
+        build(&args, &[], &Telemetry::default()).unwrap();
+        let mut written: Vec<_> = std::fs::read_dir(work)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        written.sort();
+        assert_eq!(written, ["components.toml"]);
+    }
+
+    #[test]
+    fn test_include_root() {
+        let rootfs_dir = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(rootfs_dir.path(), ambient_authority()).unwrap();
+        rootfs.write("file", "content").unwrap();
+        std::fs::set_permissions(rootfs_dir.path(), std::fs::Permissions::from_mode(0o750))
+            .unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let component = |files: FileMap| Component {
+            mtime_clamp: 1,
+            stability: 0.0,
+            files,
+            packages: Default::default(),
+        };
+        let components = || {
+            vec![
+                ("empty".to_string(), component(FileMap::new())),
+                ("first".to_string(), component(files.clone())),
+            ]
+        };
+
+        let root_mode = |args: &BuildArgs| {
+            let builder = new_builder(args, &rootfs, None, None, None, components()).unwrap();
+            let mut output = Vec::new();
+            builder.write_rootfs_tar(&mut output).unwrap();
+            let mut archive = tar::Archive::new(output.as_slice());
+            archive
+                .entries()
+                .unwrap()
+                .map(|entry| entry.unwrap())
+                .find(|entry| entry.path_bytes().as_ref() == b"./")
+                .map(|entry| entry.header().mode().unwrap() & 0o7777)
+        };
+        assert_eq!(root_mode(&BuildArgs::default()), None);
+        let args = BuildArgs {
+            include_root: true,
+            ..Default::default()
+        };
+        assert_eq!(root_mode(&args), Some(0o750));
+    }
+
     #[test]
     fn test_parse_config_direct_format() {
         // Test parsing direct OCI config format
@@ -539,6 +2643,22 @@ mod tests {
         assert_eq!(parsed.architecture, Some("amd64".to_string()));
     }
 
+    #[test]
+    fn test_parse_repo_priorities() {
+        let pairs = vec!["rpm=0".to_string(), "xattr=20".to_string()];
+        assert_eq!(
+            parse_repo_priorities(&pairs).unwrap(),
+            [("rpm".to_string(), 0), ("xattr".to_string(), 20)]
+        );
+
+        for pair in ["rpm", "=5", "rpm=-1", "rpm=high"] {
+            assert!(
+                parse_repo_priorities(&[pair.to_string()]).is_err(),
+                "{pair:?} should be rejected"
+            );
+        }
+    }
+
     #[test]
     fn test_parse_key_value_pairs_invalid() {
         let invalid_pairs = ["", "no-equals", "=", "=value", "-key", "=-"];
@@ -636,4 +2756,615 @@ mod tests {
         assert_eq!(labels.get("override-me"), Some(&"new-value".to_string()));
         assert_eq!(labels.get("new-label"), Some(&"second".to_string()));
     }
+
+    #[test]
+    fn test_layer_budget() {
+        let args = BuildArgs {
+            max_layers: Some(64),
+            ..Default::default()
+        };
+        assert_eq!(layer_budget(&args, 0).unwrap(), 64);
+
+        let args = BuildArgs {
+            max_layers: Some(64),
+            reserve_layers: 10,
+            embed_components: true,
+            ..Default::default()
+        };
+        assert_eq!(layer_budget(&args, 0).unwrap(), 53);
+        // the layers of the base image count too
+        assert_eq!(layer_budget(&args, 20).unwrap(), 33);
+
+        let args = BuildArgs {
+            max_layers: Some(10),
+            reserve_layers: 10,
+            ..Default::default()
+        };
+        assert!(layer_budget(&args, 0).is_err());
+    }
+
+    #[test]
+    fn test_select_components() {
+        let components: HashMap<String, Component> =
+            ["xattr/myapp", "xattr/myapp-data", "rpm/bash"]
+                .into_iter()
+                .map(|name| {
+                    let component = Component {
+                        mtime_clamp: 1,
+                        stability: 0.5,
+                        files: Default::default(),
+                        packages: Default::default(),
+                    };
+                    (name.to_string(), component)
+                })
+                .collect();
+
+        let selected = select_components(components.clone(), &["xattr/myapp*".into()]).unwrap();
+        let mut names: Vec<_> = selected.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        assert_eq!(names, ["xattr/myapp", "xattr/myapp-data"]);
+        // `*` doesn't match `/`
+        assert!(select_components(components.clone(), &["*bash".into()]).is_err());
+        assert!(select_components(components, &["[".into()]).is_err());
+    }
+
+    #[test]
+    fn test_pack_components_pins() {
+        let components = || -> HashMap<String, Component> {
+            ["a", "b", "c"]
+                .into_iter()
+                .map(|name| {
+                    let component = Component {
+                        mtime_clamp: 1,
+                        stability: 0.5,
+                        files: Default::default(),
+                        packages: Default::default(),
+                    };
+                    (name.to_string(), component)
+                })
+                .collect()
+        };
+
+        let args = BuildArgs {
+            max_layers: Some(2),
+            pins: vec!["a".into(), "b".into()],
+            ..Default::default()
+        };
+        let err = pack_components(&args, components(), 0).unwrap_err();
+        let msg = format!("{err:#}");
+        assert!(msg.contains("2 pinned component(s) don't fit in 2 layer(s)"));
+        assert!(msg.contains("pinned: a, b"));
+
+        let args = BuildArgs {
+            relax_pins: true,
+            ..args
+        };
+        let packed = pack_components(&args, components(), 0).unwrap();
+        assert_eq!(packed.len(), 2);
+
+        let args = BuildArgs {
+            max_layers: Some(2),
+            pins: vec!["a".into()],
+            ..Default::default()
+        };
+        let packed = pack_components(&args, components(), 0).unwrap();
+        assert!(packed.iter().any(|(name, _)| name == "a"));
+        assert!(packed.iter().any(|(name, _)| name == "b c"));
+
+        let args = BuildArgs {
+            max_layers: Some(2),
+            kernel_layer: true,
+            ..Default::default()
+        };
+        let mut kernel_components = components();
+        let kernel = kernel_components.remove("c").unwrap();
+        kernel_components.insert("kernel/6.12.0".into(), kernel);
+        let packed = pack_components(&args, kernel_components, 0).unwrap();
+        assert!(packed.iter().any(|(name, _)| name == "kernel/6.12.0"));
+        assert!(packed.iter().any(|(name, _)| name == "a b"));
+    }
+
+    #[test]
+    fn test_pack_components_exact() {
+        let components: HashMap<String, Component> = [
+            ("a", 1000, 0.99),
+            ("b", 900, 0.5),
+            ("c", 800, 0.95),
+            ("d", 300, 0.2),
+        ]
+        .into_iter()
+        .map(|(name, size, stability)| {
+            let info =
+                crate::components::FileInfo::test_file(crate::components::FileType::File, size, 1);
+            let component = Component {
+                mtime_clamp: 1,
+                stability,
+                files: [(Utf8PathBuf::from("/file"), info)].into(),
+                packages: Default::default(),
+            };
+            (name.to_string(), component)
+        })
+        .collect();
+
+        let args = BuildArgs {
+            max_layers: Some(2),
+            packing: Some(PackingAlgorithm::Exact),
+            ..Default::default()
+        };
+        let packed = pack_components(&args, components, 0).unwrap();
+        let names: Vec<&str> = packed.iter().map(|(name, _)| name.as_str()).collect();
+        // the two most stable components are worth keeping together
+        assert_eq!(names, ["a c", "b d"]);
+    }
+
+    #[test]
+    fn test_pack_components_constraints() {
+        let components = || -> HashMap<String, Component> {
+            [("a", 0.9), ("b", 0.9), ("c", 0.1), ("d", 0.1), ("e", 0.5)]
+                .into_iter()
+                .map(|(name, stability)| {
+                    let component = Component {
+                        mtime_clamp: 1,
+                        stability,
+                        files: Default::default(),
+                        packages: Default::default(),
+                    };
+                    (name.to_string(), component)
+                })
+                .collect()
+        };
+        let tmp = tempfile::tempdir().unwrap();
+        let path = Utf8Path::from_path(tmp.path())
+            .unwrap()
+            .join("constraints.toml");
+        std::fs::write(
+            &path,
+            r#"
+            [[together]]
+            components = ["a", "c"]
+
+            [[apart]]
+            components = ["b", "d"]
+
+            [[layer]]
+            components = ["e"]
+            index = 0
+            "#,
+        )
+        .unwrap();
+
+        let args = BuildArgs {
+            max_layers: Some(3),
+            pack_constraints: Some(path),
+            ..Default::default()
+        };
+        let packed = pack_components(&args, components(), 0).unwrap();
+        let layer_of = |component: &str| {
+            packed
+                .iter()
+                .position(|(name, _)| name.split(' ').any(|n| n == component))
+                .unwrap()
+        };
+        assert_eq!(packed.len(), 3);
+        assert_eq!(packed[0].0, "e");
+        assert_eq!(layer_of("a"), layer_of("c"));
+        assert_ne!(layer_of("b"), layer_of("d"));
+
+        // b and d can't share the only layer left besides e's
+        let args = BuildArgs {
+            max_layers: Some(2),
+            ..args
+        };
+        let err = pack_components(&args, components(), 0).unwrap_err();
+        assert!(format!("{err:#}").contains("components don't fit in 2 layer(s)"));
+    }
+
+    #[test]
+    fn test_split_component() {
+        use crate::components::{FileInfo, FileType};
+
+        let files = [
+            ("/usr", FileType::Directory, 0),
+            ("/usr/lib", FileType::Directory, 0),
+            ("/usr/lib/app", FileType::Directory, 0),
+            ("/usr/lib/app/a.bin", FileType::File, 40),
+            ("/usr/lib/app/b.bin", FileType::File, 40),
+            ("/usr/lib/data", FileType::Directory, 0),
+            ("/usr/lib/data/huge.bin", FileType::File, 150),
+            ("/usr/share/app", FileType::Directory, 0),
+            ("/usr/share/app/c.txt", FileType::File, 30),
+        ]
+        .into_iter()
+        .map(|(path, file_type, size)| {
+            let info = FileInfo::test_file(file_type, size, 1);
+            (Utf8PathBuf::from(path), info)
+        })
+        .collect();
+        let component = Component {
+            mtime_clamp: 1,
+            stability: 0.5,
+            files,
+            packages: Default::default(),
+        };
+
+        let parts = split_component("rpm/app".into(), component.clone(), 1000);
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].0, "rpm/app");
+
+        let parts = split_component("rpm/app".into(), component, 100);
+        let summary: Vec<(&str, Vec<&str>)> = parts
+            .iter()
+            .map(|(name, comp)| {
+                let paths = comp.files.keys().map(|p| p.as_str()).collect();
+                (name.as_str(), paths)
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (
+                    "rpm/app#1",
+                    vec![
+                        "/usr",
+                        "/usr/lib",
+                        "/usr/lib/app",
+                        "/usr/lib/app/a.bin",
+                        "/usr/lib/app/b.bin",
+                        "/usr/lib/data",
+                    ]
+                ),
+                ("rpm/app#2", vec!["/usr/lib/data/huge.bin"]),
+                ("rpm/app#3", vec!["/usr/share/app", "/usr/share/app/c.txt"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pack_components_max_layer_size() {
+        let component = |size: u64| {
+            let info =
+                crate::components::FileInfo::test_file(crate::components::FileType::File, size, 1);
+            Component {
+                mtime_clamp: 1,
+                stability: 0.5,
+                files: [(Utf8PathBuf::from("/file"), info)].into(),
+                packages: Default::default(),
+            }
+        };
+        let components = || -> HashMap<String, Component> {
+            [("a", 60), ("b", 50), ("c", 40), ("d", 10)]
+                .into_iter()
+                .map(|(name, size)| (name.to_string(), component(size)))
+                .collect()
+        };
+
+        let args = BuildArgs {
+            max_layers: Some(2),
+            max_layer_size: Some(100),
+            ..Default::default()
+        };
+        let packed = pack_components(&args, components(), 0).unwrap();
+        assert_eq!(packed.len(), 2);
+        for (name, comp) in &packed {
+            let size: u64 = comp.files.values().map(|f| f.size).sum();
+            assert!(size <= 100, "{name} is {size} bytes");
+        }
+
+        let args = BuildArgs {
+            max_layers: Some(1),
+            ..args
+        };
+        let err = pack_components(&args, components(), 0).unwrap_err();
+        let msg = format!("{err:#}");
+        assert!(msg.contains("components don't fit in 1 layer(s)"));
+        assert!(msg.contains("--max-layer-size: 100"));
+    }
+
+    #[test]
+    fn test_check_unclaimed() {
+        use crate::components::FileInfo;
+
+        let component = |size: u64| Component {
+            mtime_clamp: 1,
+            stability: 0.5,
+            files: [(
+                Utf8PathBuf::from("/file"),
+                FileInfo::test_file(FileType::File, size, 0),
+            )]
+            .into(),
+            packages: Default::default(),
+        };
+        let components: HashMap<String, Component> = [
+            ("rpm/foo".to_string(), component(700)),
+            (UNCLAIMED_COMPONENT.to_string(), component(300)),
+        ]
+        .into();
+
+        check_unclaimed(&components, 30, false).unwrap();
+        let err = check_unclaimed(&components, 29, false).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("30.0% of bytes (300 of 1000) are unclaimed")
+        );
+        check_unclaimed(&components, 29, true).unwrap();
+
+        // no files at all
+        check_unclaimed(&HashMap::new(), 0, false).unwrap();
+    }
+
+    #[test]
+    fn test_report_unclaimed() {
+        use crate::components::FileInfo;
+
+        let file = |file_type, size| FileInfo::test_file(file_type, size, 0);
+        let unclaimed = Component {
+            mtime_clamp: 1,
+            stability: 0.0,
+            files: [
+                ("/opt", file(FileType::Directory, 0)),
+                ("/opt/vendor/bin/tool", file(FileType::File, 300)),
+                ("/opt/vendor/lib/libtool.so", file(FileType::File, 500)),
+                ("/opt/vendor/lib/libtool.so.1", file(FileType::Symlink, 14)),
+                ("/etc/hostname", file(FileType::File, 10)),
+            ]
+            .into_iter()
+            .map(|(path, info)| (Utf8PathBuf::from(path), info))
+            .collect(),
+            packages: Default::default(),
+        };
+        let components: HashMap<String, Component> =
+            [(UNCLAIMED_COMPONENT.to_string(), unclaimed)].into();
+
+        let err = report_unclaimed(&components, true, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "4 file(s) (810 bytes) are unclaimed and in chunkah/unclaimed:\n  \
+             /opt/vendor: 3 file(s), 800 bytes\n  \
+             /etc: 1 file(s), 10 bytes"
+        );
+        report_unclaimed(&components, false, Some(0)).unwrap();
+        report_unclaimed(&components, false, Some(1000)).unwrap();
+
+        // only directories don't count
+        let components: HashMap<String, Component> = [(
+            UNCLAIMED_COMPONENT.to_string(),
+            Component {
+                mtime_clamp: 1,
+                stability: 0.0,
+                files: [(Utf8PathBuf::from("/tmp"), file(FileType::Directory, 0))].into(),
+                packages: Default::default(),
+            },
+        )]
+        .into();
+        report_unclaimed(&components, true, None).unwrap();
+        report_unclaimed(&HashMap::new(), true, None).unwrap();
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size("1234").unwrap(), 1234);
+        assert_eq!(parse_size("4K").unwrap(), 4096);
+        assert_eq!(parse_size("10M").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(parse_size("2G").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("10MB").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("99999999999G").is_err());
+    }
+
+    #[test]
+    fn test_unpack_rootfs_image() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(tmp.path()).unwrap();
+        assert!(unpack_rootfs_image(dir, None).unwrap().is_none());
+
+        let squashfs =
+            Utf8Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/rootfs-gzip.squashfs");
+        let unpacked = unpack_rootfs_image(&squashfs, Some(dir)).unwrap().unwrap();
+        assert!(unpacked.path().join("etc/hostname").is_file());
+
+        let truncated = dir.join("rootfs.squashfs");
+        std::fs::write(&truncated, b"hsqs\x10\x00\x00\x00").unwrap();
+        let Err(err) = unpack_rootfs_image(&truncated, None) else {
+            panic!("truncated image unpacked");
+        };
+        let err = format!("{err:#}");
+        assert!(err.starts_with("extracting squashfs image"), "{err}");
+
+        let other = dir.join("rootfs.img");
+        std::fs::write(&other, b"hs").unwrap();
+        let Err(err) = unpack_rootfs_image(&other, None) else {
+            panic!("file unpacked");
+        };
+        assert!(err.to_string().ends_with("is not a directory"), "{err}");
+    }
+
+    #[test]
+    fn test_parse_component_xattr() {
+        assert_eq!(
+            parse_component_xattr("user.build.owner").unwrap(),
+            "user.build.owner"
+        );
+        assert_eq!(
+            parse_component_xattr("trusted.chunkah.component").unwrap(),
+            "trusted.chunkah.component"
+        );
+        assert!(parse_component_xattr("security.component").is_err());
+        assert!(parse_component_xattr("component").is_err());
+        assert!(parse_component_xattr("user.").is_err());
+    }
+
+    #[test]
+    fn test_profile_defaults() {
+        let args = BuildArgs::default();
+        assert_eq!(args.max_layers(), 64);
+        assert!(!args.compressed());
+        assert!(args.prune().is_empty());
+        assert_eq!(args.packing(), PackingAlgorithm::Greedy);
+        assert_eq!(args.bigfile_threshold(), None);
+        assert_eq!(args.component_xattr(), None);
+
+        let args = BuildArgs {
+            profile: Some(Profile::Distroless),
+            ..Default::default()
+        };
+        assert_eq!(args.packing(), PackingAlgorithm::Exact);
+
+        let args = BuildArgs {
+            profile: Some(Profile::Ml),
+            ..Default::default()
+        };
+        assert_eq!(args.entry_order(), EntryOrder::Size);
+        assert_eq!(args.bigfile_threshold(), Some(64 * 1024 * 1024));
+
+        let args = BuildArgs {
+            profile: Some(Profile::Webapp),
+            prune: vec!["/srv/cache/".into()],
+            ..Default::default()
+        };
+        assert!(args.compressed());
+        assert_eq!(args.prune(), ["/run/", "/tmp/", "/var/tmp/", "/srv/cache/"]);
+
+        // explicit options override the profile
+        let args = BuildArgs {
+            profile: Some(Profile::Bootc),
+            max_layers: Some(128),
+            compressed: Some(false),
+            entry_order: Some(EntryOrder::Size),
+            packing: Some(PackingAlgorithm::Exact),
+            bigfile_threshold: Some(1024),
+            component_xattr: Some("trusted.component".into()),
+            ..Default::default()
+        };
+        assert_eq!(args.max_layers(), 128);
+        assert!(!args.compressed());
+        assert_eq!(args.entry_order(), EntryOrder::Size);
+        assert_eq!(args.packing(), PackingAlgorithm::Exact);
+        assert_eq!(args.bigfile_threshold(), Some(1024));
+        assert_eq!(args.component_xattr(), Some("trusted.component"));
+        assert_eq!(
+            BuildArgs {
+                profile: Some(Profile::Bootc),
+                ..Default::default()
+            }
+            .max_layers(),
+            96
+        );
+    }
+
+    #[test]
+    fn test_live_root() {
+        let args = BuildArgs {
+            live_root: true,
+            prune: vec!["/var/cache/".into()],
+            ..Default::default()
+        };
+        assert!(args.skip_special_files());
+        assert!(args.one_file_system());
+        assert_eq!(
+            args.prune(),
+            ["/proc/", "/sys/", "/dev/", "/run/", "/tmp/", "/var/cache/"]
+        );
+        assert!(!BuildArgs::default().one_file_system());
+    }
+
+    #[test]
+    fn test_apply_user_config() {
+        let config = || UserConfig {
+            profile: Some(Profile::Webapp),
+            max_layers: Some(48),
+            compression_level: Some(9),
+            claim_cache: Some("/var/cache/chunkah".into()),
+            jobs: Some(4),
+            ..Default::default()
+        };
+
+        let mut args = BuildArgs::default();
+        args.apply_user_config(config());
+        assert_eq!(args.max_layers(), 48);
+        assert_eq!(args.scan_threads().unwrap(), 4);
+        assert_eq!(args.build_threads().unwrap(), 4);
+        assert_eq!(args.compression_level(), 9);
+        // the user's profile applies as usual
+        assert!(args.compressed());
+        assert_eq!(
+            args.claim_cache.as_deref(),
+            Some(Utf8Path::new("/var/cache/chunkah"))
+        );
+
+        // the command line takes precedence
+        let mut args = BuildArgs {
+            profile: Some(Profile::Bootc),
+            compression_level: Some(1),
+            claim_cache: Some("/tmp/cache".into()),
+            build_threads: Some(2),
+            ..Default::default()
+        };
+        args.apply_user_config(config());
+        assert_eq!(args.scan_threads().unwrap(), 4);
+        assert_eq!(args.build_threads().unwrap(), 2);
+        assert_eq!(args.profile, Some(Profile::Bootc));
+        assert!(!args.compressed());
+        assert_eq!(args.max_layers(), 48);
+        assert_eq!(args.compression_level(), 1);
+        assert_eq!(
+            args.claim_cache.as_deref(),
+            Some(Utf8Path::new("/tmp/cache"))
+        );
+    }
+
+    #[test]
+    fn test_wants_user_config() {
+        let args = BuildArgs::default();
+        assert!(args.wants_user_config());
+
+        let args = BuildArgs {
+            no_user_config: true,
+            ..Default::default()
+        };
+        assert!(!args.wants_user_config());
+
+        // the image config is unrelated to the user's defaults
+        let args = BuildArgs {
+            config_str: Some("{}".into()),
+            ..Default::default()
+        };
+        assert!(args.wants_user_config());
+
+        // e.g. for CI, without changing the command line
+        let command = <BuildArgs as clap::CommandFactory>::command();
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == "no_user_config")
+            .unwrap();
+        assert_eq!(
+            arg.get_env(),
+            Some(std::ffi::OsStr::new("CHUNKAH_NO_USER_CONFIG"))
+        );
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_parse_config_arbitrary(content in ".*") {
+            // must not panic
+            let _ = parse_config(&content);
+        }
+
+        #[test]
+        fn test_parse_config_env_roundtrip(
+            env in proptest::collection::vec("[A-Z_]{1,10}=[^\\x00]{0,20}", 0..5),
+            inspect in proptest::bool::ANY,
+        ) {
+            let config = serde_json::json!({"Env": env});
+            let json = if inspect {
+                serde_json::json!([{"Config": config}])
+            } else {
+                config
+            };
+            let parsed = parse_config(&json.to_string()).unwrap();
+            proptest::prop_assert_eq!(parsed.config.env().clone().unwrap_or_default(), env);
+        }
+    }
 }
@@ -0,0 +1,428 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::ambient_authority;
+use cap_std_ext::cap_std::fs::Dir;
+use clap::Parser;
+use ocidir::oci_spec::image as oci_image;
+use serde::Serialize;
+
+#[derive(Parser)]
+pub struct DiffArgs {
+    /// Path to the OCI image layout of the old image
+    old: Utf8PathBuf,
+
+    /// Path to the OCI image layout of the new image
+    new: Utf8PathBuf,
+
+    /// Output the differences as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+/// What a change affects for users of the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Impact {
+    /// Only informational, e.g. labels and annotations.
+    Metadata,
+    /// Containers run differently, e.g. a different entrypoint.
+    Runtime,
+}
+
+impl std::fmt::Display for Impact {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Metadata => "metadata",
+            Self::Runtime => "runtime",
+        })
+    }
+}
+
+/// A field which differs between the two images.
+#[derive(Debug, PartialEq, Serialize)]
+struct Change {
+    /// The field, e.g. `env` or `label`.
+    field: &'static str,
+    /// The key within the field, for maps.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    old: Option<String>,
+    new: Option<String>,
+    impact: Impact,
+}
+
+/// Layers summary of the two images.
+#[derive(Debug, PartialEq, Serialize)]
+struct LayersDiff {
+    old: usize,
+    new: usize,
+    /// Layers of the new image which are also in the old one.
+    reused: usize,
+    /// Total size of the layers of the new image which aren't in the old one,
+    /// i.e. which clients with the old image cached have to pull.
+    new_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Diff {
+    layers: LayersDiff,
+    changes: Vec<Change>,
+}
+
+/// The parts of an image that get compared.
+struct Image {
+    manifest: oci_image::ImageManifest,
+    config: oci_image::ImageConfiguration,
+}
+
+pub fn run(args: &DiffArgs) -> Result<()> {
+    let old = read_image(&args.old).with_context(|| format!("reading {}", args.old))?;
+    let new = read_image(&args.new).with_context(|| format!("reading {}", args.new))?;
+    let diff = diff_images(&old, &new);
+
+    if args.json {
+        let json = serde_json::to_string_pretty(&diff).context("serializing diff")?;
+        println!("{json}");
+    } else {
+        print!("{}", format_diff(&diff));
+    }
+    Ok(())
+}
+
+/// Read the manifest and config of the image in the OCI image layout at
+/// `path`.
+fn read_image(path: &Utf8Path) -> Result<Image> {
+    let dir = Dir::open_ambient_dir(path, ambient_authority())
+        .with_context(|| format!("opening {path}"))?;
+    let oci_dir = ocidir::OciDir::open(dir).context("opening OCI image layout")?;
+
+    let (_, manifest) = crate::utils::read_image_manifest(&oci_dir)?;
+    let config: oci_image::ImageConfiguration = oci_dir
+        .read_json_blob(manifest.config())
+        .context("reading config")?;
+    Ok(Image { manifest, config })
+}
+
+fn diff_images(old: &Image, new: &Image) -> Diff {
+    let old_digests: HashSet<String> = old
+        .manifest
+        .layers()
+        .iter()
+        .map(|layer| layer.digest().to_string())
+        .collect();
+    let (reused, added): (Vec<_>, Vec<_>) = new
+        .manifest
+        .layers()
+        .iter()
+        .partition(|layer| old_digests.contains(&layer.digest().to_string()));
+    let layers = LayersDiff {
+        old: old.manifest.layers().len(),
+        new: new.manifest.layers().len(),
+        reused: reused.len(),
+        new_bytes: added.iter().map(|layer| layer.size()).sum(),
+    };
+
+    let mut changes = Vec::new();
+    let old_config = old.config.config().clone().unwrap_or_default();
+    let new_config = new.config.config().clone().unwrap_or_default();
+
+    let mut scalar = |field, old: Option<String>, new: Option<String>, impact| {
+        if old != new {
+            changes.push(Change {
+                field,
+                key: None,
+                old,
+                new,
+                impact,
+            });
+        }
+    };
+    scalar(
+        "architecture",
+        Some(old.config.architecture().to_string()),
+        Some(new.config.architecture().to_string()),
+        Impact::Runtime,
+    );
+    scalar(
+        "os",
+        Some(old.config.os().to_string()),
+        Some(new.config.os().to_string()),
+        Impact::Runtime,
+    );
+    scalar(
+        "entrypoint",
+        format_list(old_config.entrypoint()),
+        format_list(new_config.entrypoint()),
+        Impact::Runtime,
+    );
+    scalar(
+        "cmd",
+        format_list(old_config.cmd()),
+        format_list(new_config.cmd()),
+        Impact::Runtime,
+    );
+    scalar(
+        "user",
+        old_config.user().clone(),
+        new_config.user().clone(),
+        Impact::Runtime,
+    );
+    scalar(
+        "working-dir",
+        old_config.working_dir().clone(),
+        new_config.working_dir().clone(),
+        Impact::Runtime,
+    );
+    scalar(
+        "stop-signal",
+        old_config.stop_signal().clone(),
+        new_config.stop_signal().clone(),
+        Impact::Runtime,
+    );
+    scalar(
+        "exposed-ports",
+        format_set(old_config.exposed_ports()),
+        format_set(new_config.exposed_ports()),
+        Impact::Runtime,
+    );
+    scalar(
+        "volumes",
+        format_set(old_config.volumes()),
+        format_set(new_config.volumes()),
+        Impact::Runtime,
+    );
+    scalar(
+        "author",
+        old.config.author().clone(),
+        new.config.author().clone(),
+        Impact::Metadata,
+    );
+    scalar(
+        "created",
+        old.config.created().clone(),
+        new.config.created().clone(),
+        Impact::Metadata,
+    );
+
+    diff_map(
+        &mut changes,
+        "env",
+        parse_env(old_config.env()),
+        parse_env(new_config.env()),
+        Impact::Runtime,
+    );
+    diff_map(
+        &mut changes,
+        "label",
+        to_btree(old_config.labels()),
+        to_btree(new_config.labels()),
+        Impact::Metadata,
+    );
+    diff_map(
+        &mut changes,
+        "annotation",
+        to_btree(old.manifest.annotations()),
+        to_btree(new.manifest.annotations()),
+        Impact::Metadata,
+    );
+
+    // most disruptive first
+    changes.sort_by_key(|change| std::cmp::Reverse(change.impact));
+    Diff { layers, changes }
+}
+
+/// Add a change for every key of `old` and `new` with a different value.
+fn diff_map(
+    changes: &mut Vec<Change>,
+    field: &'static str,
+    old: BTreeMap<String, String>,
+    new: BTreeMap<String, String>,
+    impact: Impact,
+) {
+    let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    for key in keys {
+        let (old, new) = (old.get(key), new.get(key));
+        if old != new {
+            changes.push(Change {
+                field,
+                key: Some(key.clone()),
+                old: old.cloned(),
+                new: new.cloned(),
+                impact,
+            });
+        }
+    }
+}
+
+/// Split `KEY=VALUE` environment variables into a map.
+fn parse_env(env: &Option<Vec<String>>) -> BTreeMap<String, String> {
+    env.iter()
+        .flatten()
+        .map(|var| match var.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => (var.clone(), String::new()),
+        })
+        .collect()
+}
+
+fn to_btree(map: &Option<HashMap<String, String>>) -> BTreeMap<String, String> {
+    map.clone().unwrap_or_default().into_iter().collect()
+}
+
+/// Format an ordered list (e.g. the entrypoint) as JSON, like in the config.
+fn format_list(list: &Option<Vec<String>>) -> Option<String> {
+    list.as_ref()
+        .map(|list| serde_json::to_string(list).expect("serializing strings"))
+}
+
+/// Format an unordered list (e.g. the volumes) so that the order doesn't
+/// matter.
+fn format_set(list: &Option<Vec<String>>) -> Option<String> {
+    list.as_ref().map(|list| {
+        let mut list = list.clone();
+        list.sort();
+        list.join(", ")
+    })
+}
+
+fn format_diff(diff: &Diff) -> String {
+    let layers = &diff.layers;
+    let added = layers.new - layers.reused;
+    let mut out = format!(
+        "{}layers: {} reused, {added} new ({} bytes), {} in old image\n",
+        if added > 0 { "[pull] " } else { "" },
+        layers.reused,
+        layers.new_bytes,
+        layers.old
+    );
+    if diff.changes.is_empty() {
+        out.push_str("no config changes\n");
+    }
+    let value = |v: &Option<String>| v.clone().unwrap_or_else(|| "(unset)".to_string());
+    for change in &diff.changes {
+        let field = match &change.key {
+            Some(key) => format!("{} {key}", change.field),
+            None => change.field.to_string(),
+        };
+        out.push_str(&format!(
+            "[{}] {field}: {} -> {}\n",
+            change.impact,
+            value(&change.old),
+            value(&change.new)
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(layer: &str, config: serde_json::Value, annotations: serde_json::Value) -> Image {
+        let layer_desc = |digest: &str| {
+            serde_json::json!({
+                "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+                "digest": format!("sha256:{}", digest.repeat(64)),
+                "size": 100,
+            })
+        };
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": format!("sha256:{}", "0".repeat(64)),
+                "size": 100,
+            },
+            "layers": [layer_desc("a"), layer_desc(layer)],
+            "annotations": annotations,
+        });
+        let config = serde_json::json!({
+            "architecture": "amd64",
+            "os": "linux",
+            "config": config,
+            "rootfs": {"type": "layers", "diff_ids": []},
+        });
+        Image {
+            manifest: serde_json::from_value(manifest).unwrap(),
+            config: serde_json::from_value(config).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_diff_images() {
+        let old = image(
+            "b",
+            serde_json::json!({
+                "Env": ["PATH=/usr/bin", "LANG=C"],
+                "Entrypoint": ["/app"],
+                "Labels": {"version": "1"},
+            }),
+            serde_json::json!({"org.opencontainers.image.revision": "abc"}),
+        );
+        let new = image(
+            "c",
+            serde_json::json!({
+                "Env": ["PATH=/usr/bin", "DEBUG=1"],
+                "Entrypoint": ["/app", "--serve"],
+                "Labels": {"version": "2"},
+            }),
+            serde_json::json!({"org.opencontainers.image.revision": "abc"}),
+        );
+
+        let diff = diff_images(&old, &new);
+        assert_eq!(
+            diff.layers,
+            LayersDiff {
+                old: 2,
+                new: 2,
+                reused: 1,
+                new_bytes: 100,
+            }
+        );
+        let changes: Vec<_> = diff
+            .changes
+            .iter()
+            .map(|c| {
+                (
+                    c.impact,
+                    c.field,
+                    c.key.as_deref(),
+                    c.old.as_deref(),
+                    c.new.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (
+                    Impact::Runtime,
+                    "entrypoint",
+                    None,
+                    Some(r#"["/app"]"#),
+                    Some(r#"["/app","--serve"]"#)
+                ),
+                (Impact::Runtime, "env", Some("DEBUG"), None, Some("1")),
+                (Impact::Runtime, "env", Some("LANG"), Some("C"), None),
+                (
+                    Impact::Metadata,
+                    "label",
+                    Some("version"),
+                    Some("1"),
+                    Some("2")
+                ),
+            ]
+        );
+
+        // identical images
+        let diff = diff_images(&old, &old);
+        assert!(diff.changes.is_empty());
+        assert_eq!(
+            format_diff(&diff),
+            "layers: 2 reused, 0 new (0 bytes), 2 in old image\nno config changes\n"
+        );
+    }
+}
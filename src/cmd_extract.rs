@@ -0,0 +1,56 @@
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::Parser;
+
+use crate::cmd_inspect::OpenedImage;
+use crate::ocibuilder::unpack_layers;
+
+#[derive(Parser)]
+pub struct ExtractArgs {
+    /// Path to the OCI image layout or OCI archive of the image to extract
+    image: Utf8PathBuf,
+
+    /// Directory to extract the rootfs of the image into
+    ///
+    /// It's created if it doesn't exist, and must be empty otherwise.
+    dest: Utf8PathBuf,
+}
+
+pub fn run(args: &ExtractArgs) -> Result<()> {
+    prepare_dest(&args.dest)?;
+    let image =
+        OpenedImage::open(&args.image).with_context(|| format!("reading {}", args.image))?;
+    unpack_layers(&image.oci_dir, image.manifest.layers(), &args.dest)
+        .with_context(|| format!("extracting {} into {}", args.image, args.dest))
+}
+
+/// Create the directory `dest` if needed, making sure that it's empty.
+fn prepare_dest(dest: &Utf8Path) -> Result<()> {
+    std::fs::create_dir_all(dest).with_context(|| format!("creating {dest}"))?;
+    let mut entries = dest
+        .read_dir_utf8()
+        .with_context(|| format!("reading {dest}"))?;
+    anyhow::ensure!(entries.next().is_none(), "{dest} is not empty");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prepare_dest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(tmp.path()).unwrap();
+
+        let dest = dir.join("new/rootfs");
+        prepare_dest(&dest).unwrap();
+        assert!(dest.is_dir());
+        // empty directories are fine
+        prepare_dest(&dest).unwrap();
+
+        std::fs::write(dest.join("file"), "content").unwrap();
+        let err = prepare_dest(&dest).unwrap_err();
+        assert_eq!(err.to_string(), format!("{dest} is not empty"));
+    }
+}
@@ -0,0 +1,370 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Read, Seek};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::ambient_authority;
+use cap_std_ext::cap_std::fs::Dir;
+use clap::Parser;
+use ocidir::oci_spec::image as oci_image;
+use serde::Serialize;
+
+use crate::ocibuilder::{
+    COMPONENT_ANNOTATION, STABILITY_ANNOTATION, layer_component, layer_stability,
+};
+
+/// Prefix of the layer annotations set by chunkah.
+const ANNOTATION_PREFIX: &str = "org.chunkah.";
+
+#[derive(Parser)]
+pub struct InspectArgs {
+    /// Path to the OCI image layout or OCI archive of the image to inspect
+    image: Utf8PathBuf,
+
+    /// Mark the layers whose blob is also in this image
+    ///
+    /// Path to the OCI image layout or OCI archive of e.g. the previous
+    /// release. This shows how much of the image clients with the other image
+    /// cached can reuse.
+    #[arg(long, value_name = "PATH")]
+    against: Option<Utf8PathBuf>,
+
+    /// List the files of each layer
+    ///
+    /// This reads all the layers of the image.
+    #[arg(long)]
+    files: bool,
+
+    /// Output the layers as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+/// A layer of the inspected image.
+#[derive(Debug, PartialEq, Serialize)]
+struct Layer {
+    digest: String,
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    component: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stability: Option<f64>,
+    /// The other `org.chunkah.*` annotations of the layer.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    annotations: BTreeMap<String, String>,
+    /// Whether the other image has the same blob, with `--against`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reused: Option<bool>,
+    /// The paths in the layer, with `--files`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<String>>,
+}
+
+/// How much of the image is reused from the other image.
+#[derive(Debug, PartialEq, Serialize)]
+struct Reuse {
+    layers: usize,
+    bytes: u64,
+    /// Fraction of the bytes of the image which are reused.
+    score: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct Inspection {
+    layers: Vec<Layer>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reuse: Option<Reuse>,
+}
+
+pub fn run(args: &InspectArgs) -> Result<()> {
+    let image =
+        OpenedImage::open(&args.image).with_context(|| format!("reading {}", args.image))?;
+    let against = args
+        .against
+        .as_ref()
+        .map(|path| OpenedImage::open(path).with_context(|| format!("reading {path}")))
+        .transpose()?;
+    let mut inspection = inspect(&image.manifest, against.as_ref().map(|o| &o.manifest));
+    if args.files {
+        for (layer, desc) in inspection.layers.iter_mut().zip(image.manifest.layers()) {
+            let files = layer_files(&image.oci_dir, desc)
+                .with_context(|| format!("listing files of layer {}", layer.digest))?;
+            layer.files = Some(files);
+        }
+    }
+
+    if args.json {
+        let json = serde_json::to_string_pretty(&inspection).context("serializing layers")?;
+        println!("{json}");
+    } else {
+        print!("{}", format_inspection(&inspection));
+    }
+    Ok(())
+}
+
+/// An image in an OCI image layout.
+pub(crate) struct OpenedImage {
+    pub(crate) oci_dir: ocidir::OciDir,
+    pub(crate) manifest: oci_image::ImageManifest,
+    /// Keeps the OCI archive unpacked, if the image is one.
+    _unpacked: Option<tempfile::TempDir>,
+}
+
+impl OpenedImage {
+    /// Open the image in the OCI image layout or OCI archive at `path`.
+    pub(crate) fn open(path: &Utf8Path) -> Result<Self> {
+        let unpacked = if path.is_file() {
+            Some(unpack_archive(path).context("unpacking OCI archive")?)
+        } else {
+            None
+        };
+        let dir = match &unpacked {
+            Some(tmp) => Dir::open_ambient_dir(tmp.path(), ambient_authority()),
+            None => Dir::open_ambient_dir(path, ambient_authority()),
+        }
+        .with_context(|| format!("opening {path}"))?;
+        let oci_dir = ocidir::OciDir::open(dir).context("opening OCI image layout")?;
+        let (_, manifest) = crate::utils::read_image_manifest(&oci_dir)?;
+        Ok(Self {
+            oci_dir,
+            manifest,
+            _unpacked: unpacked,
+        })
+    }
+}
+
+/// Unpack the OCI archive at `path`, compressed or not, into a temporary
+/// directory.
+fn unpack_archive(path: &Utf8Path) -> Result<tempfile::TempDir> {
+    let mut file = std::fs::File::open(path).with_context(|| format!("opening {path}"))?;
+    let mut magic = [0u8; 6];
+    let n = file
+        .read(&mut magic)
+        .with_context(|| format!("reading {path}"))?;
+    file.rewind().with_context(|| format!("reading {path}"))?;
+    let reader: Box<dyn Read> = match &magic[..n] {
+        [0x1f, 0x8b, ..] => Box::new(flate2::read::GzDecoder::new(file)),
+        [0xfd, b'7', b'z', b'X', b'Z', 0x00] => Box::new(xz2::read::XzDecoder::new(file)),
+        [b'B', b'Z', b'h', ..] => Box::new(bzip2::read::BzDecoder::new(file)),
+        _ => Box::new(file),
+    };
+    let tmp = tempfile::tempdir().context("creating temp directory")?;
+    tar::Archive::new(reader)
+        .unpack(tmp.path())
+        .with_context(|| format!("unpacking {path}"))?;
+    Ok(tmp)
+}
+
+/// Returns the paths in `layer`, in the order they're in the layer.
+fn layer_files(oci_dir: &ocidir::OciDir, layer: &oci_image::Descriptor) -> Result<Vec<String>> {
+    let reader = crate::tar::read_layer(oci_dir, layer)?;
+    let mut archive = tar::Archive::new(reader);
+    let mut files = Vec::new();
+    for entry in archive.entries().context("reading layer")? {
+        let entry = entry.context("reading layer entry")?;
+        let path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        files.push(format!("/{}", path.trim_matches('/')));
+    }
+    Ok(files)
+}
+
+fn inspect(
+    manifest: &oci_image::ImageManifest,
+    against: Option<&oci_image::ImageManifest>,
+) -> Inspection {
+    let other_digests: Option<HashSet<String>> = against.map(|other| {
+        other
+            .layers()
+            .iter()
+            .map(|layer| layer.digest().to_string())
+            .collect()
+    });
+    let layers: Vec<Layer> = manifest
+        .layers()
+        .iter()
+        .map(|layer| {
+            let digest = layer.digest().to_string();
+            Layer {
+                reused: other_digests
+                    .as_ref()
+                    .map(|digests| digests.contains(&digest)),
+                digest,
+                size: layer.size(),
+                component: layer_component(layer).map(str::to_string),
+                stability: layer_stability(layer),
+                annotations: other_annotations(layer),
+                files: None,
+            }
+        })
+        .collect();
+
+    let reuse = other_digests.map(|_| {
+        let reused = || layers.iter().filter(|layer| layer.reused == Some(true));
+        let bytes = reused().map(|layer| layer.size).sum();
+        let total: u64 = layers.iter().map(|layer| layer.size).sum();
+        Reuse {
+            layers: reused().count(),
+            bytes,
+            score: if total == 0 {
+                1.0
+            } else {
+                bytes as f64 / total as f64
+            },
+        }
+    });
+    Inspection { layers, reuse }
+}
+
+/// Returns the `org.chunkah.*` annotations of `layer` other than the
+/// component and stability.
+fn other_annotations(layer: &oci_image::Descriptor) -> BTreeMap<String, String> {
+    let Some(annotations) = layer.annotations() else {
+        return BTreeMap::new();
+    };
+    annotations
+        .iter()
+        .filter(|(key, _)| {
+            key.starts_with(ANNOTATION_PREFIX)
+                && !matches!(key.as_str(), COMPONENT_ANNOTATION | STABILITY_ANNOTATION)
+        })
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+fn format_inspection(inspection: &Inspection) -> String {
+    let mut out = String::new();
+    for (i, layer) in inspection.layers.iter().enumerate() {
+        let reused = match layer.reused {
+            Some(true) => "reused ",
+            Some(false) => "new    ",
+            None => "",
+        };
+        let stability = layer
+            .stability
+            .map(|s| format!(" (stability {s:.3})"))
+            .unwrap_or_default();
+        out.push_str(&format!(
+            "{i:>3} {reused}{} {:>12} {}{stability}\n",
+            layer.digest,
+            layer.size,
+            layer.component.as_deref().unwrap_or("-"),
+        ));
+        for (key, value) in &layer.annotations {
+            out.push_str(&format!("      {key}: {value}\n"));
+        }
+        for path in layer.files.iter().flatten() {
+            out.push_str(&format!("      {path}\n"));
+        }
+    }
+    let total: u64 = inspection.layers.iter().map(|layer| layer.size).sum();
+    match &inspection.reuse {
+        Some(reuse) => out.push_str(&format!(
+            "reuse: {}/{} layers, {}/{total} bytes ({:.1}%)\n",
+            reuse.layers,
+            inspection.layers.len(),
+            reuse.bytes,
+            reuse.score * 100.0
+        )),
+        None => out.push_str(&format!(
+            "{} layers, {total} bytes\n",
+            inspection.layers.len()
+        )),
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(layers: &[(&str, u64, &str)]) -> oci_image::ImageManifest {
+        let layers: Vec<_> = layers
+            .iter()
+            .map(|(digest, size, component)| {
+                serde_json::json!({
+                    "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+                    "digest": format!("sha256:{}", digest.repeat(64)),
+                    "size": size,
+                    "annotations": {
+                        "org.chunkah.component": component,
+                        "org.chunkah.stability": "0.500",
+                        "org.chunkah.retention": "long",
+                        "org.example.other": "ignored",
+                    },
+                })
+            })
+            .collect();
+        serde_json::from_value(serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": format!("sha256:{}", "0".repeat(64)),
+                "size": 100,
+            },
+            "layers": layers,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_inspect() {
+        let old = manifest(&[("a", 300, "rpm/glibc"), ("b", 100, "rpm/bash")]);
+        let new = manifest(&[("a", 300, "rpm/glibc"), ("c", 100, "rpm/bash")]);
+
+        let inspection = inspect(&new, None);
+        assert_eq!(inspection.reuse, None);
+        assert_eq!(inspection.layers[1].component.as_deref(), Some("rpm/bash"));
+        assert_eq!(inspection.layers[1].stability, Some(0.5));
+        assert_eq!(inspection.layers[1].reused, None);
+        assert_eq!(
+            inspection.layers[1].annotations,
+            BTreeMap::from([("org.chunkah.retention".into(), "long".into())])
+        );
+        assert!(format_inspection(&inspection).contains("\n      org.chunkah.retention: long\n"));
+
+        let inspection = inspect(&new, Some(&old));
+        let reused: Vec<_> = inspection.layers.iter().map(|l| l.reused).collect();
+        assert_eq!(reused, [Some(true), Some(false)]);
+        assert_eq!(
+            inspection.reuse,
+            Some(Reuse {
+                layers: 1,
+                bytes: 300,
+                score: 0.75
+            })
+        );
+        let out = format_inspection(&inspection);
+        assert!(out.contains(&format!("  0 reused sha256:{}", "a".repeat(64))));
+        assert!(out.ends_with("reuse: 1/2 layers, 300/400 bytes (75.0%)\n"));
+
+        // an identical image is fully reused
+        let inspection = inspect(&new, Some(&new));
+        assert_eq!(inspection.reuse.unwrap().score, 1.0);
+    }
+
+    #[test]
+    fn test_unpack_archive() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = Utf8Path::from_path(tmp.path())
+            .unwrap()
+            .join("image.tar.gz");
+        let file = std::fs::File::create(&path).unwrap();
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            file,
+            flate2::Compression::default(),
+        ));
+        let content = br#"{"imageLayoutVersion":"1.0.0"}"#;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, "oci-layout", &content[..])
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let unpacked = unpack_archive(&path).unwrap();
+        let layout = std::fs::read(unpacked.path().join("oci-layout")).unwrap();
+        assert_eq!(layout, content);
+    }
+}
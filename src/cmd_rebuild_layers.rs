@@ -0,0 +1,157 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use clap::Parser;
+
+use crate::cmd_build::{self, BuildArgs, Telemetry};
+use crate::components::{Component, FileMap};
+use crate::diagnostics;
+use crate::ocibuilder::{BaseImage, METADATA_COMPONENT};
+use crate::rootfs_image::UnpackedImage;
+
+#[derive(Parser)]
+pub struct RebuildLayersArgs {
+    /// Path to the OCI image layout of the image to update
+    ///
+    /// It must have been built by chunkah, so that its layers are annotated
+    /// with their components.
+    image: Utf8PathBuf,
+
+    /// Rebuild the layer of this component
+    ///
+    /// If several components were packed in the same layer, they're all
+    /// rebuilt. Can be specified multiple times.
+    #[arg(long = "component", value_name = "NAME", required = true)]
+    components: Vec<String>,
+
+    #[command(flatten)]
+    build: BuildArgs,
+}
+
+pub fn run(args: &RebuildLayersArgs) -> Result<()> {
+    cmd_build::run_prepared(&args.build, |build_args, telemetry| {
+        rebuild(args, build_args, telemetry)
+    })
+}
+
+fn rebuild(args: &RebuildLayersArgs, build_args: &BuildArgs, telemetry: &Telemetry) -> Result<()> {
+    let base = BaseImage::open(&args.image).with_context(|| format!("opening {}", args.image))?;
+    let layers = layers_to_rebuild(&base.layer_names(), &args.components)?;
+
+    let created_epoch = build_args.created_epoch()?;
+    // this also keeps the unpacked rootfs image around, if any
+    let mut claimed = cmd_build::claim_rootfs(build_args, &[], created_epoch, &telemetry.bundle)?;
+
+    let components = layers
+        .iter()
+        .map(|name| {
+            let stability = base.layer_stability(name).unwrap_or(0.0);
+            let component = layer_component(name, stability, &mut claimed.components)?;
+            Ok((name.to_string(), component))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    cmd_build::new_builder(
+        build_args,
+        &claimed.rootfs,
+        claimed.overlay.clone(),
+        claimed.rewrites.clone(),
+        claimed
+            .unpacked
+            .as_ref()
+            .and_then(UnpackedImage::metadata)
+            .cloned(),
+        components,
+    )?
+    .metrics(telemetry.metrics.clone())
+    .debug_bundle(telemetry.bundle.clone())
+    .rebuild(&base, &mut build_args.open_output()?)?;
+    claimed.records.save()
+}
+
+/// Returns the names of the layers holding `components`, in layer order.
+fn layers_to_rebuild<'a>(
+    layer_names: &[Option<&'a str>],
+    components: &[String],
+) -> Result<Vec<&'a str>> {
+    let mut indices = BTreeSet::new();
+    for component in components {
+        anyhow::ensure!(
+            component != METADATA_COMPONENT,
+            "{METADATA_COMPONENT} can't be rebuilt on its own; do a full build"
+        );
+        let idx = layer_names
+            .iter()
+            .position(|name| name.is_some_and(|name| name.split(' ').any(|c| c == component)))
+            .with_context(|| format!("no layer of the image holds component {component}"))?;
+        indices.insert(idx);
+    }
+    // SAFETY: we only inserted indices of named layers
+    Ok(indices
+        .into_iter()
+        .map(|i| layer_names[i].unwrap())
+        .collect())
+}
+
+/// Merge the files of the components packed in the layer `name`, as assigned
+/// from the updated rootfs.
+fn layer_component(
+    name: &str,
+    stability: f64,
+    components: &mut HashMap<String, Component>,
+) -> Result<Component> {
+    let mut files = FileMap::new();
+    let mut packages = BTreeSet::new();
+    let mut mtime_clamp = 0u64;
+    for component in name.split(' ') {
+        let Some(component) = components.remove(component) else {
+            diagnostics::report(&[diagnostics::Diagnostic::warning(format!(
+                "component {component} of layer {name} has no files in the rootfs anymore"
+            ))]);
+            continue;
+        };
+        mtime_clamp = mtime_clamp.max(component.mtime_clamp);
+        files.extend(component.files);
+        packages.extend(component.packages);
+    }
+    anyhow::ensure!(
+        !files.is_empty(),
+        "layer {name} would be empty; do a full build to remove it"
+    );
+    Ok(Component {
+        mtime_clamp,
+        stability,
+        files,
+        packages,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layers_to_rebuild() {
+        let names = [
+            Some("rpm/bash"),
+            None,
+            Some("rpm/coreutils rpm/glibc"),
+            Some(METADATA_COMPONENT),
+        ];
+        let components = |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            layers_to_rebuild(&names, &components(&["rpm/glibc", "rpm/bash"])).unwrap(),
+            ["rpm/bash", "rpm/coreutils rpm/glibc"]
+        );
+        // components packed together are rebuilt once
+        assert_eq!(
+            layers_to_rebuild(&names, &components(&["rpm/glibc", "rpm/coreutils"])).unwrap(),
+            ["rpm/coreutils rpm/glibc"]
+        );
+        assert!(layers_to_rebuild(&names, &components(&["rpm/zsh"])).is_err());
+        assert!(layers_to_rebuild(&names, &components(&["rpm/core"])).is_err());
+        assert!(layers_to_rebuild(&names, &components(&[METADATA_COMPONENT])).is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::ambient_authority;
+use cap_std_ext::cap_std::fs::Dir;
+use clap::Parser;
+use ocidir::oci_spec::image as oci_image;
+use openssl::pkey::{PKey, Private};
+
+use crate::diagnostics::{self, Diagnostic};
+use crate::signature;
+
+#[derive(Parser)]
+pub struct SignArgs {
+    /// Path to the OCI image layout of the image to sign
+    ///
+    /// The signatures are attached to the image in place; its layers aren't
+    /// touched.
+    image: Utf8PathBuf,
+
+    /// PEM encoded private key to sign with
+    ///
+    /// Existing signatures by the same key for the same --reference are
+    /// replaced. Can be specified multiple times to add a signature per key.
+    #[arg(long = "key", value_name = "PATH", required = true)]
+    keys: Vec<Utf8PathBuf>,
+
+    /// Image reference to record in the signatures, e.g. quay.io/org/image
+    ///
+    /// This is the repository the image is pushed to, which
+    /// containers-policy.json(5) checks by default.
+    #[arg(long, value_name = "NAME", default_value = "")]
+    reference: String,
+
+    /// Remove the signatures by all other keys
+    ///
+    /// This completes a key rotation once verifiers trust the new keys.
+    #[arg(long)]
+    replace: bool,
+}
+
+pub fn run(args: &SignArgs) -> Result<()> {
+    let keys = args
+        .keys
+        .iter()
+        .map(|path| load_private_key(path).with_context(|| format!("loading key {path}")))
+        .collect::<Result<Vec<_>>>()?;
+
+    let dir = Dir::open_ambient_dir(&args.image, ambient_authority())
+        .with_context(|| format!("opening {}", args.image))?;
+    let oci_dir = ocidir::OciDir::open(dir.try_clone().context("cloning directory")?)
+        .context("opening OCI image layout")?;
+    let (image, _) = crate::utils::read_image_manifest(&oci_dir)?;
+
+    let signatures = signature::read_signatures(&oci_dir, &image).context("reading signatures")?;
+    let mut stale = Vec::new();
+    for sig in &signatures {
+        let mut by_key = false;
+        for key in &keys {
+            by_key |= sig
+                .verify(&image, &args.reference, key)
+                .context("verifying signature")?;
+        }
+        if args.replace || by_key {
+            stale.push(sig);
+        }
+    }
+    if !stale.is_empty() {
+        signature::remove_signatures(&dir, &stale).context("removing signatures")?;
+    }
+
+    let platform = match image.platform() {
+        Some(platform) => platform.clone(),
+        None => image_platform(&oci_dir, &image)?,
+    };
+    for (key, path) in keys.iter().zip(&args.keys) {
+        signature::sign(&oci_dir, &image, platform.clone(), &args.reference, key)
+            .with_context(|| format!("signing with key {path}"))?;
+        diagnostics::report(&[Diagnostic::info(format!(
+            "signed {} with key {path}",
+            image.digest()
+        ))]);
+    }
+    Ok(())
+}
+
+fn load_private_key(path: &Utf8Path) -> Result<PKey<Private>> {
+    let pem = std::fs::read(path).with_context(|| format!("reading {path}"))?;
+    PKey::private_key_from_pem(&pem).context("parsing PEM private key")
+}
+
+/// Returns the platform of the image from its config, for index entries
+/// which lack it.
+fn image_platform(
+    oci_dir: &ocidir::OciDir,
+    image: &oci_image::Descriptor,
+) -> Result<oci_image::Platform> {
+    let manifest: oci_image::ImageManifest =
+        oci_dir.read_json_blob(image).context("reading manifest")?;
+    let config: oci_image::ImageConfiguration = oci_dir
+        .read_json_blob(manifest.config())
+        .context("reading config")?;
+    oci_image::PlatformBuilder::default()
+        .os(config.os().clone())
+        .architecture(config.architecture().clone())
+        .build()
+        .context("building platform")
+}
@@ -0,0 +1,360 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use clap::Parser;
+use serde::Serialize;
+
+use crate::cmd_build::{self, BuildArgs, Telemetry};
+use crate::components::Component;
+use crate::diagnostics;
+
+#[derive(Parser)]
+pub struct SimulateArgs {
+    /// Number of update cycles to simulate
+    #[arg(long, default_value_t = 1000)]
+    cycles: usize,
+
+    /// Seed for drawing the changed components, for reproducible results
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Other --max-layers values to compare with [default: half and double]
+    #[arg(long, value_name = "N", value_delimiter = ',')]
+    compare_max_layers: Vec<usize>,
+
+    /// Output the results as JSON
+    #[arg(long)]
+    json: bool,
+
+    #[command(flatten)]
+    build: BuildArgs,
+}
+
+/// A way of packing the components into layers.
+struct Packing {
+    name: String,
+    chosen: bool,
+    layers: Vec<SimLayer>,
+}
+
+/// A layer, which is pulled again whenever one of its components changed.
+struct SimLayer {
+    size: u64,
+    /// Indices of its components.
+    components: Vec<usize>,
+}
+
+/// The bytes to pull per update with a packing.
+#[derive(Debug, PartialEq, Serialize)]
+struct PackingResult {
+    name: String,
+    chosen: bool,
+    layers: usize,
+    /// Computed from the stabilities rather than simulated.
+    expected_bytes: f64,
+    mean_bytes: f64,
+    p95_bytes: u64,
+    max_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Simulation {
+    cycles: usize,
+    seed: u64,
+    components: usize,
+    packings: Vec<PackingResult>,
+}
+
+pub fn run(args: &SimulateArgs) -> Result<()> {
+    anyhow::ensure!(args.cycles > 0, "--cycles must be greater than 0");
+    cmd_build::run_prepared(&args.build, |build_args, telemetry| {
+        let simulation = simulate_rootfs(args, build_args, telemetry)?;
+        if args.json {
+            let json =
+                serde_json::to_string_pretty(&simulation).context("serializing simulation")?;
+            println!("{json}");
+        } else {
+            print!("{}", format_simulation(&simulation));
+        }
+        Ok(())
+    })
+}
+
+/// Claim the rootfs like a build would, pack it with each `--max-layers`
+/// value and simulate updates of the components.
+fn simulate_rootfs(
+    args: &SimulateArgs,
+    build_args: &BuildArgs,
+    telemetry: &Telemetry,
+) -> Result<Simulation> {
+    let created_epoch = build_args.created_epoch()?;
+    // this also keeps the unpacked rootfs image around, if any
+    let claimed = cmd_build::claim_rootfs(build_args, &[], created_epoch, &telemetry.bundle)?;
+    let mut components: Vec<(String, Component)> = claimed
+        .components
+        .iter()
+        .map(|(name, component)| (name.clone(), component.clone()))
+        .collect();
+    // sort for the same draws on every run with the same seed
+    components.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let chosen = build_args.max_layers();
+    let mut max_layers_values: BTreeSet<usize> = if args.compare_max_layers.is_empty() {
+        [chosen / 2, chosen * 2].into_iter().collect()
+    } else {
+        args.compare_max_layers.iter().copied().collect()
+    };
+    max_layers_values.insert(chosen);
+
+    let mut packings = Vec::new();
+    for max_layers in max_layers_values {
+        let packed = match cmd_build::pack_components(
+            &build_args.with_max_layers(max_layers),
+            claimed.components.clone(),
+            0,
+        ) {
+            Ok(packed) => packed,
+            // e.g. too few layers for the pinned components
+            Err(e) if max_layers != chosen => {
+                diagnostics::report(&[diagnostics::Diagnostic::warning(format!(
+                    "skipping --max-layers {max_layers}: {e:#}"
+                ))]);
+                continue;
+            }
+            Err(e) => return Err(e.context("packing components")),
+        };
+        packings.push(Packing {
+            name: format!("--max-layers {max_layers}"),
+            chosen: max_layers == chosen,
+            layers: sim_layers(&components, &packed),
+        });
+    }
+    packings.push(Packing {
+        name: "one layer per component".into(),
+        chosen: false,
+        layers: sim_layers(&components, &components),
+    });
+
+    let change_probs: Vec<f64> = components
+        .iter()
+        .map(|(_, c)| (1.0 - c.stability).clamp(0.0, 1.0))
+        .collect();
+    Ok(Simulation {
+        cycles: args.cycles,
+        seed: args.seed,
+        components: components.len(),
+        packings: simulate(&change_probs, &packings, args.cycles, args.seed),
+    })
+}
+
+/// Map the packed layers to the components of their files.
+fn sim_layers(components: &[(String, Component)], layers: &[(String, Component)]) -> Vec<SimLayer> {
+    let component_of: HashMap<&Utf8Path, usize> = components
+        .iter()
+        .enumerate()
+        .flat_map(|(i, (_, c))| c.files.keys().map(move |path| (path.as_path(), i)))
+        .collect();
+    layers
+        .iter()
+        .map(|(_, layer)| SimLayer {
+            size: layer.files.values().map(|f| f.size).sum(),
+            components: layer
+                .files
+                .keys()
+                .filter_map(|path| component_of.get(path.as_path()).copied())
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect(),
+        })
+        .collect()
+}
+
+/// Simulate `cycles` updates, in which each component changes with its
+/// probability in `change_probs`. All the packings see the same updates.
+fn simulate(
+    change_probs: &[f64],
+    packings: &[Packing],
+    cycles: usize,
+    seed: u64,
+) -> Vec<PackingResult> {
+    let mut rng = Rng(seed);
+    let mut pulled: Vec<Vec<u64>> = vec![Vec::with_capacity(cycles); packings.len()];
+    let mut changed = vec![false; change_probs.len()];
+    for _ in 0..cycles {
+        for (changed, p) in changed.iter_mut().zip(change_probs) {
+            *changed = rng.next_f64() < *p;
+        }
+        for (packing, pulled) in packings.iter().zip(&mut pulled) {
+            let bytes = packing
+                .layers
+                .iter()
+                .filter(|layer| layer.components.iter().any(|&i| changed[i]))
+                .map(|layer| layer.size)
+                .sum();
+            pulled.push(bytes);
+        }
+    }
+
+    packings
+        .iter()
+        .zip(pulled)
+        .map(|(packing, mut pulled)| {
+            pulled.sort_unstable();
+            let p95 = pulled[(pulled.len() * 95).div_ceil(100).saturating_sub(1)];
+            PackingResult {
+                name: packing.name.clone(),
+                chosen: packing.chosen,
+                layers: packing.layers.len(),
+                expected_bytes: expected_bytes(change_probs, &packing.layers),
+                mean_bytes: pulled.iter().sum::<u64>() as f64 / pulled.len() as f64,
+                p95_bytes: p95,
+                max_bytes: pulled.last().copied().unwrap_or_default(),
+            }
+        })
+        .collect()
+}
+
+/// The expected bytes to pull per update: each layer is pulled unless none
+/// of its components changed.
+fn expected_bytes(change_probs: &[f64], layers: &[SimLayer]) -> f64 {
+    layers
+        .iter()
+        .map(|layer| {
+            let unchanged: f64 = layer
+                .components
+                .iter()
+                .map(|&i| 1.0 - change_probs[i])
+                .product();
+            layer.size as f64 * (1.0 - unchanged)
+        })
+        .sum()
+}
+
+/// A splitmix64 generator, which is all the randomness needed to draw
+/// updates, and reproducible across platforms.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn format_simulation(simulation: &Simulation) -> String {
+    let names: Vec<String> = simulation
+        .packings
+        .iter()
+        .map(|p| {
+            if p.chosen {
+                format!("{} (chosen)", p.name)
+            } else {
+                p.name.clone()
+            }
+        })
+        .collect();
+    let width = names
+        .iter()
+        .map(String::len)
+        .chain(["PACKING".len()])
+        .max()
+        .unwrap_or_default();
+    let mut out = format!(
+        "{} updates of {} components (seed {})\n",
+        simulation.cycles, simulation.components, simulation.seed
+    );
+    out.push_str(&format!(
+        "{:<width$} {:>6} {:>12} {:>12} {:>12} {:>12}\n",
+        "PACKING", "LAYERS", "EXPECTED", "MEAN", "P95", "MAX"
+    ));
+    for (name, packing) in names.iter().zip(&simulation.packings) {
+        out.push_str(&format!(
+            "{name:<width$} {:>6} {:>12.0} {:>12.0} {:>12} {:>12}\n",
+            packing.layers,
+            packing.expected_bytes,
+            packing.mean_bytes,
+            packing.p95_bytes,
+            packing.max_bytes,
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packing(name: &str, layers: &[(u64, &[usize])]) -> Packing {
+        Packing {
+            name: name.into(),
+            chosen: false,
+            layers: layers
+                .iter()
+                .map(|(size, components)| SimLayer {
+                    size: *size,
+                    components: components.to_vec(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_simulate() {
+        // a component which never changes, one which always does, and one
+        // which changes half of the time
+        let change_probs = [0.0, 1.0, 0.5];
+        let packings = [
+            packing("one", &[(600, &[0, 1, 2])]),
+            packing("two", &[(100, &[0, 2]), (500, &[1])]),
+            packing("three", &[(100, &[0]), (200, &[1]), (300, &[2])]),
+        ];
+
+        let results = simulate(&change_probs, &packings, 1000, 42);
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].expected_bytes, 600.0);
+        assert_eq!(results[0].mean_bytes, 600.0);
+        assert_eq!(results[0].p95_bytes, 600);
+        assert_eq!(results[1].expected_bytes, 550.0);
+        assert_eq!(results[1].max_bytes, 600);
+        assert_eq!(results[2].expected_bytes, 350.0);
+        assert_eq!(results[2].p95_bytes, 500);
+        for result in &results[1..] {
+            assert!((result.mean_bytes - result.expected_bytes).abs() < 25.0);
+        }
+
+        // the same seed draws the same updates
+        assert_eq!(simulate(&change_probs, &packings, 1000, 42), results);
+
+        let out = format_simulation(&Simulation {
+            cycles: 1000,
+            seed: 42,
+            components: 3,
+            packings: results,
+        });
+        assert!(out.starts_with(
+            "1000 updates of 3 components (seed 42)\n\
+             PACKING LAYERS     EXPECTED"
+        ));
+        assert!(
+            out.contains("one          1          600          600          600          600\n")
+        );
+    }
+
+    #[test]
+    fn test_rng() {
+        let mut rng = Rng(0);
+        let draws: Vec<f64> = (0..1000).map(|_| rng.next_f64()).collect();
+        assert!(draws.iter().all(|x| (0.0..1.0).contains(x)));
+        let mean = draws.iter().sum::<f64>() / draws.len() as f64;
+        assert!((mean - 0.5).abs() < 0.05);
+    }
+}
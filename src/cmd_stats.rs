@@ -0,0 +1,405 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::Parser;
+use ocidir::oci_spec::image as oci_image;
+use openssl::hash::{Hasher, MessageDigest};
+use serde::Serialize;
+
+use crate::cmd_build::{self, BuildArgs};
+use crate::cmd_inspect::OpenedImage;
+use crate::components::Component;
+use crate::debug_bundle::DebugBundle;
+use crate::ocibuilder::unpack_layers;
+
+/// Upper bounds of the layer size buckets, in bytes.
+const SIZE_BUCKETS: &[(u64, &str)] = &[
+    (1 << 20, "< 1 MiB"),
+    (10 << 20, "< 10 MiB"),
+    (100 << 20, "< 100 MiB"),
+    (1 << 30, "< 1 GiB"),
+    (u64::MAX, ">= 1 GiB"),
+];
+
+#[derive(Parser)]
+pub struct StatsArgs {
+    /// Path to the OCI image layout or OCI archive of the image
+    ///
+    /// It doesn't need to be built by chunkah.
+    image: Utf8PathBuf,
+
+    /// Project how much rechunking the image with chunkah would save
+    ///
+    /// The image is extracted into a temporary directory, whose files are
+    /// claimed and packed like `chunkah build` would. The bytes to pull per
+    /// update are then estimated from the stability of the components in
+    /// each layer, for the image as is and rechunked.
+    #[arg(long)]
+    project: bool,
+
+    /// Maximum number of layers of the rechunked image, for --project
+    #[arg(long, value_name = "N", requires = "project")]
+    max_layers: Option<usize>,
+
+    /// Output the statistics as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+/// A layer of the image, as read from its blob.
+#[derive(Debug)]
+struct LayerContent {
+    /// Size of the blob.
+    size: u64,
+    /// Regular files with their size and content digest.
+    files: Vec<(Utf8PathBuf, u64, String)>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct SizeDistribution {
+    min: u64,
+    median: u64,
+    max: u64,
+    /// Number of layers per size bucket.
+    buckets: Vec<(&'static str, usize)>,
+}
+
+/// Content found in more than one layer.
+#[derive(Debug, PartialEq, Serialize)]
+struct Duplicates {
+    /// Number of copies beyond the first of each file.
+    files: usize,
+    /// Bytes of the copies beyond the first of each file.
+    bytes: u64,
+}
+
+/// Expected bytes to pull per update, as is and rechunked.
+#[derive(Debug, PartialEq, Serialize)]
+struct Projection {
+    current: f64,
+    rechunked: f64,
+    rechunked_layers: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct Stats {
+    layers: usize,
+    size: u64,
+    uncompressed_size: u64,
+    sizes: SizeDistribution,
+    duplicates: Duplicates,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    projection: Option<Projection>,
+}
+
+pub fn run(args: &StatsArgs) -> Result<()> {
+    let image =
+        OpenedImage::open(&args.image).with_context(|| format!("reading {}", args.image))?;
+    let layers = image
+        .manifest
+        .layers()
+        .iter()
+        .enumerate()
+        .map(|(i, layer)| {
+            read_layer(&image.oci_dir, layer).with_context(|| format!("reading layer {i}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut stats = stats(&layers);
+    if args.project {
+        stats.projection = Some(project(args, &image, &layers)?);
+    }
+
+    if args.json {
+        let json = serde_json::to_string_pretty(&stats).context("serializing stats")?;
+        println!("{json}");
+    } else {
+        print!("{}", format_stats(&stats));
+    }
+    Ok(())
+}
+
+/// Read the regular files of `layer`, hashing their content.
+fn read_layer(oci_dir: &ocidir::OciDir, layer: &oci_image::Descriptor) -> Result<LayerContent> {
+    anyhow::ensure!(
+        crate::tar::layer_is_gzip(layer.media_type()).is_some(),
+        "unsupported media type {}",
+        layer.media_type()
+    );
+    let reader = crate::tar::read_layer(oci_dir, layer)?;
+    let mut archive = tar::Archive::new(reader);
+    let mut files = Vec::new();
+    for entry in archive.entries().context("reading entries")? {
+        let mut entry = entry.context("reading entry")?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        let path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        let path = Utf8PathBuf::from(format!("/{}", path.trim_matches('/')));
+        let mut hasher = Hasher::new(MessageDigest::sha256())?;
+        let size =
+            std::io::copy(&mut entry, &mut hasher).with_context(|| format!("reading {path}"))?;
+        files.push((path, size, hex::encode(hasher.finish()?)));
+    }
+    Ok(LayerContent {
+        size: layer.size(),
+        files,
+    })
+}
+
+fn stats(layers: &[LayerContent]) -> Stats {
+    let mut sizes: Vec<u64> = layers.iter().map(|layer| layer.size).collect();
+    sizes.sort_unstable();
+    let buckets = SIZE_BUCKETS
+        .iter()
+        .enumerate()
+        .map(|(i, &(bound, label))| {
+            let lower = if i == 0 { 0 } else { SIZE_BUCKETS[i - 1].0 };
+            let count = sizes.iter().filter(|&&s| s >= lower && s < bound).count();
+            (label, count)
+        })
+        .collect();
+
+    // content digest -> size and layers with it
+    let mut contents: HashMap<&str, (u64, BTreeSet<usize>)> = HashMap::new();
+    for (i, layer) in layers.iter().enumerate() {
+        for (_, size, digest) in &layer.files {
+            if *size > 0 {
+                contents
+                    .entry(digest.as_str())
+                    .or_insert((*size, BTreeSet::new()))
+                    .1
+                    .insert(i);
+            }
+        }
+    }
+    let mut duplicates = Duplicates { files: 0, bytes: 0 };
+    for (size, in_layers) in contents.values() {
+        let copies = in_layers.len() - 1;
+        duplicates.files += copies;
+        duplicates.bytes += size * copies as u64;
+    }
+
+    Stats {
+        layers: layers.len(),
+        size: sizes.iter().sum(),
+        uncompressed_size: layers
+            .iter()
+            .flat_map(|layer| layer.files.iter().map(|(_, size, _)| size))
+            .sum(),
+        sizes: SizeDistribution {
+            min: sizes.first().copied().unwrap_or_default(),
+            median: sizes.get(sizes.len() / 2).copied().unwrap_or_default(),
+            max: sizes.last().copied().unwrap_or_default(),
+            buckets,
+        },
+        duplicates,
+        projection: None,
+    }
+}
+
+/// Extract the image, claim and pack its files, and compare the expected
+/// bytes to pull per update of the image as is and rechunked.
+fn project(args: &StatsArgs, image: &OpenedImage, layers: &[LayerContent]) -> Result<Projection> {
+    let mut builder = tempfile::Builder::new();
+    builder.prefix("chunkah-stats-");
+    let tmpdir = builder.tempdir().context("creating temporary directory")?;
+    let rootfs =
+        Utf8Path::from_path(tmpdir.path()).context("temporary directory path is not UTF-8")?;
+    unpack_layers(&image.oci_dir, image.manifest.layers(), rootfs)
+        .with_context(|| format!("extracting {}", args.image))?;
+
+    let mut build_args = vec![
+        "chunkah build".to_string(),
+        "--rootfs".to_string(),
+        rootfs.to_string(),
+    ];
+    if let Some(max_layers) = args.max_layers {
+        build_args.extend(["--max-layers".to_string(), max_layers.to_string()]);
+    }
+    let build_args = BuildArgs::try_parse_from(build_args).context("parsing build options")?;
+    let claimed = cmd_build::claim_rootfs(
+        &build_args,
+        &[],
+        build_args.created_epoch()?,
+        &DebugBundle::default(),
+    )?;
+    let current = current_layers(layers, &claimed.components);
+
+    let packed = cmd_build::pack_components(&build_args, claimed.components, 0)
+        .context("packing components")?;
+    let rechunked: Vec<(u64, f64)> = packed
+        .iter()
+        .map(|(_, component)| {
+            let size = component.files.values().map(|f| f.size).sum();
+            (size, component.stability)
+        })
+        .collect();
+
+    Ok(Projection {
+        current: expected_pull(&current),
+        rechunked: expected_pull(&rechunked),
+        rechunked_layers: rechunked.len(),
+    })
+}
+
+/// Returns the uncompressed size and stability of the layers of the image as
+/// is. A layer changes when any of the components with files in it changes.
+fn current_layers(
+    layers: &[LayerContent],
+    components: &HashMap<String, Component>,
+) -> Vec<(u64, f64)> {
+    let component_of: HashMap<&Utf8Path, &str> = components
+        .iter()
+        .flat_map(|(name, component)| {
+            component
+                .files
+                .keys()
+                .map(move |path| (path.as_path(), name.as_str()))
+        })
+        .collect();
+    layers
+        .iter()
+        .map(|layer| {
+            let names: BTreeSet<&str> = layer
+                .files
+                .iter()
+                .filter_map(|(path, _, _)| component_of.get(path.as_path()).copied())
+                .collect();
+            let stability = names
+                .iter()
+                .map(|name| components[*name].stability)
+                .product();
+            let size = layer.files.iter().map(|(_, size, _)| size).sum();
+            (size, stability)
+        })
+        .collect()
+}
+
+/// Returns the expected bytes to pull per update of layers with the given
+/// size and stability, i.e. probability not to change.
+fn expected_pull(layers: &[(u64, f64)]) -> f64 {
+    layers
+        .iter()
+        .map(|&(size, stability)| size as f64 * (1.0 - stability))
+        .sum()
+}
+
+fn format_stats(stats: &Stats) -> String {
+    let mut out = format!(
+        "{} layers, {} bytes ({} bytes uncompressed)\n",
+        stats.layers, stats.size, stats.uncompressed_size
+    );
+    out.push_str(&format!(
+        "layer sizes: min {}, median {}, max {}\n",
+        stats.sizes.min, stats.sizes.median, stats.sizes.max
+    ));
+    for (label, count) in &stats.sizes.buckets {
+        out.push_str(&format!("  {label:>9}: {count}\n"));
+    }
+    out.push_str(&format!(
+        "duplicates: {} files, {} bytes in more than one layer\n",
+        stats.duplicates.files, stats.duplicates.bytes
+    ));
+    if let Some(projection) = &stats.projection {
+        let saved = if projection.current > 0.0 {
+            (1.0 - projection.rechunked / projection.current) * 100.0
+        } else {
+            0.0
+        };
+        out.push_str(&format!(
+            "expected bytes to pull per update: {:.0} as is, {:.0} rechunked in {} layers ({saved:.1}% less)\n",
+            projection.current, projection.rechunked, projection.rechunked_layers
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{FileInfo, FileMap, FileType};
+
+    fn layer(size: u64, files: &[(&str, u64, &str)]) -> LayerContent {
+        LayerContent {
+            size,
+            files: files
+                .iter()
+                .map(|(path, size, digest)| (Utf8PathBuf::from(*path), *size, digest.to_string()))
+                .collect(),
+        }
+    }
+
+    fn component(paths: &[&str], stability: f64) -> Component {
+        let files: FileMap = paths
+            .iter()
+            .map(|p| {
+                let info = FileInfo::test_file(FileType::File, 0, 1);
+                (Utf8PathBuf::from(*p), info)
+            })
+            .collect();
+        Component {
+            mtime_clamp: 1,
+            stability,
+            files,
+            packages: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let layers = [
+            layer(
+                500 << 20,
+                &[("/usr/bin/bash", 100, "a"), ("/usr/bin/ls", 50, "b")],
+            ),
+            layer(
+                2 << 20,
+                &[("/usr/bin/bash", 100, "a"), ("/etc/empty", 0, "e")],
+            ),
+            layer(3 << 20, &[("/opt/bash", 100, "a"), ("/etc/other", 0, "e")]),
+        ];
+        let stats = stats(&layers);
+        assert_eq!(stats.size, 505 << 20);
+        assert_eq!(stats.uncompressed_size, 350);
+        assert_eq!(
+            stats.sizes,
+            SizeDistribution {
+                min: 2 << 20,
+                median: 3 << 20,
+                max: 500 << 20,
+                buckets: vec![
+                    ("< 1 MiB", 0),
+                    ("< 10 MiB", 2),
+                    ("< 100 MiB", 0),
+                    ("< 1 GiB", 1),
+                    (">= 1 GiB", 0),
+                ],
+            }
+        );
+        // bash has two more copies; empty files don't count
+        assert_eq!(
+            stats.duplicates,
+            Duplicates {
+                files: 2,
+                bytes: 200
+            }
+        );
+        let out = format_stats(&stats);
+        assert!(out.contains("\n   < 10 MiB: 2\n"));
+        assert!(out.ends_with("duplicates: 2 files, 200 bytes in more than one layer\n"));
+
+        let components = HashMap::from([
+            ("bash".to_string(), component(&["/usr/bin/bash"], 0.5)),
+            ("coreutils".to_string(), component(&["/usr/bin/ls"], 0.8)),
+        ]);
+        let current = current_layers(&layers[..1], &components);
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0].0, 150);
+        assert!((current[0].1 - 0.4).abs() < 1e-9);
+        assert!((expected_pull(&current) - 90.0).abs() < 1e-9);
+        // bash and coreutils apart only pull what changed
+        assert!((expected_pull(&[(100, 0.5), (50, 0.8)]) - 60.0).abs() < 1e-9);
+    }
+}
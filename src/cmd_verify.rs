@@ -0,0 +1,526 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::Dir;
+use clap::Parser;
+use ocidir::oci_spec::image as oci_image;
+use openssl::hash::{Hasher, MessageDigest};
+
+use crate::cmd_build::{self, BuildArgs, ScannedRootfs, Telemetry};
+use crate::cmd_inspect::OpenedImage;
+use crate::components::layers::{OPAQUE_WHITEOUT, WHITEOUT_PREFIX};
+use crate::components::{FileInfo, FileMap, FileType};
+use crate::ocibuilder::{METADATA_COMPONENT, layer_component};
+use crate::overlay::{entry_path, tar_entries};
+
+#[derive(Parser)]
+pub struct VerifyArgs {
+    /// Path to the OCI image layout or OCI archive built from the rootfs
+    image: Utf8PathBuf,
+
+    #[command(flatten)]
+    build: BuildArgs,
+}
+
+/// A path, as in the rootfs or once all the layers are applied.
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    file_type: FileType,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: u64,
+    xattrs: Vec<(String, Vec<u8>)>,
+    /// The content as in inputs digests (see `src/attest.rs`), if known.
+    content: Option<String>,
+    /// The first path of the hardlinks to the same file, if there are others.
+    link_group: Option<Utf8PathBuf>,
+}
+
+/// A path of a layer, which may be a hardlink to another one.
+struct LayerEntry {
+    entry: Entry,
+    hardlink: Option<Utf8PathBuf>,
+}
+
+pub fn run(args: &VerifyArgs) -> Result<()> {
+    cmd_build::run_prepared(&args.build, |build_args, telemetry| {
+        verify(args, build_args, telemetry)
+    })
+}
+
+fn verify(args: &VerifyArgs, build_args: &BuildArgs, telemetry: &Telemetry) -> Result<()> {
+    let image =
+        OpenedImage::open(&args.image).with_context(|| format!("reading {}", args.image))?;
+    let applied = apply_layers(&image.oci_dir, image.manifest.layers())
+        .with_context(|| format!("applying the layers of {}", args.image))?;
+
+    let created_epoch = build_args.created_epoch()?;
+    let ScannedRootfs {
+        // keep the unpacked rootfs image around, if any
+        unpacked: _unpacked,
+        rootfs,
+        files,
+        overlay_components,
+        ..
+    } = cmd_build::scan_rootfs(build_args, created_epoch, &telemetry.bundle)?;
+    let mut rootfs_entries =
+        rootfs_entries(&rootfs, &files, !build_args.normalizes()).context("reading rootfs")?;
+    // the content of files from tarballs isn't in the rootfs
+    let overlay_files: FileMap = overlay_components
+        .into_iter()
+        .flat_map(|(_, component)| component.files)
+        .collect();
+    rootfs_entries.extend(entries(&overlay_files, |_, _| Ok(None))?);
+
+    let problems = compare(&rootfs_entries, &applied);
+    if !problems.is_empty() {
+        anyhow::bail!(
+            "image doesn't match the rootfs:\n{}",
+            problems
+                .iter()
+                .map(|p| format!("  - {p}"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+    }
+    println!(
+        "{} paths in {} layers match the rootfs",
+        applied.len(),
+        image.manifest.layers().len()
+    );
+    Ok(())
+}
+
+/// Apply the layers in order, like a container runtime would, except for
+/// the chunkah metadata layer.
+fn apply_layers(
+    oci_dir: &ocidir::OciDir,
+    layers: &[oci_image::Descriptor],
+) -> Result<BTreeMap<Utf8PathBuf, Entry>> {
+    let mut applied: BTreeMap<Utf8PathBuf, LayerEntry> = BTreeMap::new();
+    for (i, layer) in layers.iter().enumerate() {
+        if layer_component(layer) == Some(METADATA_COMPONENT) {
+            continue;
+        }
+        anyhow::ensure!(
+            crate::tar::layer_is_gzip(layer.media_type()).is_some(),
+            "layer {i}: unsupported media type {}",
+            layer.media_type()
+        );
+        let reader =
+            crate::tar::read_layer(oci_dir, layer).with_context(|| format!("opening layer {i}"))?;
+        apply_layer(reader, &mut applied).with_context(|| format!("reading layer {i}"))?;
+    }
+    resolve_hardlinks(applied)
+}
+
+/// Apply the uncompressed layer `reader` to `applied`. Whiteouts only apply
+/// to lower layers, regardless of where they are in the tarball.
+fn apply_layer<R: Read>(reader: R, applied: &mut BTreeMap<Utf8PathBuf, LayerEntry>) -> Result<()> {
+    let mut whiteouts = Vec::new();
+    let mut entries = Vec::new();
+    let mut archive = tar::Archive::new(reader);
+    for entry in tar_entries(&mut archive)? {
+        let mut entry = entry?;
+        let path = entry_path(entry.path())?;
+        if let Some(name) = path.file_name()
+            && name.starts_with(WHITEOUT_PREFIX)
+        {
+            whiteouts.push(path);
+            continue;
+        }
+        let header = entry.header().clone();
+        let entry_type = header.entry_type();
+        let file_type = match entry_type {
+            tar::EntryType::Directory => FileType::Directory,
+            tar::EntryType::Regular | tar::EntryType::Link => FileType::File,
+            tar::EntryType::Symlink => FileType::Symlink,
+            tar::EntryType::Block => FileType::BlockDevice,
+            tar::EntryType::Char => FileType::CharDevice,
+            tar::EntryType::Fifo => FileType::Fifo,
+            other => anyhow::bail!("{path}: unsupported entry type {other:?}"),
+        };
+        let mode = header.mode().context("reading mode")?;
+        let uid = entry.uid()?;
+        let gid = entry.gid()?;
+        let mtime = header.mtime().context("reading mtime")?;
+        let hardlink = match entry_type {
+            tar::EntryType::Link => {
+                let target = entry
+                    .link_name()
+                    .with_context(|| format!("hardlink {path} has no target"))?;
+                Some(entry_path(target)?)
+            }
+            _ => None,
+        };
+        let xattrs = entry.xattrs();
+        let content = match entry_type {
+            tar::EntryType::Directory => Some("dir".to_string()),
+            tar::EntryType::Symlink => {
+                let target = entry
+                    .link_name()
+                    .with_context(|| format!("symlink {path} has no target"))?;
+                let target = target
+                    .to_str()
+                    .with_context(|| format!("symlink {path} target is not valid UTF-8"))?;
+                Some(format!("symlink:{target}"))
+            }
+            tar::EntryType::Link => None,
+            tar::EntryType::Block | tar::EntryType::Char => {
+                let major = header.device_major().context("reading device major")?;
+                let minor = header.device_minor().context("reading device minor")?;
+                Some(format!(
+                    "device:{}:{}",
+                    major.unwrap_or(0),
+                    minor.unwrap_or(0)
+                ))
+            }
+            tar::EntryType::Fifo => Some("fifo".to_string()),
+            _ => Some(content_digest(&mut entry).with_context(|| format!("reading {path}"))?),
+        };
+        let entry = Entry {
+            file_type,
+            mode: mode & 0o7777,
+            uid,
+            gid,
+            mtime,
+            xattrs,
+            content,
+            link_group: None,
+        };
+        entries.push((path, LayerEntry { entry, hardlink }));
+    }
+
+    for path in whiteouts {
+        // SAFETY: whiteouts have a file name, and so a parent
+        let parent = path.parent().unwrap();
+        let name = path.file_name().unwrap();
+        if name == OPAQUE_WHITEOUT {
+            remove_tree(applied, parent, false);
+        } else {
+            let target = parent.join(&name[WHITEOUT_PREFIX.len()..]);
+            remove_tree(applied, &target, true);
+        }
+    }
+    applied.extend(entries);
+    Ok(())
+}
+
+/// Remove the paths under `path` from `applied`, and `path` itself if `self_too`.
+fn remove_tree(applied: &mut BTreeMap<Utf8PathBuf, LayerEntry>, path: &Utf8Path, self_too: bool) {
+    applied.retain(|p, _| !(p.starts_with(path) && (self_too || p != path)));
+}
+
+/// Give hardlinks the metadata and content of their target, and group them.
+fn resolve_hardlinks(
+    applied: BTreeMap<Utf8PathBuf, LayerEntry>,
+) -> Result<BTreeMap<Utf8PathBuf, Entry>> {
+    let mut groups: HashMap<Utf8PathBuf, Vec<Utf8PathBuf>> = HashMap::new();
+    for (path, layer_entry) in &applied {
+        if let Some(target) = &layer_entry.hardlink {
+            let target_entry = applied
+                .get(target)
+                .with_context(|| format!("hardlink {path} points to missing {target}"))?;
+            anyhow::ensure!(
+                target_entry.hardlink.is_none(),
+                "hardlink {path} points to hardlink {target}"
+            );
+            groups
+                .entry(target.clone())
+                .or_insert_with(|| vec![target.clone()])
+                .push(path.clone());
+        }
+    }
+    let mut leaders: HashMap<Utf8PathBuf, Utf8PathBuf> = HashMap::new();
+    for paths in groups.into_values() {
+        // SAFETY: groups have at least their target
+        let leader = paths.iter().min().unwrap().clone();
+        for path in paths {
+            leaders.insert(path, leader.clone());
+        }
+    }
+
+    Ok(applied
+        .iter()
+        .map(|(path, layer_entry)| {
+            let mut entry = match &layer_entry.hardlink {
+                // SAFETY: we checked that targets exist above
+                Some(target) => applied[target].entry.clone(),
+                None => layer_entry.entry.clone(),
+            };
+            entry.link_group = leaders.get(path).cloned();
+            (path.clone(), entry)
+        })
+        .collect())
+}
+
+/// Returns the entries of the files of the rootfs, reading their content if
+/// `with_content` is set.
+fn rootfs_entries(
+    rootfs: &Dir,
+    files: &FileMap,
+    with_content: bool,
+) -> Result<BTreeMap<Utf8PathBuf, Entry>> {
+    entries(files, |path, info| {
+        let rel_path = info.fs_path(path);
+        Ok(match info.file_type {
+            FileType::Directory => Some("dir".to_string()),
+            FileType::Symlink => {
+                let target = rootfs
+                    .read_link_contents(rel_path)
+                    .with_context(|| format!("reading symlink target for {path}"))?;
+                let target = Utf8PathBuf::from_path_buf(target)
+                    .map_err(|_| anyhow::anyhow!("symlink {path} target is not valid UTF-8"))?;
+                Some(format!("symlink:{target}"))
+            }
+            FileType::File if with_content => {
+                let mut file = rootfs
+                    .open(rel_path)
+                    .with_context(|| format!("opening {path}"))?;
+                Some(content_digest(&mut file).with_context(|| format!("reading {path}"))?)
+            }
+            FileType::File => None,
+            FileType::BlockDevice | FileType::CharDevice => Some(format!(
+                "device:{}:{}",
+                libc::major(info.rdev()),
+                libc::minor(info.rdev())
+            )),
+            FileType::Fifo => Some("fifo".to_string()),
+        })
+    })
+}
+
+/// Returns the entries of `files`, with the content given by `content`.
+fn entries<F>(files: &FileMap, mut content: F) -> Result<BTreeMap<Utf8PathBuf, Entry>>
+where
+    F: FnMut(&Utf8Path, &FileInfo) -> Result<Option<String>>,
+{
+    let mut inodes: HashMap<u64, Vec<&Utf8Path>> = HashMap::new();
+    for (path, info) in files {
+        if info.file_type == FileType::File && info.nlink > 1 {
+            inodes.entry(info.ino).or_default().push(path);
+        }
+    }
+    let mut leaders: HashMap<&Utf8Path, &Utf8Path> = HashMap::new();
+    for paths in inodes.values().filter(|paths| paths.len() > 1) {
+        // files are sorted by path, so the first one is the smallest
+        for &path in paths {
+            leaders.insert(path, paths[0]);
+        }
+    }
+
+    files
+        .iter()
+        .map(|(path, info)| {
+            let entry = Entry {
+                file_type: info.file_type,
+                mode: info.mode & 0o7777,
+                uid: info.uid,
+                gid: info.gid,
+                mtime: info.mtime,
+                xattrs: info.xattrs.to_vec(),
+                content: content(path.as_path(), info)?,
+                link_group: leaders.get(path.as_path()).map(|p| p.to_path_buf()),
+            };
+            Ok((path.clone(), entry))
+        })
+        .collect()
+}
+
+/// Returns the digest of `reader`, as in inputs digests.
+fn content_digest<R: Read>(reader: &mut R) -> Result<String> {
+    let mut hasher = Hasher::new(MessageDigest::sha256())?;
+    std::io::copy(reader, &mut hasher)?;
+    Ok(format!("sha256:{}", hex::encode(hasher.finish()?)))
+}
+
+/// Compare the rootfs with the applied layers. All differences are returned.
+///
+/// Mtimes are clamped when building, so they're only checked not to be newer
+/// than in the rootfs. The root directory isn't compared, since container
+/// runtimes don't apply it.
+fn compare(
+    rootfs: &BTreeMap<Utf8PathBuf, Entry>,
+    applied: &BTreeMap<Utf8PathBuf, Entry>,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (path, expected) in rootfs {
+        if path == "/" {
+            continue;
+        }
+        let Some(actual) = applied.get(path) else {
+            problems.push(format!("{path}: missing from the image"));
+            continue;
+        };
+        if expected.file_type != actual.file_type {
+            problems.push(format!(
+                "{path}: type {:?} instead of {:?}",
+                actual.file_type, expected.file_type
+            ));
+            continue;
+        }
+        if expected.mode != actual.mode {
+            problems.push(format!(
+                "{path}: mode {:o} instead of {:o}",
+                actual.mode, expected.mode
+            ));
+        }
+        if (expected.uid, expected.gid) != (actual.uid, actual.gid) {
+            problems.push(format!(
+                "{path}: owner {}:{} instead of {}:{}",
+                actual.uid, actual.gid, expected.uid, expected.gid
+            ));
+        }
+        if actual.mtime > expected.mtime {
+            problems.push(format!(
+                "{path}: mtime {} is newer than {}",
+                actual.mtime, expected.mtime
+            ));
+        }
+        let mut expected_xattrs = expected.xattrs.clone();
+        let mut actual_xattrs = actual.xattrs.clone();
+        expected_xattrs.sort();
+        actual_xattrs.sort();
+        if expected_xattrs != actual_xattrs {
+            problems.push(format!("{path}: xattrs don't match"));
+        }
+        if let (Some(expected), Some(actual)) = (&expected.content, &actual.content)
+            && expected != actual
+        {
+            problems.push(format!("{path}: content {actual} instead of {expected}"));
+        }
+        if expected.link_group != actual.link_group {
+            let group = |group: &Option<Utf8PathBuf>| match group {
+                Some(leader) => format!("hardlinked to {leader}"),
+                None => "not hardlinked".to_string(),
+            };
+            problems.push(format!(
+                "{path}: {} instead of {}",
+                group(&actual.link_group),
+                group(&expected.link_group)
+            ));
+        }
+    }
+    for path in applied.keys() {
+        if path != "/" && !rootfs.contains_key(path) {
+            problems.push(format!("{path}: not in the rootfs"));
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn append(
+        builder: &mut tar::Builder<Vec<u8>>,
+        entry_type: tar::EntryType,
+        path: &str,
+        content: &[u8],
+        target: Option<&str>,
+    ) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(100);
+        header.set_size(content.len() as u64);
+        match target {
+            Some(target) => builder.append_link(&mut header, path, target).unwrap(),
+            None => builder.append_data(&mut header, path, content).unwrap(),
+        }
+    }
+
+    fn layer(entries: &[(tar::EntryType, &str, &[u8], Option<&str>)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (entry_type, path, content, target) in entries {
+            append(&mut builder, *entry_type, path, content, *target);
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn entry(file_type: FileType, content: &str) -> Entry {
+        Entry {
+            file_type,
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            mtime: 200,
+            xattrs: Vec::new(),
+            content: Some(content.to_string()),
+            link_group: None,
+        }
+    }
+
+    #[test]
+    fn test_apply_and_compare() {
+        use tar::EntryType::{Directory, Link, Regular, Symlink};
+        let digest = |content: &[u8]| content_digest(&mut &content[..]).unwrap();
+
+        let mut applied = BTreeMap::new();
+        let lower = layer(&[
+            (Directory, "usr", b"", None),
+            (Regular, "usr/old", b"old", None),
+            (Directory, "opt", b"", None),
+            (Regular, "opt/stale", b"stale", None),
+        ]);
+        apply_layer(lower.as_slice(), &mut applied).unwrap();
+        let upper = layer(&[
+            (Regular, "usr/.wh.old", b"", None),
+            (Regular, "opt/.wh..wh..opq", b"", None),
+            (Regular, "opt/app", b"app", None),
+            (Link, "opt/app-link", b"", Some("opt/app")),
+            (Symlink, "usr/sh", b"", Some("bash")),
+        ]);
+        apply_layer(upper.as_slice(), &mut applied).unwrap();
+        let applied = resolve_hardlinks(applied).unwrap();
+        let paths: Vec<&str> = applied.keys().map(|p| p.as_str()).collect();
+        assert_eq!(
+            paths,
+            ["/opt", "/opt/app", "/opt/app-link", "/usr", "/usr/sh"]
+        );
+        assert_eq!(
+            applied[Utf8Path::new("/opt/app-link")].content,
+            Some(digest(b"app"))
+        );
+        let leader = Some(Utf8PathBuf::from("/opt/app"));
+        assert_eq!(applied[Utf8Path::new("/opt/app")].link_group, leader);
+        assert_eq!(applied[Utf8Path::new("/opt/app-link")].link_group, leader);
+
+        let mut app = entry(FileType::File, &digest(b"app"));
+        app.link_group = leader;
+        let mut rootfs = BTreeMap::from([
+            ("/opt".into(), entry(FileType::Directory, "dir")),
+            ("/opt/app".into(), app.clone()),
+            ("/opt/app-link".into(), app),
+            ("/usr".into(), entry(FileType::Directory, "dir")),
+            ("/usr/sh".into(), entry(FileType::Symlink, "symlink:bash")),
+        ]);
+        assert_eq!(compare(&rootfs, &applied), Vec::<String>::new());
+
+        rootfs.get_mut(Utf8Path::new("/opt/app")).unwrap().content = Some(digest(b"new"));
+        rootfs.get_mut(Utf8Path::new("/usr/sh")).unwrap().uid = 1000;
+        rootfs.get_mut(Utf8Path::new("/opt")).unwrap().mtime = 50;
+        rootfs.remove(Utf8Path::new("/opt/app-link"));
+        rootfs.insert("/usr/bash".into(), entry(FileType::File, "sha256:0"));
+        assert_eq!(
+            compare(&rootfs, &applied),
+            [
+                "/opt: mtime 100 is newer than 50".to_string(),
+                format!(
+                    "/opt/app: content {} instead of {}",
+                    digest(b"app"),
+                    digest(b"new")
+                ),
+                "/usr/bash: missing from the image".to_string(),
+                "/usr/sh: owner 0:0 instead of 1000:0".to_string(),
+                "/opt/app-link: not in the rootfs".to_string(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::ambient_authority;
+use cap_std_ext::cap_std::fs::Dir;
+use clap::Parser;
+use openssl::pkey::{PKey, Public};
+
+use crate::signature;
+
+#[derive(Parser)]
+pub struct VerifySignatureArgs {
+    /// Path to the OCI image layout of the image to verify
+    image: Utf8PathBuf,
+
+    /// PEM encoded public key to trust
+    ///
+    /// Can be specified multiple times, e.g. to trust both the old and the
+    /// new key during a key rotation.
+    #[arg(long = "key", value_name = "PATH", required = true)]
+    keys: Vec<Utf8PathBuf>,
+
+    /// Image reference the signatures must name, e.g. quay.io/org/image
+    ///
+    /// This is the --reference the image was signed with; signatures naming
+    /// another repository aren't valid for this one.
+    #[arg(long, value_name = "NAME", default_value = "")]
+    reference: String,
+
+    /// Require a valid signature by each key instead of by any of them
+    #[arg(long)]
+    require_all: bool,
+}
+
+pub fn run(args: &VerifySignatureArgs) -> Result<()> {
+    let keys = args
+        .keys
+        .iter()
+        .map(|path| load_public_key(path).with_context(|| format!("loading key {path}")))
+        .collect::<Result<Vec<_>>>()?;
+
+    let dir = Dir::open_ambient_dir(&args.image, ambient_authority())
+        .with_context(|| format!("opening {}", args.image))?;
+    let oci_dir = ocidir::OciDir::open(dir).context("opening OCI image layout")?;
+    let (image, _) = crate::utils::read_image_manifest(&oci_dir)?;
+    let signatures = signature::read_signatures(&oci_dir, &image).context("reading signatures")?;
+
+    let mut unverified = Vec::new();
+    for (key, path) in keys.iter().zip(&args.keys) {
+        let mut verified = false;
+        for sig in &signatures {
+            verified |= sig
+                .verify(&image, &args.reference, key)
+                .with_context(|| format!("verifying signature with key {path}"))?;
+        }
+        if verified {
+            println!("{}: valid signature by key {path}", image.digest());
+        } else {
+            unverified.push(path.as_str());
+        }
+    }
+
+    if unverified.len() == keys.len() {
+        anyhow::bail!(
+            "{} has no valid signature by any of the keys",
+            image.digest()
+        );
+    }
+    if args.require_all && !unverified.is_empty() {
+        anyhow::bail!(
+            "{} has no valid signature by {}",
+            image.digest(),
+            unverified.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn load_public_key(path: &Utf8Path) -> Result<PKey<Public>> {
+    let pem = std::fs::read(path).with_context(|| format!("reading {path}"))?;
+    PKey::public_key_from_pem(&pem).context("parsing PEM public key")
+}
@@ -2,7 +2,12 @@ use anyhow::{Context, Result, anyhow, bail};
 use camino::{Utf8Path, Utf8PathBuf};
 use cap_std_ext::cap_std::fs::Dir;
 use indexmap::IndexMap;
-use std::{collections::HashMap, io::Read, os::unix::fs::MetadataExt, str::FromStr};
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read},
+    os::unix::fs::MetadataExt,
+    str::FromStr,
+};
 
 use crate::{
     components::{ComponentId, ComponentInfo, ComponentsRepo, FileMap, FileType},
@@ -19,6 +24,11 @@ const LOCALDB_PATHS: &[&str] = &["var/lib/pacman/local", "usr/lib/sysimage/lib/p
 const FILENAME_DESC: &str = "desc";
 /// Filename of the ALPM `files` database file that contains a list of files contained in a package
 const FILENAME_FILES: &str = "files";
+/// Filename of the ALPM `mtree` database file that contains metadata about the files of a package
+const FILENAME_MTREE: &str = "mtree";
+
+/// The `mtree` file is usually gzip-compressed, which we detect by its magic number.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
 
 /// Section name for the BASE package identifier
 const SECTION_IDENTIFIER_BASE: &str = "BASE";
@@ -31,14 +41,14 @@ const SECTION_IDENTIFIER_FILES: &str = "FILES";
 const ALPM_DBFILE_MAXIMUM_SIZE: u64 = 64 * 1024 * 1024;
 
 pub struct AlpmComponentsRepo {
-    /// Unique component (BASE) names mapped to builddate and stability, indexed by ComponentId.
+    /// Unique component (BASE) names mapped to mtime clamp and stability, indexed by ComponentId.
     components: IndexMap<String, (u64, f64)>,
 
-    /// Mapping from path to list of ComponentId.
+    /// Mapping from path to list of ComponentId and the file type in the package, if known.
     ///
     /// It's common for directories to be owned by more than one component (i.e.
     /// from _different_ packages).
-    path_to_components: HashMap<Utf8PathBuf, Vec<ComponentId>>,
+    path_to_components: HashMap<Utf8PathBuf, Vec<(ComponentId, Option<FileType>)>>,
 }
 
 impl AlpmComponentsRepo {
@@ -78,7 +88,7 @@ impl AlpmComponentsRepo {
             let local_db_entry = local_db_entry?;
             if local_db_entry.file_type()?.is_dir() {
                 let package_dir = local_db_entry.open_dir()?;
-                let (desc, files) =
+                let (desc, files, mtree) =
                     Self::package_info_from_dir(&package_dir).with_context(|| {
                         format!(
                             "parsing metadata of package {:?}",
@@ -88,27 +98,34 @@ impl AlpmComponentsRepo {
                 let basename = desc.base()?;
                 let builddate = desc.builddate()?;
                 let stability = calculate_stability(&[], builddate, now)?;
+                // The mtree has the mtimes the files were packaged with, which are reproducible.
+                // They are normally all set to the builddate, but be safe and use the newest one.
+                let mtime_clamp = mtree
+                    .as_ref()
+                    .and_then(Mtree::max_time)
+                    .unwrap_or(builddate);
                 let components_entry = components.entry(basename.to_string());
                 let component_id = ComponentId(components_entry.index());
                 match components_entry {
                     indexmap::map::Entry::Occupied(mut e) => {
                         // A package built from the same %BASE% was already added:
-                        // (1) We want the most current (max) builddate as the clamp value
+                        // (1) We want the most current (max) mtime as the clamp value
                         // (2) We want the lowest stability score (min), as a layer can only be
                         //     as stable as the most unstable part.
                         let e: &mut (u64, f64) = e.get_mut();
-                        e.0 = e.0.max(builddate);
+                        e.0 = e.0.max(mtime_clamp);
                         e.1 = e.1.min(stability);
                     }
                     indexmap::map::Entry::Vacant(e) => {
                         // Package with same value for %BASE% did not exist before, so we add it
-                        e.insert((builddate, stability));
+                        e.insert((mtime_clamp, stability));
                     }
                 }
                 Self::files_to_map(
                     &mut path_to_components,
                     component_id,
                     files.files(),
+                    mtree.as_ref(),
                     image_files,
                     rootfs,
                 )?;
@@ -121,10 +138,13 @@ impl AlpmComponentsRepo {
     }
 
     /// Open a directory corresponding to a package and expect it to contain relevant metadata
-    /// in `desc` and `files` files.
+    /// in `desc` and `files` files, and optionally an `mtree` file.
     ///
-    /// Returns two [`LocalAlpmDb`]: First for the parsed `desc` file, second for the parsed `files` file.
-    fn package_info_from_dir(package_dir: &Dir) -> Result<(LocalAlpmDbFile, LocalAlpmDbFile)> {
+    /// Returns two [`LocalAlpmDbFile`]: First for the parsed `desc` file, second for the parsed
+    /// `files` file. The third element is the parsed `mtree` file, if there is one.
+    fn package_info_from_dir(
+        package_dir: &Dir,
+    ) -> Result<(LocalAlpmDbFile, LocalAlpmDbFile, Option<Mtree>)> {
         // We read two files: desc and files. Both are read and parsed in the same way.
         let read_dbfile = |filename| {
             let mut database_file = package_dir.open(filename)?.into_std();
@@ -149,27 +169,57 @@ impl AlpmComponentsRepo {
         };
         let desc = read_dbfile(FILENAME_DESC).context("read and parse desc")?;
         let files = read_dbfile(FILENAME_FILES).context("read and parse files")?;
-        Ok((desc, files))
+        let mtree = Self::read_mtree(package_dir).context("read and parse mtree")?;
+        Ok((desc, files, mtree))
+    }
+
+    /// Read and parse the (possibly gzip-compressed) `mtree` file of a package.
+    ///
+    /// Returns `None` if the package has no `mtree` file, which is optional.
+    fn read_mtree(package_dir: &Dir) -> Result<Option<Mtree>> {
+        let file = match package_dir.open(FILENAME_MTREE) {
+            Ok(file) => file.into_std(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut reader = BufReader::new(file);
+        let reader: Box<dyn Read> = if reader.fill_buf()?.starts_with(GZIP_MAGIC) {
+            Box::new(flate2::bufread::GzDecoder::new(reader))
+        } else {
+            Box::new(reader)
+        };
+
+        // The compressed size says little about the decompressed one, so limit while reading.
+        let mut content = String::new();
+        reader
+            .take(ALPM_DBFILE_MAXIMUM_SIZE + 1)
+            .read_to_string(&mut content)?;
+        if content.len() as u64 > ALPM_DBFILE_MAXIMUM_SIZE {
+            bail!("file is too large: {FILENAME_MTREE} (maximum: {ALPM_DBFILE_MAXIMUM_SIZE})");
+        }
+        content.parse().map(Some)
     }
 
     /// Associates the given `component_id` with all canonicalized paths of the package given
-    /// in `pkgdb_files` in `path_to_components`
+    /// in `pkgdb_files` in `path_to_components`, along with their file type from `mtree`
     fn files_to_map(
-        path_to_components: &mut HashMap<Utf8PathBuf, Vec<ComponentId>>,
+        path_to_components: &mut HashMap<Utf8PathBuf, Vec<(ComponentId, Option<FileType>)>>,
         component_id: ComponentId,
         pkgdb_files: Vec<&Utf8Path>,
+        mtree: Option<&Mtree>,
         image_files: &FileMap,
         rootfs: &Dir,
     ) -> Result<()> {
         let mut canonicalization_cache = HashMap::new();
         for path in pkgdb_files {
-            // Unfortunately, we cannot differentiate between file types, because we only have paths.
-            // As such, we will not use that information.
-            // If it is needed in the future, the parser would have to be extended to read `mtree` files.
-            // If only a directory/non-directory switch is needed, one could also check the paths themselves,
-            // because directories consistently have a trailing '/' in their paths (this is also mandated by the spec).
-
-            // let file_type = ...
+            // The `mtree` file knows the file type of every path. Without it, we can still tell
+            // directories apart, because they consistently have a trailing '/' in their paths
+            // (this is also mandated by the spec), but don't know whether others are files or
+            // symlinks, so they match either.
+            let file_type = match mtree {
+                Some(mtree) => mtree.file_type(path),
+                None => path.as_str().ends_with('/').then_some(FileType::Directory),
+            };
 
             // The `files` file contains relative paths like "usr/bin/sh" (as it is mandated by the spec),
             // while canonicalization wants absolute paths.
@@ -191,7 +241,7 @@ impl AlpmComponentsRepo {
             path_to_components
                 .entry(canonical_path)
                 .or_default()
-                .push(component_id);
+                .push((component_id, file_type));
         }
         Ok(())
     }
@@ -206,22 +256,28 @@ impl ComponentsRepo for AlpmComponentsRepo {
         10
     }
 
-    fn claims_for_path(&self, path: &Utf8Path, _file_type: FileType) -> Vec<ComponentId> {
+    fn claims_for_path(&self, path: &Utf8Path, file_type: FileType) -> Vec<ComponentId> {
         self.path_to_components
             .get(path)
-            .map(|components| components.to_vec())
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter(|(_, ft)| ft.is_none_or(|ft| ft == file_type))
+                    .map(|(id, _)| *id)
+                    .collect()
+            })
             .unwrap_or_default()
     }
 
     fn component_info(&self, id: ComponentId) -> ComponentInfo<'_> {
-        let (pkgbase, (builddate, stability)) = self
+        let (pkgbase, (mtime_clamp, stability)) = self
             .components
             .get_index(id.0)
             // SAFETY: We handed out the ComponentId by ourselves and obtained it directly from the `IndexMap`
             .expect("invalid ComponentId");
         ComponentInfo {
             name: pkgbase.as_str(),
-            mtime_clamp: *builddate,
+            mtime_clamp: *mtime_clamp,
             stability: *stability,
         }
     }
@@ -375,6 +431,155 @@ impl LocalAlpmDbFile {
     }
 }
 
+/// Parses the contents of ALPM `mtree` files, which describe the files of a package.
+/// Implements the [`FromStr`] trait, construct it by using `.parse()` on a &str of the
+/// decompressed file.
+///
+/// Only the `type` and `time` keywords are kept, since the rest (mode, owner, digests) is
+/// already in the rootfs.
+///
+/// cf. https://alpm.archlinux.page/specifications/ALPM-MTREE.5.html
+#[derive(Debug, Default)]
+pub struct Mtree(HashMap<Utf8PathBuf, MtreeEntry>);
+
+/// The metadata of a path in an `mtree` file we care about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MtreeEntry {
+    /// `None` for types chunkah doesn't handle (devices, FIFOs, sockets).
+    pub file_type: Option<FileType>,
+    /// Modification time in seconds, without the fractional part.
+    pub time: Option<u64>,
+}
+
+impl FromStr for Mtree {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut entries = HashMap::new();
+        // Keywords set with `/set` apply to all following entries, unless they override them.
+        let mut defaults: HashMap<&str, &str> = HashMap::new();
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_ascii_whitespace();
+            // SAFETY: The line is not empty after trimming, so there is at least one word.
+            let first = words.next().expect("empty line");
+            match first {
+                "/set" => {
+                    for word in words {
+                        let (key, value) =
+                            Self::parse_keyword(word).with_context(|| format!("line {}", i + 1))?;
+                        defaults.insert(key, value);
+                    }
+                }
+                "/unset" => {
+                    for key in words {
+                        if key == "all" {
+                            defaults.clear();
+                        } else {
+                            defaults.remove(key);
+                        }
+                    }
+                }
+                path => {
+                    let mut keywords = defaults.clone();
+                    for word in words {
+                        let (key, value) =
+                            Self::parse_keyword(word).with_context(|| format!("line {}", i + 1))?;
+                        keywords.insert(key, value);
+                    }
+                    let path = Self::unescape(path).with_context(|| format!("line {}", i + 1))?;
+                    let entry =
+                        Self::parse_entry(&keywords).with_context(|| format!("path {path}"))?;
+                    // Paths are relative to the package root, i.e. start with "./", like the
+                    // paths in `files` are relative (but without the leading "./").
+                    let path = path.strip_prefix("./").unwrap_or(&path);
+                    if path != "." {
+                        entries.insert(Utf8PathBuf::from(path.trim_end_matches('/')), entry);
+                    }
+                }
+            }
+        }
+
+        Ok(Self(entries))
+    }
+}
+
+impl Mtree {
+    /// Returns the entry of `path`, relative to the package root like in the `files` file.
+    /// The trailing '/' of directories is optional.
+    pub fn get(&self, path: &Utf8Path) -> Option<&MtreeEntry> {
+        self.0
+            .get(Utf8Path::new(path.as_str().trim_end_matches('/')))
+    }
+
+    /// Returns the file type of `path`, if it is known.
+    pub fn file_type(&self, path: &Utf8Path) -> Option<FileType> {
+        self.get(path).and_then(|entry| entry.file_type)
+    }
+
+    /// Returns the newest modification time of all entries.
+    pub fn max_time(&self) -> Option<u64> {
+        self.0.values().filter_map(|entry| entry.time).max()
+    }
+
+    /// Splits a `key=value` keyword.
+    fn parse_keyword(word: &str) -> Result<(&str, &str)> {
+        word.split_once('=')
+            .ok_or_else(|| anyhow!("invalid keyword: {word}"))
+    }
+
+    fn parse_entry(keywords: &HashMap<&str, &str>) -> Result<MtreeEntry> {
+        let file_type = match keywords.get("type").copied() {
+            Some("file") => Some(FileType::File),
+            Some("dir") => Some(FileType::Directory),
+            Some("link") => Some(FileType::Symlink),
+            Some("block" | "char" | "fifo" | "socket") => None,
+            Some(other) => bail!("unknown type: {other}"),
+            // The spec makes `type` mandatory, but don't be stricter than needed.
+            None => None,
+        };
+        let time = keywords
+            .get("time")
+            .map(|time| {
+                // Times have the form "<seconds>.<nanoseconds>"
+                let seconds = time.split_once('.').map_or(*time, |(seconds, _)| seconds);
+                seconds
+                    .parse::<u64>()
+                    .with_context(|| format!("invalid time: {time}"))
+            })
+            .transpose()?;
+        Ok(MtreeEntry { file_type, time })
+    }
+
+    /// Decodes the octal escapes (e.g. `\040` for a space) of an `mtree` path.
+    fn unescape(path: &str) -> Result<String> {
+        if !path.contains('\\') {
+            return Ok(path.to_string());
+        }
+        let mut bytes = Vec::with_capacity(path.len());
+        let mut rest = path.as_bytes();
+        while let Some((&byte, tail)) = rest.split_first() {
+            if byte == b'\\' {
+                let digits = tail
+                    .get(..3)
+                    .and_then(|digits| std::str::from_utf8(digits).ok())
+                    .and_then(|digits| u8::from_str_radix(digits, 8).ok())
+                    .ok_or_else(|| anyhow!("invalid escape in path: {path}"))?;
+                bytes.push(digits);
+                rest = &tail[3..];
+            } else {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| anyhow!("path is not valid UTF-8: {path}"))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::{
@@ -387,7 +592,7 @@ mod tests {
 
     use crate::components::{
         ComponentsRepo, FileType,
-        alpm::{AlpmComponentsRepo, LocalAlpmDbFile},
+        alpm::{AlpmComponentsRepo, LocalAlpmDbFile, Mtree, MtreeEntry},
     };
 
     pub const DESC_CONTENTS: &str = r#"%NAME%
@@ -486,8 +691,73 @@ etc/services	b80b33810d79289b09bac307a99b4b54
         let claims = alpm.claims_for_path(Utf8Path::new("/etc/fstab"), FileType::File);
         assert_eq!(claims.len(), 1);
         let mut component_info = claims.iter().map(|claim| alpm.component_info(*claim));
-        assert_eq!(component_info.next().unwrap().name, "filesystem");
+        let info = component_info.next().unwrap();
+        assert_eq!(info.name, "filesystem");
+        // from the (gzip-compressed) mtree
+        assert_eq!(info.mtime_clamp, 1760286101);
         assert!(component_info.next().is_none());
+
+        // the file types from the mtree must match
+        assert!(
+            alpm.claims_for_path(Utf8Path::new("/usr"), FileType::File)
+                .is_empty()
+        );
+        assert!(
+            alpm.claims_for_path(Utf8Path::new("/etc/fstab"), FileType::Symlink)
+                .is_empty()
+        );
+        let claims = alpm.claims_for_path(Utf8Path::new("/etc/mtab"), FileType::Symlink);
+        assert_eq!(claims.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_mtree() {
+        let mtree = r#"#mtree
+/set type=file uid=0 gid=0 mode=644
+./.PKGINFO time=1760286101.0 size=694 sha256digest=78614f07
+./usr time=1760286101.0 mode=755 type=dir
+./usr/bin/sh time=1760286101.0 mode=777 type=link link=bash
+./usr/share/my\040file time=1760286200.5 size=1
+/unset type
+./dev/null time=1760286101.0 type=char
+./usr/lib/untyped time=1760286101.0
+"#
+        .parse::<Mtree>()
+        .unwrap();
+
+        let entry = |path| mtree.get(Utf8Path::new(path)).copied();
+        assert_eq!(
+            entry("usr/"),
+            Some(MtreeEntry {
+                file_type: Some(FileType::Directory),
+                time: Some(1760286101),
+            })
+        );
+        assert_eq!(
+            mtree.file_type(Utf8Path::new("usr")),
+            Some(FileType::Directory)
+        );
+        assert_eq!(
+            mtree.file_type(Utf8Path::new("usr/bin/sh")),
+            Some(FileType::Symlink)
+        );
+        assert_eq!(
+            entry("usr/share/my file"),
+            Some(MtreeEntry {
+                file_type: Some(FileType::File),
+                time: Some(1760286200),
+            })
+        );
+        assert_eq!(mtree.file_type(Utf8Path::new("dev/null")), None);
+        assert_eq!(mtree.file_type(Utf8Path::new("usr/lib/untyped")), None);
+        assert!(entry("usr/lib/untyped").is_some());
+        assert!(entry("usr/lib").is_none());
+        assert_eq!(mtree.max_time(), Some(1760286200));
+
+        assert!("./foo type=bogus".parse::<Mtree>().is_err());
+        assert!("./foo time=yesterday".parse::<Mtree>().is_err());
+        assert!("./foo notakeyword".parse::<Mtree>().is_err());
+        assert!("./foo\\9 type=file".parse::<Mtree>().is_err());
     }
 
     #[test]
@@ -537,10 +807,7 @@ etc/services	b80b33810d79289b09bac307a99b4b54
         );
         assert_eq!(as_paths.next(), None);
 
-        let mut other_section = parsed_files
-            .get_multi_line_value("BACKUP")
-            .unwrap()
-            .into_iter();
+        let mut other_section = parsed_files.get_multi_line_value("BACKUP").unwrap().iter();
         assert_eq!(
             other_section.next().unwrap(),
             "etc/protocols\tb9833a5373ef2f5df416f4f71ccb42eb"
@@ -551,4 +818,33 @@ etc/services	b80b33810d79289b09bac307a99b4b54
         );
         assert_eq!(other_section.next(), None);
     }
+
+    proptest::proptest! {
+        #[test]
+        fn test_parse_arbitrary(content in ".*") {
+            // must not panic
+            let _ = content.parse::<LocalAlpmDbFile>();
+        }
+
+        #[test]
+        fn test_parse_roundtrip(
+            sections in proptest::collection::btree_map(
+                "[A-Z]{1,8}",
+                proptest::collection::vec("[a-z0-9 ./_-]{1,20}", 0..5),
+                0..5,
+            )
+        ) {
+            let content: String = sections
+                .iter()
+                .map(|(name, lines)| format!("%{name}%\n{}\n\n", lines.join("\n")))
+                .collect();
+            let parsed = content.parse::<LocalAlpmDbFile>().unwrap();
+            for (name, lines) in &sections {
+                proptest::prop_assert_eq!(
+                    parsed.get_multi_line_value(name),
+                    Some(lines.as_slice())
+                );
+            }
+        }
+    }
 }
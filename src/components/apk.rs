@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::Dir;
+use indexmap::IndexMap;
+
+use crate::utils::{calculate_stability, canonicalize_parent_path};
+
+use super::{ComponentId, ComponentInfo, ComponentsRepo, FileMap, FileType};
+
+const REPO_NAME: &str = "apk";
+
+/// Known locations of the apk installed database. The first is the historical
+/// path, the second is used by newer usr-merged Alpine releases.
+const INSTALLED_DB_PATHS: &[&str] = &["lib/apk/db/installed", "usr/lib/apk/db/installed"];
+
+/// Apk-based components repo implementation.
+///
+/// Uses the apk installed database to determine file ownership and groups
+/// files by their origin package (i.e. the APKBUILD they came from).
+pub struct ApkRepo {
+    /// Unique component (origin) names mapped to (buildtime, stability),
+    /// indexed by ComponentId.
+    components: IndexMap<String, (u64, f64)>,
+
+    /// Mapping from path to list of ComponentId.
+    ///
+    /// Directories are commonly owned by more than one component.
+    path_to_components: HashMap<Utf8PathBuf, Vec<ComponentId>>,
+}
+
+impl ApkRepo {
+    /// Load the apk database from the given rootfs. The `files` parameter is
+    /// used to canonicalize paths from the apk database.
+    ///
+    /// Returns `Ok(None)` if no apk database is detected.
+    pub fn load(rootfs: &Dir, files: &FileMap, now: u64) -> Result<Option<Self>> {
+        let mut db_path = None;
+        for path in INSTALLED_DB_PATHS {
+            if rootfs
+                .try_exists(path)
+                .with_context(|| format!("checking for {path}"))?
+            {
+                db_path = Some(*path);
+                break;
+            }
+        }
+        let Some(db_path) = db_path else {
+            return Ok(None);
+        };
+
+        let content = rootfs
+            .read_to_string(db_path)
+            .with_context(|| format!("reading {db_path}"))?;
+        let packages = parse_installed(&content).context("parsing apk installed database")?;
+
+        Self::load_from_packages(rootfs, files, packages, now).map(Some)
+    }
+
+    fn load_from_packages(
+        rootfs: &Dir,
+        files: &FileMap,
+        packages: Vec<ApkPackage>,
+        now: u64,
+    ) -> Result<Self> {
+        let mut components: IndexMap<String, (u64, f64)> = IndexMap::new();
+        let mut path_to_components: HashMap<Utf8PathBuf, Vec<ComponentId>> = HashMap::new();
+        let mut cache = HashMap::new();
+
+        for pkg in packages {
+            let stability = calculate_stability(&[], pkg.buildtime, now)?;
+            let entry = components.entry(pkg.origin);
+            let component_id = ComponentId(entry.index());
+            match entry {
+                indexmap::map::Entry::Occupied(mut e) => {
+                    // Same as for RPM and ALPM: the clamp is the max() of all
+                    // the subpackages and the stability the min().
+                    let (buildtime, existing_stability) = e.get_mut();
+                    *buildtime = (*buildtime).max(pkg.buildtime);
+                    *existing_stability = existing_stability.min(stability);
+                }
+                indexmap::map::Entry::Vacant(e) => {
+                    e.insert((pkg.buildtime, stability));
+                }
+            }
+
+            for path in pkg.paths {
+                let canonical = canonicalize_parent_path(rootfs, files, &path, &mut cache)
+                    .with_context(|| format!("canonicalizing {}", path))?;
+                let entries = path_to_components.entry(canonical).or_default();
+                if !entries.contains(&component_id) {
+                    entries.push(component_id);
+                }
+            }
+        }
+
+        Ok(Self {
+            components,
+            path_to_components,
+        })
+    }
+}
+
+impl ComponentsRepo for ApkRepo {
+    fn name(&self) -> &'static str {
+        REPO_NAME
+    }
+
+    fn default_priority(&self) -> usize {
+        10
+    }
+
+    fn claims_for_path(&self, path: &Utf8Path, _file_type: FileType) -> Vec<ComponentId> {
+        self.path_to_components
+            .get(path)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn component_info(&self, id: ComponentId) -> ComponentInfo<'_> {
+        let (name, (buildtime, stability)) = self
+            .components
+            .get_index(id.0)
+            // SAFETY: the ids we're given come from the IndexMap itself when we
+            // inserted the element, so it must be valid.
+            .expect("invalid ComponentId");
+        ComponentInfo {
+            name,
+            mtime_clamp: *buildtime,
+            stability: *stability,
+        }
+    }
+}
+
+/// An installed package from the apk database.
+#[derive(Debug)]
+struct ApkPackage {
+    /// Origin package name, falling back to the package name.
+    origin: String,
+    buildtime: u64,
+    /// Absolute paths of all directories and files owned by this package.
+    paths: Vec<Utf8PathBuf>,
+}
+
+/// Parse the apk installed database.
+///
+/// The database is a series of blank line-separated package stanzas made up
+/// of `K:value` lines. `F:` lines introduce a directory (relative to the root)
+/// and subsequent `R:` lines are files within that directory.
+///
+/// cf. https://wiki.alpinelinux.org/wiki/Apk_spec#Installed_Database_V2
+fn parse_installed(content: &str) -> Result<Vec<ApkPackage>> {
+    let mut packages = Vec::new();
+
+    for stanza in content.split("\n\n") {
+        let mut name = None;
+        let mut origin = None;
+        let mut buildtime = None;
+        let mut paths = Vec::new();
+        let mut current_dir = Utf8PathBuf::from("/");
+
+        for line in stanza.lines().filter(|l| !l.is_empty()) {
+            let (key, value) = line
+                .split_once(':')
+                .with_context(|| format!("invalid line in apk database: {line}"))?;
+            match key {
+                "P" => name = Some(value),
+                "o" => origin = Some(value),
+                "t" => {
+                    buildtime = Some(
+                        value
+                            .parse::<u64>()
+                            .with_context(|| format!("parsing build time {value}"))?,
+                    )
+                }
+                "F" => {
+                    current_dir = Utf8Path::new("/").join(value);
+                    paths.push(current_dir.clone());
+                }
+                "R" => paths.push(current_dir.join(value)),
+                _ => {}
+            }
+        }
+
+        let Some(name) = name else {
+            continue;
+        };
+        let buildtime =
+            buildtime.with_context(|| format!("missing build time for package {name}"))?;
+
+        packages.push(ApkPackage {
+            origin: origin.unwrap_or(name).to_string(),
+            buildtime,
+            paths,
+        });
+    }
+
+    Ok(packages)
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std::ambient_authority;
+
+    use super::*;
+    use crate::components::claim_names;
+
+    const INSTALLED_FIXTURE: &str = "\
+C:Q1+Dmp3ZiSvAHbKSQ+5iX5sD0VMOc=
+P:musl
+V:1.2.5-r0
+A:x86_64
+S:411845
+I:649216
+T:the musl c library (libc) implementation
+o:musl
+t:1712217934
+F:lib
+R:ld-musl-x86_64.so.1
+a:0:0:755
+Z:Q1nQnNGO/yRfXbsjuHnGcUeCw4lUs=
+R:libc.musl-x86_64.so.1
+
+C:Q1abcdef
+P:musl-utils
+V:1.2.5-r0
+o:musl
+t:1712217999
+F:usr
+F:usr/bin
+R:ldd
+
+C:Q1ghijkl
+P:busybox
+V:1.36.1-r28
+o:busybox
+t:1715000000
+F:bin
+R:busybox
+F:usr
+F:usr/bin
+";
+
+    #[test]
+    fn test_parse_installed() {
+        let packages = parse_installed(INSTALLED_FIXTURE).unwrap();
+        assert_eq!(packages.len(), 3);
+        assert_eq!(packages[0].origin, "musl");
+        assert_eq!(packages[0].buildtime, 1712217934);
+        assert_eq!(
+            packages[0].paths,
+            [
+                "/lib",
+                "/lib/ld-musl-x86_64.so.1",
+                "/lib/libc.musl-x86_64.so.1"
+            ]
+            .map(Utf8PathBuf::from)
+        );
+        assert_eq!(packages[1].origin, "musl");
+        assert_eq!(packages[1].paths.last().unwrap(), "/usr/bin/ldd");
+    }
+
+    #[test]
+    fn test_apk_claims() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("lib/apk/db").unwrap();
+        rootfs
+            .write("lib/apk/db/installed", INSTALLED_FIXTURE)
+            .unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let repo = ApkRepo::load(&rootfs, &files, 1715000000 + 86400)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(claim_names(&repo, "/lib/ld-musl-x86_64.so.1"), ["musl"]);
+        // subpackages are grouped by origin
+        assert_eq!(claim_names(&repo, "/usr/bin/ldd"), ["musl"]);
+        assert_eq!(claim_names(&repo, "/bin/busybox"), ["busybox"]);
+
+        // shared directories are claimed by multiple components
+        let mut names = claim_names(&repo, "/usr/bin");
+        names.sort();
+        assert_eq!(names, ["busybox", "musl"]);
+
+        // the clamp is the max build time of all subpackages
+        let claims = repo.claims_for_path(Utf8Path::new("/usr/bin/ldd"), FileType::File);
+        assert_eq!(repo.component_info(claims[0]).mtime_clamp, 1712217999);
+
+        assert!(claim_names(&repo, "/etc/unowned").is_empty());
+    }
+
+    #[test]
+    fn test_apk_not_detected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        assert!(ApkRepo::load(&rootfs, &files, 0).unwrap().is_none());
+    }
+}
@@ -3,16 +3,17 @@ use std::collections::HashMap;
 use camino::{Utf8Path, Utf8PathBuf};
 use indexmap::IndexSet;
 
-use super::{ComponentId, ComponentInfo, ComponentsRepo, FileMap, FileType};
+use super::{BigfilesGroupBy, ComponentId, ComponentInfo, ComponentsRepo, FileMap, FileType};
 
-/// Minimum file size in bytes to be considered a "big file" (1 MB).
-const MIN_SIZE: u64 = 1024 * 1024;
+/// Default minimum file size in bytes to be considered a "big file" (1 MB).
+pub(crate) const DEFAULT_MIN_SIZE: u64 = 1024 * 1024;
 
 const REPO_NAME: &str = "bigfiles";
 
 /// Big files component repo implementation.
 ///
-/// Claims any file larger than 1 MB into separate standalone components. This
+/// Claims any file larger than 1 MB (by default) into separate standalone
+/// components. This
 /// solves a conceptual issue in the unclaimed files logic: by grouping together
 /// all those files, they can't ever be broken back out into separate layers;
 /// the packer considers each component as one monolithic unit. By breaking them
@@ -22,6 +23,10 @@ const REPO_NAME: &str = "bigfiles";
 ///
 /// Some special handling for hardlinked files (same inode); we still want
 /// unclaimed files that are hardlinked to end up in the same component.
+///
+/// Related big files can also be grouped by extension (e.g. all `.wasm` files
+/// in a `*.wasm` component) or by directory, so that they share a layer rather
+/// than competing for layers one by one.
 pub struct BigfilesRepo {
     /// Component names, indexed by ComponentId.
     components: IndexSet<String>,
@@ -32,11 +37,17 @@ pub struct BigfilesRepo {
 }
 
 impl BigfilesRepo {
-    /// Load bigfiles repo by scanning for files >= MIN_SIZE.
+    /// Load bigfiles repo by scanning for files >= `min_size` bytes.
     ///
     /// Returns None if no qualifying files are found. Hardlinked files (same
-    /// inode, nlink > 1) are grouped into the same component.
-    pub fn load(files: &FileMap, default_mtime_clamp: u64) -> Option<Self> {
+    /// inode, nlink > 1) are grouped into the same component, and files into
+    /// components according to `group_by`.
+    pub fn load(
+        files: &FileMap,
+        default_mtime_clamp: u64,
+        min_size: u64,
+        group_by: BigfilesGroupBy,
+    ) -> Option<Self> {
         let mut components: IndexSet<String> = IndexSet::new();
         let mut path_to_component: HashMap<Utf8PathBuf, ComponentId> = HashMap::new();
 
@@ -45,14 +56,14 @@ impl BigfilesRepo {
         for (path, file_info) in files {
             if file_info.file_type == FileType::File
                 && file_info.nlink > 1
-                && file_info.size >= MIN_SIZE
+                && file_info.size >= min_size
             {
                 inode_to_paths.entry(file_info.ino).or_default().push(path);
             }
         }
 
         for (path, file_info) in files {
-            if file_info.file_type != FileType::File || file_info.size < MIN_SIZE {
+            if file_info.file_type != FileType::File || file_info.size < min_size {
                 continue;
             }
 
@@ -66,8 +77,20 @@ impl BigfilesRepo {
                 .map(|s| s.to_string())
                 .expect("filename has no basename");
 
-            // derive component name from filename
-            let component_name = if components.contains(&filename) {
+            let group_name = match group_by {
+                BigfilesGroupBy::File => None,
+                BigfilesGroupBy::Extension => path.extension().map(|ext| format!("*.{ext}")),
+                BigfilesGroupBy::Directory => path
+                    .parent()
+                    .and_then(|parent| parent.strip_prefix("/").ok())
+                    .filter(|parent| !parent.as_str().is_empty())
+                    .map(|parent| parent.to_string()),
+            };
+
+            // derive component name from the group, or else from the filename
+            let component_name = if let Some(name) = group_name {
+                name
+            } else if components.contains(&filename) {
                 // filename already used, use full path without leading '/'
                 path.strip_prefix("/")
                     .expect("non-absolute file path in FileMap")
@@ -111,6 +134,11 @@ impl ComponentsRepo for BigfilesRepo {
         80
     }
 
+    fn is_fallback(&self) -> bool {
+        // it claims based on size only
+        true
+    }
+
     fn claims_for_path(&self, path: &Utf8Path, _file_type: FileType) -> Vec<ComponentId> {
         self.path_to_component
             .get(path)
@@ -199,7 +227,8 @@ mod tests {
         let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
         let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
 
-        let repo = BigfilesRepo::load(&files, 12345).unwrap();
+        let repo =
+            BigfilesRepo::load(&files, 12345, DEFAULT_MIN_SIZE, BigfilesGroupBy::File).unwrap();
 
         // small file should not be claimed
         let claims = repo.claims_for_path(Utf8Path::new("/usr/bin/small"), FileType::File);
@@ -218,6 +247,15 @@ mod tests {
 
         // there should be exactly 2 components (initramfs.img + rpmdb.sqlite)
         assert_eq!(repo.components.len(), 2);
+
+        // with a higher threshold, only the initramfs is big enough
+        let repo =
+            BigfilesRepo::load(&files, 12345, 100 * 1024 * 1024, BigfilesGroupBy::File).unwrap();
+        assert_component(&repo, "/usr/lib/modules/initramfs.img", "initramfs.img");
+        assert_eq!(repo.components.len(), 1);
+        assert!(
+            BigfilesRepo::load(&files, 12345, 1024 * 1024 * 1024, BigfilesGroupBy::File).is_none()
+        );
     }
 
     #[test]
@@ -230,10 +268,41 @@ mod tests {
             create_sparse_file(rootfs, "b/foobar", 4 * 1024 * 1024);
         });
 
-        let repo = BigfilesRepo::load(&files, 0).unwrap();
+        let repo = BigfilesRepo::load(&files, 0, DEFAULT_MIN_SIZE, BigfilesGroupBy::File).unwrap();
 
         // First one uses filename, second uses full path
         assert_component(&repo, "/a/foobar", "foobar");
         assert_component(&repo, "/b/foobar", "b/foobar");
     }
+
+    #[test]
+    fn test_bigfiles_group_by() {
+        let (_tmp, files) = setup_rootfs(|rootfs| {
+            rootfs.create_dir_all("usr/lib/modules/6.1").unwrap();
+            rootfs.create_dir_all("opt/app").unwrap();
+
+            create_sparse_file(rootfs, "usr/lib/modules/6.1/a.ko", 2 * 1024 * 1024);
+            create_sparse_file(rootfs, "usr/lib/modules/6.1/b.ko", 2 * 1024 * 1024);
+            create_sparse_file(rootfs, "opt/app/app.wasm", 2 * 1024 * 1024);
+            create_sparse_file(rootfs, "opt/app/data", 2 * 1024 * 1024);
+            create_sparse_file(rootfs, "bigfile", 2 * 1024 * 1024);
+        });
+
+        let repo =
+            BigfilesRepo::load(&files, 0, DEFAULT_MIN_SIZE, BigfilesGroupBy::Extension).unwrap();
+        assert_component(&repo, "/usr/lib/modules/6.1/a.ko", "*.ko");
+        assert_component(&repo, "/usr/lib/modules/6.1/b.ko", "*.ko");
+        assert_component(&repo, "/opt/app/app.wasm", "*.wasm");
+        // files without an extension are on their own
+        assert_component(&repo, "/opt/app/data", "data");
+
+        let repo =
+            BigfilesRepo::load(&files, 0, DEFAULT_MIN_SIZE, BigfilesGroupBy::Directory).unwrap();
+        assert_component(&repo, "/usr/lib/modules/6.1/a.ko", "usr/lib/modules/6.1");
+        assert_component(&repo, "/usr/lib/modules/6.1/b.ko", "usr/lib/modules/6.1");
+        assert_component(&repo, "/opt/app/app.wasm", "opt/app");
+        assert_component(&repo, "/opt/app/data", "opt/app");
+        // files at the root are on their own
+        assert_component(&repo, "/bigfile", "bigfile");
+    }
 }
@@ -0,0 +1,301 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use indexmap::IndexMap;
+use serde::Deserialize;
+
+use crate::utils::{calculate_stability, normalize_path};
+
+use super::{ComponentId, ComponentInfo, ComponentsRepo, FileMap, FileType};
+
+const REPO_NAME: &str = "brew";
+
+/// The default Homebrew on Linux prefix.
+const PREFIX: &str = "/home/linuxbrew/.linuxbrew";
+
+/// Where kegs are installed, as `<formula>/<version>/`, relative to the prefix.
+const CELLAR: &str = "Cellar";
+
+/// Name of the install receipt in each keg.
+const INSTALL_RECEIPT: &str = "INSTALL_RECEIPT.json";
+
+/// Linuxbrew components repo implementation.
+///
+/// Claims the kegs in the Cellar, grouped by formula, along with the symlinks
+/// `brew link` creates in the prefix (`bin/`, `opt/`, ...) that point into
+/// them. The install time from the receipt of each keg is used as the mtime
+/// clamp.
+pub struct BrewRepo {
+    /// Unique component (formula) names mapped to (install time, stability),
+    /// indexed by ComponentId.
+    components: IndexMap<String, (u64, f64)>,
+    /// Mapping from path to ComponentId.
+    path_to_component: HashMap<Utf8PathBuf, ComponentId>,
+}
+
+/// The fields we care about in an install receipt.
+#[derive(Deserialize)]
+struct InstallReceipt {
+    /// Unix timestamp of when the keg was installed.
+    time: Option<u64>,
+}
+
+impl BrewRepo {
+    /// Load kegs from the Cellar in the given rootfs.
+    ///
+    /// Returns `Ok(None)` if no kegs are found.
+    pub fn load(rootfs: &Dir, files: &FileMap, now: u64) -> Result<Option<Self>> {
+        let prefix = Utf8Path::new(PREFIX);
+        let cellar = prefix.join(CELLAR);
+        if !files.contains_key(&cellar) {
+            return Ok(None);
+        }
+
+        // first, find all the kegs
+        let kegs: BTreeSet<(&str, &str)> = files
+            .keys()
+            .filter_map(|path| keg_of(&cellar, path))
+            .filter_map(|(formula, version)| Some((formula, version?)))
+            .collect();
+
+        let mut components: IndexMap<String, (u64, f64)> = IndexMap::new();
+        for (formula, version) in kegs {
+            let receipt_path = cellar.join(formula).join(version).join(INSTALL_RECEIPT);
+            let time = read_install_time(rootfs, &receipt_path)
+                .with_context(|| format!("reading {receipt_path}"))?
+                // e.g. kegs poured without a receipt; be conservative
+                .unwrap_or(now);
+            let stability = calculate_stability(&[], time, now)?;
+            match components.entry(formula.to_string()) {
+                indexmap::map::Entry::Occupied(mut e) => {
+                    // Multiple versions of the same formula; same logic as
+                    // for subpackages.
+                    let (existing_time, existing_stability) = e.get_mut();
+                    *existing_time = (*existing_time).max(time);
+                    *existing_stability = existing_stability.min(stability);
+                }
+                indexmap::map::Entry::Vacant(e) => {
+                    e.insert((time, stability));
+                }
+            }
+        }
+
+        if components.is_empty() {
+            return Ok(None);
+        }
+
+        let mut path_to_component: HashMap<Utf8PathBuf, ComponentId> = HashMap::new();
+        for (path, file_info) in files {
+            let formula = if let Some((formula, _)) = keg_of(&cellar, path) {
+                formula.to_string()
+            } else if file_info.file_type == FileType::Symlink && path.starts_with(prefix) {
+                let Some(formula) = link_target_formula(rootfs, &cellar, path)? else {
+                    continue;
+                };
+                formula
+            } else {
+                continue;
+            };
+            // the formula dir of a keg-less formula
+            let Some(idx) = components.get_index_of(&formula) else {
+                continue;
+            };
+            path_to_component.insert(path.clone(), ComponentId(idx));
+        }
+
+        Ok(Some(Self {
+            components,
+            path_to_component,
+        }))
+    }
+}
+
+impl ComponentsRepo for BrewRepo {
+    fn name(&self) -> &'static str {
+        REPO_NAME
+    }
+
+    fn default_priority(&self) -> usize {
+        // After system package managers, but before pip since kegs for Python
+        // formulae contain dist-info RECORDs.
+        15
+    }
+
+    fn claims_for_path(&self, path: &Utf8Path, _file_type: FileType) -> Vec<ComponentId> {
+        self.path_to_component
+            .get(path)
+            .map(|id| vec![*id])
+            .unwrap_or_default()
+    }
+
+    fn component_info(&self, id: ComponentId) -> ComponentInfo<'_> {
+        let (name, (time, stability)) = self
+            .components
+            .get_index(id.0)
+            // SAFETY: the ids we're given come from the IndexMap itself when we
+            // inserted the element, so it must be valid.
+            .expect("invalid ComponentId");
+        ComponentInfo {
+            name,
+            mtime_clamp: *time,
+            stability: *stability,
+        }
+    }
+}
+
+/// If `path` is in the Cellar, return the formula and keg version it belongs
+/// to. The version is `None` for the formula directory itself.
+fn keg_of<'a>(cellar: &Utf8Path, path: &'a Utf8Path) -> Option<(&'a str, Option<&'a str>)> {
+    let mut components = path.strip_prefix(cellar).ok()?.components();
+    let formula = components.next()?.as_str();
+    let version = components.next().map(|c| c.as_str());
+    Some((formula, version))
+}
+
+/// Read the install time from a keg's install receipt, if any.
+fn read_install_time(rootfs: &Dir, path: &Utf8Path) -> Result<Option<u64>> {
+    let rel_path = path.strip_prefix("/").unwrap_or(path);
+    let Some(content) = rootfs.read_to_string_optional(rel_path)? else {
+        return Ok(None);
+    };
+    let receipt: InstallReceipt = serde_json::from_str(&content).context("parsing JSON")?;
+    Ok(receipt.time)
+}
+
+/// If the symlink at `path` points into a keg, return the formula.
+fn link_target_formula(rootfs: &Dir, cellar: &Utf8Path, path: &Utf8Path) -> Result<Option<String>> {
+    let rel_path = path.strip_prefix("/").unwrap_or(path);
+    let target = rootfs
+        .read_link_contents(rel_path)
+        .with_context(|| format!("reading symlink {path}"))?;
+    let Ok(target) = Utf8PathBuf::try_from(target) else {
+        return Ok(None);
+    };
+    // SAFETY: paths in the FileMap are absolute, so they have a parent
+    let target = normalize_path(&path.parent().unwrap().join(target))?;
+    Ok(keg_of(cellar, &target).map(|(formula, _)| formula.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std::ambient_authority;
+
+    use super::*;
+    use crate::components::claim_names;
+
+    #[test]
+    fn test_keg_of() {
+        let cellar = Utf8Path::new("/home/linuxbrew/.linuxbrew/Cellar");
+        assert_eq!(
+            keg_of(
+                cellar,
+                Utf8Path::new("/home/linuxbrew/.linuxbrew/Cellar/jq/1.7.1/bin/jq")
+            ),
+            Some(("jq", Some("1.7.1")))
+        );
+        assert_eq!(
+            keg_of(
+                cellar,
+                Utf8Path::new("/home/linuxbrew/.linuxbrew/Cellar/jq")
+            ),
+            Some(("jq", None))
+        );
+        assert_eq!(keg_of(cellar, cellar), None);
+        assert_eq!(
+            keg_of(cellar, Utf8Path::new("/home/linuxbrew/.linuxbrew/bin/jq")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_brew_claims() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        let prefix = "home/linuxbrew/.linuxbrew";
+        for (keg, time) in [
+            ("jq/1.7.1", Some(1714000000)),
+            ("python@3.12/3.12.3", Some(1713000000)),
+            ("python@3.12/3.12.4", Some(1715000000)),
+            ("oniguruma/6.9.9", None),
+        ] {
+            let dir = format!("{prefix}/Cellar/{keg}");
+            rootfs.create_dir_all(format!("{dir}/bin")).unwrap();
+            rootfs.write(format!("{dir}/bin/tool"), "binary").unwrap();
+            if let Some(time) = time {
+                rootfs
+                    .write(
+                        format!("{dir}/{INSTALL_RECEIPT}"),
+                        format!(r#"{{"homebrew_version": "4.3.0", "time": {time}}}"#),
+                    )
+                    .unwrap();
+            }
+        }
+        rootfs.create_dir_all(format!("{prefix}/bin")).unwrap();
+        rootfs.create_dir_all(format!("{prefix}/opt")).unwrap();
+        rootfs
+            .symlink("../Cellar/jq/1.7.1/bin/tool", format!("{prefix}/bin/jq"))
+            .unwrap();
+        rootfs
+            .symlink(
+                "../Cellar/python@3.12/3.12.4",
+                format!("{prefix}/opt/python@3.12"),
+            )
+            .unwrap();
+        // cap-std refuses absolute targets
+        std::os::unix::fs::symlink(
+            "/usr/bin/true",
+            tmp.path().join(format!("{prefix}/bin/other")),
+        )
+        .unwrap();
+
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let repo = BrewRepo::load(&rootfs, &files, 1715000000 + 86400)
+            .unwrap()
+            .unwrap();
+
+        let cellar = "/home/linuxbrew/.linuxbrew/Cellar";
+        assert_eq!(
+            claim_names(&repo, &format!("{cellar}/jq/1.7.1/bin/tool")),
+            ["jq"]
+        );
+        assert_eq!(claim_names(&repo, &format!("{cellar}/jq")), ["jq"]);
+        assert!(claim_names(&repo, cellar).is_empty());
+
+        // versions are grouped together
+        assert_eq!(
+            claim_names(&repo, &format!("{cellar}/python@3.12/3.12.3/bin/tool")),
+            ["python@3.12"]
+        );
+
+        // links into kegs go with the formula
+        assert_eq!(
+            claim_names(&repo, "/home/linuxbrew/.linuxbrew/bin/jq"),
+            ["jq"]
+        );
+        assert_eq!(
+            claim_names(&repo, "/home/linuxbrew/.linuxbrew/opt/python@3.12"),
+            ["python@3.12"]
+        );
+        assert!(claim_names(&repo, "/home/linuxbrew/.linuxbrew/bin/other").is_empty());
+
+        // the clamp is the max install time of all versions, or now if unknown
+        let clamp = |path: &str| {
+            let claims = repo.claims_for_path(Utf8Path::new(path), FileType::File);
+            repo.component_info(claims[0]).mtime_clamp
+        };
+        assert_eq!(clamp(&format!("{cellar}/python@3.12")), 1715000000);
+        assert_eq!(clamp(&format!("{cellar}/jq")), 1714000000);
+        assert_eq!(clamp(&format!("{cellar}/oniguruma")), 1715000000 + 86400);
+    }
+
+    #[test]
+    fn test_brew_not_detected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        assert!(BrewRepo::load(&rootfs, &files, 0).unwrap().is_none());
+    }
+}
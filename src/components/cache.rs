@@ -0,0 +1,192 @@
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::{Dir, MetadataExt};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use openssl::hash::{Hasher, MessageDigest};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+/// Bump when the format of cached data changes.
+const CACHE_VERSION: u32 = 6;
+
+/// On-disk cache of parsed package databases.
+///
+/// Entries are keyed by a digest of the database files they were parsed from,
+/// so a changed database is never read from the cache. Only the parsing is
+/// cached; paths are still canonicalized against the rootfs on every build.
+/// Entries are never evicted, but the directory can be deleted at any time.
+#[derive(Debug, Clone)]
+pub struct ClaimCache {
+    dir: Utf8PathBuf,
+}
+
+/// Digest of the database files a cache entry is parsed from.
+pub(crate) struct CacheKey {
+    repo: &'static str,
+    hasher: Hasher,
+}
+
+impl CacheKey {
+    pub(crate) fn new(repo: &'static str) -> Result<Self> {
+        let mut hasher = Hasher::new(MessageDigest::sha256())?;
+        hasher.update(format!("{CACHE_VERSION}\t{repo}\n").as_bytes())?;
+        Ok(Self { repo, hasher })
+    }
+
+    /// Add the content of the file at `path` in `rootfs`.
+    pub(crate) fn file(&mut self, rootfs: &Dir, path: &str) -> Result<()> {
+        let mut file = rootfs
+            .open(path)
+            .with_context(|| format!("opening {path}"))?;
+        self.hasher.update(format!("{path}\n").as_bytes())?;
+        std::io::copy(&mut file, &mut self.hasher).with_context(|| format!("reading {path}"))?;
+        Ok(())
+    }
+
+    /// Add the size and mtime of the file at `path` in `rootfs`, or that it's
+    /// missing.
+    pub(crate) fn file_metadata(&mut self, rootfs: &Dir, path: &str) -> Result<()> {
+        let metadata = rootfs
+            .metadata_optional(path)
+            .with_context(|| format!("querying {path}"))?;
+        let line = match metadata {
+            Some(metadata) => format!("{path}\t{}\t{}\n", metadata.len(), metadata.mtime()),
+            None => format!("{path}\tmissing\n"),
+        };
+        self.hasher.update(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Add the content of all the files directly in the directory at `path`
+    /// in `rootfs`.
+    pub(crate) fn dir_files(&mut self, rootfs: &Dir, path: &str) -> Result<()> {
+        for name in sorted_entries(rootfs, path)? {
+            let entry_path = format!("{path}/{name}");
+            let metadata = rootfs
+                .symlink_metadata(&entry_path)
+                .with_context(|| format!("querying {entry_path}"))?;
+            if metadata.is_file() {
+                self.file(rootfs, &entry_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the names, sizes and mtimes of the entries in the directory at
+    /// `path` in `rootfs`, for when reading them all would cost about as much
+    /// as parsing them.
+    pub(crate) fn dir_metadata(&mut self, rootfs: &Dir, path: &str) -> Result<()> {
+        for name in sorted_entries(rootfs, path)? {
+            let entry_path = format!("{path}/{name}");
+            let metadata = rootfs
+                .symlink_metadata(&entry_path)
+                .with_context(|| format!("querying {entry_path}"))?;
+            let line = format!("{entry_path}\t{}\t{}\n", metadata.len(), metadata.mtime());
+            self.hasher.update(line.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the names of the entries in the directory at `path`, sorted.
+fn sorted_entries(rootfs: &Dir, path: &str) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in rootfs
+        .read_dir(path)
+        .with_context(|| format!("reading directory {path}"))?
+    {
+        let entry = entry.with_context(|| format!("reading entry in {path}"))?;
+        let name = entry.file_name();
+        let name = name
+            .to_str()
+            .with_context(|| format!("non-UTF-8 file name in {path}: {name:?}"))?;
+        names.push(name.to_string());
+    }
+    names.sort_unstable();
+    Ok(names)
+}
+
+impl ClaimCache {
+    pub fn new(dir: impl Into<Utf8PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Returns the cached value for `key`, or computes it with `f` and caches
+    /// it.
+    pub(crate) fn get_or_insert_with<T, F>(&self, mut key: CacheKey, f: F) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Result<T>,
+    {
+        let digest = hex::encode(key.hasher.finish()?);
+        let path = self.dir.join(format!("{}-{digest}.json", key.repo));
+
+        match std::fs::read(&path) {
+            Ok(content) => {
+                return serde_json::from_slice(&content)
+                    .with_context(|| format!("parsing cached {path}"));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("reading {path}")),
+        }
+
+        let value = f()?;
+        self.write(&path, &value)
+            .with_context(|| format!("writing {path}"))?;
+        Ok(value)
+    }
+
+    fn write<T: Serialize>(&self, path: &Utf8Path, value: &T) -> Result<()> {
+        std::fs::create_dir_all(&self.dir).with_context(|| format!("creating {}", self.dir))?;
+        // write to a temporary file first so that concurrent builds never see
+        // a partial entry
+        let tmp_path = Utf8PathBuf::from(format!("{path}.{}.tmp", std::process::id()));
+        let content = serde_json::to_vec(value).context("serializing")?;
+        std::fs::write(&tmp_path, content).with_context(|| format!("writing {tmp_path}"))?;
+        std::fs::rename(&tmp_path, path).with_context(|| format!("renaming {tmp_path}"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use cap_std_ext::cap_std::ambient_authority;
+
+    use super::*;
+
+    #[test]
+    fn test_get_or_insert_with() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("var/lib/db").unwrap();
+        rootfs.write("var/lib/db/packages", "foo").unwrap();
+
+        let cache_tmp = tempfile::tempdir().unwrap();
+        let cache = ClaimCache::new(Utf8Path::from_path(cache_tmp.path()).unwrap());
+        let key = || {
+            let mut key = CacheKey::new("test").unwrap();
+            key.dir_files(&rootfs, "var/lib/db").unwrap();
+            key
+        };
+        let parses = Cell::new(0);
+        let parse = || -> Result<Vec<String>> {
+            parses.set(parses.get() + 1);
+            Ok(vec![rootfs.read_to_string("var/lib/db/packages").unwrap()])
+        };
+
+        let value: Vec<String> = cache.get_or_insert_with(key(), parse).unwrap();
+        assert_eq!(value, ["foo"]);
+        let value: Vec<String> = cache.get_or_insert_with(key(), parse).unwrap();
+        assert_eq!(value, ["foo"]);
+        assert_eq!(parses.get(), 1);
+
+        // a changed database isn't read from the cache
+        rootfs.write("var/lib/db/packages", "bar").unwrap();
+        let value: Vec<String> = cache.get_or_insert_with(key(), parse).unwrap();
+        assert_eq!(value, ["bar"]);
+        assert_eq!(parses.get(), 2);
+        assert_eq!(cache_tmp.path().read_dir().unwrap().count(), 2);
+    }
+}
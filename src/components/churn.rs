@@ -0,0 +1,268 @@
+//! Stability learned from the components which changed between builds.
+//!
+//! Changelogs are a poor predictor for e.g. internal packages, which may be
+//! rebuilt daily without a new entry. With `--state-dir`, each build records a
+//! digest of the metadata of every component, and the times at which it
+//! differed from the previous build. Once a component has been observed for a
+//! while, the stability implied by its observed changes is blended into the
+//! stability computed by its repo, with more weight the longer it's been
+//! observed.
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use openssl::hash::{Hasher, MessageDigest};
+use serde::{Deserialize, Serialize};
+
+use super::{Component, FileType, SECS_PER_DAY, STABILITY_LOOKBACK_DAYS, STABILITY_PERIOD_DAYS};
+
+/// Name of the state file in the state directory.
+const STATE_FILE: &str = "churn.json";
+
+/// Version of the state file format.
+const STATE_VERSION: u32 = 1;
+
+/// Days of observation after which the observed stability weighs as much as
+/// the computed one.
+const BLEND_HALF_DAYS: f64 = 30.0;
+
+/// What's known about the changes of components from previous builds.
+#[derive(Debug)]
+pub struct ChurnState {
+    path: Utf8PathBuf,
+    state: State,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    version: u32,
+    components: BTreeMap<String, Observed>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Observed {
+    /// Digest of the metadata of the files of the component in the last
+    /// build.
+    digest: String,
+    /// When the component was first seen, within the lookback period.
+    first_seen: u64,
+    /// When the component was last seen.
+    last_seen: u64,
+    /// When builds saw the component change, within the lookback period.
+    changes: Vec<u64>,
+}
+
+impl ChurnState {
+    /// Load the state from the directory `dir`, if it's there.
+    pub fn load(dir: &Utf8Path) -> Result<Self> {
+        let path = dir.join(STATE_FILE);
+        let state = match std::fs::read(&path) {
+            Ok(content) => {
+                let state: State =
+                    serde_json::from_slice(&content).with_context(|| format!("parsing {path}"))?;
+                anyhow::ensure!(
+                    state.version == STATE_VERSION,
+                    "{path}: unsupported version {}",
+                    state.version
+                );
+                state
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => State {
+                version: STATE_VERSION,
+                components: BTreeMap::new(),
+            },
+            Err(e) => return Err(e).with_context(|| format!("reading {path}")),
+        };
+        Ok(Self { path, state })
+    }
+
+    /// Blend the observed stability of `components` into their computed one.
+    pub fn blend(&self, components: &mut HashMap<String, Component>, now: u64) {
+        for (name, component) in components.iter_mut() {
+            let Some(observed) = self.state.components.get(name) else {
+                continue;
+            };
+            let Some((stability, weight)) = observed.stability(now) else {
+                continue;
+            };
+            let blended = weight * stability + (1.0 - weight) * component.stability;
+            tracing::trace!(
+                target: crate::trace::PACK,
+                "{name}: stability {:.3} blended with observed {stability:.3} (weight {weight:.2}) to {blended:.3}",
+                component.stability
+            );
+            component.stability = blended;
+        }
+    }
+
+    /// Record the components of this build, noting those which changed since
+    /// the last one.
+    pub fn observe(&mut self, components: &HashMap<String, Component>, now: u64) -> Result<()> {
+        for (name, component) in components {
+            let digest =
+                component_digest(component).with_context(|| format!("hashing component {name}"))?;
+            match self.state.components.get_mut(name) {
+                Some(observed) => {
+                    if observed.digest != digest {
+                        observed.changes.push(now);
+                        observed.digest = digest;
+                    }
+                    observed.last_seen = now;
+                }
+                None => {
+                    self.state.components.insert(
+                        name.clone(),
+                        Observed {
+                            digest,
+                            first_seen: now,
+                            last_seen: now,
+                            changes: Vec::new(),
+                        },
+                    );
+                }
+            }
+        }
+
+        // forget what's too old to matter, including components gone for good
+        let lookback_start = now.saturating_sub(STABILITY_LOOKBACK_DAYS * SECS_PER_DAY);
+        self.state
+            .components
+            .retain(|_, observed| observed.last_seen >= lookback_start);
+        for observed in self.state.components.values_mut() {
+            observed.changes.retain(|&t| t >= lookback_start);
+            observed.first_seen = observed.first_seen.max(lookback_start);
+        }
+        Ok(())
+    }
+
+    /// Write the state back to the state directory, creating it if needed.
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("creating {dir}"))?;
+        }
+        let content = serde_json::to_vec_pretty(&self.state).context("serializing state")?;
+        // don't leave a truncated state behind if we're interrupted
+        let tmp_path = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, content).with_context(|| format!("writing {tmp_path}"))?;
+        std::fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("renaming {tmp_path} to {}", self.path))
+    }
+}
+
+impl Observed {
+    /// Returns the stability implied by the observed changes, and how much
+    /// weight it should get, if observed for long enough.
+    fn stability(&self, now: u64) -> Option<(f64, f64)> {
+        let span_days = now.saturating_sub(self.first_seen) as f64 / SECS_PER_DAY as f64;
+        if span_days < 1.0 {
+            return None;
+        }
+        // changes per day, as in utils::calculate_stability()
+        let lambda = self.changes.len() as f64 / span_days;
+        let stability = (-lambda * STABILITY_PERIOD_DAYS).exp().min(0.99);
+        let weight = span_days / (span_days + BLEND_HALF_DAYS);
+        Some((stability, weight))
+    }
+}
+
+/// Digest of the metadata of the files of a component, as it ends up in its
+/// layer, and of their content if it was hashed while scanning. Hashing the
+/// content is more accurate, but much slower.
+fn component_digest(component: &Component) -> Result<String> {
+    let mut hasher = Hasher::new(MessageDigest::sha256())?;
+    for (path, info) in &component.files {
+        let file_type = match info.file_type {
+            FileType::Directory => "dir",
+            FileType::File => "file",
+            FileType::Symlink => "symlink",
+            FileType::BlockDevice => "block",
+            FileType::CharDevice => "char",
+            FileType::Fifo => "fifo",
+        };
+        let line = format!(
+            "{path}\t{file_type}\t{:o}\t{}\t{}:{}\t{}\n",
+            info.mode,
+            info.size,
+            info.uid,
+            info.gid,
+            info.mtime.min(component.mtime_clamp)
+        );
+        hasher.update(line.as_bytes())?;
+        if info.rdev() != 0 {
+            hasher.update(format!("\trdev {:x}\n", info.rdev()).as_bytes())?;
+        }
+        if let Some(sha256) = info.sha256() {
+            hasher.update(format!("\tsha256 {}\n", hex::encode(sha256.as_slice())).as_bytes())?;
+        }
+        for (name, value) in info.xattrs.iter() {
+            hasher.update(format!("\txattr {name}={}\n", hex::encode(value)).as_bytes())?;
+        }
+    }
+    Ok(hex::encode(hasher.finish()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{FileInfo, FileMap};
+
+    fn component(size: u64) -> Component {
+        let info = FileInfo::test_file(FileType::File, size, 1000);
+        Component {
+            mtime_clamp: 1000,
+            stability: 0.9,
+            files: FileMap::from([(Utf8PathBuf::from("/usr/bin/app"), info)]),
+            packages: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_churn_state() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(tmp.path()).unwrap().join("state");
+        let day = SECS_PER_DAY;
+        let start = 1_700_000_000;
+
+        // a daily build for 30 days, where the app changes every day and the
+        // base never does
+        for i in 0..30 {
+            let mut state = ChurnState::load(&dir).unwrap();
+            let components: HashMap<String, Component> = [
+                ("app".to_string(), component(i)),
+                ("base".to_string(), component(42)),
+            ]
+            .into();
+            state.observe(&components, start + i * day).unwrap();
+            state.save().unwrap();
+        }
+
+        let state = ChurnState::load(&dir).unwrap();
+        assert_eq!(state.state.components["app"].changes.len(), 29);
+        assert!(state.state.components["base"].changes.is_empty());
+
+        let mut components: HashMap<String, Component> = [
+            ("app".to_string(), component(0)),
+            ("base".to_string(), component(42)),
+            ("new".to_string(), component(1)),
+        ]
+        .into();
+        state.blend(&mut components, start + 30 * day);
+        // about half the weight goes to the observed stability after 30 days
+        assert!(components["app"].stability < 0.5);
+        assert!(components["base"].stability > 0.9);
+        assert_eq!(components["new"].stability, 0.9);
+
+        // components not seen in a year are forgotten
+        let mut state = ChurnState::load(&dir).unwrap();
+        state
+            .observe(
+                &[("base".to_string(), component(42))].into(),
+                start + 400 * day,
+            )
+            .unwrap();
+        assert!(!state.state.components.contains_key("app"));
+        assert!(state.state.components.contains_key("base"));
+    }
+}
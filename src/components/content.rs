@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+use super::{ComponentId, ComponentInfo, ComponentsRepo, FileMap, FileType};
+
+const REPO_NAME: &str = "content";
+
+/// Content classes and the trees they're made of.
+const CLASSES: &[(&str, &[&str])] = &[
+    (
+        "doc",
+        &[
+            "/usr/share/doc",
+            "/usr/share/gtk-doc",
+            "/usr/share/help",
+            "/usr/share/info",
+        ],
+    ),
+    ("locale", &["/usr/share/locale"]),
+    ("man", &["/usr/share/man"]),
+    ("icons", &["/usr/share/icons", "/usr/share/pixmaps"]),
+];
+
+/// Content classes components repo implementation.
+///
+/// Carves documentation, translations, man pages and icons out of the
+/// components of their packages into one component per class, like rpm-ostree
+/// does. These trees are large and rarely read at runtime, and a few of their
+/// files change with almost every package update, so keeping them out of the
+/// layers of the packages lets those be reused more often.
+///
+/// Unlike the other repos, this one is only used when enabled with
+/// [`super::RepoConfig::content_classes`], since it goes against the
+/// packaging.
+pub struct ContentRepo {
+    /// The content classes with files, indexed by ComponentId, along with
+    /// their mtime clamp.
+    components: Vec<(&'static str, u64)>,
+    /// Mapping from path to ComponentId.
+    path_to_component: HashMap<Utf8PathBuf, ComponentId>,
+}
+
+impl ContentRepo {
+    /// Load the content classes repo by matching `files` against the trees of
+    /// each class.
+    ///
+    /// Returns None if no class has files.
+    pub fn load(files: &FileMap, default_mtime_clamp: u64) -> Option<Self> {
+        let mut components = Vec::new();
+        let mut path_to_component = HashMap::new();
+
+        for (class, roots) in CLASSES {
+            let id = ComponentId(components.len());
+            // the files keep the mtime they came with unless newer than the
+            // default clamp, so that the layer only changes with the files
+            let mut mtime_clamp = None;
+            for (path, file_info) in files {
+                if roots.iter().any(|root| path.starts_with(root)) {
+                    path_to_component.insert(path.clone(), id);
+                    let mtime = file_info.mtime.min(default_mtime_clamp);
+                    mtime_clamp = Some(mtime_clamp.map_or(mtime, |clamp: u64| clamp.max(mtime)));
+                }
+            }
+            if let Some(mtime_clamp) = mtime_clamp {
+                components.push((*class, mtime_clamp));
+            }
+        }
+
+        if components.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            components,
+            path_to_component,
+        })
+    }
+}
+
+impl ComponentsRepo for ContentRepo {
+    fn name(&self) -> &'static str {
+        REPO_NAME
+    }
+
+    fn default_priority(&self) -> usize {
+        // Before the package managers, which would otherwise claim the files
+        // for their packages, but after explicit assignments.
+        5
+    }
+
+    fn claims_for_path(&self, path: &Utf8Path, _file_type: FileType) -> Vec<ComponentId> {
+        self.path_to_component
+            .get(path)
+            .map(|id| vec![*id])
+            .unwrap_or_default()
+    }
+
+    fn component_info(&self, id: ComponentId) -> ComponentInfo<'_> {
+        let (name, mtime_clamp) = self.components[id.0];
+        ComponentInfo {
+            name,
+            mtime_clamp,
+            // 0.0 means it gets the fallback stability; these change with
+            // most package updates
+            stability: 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{FileInfo, claim_names};
+
+    fn file_map(paths: &[(&str, u64)]) -> FileMap {
+        paths
+            .iter()
+            .map(|(p, mtime)| {
+                let info = FileInfo::test_file(FileType::File, 0, *mtime);
+                (Utf8PathBuf::from(*p), info)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_content_classes() {
+        let files = file_map(&[
+            ("/usr/bin/bash", 100),
+            ("/usr/share/doc", 100),
+            ("/usr/share/doc/bash/README", 100),
+            ("/usr/share/info/bash.info.gz", 300),
+            ("/usr/share/locale/de/LC_MESSAGES/bash.mo", 200),
+            ("/usr/share/man/man1/bash.1.gz", 5000),
+            ("/usr/share/documentation/other", 100),
+        ]);
+        let repo = ContentRepo::load(&files, 1000).unwrap();
+
+        assert_eq!(claim_names(&repo, "/usr/share/doc"), ["doc"]);
+        assert_eq!(claim_names(&repo, "/usr/share/doc/bash/README"), ["doc"]);
+        assert_eq!(claim_names(&repo, "/usr/share/info/bash.info.gz"), ["doc"]);
+        assert_eq!(
+            claim_names(&repo, "/usr/share/locale/de/LC_MESSAGES/bash.mo"),
+            ["locale"]
+        );
+        assert_eq!(claim_names(&repo, "/usr/share/man/man1/bash.1.gz"), ["man"]);
+        assert!(claim_names(&repo, "/usr/bin/bash").is_empty());
+        // only whole path components match
+        assert!(claim_names(&repo, "/usr/share/documentation/other").is_empty());
+
+        // no icons, so no component for them
+        assert_eq!(repo.components.len(), 3);
+
+        let info = |path: &str| {
+            let claims = repo.claims_for_path(Utf8Path::new(path), FileType::File);
+            repo.component_info(claims[0])
+        };
+        assert_eq!(info("/usr/share/doc").mtime_clamp, 300);
+        // clamped to the default
+        assert_eq!(info("/usr/share/man/man1/bash.1.gz").mtime_clamp, 1000);
+
+        assert!(ContentRepo::load(&file_map(&[("/usr/bin/bash", 100)]), 1000).is_none());
+    }
+}
@@ -0,0 +1,699 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::diagnostics::{self, Diagnostic};
+use crate::utils::{calculate_stability, canonicalize_parent_path};
+
+use super::cache::CacheKey;
+use super::{ComponentId, ComponentInfo, ComponentsRepo, FileMap, FileType, RepoConfig};
+
+const REPO_NAME: &str = "dpkg";
+
+const DPKG_STATUS_PATH: &str = "var/lib/dpkg/status";
+const DPKG_INFO_PATH: &str = "var/lib/dpkg/info";
+const DOC_PATH: &str = "usr/share/doc";
+
+/// Names of the changelog in the doc directory of a package: built from a
+/// Debian source package, or a native one.
+const CHANGELOG_NAMES: &[&str] = &["changelog.Debian.gz", "changelog.gz"];
+
+/// Component for files which don't match their `.md5sums` with `verify`.
+const MODIFIED_COMPONENT: &str = "modified";
+
+/// Dpkg-based components repo implementation.
+///
+/// Uses the dpkg database to determine file ownership and groups files by
+/// their source package. The stability of a component is derived from the
+/// dates of the entries of the Debian changelog of its packages, like the rpm
+/// repo does with the RPM changelog.
+///
+/// When verifying, files whose digest doesn't match the `.md5sums` of their
+/// package go into a single `modified` component instead, like with rpm.
+pub struct DpkgRepo {
+    /// Component (source package) names mapped to their stability, indexed by
+    /// ComponentId.
+    components: IndexMap<String, f64>,
+
+    /// Mapping from path to list of ComponentId.
+    ///
+    /// Like for RPM, directories are commonly owned by more than one component.
+    path_to_components: HashMap<Utf8PathBuf, Vec<ComponentId>>,
+
+    /// The dpkg database doesn't record build times, so we use the default
+    /// mtime clamp for all components.
+    default_mtime_clamp: u64,
+}
+
+impl DpkgRepo {
+    /// Load the dpkg database from the given rootfs. The `files` parameter is
+    /// used to canonicalize paths from the dpkg database.
+    ///
+    /// Returns `Ok(None)` if no dpkg database is detected. The cache of parsed
+    /// databases and whether to verify files come from `config`. Verifying
+    /// files against the `.md5sums` means reading all of them.
+    pub fn load(
+        rootfs: &Dir,
+        files: &FileMap,
+        default_mtime_clamp: u64,
+        config: &RepoConfig,
+    ) -> Result<Option<Self>> {
+        if !rootfs
+            .try_exists(DPKG_STATUS_PATH)
+            .with_context(|| format!("checking for {DPKG_STATUS_PATH}"))?
+        {
+            return Ok(None);
+        }
+
+        let packages = match &config.cache {
+            Some(cache) => {
+                // the file lists are small, so reading them all to digest them
+                // would cost about as much as parsing them
+                let mut key = CacheKey::new(REPO_NAME)?;
+                key.file(rootfs, DPKG_STATUS_PATH)?;
+                key.dir_metadata(rootfs, DPKG_INFO_PATH)?;
+                // the changelogs are outside of the database, and change with
+                // the packages unless they were removed from the image
+                let status = rootfs
+                    .read_to_string(DPKG_STATUS_PATH)
+                    .with_context(|| format!("reading {DPKG_STATUS_PATH}"))?;
+                for pkg in parse_status(&status).context("parsing dpkg status")? {
+                    for changelog in CHANGELOG_NAMES {
+                        key.file_metadata(rootfs, &format!("{DOC_PATH}/{}/{changelog}", pkg.name))?;
+                    }
+                }
+                cache.get_or_insert_with(key, || read_packages(rootfs))?
+            }
+            None => read_packages(rootfs)?,
+        };
+
+        // the dpkg database doesn't record build times, so this is our best
+        // guess for the current time
+        let now = default_mtime_clamp;
+        let mut components: IndexMap<String, f64> = IndexMap::new();
+        let mut path_to_components: HashMap<Utf8PathBuf, Vec<ComponentId>> = HashMap::new();
+        let mut path_cache = HashMap::new();
+        let mut modified = HashSet::new();
+
+        for pkg in packages {
+            let entry = components.entry(pkg.source);
+            let component_id = ComponentId(entry.index());
+            let stability = entry.or_insert(0.0);
+            // the packages of a source share its changelog, but not all of
+            // them ship it; without one, the component gets the fallback
+            if *stability == 0.0
+                && let Some(&newest) = pkg.changelog_times.iter().max()
+            {
+                *stability = calculate_stability(&pkg.changelog_times, newest, now)?;
+            }
+
+            for path in pkg.paths {
+                let canonical = canonicalize_parent_path(rootfs, files, &path, &mut path_cache)
+                    .with_context(|| format!("canonicalizing {}", path))?;
+                let entries = path_to_components.entry(canonical).or_default();
+                if !entries.contains(&component_id) {
+                    entries.push(component_id);
+                }
+            }
+
+            if config.dpkg_verify {
+                for (path, md5) in pkg.md5sums {
+                    let canonical = canonicalize_parent_path(rootfs, files, &path, &mut path_cache)
+                        .with_context(|| format!("canonicalizing {}", path))?;
+                    if is_modified(rootfs, files, &canonical, &md5)
+                        .with_context(|| format!("verifying {canonical}"))?
+                    {
+                        modified.insert(canonical);
+                    }
+                }
+            }
+        }
+
+        if !modified.is_empty() {
+            let entry = components.entry(MODIFIED_COMPONENT.to_string());
+            let component_id = ComponentId(entry.index());
+            // whenever they were modified, it was after they were installed
+            entry.or_insert(0.0);
+            for path in modified {
+                path_to_components.insert(path, vec![component_id]);
+            }
+        }
+
+        Ok(Some(Self {
+            components,
+            path_to_components,
+            default_mtime_clamp,
+        }))
+    }
+}
+
+impl ComponentsRepo for DpkgRepo {
+    fn name(&self) -> &'static str {
+        REPO_NAME
+    }
+
+    fn default_priority(&self) -> usize {
+        10
+    }
+
+    fn claims_for_path(&self, path: &Utf8Path, _file_type: FileType) -> Vec<ComponentId> {
+        // The dpkg database doesn't record file types, so we can't filter on it.
+        self.path_to_components
+            .get(path)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn component_info(&self, id: ComponentId) -> ComponentInfo<'_> {
+        let (name, stability) = self
+            .components
+            .get_index(id.0)
+            // SAFETY: the ids we're given come from the IndexMap itself when
+            // we inserted the element, so it must be valid.
+            .expect("invalid ComponentId");
+        ComponentInfo {
+            name,
+            mtime_clamp: self.default_mtime_clamp,
+            stability: *stability,
+        }
+    }
+}
+
+/// The files of an installed package, in a form that can be cached.
+#[derive(Serialize, Deserialize)]
+struct PackageFiles {
+    /// Source package name.
+    source: String,
+    paths: Vec<Utf8PathBuf>,
+    /// Dates of the entries of its changelog, if it ships one.
+    changelog_times: Vec<u64>,
+    /// Paths of its regular files with their MD5 hex digest, from its
+    /// `.md5sums` file.
+    md5sums: Vec<(Utf8PathBuf, String)>,
+}
+
+/// Read the dpkg status file and the file lists of the installed packages.
+fn read_packages(rootfs: &Dir) -> Result<Vec<PackageFiles>> {
+    let status = rootfs
+        .read_to_string(DPKG_STATUS_PATH)
+        .with_context(|| format!("reading {DPKG_STATUS_PATH}"))?;
+    let packages = parse_status(&status).context("parsing dpkg status")?;
+
+    let info_dir = rootfs
+        .open_dir(DPKG_INFO_PATH)
+        .with_context(|| format!("opening {DPKG_INFO_PATH}"))?;
+
+    let mut result = Vec::new();
+    for pkg in packages {
+        let Some(list) = read_info_file(&info_dir, &pkg, "list")
+            .with_context(|| format!("reading file list for {}", pkg.name))?
+        else {
+            continue;
+        };
+
+        let mut paths = Vec::new();
+        for line in list.lines() {
+            // the root directory is listed as "/."
+            let path = match line.trim_end() {
+                "" => continue,
+                "/." => "/",
+                path => path,
+            };
+            let path = Utf8Path::new(path);
+            if !path.is_absolute() {
+                anyhow::bail!("non-absolute path in {} file list: {}", pkg.name, path);
+            }
+            paths.push(path.to_owned());
+        }
+        let md5sums = match read_info_file(&info_dir, &pkg, "md5sums")
+            .with_context(|| format!("reading md5sums for {}", pkg.name))?
+        {
+            Some(content) => parse_md5sums(&content)
+                .with_context(|| format!("parsing md5sums for {}", pkg.name))?,
+            None => Vec::new(),
+        };
+        // the changelog only feeds the stability, so a broken one isn't worth
+        // failing the build over
+        let changelog_times = read_changelog_times(rootfs, &pkg.name).unwrap_or_else(|e| {
+            diagnostics::report(&[Diagnostic::warning(format!(
+                "ignoring the changelog of dpkg package {}: {e:#}",
+                pkg.name
+            ))]);
+            Vec::new()
+        });
+        result.push(PackageFiles {
+            source: pkg.source,
+            paths,
+            changelog_times,
+            md5sums,
+        });
+    }
+    Ok(result)
+}
+
+/// An installed package from the dpkg status file.
+#[derive(Debug, PartialEq)]
+struct DpkgPackage {
+    name: String,
+    /// Source package name, falling back to the binary package name.
+    source: String,
+    architecture: Option<String>,
+}
+
+/// Parse the dpkg status file, returning only installed packages.
+///
+/// The file is a series of RFC 822-style paragraphs separated by blank lines.
+fn parse_status(content: &str) -> Result<Vec<DpkgPackage>> {
+    let mut packages = Vec::new();
+
+    for paragraph in content.split("\n\n") {
+        let mut fields: HashMap<&str, &str> = HashMap::new();
+        for line in paragraph.lines() {
+            // skip continuation lines; we don't need any multi-line fields
+            if line.is_empty() || line.starts_with([' ', '\t']) {
+                continue;
+            }
+            let (key, value) = line
+                .split_once(':')
+                .with_context(|| format!("invalid line in dpkg status: {line}"))?;
+            fields.insert(key, value.trim());
+        }
+
+        let Some(name) = fields.get("Package") else {
+            continue;
+        };
+
+        // e.g. "install ok installed"; skip anything not fully installed
+        let installed = fields
+            .get("Status")
+            .is_some_and(|s| s.split_whitespace().last() == Some("installed"));
+        if !installed {
+            continue;
+        }
+
+        // the Source field may include a version, e.g. "glibc (2.36-9)"
+        let source = fields
+            .get("Source")
+            .and_then(|s| s.split_whitespace().next())
+            .unwrap_or(name);
+
+        packages.push(DpkgPackage {
+            name: name.to_string(),
+            source: source.to_string(),
+            architecture: fields.get("Architecture").map(|s| s.to_string()),
+        });
+    }
+
+    Ok(packages)
+}
+
+/// Returns the dates of the entries of the changelog of the package `name`, or
+/// nothing if it doesn't ship one, e.g. because the docs were excluded.
+fn read_changelog_times(rootfs: &Dir, name: &str) -> Result<Vec<u64>> {
+    for changelog in CHANGELOG_NAMES {
+        let path = format!("{DOC_PATH}/{name}/{changelog}");
+        if let Some(file) = rootfs
+            .open_optional(&path)
+            .with_context(|| format!("opening {path}"))?
+        {
+            let reader = BufReader::new(flate2::read::GzDecoder::new(file));
+            return parse_changelog_times(reader).with_context(|| format!("reading {path}"));
+        }
+    }
+    Ok(Vec::new())
+}
+
+/// Parse the dates of the entries of a Debian changelog, from their trailer
+/// lines, e.g. ` -- Jane Doe <jane@example.org>  Sat, 01 Jun 2024 12:00:00 +0200`.
+///
+/// Old entries may not be UTF-8 or have malformed dates, and are skipped.
+fn parse_changelog_times<R: BufRead>(mut reader: R) -> Result<Vec<u64>> {
+    let mut times = Vec::new();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        let Some(trailer) = line.strip_prefix(b" -- ") else {
+            continue;
+        };
+        let trailer = String::from_utf8_lossy(trailer);
+        if let Some((_, date)) = trailer.rsplit_once(">  ")
+            && let Ok(date) = chrono::DateTime::parse_from_rfc2822(date.trim())
+            && let Ok(time) = u64::try_from(date.timestamp())
+        {
+            times.push(time);
+        }
+    }
+    Ok(times)
+}
+
+/// Parse a `.md5sums` file, made of lines with an MD5 hex digest and a path
+/// relative to the root, e.g. `d41d8cd98f00b204e9800998ecf8427e  usr/bin/ldd`.
+fn parse_md5sums(content: &str) -> Result<Vec<(Utf8PathBuf, String)>> {
+    let mut md5sums = Vec::new();
+    for line in content.lines() {
+        if line.is_empty() {
+            continue;
+        }
+        let (md5, path) = line
+            .split_once("  ")
+            .with_context(|| format!("invalid line: {line}"))?;
+        md5sums.push((Utf8Path::new("/").join(path), md5.to_ascii_lowercase()));
+    }
+    Ok(md5sums)
+}
+
+/// Returns whether the regular file at `path` doesn't have the MD5 digest
+/// `md5`.
+fn is_modified(rootfs: &Dir, files: &FileMap, path: &Utf8Path, md5: &str) -> Result<bool> {
+    // missing files or files replaced by another type aren't ours to claim
+    // anyway
+    if !files
+        .get(path)
+        .is_some_and(|info| info.file_type == FileType::File)
+    {
+        return Ok(false);
+    }
+    let actual = super::rpm::file_digest(rootfs, path, md5.len())?;
+    Ok(actual.is_some_and(|actual| actual != md5))
+}
+
+/// Read the `.<ext>` file (e.g. `.list`) for a package from the dpkg info
+/// directory.
+///
+/// Multi-arch packages use `<name>:<arch>.<ext>`, others use `<name>.<ext>`.
+/// Returns `Ok(None)` if neither exists (e.g. metapackages without files).
+fn read_info_file(info_dir: &Dir, pkg: &DpkgPackage, ext: &str) -> Result<Option<String>> {
+    let mut candidates = Vec::new();
+    if let Some(arch) = &pkg.architecture {
+        candidates.push(format!("{}:{}.{ext}", pkg.name, arch));
+    }
+    candidates.push(format!("{}.{ext}", pkg.name));
+
+    for candidate in candidates {
+        if info_dir
+            .try_exists(&candidate)
+            .with_context(|| format!("checking for {candidate}"))?
+        {
+            return info_dir
+                .read_to_string(&candidate)
+                .with_context(|| format!("reading {candidate}"))
+                .map(Some);
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std::ambient_authority;
+
+    use super::super::cache::ClaimCache;
+    use super::*;
+    use crate::components::claim_names;
+
+    const STATUS_FIXTURE: &str = "\
+Package: libc6
+Status: install ok installed
+Priority: optional
+Architecture: amd64
+Multi-Arch: same
+Source: glibc (2.36-9+deb12u4)
+Version: 2.36-9+deb12u4
+Description: GNU C Library: Shared libraries
+ Contains the standard libraries that are used by nearly all programs on
+ the system.
+
+Package: libc-bin
+Status: install ok installed
+Architecture: amd64
+Source: glibc
+Version: 2.36-9+deb12u4
+
+Package: bash
+Status: install ok installed
+Architecture: amd64
+Version: 5.2.15-2+b2
+
+Package: removed-pkg
+Status: deinstall ok config-files
+Architecture: amd64
+Version: 1.0
+";
+
+    fn setup_rootfs() -> (tempfile::TempDir, Dir) {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("var/lib/dpkg/info").unwrap();
+        rootfs.create_dir_all("usr/bin").unwrap();
+        rootfs.create_dir_all("usr/lib/x86_64-linux-gnu").unwrap();
+        rootfs.symlink("usr/bin", "bin").unwrap();
+        rootfs.write(DPKG_STATUS_PATH, STATUS_FIXTURE).unwrap();
+        rootfs
+            .write(
+                "var/lib/dpkg/info/libc6:amd64.list",
+                "/.\n/usr\n/usr/lib\n/usr/lib/x86_64-linux-gnu\n/usr/lib/x86_64-linux-gnu/libc.so.6\n",
+            )
+            .unwrap();
+        rootfs
+            .write(
+                "var/lib/dpkg/info/libc-bin.list",
+                "/.\n/usr\n/usr/bin\n/usr/bin/ldd\n",
+            )
+            .unwrap();
+        rootfs
+            .write("var/lib/dpkg/info/bash.list", "/.\n/bin\n/bin/bash\n")
+            .unwrap();
+        rootfs
+            .write("var/lib/dpkg/info/removed-pkg.list", "/etc/removed.conf\n")
+            .unwrap();
+        (tmp, rootfs)
+    }
+
+    #[test]
+    fn test_parse_status() {
+        let packages = parse_status(STATUS_FIXTURE).unwrap();
+        let names: Vec<_> = packages
+            .iter()
+            .map(|p| (p.name.as_str(), p.source.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            [("libc6", "glibc"), ("libc-bin", "glibc"), ("bash", "bash")]
+        );
+    }
+
+    #[test]
+    fn test_dpkg_claims() {
+        let (_tmp, rootfs) = setup_rootfs();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let repo = DpkgRepo::load(&rootfs, &files, 1234, &RepoConfig::new())
+            .unwrap()
+            .unwrap();
+
+        // subpackages of the same source are grouped together
+        assert_eq!(
+            claim_names(&repo, "/usr/lib/x86_64-linux-gnu/libc.so.6"),
+            ["glibc"]
+        );
+        assert_eq!(claim_names(&repo, "/usr/bin/ldd"), ["glibc"]);
+
+        // /bin -> usr/bin is canonicalized
+        assert_eq!(claim_names(&repo, "/usr/bin/bash"), ["bash"]);
+
+        // directories listed by subpackages of the same source are claimed once
+        assert_eq!(claim_names(&repo, "/usr"), ["glibc"]);
+
+        // shared directories are claimed by multiple components
+        let mut root = claim_names(&repo, "/");
+        root.sort();
+        assert_eq!(root, ["bash", "glibc"]);
+
+        // removed packages don't claim anything
+        assert!(claim_names(&repo, "/etc/removed.conf").is_empty());
+
+        let claims = repo.claims_for_path(Utf8Path::new("/usr/bin/ldd"), FileType::File);
+        assert_eq!(repo.component_info(claims[0]).mtime_clamp, 1234);
+    }
+
+    #[test]
+    fn test_dpkg_claims_cached() {
+        let (_tmp, rootfs) = setup_rootfs();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let cache_tmp = tempfile::tempdir().unwrap();
+        let cache = ClaimCache::new(Utf8Path::from_path(cache_tmp.path()).unwrap());
+        let config = RepoConfig::new().claim_cache(cache);
+
+        for _ in 0..2 {
+            let repo = DpkgRepo::load(&rootfs, &files, 1234, &config)
+                .unwrap()
+                .unwrap();
+            assert_eq!(claim_names(&repo, "/usr/bin/ldd"), ["glibc"]);
+            // paths are still canonicalized against the rootfs
+            assert_eq!(claim_names(&repo, "/usr/bin/bash"), ["bash"]);
+        }
+        assert_eq!(cache_tmp.path().read_dir().unwrap().count(), 1);
+
+        // a new changelog is a new entry, even with the same database
+        rootfs.create_dir_all("usr/share/doc/bash").unwrap();
+        rootfs
+            .write("usr/share/doc/bash/changelog.Debian.gz", b"")
+            .unwrap();
+        DpkgRepo::load(&rootfs, &files, 1234, &config).unwrap();
+        assert_eq!(cache_tmp.path().read_dir().unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_dpkg_stability() {
+        use std::io::Write;
+
+        const NOW: u64 = 1_717_243_200; // Sat, 01 Jun 2024 12:00:00 +0000
+        let (_tmp, rootfs) = setup_rootfs();
+        // only one of the glibc packages ships the changelog
+        rootfs.create_dir_all("usr/share/doc/libc-bin").unwrap();
+        let mut changelog = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        changelog
+            .write_all(
+                b"glibc (2.36-9+deb12u4) bookworm; urgency=medium\n\n  * Fix.\n\n \
+                  -- Jane Doe <jane@example.org>  Wed, 01 May 2024 12:00:00 +0000\n\n\
+                  glibc (2.36-9) unstable; urgency=medium\n\n  * Fix.\n\n \
+                  -- Jane Doe <jane@example.org>  Sat, 01 Apr 2023 12:00:00 +0000\n",
+            )
+            .unwrap();
+        rootfs
+            .write(
+                "usr/share/doc/libc-bin/changelog.Debian.gz",
+                changelog.finish().unwrap(),
+            )
+            .unwrap();
+
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let repo = DpkgRepo::load(&rootfs, &files, NOW, &RepoConfig::new())
+            .unwrap()
+            .unwrap();
+        let stability = |path: &str| {
+            let claims = repo.claims_for_path(Utf8Path::new(path), FileType::File);
+            repo.component_info(claims[0]).stability
+        };
+        // one change within the lookback window, 31 days ago
+        let expected = calculate_stability(&[NOW - 31 * 86400], 0, NOW).unwrap();
+        assert!(expected > 0.0);
+        assert_eq!(stability("/usr/lib/x86_64-linux-gnu/libc.so.6"), expected);
+        assert_eq!(stability("/usr/bin/ldd"), expected);
+        // no changelog, so the fallback is used
+        assert_eq!(stability("/usr/bin/bash"), 0.0);
+    }
+
+    #[test]
+    fn test_dpkg_corrupt_changelog() {
+        let (_tmp, rootfs) = setup_rootfs();
+        rootfs.create_dir_all("usr/share/doc/bash").unwrap();
+        rootfs
+            .write("usr/share/doc/bash/changelog.Debian.gz", b"not gzip")
+            .unwrap();
+
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let repo = DpkgRepo::load(&rootfs, &files, 1234, &RepoConfig::new())
+            .unwrap()
+            .unwrap();
+        // the changelog is skipped, so the fallback is used
+        let claims = repo.claims_for_path(Utf8Path::new("/usr/bin/bash"), FileType::File);
+        assert_eq!(repo.component_info(claims[0]).stability, 0.0);
+    }
+
+    #[test]
+    fn test_dpkg_verify() {
+        let (_tmp, rootfs) = setup_rootfs();
+        rootfs.write("usr/bin/ldd", "ldd").unwrap();
+        rootfs.write("usr/bin/bash", "modified").unwrap();
+        let md5 = |content: &[u8]| {
+            hex::encode(openssl::hash::hash(openssl::hash::MessageDigest::md5(), content).unwrap())
+        };
+        rootfs
+            .write(
+                "var/lib/dpkg/info/libc-bin.md5sums",
+                format!("{}  usr/bin/ldd\n", md5(b"ldd")),
+            )
+            .unwrap();
+        rootfs
+            .write(
+                "var/lib/dpkg/info/bash.md5sums",
+                format!("{}  bin/bash\n", md5(b"bash")),
+            )
+            .unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+
+        // not verified by default
+        let repo = DpkgRepo::load(&rootfs, &files, 1234, &RepoConfig::new())
+            .unwrap()
+            .unwrap();
+        assert_eq!(claim_names(&repo, "/usr/bin/bash"), ["bash"]);
+
+        let config = RepoConfig::new().dpkg_verify(true);
+        let repo = DpkgRepo::load(&rootfs, &files, 1234, &config)
+            .unwrap()
+            .unwrap();
+        assert_eq!(claim_names(&repo, "/usr/bin/ldd"), ["glibc"]);
+        // /bin -> usr/bin is canonicalized
+        assert_eq!(claim_names(&repo, "/usr/bin/bash"), ["modified"]);
+        let claims = repo.claims_for_path(Utf8Path::new("/usr/bin/bash"), FileType::File);
+        assert_eq!(repo.component_info(claims[0]).stability, 0.0);
+    }
+
+    #[test]
+    fn test_parse_md5sums() {
+        let md5sums = parse_md5sums(
+            "d41d8cd98f00b204e9800998ecf8427e  usr/bin/ldd\n\
+             0CC175B9C0F1B6A831C399E269772661  usr/share/doc/a b\n",
+        )
+        .unwrap();
+        assert_eq!(
+            md5sums,
+            [
+                (
+                    Utf8PathBuf::from("/usr/bin/ldd"),
+                    "d41d8cd98f00b204e9800998ecf8427e".to_string()
+                ),
+                (
+                    Utf8PathBuf::from("/usr/share/doc/a b"),
+                    "0cc175b9c0f1b6a831c399e269772661".to_string()
+                ),
+            ]
+        );
+        assert!(parse_md5sums("garbage\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_changelog_times() {
+        let changelog: &[u8] = b"pkg (2.0) unstable; urgency=low\n\n  * New.\n\n \
+            -- Jane Doe <jane@example.org>  Sat, 01 Jun 2024 12:00:00 +0200\n\n\
+            pkg (1.1) unstable; urgency=low\n\n  * Bad date.\n\n \
+            -- Jane Doe <jane@example.org>  sometime in 1999\n\n\
+            pkg (1.0) unstable; urgency=low\n\n  * Old.\n\n \
+            -- Ren\xe9 Doe <rene@example.org>  Fri, 01 Jan 1999 00:00:00 +0000\n";
+        assert_eq!(
+            parse_changelog_times(changelog).unwrap(),
+            [1_717_236_000, 915_148_800]
+        );
+    }
+
+    #[test]
+    fn test_dpkg_not_detected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        assert!(
+            DpkgRepo::load(&rootfs, &files, 0, &RepoConfig::new())
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+
+use super::{ComponentId, ComponentInfo, ComponentsRepo, FileMap, FileType};
+
+const REPO_NAME: &str = "external";
+
+/// Version of the protocol spoken with claimers.
+const PROTOCOL_VERSION: u32 = 1;
+
+/// External claimer components repo implementation.
+///
+/// Delegates claiming to a user-provided executable, so that e.g. proprietary
+/// package managers can be supported without changes to chunkah. The claimer
+/// is run once, gets a JSON request on stdin and must write a JSON response on
+/// stdout before exiting successfully. The request looks like:
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "rootfs": "/path/to/rootfs",
+///   "paths": [{"path": "/usr/bin/foo", "type": "file"}, ...]
+/// }
+/// ```
+///
+/// where `type` is one of `file`, `directory`, `symlink`, `block-device`,
+/// `char-device` or `fifo`. The response looks like:
+///
+/// ```json
+/// {
+///   "components": [
+///     {"name": "foo", "paths": ["/usr/bin/foo"], "stability": 0.9, "mtime": 1700000000}
+///   ]
+/// }
+/// ```
+///
+/// where `stability` and `mtime` are optional. A path may be claimed by
+/// multiple components (e.g. shared directories). Paths that aren't in the
+/// request are ignored.
+pub struct ExternalRepo {
+    /// Components, indexed by ComponentId.
+    components: Vec<ClaimedComponent>,
+    /// Mapping from path to list of ComponentId.
+    path_to_components: HashMap<Utf8PathBuf, Vec<ComponentId>>,
+    /// Default mtime clamp for components without one.
+    default_mtime_clamp: u64,
+}
+
+#[derive(Serialize)]
+struct Request<'a> {
+    version: u32,
+    rootfs: &'a Utf8Path,
+    paths: Vec<RequestPath<'a>>,
+}
+
+#[derive(Serialize)]
+struct RequestPath<'a> {
+    path: &'a Utf8Path,
+    #[serde(rename = "type")]
+    file_type: &'static str,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    components: Vec<ClaimedComponent>,
+}
+
+#[derive(Deserialize)]
+struct ClaimedComponent {
+    name: String,
+    paths: Vec<Utf8PathBuf>,
+    /// Probability the component doesn't change between updates.
+    stability: Option<f64>,
+    /// The mtime clamp for the component's files.
+    mtime: Option<u64>,
+}
+
+impl ExternalRepo {
+    /// Run the claimer at `exec` on the files of the rootfs at `rootfs`.
+    ///
+    /// Returns `Ok(None)` if the claimer doesn't claim any files. The
+    /// registry credentials file, if any, is passed to it as
+    /// `$REGISTRY_AUTH_FILE`.
+    pub fn load(
+        exec: &Utf8Path,
+        rootfs: &Utf8Path,
+        files: &FileMap,
+        default_mtime_clamp: u64,
+        registry_auth_file: Option<&Utf8Path>,
+    ) -> Result<Option<Self>> {
+        let request = Request {
+            version: PROTOCOL_VERSION,
+            rootfs,
+            paths: files
+                .iter()
+                .map(|(path, info)| RequestPath {
+                    path,
+                    file_type: match info.file_type {
+                        FileType::Directory => "directory",
+                        FileType::File => "file",
+                        FileType::Symlink => "symlink",
+                        FileType::BlockDevice => "block-device",
+                        FileType::CharDevice => "char-device",
+                        FileType::Fifo => "fifo",
+                    },
+                })
+                .collect(),
+        };
+        let request = serde_json::to_vec(&request).context("serializing request")?;
+
+        let mut cmd = Command::new(exec);
+        if let Some(path) = registry_auth_file {
+            cmd.env("REGISTRY_AUTH_FILE", path);
+        }
+        let mut child = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("spawning {exec}"))?;
+        // SAFETY: we asked for a piped stdin
+        let mut stdin = child.stdin.take().unwrap();
+        // write from another thread so that the claimer can't block us by
+        // filling its stdout before reading all of its stdin
+        let output = std::thread::scope(|s| -> Result<std::process::Output> {
+            let writer = s.spawn(move || stdin.write_all(&request));
+            let output = child.wait_with_output();
+            // SAFETY: the closure doesn't panic
+            let written = writer.join().unwrap();
+            let output = output.with_context(|| format!("waiting for {exec}"))?;
+            anyhow::ensure!(output.status.success(), "{exec} failed: {}", output.status);
+            written.with_context(|| format!("writing request to {exec}"))?;
+            Ok(output)
+        })?;
+
+        Self::parse(&output.stdout, files, default_mtime_clamp)
+            .with_context(|| format!("parsing response from {exec}"))
+    }
+
+    fn parse(content: &[u8], files: &FileMap, default_mtime_clamp: u64) -> Result<Option<Self>> {
+        let response: Response = serde_json::from_slice(content).context("parsing JSON")?;
+
+        let mut path_to_components: HashMap<Utf8PathBuf, Vec<ComponentId>> = HashMap::new();
+        for (idx, component) in response.components.iter().enumerate() {
+            anyhow::ensure!(!component.name.is_empty(), "component name cannot be empty");
+            if let Some(stability) = component.stability {
+                anyhow::ensure!(
+                    (0.0..=1.0).contains(&stability),
+                    "component {}: stability must be between 0 and 1",
+                    component.name
+                );
+            }
+            for path in &component.paths {
+                if !files.contains_key(path) {
+                    continue;
+                }
+                let ids = path_to_components.entry(path.clone()).or_default();
+                if !ids.contains(&ComponentId(idx)) {
+                    ids.push(ComponentId(idx));
+                }
+            }
+        }
+
+        if path_to_components.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            components: response.components,
+            path_to_components,
+            default_mtime_clamp,
+        }))
+    }
+}
+
+impl ComponentsRepo for ExternalRepo {
+    fn name(&self) -> &'static str {
+        REPO_NAME
+    }
+
+    fn default_priority(&self) -> usize {
+        // Same as package managers, which is what claimers usually stand in
+        // for. Since they're loaded after them, built-in repos win on ties.
+        10
+    }
+
+    fn claims_for_path(&self, path: &Utf8Path, _file_type: FileType) -> Vec<ComponentId> {
+        self.path_to_components
+            .get(path)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn component_info(&self, id: ComponentId) -> ComponentInfo<'_> {
+        // SAFETY: the ids we're given come from the index in the Vec itself,
+        // so it must be valid.
+        let component = &self.components[id.0];
+        ComponentInfo {
+            name: &component.name,
+            mtime_clamp: component.mtime.unwrap_or(self.default_mtime_clamp),
+            // 0.0 means it gets the fallback stability
+            stability: component.stability.unwrap_or(0.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use cap_std_ext::cap_std::ambient_authority;
+    use cap_std_ext::cap_std::fs::Dir;
+
+    use super::*;
+    use crate::components::claim_names;
+
+    /// Write an executable shell script and return its path.
+    fn write_claimer(dir: &Utf8Path, script: &str) -> Utf8PathBuf {
+        let path = dir.join("claimer");
+        std::fs::write(&path, format!("#!/bin/sh\n{script}")).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_external_claims() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("opt/vendor/bin").unwrap();
+        rootfs.write("opt/vendor/bin/tool", "tool").unwrap();
+        rootfs.write("opt/vendor/bin/other", "other").unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+
+        let claimer_tmp = tempfile::tempdir().unwrap();
+        let claimer_dir = Utf8Path::from_path(claimer_tmp.path()).unwrap();
+        // save the request so we can check it, and claim a couple of paths
+        let claimer = write_claimer(
+            claimer_dir,
+            &format!(
+                r#"cat > {claimer_dir}/request.json
+echo "$REGISTRY_AUTH_FILE" > {claimer_dir}/auth-file
+cat <<EOF
+{{"components": [
+  {{"name": "tool", "paths": ["/opt/vendor", "/opt/vendor/bin/tool", "/not/in/rootfs"], "mtime": 1000}},
+  {{"name": "vendor", "paths": ["/opt/vendor"], "stability": 0.5}}
+]}}
+EOF
+"#
+            ),
+        );
+        let rootfs_path = Utf8Path::from_path(tmp.path()).unwrap();
+        let auth_file = Utf8Path::new("/run/user/1000/containers/auth.json");
+        let repo = ExternalRepo::load(&claimer, rootfs_path, &files, 42, Some(auth_file))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(claimer_dir.join("auth-file")).unwrap(),
+            format!("{auth_file}\n")
+        );
+
+        let request: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(claimer_dir.join("request.json")).unwrap())
+                .unwrap();
+        assert_eq!(request["version"], 1);
+        assert_eq!(request["rootfs"], rootfs_path.as_str());
+        assert!(
+            request["paths"]
+                .as_array()
+                .unwrap()
+                .contains(&serde_json::json!({"path": "/opt/vendor/bin", "type": "directory"}))
+        );
+
+        assert_eq!(claim_names(&repo, "/opt/vendor/bin/tool"), ["tool"]);
+        assert_eq!(claim_names(&repo, "/opt/vendor"), ["tool", "vendor"]);
+        assert!(claim_names(&repo, "/opt/vendor/bin/other").is_empty());
+        assert!(claim_names(&repo, "/not/in/rootfs").is_empty());
+
+        let claims = repo.claims_for_path(Utf8Path::new("/opt/vendor"), FileType::Directory);
+        assert_eq!(repo.component_info(claims[0]).mtime_clamp, 1000);
+        assert_eq!(repo.component_info(claims[1]).mtime_clamp, 42);
+        assert_eq!(repo.component_info(claims[1]).stability, 0.5);
+    }
+
+    #[test]
+    fn test_external_failure() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(tmp.path()).unwrap();
+        let files = FileMap::new();
+
+        let claimer = write_claimer(dir, "cat > /dev/null\nexit 1\n");
+        let Err(err) = ExternalRepo::load(&claimer, dir, &files, 0, None) else {
+            panic!("expected the claimer to fail");
+        };
+        assert!(format!("{err:#}").contains("failed"), "{err:#}");
+
+        let claimer = write_claimer(dir, "cat > /dev/null\necho not json\n");
+        assert!(ExternalRepo::load(&claimer, dir, &files, 0, None).is_err());
+    }
+}
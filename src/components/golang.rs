@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+use std::os::unix::fs::FileExt;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::Dir;
+use indexmap::IndexMap;
+
+use crate::utils::calculate_stability;
+
+use super::{ComponentId, ComponentInfo, ComponentsRepo, FileMap, FileType};
+
+const REPO_NAME: &str = "go";
+
+/// Name of the ELF section holding the Go build info.
+const BUILDINFO_SECTION: &[u8] = b".go.buildinfo";
+
+/// Magic at the start of the Go build info.
+const BUILDINFO_MAGIC: &[u8] = b"\xff Go buildinf:";
+
+/// Upper bound on the build info section size we're willing to read. It's
+/// normally a few KB; the bulk of it is the list of dependencies.
+const MAX_BUILDINFO_SIZE: u64 = 1024 * 1024;
+
+/// Go binaries components repo implementation.
+///
+/// Inspects executable ELF files for the build info embedded by the Go
+/// toolchain and groups binaries by their main module. The VCS commit time, if
+/// stamped, is used as the mtime clamp. This gives useful granularity for
+/// `FROM scratch` Go images which have no package database at all.
+pub struct GoRepo {
+    /// Unique component (module) names mapped to (mtime clamp, stability),
+    /// indexed by ComponentId.
+    components: IndexMap<String, (u64, f64)>,
+    /// Mapping from path to ComponentId.
+    path_to_component: HashMap<Utf8PathBuf, ComponentId>,
+}
+
+impl GoRepo {
+    /// Load Go build info from executables in `files`.
+    ///
+    /// Returns `Ok(None)` if no Go binaries are found.
+    pub fn load(rootfs: &Dir, files: &FileMap, now: u64) -> Result<Option<Self>> {
+        let mut components: IndexMap<String, (u64, f64)> = IndexMap::new();
+        let mut path_to_component: HashMap<Utf8PathBuf, ComponentId> = HashMap::new();
+
+        for (path, file_info) in files {
+            if file_info.file_type != FileType::File || file_info.mode & 0o111 == 0 {
+                continue;
+            }
+            let file = rootfs
+                .open(file_info.fs_path(path))
+                .with_context(|| format!("opening {path}"))?
+                .into_std();
+            let Some(section) = read_elf_section(&file, BUILDINFO_SECTION)
+                .with_context(|| format!("reading {path}"))?
+            else {
+                continue;
+            };
+            let Some(info) = parse_buildinfo(&section) else {
+                continue;
+            };
+
+            let (clamp, stability) = match info.vcs_time {
+                Some(t) => (t, calculate_stability(&[], t, now)?),
+                None => (now, 0.0),
+            };
+            let entry = components.entry(info.module);
+            let component_id = ComponentId(entry.index());
+            match entry {
+                indexmap::map::Entry::Occupied(mut e) => {
+                    // Binaries from the same module may have been built from
+                    // different commits; same logic as for subpackages.
+                    let (existing_clamp, existing_stability) = e.get_mut();
+                    *existing_clamp = (*existing_clamp).max(clamp);
+                    *existing_stability = existing_stability.min(stability);
+                }
+                indexmap::map::Entry::Vacant(e) => {
+                    e.insert((clamp, stability));
+                }
+            }
+            path_to_component.insert(path.clone(), component_id);
+        }
+
+        if components.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            components,
+            path_to_component,
+        }))
+    }
+}
+
+impl ComponentsRepo for GoRepo {
+    fn name(&self) -> &'static str {
+        REPO_NAME
+    }
+
+    fn default_priority(&self) -> usize {
+        // After package managers; Go binaries shipped in packages should stay
+        // with the package that owns them.
+        25
+    }
+
+    fn claims_for_path(&self, path: &Utf8Path, _file_type: FileType) -> Vec<ComponentId> {
+        self.path_to_component
+            .get(path)
+            .map(|id| vec![*id])
+            .unwrap_or_default()
+    }
+
+    fn component_info(&self, id: ComponentId) -> ComponentInfo<'_> {
+        let (name, (mtime_clamp, stability)) = self
+            .components
+            .get_index(id.0)
+            // SAFETY: the ids we're given come from the IndexMap itself when we
+            // inserted the element, so it must be valid.
+            .expect("invalid ComponentId");
+        ComponentInfo {
+            name,
+            mtime_clamp: *mtime_clamp,
+            stability: *stability,
+        }
+    }
+}
+
+/// The bits of Go build info we care about.
+#[derive(Debug, PartialEq)]
+struct BuildInfo {
+    /// Path of the main module.
+    module: String,
+    /// Commit time from `vcs.time`, if stamped.
+    vcs_time: Option<u64>,
+}
+
+/// Read the content of the named section from an ELF file.
+///
+/// Returns `Ok(None)` if the file isn't ELF or doesn't have the section.
+fn read_elf_section(file: &std::fs::File, name: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut ident = [0u8; 64];
+    let n = read_at_most(file, &mut ident, 0)?;
+    if n < 52 || &ident[..4] != b"\x7fELF" {
+        return Ok(None);
+    }
+    let is_64 = match ident[4] {
+        1 => false,
+        2 => true,
+        _ => return Ok(None),
+    };
+    let le = match ident[5] {
+        1 => true,
+        2 => false,
+        _ => return Ok(None),
+    };
+    let elf = Elf { is_64, le };
+
+    let (shoff, shentsize, shnum, shstrndx) = if is_64 {
+        (
+            elf.u64(&ident, 0x28),
+            elf.u16(&ident, 0x3a),
+            elf.u16(&ident, 0x3c),
+            elf.u16(&ident, 0x3e),
+        )
+    } else {
+        (
+            elf.u32(&ident, 0x20) as u64,
+            elf.u16(&ident, 0x2e),
+            elf.u16(&ident, 0x30),
+            elf.u16(&ident, 0x32),
+        )
+    };
+    if shoff == 0 || shnum == 0 || shstrndx >= shnum || shentsize < elf.shdr_size() {
+        return Ok(None);
+    }
+
+    let mut shdrs = vec![0u8; shnum as usize * shentsize as usize];
+    if read_at_most(file, &mut shdrs, shoff)? != shdrs.len() {
+        return Ok(None);
+    }
+    let shdr = |i: u16| &shdrs[i as usize * shentsize as usize..][..shentsize as usize];
+
+    let Some(shstrtab) = elf.read_section(file, shdr(shstrndx))? else {
+        return Ok(None);
+    };
+    for i in 0..shnum {
+        let hdr = shdr(i);
+        let name_off = elf.u32(hdr, 0) as usize;
+        let Some(section_name) = shstrtab.get(name_off..) else {
+            continue;
+        };
+        let end = section_name
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(section_name.len());
+        if &section_name[..end] == name {
+            return elf.read_section(file, hdr);
+        }
+    }
+    Ok(None)
+}
+
+/// ELF class and endianness.
+struct Elf {
+    is_64: bool,
+    le: bool,
+}
+
+impl Elf {
+    fn u16(&self, b: &[u8], off: usize) -> u16 {
+        let bytes = [b[off], b[off + 1]];
+        if self.le {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        }
+    }
+
+    fn u32(&self, b: &[u8], off: usize) -> u32 {
+        let bytes = b[off..off + 4].try_into().unwrap();
+        if self.le {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        }
+    }
+
+    fn u64(&self, b: &[u8], off: usize) -> u64 {
+        let bytes = b[off..off + 8].try_into().unwrap();
+        if self.le {
+            u64::from_le_bytes(bytes)
+        } else {
+            u64::from_be_bytes(bytes)
+        }
+    }
+
+    /// Size of a section header.
+    fn shdr_size(&self) -> u16 {
+        if self.is_64 { 64 } else { 40 }
+    }
+
+    /// Read the content of the section described by the given header.
+    fn read_section(&self, file: &std::fs::File, shdr: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (offset, size) = if self.is_64 {
+            (self.u64(shdr, 0x18), self.u64(shdr, 0x20))
+        } else {
+            (self.u32(shdr, 0x10) as u64, self.u32(shdr, 0x14) as u64)
+        };
+        if size > MAX_BUILDINFO_SIZE {
+            return Ok(None);
+        }
+        let mut buf = vec![0u8; size as usize];
+        if read_at_most(file, &mut buf, offset)? != buf.len() {
+            return Ok(None);
+        }
+        Ok(Some(buf))
+    }
+}
+
+/// Like `read_exact_at`, but returns the number of bytes read on EOF.
+fn read_at_most(file: &std::fs::File, buf: &mut [u8], offset: u64) -> Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read_at(&mut buf[read..], offset + read as u64) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e).context("reading file"),
+        }
+    }
+    Ok(read)
+}
+
+/// Parse the content of the `.go.buildinfo` section.
+///
+/// Only the format used since Go 1.18 (strings inline, flagged by bit 2) is
+/// supported. Older binaries point into the data section instead, which
+/// isn't worth supporting here.
+///
+/// cf. https://github.com/golang/go/blob/master/src/debug/buildinfo/buildinfo.go
+fn parse_buildinfo(data: &[u8]) -> Option<BuildInfo> {
+    if data.len() < 32 || !data.starts_with(BUILDINFO_MAGIC) {
+        return None;
+    }
+    let flags = data[15];
+    if flags & 0x2 == 0 {
+        return None;
+    }
+    let rest = &data[32..];
+    let (_version, rest) = read_varint_string(rest)?;
+    let (modinfo, _) = read_varint_string(rest)?;
+
+    // modinfo is wrapped in 16 byte sentinels
+    if modinfo.len() < 33 || modinfo[modinfo.len() - 17] != b'\n' {
+        return None;
+    }
+    let modinfo = std::str::from_utf8(&modinfo[16..modinfo.len() - 16]).ok()?;
+
+    let mut module = None;
+    let mut vcs_time = None;
+    for line in modinfo.lines() {
+        let mut fields = line.split('\t');
+        match fields.next() {
+            Some("mod") => module = fields.next().map(|s| s.to_string()),
+            Some("build") => {
+                if let Some(time) = fields.next().and_then(|f| f.strip_prefix("vcs.time=")) {
+                    vcs_time = chrono::DateTime::parse_from_rfc3339(time)
+                        .ok()
+                        .and_then(|t| u64::try_from(t.timestamp()).ok());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Some(BuildInfo {
+        module: module?,
+        vcs_time,
+    })
+}
+
+/// Read a uvarint length-prefixed string, returning it and the remaining data.
+fn read_varint_string(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let mut len: u64 = 0;
+    for (i, &b) in data.iter().enumerate().take(10) {
+        len |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            let rest = &data[i + 1..];
+            let len = usize::try_from(len).ok()?;
+            return (len <= rest.len()).then(|| rest.split_at(len));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std::ambient_authority;
+    use cap_std_ext::cap_std::fs::PermissionsExt;
+
+    use super::*;
+
+    /// Build the content of a `.go.buildinfo` section.
+    fn buildinfo(modinfo: &str) -> Vec<u8> {
+        let mut data = BUILDINFO_MAGIC.to_vec();
+        data.push(8); // ptr size
+        data.push(0x2); // flags: inline strings
+        data.resize(32, 0);
+        for s in [
+            "go1.22.0".to_string(),
+            format!("{}{modinfo}{}", "0".repeat(16), "1".repeat(16)),
+        ] {
+            let mut len = s.len();
+            while len >= 0x80 {
+                data.push((len as u8) | 0x80);
+                len >>= 7;
+            }
+            data.push(len as u8);
+            data.extend(s.as_bytes());
+        }
+        data
+    }
+
+    /// Build a minimal little-endian ELF64 file with the given section.
+    fn elf_with_section(name: &str, content: &[u8]) -> Vec<u8> {
+        let shstrtab = format!("\0.shstrtab\0{name}\0");
+        let mut elf = vec![0u8; 64];
+        elf[..4].copy_from_slice(b"\x7fELF");
+        elf[4] = 2; // 64-bit
+        elf[5] = 1; // little-endian
+        let shstrtab_off = elf.len() as u64;
+        elf.extend(shstrtab.as_bytes());
+        let content_off = elf.len() as u64;
+        elf.extend(content);
+        let shoff = elf.len() as u64;
+
+        let mut shdr = |name_off: u32, offset: u64, size: u64| {
+            let mut hdr = vec![0u8; 64];
+            hdr[..4].copy_from_slice(&name_off.to_le_bytes());
+            hdr[0x18..0x20].copy_from_slice(&offset.to_le_bytes());
+            hdr[0x20..0x28].copy_from_slice(&size.to_le_bytes());
+            elf.extend(hdr);
+        };
+        shdr(0, 0, 0); // null section
+        shdr(1, shstrtab_off, shstrtab.len() as u64);
+        shdr(11, content_off, content.len() as u64);
+
+        elf[0x28..0x30].copy_from_slice(&shoff.to_le_bytes());
+        elf[0x3a..0x3c].copy_from_slice(&64u16.to_le_bytes());
+        elf[0x3c..0x3e].copy_from_slice(&3u16.to_le_bytes());
+        elf[0x3e..0x40].copy_from_slice(&1u16.to_le_bytes());
+        elf
+    }
+
+    #[test]
+    fn test_parse_buildinfo() {
+        let data = buildinfo(
+            "path\texample.com/app/cmd/server\n\
+             mod\texample.com/app\tv1.2.3\th1:abc=\n\
+             dep\tgolang.org/x/sys\tv0.1.0\th1:def=\n\
+             build\tvcs.time=2024-05-01T12:00:00Z\n",
+        );
+        assert_eq!(
+            parse_buildinfo(&data),
+            Some(BuildInfo {
+                module: "example.com/app".into(),
+                vcs_time: Some(1714564800),
+            })
+        );
+
+        // no vcs stamping
+        let data = buildinfo("path\tapp\nmod\tapp\t(devel)\t\n");
+        assert_eq!(parse_buildinfo(&data).unwrap().vcs_time, None);
+
+        // old format
+        let mut data = buildinfo("mod\tapp\t(devel)\t\n");
+        data[15] = 0;
+        assert_eq!(parse_buildinfo(&data), None);
+    }
+
+    #[test]
+    fn test_go_claims() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("usr/bin").unwrap();
+
+        let app =
+            buildinfo("mod\texample.com/app\tv1.0.0\t\nbuild\tvcs.time=2024-05-01T12:00:00Z\n");
+        let app2 =
+            buildinfo("mod\texample.com/app\tv1.0.0\t\nbuild\tvcs.time=2024-05-02T12:00:00Z\n");
+        let other = buildinfo("mod\texample.com/other\tv1.0.0\t\n");
+        for (path, content) in [
+            ("usr/bin/server", elf_with_section(".go.buildinfo", &app)),
+            ("usr/bin/client", elf_with_section(".go.buildinfo", &app2)),
+            ("usr/bin/other", elf_with_section(".go.buildinfo", &other)),
+            ("usr/bin/c-binary", elf_with_section(".data", b"nothing")),
+            ("usr/bin/script", b"#!/bin/sh\n".to_vec()),
+        ] {
+            rootfs.write(path, content).unwrap();
+            rootfs
+                .set_permissions(
+                    path,
+                    cap_std_ext::cap_std::fs::Permissions::from_mode(0o755),
+                )
+                .unwrap();
+        }
+        // not executable, so not inspected
+        rootfs
+            .write("usr/bin/data", elf_with_section(".go.buildinfo", &other))
+            .unwrap();
+
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let now = 1714564800 + 86400 * 30;
+        let repo = GoRepo::load(&rootfs, &files, now).unwrap().unwrap();
+
+        let info = |path: &str| {
+            let claims = repo.claims_for_path(Utf8Path::new(path), FileType::File);
+            claims.first().map(|id| {
+                let info = repo.component_info(*id);
+                (info.name.to_string(), info.mtime_clamp)
+            })
+        };
+        // binaries of the same module are grouped, clamped to the latest time
+        assert_eq!(
+            info("/usr/bin/server"),
+            Some(("example.com/app".into(), 1714651200))
+        );
+        assert_eq!(
+            info("/usr/bin/client"),
+            Some(("example.com/app".into(), 1714651200))
+        );
+        // without vcs.time, we fall back to the default clamp
+        assert_eq!(
+            info("/usr/bin/other"),
+            Some(("example.com/other".into(), now))
+        );
+        assert_eq!(info("/usr/bin/c-binary"), None);
+        assert_eq!(info("/usr/bin/script"), None);
+        assert_eq!(info("/usr/bin/data"), None);
+    }
+}
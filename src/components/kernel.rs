@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use indexmap::IndexMap;
+
+use super::{ComponentId, ComponentInfo, ComponentsRepo, FileMap, FileType};
+
+const REPO_NAME: &str = "kernel";
+
+/// Directory holding a subdirectory per installed kernel version, with its
+/// modules and, in bootable container images, the kernel and initramfs.
+const MODULES_DIR: &str = "/usr/lib/modules";
+
+/// Directory holding the kernel and initramfs in traditional images.
+const BOOT_DIR: &str = "/boot";
+
+/// Kernel components repo implementation.
+///
+/// Claims `/usr/lib/modules/<version>` and the files of the same version in
+/// `/boot` (e.g. `vmlinuz-<version>` and `initramfs-<version>.img`) into one
+/// component per kernel version, named after the version. The kernel is
+/// updated independently of userspace and is large, so mixing it with other
+/// packages forces large redownloads on every kernel update; bootc and
+/// rpm-ostree also expect it in a layer of its own. With `--kernel-layer`, its
+/// components are pinned so that they're never packed with others.
+///
+/// Like the `content` repo, it's only used when enabled with
+/// [`super::RepoConfig::kernel_layer`].
+pub struct KernelRepo {
+    /// Kernel versions with their mtime clamp, indexed by ComponentId.
+    components: IndexMap<String, u64>,
+    /// Mapping from path to ComponentId.
+    path_to_component: HashMap<Utf8PathBuf, ComponentId>,
+}
+
+impl KernelRepo {
+    /// Load the kernel repo by looking for kernel versions in
+    /// `/usr/lib/modules`.
+    ///
+    /// Returns None if there are none.
+    pub fn load(files: &FileMap, default_mtime_clamp: u64) -> Option<Self> {
+        let modules_dir = Utf8Path::new(MODULES_DIR);
+        let mut components: IndexMap<String, u64> = files
+            .iter()
+            .filter(|(path, info)| {
+                info.file_type == FileType::Directory && path.parent() == Some(modules_dir)
+            })
+            .filter_map(|(path, _)| Some((path.file_name()?.to_string(), 0)))
+            .collect();
+        if components.is_empty() {
+            return None;
+        }
+
+        let mut path_to_component = HashMap::new();
+        for (path, file_info) in files {
+            let Some(idx) = kernel_version(path, &components) else {
+                continue;
+            };
+            // SAFETY: kernel_version() only returns indices into the map
+            let (_, mtime_clamp) = components.get_index_mut(idx).unwrap();
+            // the files keep the mtime they came with unless newer than the
+            // default clamp, so that the layer only changes with the files
+            *mtime_clamp = (*mtime_clamp).max(file_info.mtime.min(default_mtime_clamp));
+            path_to_component.insert(path.clone(), ComponentId(idx));
+        }
+
+        Some(Self {
+            components,
+            path_to_component,
+        })
+    }
+}
+
+/// Returns the index of the kernel version among `versions` that `path`
+/// belongs to, if any.
+fn kernel_version(path: &Utf8Path, versions: &IndexMap<String, u64>) -> Option<usize> {
+    if let Ok(rel_path) = path.strip_prefix(MODULES_DIR) {
+        let version = rel_path.components().next()?.as_str();
+        return versions.get_index_of(version);
+    }
+    if path.parent() != Some(Utf8Path::new(BOOT_DIR)) {
+        return None;
+    }
+    let name = path.file_name()?;
+    let name = name.strip_suffix(".img").unwrap_or(name);
+    versions.keys().position(|version| {
+        name.strip_suffix(version.as_str())
+            .is_some_and(|prefix| prefix.ends_with('-'))
+    })
+}
+
+impl ComponentsRepo for KernelRepo {
+    fn name(&self) -> &'static str {
+        REPO_NAME
+    }
+
+    fn default_priority(&self) -> usize {
+        // Before the package managers, which would otherwise claim the files
+        // for the kernel packages, but after explicit assignments.
+        5
+    }
+
+    fn claims_for_path(&self, path: &Utf8Path, _file_type: FileType) -> Vec<ComponentId> {
+        self.path_to_component
+            .get(path)
+            .map(|id| vec![*id])
+            .unwrap_or_default()
+    }
+
+    fn component_info(&self, id: ComponentId) -> ComponentInfo<'_> {
+        // SAFETY: the ids we hand out are indices into the map
+        let (name, mtime_clamp) = self
+            .components
+            .get_index(id.0)
+            .expect("invalid ComponentId");
+        ComponentInfo {
+            name,
+            mtime_clamp: *mtime_clamp,
+            // 0.0 means it gets the fallback stability; it has a layer of its
+            // own anyway
+            stability: 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{FileInfo, claim_names};
+
+    fn file_map(paths: &[(&str, FileType, u64)]) -> FileMap {
+        paths
+            .iter()
+            .map(|(p, file_type, mtime)| {
+                let info = FileInfo::test_file(*file_type, 0, *mtime);
+                (Utf8PathBuf::from(*p), info)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_kernel_repo() {
+        use FileType::{Directory, File};
+        let files = file_map(&[
+            ("/boot", Directory, 100),
+            ("/boot/config-6.12.0-1.fc43.x86_64", File, 100),
+            ("/boot/initramfs-6.12.0-1.fc43.x86_64.img", File, 500),
+            ("/boot/vmlinuz-6.12.0-1.fc43.x86_64", File, 100),
+            ("/boot/vmlinuz-rescue", File, 100),
+            ("/usr/lib/modules", Directory, 100),
+            ("/usr/lib/modules/6.12.0-1.fc43.x86_64", Directory, 100),
+            ("/usr/lib/modules/6.12.0-1.fc43.x86_64/vmlinuz", File, 100),
+            (
+                "/usr/lib/modules/6.12.0-1.fc43.x86_64/initramfs.img",
+                File,
+                5000,
+            ),
+            (
+                "/usr/lib/modules/6.12.0-1.fc43.x86_64/kernel/fs/ext4.ko",
+                File,
+                100,
+            ),
+            ("/usr/lib/modules/6.11.0-1.fc43.x86_64", Directory, 100),
+            ("/usr/lib/modules/6.11.0-1.fc43.x86_64/vmlinuz", File, 100),
+            ("/usr/bin/bash", File, 100),
+        ]);
+        let repo = KernelRepo::load(&files, 1000).unwrap();
+
+        let new = ["6.12.0-1.fc43.x86_64"];
+        assert_eq!(
+            claim_names(&repo, "/usr/lib/modules/6.12.0-1.fc43.x86_64"),
+            new
+        );
+        assert_eq!(
+            claim_names(
+                &repo,
+                "/usr/lib/modules/6.12.0-1.fc43.x86_64/kernel/fs/ext4.ko"
+            ),
+            new
+        );
+        assert_eq!(
+            claim_names(&repo, "/boot/initramfs-6.12.0-1.fc43.x86_64.img"),
+            new
+        );
+        assert_eq!(
+            claim_names(&repo, "/boot/vmlinuz-6.12.0-1.fc43.x86_64"),
+            new
+        );
+        assert_eq!(
+            claim_names(&repo, "/usr/lib/modules/6.11.0-1.fc43.x86_64/vmlinuz"),
+            ["6.11.0-1.fc43.x86_64"]
+        );
+        // shared directories and other files aren't claimed
+        assert!(claim_names(&repo, "/usr/lib/modules").is_empty());
+        assert!(claim_names(&repo, "/boot").is_empty());
+        assert!(claim_names(&repo, "/boot/vmlinuz-rescue").is_empty());
+        assert!(claim_names(&repo, "/usr/bin/bash").is_empty());
+
+        let info = |path: &str| {
+            let claims = repo.claims_for_path(Utf8Path::new(path), FileType::File);
+            repo.component_info(claims[0])
+        };
+        // clamped to the default
+        assert_eq!(info("/boot/vmlinuz-6.12.0-1.fc43.x86_64").mtime_clamp, 1000);
+        assert_eq!(
+            info("/usr/lib/modules/6.11.0-1.fc43.x86_64").mtime_clamp,
+            100
+        );
+
+        let files = file_map(&[("/usr/lib/modules", Directory, 100)]);
+        assert!(KernelRepo::load(&files, 1000).is_none());
+    }
+}
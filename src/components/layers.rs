@@ -0,0 +1,310 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::ambient_authority;
+use cap_std_ext::cap_std::fs::Dir;
+use indexmap::IndexMap;
+use ocidir::oci_spec::image as oci_image;
+
+use crate::utils::calculate_stability;
+
+use super::{ComponentId, ComponentInfo, ComponentsRepo, FileType};
+
+const REPO_NAME: &str = "layers";
+
+/// Prefix of whiteout entries, which delete a path from lower layers.
+pub(crate) const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// Opaque whiteout entry, which hides all lower layer contents of its parent.
+pub(crate) const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Original layers components repo implementation.
+///
+/// When resplitting an existing image, uses the layers of that image as
+/// components: each path is claimed by the topmost layer that provides it.
+/// Directories are claimed by all the layers they're in. This preserves the
+/// existing chunking for files that no other repo claims, rather than dumping
+/// them all in the unclaimed component. The creation time of the layer from
+/// the image history is used as the mtime clamp.
+pub struct LayersRepo {
+    /// Component (layer index) names mapped to (created time, stability),
+    /// indexed by ComponentId.
+    components: IndexMap<String, (u64, f64)>,
+
+    /// Mapping from path to list of ComponentId.
+    path_to_components: HashMap<Utf8PathBuf, Vec<ComponentId>>,
+}
+
+impl LayersRepo {
+    /// Load the layers of the image in the OCI image layout at `path`.
+    ///
+    /// Returns `Ok(None)` if the image has no layers.
+    pub fn load(path: &Utf8Path, now: u64) -> Result<Option<Self>> {
+        let dir = Dir::open_ambient_dir(path, ambient_authority())
+            .with_context(|| format!("opening {path}"))?;
+        let oci_dir = ocidir::OciDir::open(dir).context("opening OCI image layout")?;
+
+        let (_, manifest) = crate::utils::read_image_manifest(&oci_dir)?;
+        let config: oci_image::ImageConfiguration = oci_dir
+            .read_json_blob(manifest.config())
+            .context("reading config")?;
+
+        // history entries for empty layers (e.g. ENV) don't have a layer
+        let created: Vec<Option<u64>> = config
+            .history()
+            .iter()
+            .flatten()
+            .filter(|h| !h.empty_layer().unwrap_or(false))
+            .map(|h| h.created().as_deref().and_then(parse_created))
+            .collect();
+
+        let mut components: IndexMap<String, (u64, f64)> = IndexMap::new();
+        let mut owners: BTreeMap<Utf8PathBuf, Vec<ComponentId>> = BTreeMap::new();
+        for (i, layer) in manifest.layers().iter().enumerate() {
+            anyhow::ensure!(
+                crate::tar::layer_is_gzip(layer.media_type()).is_some(),
+                "layer {i}: unsupported media type {}",
+                layer.media_type()
+            );
+            let (clamp, stability) = match created.get(i).copied().flatten() {
+                Some(t) => (t, calculate_stability(&[], t, now)?),
+                None => (now, 0.0),
+            };
+            let (idx, _) = components.insert_full(i.to_string(), (clamp, stability));
+
+            let reader = crate::tar::read_layer(&oci_dir, layer)
+                .with_context(|| format!("opening layer {i}"))?;
+            let entries = read_entries(reader).with_context(|| format!("reading layer {i}"))?;
+            apply_layer(&mut owners, ComponentId(idx), entries);
+        }
+
+        if components.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            components,
+            path_to_components: owners.into_iter().collect(),
+        }))
+    }
+}
+
+impl ComponentsRepo for LayersRepo {
+    fn name(&self) -> &'static str {
+        REPO_NAME
+    }
+
+    fn default_priority(&self) -> usize {
+        // After all the repos that know about actual software, but before
+        // bigfiles; the original layers are a better fallback.
+        50
+    }
+
+    fn is_fallback(&self) -> bool {
+        // the layers say where a path was, not what owns it
+        true
+    }
+
+    fn claims_for_path(&self, path: &Utf8Path, _file_type: FileType) -> Vec<ComponentId> {
+        self.path_to_components
+            .get(path)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn component_info(&self, id: ComponentId) -> ComponentInfo<'_> {
+        let (name, (clamp, stability)) = self
+            .components
+            .get_index(id.0)
+            // SAFETY: the ids we're given come from the IndexMap itself when we
+            // inserted the element, so it must be valid.
+            .expect("invalid ComponentId");
+        ComponentInfo {
+            name,
+            mtime_clamp: *clamp,
+            stability: *stability,
+        }
+    }
+}
+
+/// An entry of a layer tarball.
+enum LayerEntry {
+    Dir(Utf8PathBuf),
+    NonDir(Utf8PathBuf),
+    /// Delete the path from lower layers.
+    Whiteout(Utf8PathBuf),
+    /// Delete the contents of the directory from lower layers.
+    OpaqueWhiteout(Utf8PathBuf),
+}
+
+/// Read the entries of an uncompressed layer tarball.
+fn read_entries<R: std::io::Read>(reader: R) -> Result<Vec<LayerEntry>> {
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = Vec::new();
+    for entry in archive.entries().context("reading entries")? {
+        let entry = entry.context("reading entry")?;
+        let path = std::str::from_utf8(&entry.path_bytes())
+            .context("path is not valid UTF-8")?
+            .trim_matches('/')
+            .to_string();
+        let path = Utf8Path::new("/").join(path);
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            // the root directory itself
+            continue;
+        };
+        entries.push(if name == OPAQUE_WHITEOUT {
+            LayerEntry::OpaqueWhiteout(parent.to_owned())
+        } else if let Some(name) = name.strip_prefix(WHITEOUT_PREFIX) {
+            LayerEntry::Whiteout(parent.join(name))
+        } else if entry.header().entry_type() == tar::EntryType::Directory {
+            LayerEntry::Dir(path)
+        } else {
+            LayerEntry::NonDir(path)
+        });
+    }
+    Ok(entries)
+}
+
+/// Update the path owners with the entries of the next layer up.
+fn apply_layer(
+    owners: &mut BTreeMap<Utf8PathBuf, Vec<ComponentId>>,
+    id: ComponentId,
+    entries: Vec<LayerEntry>,
+) {
+    // whiteouts only apply to lower layers, regardless of where they are in
+    // the tarball, so do them first
+    for entry in &entries {
+        match entry {
+            LayerEntry::Whiteout(path) => owners.retain(|p, _| !p.starts_with(path)),
+            LayerEntry::OpaqueWhiteout(dir) => {
+                owners.retain(|p, _| p == dir || !p.starts_with(dir))
+            }
+            _ => {}
+        }
+    }
+    for entry in entries {
+        match entry {
+            LayerEntry::Dir(path) => {
+                let ids = owners.entry(path).or_default();
+                if !ids.contains(&id) {
+                    ids.push(id);
+                }
+            }
+            LayerEntry::NonDir(path) => {
+                owners.insert(path, vec![id]);
+            }
+            LayerEntry::Whiteout(_) | LayerEntry::OpaqueWhiteout(_) => {}
+        }
+    }
+}
+
+/// Parse an RFC 3339 history timestamp into a Unix timestamp.
+fn parse_created(created: &str) -> Option<u64> {
+    let created = chrono::DateTime::parse_from_rfc3339(created).ok()?;
+    u64::try_from(created.timestamp()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::claim_names;
+
+    /// Create an OCI image layout with one layer per list of (path, content)
+    /// entries. Paths ending in `/` are directories.
+    fn build_image(tmp: &tempfile::TempDir, layers: &[&[(&str, &str)]]) {
+        let dir = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        let oci_dir = ocidir::OciDir::ensure(dir).unwrap();
+        let mut manifest = oci_dir.new_empty_manifest().unwrap().build().unwrap();
+        let mut config = oci_image::ImageConfiguration::default();
+
+        for (i, entries) in layers.iter().enumerate() {
+            let mut tar_builder = crate::tar::create_layer(
+                &oci_dir,
+                crate::ocibuilder::Compression::Gzip(6),
+                crate::ocibuilder::LayerMediaType::Oci,
+            )
+            .unwrap();
+            for (path, content) in entries.iter() {
+                let mut header = tar::Header::new_gnu();
+                if path.ends_with('/') {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_mode(0o755);
+                } else {
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_mode(0o644);
+                }
+                header.set_size(content.len() as u64);
+                tar_builder
+                    .append_data(&mut header, path, content.as_bytes())
+                    .unwrap();
+            }
+            tar_builder.finish().unwrap();
+            let layer = tar_builder.into_inner().unwrap().complete().unwrap();
+            let history = oci_image::HistoryBuilder::default()
+                .created(format!("2024-05-0{}T00:00:00Z", i + 1))
+                .created_by("test".to_string())
+                .build()
+                .unwrap();
+            oci_dir.push_layer_with_history_annotated(
+                &mut manifest,
+                &mut config,
+                layer,
+                None::<HashMap<String, String>>,
+                Some(history),
+            );
+        }
+
+        let platform = oci_image::PlatformBuilder::default()
+            .os("linux")
+            .architecture("amd64")
+            .build()
+            .unwrap();
+        oci_dir
+            .insert_manifest_and_config(manifest, config, None, platform)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_layers_claims() {
+        let tmp = tempfile::tempdir().unwrap();
+        build_image(
+            &tmp,
+            &[
+                &[
+                    ("usr/", ""),
+                    ("usr/a", "a"),
+                    ("usr/b", "b"),
+                    ("etc/", ""),
+                    ("etc/x", "x"),
+                ],
+                &[
+                    ("usr/", ""),
+                    ("usr/.wh.b", ""),
+                    ("usr/a", "a2"),
+                    ("usr/c", "c"),
+                    ("etc/", ""),
+                    ("etc/y", "y"),
+                    ("etc/.wh..wh..opq", ""),
+                ],
+            ],
+        );
+        let path = Utf8Path::from_path(tmp.path()).unwrap();
+        let repo = LayersRepo::load(path, 1714953600 + 86400).unwrap().unwrap();
+
+        // the topmost layer wins
+        assert_eq!(claim_names(&repo, "/usr/a"), ["1"]);
+        assert_eq!(claim_names(&repo, "/usr/c"), ["1"]);
+        assert_eq!(claim_names(&repo, "/usr"), ["0", "1"]);
+        // whiteouts delete paths from lower layers
+        assert!(claim_names(&repo, "/usr/b").is_empty());
+        assert!(claim_names(&repo, "/usr/.wh.b").is_empty());
+        // but opaque whiteouts only apply to lower layers
+        assert!(claim_names(&repo, "/etc/x").is_empty());
+        assert_eq!(claim_names(&repo, "/etc/y"), ["1"]);
+
+        // the clamp is the layer creation time
+        let claims = repo.claims_for_path(Utf8Path::new("/usr/a"), FileType::File);
+        assert_eq!(repo.component_info(claims[0]).mtime_clamp, 1714608000);
+    }
+}
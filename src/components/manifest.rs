@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Deserialize;
+
+use super::{ComponentId, ComponentInfo, ComponentsRepo, FileMap, FileType, Retention};
+use crate::utils::GLOB_MATCH_OPTIONS;
+
+const REPO_NAME: &str = "manifest";
+
+/// Manifest-based components repo implementation.
+///
+/// Uses a user-provided TOML file mapping glob patterns to components. This is
+/// an alternative to the `user.component` xattr for when the rootfs is produced
+/// by a tool that can't set xattrs. The manifest looks like:
+///
+/// ```toml
+/// [[component]]
+/// name = "myapp"
+/// paths = ["/opt/myapp", "/opt/myapp/**", "/usr/bin/myapp"]
+/// stability = 0.9
+/// mtime = 1700000000
+/// retention = "short"
+/// ```
+///
+/// `stability`, `mtime` and `retention` are optional. If a path matches
+/// multiple components, the first one in the file wins. See [`RetentionRules`]
+/// for `retention`.
+pub struct ManifestRepo {
+    /// Components, indexed by ComponentId.
+    components: Vec<ManifestComponent>,
+    /// Mapping from path to ComponentId.
+    path_to_component: HashMap<Utf8PathBuf, ComponentId>,
+    /// Default mtime clamp for components without one.
+    default_mtime_clamp: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(rename = "component", default)]
+    components: Vec<ManifestComponent>,
+    #[serde(rename = "retention", default)]
+    retention_rules: Vec<RetentionRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestComponent {
+    name: String,
+    paths: Vec<String>,
+    /// Probability the component doesn't change between updates.
+    stability: Option<f64>,
+    /// The mtime clamp for the component's files.
+    mtime: Option<u64>,
+    /// Retention hint for the layer of the component.
+    retention: Option<Retention>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RetentionRule {
+    /// Glob patterns matching full component names, e.g. `rpm/*`.
+    components: Vec<String>,
+    retention: Retention,
+}
+
+/// Retention hints for components from the components manifest.
+///
+/// Besides the `retention` of its own components, the manifest can give hints
+/// for components of any repo:
+///
+/// ```toml
+/// [[retention]]
+/// components = ["rpm/*", "dpkg/*"]
+/// retention = "long"
+/// ```
+///
+/// The first matching rule wins, with the manifest's own components first.
+/// Layers are annotated with `org.chunkah.retention`: `short` if any of their
+/// components is short-lived, `long` if all of them are long-lived.
+#[derive(Debug, Clone)]
+pub struct RetentionRules {
+    rules: Vec<(Vec<glob::Pattern>, Retention)>,
+}
+
+impl RetentionRules {
+    /// Load the retention rules of the manifest at `path`.
+    pub fn load(path: &Utf8Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path).with_context(|| format!("reading {path}"))?;
+        Self::parse(&content).with_context(|| format!("parsing {path}"))
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let manifest: Manifest = toml::from_str(content)?;
+        let mut rules = Vec::new();
+        for component in &manifest.components {
+            if let Some(retention) = component.retention {
+                let name = format!("{REPO_NAME}/{}", glob::Pattern::escape(&component.name));
+                let pattern = glob::Pattern::new(&name)
+                    .with_context(|| format!("component {}: invalid name", component.name))?;
+                rules.push((vec![pattern], retention));
+            }
+        }
+        for rule in manifest.retention_rules {
+            let patterns = rule
+                .components
+                .iter()
+                .map(|p| {
+                    glob::Pattern::new(p).with_context(|| format!("invalid retention pattern {p}"))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            rules.push((patterns, rule.retention));
+        }
+        Ok(Self { rules })
+    }
+
+    /// Returns the retention of the component named `name`, if any rule
+    /// matches it.
+    pub fn retention(&self, name: &str) -> Option<Retention> {
+        self.rules
+            .iter()
+            .find(|(patterns, _)| {
+                patterns
+                    .iter()
+                    .any(|p| p.matches_with(name, GLOB_MATCH_OPTIONS))
+            })
+            .map(|(_, retention)| *retention)
+    }
+
+    /// Returns the retention of a layer holding the components `names`.
+    pub fn layer_retention<'a>(
+        &self,
+        names: impl IntoIterator<Item = &'a str>,
+    ) -> Option<Retention> {
+        let mut all_long = true;
+        for name in names {
+            match self.retention(name) {
+                Some(Retention::Short) => return Some(Retention::Short),
+                Some(Retention::Long) => {}
+                None => all_long = false,
+            }
+        }
+        all_long.then_some(Retention::Long)
+    }
+}
+
+impl ManifestRepo {
+    /// Load the manifest at `path` and match its patterns against `files`.
+    ///
+    /// Returns `Ok(None)` if the manifest doesn't claim any files.
+    pub fn load(
+        path: &Utf8Path,
+        files: &FileMap,
+        default_mtime_clamp: u64,
+    ) -> Result<Option<Self>> {
+        let content = std::fs::read_to_string(path).with_context(|| format!("reading {path}"))?;
+        Self::parse(&content, files, default_mtime_clamp).with_context(|| format!("parsing {path}"))
+    }
+
+    fn parse(content: &str, files: &FileMap, default_mtime_clamp: u64) -> Result<Option<Self>> {
+        let manifest: Manifest = toml::from_str(content)?;
+
+        let mut patterns = Vec::with_capacity(manifest.components.len());
+        for component in &manifest.components {
+            anyhow::ensure!(!component.name.is_empty(), "component name cannot be empty");
+            if let Some(stability) = component.stability {
+                anyhow::ensure!(
+                    (0.0..=1.0).contains(&stability),
+                    "component {}: stability must be between 0 and 1",
+                    component.name
+                );
+            }
+            let component_patterns = component
+                .paths
+                .iter()
+                .map(|p| {
+                    anyhow::ensure!(
+                        p.starts_with('/'),
+                        "component {}: pattern must be absolute: {p}",
+                        component.name
+                    );
+                    glob::Pattern::new(p).with_context(|| {
+                        format!("component {}: invalid pattern {p}", component.name)
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            patterns.push(component_patterns);
+        }
+
+        let mut path_to_component = HashMap::new();
+        for path in files.keys() {
+            let matched = patterns.iter().position(|component_patterns| {
+                component_patterns
+                    .iter()
+                    .any(|p| p.matches_with(path.as_str(), GLOB_MATCH_OPTIONS))
+            });
+            if let Some(idx) = matched {
+                path_to_component.insert(path.clone(), ComponentId(idx));
+            }
+        }
+
+        if path_to_component.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            components: manifest.components,
+            path_to_component,
+            default_mtime_clamp,
+        }))
+    }
+}
+
+impl ComponentsRepo for ManifestRepo {
+    fn name(&self) -> &'static str {
+        REPO_NAME
+    }
+
+    fn default_priority(&self) -> usize {
+        // Explicitly configured like xattrs; since it's loaded after the xattr
+        // repo, xattrs win on ties.
+        0
+    }
+
+    fn claims_for_path(&self, path: &Utf8Path, _file_type: FileType) -> Vec<ComponentId> {
+        self.path_to_component
+            .get(path)
+            .map(|id| vec![*id])
+            .unwrap_or_default()
+    }
+
+    fn component_info(&self, id: ComponentId) -> ComponentInfo<'_> {
+        // SAFETY: the ids we're given come from the index in the Vec itself,
+        // so it must be valid.
+        let component = &self.components[id.0];
+        ComponentInfo {
+            name: &component.name,
+            mtime_clamp: component.mtime.unwrap_or(self.default_mtime_clamp),
+            // 0.0 means it gets the fallback stability
+            stability: component.stability.unwrap_or(0.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{FileInfo, claim_names};
+
+    fn file_map(paths: &[&str]) -> FileMap {
+        paths
+            .iter()
+            .map(|p| {
+                let info = FileInfo::test_file(FileType::File, 0, 0);
+                (Utf8PathBuf::from(*p), info)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_manifest_claims() {
+        let files = file_map(&[
+            "/opt/myapp",
+            "/opt/myapp/bin/myapp",
+            "/opt/myapp/share/data.db",
+            "/usr/bin/myapp",
+            "/usr/bin/other",
+            "/usr/lib/libfoo.so.1",
+        ]);
+        let repo = ManifestRepo::parse(
+            r#"
+            [[component]]
+            name = "myapp-data"
+            paths = ["/opt/myapp/share/**"]
+            stability = 0.2
+
+            [[component]]
+            name = "myapp"
+            paths = ["/opt/myapp", "/opt/myapp/**", "/usr/bin/myapp"]
+            mtime = 1700000000
+
+            [[component]]
+            name = "libs"
+            paths = ["/usr/lib/*.so*", "/usr/*"]
+            "#,
+            &files,
+            42,
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(claim_names(&repo, "/opt/myapp"), ["myapp"]);
+        assert_eq!(claim_names(&repo, "/opt/myapp/bin/myapp"), ["myapp"]);
+        assert_eq!(claim_names(&repo, "/usr/bin/myapp"), ["myapp"]);
+        // the first matching component wins
+        assert_eq!(
+            claim_names(&repo, "/opt/myapp/share/data.db"),
+            ["myapp-data"]
+        );
+        assert_eq!(claim_names(&repo, "/usr/lib/libfoo.so.1"), ["libs"]);
+        // `*` doesn't match `/`
+        assert!(claim_names(&repo, "/usr/bin/other").is_empty());
+
+        let info = |path: &str| {
+            let claims = repo.claims_for_path(Utf8Path::new(path), FileType::File);
+            repo.component_info(claims[0])
+        };
+        assert_eq!(info("/opt/myapp").mtime_clamp, 1700000000);
+        assert_eq!(info("/opt/myapp").stability, 0.0);
+        assert_eq!(info("/opt/myapp/share/data.db").mtime_clamp, 42);
+        assert_eq!(info("/opt/myapp/share/data.db").stability, 0.2);
+    }
+
+    #[test]
+    fn test_manifest_invalid() {
+        let files = file_map(&["/usr/bin/foo"]);
+        for content in [
+            "[[component]]\nname = \"foo\"\npaths = [\"usr/bin/foo\"]",
+            "[[component]]\nname = \"foo\"\npaths = [\"/usr/[\"]",
+            "[[component]]\nname = \"foo\"\npaths = []\nstability = 2.0",
+            "[[component]]\nname = \"\"\npaths = []",
+            "[[component]]\nname = \"foo\"\npath = []",
+        ] {
+            assert!(
+                ManifestRepo::parse(content, &files, 0).is_err(),
+                "{content:?} should be rejected"
+            );
+        }
+
+        // nothing claimed
+        let content = "[[component]]\nname = \"foo\"\npaths = [\"/opt/**\"]";
+        assert!(ManifestRepo::parse(content, &files, 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_retention_rules() {
+        let rules = RetentionRules::parse(
+            r#"
+            [[component]]
+            name = "myapp"
+            paths = ["/opt/myapp/**"]
+            retention = "short"
+
+            [[component]]
+            name = "libs"
+            paths = ["/usr/lib/**"]
+
+            [[retention]]
+            components = ["rpm/*", "manifest/*"]
+            retention = "long"
+
+            [[retention]]
+            components = ["rpm/kernel*"]
+            retention = "short"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(rules.retention("manifest/myapp"), Some(Retention::Short));
+        assert_eq!(rules.retention("manifest/libs"), Some(Retention::Long));
+        // the first matching rule wins
+        assert_eq!(rules.retention("rpm/kernel-core"), Some(Retention::Long));
+        assert_eq!(rules.retention("xattr/app"), None);
+
+        assert_eq!(
+            rules.layer_retention(["rpm/bash", "manifest/libs"]),
+            Some(Retention::Long)
+        );
+        assert_eq!(
+            rules.layer_retention(["rpm/bash", "manifest/myapp"]),
+            Some(Retention::Short)
+        );
+        assert_eq!(rules.layer_retention(["rpm/bash", "xattr/app"]), None);
+
+        assert!(
+            RetentionRules::parse("[[retention]]\ncomponents = [\"*\"]\nretention = \"forever\"\n")
+                .is_err()
+        );
+    }
+}
@@ -1,31 +1,294 @@
-mod alpm;
+pub(crate) mod alpm;
+mod apk;
 mod bigfiles;
+mod brew;
+mod cache;
+mod churn;
+mod content;
+mod dpkg;
+mod external;
+mod golang;
+mod kernel;
+pub(crate) mod layers;
+mod manifest;
+mod models;
+mod pip;
+mod portage;
+mod previous;
 mod rpm;
+mod stability;
+mod updateinfo;
 mod xattr;
 
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+
+pub use cache::ClaimCache;
+pub(crate) use churn::ChurnState;
+pub(crate) use manifest::RetentionRules;
+pub use stability::StabilityOverrides;
+pub(crate) use stability::StabilitySnapshot;
+pub use updateinfo::UpdateInfo;
+pub use xattr::DEFAULT_XATTR_NAME;
 
 /// The name of the component for files not claimed by any repo.
 pub const UNCLAIMED_COMPONENT: &str = "chunkah/unclaimed";
 
 use anyhow::{Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
-use cap_std_ext::cap_std::fs::{Dir, FileType as CapFileType, Metadata, MetadataExt};
+use cap_std_ext::cap_std::fs::{Dir, FileType as CapFileType, FileTypeExt, Metadata, MetadataExt};
 
 /// Seconds per day.
-pub const SECS_PER_DAY: u64 = 60 * 60 * 24;
+pub(crate) const SECS_PER_DAY: u64 = 60 * 60 * 24;
 
 /// Period in days for calculating stability probability.
 /// TODO: make this configurable via CLI
-pub const STABILITY_PERIOD_DAYS: f64 = 7.0;
+pub(crate) const STABILITY_PERIOD_DAYS: f64 = 7.0;
 
 /// Maximum lookback period in days for changelog analysis.
-pub const STABILITY_LOOKBACK_DAYS: u64 = 365;
+pub(crate) const STABILITY_LOOKBACK_DAYS: u64 = 365;
+
+/// Names of the built-in repos.
+const BUILTIN_REPOS: &[&str] = &[
+    "xattr", "manifest", "content", "kernel", "rpm", "alpm", "dpkg", "apk", "portage", "external",
+    "brew", "pip", "go", "models", "previous", "layers", "bigfiles",
+];
+
+/// Maximum number of conflicting paths listed in the error.
+const MAX_REPORTED_CONFLICTS: usize = 20;
+
+/// How to resolve paths claimed by more than one repo.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ClaimPolicy {
+    /// The highest priority repo claiming a path gets it.
+    #[default]
+    FirstWins,
+    /// Every repo claiming a path gets it, so it ends up in several
+    /// components.
+    Union,
+    /// Fail if repos claim a file or symlink for different components.
+    /// Directories are commonly shared, so they're resolved like with
+    /// `FirstWins`.
+    ErrorOnConflict,
+}
+
+/// What the rpm repo groups files into components by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RpmGroupBy {
+    /// The source RPM, which merges all its subpackages (e.g. `-devel`,
+    /// `-libs`) into one component.
+    #[default]
+    Srpm,
+    /// The binary package, so that subpackages which change at different
+    /// rates can end up in different layers.
+    Package,
+}
+
+/// Where the rpm repo puts `%config(noreplace)` files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RpmConfigFiles {
+    /// In the component of their package, like all other files.
+    #[default]
+    Keep,
+    /// In a `<name>-config` component next to the component of their package.
+    Split,
+    /// In a single `config` component for all packages.
+    Shared,
+}
+
+/// What the bigfiles repo groups big files into components by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum BigfilesGroupBy {
+    /// Each file (with its hardlinks) is a component of its own.
+    #[default]
+    File,
+    /// Files with the same extension share a component, e.g. `*.ko`.
+    Extension,
+    /// Files in the same directory share a component.
+    Directory,
+}
+
+/// How long registries should keep layers around, as a hint for garbage
+/// collection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Retention {
+    /// Stable layers, e.g. of the base OS, shared by many tags.
+    Long,
+    /// Layers which churn, e.g. of the application.
+    Short,
+}
+
+impl std::fmt::Display for Retention {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Long => "long",
+            Self::Short => "short",
+        })
+    }
+}
+
+/// Loads a repo registered by a downstream crate.
+///
+/// It's called with the rootfs, the files in it and the default mtime clamp,
+/// like the built-in repos are. It should return `Ok(None)` if the repo
+/// doesn't apply to the rootfs.
+pub type RepoLoader = Box<dyn Fn(&Dir, &FileMap, u64) -> Result<Option<Box<dyn ComponentsRepo>>>>;
 
 /// Loaded component repos along with the default mtime to use.
 pub struct ComponentsRepos {
     repos: Vec<Box<dyn ComponentsRepo>>,
     default_mtime_clamp: u64,
+    config: RepoConfig,
+}
+
+/// Options for loading repos: which are used, in which order, and where to
+/// cache parsed package databases.
+#[derive(Debug, Clone, Default)]
+pub struct RepoConfig {
+    priorities: HashMap<String, usize>,
+    disabled: HashSet<String>,
+    cache: Option<ClaimCache>,
+    policy: ClaimPolicy,
+    component_xattr: Option<String>,
+    rpm_group_by: RpmGroupBy,
+    rpm_config_files: RpmConfigFiles,
+    rpm_verify: bool,
+    dpkg_verify: bool,
+    bigfile_threshold: Option<u64>,
+    bigfile_group_by: BigfilesGroupBy,
+    rpm_updateinfo: Option<UpdateInfo>,
+    stability_overrides: Option<StabilityOverrides>,
+    content_classes: bool,
+    kernel_layer: bool,
+}
+
+impl RepoConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use `priority` instead of the default priority of the repo named `name`.
+    pub fn priority(mut self, name: impl Into<String>, priority: usize) -> Self {
+        self.priorities.insert(name.into(), priority);
+        self
+    }
+
+    /// Don't use the repo named `name`.
+    pub fn disable(mut self, name: impl Into<String>) -> Self {
+        self.disabled.insert(name.into());
+        self
+    }
+
+    /// Cache parsed package databases (rpm, dpkg) in `cache`.
+    pub fn claim_cache(mut self, cache: ClaimCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Resolve paths claimed by more than one repo according to `policy`.
+    pub fn claim_policy(mut self, policy: ClaimPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Read the component of paths from the `name` xattr instead of
+    /// `user.component`.
+    pub fn component_xattr(mut self, name: impl Into<String>) -> Self {
+        self.component_xattr = Some(name.into());
+        self
+    }
+
+    /// Group rpm files into components by `group_by` instead of by SRPM.
+    pub fn rpm_group_by(mut self, group_by: RpmGroupBy) -> Self {
+        self.rpm_group_by = group_by;
+        self
+    }
+
+    /// Put rpm `%config(noreplace)` files in components according to
+    /// `config_files`.
+    pub fn rpm_config_files(mut self, config_files: RpmConfigFiles) -> Self {
+        self.rpm_config_files = config_files;
+        self
+    }
+
+    /// Move rpm files whose content doesn't match the rpmdb to a separate
+    /// component.
+    pub fn rpm_verify(mut self, verify: bool) -> Self {
+        self.rpm_verify = verify;
+        self
+    }
+
+    /// Move dpkg files whose content doesn't match their `.md5sums` to a
+    /// separate component.
+    pub fn dpkg_verify(mut self, verify: bool) -> Self {
+        self.dpkg_verify = verify;
+        self
+    }
+
+    /// Estimate the stability of rpm components from the updates published in
+    /// `updateinfo` too, not only from their changelogs.
+    pub fn rpm_updateinfo(mut self, updateinfo: UpdateInfo) -> Self {
+        self.rpm_updateinfo = Some(updateinfo);
+        self
+    }
+
+    /// Use the stabilities in `overrides` instead of the ones computed by the
+    /// repos.
+    pub fn stability_overrides(mut self, overrides: StabilityOverrides) -> Self {
+        self.stability_overrides = Some(overrides);
+        self
+    }
+
+    /// Only put files of at least `size` bytes in components of their own in
+    /// the `bigfiles` repo, instead of 1 MB.
+    pub fn bigfile_threshold(mut self, size: u64) -> Self {
+        self.bigfile_threshold = Some(size);
+        self
+    }
+
+    /// Group big files into components by `group_by` instead of one per file.
+    pub fn bigfile_group_by(mut self, group_by: BigfilesGroupBy) -> Self {
+        self.bigfile_group_by = group_by;
+        self
+    }
+
+    /// Carve docs, translations, man pages and icons out of the components of
+    /// their packages into components of their own (see the `content` repo).
+    pub fn content_classes(mut self, enabled: bool) -> Self {
+        self.content_classes = enabled;
+        self
+    }
+
+    /// Claim each kernel version, with its modules and initramfs, into a
+    /// component of its own (see the `kernel` repo).
+    pub fn kernel_layer(mut self, enabled: bool) -> Self {
+        self.kernel_layer = enabled;
+        self
+    }
+
+    fn is_enabled(&self, name: &str) -> bool {
+        !self.disabled.contains(name)
+    }
+
+    fn xattr_name(&self) -> &str {
+        self.component_xattr
+            .as_deref()
+            .unwrap_or(xattr::DEFAULT_XATTR_NAME)
+    }
+
+    fn bigfile_min_size(&self) -> u64 {
+        self.bigfile_threshold.unwrap_or(bigfiles::DEFAULT_MIN_SIZE)
+    }
+
+    /// Returns the priority of a repo, taking overrides into account.
+    fn priority_of(&self, repo: &dyn ComponentsRepo) -> usize {
+        self.priorities
+            .get(repo.name())
+            .copied()
+            .unwrap_or_else(|| repo.default_priority())
+    }
 }
 
 /// Files belonging to a component.
@@ -39,38 +302,76 @@ pub struct Component {
     pub stability: f64,
     /// The files belonging to this component, with their metadata.
     pub files: FileMap,
+    /// The packages the component is made of, e.g. as NEVRAs for rpm, if
+    /// the repo knows them.
+    pub packages: BTreeSet<String>,
 }
 
 /// A map from file paths to their metadata.
 pub type FileMap = BTreeMap<Utf8PathBuf, FileInfo>;
 
+/// The xattrs of a file, as names and values.
+///
+/// Most files of a rootfs have the same few xattrs, or none at all, so the scan
+/// interns them with [`XattrInterner`] and files share them.
+pub type Xattrs = Arc<[(String, Vec<u8>)]>;
+
+/// Deduplicates the xattrs of files.
+#[derive(Default)]
+pub(crate) struct XattrInterner(HashSet<Xattrs>);
+
+impl XattrInterner {
+    /// Returns the shared copy of `xattrs`.
+    pub(crate) fn intern(&mut self, xattrs: Vec<(String, Vec<u8>)>) -> Xattrs {
+        if let Some(interned) = self.0.get(xattrs.as_slice()) {
+            return interned.clone();
+        }
+        let interned = Xattrs::from(xattrs);
+        self.0.insert(interned.clone());
+        interned
+    }
+}
+
 /// Cached file metadata from the scan.
 #[derive(Debug, Clone)]
 pub struct FileInfo {
     pub file_type: FileType,
     pub mode: u32,
-    #[allow(dead_code)]
     pub size: u64,
     pub uid: u32,
     pub gid: u32,
     pub mtime: u64,
     pub ino: u64,
     pub nlink: u64,
-    pub xattrs: Vec<(String, Vec<u8>)>,
+    pub xattrs: Xattrs,
+    /// Metadata which most files don't have, so that it only costs them a
+    /// pointer. Use the accessors, which keep it `None` when it's empty.
+    pub(crate) extra: Option<Box<FileExtra>>,
+}
+
+/// Rarely set metadata of a [`FileInfo`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct FileExtra {
+    rdev: u64,
+    sha256: Option<[u8; 32]>,
+    disk_path: Option<Box<Path>>,
 }
 
 /// File type for entries in the rootfs.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum FileType {
     Directory,
     File,
     Symlink,
+    BlockDevice,
+    CharDevice,
+    Fifo,
 }
 
 impl FileType {
     /// Try to convert from cap_std file type.
     ///
-    /// Returns `None` for unsupported types (sockets, FIFOs, block/char devices).
+    /// Returns `None` for sockets, which tar can't represent.
     pub fn from_cap_std(file_type: &CapFileType) -> Option<Self> {
         if file_type.is_dir() {
             Some(FileType::Directory)
@@ -78,20 +379,77 @@ impl FileType {
             Some(FileType::File)
         } else if file_type.is_symlink() {
             Some(FileType::Symlink)
+        } else if file_type.is_block_device() {
+            Some(FileType::BlockDevice)
+        } else if file_type.is_char_device() {
+            Some(FileType::CharDevice)
+        } else if file_type.is_fifo() {
+            Some(FileType::Fifo)
         } else {
             None
         }
     }
+
+    /// Returns whether this is a device or a FIFO.
+    pub fn is_special(self) -> bool {
+        matches!(self, Self::BlockDevice | Self::CharDevice | Self::Fifo)
+    }
 }
 
 impl FileInfo {
+    /// Returns the path relative to the rootfs of the file at `path`, which
+    /// differs from `path` if the actual one isn't valid UTF-8 or was
+    /// rewritten.
+    pub fn fs_path<'a>(&'a self, path: &'a Utf8Path) -> &'a Path {
+        match self.disk_path() {
+            Some(fs_path) => fs_path,
+            None => path.strip_prefix("/").unwrap_or(path).as_std_path(),
+        }
+    }
+
+    /// Returns the device number of block and character devices, or 0.
+    pub fn rdev(&self) -> u64 {
+        self.extra.as_ref().map_or(0, |extra| extra.rdev)
+    }
+
+    pub fn set_rdev(&mut self, rdev: u64) {
+        self.update_extra(|extra| extra.rdev = rdev);
+    }
+
+    /// Returns the SHA-256 of the content of regular files, if hashed while
+    /// scanning.
+    pub fn sha256(&self) -> Option<&[u8; 32]> {
+        self.extra.as_ref().and_then(|extra| extra.sha256.as_ref())
+    }
+
+    pub fn set_sha256(&mut self, sha256: Option<[u8; 32]>) {
+        self.update_extra(|extra| extra.sha256 = sha256);
+    }
+
+    /// Returns the actual path of the file relative to the rootfs, if the
+    /// file goes by another path: a lossy version of a path which isn't valid
+    /// UTF-8, or a path rewritten by `--transform`.
+    pub fn disk_path(&self) -> Option<&Path> {
+        self.extra
+            .as_ref()
+            .and_then(|extra| extra.disk_path.as_deref())
+    }
+
+    pub fn set_disk_path(&mut self, disk_path: Option<Box<Path>>) {
+        self.update_extra(|extra| extra.disk_path = disk_path);
+    }
+
+    fn update_extra(&mut self, update: impl FnOnce(&mut FileExtra)) {
+        let extra = self.extra.get_or_insert_with(Default::default);
+        update(extra);
+        if **extra == FileExtra::default() {
+            self.extra = None;
+        }
+    }
+
     /// Create FileInfo from metadata and xattrs.
-    pub fn from_metadata(
-        metadata: &Metadata,
-        file_type: FileType,
-        xattrs: Vec<(String, Vec<u8>)>,
-    ) -> Self {
-        Self {
+    pub fn from_metadata(metadata: &Metadata, file_type: FileType, xattrs: Xattrs) -> Self {
+        let mut info = Self {
             file_type,
             mode: metadata.mode(),
             size: metadata.len(),
@@ -101,10 +459,43 @@ impl FileInfo {
             ino: metadata.ino(),
             nlink: metadata.nlink(),
             xattrs,
+            extra: None,
+        };
+        if matches!(file_type, FileType::BlockDevice | FileType::CharDevice) {
+            info.set_rdev(metadata.rdev());
+        }
+        info
+    }
+}
+
+#[cfg(test)]
+impl FileInfo {
+    /// Create a FileInfo for tests, with mode 0644 owned by root.
+    pub(crate) fn test_file(file_type: FileType, size: u64, mtime: u64) -> Self {
+        Self {
+            file_type,
+            mode: 0o644,
+            size,
+            uid: 0,
+            gid: 0,
+            mtime,
+            ino: 0,
+            nlink: 1,
+            xattrs: Default::default(),
+            extra: None,
         }
     }
 }
 
+/// Returns the names of the components `repo` claims the file at `path` for.
+#[cfg(test)]
+pub(crate) fn claim_names<'a>(repo: &'a dyn ComponentsRepo, path: &str) -> Vec<&'a str> {
+    repo.claims_for_path(Utf8Path::new(path), FileType::File)
+        .into_iter()
+        .map(|id| repo.component_info(id).name)
+        .collect()
+}
+
 impl ComponentsRepos {
     /// Detect and load all component repos present in the given rootfs.
     ///
@@ -113,90 +504,353 @@ impl ComponentsRepos {
     /// used as the mtime clamp for components that don't have a reproducible
     /// clamp (e.g. xattr-claimed files, unclaimed files).
     pub fn load(rootfs: &Dir, files: &FileMap, default_mtime_clamp: u64) -> Result<Self> {
+        Self::load_with_config(rootfs, files, default_mtime_clamp, RepoConfig::default())
+    }
+
+    /// Like [`ComponentsRepos::load`], but with overrides for which repos are
+    /// used and their priorities. Disabled repos aren't loaded at all.
+    pub fn load_with_config(
+        rootfs: &Dir,
+        files: &FileMap,
+        default_mtime_clamp: u64,
+        config: RepoConfig,
+    ) -> Result<Self> {
         let mut repos: Vec<Box<dyn ComponentsRepo>> = Vec::new();
 
-        if let Some(repo) =
-            xattr::XattrRepo::load(files, default_mtime_clamp).context("loading xattrs")?
+        if config.is_enabled("xattr")
+            && let Some(repo) =
+                xattr::XattrRepo::load(files, default_mtime_clamp, config.xattr_name())
+                    .context("loading xattrs")?
         {
             repos.push(Box::new(repo));
         }
 
-        if let Some(repo) =
-            rpm::RpmRepo::load(rootfs, files, default_mtime_clamp).context("loading rpmdb")?
+        if config.content_classes
+            && config.is_enabled("content")
+            && let Some(repo) = content::ContentRepo::load(files, default_mtime_clamp)
         {
             repos.push(Box::new(repo));
         }
 
-        if let Some(repo) = alpm::AlpmComponentsRepo::load(rootfs, files, default_mtime_clamp)
-            .context("loading alpm packages")?
+        if config.kernel_layer
+            && config.is_enabled("kernel")
+            && let Some(repo) = kernel::KernelRepo::load(files, default_mtime_clamp)
         {
             repos.push(Box::new(repo));
         }
 
-        if let Some(repo) = bigfiles::BigfilesRepo::load(files, default_mtime_clamp) {
+        if config.is_enabled("rpm")
+            && let Some(repo) = rpm::RpmRepo::load(rootfs, files, default_mtime_clamp, &config)
+                .context("loading rpmdb")?
+        {
             repos.push(Box::new(repo));
         }
 
-        // Other backends (e.g. deb, apk, pip, etc.) would go here...
+        if config.is_enabled("alpm")
+            && let Some(repo) = alpm::AlpmComponentsRepo::load(rootfs, files, default_mtime_clamp)
+                .context("loading alpm packages")?
+        {
+            repos.push(Box::new(repo));
+        }
+
+        if config.is_enabled("dpkg")
+            && let Some(repo) = dpkg::DpkgRepo::load(rootfs, files, default_mtime_clamp, &config)
+                .context("loading dpkg database")?
+        {
+            repos.push(Box::new(repo));
+        }
+
+        if config.is_enabled("apk")
+            && let Some(repo) = apk::ApkRepo::load(rootfs, files, default_mtime_clamp)
+                .context("loading apk database")?
+        {
+            repos.push(Box::new(repo));
+        }
+
+        if config.is_enabled("portage")
+            && let Some(repo) = portage::PortageRepo::load(rootfs, files, default_mtime_clamp)
+                .context("loading portage database")?
+        {
+            repos.push(Box::new(repo));
+        }
+
+        if config.is_enabled("brew")
+            && let Some(repo) = brew::BrewRepo::load(rootfs, files, default_mtime_clamp)
+                .context("loading Homebrew Cellar")?
+        {
+            repos.push(Box::new(repo));
+        }
+
+        if config.is_enabled("pip")
+            && let Some(repo) = pip::PipRepo::load(rootfs, files, default_mtime_clamp)
+                .context("loading pip RECORDs")?
+        {
+            repos.push(Box::new(repo));
+        }
+
+        if config.is_enabled("go")
+            && let Some(repo) = golang::GoRepo::load(rootfs, files, default_mtime_clamp)
+                .context("loading Go build info")?
+        {
+            repos.push(Box::new(repo));
+        }
+
+        if config.is_enabled("models")
+            && let Some(repo) = models::ModelsRepo::load(files, default_mtime_clamp)
+        {
+            repos.push(Box::new(repo));
+        }
+
+        if config.is_enabled("bigfiles")
+            && let Some(repo) = bigfiles::BigfilesRepo::load(
+                files,
+                default_mtime_clamp,
+                config.bigfile_min_size(),
+                config.bigfile_group_by,
+            )
+        {
+            repos.push(Box::new(repo));
+        }
 
         Ok(Self {
             repos,
             default_mtime_clamp,
+            config,
         })
     }
 
-    /// Returns true if no repos were loaded.
+    /// Add a repo using the layers of the image in the OCI image layout at
+    /// `path` as components.
+    ///
+    /// This is meant for when the rootfs comes from that image.
+    pub fn load_original_layers(&mut self, path: &Utf8Path) -> Result<()> {
+        if let Some(repo) = layers::LayersRepo::load(path, self.default_mtime_clamp)? {
+            self.register(Box::new(repo));
+        }
+        Ok(())
+    }
+
+    /// Add a repo using the claims of the external claimer at `exec`, run on
+    /// the files of the rootfs at `rootfs`.
+    ///
+    /// See [`external::ExternalRepo`] for the protocol.
+    pub fn load_external_claimer(
+        &mut self,
+        exec: &Utf8Path,
+        rootfs: &Utf8Path,
+        files: &FileMap,
+        registry_auth_file: Option<&Utf8Path>,
+    ) -> Result<()> {
+        if let Some(repo) = external::ExternalRepo::load(
+            exec,
+            rootfs,
+            files,
+            self.default_mtime_clamp,
+            registry_auth_file,
+        )? {
+            self.register(Box::new(repo));
+        }
+        Ok(())
+    }
+
+    /// Add a repo using the components declared in the manifest at `path`.
+    ///
+    /// See [`manifest::ManifestRepo`] for the format.
+    pub fn load_components_manifest(&mut self, path: &Utf8Path, files: &FileMap) -> Result<()> {
+        if let Some(repo) = manifest::ManifestRepo::load(path, files, self.default_mtime_clamp)? {
+            self.register(Box::new(repo));
+        }
+        Ok(())
+    }
+
+    /// Add a repo using the component to path mapping of a previous build, so
+    /// that paths keep their historical component when no other repo claims
+    /// them anymore.
+    ///
+    /// `path` is either a components JSON file or an OCI image layout of an
+    /// image built with `--embed-components`.
+    pub fn load_previous_claims(&mut self, path: &Utf8Path) -> Result<()> {
+        if let Some(repo) = previous::PreviousRepo::load(path, self.default_mtime_clamp)? {
+            self.register(Box::new(repo));
+        }
+        Ok(())
+    }
+
+    /// Add a repo, e.g. one implemented by a downstream crate.
+    ///
+    /// It's queried according to its priority like the built-in repos. It's
+    /// ignored if its name was disabled in the [`RepoConfig`].
+    pub fn register(&mut self, repo: Box<dyn ComponentsRepo>) {
+        self.repos.push(repo);
+    }
+
+    /// Returns the repo names in the [`RepoConfig`] which are neither built-in
+    /// nor loaded, which are likely typos.
+    pub(crate) fn unknown_config_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .config
+            .priorities
+            .keys()
+            .chain(&self.config.disabled)
+            .map(String::as_str)
+            .filter(|name| {
+                !BUILTIN_REPOS.contains(name) && !self.repos.iter().any(|r| r.name() == *name)
+            })
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+
+    /// Returns true if no enabled repos were loaded.
     pub fn is_empty(&self) -> bool {
-        self.repos.is_empty()
+        !self.repos.iter().any(|r| self.config.is_enabled(r.name()))
     }
 
     /// Claim files from repos and return the mapping of component names to files.
     ///
     /// Repos are sorted by priority (lower values first) before processing.
-    /// Higher priority repos "win" - if they claim a path, lower priority repos
-    /// are not consulted for that path. All unclaimed paths go into a catch-all.
-    pub fn into_components(mut self, files: FileMap) -> HashMap<String, Component> {
+    /// By default, higher priority repos "win" - if they claim a path, lower
+    /// priority repos are not consulted for that path; see [`ClaimPolicy`] for
+    /// the alternatives. Fallback repos are never consulted for claimed paths.
+    /// All unclaimed paths go into a catch-all.
+    pub fn into_components(mut self, files: FileMap) -> Result<HashMap<String, Component>> {
         let mut claims: HashMap<(usize, ComponentId), FileMap> = HashMap::new();
 
+        // registered repos are only dropped now, so that their names are
+        // known
+        let config = &self.config;
+        self.repos.retain(|r| config.is_enabled(r.name()));
+
         // make sure they're in priority order
-        self.repos.sort_by_key(|r| r.default_priority());
+        self.repos.sort_by_key(|r| config.priority_of(r.as_ref()));
 
         // check for claims!
-        let unclaimed: FileMap = files
-            .into_iter()
-            .filter_map(|(path, file_info)| {
-                // This is O(files x repos), though really the number of active
-                // repos at any time is incredibly small; in the common case, 1.
-                for (repo_idx, repo) in self.repos.iter().enumerate() {
-                    let component_ids = repo.claims_for_path(&path, file_info.file_type);
-                    if !component_ids.is_empty() {
-                        for id in component_ids {
-                            claims
-                                .entry((repo_idx, id))
-                                .or_default()
-                                .insert(path.clone(), file_info.clone());
-                        }
-                        return None; // claimed
+        let mut unclaimed = FileMap::new();
+        let mut conflicts = Vec::new();
+        for (path, file_info) in files {
+            // the first repo to claim the path, and the components it claims
+            // it for
+            let mut first: Option<(usize, Vec<ComponentId>)> = None;
+            // the components claiming the path; the file is only cloned when
+            // there are several, which is rare
+            let mut claimed_by: Vec<(usize, ComponentId)> = Vec::new();
+            // This is O(files x repos), though really the number of active
+            // repos at any time is incredibly small; in the common case, 1.
+            for (repo_idx, repo) in self.repos.iter().enumerate() {
+                if first.is_some() && repo.is_fallback() {
+                    break;
+                }
+                let component_ids = repo.claims_for_path(&path, file_info.file_type);
+                if component_ids.is_empty() {
+                    continue;
+                }
+                // only reached with the error-on-conflict and union policies
+                if let Some((first_idx, first_ids)) = &first
+                    && config.policy == ClaimPolicy::ErrorOnConflict
+                {
+                    let first_repo = &self.repos[*first_idx];
+                    let mut first_names: Vec<_> = first_ids
+                        .iter()
+                        .map(|id| first_repo.full_name(*id))
+                        .collect();
+                    let mut names: Vec<_> =
+                        component_ids.iter().map(|id| repo.full_name(*id)).collect();
+                    first_names.sort_unstable();
+                    names.sort_unstable();
+                    if first_names != names {
+                        conflicts.push(format!(
+                            "{path}: {} ({}) vs {} ({})",
+                            first_names.join(", "),
+                            first_repo.name(),
+                            names.join(", "),
+                            repo.name()
+                        ));
                     }
+                    continue;
                 }
-                Some((path, file_info)) // not claimed
-            })
-            .collect();
+                tracing::trace!(
+                    target: crate::trace::CLAIM,
+                    "{path} -> {} (repo priority {})",
+                    component_ids
+                        .iter()
+                        .map(|id| repo.full_name(*id))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    config.priority_of(repo.as_ref())
+                );
+                claimed_by.extend(component_ids.iter().map(|id| (repo_idx, *id)));
+                if first.is_none() {
+                    first = Some((repo_idx, component_ids));
+                }
+                let done = match config.policy {
+                    ClaimPolicy::FirstWins => true,
+                    ClaimPolicy::Union => false,
+                    ClaimPolicy::ErrorOnConflict => file_info.file_type == FileType::Directory,
+                };
+                if done {
+                    break;
+                }
+            }
+            let Some(last) = claimed_by.pop() else {
+                tracing::trace!(
+                    target: crate::trace::CLAIM,
+                    "{path} -> {UNCLAIMED_COMPONENT} (no repo claimed it)"
+                );
+                unclaimed.insert(path, file_info);
+                continue;
+            };
+            for key in claimed_by {
+                claims
+                    .entry(key)
+                    .or_default()
+                    .insert(path.clone(), file_info.clone());
+            }
+            claims.entry(last).or_default().insert(path, file_info);
+        }
+
+        if !conflicts.is_empty() {
+            let mut report = format!(
+                "{} path(s) claimed for different components:",
+                conflicts.len()
+            );
+            for conflict in conflicts.iter().take(MAX_REPORTED_CONFLICTS) {
+                report.push_str(&format!("\n  {conflict}"));
+            }
+            if conflicts.len() > MAX_REPORTED_CONFLICTS {
+                report.push_str(&format!(
+                    "\n  ... and {} more",
+                    conflicts.len() - MAX_REPORTED_CONFLICTS
+                ));
+            }
+            anyhow::bail!(report);
+        }
 
         // build final components map
         let mut components = HashMap::new();
         for ((repo_idx, comp_id), files) in claims {
             let repo = &self.repos[repo_idx];
             let info = repo.component_info(comp_id);
-            let full_name = format!("{}/{}", repo.name(), info.name);
-            components.insert(
-                full_name,
-                Component {
-                    mtime_clamp: info.mtime_clamp,
-                    stability: info.stability,
-                    files,
-                },
-            );
+            match components.entry(repo.full_name(comp_id)) {
+                std::collections::hash_map::Entry::Occupied(mut e) => {
+                    // Different repos can claim for the same component (e.g.
+                    // the previous build's claims); same logic as for
+                    // subpackages.
+                    let existing: &mut Component = e.get_mut();
+                    existing.mtime_clamp = existing.mtime_clamp.max(info.mtime_clamp);
+                    existing.stability = existing.stability.min(info.stability);
+                    existing.files.extend(files);
+                    existing.packages.extend(repo.component_packages(comp_id));
+                }
+                std::collections::hash_map::Entry::Vacant(e) => {
+                    e.insert(Component {
+                        mtime_clamp: info.mtime_clamp,
+                        stability: info.stability,
+                        files,
+                        packages: repo.component_packages(comp_id).into_iter().collect(),
+                    });
+                }
+            }
         }
 
         // and the catch-all component for anything unclaimed
@@ -207,10 +861,24 @@ impl ComponentsRepos {
                     mtime_clamp: self.default_mtime_clamp,
                     stability: 0.0,
                     files: unclaimed,
+                    packages: BTreeSet::new(),
                 },
             );
         }
 
+        if let Some(overrides) = &self.config.stability_overrides {
+            for (name, comp) in components.iter_mut() {
+                if let Some(stability) = overrides.stability(name) {
+                    tracing::trace!(
+                        target: crate::trace::PACK,
+                        "{name}: stability {:.3} overridden to {stability:.3}",
+                        comp.stability
+                    );
+                    comp.stability = stability;
+                }
+            }
+        }
+
         // Final pass: fill in stability for components with 0.0 (xattr,
         // bigfiles, unclaimed). Use half the minimum non-zero stability so
         // they're considered less stable than any known component, but non-zero
@@ -231,16 +899,52 @@ impl ComponentsRepos {
             }
         }
 
-        components
+        Ok(components)
     }
 }
 
-/// Opaque identifier for a component within a repo.
+/// Serialize the component to path ownership mapping as JSON.
+///
+/// This is what gets embedded as `/usr/share/chunkah/components.json` when
+/// requested so that tools inside the container can introspect it. The output
+/// is sorted so that it's reproducible.
+pub fn components_json(components: &HashMap<String, Component>) -> Result<Vec<u8>> {
+    #[derive(serde::Serialize)]
+    struct ComponentEntry<'a> {
+        stability: f64,
+        paths: Vec<&'a Utf8Path>,
+    }
+
+    #[derive(serde::Serialize)]
+    struct Document<'a> {
+        version: u32,
+        components: BTreeMap<&'a str, ComponentEntry<'a>>,
+    }
+
+    let doc = Document {
+        version: 1,
+        components: components
+            .iter()
+            .map(|(name, component)| {
+                let entry = ComponentEntry {
+                    stability: component.stability,
+                    paths: component.files.keys().map(|p| p.as_path()).collect(),
+                };
+                (name.as_str(), entry)
+            })
+            .collect(),
+    };
+    serde_json::to_vec_pretty(&doc).context("serializing components")
+}
+
+/// Identifier for a component within a repo.
+///
+/// Its meaning is up to the repo; typically an index into its components.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-struct ComponentId(usize);
+pub struct ComponentId(pub usize);
 
 /// Information about a component.
-struct ComponentInfo<'a> {
+pub struct ComponentInfo<'a> {
     pub name: &'a str,
     pub mtime_clamp: u64,
     pub stability: f64,
@@ -249,7 +953,9 @@ struct ComponentInfo<'a> {
 /// A trait for any type of "repo" of components (e.g., rpm, dpkg, etc.)
 ///
 /// Components repos are query objects that answer which components claim a path.
-trait ComponentsRepo {
+/// Downstream crates can implement it and add their repos with
+/// [`ComponentsRepos::register`] or [`crate::cmd_build::run_with_repos`].
+pub trait ComponentsRepo {
     /// Returns the name of this repo type (e.g., "rpm", "xattr").
     fn name(&self) -> &'static str;
 
@@ -257,8 +963,8 @@ trait ComponentsRepo {
     ///
     /// Lower values indicate higher priority. Used to determine the order in
     /// which repos are queried. Higher priority repos "win" - if they claim a
-    /// path, lower priority repos are not consulted. We could make the default
-    /// overridable on the CLI in the future.
+    /// path, lower priority repos are not consulted. Can be overridden with
+    /// [`RepoConfig::priority`].
     fn default_priority(&self) -> usize;
 
     /// Query which components claim this path.
@@ -270,6 +976,32 @@ trait ComponentsRepo {
 
     /// Get info about a component by ID.
     fn component_info(&self, id: ComponentId) -> ComponentInfo<'_>;
+
+    /// Returns true if this repo is a fallback, i.e. it claims paths based on
+    /// something other than which component owns them (e.g. their size), so
+    /// that it only makes sense for paths no other repo claims.
+    ///
+    /// Fallback repos don't co-claim paths or conflict with other repos,
+    /// whatever the [`ClaimPolicy`].
+    fn is_fallback(&self) -> bool {
+        false
+    }
+
+    /// Returns the name of a component in the final components map.
+    ///
+    /// By default, component names are namespaced by the repo name.
+    fn full_name(&self, id: ComponentId) -> String {
+        format!("{}/{}", self.name(), self.component_info(id).name)
+    }
+
+    /// Returns the packages a component is made of, in the format usual for
+    /// the package system (e.g. `name-[epoch:]version-release.arch` for rpm).
+    ///
+    /// They're listed in the layer annotations with `--package-annotations`.
+    /// By default, components aren't made of packages.
+    fn component_packages(&self, _id: ComponentId) -> Vec<String> {
+        Vec::new()
+    }
 }
 
 #[cfg(test)]
@@ -284,6 +1016,26 @@ mod tests {
 
     const XATTR_NAME: &str = "user.component";
 
+    #[test]
+    fn test_file_info_extra() {
+        let mut info = FileInfo::test_file(FileType::CharDevice, 0, 0);
+        assert!(info.extra.is_none());
+
+        info.set_rdev(libc::makedev(1, 3));
+        info.set_sha256(Some([1; 32]));
+        info.set_disk_path(Some(Path::new("dev/null").into()));
+        assert_eq!(info.rdev(), libc::makedev(1, 3));
+        assert_eq!(info.sha256(), Some(&[1; 32]));
+        assert_eq!(info.disk_path(), Some(Path::new("dev/null")));
+
+        // clearing all of them frees the side table again
+        info.set_rdev(0);
+        info.set_sha256(None);
+        assert!(info.extra.is_some());
+        info.set_disk_path(None);
+        assert!(info.extra.is_none());
+    }
+
     #[test]
     fn test_into_components() {
         let tmp = tempfile::tempdir().unwrap();
@@ -313,7 +1065,9 @@ mod tests {
 
         let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
 
-        let xattr_repo = xattr::XattrRepo::load(&files, 0).unwrap().unwrap();
+        let xattr_repo = xattr::XattrRepo::load(&files, 0, xattr::DEFAULT_XATTR_NAME)
+            .unwrap()
+            .unwrap();
         let packages = rpm_qa::load_from_str(RPM_FIXTURE).unwrap();
         let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
@@ -325,9 +1079,10 @@ mod tests {
         let loaded = ComponentsRepos {
             repos,
             default_mtime_clamp: 0,
+            config: RepoConfig::default(),
         };
 
-        let components = loaded.into_components(files);
+        let components = loaded.into_components(files).unwrap();
 
         // example xattr overrides rpm entry
         assert!(
@@ -381,14 +1136,17 @@ mod tests {
 
         let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
 
-        let xattr_repo = xattr::XattrRepo::load(&files, 0).unwrap().unwrap();
+        let xattr_repo = xattr::XattrRepo::load(&files, 0, xattr::DEFAULT_XATTR_NAME)
+            .unwrap()
+            .unwrap();
         let repos: Vec<Box<dyn ComponentsRepo>> = vec![Box::new(xattr_repo)];
         let loaded = ComponentsRepos {
             repos,
             default_mtime_clamp: 0,
+            config: RepoConfig::default(),
         };
 
-        let components = loaded.into_components(files);
+        let components = loaded.into_components(files).unwrap();
 
         assert!(components.contains_key("xattr/myapp"));
         assert!(
@@ -397,4 +1155,276 @@ mod tests {
                 .contains_key(Utf8Path::new("/opt/myapp/config"))
         );
     }
+
+    #[test]
+    fn test_previous_claims_merged() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("opt/myapp").unwrap();
+        rootfs.write("opt/myapp/config", "config").unwrap();
+        rootfs
+            .setxattr("opt/myapp/config", XATTR_NAME, b"myapp")
+            .unwrap();
+        rootfs.write("opt/myapp/leftover", "").unwrap();
+
+        let previous_tmp = tempfile::tempdir().unwrap();
+        let previous = previous_tmp.path().join("components.json");
+        std::fs::write(
+            &previous,
+            serde_json::json!({
+                "version": 1,
+                "components": {
+                    "xattr/myapp": {
+                        "stability": 0.5,
+                        "paths": ["/opt/myapp/config", "/opt/myapp/leftover"],
+                    },
+                },
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let xattr_repo = xattr::XattrRepo::load(&files, 0, xattr::DEFAULT_XATTR_NAME)
+            .unwrap()
+            .unwrap();
+        let mut loaded = ComponentsRepos {
+            repos: vec![Box::new(xattr_repo)],
+            default_mtime_clamp: 0,
+            config: RepoConfig::default(),
+        };
+        loaded
+            .load_previous_claims(Utf8Path::from_path(&previous).unwrap())
+            .unwrap();
+        let components = loaded.into_components(files).unwrap();
+
+        // the leftover file goes back in the component it was in before
+        let myapp = &components["xattr/myapp"];
+        assert!(myapp.files.contains_key(Utf8Path::new("/opt/myapp/config")));
+        assert!(
+            myapp
+                .files
+                .contains_key(Utf8Path::new("/opt/myapp/leftover"))
+        );
+        assert!(!components.contains_key("previous/xattr/myapp"));
+    }
+
+    #[test]
+    fn test_components_json() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("opt/myapp").unwrap();
+        rootfs.write("opt/myapp/config", "config").unwrap();
+        rootfs.setxattr("opt/myapp", XATTR_NAME, b"myapp").unwrap();
+        rootfs.write("unowned", "").unwrap();
+
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let xattr_repo = xattr::XattrRepo::load(&files, 0, xattr::DEFAULT_XATTR_NAME)
+            .unwrap()
+            .unwrap();
+        let loaded = ComponentsRepos {
+            repos: vec![Box::new(xattr_repo)],
+            default_mtime_clamp: 0,
+            config: RepoConfig::default(),
+        };
+        let components = loaded.into_components(files).unwrap();
+
+        let json = components_json(&components).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(value["version"], 1);
+        assert_eq!(
+            value["components"]["xattr/myapp"]["paths"],
+            serde_json::json!(["/opt/myapp", "/opt/myapp/config"])
+        );
+        assert_eq!(
+            value["components"][UNCLAIMED_COMPONENT]["paths"],
+            serde_json::json!(["/opt", "/unowned"])
+        );
+    }
+
+    #[test]
+    fn test_repo_config() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("usr/bin").unwrap();
+        rootfs.write("usr/bin/bash", "fake bash").unwrap();
+        rootfs
+            .setxattr("usr/bin/bash", XATTR_NAME, b"stale")
+            .unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+
+        let packages = rpm_qa::load_from_str(RPM_FIXTURE).unwrap();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let rpm_repo = rpm::RpmRepo::load_from_packages(packages, now).unwrap();
+        let xattr_repo = xattr::XattrRepo::load(&files, 0, xattr::DEFAULT_XATTR_NAME)
+            .unwrap()
+            .unwrap();
+        let overrides_dir = tempfile::tempdir().unwrap();
+        let overrides_path = overrides_dir.path().join("overrides.toml");
+        std::fs::write(&overrides_path, "\"rpm/bash\" = 0.01\n").unwrap();
+        let overrides =
+            StabilityOverrides::load(Utf8Path::from_path(&overrides_path).unwrap()).unwrap();
+        let config = RepoConfig::new()
+            .priority("xattr", 20)
+            .priority("typo", 1)
+            .disable("prefix")
+            .stability_overrides(overrides);
+        let mut loaded = ComponentsRepos {
+            repos: vec![Box::new(xattr_repo), Box::new(rpm_repo)],
+            default_mtime_clamp: 0,
+            config,
+        };
+        loaded.register(Box::new(PrefixRepo("/usr")));
+        assert_eq!(loaded.unknown_config_names(), ["typo"]);
+        let components = loaded.into_components(files).unwrap();
+
+        // rpm now beats the stale xattr, and the disabled repo claims nothing
+        assert!(
+            components["rpm/bash"]
+                .files
+                .contains_key(Utf8Path::new("/usr/bin/bash"))
+        );
+        assert!(!components.contains_key("xattr/stale"));
+        assert!(!components.contains_key("prefix/vendor"));
+        assert_eq!(components["rpm/bash"].stability, 0.01);
+    }
+
+    #[test]
+    fn test_claim_policy() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("usr/bin").unwrap();
+        rootfs.write("usr/bin/bash", "fake bash").unwrap();
+        rootfs
+            .setxattr("usr/bin/bash", XATTR_NAME, b"shell")
+            .unwrap();
+        rootfs.create_dir_all("usr/share/doc/bash").unwrap();
+        rootfs
+            .setxattr("usr/share/doc/bash", XATTR_NAME, b"doc")
+            .unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let into_components = |policy| {
+            let packages = rpm_qa::load_from_str(RPM_FIXTURE).unwrap();
+            let rpm_repo = rpm::RpmRepo::load_from_packages(packages, now).unwrap();
+            let xattr_repo = xattr::XattrRepo::load(&files, 0, xattr::DEFAULT_XATTR_NAME)
+                .unwrap()
+                .unwrap();
+            let loaded = ComponentsRepos {
+                repos: vec![Box::new(xattr_repo), Box::new(rpm_repo)],
+                default_mtime_clamp: 0,
+                config: RepoConfig::new().claim_policy(policy),
+            };
+            loaded.into_components(files.clone())
+        };
+        let owns = |components: &HashMap<String, Component>, name: &str, path: &str| {
+            components
+                .get(name)
+                .is_some_and(|c| c.files.contains_key(Utf8Path::new(path)))
+        };
+
+        let components = into_components(ClaimPolicy::FirstWins).unwrap();
+        assert!(owns(&components, "xattr/shell", "/usr/bin/bash"));
+        assert!(!owns(&components, "rpm/bash", "/usr/bin/bash"));
+
+        let components = into_components(ClaimPolicy::Union).unwrap();
+        assert!(owns(&components, "xattr/shell", "/usr/bin/bash"));
+        assert!(owns(&components, "rpm/bash", "/usr/bin/bash"));
+        assert!(owns(&components, "xattr/doc", "/usr/share/doc/bash"));
+        assert!(owns(&components, "rpm/bash", "/usr/share/doc/bash"));
+
+        // directories don't conflict
+        let err = into_components(ClaimPolicy::ErrorOnConflict).unwrap_err();
+        let err = format!("{err:#}");
+        assert!(err.starts_with("1 path(s)"), "{err}");
+        assert!(
+            err.contains("/usr/bin/bash: xattr/shell (xattr) vs rpm/bash (rpm)"),
+            "{err}"
+        );
+    }
+
+    /// A repo like a downstream crate would implement, claiming everything
+    /// under a prefix.
+    struct PrefixRepo(&'static str);
+
+    impl ComponentsRepo for PrefixRepo {
+        fn name(&self) -> &'static str {
+            "prefix"
+        }
+
+        fn default_priority(&self) -> usize {
+            5
+        }
+
+        fn claims_for_path(&self, path: &Utf8Path, _file_type: FileType) -> Vec<ComponentId> {
+            if path.starts_with(self.0) {
+                vec![ComponentId(0)]
+            } else {
+                Vec::new()
+            }
+        }
+
+        fn component_info(&self, _id: ComponentId) -> ComponentInfo<'_> {
+            ComponentInfo {
+                name: "vendor",
+                mtime_clamp: 42,
+                stability: 0.9,
+            }
+        }
+    }
+
+    #[test]
+    fn test_registered_repo() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("opt/vendor").unwrap();
+        rootfs.write("opt/vendor/tool", "tool").unwrap();
+        rootfs.write("opt/other", "other").unwrap();
+        rootfs
+            .setxattr("opt/vendor/tool", XATTR_NAME, b"tool")
+            .unwrap();
+
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let mut loaded = ComponentsRepos::load(&rootfs, &files, 0).unwrap();
+        loaded.register(Box::new(PrefixRepo("/opt/vendor")));
+        let components = loaded.into_components(files).unwrap();
+
+        // xattrs still win since they have a higher priority
+        assert!(
+            components["xattr/tool"]
+                .files
+                .contains_key(Utf8Path::new("/opt/vendor/tool"))
+        );
+        let vendor = &components["prefix/vendor"];
+        assert!(vendor.files.contains_key(Utf8Path::new("/opt/vendor")));
+        assert_eq!(vendor.mtime_clamp, 42);
+        assert_eq!(vendor.stability, 0.9);
+        assert!(
+            components[UNCLAIMED_COMPONENT]
+                .files
+                .contains_key(Utf8Path::new("/opt/other"))
+        );
+    }
+
+    #[test]
+    fn test_xattr_interner() {
+        let mut interner = XattrInterner::default();
+        let cap = || vec![("security.capability".to_string(), b"\x01".to_vec())];
+        let a = interner.intern(cap());
+        let b = interner.intern(cap());
+        assert!(Arc::ptr_eq(&a, &b));
+        assert_eq!(*a, cap());
+        assert!(Arc::ptr_eq(
+            &interner.intern(Vec::new()),
+            &interner.intern(Vec::new())
+        ));
+        assert!(!Arc::ptr_eq(&a, &interner.intern(Vec::new())));
+    }
 }
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use indexmap::IndexSet;
+
+use super::{ComponentId, ComponentInfo, ComponentsRepo, FileMap, FileType};
+
+const REPO_NAME: &str = "models";
+
+/// File extensions of common model weight formats.
+const WEIGHT_EXTENSIONS: &[&str] = &["safetensors", "gguf", "onnx", "pt", "pth", "ckpt"];
+
+/// Minimum size in bytes for a weight file outside of a HuggingFace cache to be
+/// considered a model (1 MB). This avoids claiming e.g. small test fixtures.
+const MIN_SIZE: u64 = 1024 * 1024;
+
+/// Prefix of model directories in the HuggingFace hub cache, e.g.
+/// `hub/models--org--name/`.
+const HF_MODEL_DIR_PREFIX: &str = "models--";
+
+/// Model weights essentially never change once published; new versions of a
+/// model are new files. Use a very high stability so they don't get merged
+/// with volatile content.
+const MODEL_STABILITY: f64 = 0.99;
+
+/// AI/ML models components repo implementation.
+///
+/// Claims model weights into one component per model. Models in a HuggingFace
+/// hub cache (`models--<org>--<name>/`) are claimed as a whole, including the
+/// blobs, snapshots and refs. Elsewhere, weight files are identified by their
+/// extension and grouped by the directory containing them.
+pub struct ModelsRepo {
+    /// Component (model) names, indexed by ComponentId.
+    components: IndexSet<String>,
+    /// Mapping from path to ComponentId.
+    path_to_component: HashMap<Utf8PathBuf, ComponentId>,
+    /// Default mtime clamp for components.
+    default_mtime_clamp: u64,
+}
+
+impl ModelsRepo {
+    /// Load models repo by scanning `files` for model directories and weights.
+    ///
+    /// Returns None if no models are found.
+    pub fn load(files: &FileMap, default_mtime_clamp: u64) -> Option<Self> {
+        let mut components: IndexSet<String> = IndexSet::new();
+        let mut path_to_component: HashMap<Utf8PathBuf, ComponentId> = HashMap::new();
+
+        for (path, file_info) in files {
+            let component_name = if let Some(name) = hf_model_name(path) {
+                name
+            } else if file_info.file_type == FileType::File
+                && file_info.size >= MIN_SIZE
+                && is_weight_file(path)
+            {
+                let parent = path.parent().expect("file path has no parent");
+                parent
+                    .strip_prefix("/")
+                    .expect("non-absolute file path in FileMap")
+                    .as_str()
+                    .to_string()
+            } else {
+                continue;
+            };
+
+            let (idx, _) = components.insert_full(component_name);
+            path_to_component.insert(path.clone(), ComponentId(idx));
+        }
+
+        if components.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            components,
+            path_to_component,
+            default_mtime_clamp,
+        })
+    }
+}
+
+impl ComponentsRepo for ModelsRepo {
+    fn name(&self) -> &'static str {
+        REPO_NAME
+    }
+
+    fn default_priority(&self) -> usize {
+        // After package managers (models shipped in packages stay with them),
+        // but before bigfiles, which would otherwise claim weights one by one.
+        30
+    }
+
+    fn claims_for_path(&self, path: &Utf8Path, _file_type: FileType) -> Vec<ComponentId> {
+        self.path_to_component
+            .get(path)
+            .map(|id| vec![*id])
+            .unwrap_or_default()
+    }
+
+    fn component_info(&self, id: ComponentId) -> ComponentInfo<'_> {
+        ComponentInfo {
+            name: self
+                .components
+                .get_index(id.0)
+                // SAFETY: the ids we're given come from the IndexSet itself
+                // when we inserted the element, so it must be valid.
+                .expect("invalid ComponentId"),
+            mtime_clamp: self.default_mtime_clamp,
+            stability: MODEL_STABILITY,
+        }
+    }
+}
+
+/// If `path` is in (or is) a HuggingFace hub cache model directory, return
+/// the model name as `hf/<org>/<name>`.
+fn hf_model_name(path: &Utf8Path) -> Option<String> {
+    let model_dir = path
+        .components()
+        .find_map(|c| c.as_str().strip_prefix(HF_MODEL_DIR_PREFIX))?;
+    let (org, name) = model_dir.split_once("--")?;
+    if org.is_empty() || name.is_empty() {
+        return None;
+    }
+    Some(format!("hf/{org}/{name}"))
+}
+
+/// Returns true if the path has a known model weights extension.
+fn is_weight_file(path: &Utf8Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| WEIGHT_EXTENSIONS.contains(&ext))
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std::ambient_authority;
+    use cap_std_ext::cap_std::fs::Dir;
+
+    use super::*;
+    use crate::components::claim_names;
+
+    #[test]
+    fn test_hf_model_name() {
+        assert_eq!(
+            hf_model_name(Utf8Path::new(
+                "/root/.cache/huggingface/hub/models--ibm-granite--granite-3.0-2b/blobs/abc"
+            ))
+            .as_deref(),
+            Some("hf/ibm-granite/granite-3.0-2b")
+        );
+        assert_eq!(
+            hf_model_name(Utf8Path::new("/hub/models--org--name")).as_deref(),
+            Some("hf/org/name")
+        );
+        assert_eq!(hf_model_name(Utf8Path::new("/srv/models--foo")), None);
+        assert_eq!(hf_model_name(Utf8Path::new("/usr/lib/models")), None);
+    }
+
+    #[test]
+    fn test_models_claims() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        let hf = "cache/hub/models--org--model";
+        rootfs.create_dir_all(format!("{hf}/blobs")).unwrap();
+        rootfs
+            .create_dir_all(format!("{hf}/snapshots/rev"))
+            .unwrap();
+        rootfs.write(format!("{hf}/blobs/abc"), "weights").unwrap();
+        rootfs
+            .symlink("../../blobs/abc", format!("{hf}/snapshots/rev/model.bin"))
+            .unwrap();
+
+        rootfs.create_dir_all("srv/llama").unwrap();
+        let file = rootfs.create("srv/llama/model.gguf").unwrap();
+        file.set_len(MIN_SIZE).unwrap();
+        rootfs.write("srv/llama/README.md", "").unwrap();
+        rootfs.create_dir_all("app/tests").unwrap();
+        rootfs.write("app/tests/tiny.onnx", "").unwrap();
+
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let repo = ModelsRepo::load(&files, 0).unwrap();
+
+        for path in [
+            "/cache/hub/models--org--model",
+            "/cache/hub/models--org--model/blobs/abc",
+            "/cache/hub/models--org--model/snapshots/rev/model.bin",
+        ] {
+            assert_eq!(claim_names(&repo, path), ["hf/org/model"], "{path}");
+        }
+        assert_eq!(claim_names(&repo, "/srv/llama/model.gguf"), ["srv/llama"]);
+
+        assert!(claim_names(&repo, "/cache/hub").is_empty());
+        assert!(claim_names(&repo, "/srv/llama/README.md").is_empty());
+        assert!(claim_names(&repo, "/app/tests/tiny.onnx").is_empty());
+
+        let claims = repo.claims_for_path(Utf8Path::new("/srv/llama/model.gguf"), FileType::File);
+        assert_eq!(repo.component_info(claims[0]).stability, MODEL_STABILITY);
+    }
+}
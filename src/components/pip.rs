@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::Dir;
+use indexmap::IndexSet;
+
+use crate::utils::{canonicalize_parent_path, normalize_path};
+
+use super::{ComponentId, ComponentInfo, ComponentsRepo, FileMap, FileType};
+
+const REPO_NAME: &str = "pip";
+
+/// Names of directories Python packages get installed into.
+const SITE_DIRS: &[&str] = &["site-packages", "dist-packages"];
+
+/// Python packages components repo implementation.
+///
+/// Finds installed Python distributions via their `*.dist-info/RECORD` files
+/// and claims the files listed in them, as well as the directories containing
+/// them inside the site directory. This covers e.g. pip-installed venvs.
+pub struct PipRepo {
+    /// Component (distribution) names, indexed by ComponentId.
+    components: IndexSet<String>,
+
+    /// Mapping from path to list of ComponentId.
+    ///
+    /// Directories may be shared by multiple distributions (e.g. namespace
+    /// packages).
+    path_to_components: HashMap<Utf8PathBuf, Vec<ComponentId>>,
+
+    /// RECORD files don't carry timestamps, so we use the default mtime clamp.
+    default_mtime_clamp: u64,
+}
+
+impl PipRepo {
+    /// Load Python distributions by looking for RECORD files in `files`.
+    ///
+    /// Returns `Ok(None)` if no distributions are found.
+    pub fn load(rootfs: &Dir, files: &FileMap, default_mtime_clamp: u64) -> Result<Option<Self>> {
+        let mut components: IndexSet<String> = IndexSet::new();
+        let mut path_to_components: HashMap<Utf8PathBuf, Vec<ComponentId>> = HashMap::new();
+        let mut cache = HashMap::new();
+
+        for (path, file_info) in files {
+            if file_info.file_type != FileType::File {
+                continue;
+            }
+            let Some((site_dir, dist_name)) = parse_record_path(path) else {
+                continue;
+            };
+
+            let record = rootfs
+                .read_to_string(path.strip_prefix("/").unwrap_or(path))
+                .with_context(|| format!("reading {path}"))?;
+
+            let (idx, _) = components.insert_full(dist_name.to_string());
+            let component_id = ComponentId(idx);
+
+            for line in record.lines() {
+                let Some(rel) = parse_record_line(line) else {
+                    continue;
+                };
+                let full = normalize_path(&site_dir.join(&rel))
+                    .with_context(|| format!("normalizing {rel} from {path}"))?;
+                let canonical = canonicalize_parent_path(rootfs, files, &full, &mut cache)
+                    .with_context(|| format!("canonicalizing {full}"))?;
+
+                // also claim the package directories inside the site dir
+                // (e.g. site-packages/foo/ and site-packages/foo/sub/)
+                let dirs = canonical
+                    .ancestors()
+                    .skip(1)
+                    .take_while(|p| p.starts_with(site_dir) && *p != site_dir);
+                for p in std::iter::once(canonical.as_path()).chain(dirs) {
+                    let entries = path_to_components.entry(p.to_owned()).or_default();
+                    if !entries.contains(&component_id) {
+                        entries.push(component_id);
+                    }
+                }
+            }
+        }
+
+        if components.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            components,
+            path_to_components,
+            default_mtime_clamp,
+        }))
+    }
+}
+
+impl ComponentsRepo for PipRepo {
+    fn name(&self) -> &'static str {
+        REPO_NAME
+    }
+
+    fn default_priority(&self) -> usize {
+        // Lower priority than system package managers; Python packages shipped
+        // by the distro also have RECORD files but should stay with the package
+        // that owns them.
+        20
+    }
+
+    fn claims_for_path(&self, path: &Utf8Path, _file_type: FileType) -> Vec<ComponentId> {
+        self.path_to_components
+            .get(path)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn component_info(&self, id: ComponentId) -> ComponentInfo<'_> {
+        ComponentInfo {
+            name: self
+                .components
+                .get_index(id.0)
+                // SAFETY: the ids we're given come from the IndexSet itself
+                // when we inserted the element, so it must be valid.
+                .expect("invalid ComponentId"),
+            mtime_clamp: self.default_mtime_clamp,
+            stability: 0.0,
+        }
+    }
+}
+
+/// If `path` is a `<site>/<name>-<version>.dist-info/RECORD` file, return the
+/// site directory and the distribution name.
+fn parse_record_path(path: &Utf8Path) -> Option<(&Utf8Path, &str)> {
+    if path.file_name() != Some("RECORD") {
+        return None;
+    }
+    let dist_info = path.parent()?;
+    let site_dir = dist_info.parent()?;
+    if !SITE_DIRS.contains(&site_dir.file_name()?) {
+        return None;
+    }
+    let stem = dist_info.file_name()?.strip_suffix(".dist-info")?;
+    // names are normalized to not contain dashes in dist-info dir names
+    let (name, _version) = stem.split_once('-')?;
+    Some((site_dir, name))
+}
+
+/// Parse the path out of a RECORD CSV line (`path,hash,size`).
+///
+/// The path is quoted (with doubled quotes as escapes) if it contains a comma
+/// or quote. Returns `None` for empty lines.
+fn parse_record_line(line: &str) -> Option<String> {
+    let line = line.trim_end_matches('\r');
+    if line.is_empty() {
+        return None;
+    }
+    if let Some(rest) = line.strip_prefix('"') {
+        let mut path = String::new();
+        let mut chars = rest.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == '"' {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            path.push(c);
+        }
+        Some(path)
+    } else {
+        line.split(',').next().map(|s| s.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std::ambient_authority;
+
+    use super::*;
+    use crate::components::claim_names;
+
+    const SITE: &str = "opt/venv/lib/python3.12/site-packages";
+
+    #[test]
+    fn test_parse_record_line() {
+        assert_eq!(
+            parse_record_line("foo/__init__.py,sha256=abc,123").as_deref(),
+            Some("foo/__init__.py")
+        );
+        assert_eq!(
+            parse_record_line("foo.dist-info/RECORD,,").as_deref(),
+            Some("foo.dist-info/RECORD")
+        );
+        assert_eq!(
+            parse_record_line(r#""weird,""name"".py",sha256=abc,1"#).as_deref(),
+            Some(r#"weird,"name".py"#)
+        );
+        assert_eq!(parse_record_line(""), None);
+    }
+
+    #[test]
+    fn test_parse_record_path() {
+        assert_eq!(
+            parse_record_path(Utf8Path::new(
+                "/usr/lib/python3/dist-packages/requests-2.31.0.dist-info/RECORD"
+            )),
+            Some((Utf8Path::new("/usr/lib/python3/dist-packages"), "requests"))
+        );
+        assert_eq!(
+            parse_record_path(Utf8Path::new("/srv/foo-1.0.dist-info/RECORD")),
+            None
+        );
+        assert_eq!(
+            parse_record_path(Utf8Path::new(
+                "/usr/lib/python3/site-packages/foo-1.0.dist-info/METADATA"
+            )),
+            None
+        );
+    }
+
+    #[test]
+    fn test_pip_claims() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all(format!("{SITE}/foo/sub")).unwrap();
+        rootfs
+            .create_dir_all(format!("{SITE}/foo_bar-1.2.3.dist-info"))
+            .unwrap();
+        rootfs.create_dir_all("opt/venv/bin").unwrap();
+        rootfs.write(format!("{SITE}/foo/__init__.py"), "").unwrap();
+        rootfs.write(format!("{SITE}/foo/sub/mod.py"), "").unwrap();
+        rootfs.write(format!("{SITE}/unowned.py"), "").unwrap();
+        rootfs.write("opt/venv/bin/foo", "").unwrap();
+        rootfs
+            .write(
+                format!("{SITE}/foo_bar-1.2.3.dist-info/RECORD"),
+                "foo/__init__.py,sha256=x,0\n\
+                 foo/sub/mod.py,sha256=x,0\n\
+                 ../../../bin/foo,sha256=x,0\n\
+                 foo_bar-1.2.3.dist-info/RECORD,,\n",
+            )
+            .unwrap();
+
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let repo = PipRepo::load(&rootfs, &files, 42).unwrap().unwrap();
+
+        let site = format!("/{SITE}");
+        for path in [
+            format!("{site}/foo"),
+            format!("{site}/foo/__init__.py"),
+            format!("{site}/foo/sub"),
+            format!("{site}/foo/sub/mod.py"),
+            format!("{site}/foo_bar-1.2.3.dist-info"),
+            format!("{site}/foo_bar-1.2.3.dist-info/RECORD"),
+            "/opt/venv/bin/foo".to_string(),
+        ] {
+            assert_eq!(claim_names(&repo, &path), ["foo_bar"], "{path}");
+        }
+
+        // the site dir itself and files not in RECORD aren't claimed
+        assert!(claim_names(&repo, &site).is_empty());
+        assert!(claim_names(&repo, &format!("{site}/unowned.py")).is_empty());
+        assert!(claim_names(&repo, "/opt/venv/bin").is_empty());
+    }
+}
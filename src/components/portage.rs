@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use indexmap::IndexMap;
+
+use crate::utils::{calculate_stability, canonicalize_parent_path};
+
+use super::{ComponentId, ComponentInfo, ComponentsRepo, FileMap, FileType};
+
+const REPO_NAME: &str = "portage";
+
+const VDB_PATH: &str = "var/db/pkg";
+
+/// Portage-based (Gentoo) components repo implementation.
+///
+/// Uses the installed package database (VDB) to determine file ownership and
+/// groups files by package name (i.e. all slots of a package go together).
+pub struct PortageRepo {
+    /// Unique component (category/package) names mapped to (buildtime,
+    /// stability), indexed by ComponentId.
+    components: IndexMap<String, (u64, f64)>,
+
+    /// Mapping from path to list of ComponentId.
+    ///
+    /// Directories are commonly owned by more than one component.
+    path_to_components: HashMap<Utf8PathBuf, Vec<ComponentId>>,
+}
+
+impl PortageRepo {
+    /// Load the Portage VDB from the given rootfs. The `files` parameter is
+    /// used to canonicalize paths from the VDB.
+    ///
+    /// Returns `Ok(None)` if no VDB is detected.
+    pub fn load(rootfs: &Dir, files: &FileMap, now: u64) -> Result<Option<Self>> {
+        let Some(vdb) = rootfs
+            .open_dir_optional(VDB_PATH)
+            .with_context(|| format!("opening {VDB_PATH}"))?
+        else {
+            return Ok(None);
+        };
+
+        let mut components: IndexMap<String, (u64, f64)> = IndexMap::new();
+        let mut path_to_components: HashMap<Utf8PathBuf, Vec<ComponentId>> = HashMap::new();
+        let mut cache = HashMap::new();
+
+        for (category, pf) in list_packages(&vdb)? {
+            let pkg_dir = vdb
+                .open_dir(format!("{category}/{pf}"))
+                .with_context(|| format!("opening {category}/{pf}"))?;
+            let Some(contents) = pkg_dir
+                .read_to_string_optional("CONTENTS")
+                .with_context(|| format!("reading CONTENTS of {category}/{pf}"))?
+            else {
+                continue;
+            };
+            let buildtime = match pkg_dir
+                .read_to_string_optional("BUILD_TIME")
+                .with_context(|| format!("reading BUILD_TIME of {category}/{pf}"))?
+            {
+                Some(s) => s
+                    .trim()
+                    .parse::<u64>()
+                    .with_context(|| format!("parsing BUILD_TIME of {category}/{pf}"))?,
+                // shouldn't happen, but be conservative
+                None => now,
+            };
+
+            let stability = calculate_stability(&[], buildtime, now)?;
+            let name = format!("{category}/{}", package_name(&pf));
+            let entry = components.entry(name);
+            let component_id = ComponentId(entry.index());
+            match entry {
+                indexmap::map::Entry::Occupied(mut e) => {
+                    // Multiple slots of the same package; same logic as for
+                    // subpackages.
+                    let (existing_buildtime, existing_stability) = e.get_mut();
+                    *existing_buildtime = (*existing_buildtime).max(buildtime);
+                    *existing_stability = existing_stability.min(stability);
+                }
+                indexmap::map::Entry::Vacant(e) => {
+                    e.insert((buildtime, stability));
+                }
+            }
+
+            for line in contents.lines() {
+                let Some(path) = parse_contents_line(line) else {
+                    continue;
+                };
+                let canonical = canonicalize_parent_path(rootfs, files, path, &mut cache)
+                    .with_context(|| format!("canonicalizing {}", path))?;
+                let entries = path_to_components.entry(canonical).or_default();
+                if !entries.contains(&component_id) {
+                    entries.push(component_id);
+                }
+            }
+        }
+
+        if components.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            components,
+            path_to_components,
+        }))
+    }
+}
+
+impl ComponentsRepo for PortageRepo {
+    fn name(&self) -> &'static str {
+        REPO_NAME
+    }
+
+    fn default_priority(&self) -> usize {
+        10
+    }
+
+    fn claims_for_path(&self, path: &Utf8Path, _file_type: FileType) -> Vec<ComponentId> {
+        self.path_to_components
+            .get(path)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn component_info(&self, id: ComponentId) -> ComponentInfo<'_> {
+        let (name, (buildtime, stability)) = self
+            .components
+            .get_index(id.0)
+            // SAFETY: the ids we're given come from the IndexMap itself when we
+            // inserted the element, so it must be valid.
+            .expect("invalid ComponentId");
+        ComponentInfo {
+            name,
+            mtime_clamp: *buildtime,
+            stability: *stability,
+        }
+    }
+}
+
+/// List the installed packages as sorted (category, PF) pairs, where PF is
+/// the package name with version and revision.
+fn list_packages(vdb: &Dir) -> Result<Vec<(String, String)>> {
+    let mut packages = Vec::new();
+    for category in vdb.entries().context("listing VDB")? {
+        let category = category.context("reading VDB entry")?;
+        if !category.file_type()?.is_dir() {
+            continue;
+        }
+        let category_name = category.file_name().into_string().map_err(|name| {
+            anyhow::anyhow!("non-UTF-8 category name in VDB: {}", name.to_string_lossy())
+        })?;
+        let category_dir = category.open_dir().context("opening category")?;
+        for pkg in category_dir
+            .entries()
+            .with_context(|| format!("listing {category_name}"))?
+        {
+            let pkg = pkg.context("reading category entry")?;
+            if !pkg.file_type()?.is_dir() {
+                continue;
+            }
+            let pf = pkg.file_name().into_string().map_err(|name| {
+                anyhow::anyhow!("non-UTF-8 package name in VDB: {}", name.to_string_lossy())
+            })?;
+            // skip in-progress merges (e.g. "-MERGING-foo-1.0")
+            if pf.starts_with('-') {
+                continue;
+            }
+            packages.push((category_name.clone(), pf));
+        }
+    }
+    packages.sort();
+    Ok(packages)
+}
+
+/// Strip the version and revision from a PF (e.g. `gcc-13.2.1_p20240210-r1`),
+/// returning the package name (e.g. `gcc`).
+///
+/// Versions can't contain dashes apart from the `-rN` revision suffix, while
+/// package names can, so we split on the last dash after stripping that.
+fn package_name(pf: &str) -> &str {
+    let without_rev = match pf.rsplit_once("-r") {
+        Some((rest, rev)) if !rev.is_empty() && rev.bytes().all(|b| b.is_ascii_digit()) => rest,
+        _ => pf,
+    };
+    without_rev
+        .rsplit_once('-')
+        .map(|(name, _version)| name)
+        .unwrap_or(pf)
+}
+
+/// Parse the path out of a CONTENTS line.
+///
+/// Lines are of the form `dir <path>`, `obj <path> <md5> <mtime>` or
+/// `sym <path> -> <target> <mtime>`. Paths may contain spaces, so the trailing
+/// fields are stripped from the right.
+fn parse_contents_line(line: &str) -> Option<&Utf8Path> {
+    let (kind, rest) = line.split_once(' ')?;
+    let path = match kind {
+        "dir" => rest,
+        "obj" => {
+            let (rest, _mtime) = rest.rsplit_once(' ')?;
+            let (path, _md5) = rest.rsplit_once(' ')?;
+            path
+        }
+        "sym" => rest.split_once(" -> ")?.0,
+        // fifos and devices; we don't claim those
+        _ => return None,
+    };
+    let path = Utf8Path::new(path);
+    path.is_absolute().then_some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std::ambient_authority;
+
+    use super::*;
+    use crate::components::claim_names;
+
+    #[test]
+    fn test_package_name() {
+        assert_eq!(package_name("gcc-13.2.1_p20240210-r1"), "gcc");
+        assert_eq!(package_name("font-adobe-100dpi-1.0.4"), "font-adobe-100dpi");
+        assert_eq!(package_name("python-3.12.3"), "python");
+        assert_eq!(package_name("go-md2man-2.0.3-r2"), "go-md2man");
+    }
+
+    #[test]
+    fn test_parse_contents_line() {
+        assert_eq!(parse_contents_line("dir /usr/bin"), Some("/usr/bin".into()));
+        assert_eq!(
+            parse_contents_line("obj /usr/share/doc/my file.txt 0123abcd 1700000000"),
+            Some("/usr/share/doc/my file.txt".into())
+        );
+        assert_eq!(
+            parse_contents_line("sym /usr/lib/libfoo.so -> libfoo.so.1 1700000000"),
+            Some("/usr/lib/libfoo.so".into())
+        );
+        assert_eq!(parse_contents_line("fif /run/foo"), None);
+        assert_eq!(parse_contents_line(""), None);
+    }
+
+    #[test]
+    fn test_portage_claims() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        for (pkg, buildtime, contents) in [
+            (
+                "sys-libs/glibc-2.39-r6",
+                "1714000000",
+                "dir /usr\ndir /usr/lib64\nobj /usr/lib64/libc.so.6 abc 1714000000\n",
+            ),
+            (
+                "dev-lang/python-3.11.9",
+                "1714000000",
+                "dir /usr\ndir /usr/bin\nobj /usr/bin/python3.11 abc 1714000000\n",
+            ),
+            (
+                "dev-lang/python-3.12.3",
+                "1715000000",
+                "dir /usr\ndir /usr/bin\nobj /usr/bin/python3.12 abc 1715000000\n\
+                 sym /usr/bin/python3 -> python3.12 1715000000\n",
+            ),
+        ] {
+            let dir = format!("{VDB_PATH}/{pkg}");
+            rootfs.create_dir_all(&dir).unwrap();
+            rootfs.write(format!("{dir}/CONTENTS"), contents).unwrap();
+            rootfs
+                .write(format!("{dir}/BUILD_TIME"), format!("{buildtime}\n"))
+                .unwrap();
+        }
+
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let repo = PortageRepo::load(&rootfs, &files, 1715000000 + 86400)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            claim_names(&repo, "/usr/lib64/libc.so.6"),
+            ["sys-libs/glibc"]
+        );
+        // slots are grouped together
+        assert_eq!(
+            claim_names(&repo, "/usr/bin/python3.11"),
+            ["dev-lang/python"]
+        );
+        assert_eq!(claim_names(&repo, "/usr/bin/python3"), ["dev-lang/python"]);
+
+        let mut names = claim_names(&repo, "/usr");
+        names.sort();
+        assert_eq!(names, ["dev-lang/python", "sys-libs/glibc"]);
+
+        // the clamp is the max build time of all slots
+        let claims = repo.claims_for_path(Utf8Path::new("/usr/bin/python3.11"), FileType::File);
+        assert_eq!(repo.component_info(claims[0]).mtime_clamp, 1715000000);
+    }
+
+    #[test]
+    fn test_portage_not_detected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        assert!(PortageRepo::load(&rootfs, &files, 0).unwrap().is_none());
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::ambient_authority;
+use cap_std_ext::cap_std::fs::Dir;
+use indexmap::IndexMap;
+use serde::Deserialize;
+
+use super::{ComponentId, ComponentInfo, ComponentsRepo, FileType};
+
+const REPO_NAME: &str = "previous";
+
+/// The only supported version of the components JSON.
+const COMPONENTS_JSON_VERSION: u32 = 1;
+
+/// Previous build components repo implementation.
+///
+/// Uses the component to path mapping of a previous build (i.e. the components
+/// JSON written by `--embed-components`) so that paths which no other repo
+/// claims anymore (e.g. files left behind by a package that was removed from
+/// the database, or new files added in a directory that was claimed before)
+/// stay with their historical component instead of ending up unclaimed. Unlike
+/// other repos, component names aren't namespaced since they're already full
+/// names, so files get merged back into the same component.
+pub struct PreviousRepo {
+    /// Component names mapped to their stability, indexed by ComponentId.
+    components: IndexMap<String, f64>,
+    /// Mapping from path to ComponentId.
+    path_to_component: HashMap<Utf8PathBuf, ComponentId>,
+    /// Mtime clamp for all components; the previous build doesn't record it.
+    mtime_clamp: u64,
+}
+
+#[derive(Deserialize)]
+struct Document {
+    version: u32,
+    components: BTreeMap<String, ComponentEntry>,
+}
+
+#[derive(Deserialize)]
+struct ComponentEntry {
+    stability: f64,
+    paths: Vec<Utf8PathBuf>,
+}
+
+impl PreviousRepo {
+    /// Load the components JSON at `path`, or embedded in the image in the OCI
+    /// image layout at `path` if it's a directory.
+    ///
+    /// Returns `Ok(None)` if the previous build has no components to reuse.
+    pub fn load(path: &Utf8Path, mtime_clamp: u64) -> Result<Option<Self>> {
+        let content = if path.is_dir() {
+            read_embedded_components_json(path)?
+        } else {
+            std::fs::read(path).with_context(|| format!("reading {path}"))?
+        };
+        Self::parse(&content, mtime_clamp)
+            .with_context(|| format!("parsing components from {path}"))
+    }
+
+    fn parse(content: &[u8], mtime_clamp: u64) -> Result<Option<Self>> {
+        let doc: Document = serde_json::from_slice(content).context("parsing JSON")?;
+        anyhow::ensure!(
+            doc.version == COMPONENTS_JSON_VERSION,
+            "unsupported components JSON version {}",
+            doc.version
+        );
+
+        let mut components = IndexMap::new();
+        let mut path_to_component = HashMap::new();
+        for (name, entry) in doc.components {
+            // chunkah's own components (e.g. unclaimed) aren't worth keeping
+            if name.starts_with("chunkah/") {
+                continue;
+            }
+            let (idx, _) = components.insert_full(name, entry.stability);
+            for path in entry.paths {
+                // if a path was in multiple components (shared directories),
+                // just keep the first one
+                path_to_component.entry(path).or_insert(ComponentId(idx));
+            }
+        }
+
+        if components.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            components,
+            path_to_component,
+            mtime_clamp,
+        }))
+    }
+}
+
+impl ComponentsRepo for PreviousRepo {
+    fn name(&self) -> &'static str {
+        REPO_NAME
+    }
+
+    fn default_priority(&self) -> usize {
+        // After all the repos that know about actual software, so it only
+        // catches what they don't claim anymore, but before the original
+        // layers and bigfiles.
+        40
+    }
+
+    fn is_fallback(&self) -> bool {
+        // it only catches what other repos don't claim anymore
+        true
+    }
+
+    fn claims_for_path(&self, path: &Utf8Path, _file_type: FileType) -> Vec<ComponentId> {
+        self.path_to_component
+            .get(path)
+            .map(|id| vec![*id])
+            .unwrap_or_default()
+    }
+
+    fn component_info(&self, id: ComponentId) -> ComponentInfo<'_> {
+        let (name, stability) = self
+            .components
+            .get_index(id.0)
+            // SAFETY: the ids we're given come from the IndexMap itself when we
+            // inserted the element, so it must be valid.
+            .expect("invalid ComponentId");
+        ComponentInfo {
+            name,
+            mtime_clamp: self.mtime_clamp,
+            stability: *stability,
+        }
+    }
+
+    fn full_name(&self, id: ComponentId) -> String {
+        self.component_info(id).name.to_string()
+    }
+}
+
+/// Read the components JSON embedded in the image in the OCI image layout at
+/// `path`.
+fn read_embedded_components_json(path: &Utf8Path) -> Result<Vec<u8>> {
+    let dir = Dir::open_ambient_dir(path, ambient_authority())
+        .with_context(|| format!("opening {path}"))?;
+    let oci_dir = ocidir::OciDir::open(dir).context("opening OCI image layout")?;
+
+    let (_, manifest) = crate::utils::read_image_manifest(&oci_dir)?;
+
+    let wanted = crate::ocibuilder::COMPONENTS_JSON_PATH.trim_start_matches('/');
+    // the metadata layer is last, so start from the top
+    for layer in manifest.layers().iter().rev() {
+        let reader = crate::tar::read_layer(&oci_dir, layer)
+            .with_context(|| format!("opening layer {}", layer.digest()))?;
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries().context("reading entries")? {
+            let mut entry = entry.context("reading entry")?;
+            if entry.path_bytes().as_ref() != wanted.as_bytes() {
+                continue;
+            }
+            let mut content = Vec::new();
+            entry
+                .read_to_end(&mut content)
+                .with_context(|| format!("reading {wanted}"))?;
+            return Ok(content);
+        }
+    }
+    anyhow::bail!(
+        "image has no {}; was it built with --embed-components?",
+        crate::ocibuilder::COMPONENTS_JSON_PATH
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_previous_claims() {
+        let json = serde_json::json!({
+            "version": 1,
+            "components": {
+                "rpm/bash": {
+                    "stability": 0.9,
+                    "paths": ["/usr", "/usr/bin", "/usr/bin/bash"],
+                },
+                "rpm/coreutils": {
+                    "stability": 0.8,
+                    "paths": ["/usr", "/usr/bin", "/usr/bin/ls"],
+                },
+                "chunkah/unclaimed": {
+                    "stability": 0.0,
+                    "paths": ["/etc/hostname"],
+                },
+            },
+        });
+        let repo = PreviousRepo::parse(json.to_string().as_bytes(), 42)
+            .unwrap()
+            .unwrap();
+
+        let claim = |path: &str| {
+            repo.claims_for_path(Utf8Path::new(path), FileType::File)
+                .into_iter()
+                .map(|id| repo.full_name(id))
+                .collect::<Vec<_>>()
+        };
+        // names aren't namespaced
+        assert_eq!(claim("/usr/bin/bash"), ["rpm/bash"]);
+        assert_eq!(claim("/usr/bin/ls"), ["rpm/coreutils"]);
+        assert_eq!(claim("/usr/bin"), ["rpm/bash"]);
+        assert!(claim("/etc/hostname").is_empty());
+        assert!(claim("/usr/bin/new").is_empty());
+
+        let claims = repo.claims_for_path(Utf8Path::new("/usr/bin/ls"), FileType::File);
+        let info = repo.component_info(claims[0]);
+        assert_eq!(info.stability, 0.8);
+        assert_eq!(info.mtime_clamp, 42);
+    }
+
+    #[test]
+    fn test_previous_unsupported_version() {
+        let json = br#"{"version": 2, "components": {}}"#;
+        assert!(PreviousRepo::parse(json, 0).is_err());
+        let json = br#"{"version": 1, "components": {}}"#;
+        assert!(PreviousRepo::parse(json, 0).unwrap().is_none());
+    }
+}
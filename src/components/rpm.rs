@@ -1,96 +1,240 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::Read;
 
 use anyhow::{Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
 use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
 use indexmap::IndexMap;
-use rpm_qa::FileInfo;
+use openssl::hash::{Hasher, MessageDigest};
+use serde::{Deserialize, Serialize};
 
 use crate::utils::{calculate_stability, canonicalize_parent_path};
 
-use super::{ComponentId, ComponentInfo, ComponentsRepo, FileType};
+use super::cache::CacheKey;
+use super::{
+    ComponentId, ComponentInfo, ComponentsRepo, FileType, RepoConfig, RpmConfigFiles, RpmGroupBy,
+};
 
 const REPO_NAME: &str = "rpm";
 
 const RPMDB_PATHS: &[&str] = &["usr/lib/sysimage/rpm", "usr/share/rpm", "var/lib/rpm"];
 
+/// Component for the config files of all packages with [`RpmConfigFiles::Shared`].
+const SHARED_CONFIG_COMPONENT: &str = "config";
+
+/// Component for files which don't match the rpmdb with `verify`.
+const MODIFIED_COMPONENT: &str = "modified";
+
 /// RPM-based components repo implementation.
 ///
 /// Uses the RPM database to determine file ownership and groups files
-/// by their SRPM.
+/// by their SRPM, or by their package with [`RpmGroupBy::Package`].
+///
+/// `%config(noreplace)` files are commonly modified after installation, so
+/// with [`RpmConfigFiles::Split`] or [`RpmConfigFiles::Shared`] they go into
+/// separate components with a stability of 0, which keeps them from
+/// invalidating the layers with the rest of the package.
+///
+/// When verifying, files whose size or digest doesn't match the rpmdb go into
+/// a single `modified` component instead, so that files changed after
+/// installation don't get the stability and mtime clamp of their package.
 pub struct RpmRepo {
-    /// Unique component (SRPM) names mapped to (buildtime, stability), indexed by ComponentId.
+    /// Unique component (SRPM or package) names mapped to (buildtime, stability), indexed by ComponentId.
     components: IndexMap<String, (u64, f64)>,
 
-    /// Mapping from path to list of (ComponentId, FileInfo).
+    /// Mapping from path to list of (ComponentId, file type).
     ///
     /// It's common for directories to be owned by more than one component (i.e.
     /// from _different_ SRPMs). It's much more uncommon for files/symlinks
     /// though we do handle it to ensure reproducible layers.
-    path_to_components: HashMap<Utf8PathBuf, Vec<(ComponentId, FileInfo)>>,
+    path_to_components: HashMap<Utf8PathBuf, Vec<(ComponentId, Option<FileType>)>>,
+
+    /// NEVRAs of the packages with files in each component, indexed by
+    /// ComponentId.
+    packages: Vec<BTreeSet<String>>,
+}
+
+/// The parts of an RPM package header we need, in a form that can be cached.
+#[derive(Serialize, Deserialize)]
+struct Package {
+    name: String,
+    /// `name-[epoch:]version-release.arch`
+    nevra: String,
+    sourcerpm: Option<String>,
+    buildtime: u64,
+    changelog_times: Vec<u64>,
+    files: Vec<PackageFile>,
+}
+
+/// A file of an RPM package.
+#[derive(Serialize, Deserialize)]
+struct PackageFile {
+    path: Utf8PathBuf,
+    /// `None` for unsupported types.
+    file_type: Option<FileType>,
+    /// Whether it's a `%config(noreplace)` file.
+    noreplace_config: bool,
+    size: u64,
+    /// Hex digest of the content, for regular files in the payload.
+    digest: Option<String>,
 }
 
 impl RpmRepo {
     /// Load the RPM database from the given rootfs. The `files` parameter is
     /// used to canonicalize paths from the RPM database.
     ///
-    /// Returns `Ok(None)` if no RPM database is detected.
-    pub fn load(rootfs: &Dir, files: &super::FileMap, now: u64) -> Result<Option<Self>> {
+    /// Returns `Ok(None)` if no RPM database is detected. The rpm options and
+    /// the cache of parsed databases come from `config`. Verifying files
+    /// against the rpmdb means reading all of them.
+    pub fn load(
+        rootfs: &Dir,
+        files: &super::FileMap,
+        now: u64,
+        config: &RepoConfig,
+    ) -> Result<Option<Self>> {
         if !has_rpmdb(rootfs)? {
             return Ok(None);
         }
 
-        let mut packages =
-            rpm_qa::load_from_rootfs_dir(rootfs).context("loading rpmdb from rootfs")?;
+        let read_packages = || -> Result<Vec<Package>> {
+            let packages =
+                rpm_qa::load_from_rootfs_dir(rootfs).context("loading rpmdb from rootfs")?;
+            Ok(convert_packages(packages))
+        };
+        let mut packages = match &config.cache {
+            Some(cache) => {
+                let mut key = CacheKey::new(REPO_NAME)?;
+                for path in RPMDB_PATHS {
+                    let metadata = rootfs
+                        .symlink_metadata_optional(path)
+                        .with_context(|| format!("querying {path}"))?;
+                    // symlinks to another one of the paths are covered by it
+                    if metadata.is_some_and(|m| m.is_dir()) {
+                        key.dir_files(rootfs, path)?;
+                    }
+                }
+                cache.get_or_insert_with(key, read_packages)?
+            }
+            None => read_packages()?,
+        };
 
         canonicalize_package_paths(rootfs, files, &mut packages)
             .context("canonicalizing package paths")?;
 
-        Self::load_from_packages(packages, now).map(Some)
+        let modified = if config.rpm_verify {
+            find_modified_files(rootfs, files, &packages).context("verifying files")?
+        } else {
+            HashSet::new()
+        };
+
+        Self::from_packages(packages, now, config, &modified).map(Some)
     }
 
+    #[cfg(test)]
     pub fn load_from_packages(packages: rpm_qa::Packages, now: u64) -> Result<Self> {
+        Self::from_packages(
+            convert_packages(packages),
+            now,
+            &RepoConfig::new(),
+            &HashSet::new(),
+        )
+    }
+
+    fn from_packages(
+        packages: Vec<Package>,
+        now: u64,
+        config: &RepoConfig,
+        modified: &HashSet<Utf8PathBuf>,
+    ) -> Result<Self> {
         let mut components: IndexMap<String, (u64, f64)> = IndexMap::new();
-        let mut path_to_components: HashMap<Utf8PathBuf, Vec<(ComponentId, FileInfo)>> =
+        let mut path_to_components: HashMap<Utf8PathBuf, Vec<(ComponentId, Option<FileType>)>> =
             HashMap::new();
+        let mut packages_by_id: Vec<BTreeSet<String>> = Vec::new();
 
-        for pkg in packages.into_values() {
+        for pkg in packages {
             // Use the source RPM as the component name, falling back to package name
-            let component_name: &str = pkg
-                .sourcerpm
-                .as_deref()
-                .map(parse_srpm_name)
-                .unwrap_or(&pkg.name);
+            let component_name: &str = match config.rpm_group_by {
+                RpmGroupBy::Srpm => pkg
+                    .sourcerpm
+                    .as_deref()
+                    .map(parse_srpm_name)
+                    .unwrap_or(&pkg.name),
+                RpmGroupBy::Package => &pkg.name,
+            };
 
             let entry = components.entry(component_name.to_string());
             let component_id = ComponentId(entry.index());
             match entry {
                 indexmap::map::Entry::Occupied(mut e) => {
-                    // Build time across subpackages for a given SRPM can vary.
-                    // We want the max() of all of them as the clamp.
+                    // Build time across subpackages for a given SRPM (or
+                    // across arches of a package) can vary. We want the max()
+                    // of all of them as the clamp.
                     let (existing_bt, _) = e.get_mut();
                     *existing_bt = (*existing_bt).max(pkg.buildtime);
                 }
                 indexmap::map::Entry::Vacant(e) => {
-                    let stability = calculate_stability(&pkg.changelog_times, pkg.buildtime, now)?;
+                    let mut stability =
+                        calculate_stability(&pkg.changelog_times, pkg.buildtime, now)?;
+                    if let Some(updateinfo) = &config.rpm_updateinfo
+                        && let Some(times) = updateinfo.times(component_name)
+                    {
+                        // changelogs often miss updates, so go with whichever
+                        // shows the most churn
+                        stability = stability.min(calculate_stability(&times, pkg.buildtime, now)?);
+                    }
                     e.insert((pkg.buildtime, stability));
                 }
             }
 
-            for (path, file_info) in pkg.files.into_iter() {
+            let config_name = match config.rpm_config_files {
+                RpmConfigFiles::Keep => None,
+                RpmConfigFiles::Split => Some(format!("{component_name}-config")),
+                RpmConfigFiles::Shared => Some(SHARED_CONFIG_COMPONENT.to_string()),
+            };
+            let mut config_id = None;
+            let mut ids = BTreeSet::new();
+
+            for file in pkg.files {
+                let id = match &config_name {
+                    _ if modified.contains(&file.path) => {
+                        let entry = components.entry(MODIFIED_COMPONENT.to_string());
+                        let id = ComponentId(entry.index());
+                        // whenever they were modified, it was after the build
+                        entry.or_insert((now, 0.0));
+                        id
+                    }
+                    Some(name) if file.noreplace_config => *config_id.get_or_insert_with(|| {
+                        let entry = components.entry(name.clone());
+                        let id = ComponentId(entry.index());
+                        // config files are expected to change, whatever the
+                        // package does
+                        let (buildtime, _) = entry.or_insert((pkg.buildtime, 0.0));
+                        *buildtime = (*buildtime).max(pkg.buildtime);
+                        id
+                    }),
+                    _ => component_id,
+                };
+                ids.insert(id);
                 // Accumulate entries for all file types. Skip if this component
                 // already owns this path (can happen when multiple subpackages
                 // from the same SRPM own the same path).
-                let entries = path_to_components.entry(path).or_default();
-                if !entries.iter().any(|(id, _)| *id == component_id) {
-                    entries.push((component_id, file_info));
+                let entries = path_to_components.entry(file.path).or_default();
+                if !entries.iter().any(|(existing, _)| *existing == id) {
+                    entries.push((id, file.file_type));
                 }
             }
+
+            packages_by_id.resize_with(components.len(), BTreeSet::new);
+            for id in ids {
+                packages_by_id[id.0].insert(pkg.nevra.clone());
+            }
         }
 
         Ok(Self {
             components,
             path_to_components,
+            packages: packages_by_id,
         })
     }
 }
@@ -117,7 +261,7 @@ impl ComponentsRepo for RpmRepo {
             .map(|entries| {
                 entries
                     .iter()
-                    .filter(|(_, fi)| file_info_to_file_type(fi) == Some(file_type))
+                    .filter(|(_, ft)| *ft == Some(file_type))
                     .map(|(id, _)| *id)
                     .collect()
             })
@@ -137,6 +281,13 @@ impl ComponentsRepo for RpmRepo {
             stability: *stability,
         }
     }
+
+    fn component_packages(&self, id: ComponentId) -> Vec<String> {
+        self.packages
+            .get(id.0)
+            .map(|packages| packages.iter().cloned().collect())
+            .unwrap_or_default()
+    }
 }
 
 /// Check if any known RPM database path exists in the rootfs.
@@ -154,30 +305,145 @@ fn has_rpmdb(rootfs: &Dir) -> anyhow::Result<bool> {
     Ok(false)
 }
 
+/// Convert packages from the rpmdb into the parts we need.
+fn convert_packages(packages: rpm_qa::Packages) -> Vec<Package> {
+    packages
+        .into_values()
+        .map(|pkg| Package {
+            files: pkg
+                .files
+                .into_iter()
+                .map(|(path, fi)| {
+                    let file_type = file_info_to_file_type(&fi);
+                    // ghosts aren't installed from the payload, so they're
+                    // expected to differ
+                    let in_payload = !fi.flags.is_ghost();
+                    PackageFile {
+                        path,
+                        file_type,
+                        noreplace_config: is_noreplace_config(&fi),
+                        size: fi.size,
+                        digest: fi.digest.map(|d| d.hex).filter(|hex| {
+                            in_payload && !hex.is_empty() && file_type == Some(FileType::File)
+                        }),
+                    }
+                })
+                .collect(),
+            nevra: format!(
+                "{}-{}{}-{}.{}",
+                pkg.name,
+                pkg.epoch.map(|e| format!("{e}:")).unwrap_or_default(),
+                pkg.version,
+                pkg.release,
+                pkg.arch
+            ),
+            name: pkg.name,
+            sourcerpm: pkg.sourcerpm,
+            buildtime: pkg.buildtime,
+            changelog_times: pkg.changelog_times,
+        })
+        .collect()
+}
+
 /// Canonicalize all file paths in packages by resolving directory symlinks.
 fn canonicalize_package_paths(
     rootfs: &Dir,
     files: &super::FileMap,
-    packages: &mut rpm_qa::Packages,
+    packages: &mut [Package],
 ) -> Result<()> {
     let mut cache = HashMap::new();
 
-    for package in packages.values_mut() {
-        let old_files = std::mem::take(&mut package.files);
-        for (path, info) in old_files {
-            let canonical = canonicalize_parent_path(rootfs, files, &path, &mut cache)
-                .with_context(|| format!("canonicalizing {}", path))?;
-            package.files.insert(canonical, info);
+    for package in packages {
+        for file in &mut package.files {
+            let canonical = canonicalize_parent_path(rootfs, files, &file.path, &mut cache)
+                .with_context(|| format!("canonicalizing {}", file.path))?;
+            file.path = canonical;
         }
     }
 
     Ok(())
 }
 
+/// Returns the files of `packages` whose size or digest in the rootfs doesn't
+/// match the rpmdb. Files only need to match one of the packages owning them.
+fn find_modified_files(
+    rootfs: &Dir,
+    files: &super::FileMap,
+    packages: &[Package],
+) -> Result<HashSet<Utf8PathBuf>> {
+    let mut expected: HashMap<&Utf8Path, Vec<(u64, &str)>> = HashMap::new();
+    for file in packages.iter().flat_map(|pkg| &pkg.files) {
+        if let Some(digest) = &file.digest {
+            expected
+                .entry(&file.path)
+                .or_default()
+                .push((file.size, digest));
+        }
+    }
+
+    let mut modified = HashSet::new();
+    for (path, candidates) in expected {
+        // missing files or files replaced by another type aren't ours to
+        // claim anyway
+        let Some(info) = files.get(path) else {
+            continue;
+        };
+        if info.file_type != FileType::File {
+            continue;
+        }
+        let mut matches = false;
+        for (size, digest) in candidates.iter().filter(|(size, _)| *size == info.size) {
+            let actual = file_digest(rootfs, path, digest.len())
+                .with_context(|| format!("hashing {path} ({size} bytes)"))?;
+            if actual.as_deref() == Some(*digest) {
+                matches = true;
+                break;
+            }
+        }
+        if !matches {
+            modified.insert(path.to_path_buf());
+        }
+    }
+    Ok(modified)
+}
+
+/// Returns the hex digest of the file at `path`, with the algorithm producing
+/// digests of `hex_len` characters, or `None` if there's no such algorithm.
+///
+/// The rpmdb records the algorithm per package, but the length of digests is
+/// enough to tell the ones rpm supports apart.
+pub(super) fn file_digest(rootfs: &Dir, path: &Utf8Path, hex_len: usize) -> Result<Option<String>> {
+    let md = match hex_len {
+        32 => MessageDigest::md5(),
+        40 => MessageDigest::sha1(),
+        56 => MessageDigest::sha224(),
+        64 => MessageDigest::sha256(),
+        96 => MessageDigest::sha384(),
+        128 => MessageDigest::sha512(),
+        _ => return Ok(None),
+    };
+    let rel_path = path.strip_prefix("/").unwrap_or(path);
+    let mut file = rootfs
+        .open(rel_path)
+        .with_context(|| format!("opening {path}"))?;
+    let mut hasher = Hasher::new(md)?;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file
+            .read(&mut buf)
+            .with_context(|| format!("reading {path}"))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n])?;
+    }
+    Ok(Some(hex::encode(hasher.finish()?)))
+}
+
 /// Parse the SRPM name from a full SRPM filename.
 ///
 /// e.g., "bash-5.2.15-5.fc40.src.rpm" -> "bash"
-fn parse_srpm_name(srpm: &str) -> &str {
+pub(super) fn parse_srpm_name(srpm: &str) -> &str {
     // Remove .src.rpm suffix
     let without_suffix = srpm.strip_suffix(".src.rpm").unwrap_or(srpm);
 
@@ -191,12 +457,21 @@ fn parse_srpm_name(srpm: &str) -> &str {
     }
 }
 
-fn file_info_to_file_type(fi: &FileInfo) -> Option<FileType> {
+/// Whether the file is marked as `%config(noreplace)`, i.e. upgrades keep
+/// local modifications to it.
+fn is_noreplace_config(fi: &rpm_qa::FileInfo) -> bool {
+    fi.flags.is_config() && fi.flags.is_noreplace()
+}
+
+fn file_info_to_file_type(fi: &rpm_qa::FileInfo) -> Option<FileType> {
     let file_type = (fi.mode as libc::mode_t) & libc::S_IFMT;
     match file_type {
         libc::S_IFDIR => Some(FileType::Directory),
         libc::S_IFREG => Some(FileType::File),
         libc::S_IFLNK => Some(FileType::Symlink),
+        libc::S_IFBLK => Some(FileType::BlockDevice),
+        libc::S_IFCHR => Some(FileType::CharDevice),
+        libc::S_IFIFO => Some(FileType::Fifo),
         _ => None,
     }
 }
@@ -207,6 +482,7 @@ mod tests {
     use cap_std_ext::cap_std::ambient_authority;
 
     use super::*;
+    use crate::components::{SECS_PER_DAY, UpdateInfo};
 
     const FIXTURE: &str = include_str!("../../tests/fixtures/fedora.json");
 
@@ -287,6 +563,176 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_group_by_package() {
+        let packages = rpm_qa::load_from_str(FIXTURE).unwrap();
+        let repo = RpmRepo::load_from_packages(packages, now_secs()).unwrap();
+        let claims = repo.claims_for_path(Utf8Path::new("/usr/bin/dmesg"), FileType::File);
+        assert_eq!(repo.component_info(claims[0]).name, "util-linux");
+
+        // util-linux-core is built from the util-linux SRPM, but is its own
+        // component
+        let packages = rpm_qa::load_from_str(FIXTURE).unwrap();
+        let repo = RpmRepo::from_packages(
+            convert_packages(packages),
+            now_secs(),
+            &RepoConfig::new().rpm_group_by(RpmGroupBy::Package),
+            &HashSet::new(),
+        )
+        .unwrap();
+        let claims = repo.claims_for_path(Utf8Path::new("/usr/bin/dmesg"), FileType::File);
+        assert_eq!(claims.len(), 1);
+        assert_eq!(repo.component_info(claims[0]).name, "util-linux-core");
+        let claims = repo.claims_for_path(Utf8Path::new("/usr/bin/bash"), FileType::File);
+        assert_eq!(repo.component_info(claims[0]).name, "bash");
+    }
+
+    #[test]
+    fn test_component_packages() {
+        let packages = rpm_qa::load_from_str(FIXTURE).unwrap();
+        let repo = RpmRepo::load_from_packages(packages, now_secs()).unwrap();
+        let claims = repo.claims_for_path(Utf8Path::new("/usr/bin/bash"), FileType::File);
+        assert_eq!(
+            repo.component_packages(claims[0]),
+            ["bash-5.3.0-2.fc43.x86_64"]
+        );
+        // the epoch is only there if the package has one
+        let claims = repo.claims_for_path(Utf8Path::new("/usr/bin/useradd"), FileType::File);
+        assert_eq!(
+            repo.component_packages(claims[0]),
+            ["shadow-utils-2:4.18.0-3.fc43.x86_64"]
+        );
+    }
+
+    #[test]
+    fn test_updateinfo_stability() {
+        let now = now_secs();
+        let load = |config: &RepoConfig| {
+            let packages = rpm_qa::load_from_str(FIXTURE).unwrap();
+            RpmRepo::from_packages(convert_packages(packages), now, config, &HashSet::new())
+                .unwrap()
+        };
+        let stability = |repo: &RpmRepo, path: &str| {
+            let claims = repo.claims_for_path(Utf8Path::new(path), FileType::File);
+            repo.component_info(claims[0]).stability
+        };
+
+        // an update of bash every week for the past 10 weeks
+        let updates: String = (1..=10)
+            .map(|week| {
+                let issued = now - week * 7 * SECS_PER_DAY;
+                format!(
+                    r#"<update><issued date="{issued}"/><pkglist><collection>
+                    <package name="bash" src="bash-5.3.0-{week}.fc43.src.rpm"/>
+                    </collection></pkglist></update>"#
+                )
+            })
+            .collect();
+        let mut updateinfo = UpdateInfo::new();
+        updateinfo
+            .parse(&format!("<updates>{updates}</updates>"))
+            .unwrap();
+
+        let repo = load(&RepoConfig::new());
+        let refined = load(&RepoConfig::new().rpm_updateinfo(updateinfo));
+        assert!(stability(&refined, "/usr/bin/bash") < stability(&repo, "/usr/bin/bash"));
+        assert!(stability(&refined, "/usr/bin/bash") < 0.5);
+        // packages without updates keep their changelog-based stability
+        assert_eq!(
+            stability(&refined, "/usr/bin/dmesg"),
+            stability(&repo, "/usr/bin/dmesg")
+        );
+    }
+
+    #[test]
+    fn test_config_files() {
+        let load = |config_files| {
+            let packages = rpm_qa::load_from_str(FIXTURE).unwrap();
+            RpmRepo::from_packages(
+                convert_packages(packages),
+                now_secs(),
+                &RepoConfig::new().rpm_config_files(config_files),
+                &HashSet::new(),
+            )
+            .unwrap()
+        };
+        let claim = |repo: &RpmRepo, path: &str| {
+            let claims = repo.claims_for_path(Utf8Path::new(path), FileType::File);
+            assert_eq!(claims.len(), 1, "{path}");
+            let info = repo.component_info(claims[0]);
+            (info.name.to_string(), info.stability)
+        };
+
+        let repo = load(RpmConfigFiles::Keep);
+        assert_eq!(claim(&repo, "/etc/ld.so.conf").0, "glibc");
+
+        let repo = load(RpmConfigFiles::Split);
+        assert_eq!(
+            claim(&repo, "/etc/ld.so.conf"),
+            ("glibc-config".to_string(), 0.0)
+        );
+        assert_eq!(claim(&repo, "/etc/skel/.bashrc").0, "bash-config");
+        // the rest of the package stays where it was
+        assert_eq!(claim(&repo, "/usr/bin/bash").0, "bash");
+
+        let repo = load(RpmConfigFiles::Shared);
+        assert_eq!(claim(&repo, "/etc/ld.so.conf").0, "config");
+        assert_eq!(claim(&repo, "/etc/skel/.bashrc").0, "config");
+        assert_eq!(claim(&repo, "/usr/bin/bash").0, "bash");
+    }
+
+    #[test]
+    fn test_modified_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join("a"), "hello").unwrap();
+        std::fs::write(tmp.path().join("b"), "hellO").unwrap();
+        std::fs::write(tmp.path().join("c"), "hello!").unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+
+        let sha256 = hex::encode(openssl::sha::sha256(b"hello"));
+        let md5 = hex::encode(openssl::hash::hash(MessageDigest::md5(), b"hello").unwrap());
+        let file = |path: &str, digest: &str| PackageFile {
+            path: path.into(),
+            file_type: Some(FileType::File),
+            noreplace_config: false,
+            size: 5,
+            digest: Some(digest.to_string()),
+        };
+        let packages = vec![Package {
+            name: "foo".to_string(),
+            nevra: "foo-1.0-1.noarch".to_string(),
+            sourcerpm: None,
+            buildtime: 0,
+            changelog_times: vec![],
+            files: vec![
+                file("/a", &md5),
+                file("/b", &sha256),
+                file("/c", &sha256),
+                file("/missing", &sha256),
+            ],
+        }];
+        let modified = find_modified_files(&rootfs, &files, &packages).unwrap();
+        let mut modified: Vec<_> = modified.into_iter().collect();
+        modified.sort();
+        assert_eq!(modified, ["/b", "/c"]);
+
+        let packages = rpm_qa::load_from_str(FIXTURE).unwrap();
+        let repo = RpmRepo::from_packages(
+            convert_packages(packages),
+            now_secs(),
+            &RepoConfig::new(),
+            &HashSet::from([Utf8PathBuf::from("/usr/bin/bash")]),
+        )
+        .unwrap();
+        let claims = repo.claims_for_path(Utf8Path::new("/usr/bin/bash"), FileType::File);
+        assert_eq!(claims.len(), 1);
+        let info = repo.component_info(claims[0]);
+        assert_eq!((info.name, info.stability), ("modified", 0.0));
+        let claims = repo.claims_for_path(Utf8Path::new("/usr/bin/sh"), FileType::Symlink);
+        assert_eq!(repo.component_info(claims[0]).name, "bash");
+    }
+
     #[test]
     fn test_claims_for_path_wrong_type() {
         let packages = rpm_qa::load_from_str(FIXTURE).unwrap();
@@ -345,7 +791,9 @@ mod tests {
         let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
 
         let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
-        let repo = RpmRepo::load(&rootfs, &files, now_secs()).unwrap().unwrap();
+        let repo = RpmRepo::load(&rootfs, &files, now_secs(), &RepoConfig::new())
+            .unwrap()
+            .unwrap();
 
         // Test that paths we know are in filesystem and setup are claimed
         let claims = repo.claims_for_path(Utf8Path::new("/"), FileType::Directory);
@@ -0,0 +1,212 @@
+//! Stability overrides from a user-provided TOML file.
+//!
+//! Repos estimate the stability of their components from e.g. changelogs,
+//! which isn't always right: some packages change daily even though their
+//! changelogs say otherwise. The overrides file maps component names or glob
+//! patterns over them to the stability to use instead:
+//!
+//! ```toml
+//! "rpm/internal-*" = 0.05
+//! "manifest/myapp" = 0.5
+//! ```
+//!
+//! An exact name takes precedence over patterns. If several patterns match, the
+//! lowest stability wins, since the file is mostly used to flag churny
+//! components.
+//!
+//! Since stabilities depend on the time of the build, this also records the
+//! final stabilities of a build as a snapshot, which later builds can replay so
+//! that their packing is comparable.
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use serde::{Deserialize, Serialize};
+
+use super::Component;
+use crate::utils::GLOB_MATCH_OPTIONS;
+
+/// Version of the stability snapshot format.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// The stability overrides of components.
+#[derive(Debug, Clone, Default)]
+pub struct StabilityOverrides {
+    /// Overrides of components by exact name.
+    names: HashMap<String, f64>,
+    /// Overrides of components matching a pattern.
+    patterns: Vec<(glob::Pattern, f64)>,
+}
+
+impl StabilityOverrides {
+    /// Load the overrides from the TOML file at `path`.
+    pub fn load(path: &Utf8Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path).with_context(|| format!("reading {path}"))?;
+        Self::parse(&content).with_context(|| format!("parsing {path}"))
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let table: HashMap<String, f64> = toml::from_str(content)?;
+        let mut overrides = Self::default();
+        for (name, stability) in table {
+            // 0.0 means "unknown" to the packing, which then picks a fallback
+            anyhow::ensure!(
+                stability > 0.0 && stability <= 1.0,
+                "{name}: stability must be greater than 0 and at most 1"
+            );
+            if name.contains(['*', '?', '[']) {
+                let pattern =
+                    glob::Pattern::new(&name).with_context(|| format!("invalid pattern {name}"))?;
+                overrides.patterns.push((pattern, stability));
+            } else {
+                overrides.names.insert(name, stability);
+            }
+        }
+        Ok(overrides)
+    }
+
+    /// Returns the stability to use for the component named `name`, if
+    /// overridden.
+    pub fn stability(&self, name: &str) -> Option<f64> {
+        if let Some(stability) = self.names.get(name) {
+            return Some(*stability);
+        }
+        self.patterns
+            .iter()
+            .filter(|(pattern, _)| pattern.matches_with(name, GLOB_MATCH_OPTIONS))
+            .map(|(_, stability)| *stability)
+            .min_by(f64::total_cmp)
+    }
+}
+
+/// The stabilities of all the components of a build.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StabilitySnapshot {
+    version: u32,
+    stabilities: BTreeMap<String, f64>,
+}
+
+impl StabilitySnapshot {
+    /// Record the stabilities of `components`.
+    pub fn new(components: &HashMap<String, Component>) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            stabilities: components
+                .iter()
+                .map(|(name, component)| (name.clone(), component.stability))
+                .collect(),
+        }
+    }
+
+    /// Load a snapshot written by [`StabilitySnapshot::write`].
+    pub fn load(path: &Utf8Path) -> Result<Self> {
+        let content = std::fs::read(path).with_context(|| format!("reading {path}"))?;
+        let snapshot: Self =
+            serde_json::from_slice(&content).with_context(|| format!("parsing {path}"))?;
+        anyhow::ensure!(
+            snapshot.version == SNAPSHOT_VERSION,
+            "{path}: unsupported version {}",
+            snapshot.version
+        );
+        Ok(snapshot)
+    }
+
+    /// Write the snapshot to `path` as JSON.
+    pub fn write(&self, path: &Utf8Path) -> Result<()> {
+        let content = serde_json::to_vec_pretty(self).context("serializing stabilities")?;
+        std::fs::write(path, content).with_context(|| format!("writing {path}"))
+    }
+
+    /// Replace the stabilities of `components` with the recorded ones.
+    ///
+    /// Returns the names of the components which aren't in the snapshot, and
+    /// so keep their computed stability, sorted.
+    pub fn replay(&self, components: &mut HashMap<String, Component>) -> Vec<String> {
+        let mut missing = Vec::new();
+        for (name, component) in components.iter_mut() {
+            match self.stabilities.get(name) {
+                Some(stability) => component.stability = *stability,
+                None => missing.push(name.clone()),
+            }
+        }
+        missing.sort_unstable();
+        missing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stability_overrides() {
+        let overrides = StabilityOverrides::parse(
+            r#"
+            "rpm/internal-*" = 0.2
+            "rpm/internal-agent*" = 0.05
+            "rpm/internal-tools" = 0.5
+            "manifest/myapp" = 0.9
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(overrides.stability("manifest/myapp"), Some(0.9));
+        // exact names win over patterns
+        assert_eq!(overrides.stability("rpm/internal-tools"), Some(0.5));
+        // the lowest matching pattern wins
+        assert_eq!(overrides.stability("rpm/internal-agent"), Some(0.05));
+        assert_eq!(overrides.stability("rpm/internal-lib"), Some(0.2));
+        assert_eq!(overrides.stability("rpm/bash"), None);
+        // `*` doesn't match `/`
+        assert_eq!(overrides.stability("rpm/internal-x/sub"), None);
+
+        for content in [
+            "\"rpm/bash\" = 0.0",
+            "\"rpm/bash\" = 1.5",
+            "\"rpm/bash\" = \"high\"",
+            "\"rpm/[\" = 0.5",
+        ] {
+            assert!(
+                StabilityOverrides::parse(content).is_err(),
+                "{content:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn test_stability_snapshot() {
+        let component = |stability| Component {
+            mtime_clamp: 0,
+            stability,
+            files: Default::default(),
+            packages: Default::default(),
+        };
+        let recorded: HashMap<String, Component> = [
+            ("rpm/bash".to_string(), component(0.9)),
+            ("chunkah/unclaimed".to_string(), component(0.1)),
+        ]
+        .into();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = Utf8Path::from_path(tmp.path())
+            .unwrap()
+            .join("stability.json");
+        StabilitySnapshot::new(&recorded).write(&path).unwrap();
+        let snapshot = StabilitySnapshot::load(&path).unwrap();
+
+        let mut components: HashMap<String, Component> = [
+            ("rpm/bash".to_string(), component(0.5)),
+            ("rpm/zsh".to_string(), component(0.7)),
+            ("chunkah/unclaimed".to_string(), component(0.25)),
+        ]
+        .into();
+        assert_eq!(snapshot.replay(&mut components), ["rpm/zsh"]);
+        assert_eq!(components["rpm/bash"].stability, 0.9);
+        assert_eq!(components["chunkah/unclaimed"].stability, 0.1);
+        assert_eq!(components["rpm/zsh"].stability, 0.7);
+
+        std::fs::write(&path, r#"{"version": 2, "stabilities": {}}"#).unwrap();
+        assert!(StabilitySnapshot::load(&path).is_err());
+    }
+}
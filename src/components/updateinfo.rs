@@ -0,0 +1,319 @@
+//! Published updates from `updateinfo.xml` repo metadata.
+//!
+//! Changelogs undercount how often packages change: many updates (e.g.
+//! rebuilds for a dependency, or new upstream releases) don't document
+//! themselves, or only in one entry. The advisories of the distribution's
+//! update repos record when each update actually shipped, which gives the rpm
+//! repo a better estimate of the stability of packages.
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::Read;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+
+/// When updates of packages were published, from one or more
+/// `updateinfo.xml` files.
+#[derive(Debug, Clone, Default)]
+pub struct UpdateInfo {
+    /// Issue times of the updates of packages, by binary package name and by
+    /// source package name.
+    times: HashMap<String, BTreeSet<u64>>,
+}
+
+impl UpdateInfo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load updates from `location`, a path or an `http(s)://` URL (fetched
+    /// with `curl`). The file may be compressed with gzip, xz or bzip2, as in
+    /// repo metadata.
+    pub fn load(&mut self, location: &str) -> Result<()> {
+        let content = if location.starts_with("http://") || location.starts_with("https://") {
+            let output = Command::new("curl")
+                .args(["--fail", "--silent", "--show-error", "--location", location])
+                .output()
+                .context("spawning curl")?;
+            anyhow::ensure!(
+                output.status.success(),
+                "fetching {location} failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            output.stdout
+        } else {
+            std::fs::read(location).with_context(|| format!("reading {location}"))?
+        };
+        let xml = decompress(content).with_context(|| format!("decompressing {location}"))?;
+        self.parse(&xml)
+            .with_context(|| format!("parsing {location}"))
+    }
+
+    /// Add the updates of an `updateinfo.xml` document.
+    pub fn parse(&mut self, xml: &str) -> Result<()> {
+        // the packages of the current update, which may come before or after
+        // its issue date
+        let mut names: Vec<String> = Vec::new();
+        let mut issued: Option<u64> = None;
+        for tag in Tags::new(xml) {
+            let tag = tag?;
+            match (tag.name, tag.closing) {
+                ("update", false) => {
+                    names.clear();
+                    issued = None;
+                }
+                ("issued", false) => {
+                    let date = tag.attr("date").context("issued tag without date")?;
+                    issued = Some(parse_date(&date)?);
+                }
+                ("package", false) => {
+                    names.extend(tag.attr("name"));
+                    if let Some(src) = tag.attr("src") {
+                        names.push(super::rpm::parse_srpm_name(&src).to_string());
+                    }
+                }
+                ("update", true) => {
+                    // updates without a date don't tell us anything
+                    if let Some(issued) = issued {
+                        for name in names.drain(..) {
+                            self.times.entry(name).or_default().insert(issued);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the times updates of the package or source package `name` were
+    /// issued, in order.
+    pub fn times(&self, name: &str) -> Option<Vec<u64>> {
+        self.times.get(name).map(|t| t.iter().copied().collect())
+    }
+}
+
+/// Decompress `content` according to its magic bytes.
+fn decompress(content: Vec<u8>) -> Result<String> {
+    let mut xml = String::new();
+    if content.starts_with(&[0x1f, 0x8b]) {
+        flate2::read::GzDecoder::new(content.as_slice()).read_to_string(&mut xml)?;
+    } else if content.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        xz2::read::XzDecoder::new(content.as_slice()).read_to_string(&mut xml)?;
+    } else if content.starts_with(b"BZh") {
+        bzip2::read::BzDecoder::new(content.as_slice()).read_to_string(&mut xml)?;
+    } else {
+        xml = String::from_utf8(content).context("content is not UTF-8")?;
+    }
+    Ok(xml)
+}
+
+/// Parse the date of an update, which is either a timestamp or a UTC date and
+/// time, depending on the tool which generated the metadata.
+fn parse_date(date: &str) -> Result<u64> {
+    if let Ok(timestamp) = date.parse::<u64>() {
+        return Ok(timestamp);
+    }
+    let datetime = chrono::NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| {
+            chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .map(|d| d.and_time(chrono::NaiveTime::MIN))
+        })
+        .with_context(|| format!("invalid date {date}"))?;
+    u64::try_from(datetime.and_utc().timestamp())
+        .with_context(|| format!("date {date} before epoch"))
+}
+
+/// An XML start or end tag.
+struct Tag<'a> {
+    name: &'a str,
+    /// Whether it's an end tag.
+    closing: bool,
+    /// The raw attributes.
+    attrs: &'a str,
+}
+
+impl Tag<'_> {
+    /// Returns the unescaped value of the attribute `name`.
+    fn attr(&self, name: &str) -> Option<String> {
+        let mut rest = self.attrs;
+        while let Some((key, after)) = rest.split_once('=') {
+            let after = after.trim_start();
+            let quote = after.chars().next()?;
+            if quote != '"' && quote != '\'' {
+                return None;
+            }
+            let (value, after) = after[1..].split_once(quote)?;
+            if key.trim() == name {
+                return Some(unescape(value));
+            }
+            rest = after;
+        }
+        None
+    }
+}
+
+/// Iterator over the tags of an XML document, skipping the text, comments,
+/// processing instructions and declarations. This is all we need from
+/// `updateinfo.xml`, which doesn't warrant an XML parser dependency.
+struct Tags<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Tags<'a> {
+    fn new(xml: &'a str) -> Self {
+        Self { rest: xml }
+    }
+}
+
+impl<'a> Iterator for Tags<'a> {
+    type Item = Result<Tag<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let start = self.rest.find('<')?;
+            self.rest = &self.rest[start..];
+            let skipped = [("<!--", "-->"), ("<![CDATA[", "]]>"), ("<?", "?>")]
+                .into_iter()
+                .find(|(open, _)| self.rest.starts_with(open));
+            if let Some((open, close)) = skipped {
+                let Some(end) = self.rest.find(close) else {
+                    return Some(Err(anyhow::anyhow!("unterminated {open}")));
+                };
+                self.rest = &self.rest[end + close.len()..];
+                continue;
+            }
+            let Some(end) = self.rest.find('>') else {
+                return Some(Err(anyhow::anyhow!("unterminated tag")));
+            };
+            let content = &self.rest[1..end];
+            self.rest = &self.rest[end + 1..];
+            if content.starts_with('!') {
+                // e.g. a DOCTYPE
+                continue;
+            }
+            let (closing, content) = match content.strip_prefix('/') {
+                Some(content) => (true, content),
+                None => (false, content.strip_suffix('/').unwrap_or(content)),
+            };
+            let (name, attrs) = content
+                .split_once(char::is_whitespace)
+                .unwrap_or((content, ""));
+            return Some(Ok(Tag {
+                name,
+                closing,
+                attrs,
+            }));
+        }
+    }
+}
+
+/// Replace the predefined XML entities and character references in `value`.
+fn unescape(value: &str) -> String {
+    if !value.contains('&') {
+        return value.to_string();
+    }
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..end];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        match c {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    const UPDATEINFO: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<updates>
+  <!-- <update><issued date="0"/></update> -->
+  <update from="updates@fedoraproject.org" status="stable" type="bugfix" version="2.0">
+    <id>FEDORA-2025-0001</id>
+    <title>bash-5.3.0-2.fc43</title>
+    <issued date="2025-03-01 12:00:00"/>
+    <description>Fixes &amp; improvements</description>
+    <pkglist>
+      <collection short="F43">
+        <package name="bash" version="5.3.0" release="2.fc43" epoch="0" arch="x86_64" src="bash-5.3.0-2.fc43.src.rpm">
+          <filename>bash-5.3.0-2.fc43.x86_64.rpm</filename>
+        </package>
+        <package name="bash-devel" version="5.3.0" release="2.fc43" epoch="0" arch="x86_64" src="bash-5.3.0-2.fc43.src.rpm"/>
+      </collection>
+    </pkglist>
+  </update>
+  <update type="security">
+    <id>FEDORA-2025-0002</id>
+    <pkglist><collection><package name='bash' src='bash-5.3.0-3.fc43.src.rpm'/></collection></pkglist>
+    <issued date="1740000000"/>
+  </update>
+</updates>
+"#;
+
+    #[test]
+    fn test_parse() {
+        let mut updateinfo = UpdateInfo::new();
+        updateinfo.parse(UPDATEINFO).unwrap();
+        assert_eq!(updateinfo.times("bash"), Some(vec![1740000000, 1740830400]));
+        assert_eq!(updateinfo.times("bash-devel"), Some(vec![1740830400]));
+        assert_eq!(updateinfo.times("glibc"), None);
+
+        assert!(
+            UpdateInfo::new()
+                .parse("<update><issued date=\"soon\"/>")
+                .is_err()
+        );
+        assert!(UpdateInfo::new().parse("<updates><update").is_err());
+    }
+
+    #[test]
+    fn test_load_compressed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("updateinfo.xml.gz");
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(UPDATEINFO.as_bytes()).unwrap();
+        std::fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+        let mut updateinfo = UpdateInfo::new();
+        updateinfo.load(path.to_str().unwrap()).unwrap();
+        assert_eq!(updateinfo.times("bash").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(
+            unescape("a &amp; b &lt;c&gt; &#65;&#x42; &bogus; &"),
+            "a & b <c> AB &bogus; &"
+        );
+    }
+}
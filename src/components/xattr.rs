@@ -2,35 +2,61 @@ use std::collections::HashMap;
 
 use anyhow::{Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
-use indexmap::IndexSet;
+use indexmap::IndexMap;
 
 use super::{ComponentId, ComponentInfo, ComponentsRepo, FileInfo, FileMap, FileType};
 
-const XATTR_NAME: &str = "user.component";
+/// Default name of the xattr holding the component of a path.
+pub const DEFAULT_XATTR_NAME: &str = "user.component";
+/// Suffix of the xattr holding the stability of a component.
+const STABILITY_SUFFIX: &str = ".stability";
+/// Suffix of the xattr holding the mtime clamp of a component.
+const MTIME_SUFFIX: &str = ".mtime";
 const REPO_NAME: &str = "xattr";
 
 /// Xattr-based components repo implementation.
 ///
-/// Uses the `user.component` extended attribute to determine file ownership.
-/// Directories with this xattr apply to all files underneath unless overridden.
-/// Directory inheritance is pre-computed during load.
+/// Uses the `user.component` extended attribute (or another name given to
+/// [`XattrRepo::load`]) to determine file ownership. Directories with this
+/// xattr apply to all files underneath unless overridden. Directory
+/// inheritance is pre-computed during load.
+///
+/// The `user.component.stability` and `user.component.mtime` xattrs (i.e. the
+/// name with a `.stability` or `.mtime` suffix) set the stability and mtime
+/// clamp of the component of the path they're on, instead of the fallbacks.
+/// They may be on several paths of a component, but must then agree.
 pub struct XattrRepo {
-    /// Component names, indexed by ComponentId.
-    components: IndexSet<String>,
+    /// Component names mapped to their overrides, indexed by ComponentId.
+    components: IndexMap<String, Overrides>,
     /// Mapping from path to ComponentId (pre-computed with inheritance).
     path_to_component: HashMap<Utf8PathBuf, ComponentId>,
-    /// Currently, the on-disk mtime is canonical and we clamp it, but it would
-    /// make sense in the future to support another user xattr to specify a
-    /// canonical mtime for easier layer reproducibility.
+    /// Mtime clamp for components without a `user.component.mtime` xattr.
     default_mtime_clamp: u64,
 }
 
+/// Component properties set through xattrs.
+#[derive(Default)]
+struct Overrides {
+    /// Probability the component doesn't change between updates.
+    stability: Option<f64>,
+    /// The mtime clamp for the component's files.
+    mtime: Option<u64>,
+}
+
 impl XattrRepo {
-    /// Load xattr repo by scanning rootfs for user.component xattrs.
+    /// Load xattr repo by scanning rootfs for `xattr_name` xattrs.
     /// Pre-computes directory inheritance for all paths in `files`.
-    /// Uses cached xattrs from FileInfo rather than reading from disk.
-    pub fn load(files: &FileMap, default_mtime_clamp: u64) -> Result<Option<Self>> {
-        let mut components: IndexSet<String> = IndexSet::new();
+    /// Uses cached xattrs from FileInfo rather than reading from disk, so
+    /// xattrs in the `trusted` namespace are only found if the rootfs was
+    /// scanned as root.
+    pub fn load(
+        files: &FileMap,
+        default_mtime_clamp: u64,
+        xattr_name: &str,
+    ) -> Result<Option<Self>> {
+        let stability_xattr_name = format!("{xattr_name}{STABILITY_SUFFIX}");
+        let mtime_xattr_name = format!("{xattr_name}{MTIME_SUFFIX}");
+        let mut components: IndexMap<String, Overrides> = IndexMap::new();
         let mut path_to_component: HashMap<Utf8PathBuf, ComponentId> = HashMap::new();
 
         // Track active directory components: (path, ComponentId)
@@ -46,17 +72,14 @@ impl XattrRepo {
                 dir_stack.pop();
             }
 
-            let own_xattr = get_component_xattr(file_info)
+            let own_xattr = get_xattr(file_info, xattr_name)
                 .with_context(|| format!("reading xattr for {}", path))?;
 
             // If this path has an xattr, get or create its ComponentId
-            let own_component_id = own_xattr.as_ref().map(|name| {
-                // simplify this when we have either
-                // https://github.com/indexmap-rs/indexmap/issues/355 or
-                // https://github.com/indexmap-rs/indexmap/issues/388
-                let idx = components
-                    .get_index_of(name)
-                    .unwrap_or_else(|| components.insert_full(name.clone()).0);
+            let own_component_id = own_xattr.map(|name| {
+                let entry = components.entry(name);
+                let idx = entry.index();
+                entry.or_default();
                 ComponentId(idx)
             });
 
@@ -72,6 +95,11 @@ impl XattrRepo {
 
             if let Some(id) = effective_id {
                 path_to_component.insert(path.clone(), id);
+                // SAFETY: the id comes from the IndexMap itself
+                let (name, overrides) = components.get_index_mut(id.0).unwrap();
+                overrides
+                    .update(file_info, &stability_xattr_name, &mtime_xattr_name)
+                    .with_context(|| format!("reading xattrs for {path} (component {name})"))?;
             }
         }
 
@@ -87,15 +115,58 @@ impl XattrRepo {
     }
 }
 
-/// Extract the user.component xattr value from cached xattrs.
-fn get_component_xattr(file_info: &FileInfo) -> Result<Option<String>> {
+impl Overrides {
+    /// Add the overrides set through the xattrs of a path of the component.
+    fn update(
+        &mut self,
+        file_info: &FileInfo,
+        stability_xattr_name: &str,
+        mtime_xattr_name: &str,
+    ) -> Result<()> {
+        if let Some(value) = get_xattr(file_info, stability_xattr_name)? {
+            let stability: f64 = value
+                .parse()
+                .with_context(|| format!("invalid {stability_xattr_name} xattr: {value}"))?;
+            anyhow::ensure!(
+                (0.0..=1.0).contains(&stability),
+                "{stability_xattr_name} must be between 0 and 1, got {stability}"
+            );
+            set_once(&mut self.stability, stability, stability_xattr_name)?;
+        }
+        if let Some(value) = get_xattr(file_info, mtime_xattr_name)? {
+            let mtime: u64 = value
+                .parse()
+                .with_context(|| format!("invalid {mtime_xattr_name} xattr: {value}"))?;
+            set_once(&mut self.mtime, mtime, mtime_xattr_name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Set `slot` to `value`, unless it's already set to a different value.
+fn set_once<T: PartialEq + std::fmt::Display>(
+    slot: &mut Option<T>,
+    value: T,
+    xattr: &str,
+) -> Result<()> {
+    match slot {
+        Some(existing) if *existing != value => {
+            anyhow::bail!("{xattr} xattr is {value}, but {existing} elsewhere in the component")
+        }
+        _ => *slot = Some(value),
+    }
+    Ok(())
+}
+
+/// Extract the value of the xattr `name` from cached xattrs.
+fn get_xattr(file_info: &FileInfo, name: &str) -> Result<Option<String>> {
     file_info
         .xattrs
         .iter()
-        .find(|(k, _)| k == XATTR_NAME)
+        .find(|(k, _)| k == name)
         .map(|(_, v)| {
             String::from_utf8(v.clone())
-                .map_err(|e| anyhow::anyhow!("invalid UTF-8 in {XATTR_NAME} xattr: {e}"))
+                .map_err(|e| anyhow::anyhow!("invalid UTF-8 in {name} xattr: {e}"))
         })
         .transpose()
 }
@@ -117,16 +188,17 @@ impl ComponentsRepo for XattrRepo {
     }
 
     fn component_info(&self, id: ComponentId) -> ComponentInfo<'_> {
+        let (name, overrides) = self
+            .components
+            .get_index(id.0)
+            // SAFETY: the ids we're given come from the IndexMap itself when we
+            // inserted the element, so it must be valid.
+            .expect("invalid ComponentId");
         ComponentInfo {
-            name: self
-                .components
-                .get_index(id.0)
-                // SAFETY: the ids we're given come from the IndexSet itself
-                // when we inserted the element, so it must be valid.
-                .expect("invalid ComponentId"),
-            mtime_clamp: self.default_mtime_clamp,
-            // TODO: make this configurable via xattr or CLI
-            stability: 0.0,
+            name,
+            mtime_clamp: overrides.mtime.unwrap_or(self.default_mtime_clamp),
+            // 0.0 means it gets the fallback stability
+            stability: overrides.stability.unwrap_or(0.0),
         }
     }
 }
@@ -139,6 +211,9 @@ mod tests {
 
     use super::*;
 
+    const STABILITY_XATTR_NAME: &str = "user.component.stability";
+    const MTIME_XATTR_NAME: &str = "user.component.mtime";
+
     /// Helper to set up a rootfs, run setup, and scan files.
     /// Returns (tempdir, files) - caller must keep tempdir alive.
     fn setup_rootfs<F>(setup: F) -> (tempfile::TempDir, FileMap)
@@ -155,7 +230,7 @@ mod tests {
     /// Helper to set the component xattr on a path.
     fn set_component(rootfs: &Dir, path: &str, component: &str) {
         rootfs
-            .setxattr(path, XATTR_NAME, component.as_bytes())
+            .setxattr(path, DEFAULT_XATTR_NAME, component.as_bytes())
             .unwrap();
     }
 
@@ -187,7 +262,9 @@ mod tests {
             // File without xattr outside of directory - should not be claimed
             rootfs.write("noattr", "content").unwrap();
         });
-        let repo = XattrRepo::load(&files, 0).unwrap().unwrap();
+        let repo = XattrRepo::load(&files, 0, DEFAULT_XATTR_NAME)
+            .unwrap()
+            .unwrap();
 
         // /mydir and /mydir/normal should be dircomponent
         assert_component(&repo, "/mydir", FileType::Directory, "dircomponent");
@@ -217,7 +294,9 @@ mod tests {
             set_component(rootfs, "a/b/c/d", "compD");
             set_component(rootfs, "x", "compX");
         });
-        let repo = XattrRepo::load(&files, 0).unwrap().unwrap();
+        let repo = XattrRepo::load(&files, 0, DEFAULT_XATTR_NAME)
+            .unwrap()
+            .unwrap();
 
         assert_component(&repo, "/a", FileType::Directory, "compA");
         assert_component(&repo, "/a/other", FileType::File, "compA"); // inherits from /a
@@ -228,6 +307,92 @@ mod tests {
         assert_component(&repo, "/x/file", FileType::File, "compX"); // inherits from /x
     }
 
+    #[test]
+    fn test_xattr_overrides() {
+        let (_tmp, files) = setup_rootfs(|rootfs| {
+            rootfs.create_dir("app").unwrap();
+            set_component(rootfs, "app", "app");
+            rootfs
+                .setxattr("app", STABILITY_XATTR_NAME, b"0.9")
+                .unwrap();
+            rootfs.write("app/bin", "content").unwrap();
+            // agrees with the directory
+            rootfs
+                .setxattr("app/bin", STABILITY_XATTR_NAME, b"0.9")
+                .unwrap();
+            rootfs
+                .setxattr("app/bin", MTIME_XATTR_NAME, b"1700000000")
+                .unwrap();
+
+            rootfs.write("other", "content").unwrap();
+            set_component(rootfs, "other", "other");
+        });
+        let repo = XattrRepo::load(&files, 42, DEFAULT_XATTR_NAME)
+            .unwrap()
+            .unwrap();
+
+        let info = |path: &str| {
+            let claims = repo.claims_for_path(Utf8Path::new(path), FileType::File);
+            repo.component_info(claims[0])
+        };
+        assert_eq!(info("/app").stability, 0.9);
+        assert_eq!(info("/app").mtime_clamp, 1700000000);
+        assert_eq!(info("/other").stability, 0.0);
+        assert_eq!(info("/other").mtime_clamp, 42);
+    }
+
+    #[test]
+    fn test_xattr_invalid_overrides() {
+        let load = |stability: &[u8], file_stability: &[u8]| {
+            let (_tmp, files) = setup_rootfs(|rootfs| {
+                rootfs.create_dir("app").unwrap();
+                set_component(rootfs, "app", "app");
+                rootfs
+                    .setxattr("app", STABILITY_XATTR_NAME, stability)
+                    .unwrap();
+                rootfs.write("app/bin", "content").unwrap();
+                rootfs
+                    .setxattr("app/bin", STABILITY_XATTR_NAME, file_stability)
+                    .unwrap();
+            });
+            XattrRepo::load(&files, 0, DEFAULT_XATTR_NAME).map(|_| ())
+        };
+        load(b"0.5", b"0.5").unwrap();
+        let err = load(b"0.5", b"0.9").unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "reading xattrs for /app/bin (component app): \
+             user.component.stability xattr is 0.9, but 0.5 elsewhere in the component"
+        );
+        assert!(load(b"2", b"2").is_err());
+        assert!(load(b"high", b"high").is_err());
+    }
+
+    #[test]
+    fn test_xattr_custom_name() {
+        let (_tmp, files) = setup_rootfs(|rootfs| {
+            rootfs.create_dir("app").unwrap();
+            rootfs.setxattr("app", "user.build.owner", b"app").unwrap();
+            rootfs
+                .setxattr("app", "user.build.owner.stability", b"0.5")
+                .unwrap();
+            rootfs.write("other", "content").unwrap();
+            set_component(rootfs, "other", "other");
+        });
+        let repo = XattrRepo::load(&files, 0, "user.build.owner")
+            .unwrap()
+            .unwrap();
+
+        assert_component(&repo, "/app", FileType::Directory, "app");
+        let claims = repo.claims_for_path(Utf8Path::new("/app"), FileType::Directory);
+        assert_eq!(repo.component_info(claims[0]).stability, 0.5);
+        // the default xattr is ignored
+        assert!(
+            repo.claims_for_path(Utf8Path::new("/other"), FileType::File)
+                .is_empty()
+        );
+    }
+
     #[test]
     fn test_xattr_symlink_inherits_from_parent() {
         // Symlinks don't support user xattrs, but they should inherit from parent directory
@@ -238,7 +403,9 @@ mod tests {
             // Create a symlink inside the directory - it should inherit from parent
             rootfs.symlink("../somewhere", "mydir/link").unwrap();
         });
-        let repo = XattrRepo::load(&files, 0).unwrap().unwrap();
+        let repo = XattrRepo::load(&files, 0, DEFAULT_XATTR_NAME)
+            .unwrap()
+            .unwrap();
 
         // Both should be claimed by mycomp
         assert_component(&repo, "/mydir", FileType::Directory, "mycomp");
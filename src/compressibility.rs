@@ -0,0 +1,174 @@
+//! Spotting components made of already compressed files (jars, PNGs, zstd
+//! firmware, ...), which gzip can't shrink, for `--adaptive-compression`.
+//!
+//! Only the magic numbers at the start of the largest files of a component are
+//! checked, so the decision is cheap and, like the layers, only depends on the
+//! content of the files.
+
+use std::io::Read;
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use cap_std_ext::cap_std::fs::Dir;
+
+use crate::components::{FileInfo, FileMap, FileType};
+use crate::ocibuilder::Compression;
+use crate::overlay::Overlay;
+
+/// Number of bytes read from the start of each sampled file.
+const MAGIC_LEN: usize = 12;
+
+/// Maximum number of files sampled per component, largest first.
+const SAMPLE_FILES: usize = 256;
+
+/// Share of already compressed bytes from which a layer isn't compressed.
+const SKIP_THRESHOLD: f64 = 0.9;
+
+/// Share of already compressed bytes from which a layer is compressed at the
+/// fastest level.
+const FAST_THRESHOLD: f64 = 0.5;
+
+/// Magic numbers of compressed file formats.
+const MAGICS: &[&[u8]] = &[
+    // gzip
+    &[0x1f, 0x8b],
+    // xz
+    &[0xfd, b'7', b'z', b'X', b'Z', 0x00],
+    // zstd
+    &[0x28, 0xb5, 0x2f, 0xfd],
+    // bzip2
+    b"BZh",
+    // lz4
+    &[0x04, 0x22, 0x4d, 0x18],
+    // zip, e.g. jars, wheels and APKs
+    b"PK\x03\x04",
+    // 7z
+    &[b'7', b'z', 0xbc, 0xaf, 0x27, 0x1c],
+    // squashfs
+    b"hsqs",
+    // PNG
+    &[0x89, b'P', b'N', b'G'],
+    // JPEG
+    &[0xff, 0xd8, 0xff],
+    // GIF
+    b"GIF8",
+    // WOFF and WOFF2 fonts
+    b"wOFF",
+    b"wOF2",
+];
+
+/// Returns whether `head`, the start of a file, is that of a compressed
+/// format.
+fn is_compressed(head: &[u8]) -> bool {
+    // WebP images are RIFF containers
+    let webp = head.starts_with(b"RIFF") && head.get(8..12) == Some(b"WEBP".as_slice());
+    webp || MAGICS.iter().any(|magic| head.starts_with(magic))
+}
+
+/// Returns the share of the bytes of the largest files of `files` which are
+/// already compressed.
+pub(crate) fn compressed_fraction(
+    rootfs: &Dir,
+    files: &FileMap,
+    overlay: Option<&Overlay>,
+) -> Result<f64> {
+    let mut sample: Vec<_> = files
+        .iter()
+        .filter(|(_, info)| info.file_type == FileType::File && info.size > 0)
+        .collect();
+    sample.sort_by(|a, b| b.1.size.cmp(&a.1.size).then_with(|| a.0.cmp(b.0)));
+
+    let mut total = 0;
+    let mut compressed = 0;
+    for (path, info) in sample.into_iter().take(SAMPLE_FILES) {
+        total += info.size;
+        let head =
+            read_head(rootfs, path, info, overlay).with_context(|| format!("reading {path}"))?;
+        if is_compressed(&head) {
+            compressed += info.size;
+        }
+    }
+    if total == 0 {
+        return Ok(0.0);
+    }
+    Ok(compressed as f64 / total as f64)
+}
+
+/// Returns the compression of a layer whose bytes are `compressed` (from 0 to
+/// 1) already compressed, given the one requested for all the layers.
+pub(crate) fn adapt(compression: Compression, compressed: f64) -> Compression {
+    match compression {
+        Compression::Gzip(_) if compressed >= SKIP_THRESHOLD => Compression::None,
+        Compression::Gzip(level) if compressed >= FAST_THRESHOLD => Compression::Gzip(level.min(1)),
+        compression => compression,
+    }
+}
+
+fn read_head(
+    rootfs: &Dir,
+    path: &Utf8Path,
+    info: &FileInfo,
+    overlay: Option<&Overlay>,
+) -> Result<Vec<u8>> {
+    if let Some(head) = overlay
+        .map(|overlay| overlay.read_prefix(path, MAGIC_LEN))
+        .transpose()?
+        .flatten()
+    {
+        return Ok(head);
+    }
+    let mut head = Vec::with_capacity(MAGIC_LEN);
+    rootfs
+        .open(info.fs_path(path))?
+        .take(MAGIC_LEN as u64)
+        .read_to_end(&mut head)?;
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std::ambient_authority;
+
+    use super::*;
+    use crate::scan::Scanner;
+
+    #[test]
+    fn test_is_compressed() {
+        assert!(is_compressed(&[0x1f, 0x8b, 0x08, 0x00]));
+        assert!(is_compressed(b"PK\x03\x04\x14\x00"));
+        assert!(is_compressed(&[0x28, 0xb5, 0x2f, 0xfd, 0x00]));
+        assert!(is_compressed(b"RIFF\x10\x00\x00\x00WEBPVP8 "));
+        assert!(!is_compressed(b"RIFF\x10\x00\x00\x00WAVEfmt "));
+        assert!(!is_compressed(b"\x7fELF\x02\x01\x01"));
+        assert!(!is_compressed(b"#!/bin/sh\n"));
+        assert!(!is_compressed(b""));
+    }
+
+    #[test]
+    fn test_compressed_fraction() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir("usr").unwrap();
+        let mut jar = b"PK\x03\x04".to_vec();
+        jar.resize(300, 0);
+        rootfs.write("usr/app.jar", &jar).unwrap();
+        rootfs.write("usr/app.conf", vec![b'a'; 100]).unwrap();
+        rootfs.write("usr/empty", "").unwrap();
+
+        let files = Scanner::new(&rootfs).scan().unwrap();
+        let fraction = compressed_fraction(&rootfs, &files, None).unwrap();
+        assert_eq!(fraction, 0.75);
+
+        let empty = FileMap::new();
+        assert_eq!(compressed_fraction(&rootfs, &empty, None).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_adapt() {
+        assert_eq!(adapt(Compression::Gzip(6), 0.95), Compression::None);
+        assert_eq!(adapt(Compression::Gzip(6), 0.75), Compression::Gzip(1));
+        assert_eq!(adapt(Compression::Gzip(0), 0.75), Compression::Gzip(0));
+        assert_eq!(adapt(Compression::Gzip(6), 0.2), Compression::Gzip(6));
+        assert_eq!(adapt(Compression::None, 0.2), Compression::None);
+    }
+}
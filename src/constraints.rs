@@ -0,0 +1,264 @@
+//! Constraints on how components are packed into layers.
+//!
+//! Some tooling relies on components sharing a layer, or not. The constraints
+//! file lists groups of components which must always be packed together,
+//! groups of components which must each be in a different layer, and groups
+//! which must get a layer of their own at a given position:
+//!
+//! ```toml
+//! [[together]]
+//! components = ["rpm/glibc", "rpm/openssl"]
+//!
+//! [[apart]]
+//! components = ["rpm/kernel", "rpm/linux-firmware"]
+//!
+//! # the first layer after the layers of the base image, if any
+//! [[layer]]
+//! components = ["rpm/filesystem", "rpm/setup"]
+//! index = 0
+//! ```
+//!
+//! The other components are packed freely around them.
+
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use serde::Deserialize;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConstraintsFile {
+    #[serde(default)]
+    together: Vec<Rule>,
+    #[serde(default)]
+    apart: Vec<Rule>,
+    #[serde(default)]
+    layer: Vec<LayerRule>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    components: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LayerRule {
+    components: Vec<String>,
+    index: usize,
+}
+
+/// The packing constraints of components.
+#[derive(Debug, Default)]
+pub struct LayerConstraints {
+    /// Groups of components which must be packed together, with the index
+    /// among the component layers of their layer if they get one of their
+    /// own.
+    groups: Vec<Option<usize>>,
+    /// The group of each component in a group.
+    group_of: HashMap<String, usize>,
+    /// Sets of components which must each be in a different layer.
+    apart: Vec<BTreeSet<String>>,
+}
+
+/// The constraints resolved against the components being packed.
+#[derive(Debug, PartialEq)]
+pub struct ResolvedConstraints {
+    /// The units to pack, as indices into the components. Components of the
+    /// same group make up a unit, other components a unit each.
+    pub units: Vec<Vec<usize>>,
+    /// The layer index of each unit, if it gets a layer of its own.
+    pub indices: Vec<Option<usize>>,
+    /// Sets of units which must each be in a different layer.
+    pub apart: Vec<Vec<usize>>,
+}
+
+impl LayerConstraints {
+    /// Load the constraints from the TOML file at `path`.
+    pub fn load(path: &Utf8Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path).with_context(|| format!("reading {path}"))?;
+        Self::parse(&content).with_context(|| format!("parsing {path}"))
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let file: ConstraintsFile = toml::from_str(content)?;
+        let mut constraints = Self::default();
+
+        let groups = file
+            .together
+            .into_iter()
+            .map(|rule| (rule.components, None))
+            .chain(
+                file.layer
+                    .into_iter()
+                    .map(|rule| (rule.components, Some(rule.index))),
+            );
+        for (components, index) in groups {
+            match index {
+                Some(index) => {
+                    anyhow::ensure!(!components.is_empty(), "layer {index} has no components");
+                    anyhow::ensure!(
+                        !constraints.groups.contains(&Some(index)),
+                        "layer {index} is constrained more than once"
+                    );
+                }
+                None => anyhow::ensure!(
+                    components.len() >= 2,
+                    "together needs at least 2 components: {components:?}"
+                ),
+            }
+            let group_idx = constraints.groups.len();
+            for name in &components {
+                let prev = constraints.group_of.insert(name.clone(), group_idx);
+                anyhow::ensure!(prev.is_none(), "{name} is in more than one group");
+            }
+            constraints.groups.push(index);
+        }
+
+        for rule in file.apart {
+            let set: BTreeSet<String> = rule.components.into_iter().collect();
+            anyhow::ensure!(set.len() >= 2, "apart needs at least 2 components: {set:?}");
+            let mut groups = HashMap::new();
+            for name in &set {
+                if let Some(group_idx) = constraints.group_of.get(name)
+                    && let Some(other) = groups.insert(group_idx, name)
+                {
+                    anyhow::bail!("{other} and {name} must be both together and apart");
+                }
+            }
+            constraints.apart.push(set);
+        }
+
+        Ok(constraints)
+    }
+
+    /// Returns the constrained components which aren't in `names`, sorted.
+    pub fn missing(&self, names: &[&str]) -> Vec<String> {
+        let names: BTreeSet<&str> = names.iter().copied().collect();
+        let constrained: BTreeSet<&String> = self
+            .group_of
+            .keys()
+            .chain(self.apart.iter().flatten())
+            .collect();
+        constrained
+            .into_iter()
+            .filter(|name| !names.contains(name.as_str()))
+            .cloned()
+            .collect()
+    }
+
+    /// Resolve the constraints against the components named `names`. The
+    /// same name may appear several times, e.g. for the parts of a split
+    /// component, which are then all constrained.
+    pub fn resolve(&self, names: &[&str]) -> ResolvedConstraints {
+        let mut units: Vec<Vec<usize>> = Vec::new();
+        let mut indices = Vec::new();
+        let mut unit_of_group: HashMap<usize, usize> = HashMap::new();
+        let mut units_of_name: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (i, name) in names.iter().enumerate() {
+            let unit_idx = match self.group_of.get(*name) {
+                Some(&group_idx) => *unit_of_group.entry(group_idx).or_insert_with(|| {
+                    units.push(Vec::new());
+                    indices.push(self.groups[group_idx]);
+                    units.len() - 1
+                }),
+                None => {
+                    units.push(Vec::new());
+                    indices.push(None);
+                    units.len() - 1
+                }
+            };
+            units[unit_idx].push(i);
+            let units_of = units_of_name.entry(*name).or_default();
+            if !units_of.contains(&unit_idx) {
+                units_of.push(unit_idx);
+            }
+        }
+
+        let apart = self
+            .apart
+            .iter()
+            .map(|set| {
+                let mut set_units: Vec<usize> = set
+                    .iter()
+                    .filter_map(|name| units_of_name.get(name.as_str()))
+                    .flatten()
+                    .copied()
+                    .collect();
+                set_units.sort_unstable();
+                set_units.dedup();
+                set_units
+            })
+            .filter(|set_units| set_units.len() >= 2)
+            .collect();
+
+        ResolvedConstraints {
+            units,
+            indices,
+            apart,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONSTRAINTS: &str = r#"
+        [[together]]
+        components = ["rpm/glibc", "rpm/openssl"]
+
+        [[apart]]
+        components = ["rpm/kernel", "rpm/glibc", "rpm/firmware"]
+
+        [[layer]]
+        components = ["rpm/filesystem"]
+        index = 0
+    "#;
+
+    #[test]
+    fn test_resolve() {
+        let constraints = LayerConstraints::parse(CONSTRAINTS).unwrap();
+        // the kernel was split into two parts
+        let names = [
+            "rpm/bash",
+            "rpm/filesystem",
+            "rpm/glibc",
+            "rpm/kernel",
+            "rpm/kernel",
+            "rpm/openssl",
+        ];
+        assert_eq!(constraints.missing(&names), ["rpm/firmware"]);
+
+        let resolved = constraints.resolve(&names);
+        assert_eq!(
+            resolved,
+            ResolvedConstraints {
+                units: vec![vec![0], vec![1], vec![2, 5], vec![3], vec![4]],
+                indices: vec![None, Some(0), None, None, None],
+                // both parts of the kernel are apart from glibc
+                apart: vec![vec![2, 3, 4]],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for content in [
+            "[[together]]\ncomponents = [\"a\"]",
+            "[[apart]]\ncomponents = [\"a\", \"a\"]",
+            "[[layer]]\ncomponents = []\nindex = 0",
+            "[[layer]]\ncomponents = [\"a\"]\nindex = 0\n[[layer]]\ncomponents = [\"b\"]\nindex = 0",
+            "[[together]]\ncomponents = [\"a\", \"b\"]\n[[layer]]\ncomponents = [\"a\"]\nindex = 1",
+            "[[together]]\ncomponents = [\"a\", \"b\"]\n[[apart]]\ncomponents = [\"a\", \"b\"]",
+            "[[unknown]]\ncomponents = [\"a\", \"b\"]",
+        ] {
+            assert!(
+                LayerConstraints::parse(content).is_err(),
+                "{content:?} should be rejected"
+            );
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Debug bundles: the state of the pipeline at named checkpoints, written out
+//! when a build fails.
+//!
+//! Each phase of the build (scanning, claiming, packing, writing layers)
+//! records a summary of its results as a checkpoint. If the build then fails,
+//! the checkpoints reached and the error are written as JSON to the path given
+//! with `--debug-bundle`, which users can attach to bug reports. Checkpoints
+//! are only built when a bundle was requested.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use indexmap::IndexMap;
+use serde::Serialize;
+
+use crate::components::{Component, FileMap, FileType};
+
+/// The debug bundle of a build, shared by the phases recording checkpoints.
+/// Cloning it gives another handle to the same bundle.
+#[derive(Clone, Default)]
+pub struct DebugBundle(Option<Arc<Mutex<Bundle>>>);
+
+impl DebugBundle {
+    /// Returns the debug bundle of a build, which only records checkpoints
+    /// if it's to be written to `path`.
+    pub fn new(path: Option<&Utf8Path>) -> Self {
+        Self(path.map(|path| Arc::new(Mutex::new(Bundle::new(path)))))
+    }
+
+    /// Record the checkpoint `name`, replacing any previous one of the same
+    /// name. The data is only built if a bundle was requested.
+    pub fn checkpoint(&self, name: &'static str, data: impl FnOnce() -> serde_json::Value) {
+        if let Some(bundle) = &self.0 {
+            // SAFETY: we never panic while holding the lock
+            bundle.lock().unwrap().checkpoint(name, data());
+        }
+    }
+
+    /// Write the bundle for the build which failed with `err`, if one was
+    /// requested.
+    pub fn write(&self, err: &anyhow::Error) -> Result<()> {
+        let Some(bundle) = &self.0 else {
+            return Ok(());
+        };
+        // SAFETY: we never panic while holding the lock
+        let bundle = bundle.lock().unwrap();
+        let content = bundle.to_json(err).context("serializing debug bundle")?;
+        std::fs::write(&bundle.path, content)
+            .with_context(|| format!("writing debug bundle {}", bundle.path))?;
+        crate::diagnostics::report(&[crate::diagnostics::Diagnostic::info(format!(
+            "wrote debug bundle to {}",
+            bundle.path
+        ))]);
+        Ok(())
+    }
+}
+
+/// Summary of the files of the rootfs or of a component.
+pub fn files_summary(files: &FileMap) -> serde_json::Value {
+    let count = |file_type| files.values().filter(|f| f.file_type == file_type).count();
+    serde_json::json!({
+        "files": count(FileType::File),
+        "directories": count(FileType::Directory),
+        "symlinks": count(FileType::Symlink),
+        "special": files.values().filter(|f| f.file_type.is_special()).count(),
+        "bytes": files.values().map(|f| f.size).sum::<u64>(),
+    })
+}
+
+/// Summary of components, sorted by name.
+pub fn components_summary<'a>(
+    components: impl IntoIterator<Item = (&'a String, &'a Component)>,
+) -> serde_json::Value {
+    let mut summary: Vec<_> = components
+        .into_iter()
+        .map(|(name, component)| {
+            let mut value = files_summary(&component.files);
+            value["name"] = name.as_str().into();
+            value["stability"] = component.stability.into();
+            value["mtime-clamp"] = component.mtime_clamp.into();
+            value
+        })
+        .collect();
+    summary.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    serde_json::Value::Array(summary)
+}
+
+/// The checkpoints recorded so far.
+struct Bundle {
+    path: Utf8PathBuf,
+    checkpoints: IndexMap<&'static str, serde_json::Value>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct BundleJson<'a> {
+    version: &'static str,
+    args: Vec<String>,
+    /// The error and its causes, outermost first.
+    error: Vec<String>,
+    /// The checkpoints in the order they were first reached.
+    checkpoints: Vec<Checkpoint<'a>>,
+}
+
+#[derive(Serialize)]
+struct Checkpoint<'a> {
+    name: &'static str,
+    data: &'a serde_json::Value,
+}
+
+impl Bundle {
+    fn new(path: &Utf8Path) -> Self {
+        Self {
+            path: path.to_owned(),
+            checkpoints: IndexMap::new(),
+        }
+    }
+
+    fn checkpoint(&mut self, name: &'static str, data: serde_json::Value) {
+        self.checkpoints.insert(name, data);
+    }
+
+    fn to_json(&self, err: &anyhow::Error) -> Result<Vec<u8>> {
+        let json = BundleJson {
+            version: env!("CARGO_PKG_VERSION"),
+            args: std::env::args().collect(),
+            error: err.chain().map(|cause| cause.to_string()).collect(),
+            checkpoints: self
+                .checkpoints
+                .iter()
+                .map(|(name, data)| Checkpoint { name, data })
+                .collect(),
+        };
+        Ok(serde_json::to_vec_pretty(&json)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bundle_json() {
+        let mut bundle = Bundle::new(Utf8Path::new("/tmp/bundle.json"));
+        bundle.checkpoint("scan", serde_json::json!({"files": 1}));
+        bundle.checkpoint("claim", serde_json::json!([]));
+        bundle.checkpoint("scan", serde_json::json!({"files": 2}));
+
+        let err = anyhow::anyhow!("disk full").context("writing layer");
+        let json: serde_json::Value =
+            serde_json::from_slice(&bundle.to_json(&err).unwrap()).unwrap();
+        assert_eq!(
+            json["error"],
+            serde_json::json!(["writing layer", "disk full"])
+        );
+        // replaced checkpoints keep their place
+        let checkpoints = json["checkpoints"].as_array().unwrap();
+        let names: Vec<_> = checkpoints.iter().map(|c| c["name"].clone()).collect();
+        assert_eq!(names, ["scan", "claim"]);
+        assert_eq!(checkpoints[0]["data"]["files"], 2);
+    }
+}
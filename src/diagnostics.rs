@@ -0,0 +1,261 @@
+//! Diagnostics for things that hurt layer reuse or reproducibility.
+//!
+//! These are purely informational: they never fail the build. They're printed
+//! to stderr so they don't interfere with the OCI archive on stdout.
+
+use std::fmt;
+
+use ocidir::oci_spec::image as oci_image;
+
+use crate::ocibuilder::{Compression, LayerMediaType};
+use crate::trace::{self, LogFormat};
+
+/// Severity of a diagnostic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Something worth knowing, but not a problem.
+    Info,
+    /// Something that likely defeats reproducibility or layer reuse.
+    Warning,
+}
+
+/// A single diagnostic message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn info(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Info,
+            message: message.into(),
+        }
+    }
+
+    pub fn warning(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Info => write!(f, "info"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
+
+/// Print diagnostics to stderr, in the `--log-format`.
+pub fn report(diagnostics: &[Diagnostic]) {
+    for diagnostic in diagnostics {
+        match trace::format() {
+            LogFormat::Text => eprintln!("{diagnostic}"),
+            LogFormat::Json => eprintln!(
+                "{}",
+                trace::json_line(
+                    "diagnostic",
+                    serde_json::json!({
+                        "level": diagnostic.severity.to_string(),
+                        "message": diagnostic.message,
+                    }),
+                )
+            ),
+        }
+    }
+}
+
+/// Lint the final image config for values which break reproducibility.
+///
+/// `created` is the image creation timestamp and is used to spot env vars
+/// which embed the build time.
+pub fn lint_config(config: &oci_image::Config, created: u64) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    for var in config.env().iter().flatten() {
+        let Some((key, value)) = var.split_once('=') else {
+            continue;
+        };
+        if looks_like_timestamp(value, created) {
+            diagnostics.push(Diagnostic::warning(format!(
+                "env var {key} appears to embed a timestamp ({value}); \
+                 this breaks image config reproducibility"
+            )));
+        }
+    }
+
+    diagnostics
+}
+
+/// Lint the image built against the previous build of it.
+///
+/// Spots rebuilds where the rootfs is identical, i.e. all the layers are the
+/// same, and only the config changed.
+pub fn lint_rebuild(
+    previous: &oci_image::ImageConfiguration,
+    image: &oci_image::ImageConfiguration,
+) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    if previous.rootfs().diff_ids() == image.rootfs().diff_ids()
+        && previous.config() != image.config()
+    {
+        // The config blob isn't part of any layer, so tweaking it (e.g. adding
+        // labels) doesn't invalidate layers. Users often assume otherwise.
+        diagnostics.push(Diagnostic::info(
+            "only the image config changed since the previous image; \
+             all layers remain reusable, only the config blob changes",
+        ));
+    }
+
+    diagnostics
+}
+
+/// Lint the layer media types against what consumers are known to accept.
+pub fn lint_layer_media_type(
+    media_type: LayerMediaType,
+    compression: Compression,
+) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    if media_type == LayerMediaType::Docker {
+        // The OCI compatibility matrix allows this, but strict consumers only
+        // look at OCI media types in OCI manifests.
+        diagnostics.push(Diagnostic::info(
+            "using Docker layer media types in an OCI manifest; \
+             some strict OCI consumers may reject the image",
+        ));
+        if let Compression::None = compression {
+            diagnostics.push(Diagnostic::warning(format!(
+                "{} isn't part of the Docker image spec and is rejected by \
+                 some registries; consider --compressed",
+                crate::tar::DOCKER_LAYER_MEDIA_TYPE
+            )));
+        }
+    }
+
+    diagnostics
+}
+
+/// Earliest epoch we consider a plausible build timestamp (2000-01-01).
+const MIN_PLAUSIBLE_EPOCH: u64 = 946_684_800;
+
+/// Heuristically check whether a value looks like a build timestamp.
+///
+/// This matches either a Unix epoch between 2000 and a year past `created`,
+/// or a value starting with an ISO 8601 date-time (e.g. `2024-01-02T03:04`).
+fn looks_like_timestamp(value: &str, created: u64) -> bool {
+    let value = value.trim();
+    if value.len() >= 9
+        && value.len() <= 10
+        && let Ok(epoch) = value.parse::<u64>()
+    {
+        let max = created.saturating_add(365 * crate::components::SECS_PER_DAY);
+        return (MIN_PLAUSIBLE_EPOCH..=max).contains(&epoch);
+    }
+
+    // YYYY-MM-DDTHH:MM or YYYY-MM-DD HH:MM
+    let bytes = value.as_bytes();
+    if bytes.len() < 16 {
+        return false;
+    }
+    let digits_at = |idxs: &[usize]| idxs.iter().all(|&i| bytes[i].is_ascii_digit());
+    digits_at(&[0, 1, 2, 3, 5, 6, 8, 9, 11, 12, 14, 15])
+        && bytes[4] == b'-'
+        && bytes[7] == b'-'
+        && (bytes[10] == b'T' || bytes[10] == b' ')
+        && bytes[13] == b':'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_with_env(env: &[&str]) -> oci_image::Config {
+        oci_image::ConfigBuilder::default()
+            .env(env.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_lint_layer_media_type() {
+        assert!(lint_layer_media_type(LayerMediaType::Oci, Compression::None).is_empty());
+        assert!(lint_layer_media_type(LayerMediaType::Oci, Compression::Gzip(6)).is_empty());
+
+        let diagnostics = lint_layer_media_type(LayerMediaType::Docker, Compression::Gzip(6));
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Info);
+
+        let diagnostics = lint_layer_media_type(LayerMediaType::Docker, Compression::None);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[1].severity, Severity::Warning);
+    }
+
+    #[test]
+    fn test_looks_like_timestamp() {
+        let created = 1_700_000_000;
+        assert!(looks_like_timestamp("1699999999", created));
+        assert!(looks_like_timestamp("2024-01-02T03:04:05Z", created));
+        assert!(looks_like_timestamp("2024-01-02 03:04", created));
+        assert!(!looks_like_timestamp("/usr/bin:/bin", created));
+        assert!(!looks_like_timestamp("12345", created));
+        assert!(!looks_like_timestamp("2024-01-02", created));
+        // way past the build time; probably not a timestamp
+        assert!(!looks_like_timestamp("9999999999", created));
+    }
+
+    fn image_config(env: &[&str], diff_ids: &[&str]) -> oci_image::ImageConfiguration {
+        let mut image = oci_image::ImageConfiguration::default();
+        image.set_config(Some(config_with_env(env)));
+        let mut rootfs = image.rootfs().clone();
+        rootfs.set_diff_ids(diff_ids.iter().map(|s| s.to_string()).collect());
+        image.set_rootfs(rootfs);
+        image
+    }
+
+    #[test]
+    fn test_lint_rebuild_unchanged() {
+        let image = image_config(&["PATH=/usr/bin"], &["sha256:aa"]);
+        let diagnostics = lint_rebuild(&image, &image);
+        assert!(diagnostics.is_empty(), "{diagnostics:?}");
+    }
+
+    #[test]
+    fn test_lint_rebuild_config_only() {
+        let previous = image_config(&["PATH=/usr/bin"], &["sha256:aa"]);
+        let image = image_config(&["PATH=/usr/bin", "FOO=bar"], &["sha256:aa"]);
+        let diagnostics = lint_rebuild(&previous, &image);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Info);
+        assert!(diagnostics[0].message.contains("layers remain reusable"));
+    }
+
+    #[test]
+    fn test_lint_rebuild_rootfs_changed() {
+        let previous = image_config(&["PATH=/usr/bin"], &["sha256:aa"]);
+        let image = image_config(&["PATH=/usr/bin", "FOO=bar"], &["sha256:bb"]);
+        let diagnostics = lint_rebuild(&previous, &image);
+        assert!(diagnostics.is_empty(), "{diagnostics:?}");
+    }
+
+    #[test]
+    fn test_lint_config_timestamp_env() {
+        let config = config_with_env(&["PATH=/usr/bin", "BUILD_DATE=2023-11-14T22:13:20Z"]);
+        let diagnostics = lint_config(&config, 1_700_000_000);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert!(diagnostics[0].message.contains("BUILD_DATE"));
+    }
+}
@@ -0,0 +1,749 @@
+//! Reading erofs images given as the rootfs, without mounting them.
+//!
+//! Only uncompressed images are supported, with their files stored in plain
+//! blocks, inline after their inode, or in chunks as composefs images do. The
+//! on-disk format is described in the kernel's `fs/erofs/erofs_fs.h`.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::components::{FileInfo, FileType, XattrInterner};
+use crate::rootfs_image::{Fields, ImageEntry, VisitEntry, decode_dev, entry_name};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 128;
+const MAGIC: u32 = 0xE0F5_E1E2;
+
+/// Incompatible features we can read images with; the others move the
+/// metadata around.
+const KNOWN_FEATURES: u32 = 0x7f;
+
+/// Inodes are addressed by their number of 32-byte slots from the start of
+/// the metadata.
+const INODE_SLOT_SIZE: u64 = 32;
+const COMPACT_INODE_SIZE: usize = 32;
+const EXTENDED_INODE_SIZE: usize = 64;
+
+/// Data layouts of inodes.
+const FLAT_PLAIN: u16 = 0;
+const COMPRESSED_FULL: u16 = 1;
+const FLAT_INLINE: u16 = 2;
+const COMPRESSED_COMPACT: u16 = 3;
+const CHUNK_BASED: u16 = 4;
+
+/// Chunk format flags of chunk-based inodes.
+const CHUNK_BLOCK_BITS: u16 = 0x1f;
+const CHUNK_INDEXES: u16 = 0x20;
+
+/// Block address of holes.
+const NULL_ADDR: u32 = u32::MAX;
+
+const XATTR_HEADER_SIZE: usize = 12;
+const DIRENT_SIZE: usize = 12;
+
+/// Read the erofs image `file`, calling `visit` for each of its entries.
+pub(crate) fn read_image(
+    file: &File,
+    interner: &mut XattrInterner,
+    visit: &mut VisitEntry,
+) -> Result<()> {
+    let image = Image::open(file).context("reading erofs superblock")?;
+    let root = image.inode(image.root_nid).context("reading root inode")?;
+    anyhow::ensure!(
+        root.file_type == Some(FileType::Directory),
+        "the root of the image isn't a directory"
+    );
+    image.walk(root, interner, visit)
+}
+
+/// An inode, with the fields we need.
+struct Inode {
+    nid: u64,
+    /// `None` for sockets, which tar can't represent.
+    file_type: Option<FileType>,
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    mtime: i64,
+    size: u64,
+    layout: u16,
+    /// Block address, device number or chunk format, depending on the type
+    /// and layout.
+    raw: u32,
+    xattrs: Vec<(String, Vec<u8>)>,
+    /// Position of what follows the inode and its xattrs: the inline tail or
+    /// the chunk indexes.
+    tail: u64,
+}
+
+/// Part of the content of a file.
+enum Extent {
+    Data { position: u64, len: u64 },
+    Hole(u64),
+}
+
+/// An opened image.
+struct Image<'a> {
+    file: &'a File,
+    len: u64,
+    block_bits: u32,
+    root_nid: u64,
+    /// Position of the inodes.
+    meta_start: u64,
+    /// Position of the shared xattrs.
+    xattr_start: u64,
+    /// The base of the mtimes of compact inodes.
+    epoch: i64,
+}
+
+impl<'a> Image<'a> {
+    fn open(file: &'a File) -> Result<Self> {
+        let mut data = [0u8; SUPERBLOCK_SIZE];
+        file.read_exact_at(&mut data, SUPERBLOCK_OFFSET)?;
+        let mut fields = Fields::new(&data);
+        anyhow::ensure!(fields.u32()? == MAGIC, "not an erofs image");
+        let _checksum = fields.u32()?;
+        let _compat_features = fields.u32()?;
+        let block_bits = fields.u8()?.into();
+        anyhow::ensure!(
+            (9..=16).contains(&block_bits),
+            "invalid block size 2^{block_bits}"
+        );
+        let _extension_slots = fields.u8()?;
+        let root_nid = fields.u16()?.into();
+        let _inode_count = fields.u64()?;
+        let epoch = fields.u64()? as i64;
+        let _epoch_nsec = fields.u32()?;
+        let _block_count = fields.u32()?;
+        let meta_block = fields.u32()?;
+        let xattr_block = fields.u32()?;
+        let _uuid = fields.bytes(16)?;
+        let _volume_name = fields.bytes(16)?;
+        let features = fields.u32()?;
+        anyhow::ensure!(
+            features & !KNOWN_FEATURES == 0,
+            "unsupported features {:#x}",
+            features & !KNOWN_FEATURES
+        );
+        let _compression_algorithms = fields.u16()?;
+        let _extra_devices = fields.u16()?;
+        let _device_table = fields.u16()?;
+        let dir_block_bits = fields.u8()?;
+        anyhow::ensure!(
+            dir_block_bits == 0,
+            "directory blocks larger than blocks aren't supported"
+        );
+        let len = file.metadata().context("getting image size")?.len();
+        Ok(Self {
+            file,
+            len,
+            block_bits,
+            root_nid,
+            meta_start: u64::from(meta_block) << block_bits,
+            xattr_start: u64::from(xattr_block) << block_bits,
+            epoch,
+        })
+    }
+
+    fn read_at(&self, position: u64, len: usize) -> Result<Vec<u8>> {
+        anyhow::ensure!(
+            position.saturating_add(len as u64) <= self.len,
+            "reading past the end of the image"
+        );
+        let mut data = vec![0u8; len];
+        self.file
+            .read_exact_at(&mut data, position)
+            .with_context(|| format!("reading image at {position}"))?;
+        Ok(data)
+    }
+
+    fn inode(&self, nid: u64) -> Result<Inode> {
+        let position = nid
+            .checked_mul(INODE_SLOT_SIZE)
+            .and_then(|offset| offset.checked_add(self.meta_start))
+            .with_context(|| format!("invalid inode number {nid}"))?;
+        let mut data = self.read_at(position, COMPACT_INODE_SIZE)?;
+        let format = u16::from_le_bytes([data[0], data[1]]);
+        let extended = format & 1 != 0;
+        if extended {
+            data = self.read_at(position, EXTENDED_INODE_SIZE)?;
+        }
+        let mut fields = Fields::new(&data);
+        let _format = fields.u16()?;
+        let xattr_count = fields.u16()?;
+        let mode = fields.u16()?;
+        let (size, raw, uid, gid, mtime) = if extended {
+            let _reserved = fields.u16()?;
+            let size = fields.u64()?;
+            let raw = fields.u32()?;
+            let _ino = fields.u32()?;
+            let uid = fields.u32()?;
+            let gid = fields.u32()?;
+            let mtime = fields.u64()? as i64;
+            (size, raw, uid, gid, mtime)
+        } else {
+            let _nlink = fields.u16()?;
+            let size = fields.u32()?.into();
+            // relative to the epoch in the superblock
+            let mtime = self.epoch.saturating_add(fields.u32()?.into());
+            let raw = fields.u32()?;
+            let _ino = fields.u32()?;
+            let uid = fields.u16()?.into();
+            let gid = fields.u16()?.into();
+            (size, raw, uid, gid, mtime)
+        };
+        let file_type = match u32::from(mode) & libc::S_IFMT {
+            libc::S_IFDIR => Some(FileType::Directory),
+            libc::S_IFREG => Some(FileType::File),
+            libc::S_IFLNK => Some(FileType::Symlink),
+            libc::S_IFBLK => Some(FileType::BlockDevice),
+            libc::S_IFCHR => Some(FileType::CharDevice),
+            libc::S_IFIFO => Some(FileType::Fifo),
+            libc::S_IFSOCK => None,
+            _ => anyhow::bail!("invalid mode {mode:#o}"),
+        };
+
+        let xattr_start = position + data.len() as u64;
+        let xattr_size = match xattr_count {
+            0 => 0,
+            n => XATTR_HEADER_SIZE + (usize::from(n) - 1) * 4,
+        };
+        let xattrs = if xattr_size == 0 {
+            Vec::new()
+        } else {
+            self.xattrs(&self.read_at(xattr_start, xattr_size)?)
+                .context("reading xattrs")?
+        };
+        Ok(Inode {
+            nid,
+            file_type,
+            mode,
+            uid,
+            gid,
+            mtime,
+            size,
+            layout: (format >> 1) & 0x7,
+            raw,
+            xattrs,
+            tail: xattr_start + xattr_size as u64,
+        })
+    }
+
+    /// Parse the xattrs of an inode, which are stored after a header listing
+    /// the ones shared with other inodes.
+    fn xattrs(&self, data: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut fields = Fields::new(data);
+        let _name_filter = fields.u32()?;
+        let shared_count = fields.u8()?;
+        let _reserved = fields.bytes(7)?;
+        let mut xattrs = Vec::new();
+        for _ in 0..shared_count {
+            let id = fields.u32()?;
+            let position = self.xattr_start + u64::from(id) * 4;
+            let header = self.read_at(position, 4)?;
+            let (name_len, value_len) = (
+                usize::from(header[0]),
+                u16::from_le_bytes([header[2], header[3]]),
+            );
+            let entry = self.read_at(position, 4 + name_len + usize::from(value_len))?;
+            xattrs.extend(xattr(&mut Fields::new(&entry))?);
+        }
+        while !fields.is_empty() {
+            xattrs.extend(xattr(&mut fields)?);
+        }
+        Ok(xattrs)
+    }
+
+    /// Returns the parts of the content of `inode`.
+    fn extents(&self, inode: &Inode) -> Result<Vec<Extent>> {
+        let block_size = 1u64 << self.block_bits;
+        let size = inode.size;
+        let start = u64::from(inode.raw) << self.block_bits;
+        let mut extents = Vec::new();
+        match inode.layout {
+            _ if size == 0 => {}
+            FLAT_PLAIN if inode.raw == NULL_ADDR => extents.push(Extent::Hole(size)),
+            FLAT_PLAIN => extents.push(Extent::Data {
+                position: start,
+                len: size,
+            }),
+            // the last block is stored right after the inode, in the same block
+            FLAT_INLINE => {
+                let len = (size.div_ceil(block_size) - 1) * block_size;
+                let tail_len = size - len;
+                anyhow::ensure!(
+                    inode.tail % block_size + tail_len <= block_size,
+                    "inline data crosses a block boundary"
+                );
+                if len > 0 {
+                    extents.push(Extent::Data {
+                        position: start,
+                        len,
+                    });
+                }
+                extents.push(Extent::Data {
+                    position: inode.tail,
+                    len: tail_len,
+                });
+            }
+            CHUNK_BASED => {
+                let format = inode.raw as u16;
+                let chunk_bits = self.block_bits + u32::from(format & CHUNK_BLOCK_BITS);
+                let chunk_size = 1u64 << chunk_bits;
+                let index_size = if format & CHUNK_INDEXES != 0 { 8 } else { 4 };
+                let count = size.div_ceil(chunk_size);
+                let indexes = self.read_at(
+                    inode.tail.next_multiple_of(index_size),
+                    usize::try_from(count * index_size)?,
+                )?;
+                for (i, index) in indexes.chunks_exact(index_size as usize).enumerate() {
+                    let mut fields = Fields::new(index);
+                    let block = if index_size == 8 {
+                        let _reserved = fields.u16()?;
+                        let device = fields.u16()?;
+                        anyhow::ensure!(device == 0, "chunks on other devices aren't supported");
+                        fields.u32()?
+                    } else {
+                        fields.u32()?
+                    };
+                    let len = chunk_size.min(size - i as u64 * chunk_size);
+                    extents.push(if block == NULL_ADDR {
+                        Extent::Hole(len)
+                    } else {
+                        Extent::Data {
+                            position: u64::from(block) << self.block_bits,
+                            len,
+                        }
+                    });
+                }
+            }
+            COMPRESSED_FULL | COMPRESSED_COMPACT => {
+                anyhow::bail!("compressed files aren't supported; use `mkfs.erofs` without -z")
+            }
+            layout => anyhow::bail!("unknown data layout {layout}"),
+        }
+        Ok(extents)
+    }
+
+    /// Read the whole content of the directory or symlink `inode`.
+    fn read_content(&self, inode: &Inode) -> Result<Vec<u8>> {
+        anyhow::ensure!(inode.size <= self.len, "invalid size {}", inode.size);
+        let mut data = Vec::new();
+        Content::new(self.file, self.extents(inode)?).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Returns the names of the entries of the directory `inode`, with their
+    /// inode numbers.
+    fn dir_entries(&self, inode: &Inode) -> Result<Vec<(Vec<u8>, u64)>> {
+        let data = self.read_content(inode)?;
+        let mut entries = Vec::new();
+        for block in data.chunks(1 << self.block_bits) {
+            // the names follow the entries, the first of which tells where
+            let mut fields = Fields::new(block);
+            let _nid = fields.u64()?;
+            let names_start = usize::from(fields.u16()?);
+            anyhow::ensure!(
+                names_start >= DIRENT_SIZE
+                    && names_start % DIRENT_SIZE == 0
+                    && names_start <= block.len(),
+                "invalid directory block"
+            );
+            let count = names_start / DIRENT_SIZE;
+            let mut fields = Fields::new(block);
+            let dirents = (0..count)
+                .map(|_| {
+                    let nid = fields.u64()?;
+                    let name_start = usize::from(fields.u16()?);
+                    let _file_type = fields.u16()?;
+                    Ok((nid, name_start))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            for (i, &(nid, name_start)) in dirents.iter().enumerate() {
+                // the last name ends at the end of the block, or at padding
+                let name_end = match dirents.get(i + 1) {
+                    Some(&(_, next)) => next,
+                    None => block[name_start.min(block.len())..]
+                        .iter()
+                        .position(|&b| b == 0)
+                        .map_or(block.len(), |len| name_start + len),
+                };
+                let name = block
+                    .get(name_start..name_end)
+                    .context("invalid directory block")?;
+                if name != b"." && name != b".." {
+                    entries.push((name.to_vec(), nid));
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Visit the root directory, described by `root`, and everything under
+    /// it, parents first.
+    ///
+    /// Like for squashfs, the directories to visit are kept on the heap
+    /// rather than recursing, and the nids of those visited are kept to stop
+    /// on loops.
+    fn walk(
+        &self,
+        root: Inode,
+        interner: &mut XattrInterner,
+        visit: &mut VisitEntry,
+    ) -> Result<()> {
+        let mut dirs = HashSet::from([self.root_nid]);
+        let mut stack = vec![(Utf8PathBuf::from("/"), root)];
+        while let Some((path, inode)) = stack.pop() {
+            let entries = self
+                .dir_entries(&inode)
+                .with_context(|| format!("reading directory {path}"))?;
+            self.emit(&path, inode, interner, visit)?;
+            let mut subdirs = Vec::new();
+            for (name, nid) in entries {
+                let child_path = path.join(entry_name(&path, &name)?);
+                let child = self
+                    .inode(nid)
+                    .with_context(|| format!("reading inode of {child_path}"))?;
+                if child.file_type == Some(FileType::Directory) {
+                    anyhow::ensure!(dirs.insert(nid), "{child_path}: directory loop");
+                    subdirs.push((child_path, child));
+                } else {
+                    self.emit(&child_path, child, interner, visit)?;
+                }
+            }
+            stack.extend(subdirs.into_iter().rev());
+        }
+        Ok(())
+    }
+
+    /// Call `visit` for the entry at `path`, described by `inode`.
+    fn emit(
+        &self,
+        path: &Utf8Path,
+        inode: Inode,
+        interner: &mut XattrInterner,
+        visit: &mut VisitEntry,
+    ) -> Result<()> {
+        let Some(file_type) = inode.file_type else {
+            return Ok(());
+        };
+        let mtime =
+            u64::try_from(inode.mtime).with_context(|| format!("{path}: mtime is before 1970"))?;
+        let mut info = FileInfo {
+            file_type,
+            mode: inode.mode.into(),
+            size: 0,
+            uid: inode.uid,
+            gid: inode.gid,
+            mtime,
+            ino: 0,
+            nlink: 1,
+            xattrs: interner.intern(inode.xattrs.clone()),
+            extra: None,
+        };
+        let mut symlink_target = None;
+        let mut content: Box<dyn Read + '_> = Box::new(std::io::empty());
+        match file_type {
+            FileType::File => {
+                info.size = inode.size;
+                let extents = self
+                    .extents(&inode)
+                    .with_context(|| format!("reading {path}"))?;
+                content = Box::new(Content::new(self.file, extents));
+            }
+            FileType::Symlink => {
+                anyhow::ensure!(inode.size <= 4096, "symlink {path} target is too long");
+                let target = self
+                    .read_content(&inode)
+                    .with_context(|| format!("reading symlink {path}"))?;
+                info.size = target.len() as u64;
+                let target = String::from_utf8(target)
+                    .with_context(|| format!("symlink {path} target is not UTF-8"))?;
+                symlink_target = Some(Utf8PathBuf::from(target));
+            }
+            FileType::BlockDevice | FileType::CharDevice => info.set_rdev(decode_dev(inode.raw)),
+            FileType::Directory | FileType::Fifo => {}
+        }
+        let entry = ImageEntry {
+            path: path.to_owned(),
+            info,
+            inode: inode.nid,
+            symlink_target,
+        };
+        visit(entry, &mut content)
+    }
+}
+
+/// Parse the xattr at the start of `fields`, padded to 4 bytes, or `None` if
+/// it's padding.
+fn xattr(fields: &mut Fields) -> Result<Option<(String, Vec<u8>)>> {
+    let name_len = usize::from(fields.u8()?);
+    let name_index = fields.u8()?;
+    let value_len = usize::from(fields.u16()?);
+    let name = fields.bytes(name_len)?;
+    let value = fields.bytes(value_len)?;
+    let padding = (4 + name_len + value_len).next_multiple_of(4) - (4 + name_len + value_len);
+    if !fields.is_empty() {
+        fields.bytes(padding)?;
+    }
+    let prefix = match name_index {
+        0 => return Ok(None),
+        1 => "user.",
+        2 => "system.posix_acl_access",
+        3 => "system.posix_acl_default",
+        4 => "trusted.",
+        6 => "security.",
+        i if i & 0x80 != 0 => anyhow::bail!("long xattr name prefixes aren't supported"),
+        i => anyhow::bail!("unknown xattr name index {i}"),
+    };
+    let name =
+        String::from_utf8([prefix.as_bytes(), name].concat()).context("xattr name is not UTF-8")?;
+    Ok(Some((name, value.to_vec())))
+}
+
+/// The content of a file, read extent by extent.
+struct Content<'a> {
+    file: &'a File,
+    extents: std::vec::IntoIter<Extent>,
+    current: Option<Extent>,
+}
+
+impl<'a> Content<'a> {
+    fn new(file: &'a File, extents: Vec<Extent>) -> Self {
+        Self {
+            file,
+            extents: extents.into_iter(),
+            current: None,
+        }
+    }
+}
+
+impl Read for Content<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let extent = match &mut self.current {
+                Some(Extent::Data { len: 0, .. } | Extent::Hole(0)) | None => {
+                    match self.extents.next() {
+                        Some(extent) => {
+                            self.current = Some(extent);
+                            continue;
+                        }
+                        None => return Ok(0),
+                    }
+                }
+                Some(extent) => extent,
+            };
+            return match extent {
+                Extent::Data { position, len } => {
+                    let n = (buf.len() as u64).min(*len) as usize;
+                    let n = self.file.read_at(&mut buf[..n], *position)?;
+                    if n == 0 {
+                        return Err(std::io::ErrorKind::UnexpectedEof.into());
+                    }
+                    *position += n as u64;
+                    *len -= n as u64;
+                    Ok(n)
+                }
+                Extent::Hole(len) => {
+                    let n = (buf.len() as u64).min(*len) as usize;
+                    buf[..n].fill(0);
+                    *len -= n as u64;
+                    Ok(n)
+                }
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    const BLOCK_SIZE: usize = 4096;
+
+    /// A compact inode, followed by `data`.
+    fn inode(layout: u16, mode: u32, raw: u32, size: usize, data: &[u8]) -> Vec<u8> {
+        let mut inode = Vec::new();
+        inode.extend((layout << 1).to_le_bytes());
+        // no xattrs
+        inode.extend(0u16.to_le_bytes());
+        inode.extend((mode as u16).to_le_bytes());
+        // nlink
+        inode.extend(1u16.to_le_bytes());
+        inode.extend((size as u32).to_le_bytes());
+        // mtime, raw, ino, uid, gid and reserved
+        inode.extend(0u32.to_le_bytes());
+        inode.extend(raw.to_le_bytes());
+        inode.extend([0u8; 12]);
+        inode.extend(data);
+        inode
+    }
+
+    fn file(layout: u16, content: &[u8]) -> Vec<u8> {
+        inode(layout, libc::S_IFREG | 0o644, 0, content.len(), content)
+    }
+
+    /// A directory listing `entries` inline.
+    fn dir(entries: &[(&str, u64)]) -> Vec<u8> {
+        let names_start = entries.len() * DIRENT_SIZE;
+        let mut dirents = Vec::new();
+        let mut names: Vec<u8> = Vec::new();
+        for (name, nid) in entries {
+            dirents.extend(nid.to_le_bytes());
+            dirents.extend(((names_start + names.len()) as u16).to_le_bytes());
+            // file type and reserved
+            dirents.extend([0u8; 2]);
+            names.extend(name.as_bytes());
+        }
+        dirents.extend(names);
+        inode(
+            FLAT_INLINE,
+            libc::S_IFDIR | 0o755,
+            0,
+            dirents.len(),
+            &dirents,
+        )
+    }
+
+    /// Read an image with the root at nid 0, `inodes` at their nid and the
+    /// given incompatible features, and return the paths of its entries.
+    fn read(features: u32, inodes: &[(u64, Vec<u8>)]) -> Result<Vec<Utf8PathBuf>> {
+        let end = inodes
+            .iter()
+            .map(|(nid, inode)| BLOCK_SIZE + *nid as usize * 32 + inode.len())
+            .max()
+            .unwrap_or(0);
+        let mut data = vec![0u8; end.max(3 * BLOCK_SIZE).next_multiple_of(BLOCK_SIZE)];
+        let superblock = &mut data[SUPERBLOCK_OFFSET as usize..];
+        superblock[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        superblock[12] = BLOCK_SIZE.trailing_zeros() as u8;
+        // the inodes are in the second block
+        superblock[40..44].copy_from_slice(&1u32.to_le_bytes());
+        superblock[80..84].copy_from_slice(&features.to_le_bytes());
+        for (nid, inode) in inodes {
+            let position = BLOCK_SIZE + *nid as usize * 32;
+            data[position..position + inode.len()].copy_from_slice(inode);
+        }
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&data).unwrap();
+
+        let mut paths = Vec::new();
+        read_image(
+            &file,
+            &mut XattrInterner::default(),
+            &mut |entry, content| {
+                std::io::copy(content, &mut std::io::sink())?;
+                paths.push(entry.path);
+                Ok(())
+            },
+        )?;
+        Ok(paths)
+    }
+
+    fn read_err(features: u32, inodes: &[(u64, Vec<u8>)]) -> String {
+        match read(features, inodes) {
+            Ok(paths) => panic!("read {paths:?}"),
+            Err(err) => format!("{err:#}"),
+        }
+    }
+
+    #[test]
+    fn test_read_image() {
+        let inodes = [
+            (0, dir(&[("file", 2), ("sub", 4)])),
+            (2, file(FLAT_INLINE, b"hello")),
+            (4, dir(&[("empty", 6)])),
+            (6, file(FLAT_PLAIN, b"")),
+        ];
+        assert_eq!(
+            read(0, &inodes).unwrap(),
+            ["/", "/file", "/sub", "/sub/empty"]
+        );
+        assert!(read(KNOWN_FEATURES, &inodes).is_ok());
+
+        let err = read_err(0x100, &inodes);
+        assert!(err.contains("unsupported features 0x100"), "{err}");
+    }
+
+    #[test]
+    fn test_read_image_invalid_files() {
+        let with_file = |inode| [(0, dir(&[("file", 2)])), (2, inode)];
+        for layout in [COMPRESSED_FULL, COMPRESSED_COMPACT] {
+            let err = read_err(0, &with_file(file(layout, b"hello")));
+            assert!(err.contains("compressed files aren't supported"), "{err}");
+        }
+        let err = read_err(0, &with_file(file(5, b"hello")));
+        assert!(err.contains("unknown data layout 5"), "{err}");
+
+        // near the end of the block, with its data spilling into the next one
+        let err = read_err(
+            0,
+            &[
+                (0, dir(&[("file", 126)])),
+                (126, file(FLAT_INLINE, &[1; 64])),
+            ],
+        );
+        assert!(
+            err.contains("inline data crosses a block boundary"),
+            "{err}"
+        );
+
+        let err = read_err(0, &with_file(inode(FLAT_PLAIN, 0o170000, 0, 0, &[])));
+        assert!(err.contains("invalid mode"), "{err}");
+    }
+
+    #[test]
+    fn test_read_image_invalid_dirs() {
+        for nid in [1 << 40, u64::MAX] {
+            assert!(read(0, &[(0, dir(&[("file", nid)]))]).is_err());
+        }
+
+        let err = read_err(0, &[(0, dir(&[("sub", 2)])), (2, dir(&[("sub", 2)]))]);
+        assert!(err.contains("/sub/sub: directory loop"), "{err}");
+        let err = read_err(0, &[(0, dir(&[("sub", 2)])), (2, dir(&[("root", 0)]))]);
+        assert!(err.contains("/sub/root: directory loop"), "{err}");
+
+        for name in ["", "a/b"] {
+            assert!(read(0, &[(0, dir(&[(name, 2)])), (2, file(FLAT_PLAIN, b""))]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_read_image_deep() {
+        // walked without recursing, however deep
+        let depth = 10_000;
+        let inodes = (0..depth)
+            .map(|i| (2 * i, dir(&[("a", 2 * (i + 1))])))
+            .chain([(2 * depth, dir(&[]))])
+            .collect::<Vec<_>>();
+        let paths = read(0, &inodes).unwrap();
+        assert_eq!(paths.len() as u64, depth + 1);
+    }
+
+    #[test]
+    fn test_read_image_truncated() {
+        let image = std::fs::read(
+            Utf8Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/rootfs.erofs"),
+        )
+        .unwrap();
+        for len in (0..image.len()).step_by(61) {
+            let mut file = tempfile::tempfile().unwrap();
+            file.write_all(&image[..len]).unwrap();
+            let result = read_image(&file, &mut XattrInterner::default(), &mut |_, content| {
+                std::io::copy(content, &mut std::io::sink())?;
+                Ok(())
+            });
+            assert!(result.is_err(), "truncated to {len}");
+        }
+    }
+}
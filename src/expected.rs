@@ -0,0 +1,149 @@
+//! Checking the rootfs against a declared list of expected paths.
+//!
+//! This allows using chunkah as a final gate against build artifacts leaking
+//! into images: the build fails if a path shows up that isn't listed, or if a
+//! listed path is missing.
+
+use std::collections::BTreeSet;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+/// Maximum number of paths of each kind listed in the error.
+const MAX_REPORTED_PATHS: usize = 20;
+
+/// The set of paths expected in the image.
+#[derive(Debug)]
+pub struct ExpectedPaths {
+    /// Paths listed explicitly.
+    paths: BTreeSet<Utf8PathBuf>,
+    /// Parent directories of listed paths, which are implicitly expected.
+    parents: BTreeSet<Utf8PathBuf>,
+}
+
+impl ExpectedPaths {
+    /// Load the expected paths from a file.
+    pub fn load(path: &Utf8Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path).with_context(|| format!("reading {path}"))?;
+        Self::parse(&content).with_context(|| format!("parsing {path}"))
+    }
+
+    /// Parse expected paths, one absolute path per line.
+    ///
+    /// Empty lines and lines starting with `#` are ignored. Parent directories
+    /// of listed paths don't need to be listed, but empty directories do.
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut paths = BTreeSet::new();
+        let mut parents = BTreeSet::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let path = Utf8Path::new(line);
+            anyhow::ensure!(
+                path.is_absolute(),
+                "line {}: path must be absolute: {line}",
+                i + 1
+            );
+            // this also normalizes away trailing slashes
+            let path: Utf8PathBuf = path.components().collect();
+            parents.extend(path.ancestors().skip(1).map(Utf8Path::to_owned));
+            paths.insert(path);
+        }
+        Ok(Self { paths, parents })
+    }
+
+    fn contains(&self, path: &Utf8Path) -> bool {
+        self.paths.contains(path) || self.parents.contains(path)
+    }
+
+    /// Check that the paths of the scanned files match the expected paths
+    /// exactly.
+    pub fn check(&self, files: &BTreeSet<&Utf8Path>) -> Result<()> {
+        let unexpected: Vec<&Utf8Path> = files
+            .iter()
+            .copied()
+            .filter(|path| path.as_str() != "/" && !self.contains(path))
+            .collect();
+        let missing: Vec<&Utf8Path> = self
+            .paths
+            .iter()
+            .map(Utf8PathBuf::as_path)
+            .filter(|path| !files.contains(path))
+            .collect();
+
+        if unexpected.is_empty() && missing.is_empty() {
+            return Ok(());
+        }
+
+        let mut report = String::from("rootfs doesn't match the expected paths");
+        for (kind, paths) in [("unexpected", &unexpected), ("missing", &missing)] {
+            if paths.is_empty() {
+                continue;
+            }
+            report.push_str(&format!("\n{} {kind} path(s):", paths.len()));
+            for path in paths.iter().take(MAX_REPORTED_PATHS) {
+                report.push_str(&format!("\n  {path}"));
+            }
+            if paths.len() > MAX_REPORTED_PATHS {
+                report.push_str(&format!(
+                    "\n  ... and {} more",
+                    paths.len() - MAX_REPORTED_PATHS
+                ));
+            }
+        }
+        anyhow::bail!(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths<'a>(paths: &[&'a str]) -> BTreeSet<&'a Utf8Path> {
+        paths.iter().map(|p| Utf8Path::new(*p)).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let expected =
+            ExpectedPaths::parse("# comment\n\n/usr/bin/app\n  /etc/app.conf  \n/var/empty/\n")
+                .unwrap();
+        assert!(expected.contains(Utf8Path::new("/usr/bin/app")));
+        assert!(expected.contains(Utf8Path::new("/usr/bin")));
+        assert!(expected.contains(Utf8Path::new("/usr")));
+        assert!(expected.contains(Utf8Path::new("/etc/app.conf")));
+        assert!(expected.contains(Utf8Path::new("/var/empty")));
+        assert!(!expected.contains(Utf8Path::new("/usr/lib")));
+
+        let err = ExpectedPaths::parse("/usr\nrelative/path\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2: path must be absolute: relative/path"
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let expected = ExpectedPaths::parse("/usr/bin/app\n/etc/app.conf\n").unwrap();
+        let files = paths(&[
+            "/",
+            "/etc",
+            "/etc/app.conf",
+            "/usr",
+            "/usr/bin",
+            "/usr/bin/app",
+        ]);
+        expected.check(&files).unwrap();
+
+        let files = paths(&["/etc", "/etc/app.conf", "/tmp", "/tmp/build.log"]);
+        let err = expected.check(&files).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "rootfs doesn't match the expected paths\n\
+             2 unexpected path(s):\n  /tmp\n  /tmp/build.log\n\
+             1 missing path(s):\n  /usr/bin/app"
+        );
+    }
+}
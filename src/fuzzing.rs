@@ -0,0 +1,242 @@
+//! Entry points for the fuzz targets in `fuzz/`.
+//!
+//! These wrap the parsers which consume untrusted image contents or user
+//! input. They must never panic, except for the checks they do themselves.
+
+use std::fs::File;
+use std::io::{Read, Write};
+
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+
+use crate::components::alpm::{LocalAlpmDbFile, Mtree};
+use crate::components::{FileInfo, FileType, XattrInterner};
+use crate::overlay::tar_entries;
+use crate::rootfs_image::VisitEntry;
+use crate::scan::{PruneAction, PrunePath, check_prune, parse_prune_path};
+
+/// Parse an ALPM local database file (`desc` or `files`) and query it.
+pub fn alpm_db_file(data: &str) {
+    let Ok(file) = data.parse::<LocalAlpmDbFile>() else {
+        return;
+    };
+    let _ = file.base();
+    let _ = file.builddate();
+    let _ = file.files();
+}
+
+/// Parse a decompressed ALPM `mtree` file and query it.
+pub fn alpm_mtree(data: &str) {
+    let Ok(mtree) = data.parse::<Mtree>() else {
+        return;
+    };
+    let _ = mtree.max_time();
+    let _ = mtree.file_type(Utf8Path::new("usr/bin/"));
+}
+
+/// Parse an image config as given to `--config`.
+pub fn image_config(data: &str) {
+    let _ = crate::cmd_build::parse_config(data);
+}
+
+/// Parse the prune path on the first line, and check it against the paths on
+/// the following lines.
+pub fn prune_paths(data: &str) {
+    let mut lines = data.lines();
+    let Some(prune) = lines.next() else {
+        return;
+    };
+    let Ok(prune_path) = parse_prune_path(&Utf8PathBuf::from(prune)) else {
+        return;
+    };
+    let base = match &prune_path {
+        PrunePath::Exact(base) | PrunePath::ChildrenOnly(base) => base.clone(),
+    };
+    for path in lines {
+        let path = Utf8Path::new(path);
+        let action = check_prune(path, std::slice::from_ref(&prune_path));
+        let expected = match &prune_path {
+            _ if !path.starts_with(&base) => PruneAction::Keep,
+            PrunePath::ChildrenOnly(_) if path == base => PruneAction::SkipChildren,
+            _ => PruneAction::SkipEntirely,
+        };
+        assert_eq!(action, expected, "pruning {path} with {prune_path:?}");
+    }
+}
+
+/// Read a squashfs image given as the rootfs.
+pub fn squashfs_image(data: &[u8]) {
+    read_image(data, crate::squashfs::read_image);
+}
+
+/// Read an erofs image given as the rootfs.
+pub fn erofs_image(data: &[u8]) {
+    read_image(data, crate::erofs::read_image);
+}
+
+/// Read the filesystem image `data` with `read`, and the content of its files.
+fn read_image(
+    data: &[u8],
+    read: fn(&File, &mut XattrInterner, &mut VisitEntry) -> anyhow::Result<()>,
+) {
+    let mut file = tempfile::tempfile().expect("creating temporary file");
+    file.write_all(data).expect("writing image");
+    let _ = read(&file, &mut XattrInterner::default(), &mut |_, content| {
+        // files may be huge holes
+        std::io::copy(&mut content.take(1 << 20), &mut std::io::sink())?;
+        Ok(())
+    });
+}
+
+/// Write a directory entry with the given metadata to a tarball, and check
+/// that it reads back the same.
+///
+/// Inputs which aren't representable (e.g. non-normalized paths or modes
+/// beyond the permission and file type bits) are ignored.
+pub fn tar_dir_header(
+    path: &str,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: u64,
+    mtime_clamp: u64,
+    xattrs: &[(String, Vec<u8>)],
+) {
+    let path = Utf8Path::new(path);
+    let is_normal = path.is_absolute()
+        && path.components().count() > 1
+        && path
+            .components()
+            .skip(1)
+            .all(|c| matches!(c, Utf8Component::Normal(n) if !n.contains('\0')))
+        && path.components().collect::<Utf8PathBuf>().as_str() == path.as_str();
+    // PAX records are `key=value`, so keys can't contain `=`
+    let xattrs_ok = xattrs
+        .iter()
+        .all(|(k, _)| !k.is_empty() && !k.contains(['=', '\0']));
+    if !is_normal || !xattrs_ok {
+        return;
+    }
+
+    let file_info = FileInfo {
+        file_type: FileType::Directory,
+        mode: libc::S_IFDIR | (mode & 0o7777),
+        size: 0,
+        uid,
+        gid,
+        mtime,
+        ino: 0,
+        nlink: 1,
+        xattrs: xattrs.into(),
+        extra: None,
+    };
+    let mut builder = tar::Builder::new(Vec::new());
+    crate::tar::write_dir_entry(
+        &mut builder,
+        path,
+        mtime_clamp,
+        &file_info,
+        &crate::tar::TarOptions::default(),
+    )
+    .expect("writing directory entry");
+    let data = builder.into_inner().expect("finishing tarball");
+
+    let mut archive = tar::Archive::new(data.as_slice());
+    let mut entries = tar_entries(&mut archive).expect("reading tarball");
+    let entry = entries
+        .next()
+        .expect("tarball has no entries")
+        .expect("reading entry");
+    let header = entry.header();
+    assert_eq!(header.entry_type(), tar::EntryType::Directory);
+    assert_eq!(header.mode().unwrap(), file_info.mode);
+    assert_eq!(entry.uid().unwrap(), uid);
+    assert_eq!(entry.gid().unwrap(), gid);
+    assert_eq!(header.mtime().unwrap(), mtime.min(mtime_clamp));
+    assert_eq!(entry.path(), path.strip_prefix("/").unwrap().as_std_path());
+    assert_eq!(entry.xattrs(), xattrs);
+    assert!(entries.next().is_none(), "tarball has extra entries");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run all the files in the corpus of a fuzz target through `f`.
+    fn replay_corpus(target: &str, f: fn(&str)) {
+        let dir = Utf8Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fuzz/corpus")
+            .join(target);
+        let mut count = 0;
+        for entry in dir.read_dir_utf8().unwrap() {
+            let path = entry.unwrap().into_path();
+            if let Ok(data) = std::fs::read_to_string(&path) {
+                f(&data);
+                count += 1;
+            }
+        }
+        assert!(count > 0, "no corpus for {target}");
+    }
+
+    /// Like [`replay_corpus`], for targets taking binary inputs.
+    fn replay_binary_corpus(target: &str, f: fn(&[u8])) {
+        let dir = Utf8Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fuzz/corpus")
+            .join(target);
+        let mut count = 0;
+        for entry in dir.read_dir_utf8().unwrap() {
+            f(&std::fs::read(entry.unwrap().path()).unwrap());
+            count += 1;
+        }
+        assert!(count > 0, "no corpus for {target}");
+    }
+
+    #[test]
+    fn test_replay_corpus() {
+        replay_corpus("alpm_db_file", alpm_db_file);
+        replay_corpus("alpm_mtree", alpm_mtree);
+        replay_corpus("image_config", image_config);
+        replay_corpus("prune_paths", prune_paths);
+        replay_binary_corpus("squashfs_image", squashfs_image);
+        replay_binary_corpus("erofs_image", erofs_image);
+    }
+
+    #[test]
+    fn test_tar_dir_header() {
+        tar_dir_header(
+            "/usr/lib",
+            0o755,
+            0,
+            0,
+            2000,
+            1000,
+            &[("user.component".into(), b"foo".to_vec())],
+        );
+        // binary values such as capabilities may contain newlines
+        tar_dir_header(
+            "/usr/bin",
+            0o755,
+            0,
+            0,
+            1000,
+            1000,
+            &[
+                (
+                    "security.capability".into(),
+                    b"\x01\x00\x00\x02\n\x00".to_vec(),
+                ),
+                ("user..".into(), b"\n".to_vec()),
+            ],
+        );
+        // long paths and big ids need GNU extensions
+        let long = format!("/{}", ["component"; 30].join("/"));
+        tar_dir_header(
+            &long,
+            0o1777,
+            u32::MAX,
+            u32::MAX,
+            u64::MAX / 2,
+            u64::MAX,
+            &[],
+        );
+    }
+}
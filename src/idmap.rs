@@ -0,0 +1,131 @@
+//! Remapping of user and group IDs between the rootfs and the image.
+//!
+//! A rootfs created inside a user namespace has its IDs shifted on disk (e.g.
+//! root is 100000). The maps translate them back to the IDs the image should
+//! have when writing tar headers.
+
+use anyhow::{Context, Result};
+
+/// A range of `count` IDs starting at `from` on disk, mapped to the IDs
+/// starting at `to` in the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdRange {
+    from: u32,
+    to: u32,
+    count: u64,
+}
+
+impl IdRange {
+    /// Parse a range as `FROM:TO:COUNT`, or `FROM:TO` to shift all the IDs
+    /// from `FROM` up.
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut fields = s.split(':');
+        let mut id = |what: &str| -> Result<Option<u32>> {
+            fields
+                .next()
+                .map(|field| {
+                    field
+                        .parse::<u32>()
+                        .with_context(|| format!("invalid {what} in ID map {s}"))
+                })
+                .transpose()
+        };
+        let (Some(from), Some(to)) = (id("source ID")?, id("target ID")?) else {
+            anyhow::bail!("ID map {s} is not FROM:TO or FROM:TO:COUNT");
+        };
+        // a shift goes on until either side runs out of IDs
+        let max_count = u64::from(u32::MAX - from.max(to)) + 1;
+        let count = match id("count")? {
+            Some(count) => u64::from(count),
+            None => max_count,
+        };
+        anyhow::ensure!(fields.next().is_none(), "too many fields in ID map {s}");
+        anyhow::ensure!(count > 0, "empty ID map {s}");
+        anyhow::ensure!(count <= max_count, "ID map {s} goes past the largest ID");
+        Ok(Self { from, to, count })
+    }
+
+    fn map(&self, id: u32) -> Option<u32> {
+        let offset = id.checked_sub(self.from)?;
+        // parse() made sure that the range fits in u32 on both sides
+        (u64::from(offset) < self.count).then(|| self.to + offset)
+    }
+}
+
+/// A mapping of IDs made of ranges. The first range containing an ID maps
+/// it, and IDs outside of all ranges are kept as they are.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdMap {
+    ranges: Vec<IdRange>,
+}
+
+impl IdMap {
+    pub fn new(ranges: Vec<IdRange>) -> Self {
+        Self { ranges }
+    }
+
+    /// Whether the map keeps all IDs as they are.
+    pub fn is_identity(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Returns the image ID of `id`.
+    pub fn map(&self, id: u32) -> u32 {
+        self.ranges.iter().find_map(|r| r.map(id)).unwrap_or(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            IdRange::parse("100000:0:65536").unwrap(),
+            IdRange {
+                from: 100000,
+                to: 0,
+                count: 65536
+            }
+        );
+        assert_eq!(
+            IdRange::parse("100000:0").unwrap().count,
+            u64::from(u32::MAX - 100000) + 1
+        );
+        assert_eq!(IdRange::parse("0:0").unwrap().count, 1 << 32);
+
+        for invalid in [
+            "",
+            "100000",
+            "a:0",
+            "0:b",
+            "0:0:c",
+            "0:0:0",
+            "0:0:1:1",
+            "4294967295:0:2",
+            "0:4294967295:2",
+        ] {
+            assert!(IdRange::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_map() {
+        let map = IdMap::new(vec![
+            IdRange::parse("100000:0:1000").unwrap(),
+            IdRange::parse("100000:5000:2000").unwrap(),
+            IdRange::parse("200000:1000").unwrap(),
+        ]);
+        assert_eq!(map.map(100000), 0);
+        assert_eq!(map.map(100999), 999);
+        // the first range containing the ID wins
+        assert_eq!(map.map(101000), 6000);
+        assert_eq!(map.map(250000), 51000);
+        assert_eq!(map.map(u32::MAX), u32::MAX - 199000);
+        // unmapped IDs are kept
+        assert_eq!(map.map(42), 42);
+        assert_eq!(map.map(150000), 150000);
+        assert!(IdMap::default().is_identity());
+    }
+}
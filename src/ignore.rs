@@ -0,0 +1,148 @@
+//! Ignore files in the style of `.dockerignore` and `.containerignore`.
+//!
+//! This eases building from a working directory instead of a pristine rootfs,
+//! reusing the ignore file of the Docker build context. As there, each line is
+//! a pattern relative to the root, `*` and `?` don't match `/` but `**` does,
+//! and a pattern matching a directory also matches everything below it. Lines
+//! starting with `!` re-include paths matched by earlier patterns; the last
+//! matching pattern wins.
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+
+use crate::utils::GLOB_MATCH_OPTIONS;
+
+/// A pattern of an ignore file.
+#[derive(Debug)]
+struct Rule {
+    pattern: glob::Pattern,
+    /// Whether it's an exception, i.e. it started with `!`.
+    exception: bool,
+}
+
+/// The patterns of an ignore file.
+#[derive(Debug)]
+pub struct IgnoreRules {
+    rules: Vec<Rule>,
+}
+
+impl IgnoreRules {
+    /// Load the patterns from a file.
+    pub fn load(path: &Utf8Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path).with_context(|| format!("reading {path}"))?;
+        Self::parse(&content).with_context(|| format!("parsing {path}"))
+    }
+
+    /// Parse patterns, one per line.
+    ///
+    /// Empty lines and lines starting with `#` are ignored. Leading and
+    /// trailing slashes don't matter.
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut rules = Vec::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (exception, pattern) = match line.strip_prefix('!') {
+                Some(pattern) => (true, pattern.trim()),
+                None => (false, line),
+            };
+            // like filepath.Clean() in Docker, which also drops `.` components
+            let pattern = pattern
+                .split('/')
+                .filter(|c| !c.is_empty() && *c != ".")
+                .collect::<Vec<_>>()
+                .join("/");
+            if pattern.is_empty() {
+                continue;
+            }
+            anyhow::ensure!(
+                !pattern.split('/').any(|c| c == ".."),
+                "line {}: pattern can't go up: {line}",
+                i + 1
+            );
+            let pattern = glob::Pattern::new(&pattern)
+                .with_context(|| format!("line {}: invalid pattern {line}", i + 1))?;
+            rules.push(Rule { pattern, exception });
+        }
+        Ok(Self { rules })
+    }
+
+    /// Whether some paths are re-included, in which case ignored directories
+    /// still need to be walked.
+    pub fn has_exceptions(&self) -> bool {
+        self.rules.iter().any(|rule| rule.exception)
+    }
+
+    /// Whether the absolute path `path` is ignored.
+    pub fn is_ignored(&self, path: &Utf8Path) -> bool {
+        let rel_path = path.strip_prefix("/").unwrap_or(path);
+        if rel_path.as_str().is_empty() {
+            return false;
+        }
+        let mut ignored = false;
+        for rule in &self.rules {
+            // the rule doesn't change anything, so don't bother matching
+            if rule.exception != ignored {
+                continue;
+            }
+            let matches = rel_path
+                .ancestors()
+                .filter(|p| !p.as_str().is_empty())
+                .any(|p| rule.pattern.matches_with(p.as_str(), GLOB_MATCH_OPTIONS));
+            if matches {
+                ignored = !rule.exception;
+            }
+        }
+        ignored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_ignored() {
+        let rules = IgnoreRules::parse(
+            r#"
+# build artifacts
+target
+/node_modules/
+*.log
+**/.git
+docs/*
+!docs/README.md
+./tmp/*.swp
+"#,
+        )
+        .unwrap();
+        assert!(rules.has_exceptions());
+
+        let ignored = |path: &str| rules.is_ignored(Utf8Path::new(path));
+        assert!(!ignored("/"));
+        assert!(ignored("/target"));
+        assert!(ignored("/target/debug/app"));
+        // patterns are relative to the root
+        assert!(!ignored("/src/target"));
+        assert!(ignored("/node_modules/foo/index.js"));
+        assert!(ignored("/build.log"));
+        assert!(!ignored("/logs/build.log"));
+        assert!(ignored("/.git/config"));
+        assert!(ignored("/vendor/foo/.git/HEAD"));
+        assert!(!ignored("/docs"));
+        assert!(ignored("/docs/guide.md"));
+        assert!(!ignored("/docs/README.md"));
+        assert!(ignored("/tmp/.file.swp"));
+        assert!(!ignored("/src/main.rs"));
+
+        let rules = IgnoreRules::parse("*\n!app/**\n").unwrap();
+        assert!(rules.is_ignored(Utf8Path::new("/etc/passwd")));
+        assert!(!rules.is_ignored(Utf8Path::new("/app/bin/app")));
+
+        assert!(IgnoreRules::parse("../outside\n").is_err());
+        assert!(IgnoreRules::parse("[\n").is_err());
+        assert!(!IgnoreRules::parse("target\n").unwrap().has_exceptions());
+    }
+}
@@ -0,0 +1,64 @@
+//! chunkah is a CLI first and foremost. The library target exists so that it
+//! can be embedded in other build tools, which can add their own components
+//! repos (see [`components::ComponentsRepo`]) and run a build with
+//! [`cmd_build::run_with_repos`]. Only those two modules are stable API.
+
+mod attest;
+#[doc(hidden)]
+pub mod cmd_analyze;
+#[doc(hidden)]
+pub mod cmd_bake;
+pub mod cmd_build;
+#[doc(hidden)]
+pub mod cmd_diff;
+#[doc(hidden)]
+pub mod cmd_extract;
+#[doc(hidden)]
+pub mod cmd_inspect;
+#[doc(hidden)]
+pub mod cmd_rebuild_layers;
+#[doc(hidden)]
+pub mod cmd_sign;
+#[doc(hidden)]
+pub mod cmd_simulate;
+#[doc(hidden)]
+pub mod cmd_stats;
+#[doc(hidden)]
+pub mod cmd_verify;
+#[doc(hidden)]
+pub mod cmd_verify_signature;
+pub mod components;
+mod compressibility;
+mod constraints;
+mod debug_bundle;
+mod diagnostics;
+mod erofs;
+mod expected;
+#[doc(hidden)]
+pub mod fuzzing;
+mod idmap;
+mod ignore;
+mod metrics;
+mod normalize;
+mod ocibuilder;
+mod overlay;
+mod owners;
+#[allow(dead_code)]
+mod packing;
+mod parallel_gzip;
+mod plan;
+mod profile;
+mod progress;
+mod readahead;
+mod rewrite;
+mod rootfs_image;
+mod scan;
+mod selftest;
+mod signature;
+mod squashfs;
+mod tar;
+mod trace;
+mod transform;
+mod user_config;
+mod utils;
+mod validate;
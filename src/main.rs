@@ -1,13 +1,8 @@
-mod cmd_build;
-mod components;
-mod ocibuilder;
-#[allow(dead_code)]
-mod packing;
-mod scan;
-mod tar;
-mod utils;
-
 use anyhow::{Context, Result};
+use chunkah::{
+    cmd_analyze, cmd_bake, cmd_build, cmd_diff, cmd_extract, cmd_inspect, cmd_rebuild_layers,
+    cmd_sign, cmd_simulate, cmd_stats, cmd_verify, cmd_verify_signature,
+};
 use clap::{Parser, Subcommand};
 
 #[derive(Parser)]
@@ -22,6 +17,28 @@ struct Cli {
 enum Command {
     /// Build an OCI archive from a rootfs
     Build(Box<cmd_build::BuildArgs>),
+    /// Show the components of a rootfs and how they would be packed in layers
+    Analyze(Box<cmd_analyze::AnalyzeArgs>),
+    /// Build an image derived from a base image, as described by a spec file
+    Bake(cmd_bake::BakeArgs),
+    /// Compare the layers and configs of two images
+    Diff(cmd_diff::DiffArgs),
+    /// Extract the rootfs of an image into a directory
+    Extract(cmd_extract::ExtractArgs),
+    /// List the layers of an image and how many are reused from another one
+    Inspect(cmd_inspect::InspectArgs),
+    /// Rebuild the layers of some components of an existing image
+    RebuildLayers(Box<cmd_rebuild_layers::RebuildLayersArgs>),
+    /// Sign an image, or re-sign it with other keys
+    Sign(cmd_sign::SignArgs),
+    /// Estimate the bytes to pull per update with several numbers of layers
+    Simulate(Box<cmd_simulate::SimulateArgs>),
+    /// Report the layer sizes and duplicate content of any image
+    Stats(cmd_stats::StatsArgs),
+    /// Check that applying the layers of an image gives back its rootfs
+    Verify(Box<cmd_verify::VerifyArgs>),
+    /// Verify the signatures of an image
+    VerifySignature(cmd_verify_signature::VerifySignatureArgs),
 }
 
 fn main() -> Result<()> {
@@ -35,6 +52,17 @@ fn main() -> Result<()> {
 
     match cli.command {
         Command::Build(args) => cmd_build::run(&args)?,
+        Command::Analyze(args) => cmd_analyze::run(&args)?,
+        Command::Bake(args) => cmd_bake::run(&args)?,
+        Command::Diff(args) => cmd_diff::run(&args)?,
+        Command::Extract(args) => cmd_extract::run(&args)?,
+        Command::Inspect(args) => cmd_inspect::run(&args)?,
+        Command::RebuildLayers(args) => cmd_rebuild_layers::run(&args)?,
+        Command::Sign(args) => cmd_sign::run(&args)?,
+        Command::Simulate(args) => cmd_simulate::run(&args)?,
+        Command::Stats(args) => cmd_stats::run(&args)?,
+        Command::Verify(args) => cmd_verify::run(&args)?,
+        Command::VerifySignature(args) => cmd_verify_signature::run(&args)?,
     }
 
     Ok(())
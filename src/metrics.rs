@@ -0,0 +1,201 @@
+//! Metrics: how long each step of a build took and how many bytes went in and
+//! out of each layer, summarized at the end of the build with `--metrics`.
+//!
+//! The steps are the `info` spans of [`crate::trace`], and the layers are
+//! recorded by the builder as they're written, in the [`Metrics`] of the
+//! build. Nothing is recorded unless metrics were requested.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::trace::{self, LogFormat};
+
+/// Number of layers listed in the text summary, slowest first.
+const SLOWEST_LAYERS: usize = 5;
+
+/// The metrics of a build, shared by the steps recording them. Cloning it
+/// gives another handle to the same metrics.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics(Option<Arc<Mutex<Recorded>>>);
+
+#[derive(Debug, Default, Serialize)]
+struct Recorded {
+    steps: Vec<StepMetrics>,
+    layers: Vec<LayerMetrics>,
+}
+
+#[derive(Debug, Serialize)]
+struct StepMetrics {
+    name: &'static str,
+    duration_ms: u64,
+}
+
+/// A layer, from the files of its component to its blob.
+#[derive(Debug, Serialize)]
+struct LayerMetrics {
+    name: String,
+    files: usize,
+    bytes_in: u64,
+    /// Size of the blob, compressed if the layers are.
+    bytes_out: u64,
+    /// Time spent writing and compressing the tarball.
+    duration_ms: u64,
+}
+
+impl Metrics {
+    /// Returns the metrics of a build, which are only recorded if `enabled`.
+    pub fn new(enabled: bool) -> Self {
+        Self(enabled.then(Default::default))
+    }
+
+    /// Record that the step `name` took `elapsed`.
+    pub fn step(&self, name: &'static str, elapsed: Duration) {
+        if let Some(metrics) = &self.0 {
+            // SAFETY: we never panic while holding the lock
+            metrics.lock().unwrap().steps.push(StepMetrics {
+                name,
+                duration_ms: millis(elapsed),
+            });
+        }
+    }
+
+    /// Record that writing the layer of component `name` took `elapsed`.
+    pub fn layer(
+        &self,
+        name: &str,
+        files: usize,
+        bytes_in: u64,
+        bytes_out: u64,
+        elapsed: Duration,
+    ) {
+        if let Some(metrics) = &self.0 {
+            // SAFETY: we never panic while holding the lock
+            metrics.lock().unwrap().layers.push(LayerMetrics {
+                name: name.to_string(),
+                files,
+                bytes_in,
+                bytes_out,
+                duration_ms: millis(elapsed),
+            });
+        }
+    }
+
+    /// Print the summary of the metrics to stderr in the `--log-format`, if
+    /// they were requested.
+    pub fn report(&self) -> Result<()> {
+        let Some(metrics) = &self.0 else {
+            return Ok(());
+        };
+        // SAFETY: we never panic while holding the lock
+        let metrics = metrics.lock().unwrap();
+        match trace::format() {
+            LogFormat::Text => eprint!("{}", format_metrics(&metrics)),
+            LogFormat::Json => {
+                let mut record = serde_json::to_value(&*metrics).context("serializing metrics")?;
+                record["bytes_in"] = metrics
+                    .layers
+                    .iter()
+                    .map(|l| l.bytes_in)
+                    .sum::<u64>()
+                    .into();
+                record["bytes_out"] = metrics
+                    .layers
+                    .iter()
+                    .map(|l| l.bytes_out)
+                    .sum::<u64>()
+                    .into();
+                eprintln!("{}", trace::json_line("metrics", record));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn millis(elapsed: Duration) -> u64 {
+    u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX)
+}
+
+fn format_metrics(metrics: &Recorded) -> String {
+    let seconds = |ms: u64| format!("{:.3}s", ms as f64 / 1000.0);
+    let mut out = String::from("metrics:\n");
+    for step in &metrics.steps {
+        out.push_str(&format!(
+            "  {:<8} {:>10}\n",
+            step.name,
+            seconds(step.duration_ms)
+        ));
+    }
+
+    if metrics.layers.is_empty() {
+        return out;
+    }
+    let bytes_in: u64 = metrics.layers.iter().map(|l| l.bytes_in).sum();
+    let bytes_out: u64 = metrics.layers.iter().map(|l| l.bytes_out).sum();
+    let duration: u64 = metrics.layers.iter().map(|l| l.duration_ms).sum();
+    out.push_str(&format!(
+        "  {} layers in {}: {bytes_in} bytes in, {bytes_out} bytes out\n",
+        metrics.layers.len(),
+        seconds(duration),
+    ));
+    let mut slowest: Vec<&LayerMetrics> = metrics.layers.iter().collect();
+    slowest.sort_by_key(|step| std::cmp::Reverse(step.duration_ms));
+    out.push_str("  slowest layers:\n");
+    for layer in slowest.into_iter().take(SLOWEST_LAYERS) {
+        out.push_str(&format!(
+            "    {:>10}  {}: {} files, {} bytes in, {} bytes out\n",
+            seconds(layer.duration_ms),
+            layer.name,
+            layer.files,
+            layer.bytes_in,
+            layer.bytes_out,
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_metrics() {
+        let layer = |name: &str, bytes_in, bytes_out, duration_ms| LayerMetrics {
+            name: name.to_string(),
+            files: 10,
+            bytes_in,
+            bytes_out,
+            duration_ms,
+        };
+        let mut metrics = Recorded {
+            steps: vec![
+                StepMetrics {
+                    name: "scan",
+                    duration_ms: 12345,
+                },
+                StepMetrics {
+                    name: "claim",
+                    duration_ms: 210,
+                },
+            ],
+            layers: Vec::new(),
+        };
+        assert_eq!(
+            format_metrics(&metrics),
+            "metrics:\n  scan        12.345s\n  claim        0.210s\n"
+        );
+
+        metrics.layers = (0..7)
+            .map(|i| layer(&format!("rpm/pkg{i}"), 1000, 400, i * 100))
+            .collect();
+        let out = format_metrics(&metrics);
+        assert!(out.contains("  7 layers in 2.100s: 7000 bytes in, 2800 bytes out\n"));
+        assert!(out.contains(
+            "  slowest layers:\n        0.600s  rpm/pkg6: 10 files, 1000 bytes in, 400 bytes out\n"
+        ));
+        assert_eq!(out.lines().count(), 3 + 1 + 1 + SLOWEST_LAYERS);
+        assert!(!out.contains("rpm/pkg1:"));
+    }
+}
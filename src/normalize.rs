@@ -0,0 +1,368 @@
+//! Normalizers for file formats that embed nondeterministic timestamps.
+//!
+//! These are opt-in because they modify file content, which otherwise chunkah
+//! never does. All normalizers edit content in place without changing its
+//! size, and clamp embedded timestamps to the component's mtime clamp the same
+//! way file mtimes are clamped.
+
+use camino::Utf8Path;
+
+/// A file format normalizer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Normalizer {
+    /// Clamp the mtime in gzip headers.
+    Gzip,
+    /// Clamp the source mtime in timestamp-based Python bytecode files.
+    Pyc,
+    /// Clamp member mtimes and zero out uid/gid in ar archives (e.g. static
+    /// libraries).
+    Ar,
+    /// Clamp entry modification times in zip archives (e.g. jars, wheels).
+    Zip,
+}
+
+/// Run all applicable normalizers on the file content.
+///
+/// Returns true if the content was modified.
+pub fn normalize(
+    path: &Utf8Path,
+    content: &mut [u8],
+    normalizers: &[Normalizer],
+    mtime_clamp: u64,
+) -> bool {
+    let mut modified = false;
+    for normalizer in normalizers {
+        modified |= match normalizer {
+            Normalizer::Gzip => normalize_gzip(content, mtime_clamp),
+            Normalizer::Pyc => {
+                matches!(path.extension(), Some("pyc" | "pyo"))
+                    && normalize_pyc(content, mtime_clamp)
+            }
+            Normalizer::Ar => normalize_ar(content, mtime_clamp),
+            Normalizer::Zip => normalize_zip(content, mtime_clamp),
+        };
+    }
+    modified
+}
+
+/// Clamp a little-endian u32 timestamp at `offset`.
+fn clamp_u32_le(content: &mut [u8], offset: usize, mtime_clamp: u64) -> bool {
+    let Some(bytes) = content.get_mut(offset..offset + 4) else {
+        return false;
+    };
+    // SAFETY: we just sliced exactly 4 bytes
+    let value = u32::from_le_bytes(bytes.try_into().unwrap());
+    let clamp = u32::try_from(mtime_clamp).unwrap_or(u32::MAX);
+    if value <= clamp {
+        return false;
+    }
+    bytes.copy_from_slice(&clamp.to_le_bytes());
+    true
+}
+
+/// Gzip: the MTIME field is at offset 4 (RFC 1952). The CRC only covers the
+/// uncompressed data, so we can rewrite it freely, unless a header CRC is
+/// present (FHCRC flag), in which case we leave the file alone.
+fn normalize_gzip(content: &mut [u8], mtime_clamp: u64) -> bool {
+    const FHCRC: u8 = 0x02;
+    if content.len() < 10 || content[..3] != [0x1f, 0x8b, 0x08] || content[3] & FHCRC != 0 {
+        return false;
+    }
+    clamp_u32_le(content, 4, mtime_clamp)
+}
+
+/// Python bytecode (PEP 552): after the 4-byte magic comes a 4-byte flags
+/// field. If it's zero, the pyc is timestamp-based and the next 4 bytes are
+/// the source mtime. Python checks this against the source file's mtime, which
+/// is clamped in the layer, so clamping it here keeps them consistent.
+fn normalize_pyc(content: &mut [u8], mtime_clamp: u64) -> bool {
+    if content.len() < 16 || content[2..4] != [b'\r', b'\n'] || content[4..8] != [0; 4] {
+        return false;
+    }
+    clamp_u32_le(content, 8, mtime_clamp)
+}
+
+/// Write `value` as a left-aligned, space-padded ASCII field.
+fn write_ar_field(field: &mut [u8], value: u64) {
+    let s = value.to_string();
+    field.fill(b' ');
+    field[..s.len()].copy_from_slice(s.as_bytes());
+}
+
+/// Ar archives: a global `!<arch>\n` header followed by members each with a
+/// 60-byte header. We clamp the mtime and zero the uid/gid, which is what
+/// `ar D` (deterministic mode) does.
+fn normalize_ar(content: &mut [u8], mtime_clamp: u64) -> bool {
+    const MAGIC: &[u8] = b"!<arch>\n";
+    const HEADER_LEN: usize = 60;
+    if !content.starts_with(MAGIC) {
+        return false;
+    }
+
+    let mut modified = false;
+    let mut offset = MAGIC.len();
+    while offset + HEADER_LEN <= content.len() {
+        let header = &mut content[offset..offset + HEADER_LEN];
+        if header[58..60] != *b"`\n" {
+            // corrupt or not really an ar archive; stop here
+            break;
+        }
+        let parse =
+            |field: &[u8]| -> Option<u64> { std::str::from_utf8(field).ok()?.trim().parse().ok() };
+        let Some(size) = parse(&header[48..58]) else {
+            break;
+        };
+        // the symbol table and long name table have no meaningful metadata
+        if let Some(mtime) = parse(&header[16..28])
+            && mtime > mtime_clamp
+        {
+            write_ar_field(&mut header[16..28], mtime_clamp);
+            modified = true;
+        }
+        for field in [28..34, 34..40] {
+            if parse(&header[field.clone()]).is_some_and(|v| v != 0) {
+                write_ar_field(&mut header[field], 0);
+                modified = true;
+            }
+        }
+        // members are 2-byte aligned
+        let Ok(size) = usize::try_from(size) else {
+            break;
+        };
+        let size = size.min(content.len());
+        offset = offset.saturating_add(HEADER_LEN + size + (size % 2));
+    }
+    modified
+}
+
+/// Convert an epoch to a DOS (date, time) pair as used in zip headers.
+///
+/// DOS times can't represent anything before 1980, so those saturate to
+/// 1980-01-01 00:00:00.
+fn epoch_to_dos(epoch: u64) -> (u16, u16) {
+    use chrono::{Datelike, Timelike};
+
+    let dt = i64::try_from(epoch)
+        .ok()
+        .and_then(|e| chrono::DateTime::from_timestamp(e, 0))
+        .unwrap_or_default();
+    if dt.year() < 1980 {
+        return (1 << 5 | 1, 0);
+    }
+    let year = (dt.year() - 1980).min(127) as u16;
+    let date = year << 9 | (dt.month() as u16) << 5 | dt.day() as u16;
+    let time = (dt.hour() as u16) << 11 | (dt.minute() as u16) << 5 | (dt.second() / 2) as u16;
+    (date, time)
+}
+
+/// Clamp the DOS time/date pair starting at `offset` (time first, then date).
+fn clamp_dos_time(content: &mut [u8], offset: usize, clamp: (u16, u16)) -> bool {
+    let Some(bytes) = content.get_mut(offset..offset + 4) else {
+        return false;
+    };
+    let time = u16::from_le_bytes([bytes[0], bytes[1]]);
+    let date = u16::from_le_bytes([bytes[2], bytes[3]]);
+    // (date, time) compares lexicographically in the right order
+    if (date, time) <= clamp {
+        return false;
+    }
+    let (clamp_date, clamp_time) = clamp;
+    bytes[..2].copy_from_slice(&clamp_time.to_le_bytes());
+    bytes[2..].copy_from_slice(&clamp_date.to_le_bytes());
+    true
+}
+
+/// Zip archives: walk the central directory and clamp the modification time
+/// in both the central directory entry and its local file header.
+///
+/// Extended timestamp extra fields are left alone.
+fn normalize_zip(content: &mut [u8], mtime_clamp: u64) -> bool {
+    const LOCAL_SIG: &[u8] = b"PK\x03\x04";
+    const CENTRAL_SIG: &[u8] = b"PK\x01\x02";
+    const EOCD_SIG: &[u8] = b"PK\x05\x06";
+    const EOCD_LEN: usize = 22;
+
+    if !content.starts_with(LOCAL_SIG) || content.len() < EOCD_LEN {
+        return false;
+    }
+
+    // the EOCD is at the end, possibly followed by a comment of up to 64k
+    let search_start = content.len().saturating_sub(EOCD_LEN + u16::MAX as usize);
+    let Some(eocd) = (search_start..=content.len() - EOCD_LEN)
+        .rev()
+        .find(|&i| content[i..].starts_with(EOCD_SIG))
+    else {
+        return false;
+    };
+    let read_u16 = |c: &[u8], off: usize| u16::from_le_bytes([c[off], c[off + 1]]) as usize;
+    let read_u32 = |c: &[u8], off: usize| {
+        u32::from_le_bytes([c[off], c[off + 1], c[off + 2], c[off + 3]]) as usize
+    };
+    let entries = read_u16(content, eocd + 10);
+    let mut offset = read_u32(content, eocd + 16);
+
+    let clamp = epoch_to_dos(mtime_clamp);
+    let mut modified = false;
+    for _ in 0..entries {
+        if offset + 46 > content.len() || !content[offset..].starts_with(CENTRAL_SIG) {
+            break;
+        }
+        let local = read_u32(content, offset + 42);
+        modified |= clamp_dos_time(content, offset + 12, clamp);
+        if content
+            .get(local..)
+            .is_some_and(|c| c.starts_with(LOCAL_SIG))
+        {
+            modified |= clamp_dos_time(content, local + 10, clamp);
+        }
+        offset += 46
+            + read_u16(content, offset + 28)
+            + read_u16(content, offset + 30)
+            + read_u16(content, offset + 32);
+    }
+    modified
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn test_normalize_gzip() {
+        let mut encoder = flate2::GzBuilder::new()
+            .mtime(2000)
+            .write(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(b"hello").unwrap();
+        let mut content = encoder.finish().unwrap();
+
+        let path = Utf8Path::new("/usr/share/doc/foo.gz");
+        assert!(normalize(path, &mut content, &[Normalizer::Gzip], 1000));
+        assert_eq!(content[4..8], 1000u32.to_le_bytes());
+        // already clamped; nothing to do
+        assert!(!normalize(path, &mut content, &[Normalizer::Gzip], 1000));
+
+        // still decompresses fine
+        let mut decoder = flate2::read::GzDecoder::new(content.as_slice());
+        let mut out = String::new();
+        std::io::Read::read_to_string(&mut decoder, &mut out).unwrap();
+        assert_eq!(out, "hello");
+    }
+
+    #[test]
+    fn test_normalize_pyc() {
+        let mut content = vec![0xcb, 0x0d, b'\r', b'\n', 0, 0, 0, 0];
+        content.extend(5000u32.to_le_bytes());
+        content.extend(42u32.to_le_bytes());
+        content.extend(b"code");
+
+        // only applies to .pyc files
+        let py = Utf8Path::new("/usr/lib/foo.py");
+        assert!(!normalize(py, &mut content, &[Normalizer::Pyc], 1000));
+
+        let pyc = Utf8Path::new("/usr/lib/__pycache__/foo.cpython-312.pyc");
+        assert!(normalize(pyc, &mut content, &[Normalizer::Pyc], 1000));
+        assert_eq!(content[8..12], 1000u32.to_le_bytes());
+        // source size untouched
+        assert_eq!(content[12..16], 42u32.to_le_bytes());
+
+        // hash-based pycs are left alone
+        let mut hashed = content.clone();
+        hashed[4] = 1;
+        hashed[8..12].copy_from_slice(&5000u32.to_le_bytes());
+        assert!(!normalize(pyc, &mut hashed, &[Normalizer::Pyc], 1000));
+    }
+
+    #[test]
+    fn test_normalize_ar() {
+        let mut content = b"!<arch>\n".to_vec();
+        for (name, data) in [("a.o/", "abc"), ("b.o/", "defg")] {
+            content.extend(
+                format!(
+                    "{name:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+                    5000,
+                    1000,
+                    1000,
+                    "100644",
+                    data.len()
+                )
+                .as_bytes(),
+            );
+            content.extend(data.as_bytes());
+            if data.len() % 2 == 1 {
+                content.push(b'\n');
+            }
+        }
+        let len = content.len();
+
+        let path = Utf8Path::new("/usr/lib64/libfoo.a");
+        assert!(normalize(path, &mut content, &[Normalizer::Ar], 1234));
+        assert_eq!(content.len(), len);
+        let second = 8 + 60 + 4;
+        for header in [8, second] {
+            assert_eq!(&content[header + 16..header + 28], b"1234        ");
+            assert_eq!(&content[header + 28..header + 40], b"0     0     ");
+        }
+        assert_eq!(&content[second + 60..second + 64], b"defg");
+    }
+
+    #[test]
+    fn test_normalize_zip() {
+        // hand-craft a single-entry stored zip
+        let name = b"a.txt";
+        let data = b"hi";
+        let (date, time) = epoch_to_dos(1_700_000_000);
+        let mut content = Vec::new();
+        content.extend(b"PK\x03\x04");
+        content.extend([20, 0, 0, 0, 0, 0]);
+        content.extend(time.to_le_bytes());
+        content.extend(date.to_le_bytes());
+        content.extend([0; 4]); // crc (unchecked here)
+        content.extend((data.len() as u32).to_le_bytes());
+        content.extend((data.len() as u32).to_le_bytes());
+        content.extend((name.len() as u16).to_le_bytes());
+        content.extend([0, 0]);
+        content.extend(name);
+        content.extend(data);
+        let central = content.len();
+        content.extend(b"PK\x01\x02");
+        content.extend([20, 0, 20, 0, 0, 0, 0, 0]);
+        content.extend(time.to_le_bytes());
+        content.extend(date.to_le_bytes());
+        content.extend([0; 4]);
+        content.extend((data.len() as u32).to_le_bytes());
+        content.extend((data.len() as u32).to_le_bytes());
+        content.extend((name.len() as u16).to_le_bytes());
+        content.extend([0; 12]);
+        content.extend(0u32.to_le_bytes()); // local header offset
+        content.extend(name);
+        let central_len = content.len() - central;
+        content.extend(b"PK\x05\x06");
+        content.extend([0; 4]);
+        content.extend(1u16.to_le_bytes());
+        content.extend(1u16.to_le_bytes());
+        content.extend((central_len as u32).to_le_bytes());
+        content.extend((central as u32).to_le_bytes());
+        content.extend([0, 0]);
+
+        let clamp = 1_600_000_000;
+        let path = Utf8Path::new("/usr/share/java/foo.jar");
+        assert!(normalize(path, &mut content, &[Normalizer::Zip], clamp));
+        let (clamp_date, clamp_time) = epoch_to_dos(clamp);
+        for off in [10, central + 12] {
+            assert_eq!(content[off..off + 2], clamp_time.to_le_bytes());
+            assert_eq!(content[off + 2..off + 4], clamp_date.to_le_bytes());
+        }
+        assert!(!normalize(path, &mut content, &[Normalizer::Zip], clamp));
+    }
+
+    #[test]
+    fn test_epoch_to_dos() {
+        // 2020-06-15 12:30:44 UTC
+        let (date, time) = epoch_to_dos(1_592_224_244);
+        assert_eq!(date, (40 << 9) | (6 << 5) | 15);
+        assert_eq!(time, (12 << 11) | (30 << 5) | 22);
+        assert_eq!(epoch_to_dos(0), (1 << 5 | 1, 0));
+    }
+}
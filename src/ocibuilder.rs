@@ -1,14 +1,56 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
 
 use anyhow::{Context, Result};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
 use cap_std_ext::cap_std::fs::Dir;
 use ocidir::oci_spec::image as oci_image;
 
-use crate::components::Component;
+use crate::components::layers::{OPAQUE_WHITEOUT, WHITEOUT_PREFIX};
+use crate::components::{Component, FileMap, RetentionRules};
+use crate::debug_bundle::DebugBundle;
+use crate::idmap::IdMap;
+use crate::metrics::Metrics;
+use crate::normalize::Normalizer;
+use crate::overlay::Overlay;
+use crate::owners::OwnerNames;
+use crate::rewrite::PathRewrites;
+use crate::selftest::SelfTest;
+use crate::tar::{EntryOrder, TarFormat, TarOptions};
+use crate::transform::{Chmod, Chown};
+use crate::validate::MAX_MANIFEST_SIZE;
+
+/// Where the component ownership mapping is embedded in the image, if enabled.
+pub const COMPONENTS_JSON_PATH: &str = "/usr/share/chunkah/components.json";
+
+/// Name of the layer holding chunkah metadata such as the components JSON.
+pub const METADATA_COMPONENT: &str = "chunkah/metadata";
+
+/// Layer annotation holding the component name.
+pub(crate) const COMPONENT_ANNOTATION: &str = "org.chunkah.component";
+
+/// Layer annotation holding the stability of the layer.
+pub(crate) const STABILITY_ANNOTATION: &str = "org.chunkah.stability";
+
+/// Layer annotation holding the space-separated packages in the layer, e.g.
+/// rpm NEVRAs.
+const PACKAGES_ANNOTATION: &str = "org.chunkah.packages";
+
+/// Layer annotation holding the retention hint of the layer for registry
+/// garbage collection, `long` or `short`.
+const RETENTION_ANNOTATION: &str = "org.chunkah.retention";
+
+/// Artifact type of the layer to component mapping attached to the image when
+/// it doesn't fit in the manifest.
+pub const LAYER_COMPONENTS_ARTIFACT_TYPE: &str = "application/vnd.chunkah.layer-components.v1+json";
 
 /// Compression settings for the OCI image.
-#[derive(Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum Compression {
     /// No compression.
     #[default]
@@ -17,6 +59,62 @@ pub enum Compression {
     Gzip(u32),
 }
 
+/// Compression format of the OCI archive, independent of the layers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ArchiveFormat {
+    /// No compression.
+    None,
+    /// Gzip compression.
+    Gzip,
+    /// Xz compression.
+    Xz,
+    /// Bzip2 compression.
+    Bzip2,
+}
+
+impl ArchiveFormat {
+    /// Returns the archive compression with the given level (0-9).
+    pub fn compression(self, level: u32) -> crate::tar::ArchiveCompression {
+        match self {
+            Self::None => crate::tar::ArchiveCompression::None,
+            Self::Gzip => crate::tar::ArchiveCompression::Gzip(flate2::Compression::new(level)),
+            Self::Xz => crate::tar::ArchiveCompression::Xz(level),
+            // bzip2 has no level 0
+            Self::Bzip2 => {
+                crate::tar::ArchiveCompression::Bzip2(bzip2::Compression::new(level.max(1)))
+            }
+        }
+    }
+}
+
+/// Family of media types used for layers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LayerMediaType {
+    /// `application/vnd.oci.image.layer.v1.tar[+gzip]`.
+    #[default]
+    Oci,
+    /// `application/vnd.docker.image.rootfs.diff.tar[.gzip]`, for consumers
+    /// which predate OCI media types.
+    Docker,
+}
+
+impl LayerMediaType {
+    /// Returns the media type of layers with the given compression.
+    pub fn media_type(self, compression: Compression) -> oci_image::MediaType {
+        match (self, compression) {
+            (Self::Oci, Compression::None) => oci_image::MediaType::ImageLayer,
+            (Self::Oci, Compression::Gzip(_)) => oci_image::MediaType::ImageLayerGzip,
+            (Self::Docker, Compression::None) => {
+                oci_image::MediaType::Other(crate::tar::DOCKER_LAYER_MEDIA_TYPE.into())
+            }
+            (Self::Docker, Compression::Gzip(_)) => {
+                oci_image::MediaType::Other(crate::tar::DOCKER_LAYER_GZIP_MEDIA_TYPE.into())
+            }
+        }
+    }
+}
+
 /// Builder for creating OCI images from components.
 pub struct Builder {
     /// The rootfs to build from.
@@ -27,10 +125,40 @@ pub struct Builder {
     components: Vec<(String, Component)>,
     /// Compression settings for layers and archive.
     compression: Compression,
+    /// Compression of the archive, if it differs from the layers.
+    archive_compression: Option<crate::tar::ArchiveCompression>,
+    /// Family of media types used for layers.
+    layer_media_type: LayerMediaType,
+    /// Options for writing layer tarballs.
+    tar_options: TarOptions,
     /// Annotations to add to the image manifest.
     annotations: Option<HashMap<String, String>>,
     /// The image configuration.
     config: Option<oci_image::ImageConfiguration>,
+    /// Whether to validate the OCI directory before writing it out.
+    validate: bool,
+    /// Components JSON content and mtime to embed in a metadata layer.
+    components_json: Option<(Vec<u8>, u64)>,
+    /// Whether to annotate layers with their inputs digest.
+    inputs_digests: bool,
+    /// Whether to annotate layers with the packages they contain.
+    package_annotations: bool,
+    /// Rules to annotate layers with their retention hint.
+    retention: Option<RetentionRules>,
+    /// Self-test of the layers, if enabled.
+    self_test: Option<SelfTest>,
+    /// Whether to print each layer to stderr once written.
+    progress: bool,
+    /// Whether to lower the compression of layers of compressed files.
+    adaptive_compression: bool,
+    /// Number of threads compressing each layer.
+    compression_threads: usize,
+    /// Number of layers written in parallel.
+    build_threads: usize,
+    /// Where to record how long writing each layer took.
+    metrics: Metrics,
+    /// Where to record the layers written so far.
+    debug_bundle: DebugBundle,
 }
 
 impl Builder {
@@ -38,14 +166,48 @@ impl Builder {
     pub fn new(rootfs: &Dir, components: Vec<(String, Component)>) -> Result<Self> {
         let oci_dir = cap_std_ext::cap_tempfile::tempdir(cap_std_ext::cap_std::ambient_authority())
             .context("creating temp directory")?;
+        Self::with_oci_dir(rootfs, components, oci_dir)
+    }
 
+    /// Same as [`Builder::new`], but with the OCI directory created in
+    /// `workdir` rather than in the system temporary directory.
+    pub fn new_in(
+        rootfs: &Dir,
+        components: Vec<(String, Component)>,
+        workdir: &Dir,
+    ) -> Result<Self> {
+        let oci_dir = cap_std_ext::cap_tempfile::TempDir::new_in(workdir)
+            .context("creating temp directory")?;
+        Self::with_oci_dir(rootfs, components, oci_dir)
+    }
+
+    fn with_oci_dir(
+        rootfs: &Dir,
+        components: Vec<(String, Component)>,
+        oci_dir: cap_std_ext::cap_tempfile::TempDir,
+    ) -> Result<Self> {
         Ok(Self {
             rootfs: rootfs.try_clone().context("cloning rootfs")?,
             oci_dir,
             components,
             compression: Compression::default(),
+            archive_compression: None,
+            layer_media_type: LayerMediaType::default(),
+            tar_options: TarOptions::default(),
             annotations: None,
             config: None,
+            validate: false,
+            components_json: None,
+            inputs_digests: false,
+            package_annotations: false,
+            retention: None,
+            self_test: None,
+            progress: false,
+            adaptive_compression: false,
+            compression_threads: 1,
+            build_threads: 1,
+            metrics: Metrics::default(),
+            debug_bundle: DebugBundle::default(),
         })
     }
 
@@ -55,6 +217,31 @@ impl Builder {
         self
     }
 
+    /// Set the compression of the OCI archive, overriding the one from
+    /// [`Builder::compression`].
+    pub fn archive_compression(mut self, compression: crate::tar::ArchiveCompression) -> Self {
+        self.archive_compression = Some(compression);
+        self
+    }
+
+    /// Set the family of media types used for layers.
+    pub fn layer_media_type(mut self, layer_media_type: LayerMediaType) -> Self {
+        self.layer_media_type = layer_media_type;
+        self
+    }
+
+    /// Set the order of entries within each layer.
+    pub fn entry_order(mut self, entry_order: EntryOrder) -> Self {
+        self.tar_options.entry_order = entry_order;
+        self
+    }
+
+    /// Set the normalizers to run on file content.
+    pub fn normalizers(mut self, normalizers: Vec<Normalizer>) -> Self {
+        self.tar_options.normalizers = normalizers;
+        self
+    }
+
     /// Set annotations to add to the image manifest.
     pub fn annotations(mut self, annotations: HashMap<String, String>) -> Self {
         self.annotations = Some(annotations);
@@ -67,16 +254,255 @@ impl Builder {
         self
     }
 
-    /// Build the OCI image and write it to the given output.
-    pub fn build<W: Write>(self, output: &mut W) -> Result<()> {
-        self.build_oci_dir().context("building OCI directory")?;
+    /// Validate the OCI image against the image-spec before writing it out.
+    pub fn validate(mut self, validate: bool) -> Self {
+        self.validate = validate;
+        self
+    }
+
+    /// Embed the components JSON in an extra layer at [`COMPONENTS_JSON_PATH`].
+    pub fn components_json(mut self, content: Vec<u8>, mtime: u64) -> Self {
+        self.components_json = Some((content, mtime));
+        self
+    }
+
+    /// Read the files of the components which come from tarballs applied on
+    /// top of the rootfs from `overlay`.
+    pub fn overlay(mut self, overlay: Arc<Overlay>) -> Self {
+        self.tar_options.overlay = Some(overlay);
+        self
+    }
+
+    /// Write the parent directories of the files of a rootfs extracted from a
+    /// tarball with their metadata from its entries, `metadata`.
+    pub fn rootfs_metadata(mut self, metadata: Arc<FileMap>) -> Self {
+        self.tar_options.rootfs_metadata = Some(metadata);
+        self
+    }
+
+    /// Rewrite symlink targets and find the parent directories of the
+    /// rewritten paths with `rewrites`.
+    pub fn rewrites(mut self, rewrites: Arc<PathRewrites>) -> Self {
+        self.tar_options.rewrites = Some(rewrites);
+        self
+    }
+
+    /// Annotate each layer with the digest of its inputs (see [`crate::attest`]).
+    pub fn inputs_digests(mut self, enabled: bool) -> Self {
+        self.inputs_digests = enabled;
+        self
+    }
+
+    /// Annotate each layer made of packages with their list (see
+    /// [`PACKAGES_ANNOTATION`]).
+    pub fn package_annotations(mut self, enabled: bool) -> Self {
+        self.package_annotations = enabled;
+        self
+    }
+
+    /// Annotate layers with their retention hint according to `rules` (see
+    /// [`RETENTION_ANNOTATION`]).
+    pub fn retention(mut self, rules: RetentionRules) -> Self {
+        self.retention = Some(rules);
+        self
+    }
+
+    /// Extract each layer after writing it and check that its files kept
+    /// their metadata (see [`crate::selftest`]).
+    pub fn self_test(mut self, self_test: SelfTest) -> Self {
+        self.self_test = Some(self_test);
+        self
+    }
+
+    /// Compress the layers whose files are mostly already compressed (e.g.
+    /// jars, PNGs, zstd firmware) at the fastest level, or not at all (see
+    /// [`crate::compressibility`]).
+    pub fn adaptive_compression(mut self, enabled: bool) -> Self {
+        self.adaptive_compression = enabled;
+        self
+    }
+
+    /// Compress each gzip layer on `threads` threads. With more than one, the
+    /// layers differ from those compressed on a single thread, but not
+    /// depending on the number of threads.
+    pub fn compression_threads(mut self, threads: usize) -> Self {
+        self.compression_threads = threads.max(1);
+        self
+    }
+
+    /// Write up to `threads` layers in parallel. Each layer is still written
+    /// by a single thread, and the image doesn't depend on the number of
+    /// threads.
+    pub fn build_threads(mut self, threads: usize) -> Self {
+        self.build_threads = threads.max(1);
+        self
+    }
+
+    /// Read the files of each layer ahead of writing them on `threads`
+    /// threads, or 0 to read each file when it's written.
+    pub fn read_ahead(mut self, threads: usize) -> Self {
+        self.tar_options.read_ahead = threads;
+        self
+    }
+
+    /// Format of the tar headers written in each layer.
+    pub fn tar_format(mut self, format: TarFormat) -> Self {
+        self.tar_options.format = format;
+        self
+    }
+
+    /// Set the user and group names of entries from `names`, rather than
+    /// only their IDs.
+    pub fn owner_names(mut self, names: OwnerNames) -> Self {
+        self.tar_options.owner_names = Some(Arc::new(names));
+        self
+    }
+
+    /// Map the user and group IDs of the rootfs to those of the image.
+    pub fn id_maps(mut self, uid_map: IdMap, gid_map: IdMap) -> Self {
+        self.tar_options.uid_map = uid_map;
+        self.tar_options.gid_map = gid_map;
+        self
+    }
+
+    /// Override the owners and permission bits of matching paths, the last
+    /// matching transformation winning.
+    pub fn transforms(mut self, chown: Vec<Chown>, chmod: Vec<Chmod>) -> Self {
+        self.tar_options.chown = chown;
+        self.tar_options.chmod = chmod;
+        self
+    }
+
+    /// Print the size of each layer to stderr once written.
+    pub fn progress(mut self, enabled: bool) -> Self {
+        self.progress = enabled;
+        self
+    }
+
+    /// Record how long writing each layer took in `metrics`.
+    pub fn metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Record the layers written so far as a checkpoint of `bundle`.
+    pub fn debug_bundle(mut self, bundle: DebugBundle) -> Self {
+        self.debug_bundle = bundle;
+        self
+    }
+
+    /// Write the merged rootfs of the image to the given output as a single
+    /// uncompressed tarball.
+    ///
+    /// Entries get the same metadata, content and ordering rules as in the
+    /// layers. Each path is clamped to the mtime of the topmost layer it's in,
+    /// which is what extracting all the layers results in. The components JSON
+    /// isn't included since it's not part of the rootfs.
+    pub fn write_rootfs_tar<W: Write>(&self, output: &mut W) -> Result<()> {
+        let mut files = FileMap::new();
+        let mut clamps: HashMap<&Utf8Path, u64> = HashMap::new();
+        for (_, component) in &self.components {
+            for (path, info) in &component.files {
+                files.insert(path.clone(), info.clone());
+                // parent directories are written in the layer too
+                for ancestor in path.ancestors() {
+                    clamps.insert(ancestor, component.mtime_clamp);
+                }
+            }
+        }
+
+        let mut tar_builder = tar::Builder::new(&mut *output);
+        crate::tar::write_files_to_tar_with_clamps(
+            &mut tar_builder,
+            &self.rootfs,
+            &files,
+            // SAFETY: only the files and their parents are written, and we
+            // have a clamp for all of them
+            |path| clamps[path],
+            &self.tar_options,
+        )
+        .context("writing files")?;
+
+        tar_builder
+            .into_inner()
+            .context("finishing tar archive")?
+            .flush()
+            .context("flushing output")
+    }
+
+    /// Build the OCI image and write it to the given output. Returns the
+    /// config of the image.
+    pub fn build<W: Write>(self, output: &mut W) -> Result<oci_image::ImageConfiguration> {
+        let (moved, config) = self.build_oci_dir(None).context("building OCI directory")?;
+
+        if self.validate {
+            let oci_dir =
+                ocidir::OciDir::open(self.oci_dir.try_clone().context("cloning temp directory")?)
+                    .context("opening OCI directory")?;
+            crate::validate::validate_oci_dir(&oci_dir).context("validating OCI image")?;
+        }
+
+        // this is done after validation, which only knows about images
+        if let Some(moved) = moved {
+            self.attach_layer_components(moved)
+                .context("attaching layer components artifact")?;
+        }
+
+        self.write_archive(output)?;
+        Ok(config)
+    }
+
+    /// Build the OCI image with the layers of the components on top of the
+    /// layers of `base`, and write it to the given output. The layers of
+    /// `base` are only referenced by digest, not copied, so the image can
+    /// only be pulled from registries which already have them. Returns the
+    /// config of the image.
+    pub fn build_on<W: Write>(
+        self,
+        base: &BaseImage,
+        output: &mut W,
+    ) -> Result<oci_image::ImageConfiguration> {
+        anyhow::ensure!(
+            !self.validate,
+            "validating images without their base layers isn't supported"
+        );
+        let (moved, config) = self
+            .build_oci_dir(Some(base))
+            .context("building OCI directory")?;
+        if let Some(moved) = moved {
+            self.attach_layer_components(moved)
+                .context("attaching layer components artifact")?;
+        }
+
+        self.write_archive(output)?;
+        Ok(config)
+    }
+
+    /// Build the layers of the components, and write out `base` with its
+    /// layers for the same components replaced by them. All the other layers
+    /// are copied as is, so they keep their digests.
+    pub fn rebuild<W: Write>(self, base: &BaseImage, output: &mut W) -> Result<()> {
+        self.splice_into(base)
+            .context("splicing layers into image")?;
+
+        if self.validate {
+            let oci_dir =
+                ocidir::OciDir::open(self.oci_dir.try_clone().context("cloning temp directory")?)
+                    .context("opening OCI directory")?;
+            crate::validate::validate_oci_dir(&oci_dir).context("validating OCI image")?;
+        }
+
+        self.write_archive(output)
+    }
 
-        let compression = match self.compression {
+    /// Write the OCI directory out as an archive.
+    fn write_archive<W: Write>(&self, output: &mut W) -> Result<()> {
+        let compression = self.archive_compression.unwrap_or(match self.compression {
             Compression::None => crate::tar::ArchiveCompression::None,
             Compression::Gzip(level) => {
                 crate::tar::ArchiveCompression::Gzip(flate2::Compression::new(level))
             }
-        };
+        });
 
         crate::tar::write_oci_archive(&self.oci_dir, &mut *output, compression)
             .context("writing OCI archive")?;
@@ -84,7 +510,94 @@ impl Builder {
         output.flush().context("flushing output")
     }
 
-    fn build_oci_dir(&self) -> Result<()> {
+    /// Build the image in the OCI directory from `base`, with the layers of
+    /// our components in place of the layers of the same name.
+    fn splice_into(&self, base: &BaseImage) -> Result<()> {
+        let oci_dir =
+            ocidir::OciDir::ensure(self.oci_dir.try_clone().context("cloning temp directory")?)
+                .context("creating OCI directory")?;
+
+        // build the new layers on their own first, to get their descriptors,
+        // diff_ids and history entries
+        let mut new_manifest = oci_dir
+            .new_empty_manifest()
+            .context("creating empty manifest")?
+            .build()
+            .context("building manifest")?;
+        let mut new_config = oci_image::ImageConfiguration::default();
+        self.add_components(&mut new_manifest, &mut new_config)
+            .context("adding layers to OCI directory")?;
+        let new_history = new_config.history().clone().unwrap_or_default();
+
+        let mut manifest = base.manifest.clone();
+        let mut diff_ids = base.config.rootfs().diff_ids().clone();
+        anyhow::ensure!(
+            diff_ids.len() == manifest.layers().len(),
+            "image has {} diff_ids for {} layers",
+            diff_ids.len(),
+            manifest.layers().len()
+        );
+        let mut history = base.config.history().clone().unwrap_or_default();
+        // history entries for empty layers (e.g. ENV) don't have a layer
+        let layer_history: Vec<usize> = history
+            .iter()
+            .enumerate()
+            .filter(|(_, h)| !h.empty_layer().unwrap_or(false))
+            .map(|(i, _)| i)
+            .collect();
+
+        let mut replaced = vec![false; manifest.layers().len()];
+        for (j, layer) in new_manifest.layers().iter().enumerate() {
+            let name = layer_component(layer).context("new layer has no component")?;
+            let i = base
+                .layer_index(name)
+                .with_context(|| format!("no layer for {name} in the image"))?;
+            manifest.layers_mut()[i] = layer.clone();
+            diff_ids[i] = new_config.rootfs().diff_ids()[j].clone();
+            if let (Some(&h), Some(entry)) = (layer_history.get(i), new_history.get(j)) {
+                history[h] = entry.clone();
+            }
+            replaced[i] = true;
+        }
+
+        for (layer, _) in base
+            .manifest
+            .layers()
+            .iter()
+            .zip(replaced)
+            .filter(|(_, replaced)| !replaced)
+        {
+            base.copy_blob(layer, &self.oci_dir)
+                .with_context(|| format!("copying layer {}", layer.digest()))?;
+        }
+
+        let mut config = base.config.clone();
+        let mut rootfs = config.rootfs().clone();
+        rootfs.set_diff_ids(diff_ids);
+        config.set_rootfs(rootfs);
+        config.set_history((!history.is_empty()).then_some(history));
+
+        let platform = oci_image::PlatformBuilder::default()
+            .os(config.os().clone())
+            .architecture(config.architecture().clone())
+            .build()
+            .context("building platform")?;
+        oci_dir
+            .insert_manifest_and_config(manifest, config, None, platform)
+            .context("inserting manifest and config")?;
+        Ok(())
+    }
+
+    /// Build the image in the OCI directory, on top of the layers of `base` if
+    /// provided.
+    ///
+    /// If the manifest would exceed [`MAX_MANIFEST_SIZE`], the component names
+    /// are removed from the layer annotations and returned so they can be
+    /// attached as an artifact instead.
+    fn build_oci_dir(
+        &self,
+        base: Option<&BaseImage>,
+    ) -> Result<(Option<MovedComponents>, oci_image::ImageConfiguration)> {
         let oci_dir =
             ocidir::OciDir::ensure(self.oci_dir.try_clone().context("cloning temp directory")?)
                 .context("creating OCI directory")?;
@@ -98,6 +611,16 @@ impl Builder {
 
         let mut config = self.config.clone().unwrap_or_default();
 
+        // the base layers are only referenced, so they aren't in the OCI
+        // directory
+        if let Some(base) = base {
+            manifest.set_layers(base.manifest.layers().clone());
+            let mut rootfs = config.rootfs().clone();
+            rootfs.set_diff_ids(base.config.rootfs().diff_ids().clone());
+            config.set_rootfs(rootfs);
+            config.set_history(base.config.history().clone());
+        }
+
         // this is the important bit: we add all the layers
         self.add_components(&mut manifest, &mut config)
             .context("adding layers to OCI directory")?;
@@ -106,6 +629,24 @@ impl Builder {
             manifest.set_annotations(Some(annotations.clone()));
         }
 
+        let size = manifest_size(&manifest)?;
+        let components = if size > MAX_MANIFEST_SIZE {
+            let components = take_layer_components(&mut manifest);
+            crate::diagnostics::report(&[crate::diagnostics::Diagnostic::warning(format!(
+                "manifest would be {size} bytes, over the {MAX_MANIFEST_SIZE} bytes registries accept; \
+                 moving layer component names to an attached {LAYER_COMPONENTS_ARTIFACT_TYPE} artifact"
+            ))]);
+            let size = manifest_size(&manifest)?;
+            if size > MAX_MANIFEST_SIZE {
+                crate::diagnostics::report(&[crate::diagnostics::Diagnostic::warning(format!(
+                    "manifest is still {size} bytes; reduce the number or size of annotations"
+                ))]);
+            }
+            Some(components)
+        } else {
+            None
+        };
+
         let arch = config.architecture().to_string();
         let platform = oci_image::PlatformBuilder::default()
             .os("linux")
@@ -113,73 +654,317 @@ impl Builder {
             .build()
             .context("building platform")?;
 
-        oci_dir
-            .insert_manifest_and_config(manifest, config, None, platform)
+        let subject = oci_dir
+            .insert_manifest_and_config(manifest, config.clone(), None, platform.clone())
             .context("inserting manifest and config")?;
 
+        let moved = components.map(|components| MovedComponents {
+            subject,
+            platform,
+            components,
+        });
+        Ok((moved, config))
+    }
+
+    /// Attach the layer to component mapping as an artifact referring to the
+    /// image manifest.
+    fn attach_layer_components(&self, moved: MovedComponents) -> Result<()> {
+        let oci_dir = ocidir::OciDir::open(self.oci_dir.try_clone().context("cloning oci_dir")?)
+            .context("opening OCI directory")?;
+
+        let artifact_type = oci_image::MediaType::Other(LAYER_COMPONENTS_ARTIFACT_TYPE.into());
+        let empty_config = oci_dir
+            .write_json_blob(&serde_json::json!({}), oci_image::MediaType::EmptyJSON)
+            .context("writing empty config")?
+            .build()
+            .context("building empty config descriptor")?;
+        let blob = oci_dir
+            .write_json_blob(&moved.components, artifact_type.clone())
+            .context("writing layer components")?
+            .build()
+            .context("building layer components descriptor")?;
+
+        let artifact = oci_image::ImageManifestBuilder::default()
+            .schema_version(oci_image::SCHEMA_VERSION)
+            .media_type(oci_image::MediaType::ImageManifest)
+            .artifact_type(artifact_type)
+            .config(empty_config)
+            .layers(vec![blob])
+            .subject(moved.subject)
+            .build()
+            .context("building artifact manifest")?;
+        oci_dir
+            .insert_manifest(artifact, None, moved.platform)
+            .context("inserting artifact manifest")?;
+
         Ok(())
     }
 
     /// Add layers to the OCI directory and update the manifest and config.
+    ///
+    /// The layers are written on up to `build_threads` threads, and added to
+    /// the manifest in the order of the components as they complete.
     fn add_components(
         &self,
         manifest: &mut oci_image::ImageManifest,
         config: &mut oci_image::ImageConfiguration,
     ) -> Result<()> {
-        for (name, component) in &self.components {
-            if component.files.is_empty() {
-                continue;
+        let components: Vec<&(String, Component)> = self
+            .components
+            .iter()
+            .filter(|(_, component)| !component.files.is_empty())
+            .collect();
+        let next = AtomicUsize::new(0);
+        let stop = AtomicBool::new(false);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        // threads don't inherit the subscriber of the build, nor its span
+        let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+        let span = tracing::Span::current();
+        std::thread::scope(|scope| {
+            for _ in 0..self.build_threads.min(components.len()) {
+                let sender = sender.clone();
+                let (next, stop, components) = (&next, &stop, &components);
+                let (dispatch, span) = (&dispatch, &span);
+                scope.spawn(move || {
+                    let _dispatch = tracing::dispatcher::set_default(dispatch);
+                    let _span = span.enter();
+                    while !stop.load(Ordering::Relaxed) {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some((name, component)) = components.get(index) else {
+                            break;
+                        };
+                        let started = std::time::Instant::now();
+                        let layer = self
+                            .write_component_layer(name, component)
+                            .with_context(|| format!("adding component {name}"));
+                        // the receiver is only gone if adding a layer failed
+                        if sender.send((index, layer, started.elapsed())).is_err() {
+                            break;
+                        }
+                    }
+                });
             }
-            self.add_component(manifest, config, name, component)
-                .with_context(|| format!("adding component {}", name))?;
+            drop(sender);
+            let result = self.push_component_layers(manifest, config, &components, receiver);
+            if result.is_err() {
+                stop.store(true, Ordering::Relaxed);
+            }
+            result
+        })?;
+
+        // the metadata goes last since it changes whenever anything else does
+        if let Some((content, mtime)) = &self.components_json {
+            let spec = LayerSpec {
+                name: METADATA_COMPONENT,
+                stability: 0.0,
+                mtime_clamp: *mtime,
+            };
+            let layer = self
+                .write_layer(self.compression, |tar_builder| {
+                    crate::tar::write_generated_file(
+                        tar_builder,
+                        &self.rootfs,
+                        camino::Utf8Path::new(COMPONENTS_JSON_PATH),
+                        content,
+                        *mtime,
+                        &self.tar_options,
+                    )
+                })
+                .context("writing metadata layer")?;
+            self.push_layer(manifest, config, &spec, layer)
+                .context("adding metadata layer")?;
         }
 
         Ok(())
     }
 
-    /// Add a single component as a layer to the OCI directory.
-    fn add_component(
+    /// Write the layer of a component, with its compression adapted to its
+    /// content if requested.
+    fn write_component_layer(&self, name: &str, component: &Component) -> Result<ocidir::Layer> {
+        let _span = tracing::debug_span!("layer", component = name).entered();
+        let compression = if self.adaptive_compression {
+            let compressed = crate::compressibility::compressed_fraction(
+                &self.rootfs,
+                &component.files,
+                self.tar_options.overlay.as_deref(),
+            )
+            .with_context(|| format!("sampling content of component {name}"))?;
+            crate::compressibility::adapt(self.compression, compressed)
+        } else {
+            self.compression
+        };
+        self.write_layer(compression, |tar_builder| {
+            crate::tar::write_files_to_tar(
+                tar_builder,
+                &self.rootfs,
+                &component.files,
+                component.mtime_clamp,
+                &self.tar_options,
+            )
+        })
+    }
+
+    /// Add the layers of `components` to the manifest in order, as they're
+    /// received from the threads writing them.
+    fn push_component_layers(
         &self,
         manifest: &mut oci_image::ImageManifest,
         config: &mut oci_image::ImageConfiguration,
-        name: &str,
-        component: &Component,
+        components: &[&(String, Component)],
+        receiver: Receiver<(usize, Result<ocidir::Layer>, Duration)>,
     ) -> Result<()> {
+        let mut pending = HashMap::new();
+        for (index, (name, component)) in components.iter().enumerate() {
+            let (layer, elapsed) = loop {
+                if let Some(written) = pending.remove(&index) {
+                    break written;
+                }
+                let (written_index, layer, elapsed) = receiver
+                    .recv()
+                    .context("layer writing threads stopped early")?;
+                pending.insert(written_index, (layer, elapsed));
+            };
+            let spec = LayerSpec {
+                name,
+                stability: component.stability,
+                mtime_clamp: component.mtime_clamp,
+            };
+            self.push_layer(manifest, config, &spec, layer?)
+                .with_context(|| format!("adding component {}", name))?;
+            // SAFETY: we just added a layer
+            let size = manifest.layers().last().unwrap().size();
+            self.metrics.layer(
+                name,
+                component.files.len(),
+                component.files.values().map(|f| f.size).sum(),
+                size,
+                elapsed,
+            );
+            if self.progress {
+                crate::progress::layer_written(
+                    index,
+                    components.len(),
+                    name,
+                    component.files.len(),
+                    size,
+                );
+            }
+            self.debug_bundle.checkpoint("layers", || {
+                let layers: Vec<_> = manifest
+                    .layers()
+                    .iter()
+                    .map(|layer| {
+                        serde_json::json!({
+                            "digest": layer.digest().to_string(),
+                            "size": layer.size(),
+                            "component": layer.annotations().as_ref().and_then(|a| a.get(COMPONENT_ANNOTATION)),
+                        })
+                    })
+                    .collect();
+                serde_json::Value::Array(layers)
+            });
+
+            if self.package_annotations && !component.packages.is_empty() {
+                // SAFETY: we just added a layer
+                let desc = manifest.layers_mut().last_mut().unwrap();
+                let mut annotations = desc.annotations().clone().unwrap_or_default();
+                let packages: Vec<&str> = component.packages.iter().map(String::as_str).collect();
+                annotations.insert(PACKAGES_ANNOTATION.to_string(), packages.join(" "));
+                desc.set_annotations(Some(annotations));
+            }
+
+            // merged layers are named after all their components
+            if let Some(retention) = self
+                .retention
+                .as_ref()
+                .and_then(|rules| rules.layer_retention(name.split(' ')))
+            {
+                // SAFETY: we just added a layer
+                let desc = manifest.layers_mut().last_mut().unwrap();
+                let mut annotations = desc.annotations().clone().unwrap_or_default();
+                annotations.insert(RETENTION_ANNOTATION.to_string(), retention.to_string());
+                desc.set_annotations(Some(annotations));
+            }
+
+            if let Some(self_test) = &self.self_test {
+                let oci_dir =
+                    ocidir::OciDir::open(self.oci_dir.try_clone().context("cloning oci_dir")?)
+                        .context("opening OCI directory")?;
+                // SAFETY: we just added a layer
+                let layer = manifest.layers().last().unwrap();
+                crate::selftest::check_layer(
+                    &oci_dir,
+                    layer,
+                    &component.files,
+                    component.mtime_clamp,
+                    &self.tar_options,
+                    self_test,
+                )
+                .with_context(|| format!("self-testing layer of component {name}"))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write a single layer blob to the OCI directory, with its content
+    /// written by `write_content`. Each call opens its own handle on the OCI
+    /// directory, so layers can be written from several threads.
+    fn write_layer<F>(&self, compression: Compression, write_content: F) -> Result<ocidir::Layer>
+    where
+        F: FnOnce(&mut tar::Builder<crate::tar::LayerWriter<'_>>) -> Result<()>,
+    {
         let oci_dir = ocidir::OciDir::open(self.oci_dir.try_clone().context("cloning oci_dir")?)
             .context("opening OCI directory")?;
-        let mut tar_builder =
-            crate::tar::create_layer(&oci_dir, self.compression).context("creating layer")?;
-
-        crate::tar::write_files_to_tar(
-            &mut tar_builder,
-            &self.rootfs,
-            &component.files,
-            component.mtime_clamp,
+        let mut tar_builder = crate::tar::create_layer_with_threads(
+            &oci_dir,
+            compression,
+            self.layer_media_type,
+            self.compression_threads,
         )
-        .context("building tar layer")?;
+        .context("creating layer")?;
+
+        write_content(&mut tar_builder).context("building tar layer")?;
 
         tar_builder.finish().context("finishing layer tar")?;
-        let layer = tar_builder
+        tar_builder
             .into_inner()
             .context("getting layer writer")?
             .complete()
-            .context("completing layer")?;
+            .context("completing layer")
+    }
+
+    /// Add a layer written by [`Builder::write_layer`] to the manifest and
+    /// config.
+    fn push_layer(
+        &self,
+        manifest: &mut oci_image::ImageManifest,
+        config: &mut oci_image::ImageConfiguration,
+        spec: &LayerSpec<'_>,
+        layer: ocidir::Layer,
+    ) -> Result<()> {
+        let LayerSpec {
+            name,
+            stability,
+            mtime_clamp,
+        } = *spec;
+        let oci_dir = ocidir::OciDir::open(self.oci_dir.try_clone().context("cloning oci_dir")?)
+            .context("opening OCI directory")?;
 
         let annotations = {
             let mut hm = HashMap::new();
-            hm.insert("org.chunkah.component".to_string(), name.to_string());
+            hm.insert(COMPONENT_ANNOTATION.to_string(), name.to_string());
             hm.insert(
-                "org.chunkah.stability".to_string(),
-                format!("{:.3}", component.stability),
+                STABILITY_ANNOTATION.to_string(),
+                format!("{:.3}", stability),
             );
             hm
         };
 
-        let mtime_i64 =
-            i64::try_from(component.mtime_clamp).context("mtime_clamp overflows i64")?;
+        let mtime_i64 = i64::try_from(mtime_clamp).context("mtime_clamp overflows i64")?;
 
         let created = chrono::DateTime::from_timestamp(mtime_i64, 0)
-            .with_context(|| format!("invalid mtime_clamp: {}", component.mtime_clamp))?
+            .with_context(|| format!("invalid mtime_clamp: {}", mtime_clamp))?
             .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
 
         let history = oci_image::HistoryBuilder::default()
@@ -198,14 +983,270 @@ impl Builder {
             Some(history),
         );
 
+        if self.inputs_digests {
+            // SAFETY: we just pushed a layer
+            let desc = manifest.layers_mut().last_mut().unwrap();
+            let digest = crate::attest::layer_inputs_digest(&oci_dir, desc)
+                .context("computing inputs digest")?;
+            let mut annotations = desc.annotations().clone().unwrap_or_default();
+            annotations.insert(crate::attest::INPUTS_DIGEST_ANNOTATION.to_string(), digest);
+            desc.set_annotations(Some(annotations));
+        }
+
+        Ok(())
+    }
+}
+
+/// The properties of a layer besides its content.
+struct LayerSpec<'a> {
+    name: &'a str,
+    stability: f64,
+    mtime_clamp: u64,
+}
+
+/// An image previously built by chunkah, whose layers can be rebuilt with
+/// [`Builder::rebuild`].
+pub struct BaseImage {
+    oci_dir: ocidir::OciDir,
+    manifest: oci_image::ImageManifest,
+    config: oci_image::ImageConfiguration,
+}
+
+impl BaseImage {
+    /// Open the image in the OCI image layout at `path`.
+    pub fn open(path: &Utf8Path) -> Result<Self> {
+        let dir = Dir::open_ambient_dir(path, cap_std_ext::cap_std::ambient_authority())
+            .with_context(|| format!("opening {path}"))?;
+        let oci_dir = ocidir::OciDir::open(dir).context("opening OCI image layout")?;
+
+        let (_, manifest) = crate::utils::read_image_manifest(&oci_dir)?;
+        let config: oci_image::ImageConfiguration = oci_dir
+            .read_json_blob(manifest.config())
+            .context("reading config")?;
+        Ok(Self {
+            oci_dir,
+            manifest,
+            config,
+        })
+    }
+
+    /// Returns the names of the layers, i.e. their component names joined
+    /// with spaces when several components were packed together.
+    pub fn layer_names(&self) -> Vec<Option<&str>> {
+        self.manifest.layers().iter().map(layer_component).collect()
+    }
+
+    /// Returns the number of layers of the image.
+    pub fn layer_count(&self) -> usize {
+        self.manifest.layers().len()
+    }
+
+    /// Returns the stability the layer named `name` was built with.
+    pub fn layer_stability(&self, name: &str) -> Option<f64> {
+        layer_stability(&self.manifest.layers()[self.layer_index(name)?])
+    }
+
+    /// Returns the image config.
+    pub fn config(&self) -> &oci_image::ImageConfiguration {
+        &self.config
+    }
+
+    /// Unpack the layers of the image into the directory `dest`, i.e. the
+    /// rootfs of a container running it.
+    ///
+    /// Ownership is only preserved when running as root.
+    pub fn unpack(&self, dest: &Utf8Path) -> Result<()> {
+        unpack_layers(&self.oci_dir, self.manifest.layers(), dest)
+    }
+
+    fn layer_index(&self, name: &str) -> Option<usize> {
+        self.manifest
+            .layers()
+            .iter()
+            .position(|layer| layer_component(layer) == Some(name))
+    }
+
+    /// Copy the blob of `desc` to the OCI directory `dest`.
+    fn copy_blob(&self, desc: &oci_image::Descriptor, dest: &Dir) -> Result<()> {
+        let digest = desc.digest().to_string();
+        let (algorithm, hex) = digest
+            .split_once(':')
+            .with_context(|| format!("invalid digest {digest}"))?;
+        let dir = format!("blobs/{algorithm}");
+        dest.create_dir_all(&dir)
+            .with_context(|| format!("creating {dir}"))?;
+        let path = format!("{dir}/{hex}");
+        let mut src = self.oci_dir.read_blob(desc).context("opening blob")?;
+        let mut dst = dest
+            .create(&path)
+            .with_context(|| format!("creating {path}"))?
+            .into_std();
+        std::io::copy(&mut src, &mut dst).with_context(|| format!("writing {path}"))?;
         Ok(())
     }
 }
 
+/// Unpack `layers` from `oci_dir` into the directory `dest`, i.e. the
+/// rootfs of a container running the image.
+///
+/// Ownership is only preserved when running as root.
+pub(crate) fn unpack_layers(
+    oci_dir: &ocidir::OciDir,
+    layers: &[oci_image::Descriptor],
+    dest: &Utf8Path,
+) -> Result<()> {
+    let as_root = dest
+        .metadata()
+        .with_context(|| format!("querying {dest}"))?
+        .uid()
+        == 0;
+    for (i, layer) in layers.iter().enumerate() {
+        anyhow::ensure!(
+            crate::tar::layer_is_gzip(layer.media_type()).is_some(),
+            "layer {i}: unsupported media type {}",
+            layer.media_type()
+        );
+        // whiteouts only apply to lower layers, regardless of where they
+        // are in the tarball, so apply them in a first pass
+        let reader =
+            crate::tar::read_layer(oci_dir, layer).with_context(|| format!("opening layer {i}"))?;
+        apply_whiteouts(reader, dest)
+            .with_context(|| format!("applying whiteouts of layer {i}"))?;
+
+        let reader =
+            crate::tar::read_layer(oci_dir, layer).with_context(|| format!("opening layer {i}"))?;
+        let mut archive = tar::Archive::new(reader);
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_mtime(true);
+        archive.set_unpack_xattrs(true);
+        archive.set_preserve_ownerships(as_root);
+        archive.set_overwrite(true);
+        for entry in archive.entries().context("reading entries")? {
+            let mut entry = entry.context("reading entry")?;
+            let path = entry.path().context("reading entry path")?;
+            if !is_whiteout(&path) {
+                entry
+                    .unpack_in(dest)
+                    .with_context(|| format!("unpacking layer {i}"))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether the layer entry at `path` is a whiteout.
+fn is_whiteout(path: &std::path::Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(WHITEOUT_PREFIX))
+}
+
+/// Delete the paths whited out by the layer tarball `reader` from `dest`.
+fn apply_whiteouts<R: std::io::Read>(reader: R, dest: &Utf8Path) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries().context("reading entries")? {
+        let entry = entry.context("reading entry")?;
+        let path = std::str::from_utf8(&entry.path_bytes())
+            .context("path is not valid UTF-8")?
+            .trim_matches('/')
+            .to_string();
+        let path = Utf8PathBuf::from(path);
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            continue;
+        };
+        let Some(target) = name.strip_prefix(WHITEOUT_PREFIX) else {
+            continue;
+        };
+        // don't let whiteouts delete anything outside of `dest`
+        anyhow::ensure!(
+            !matches!(target, "" | "." | "..")
+                && parent
+                    .components()
+                    .all(|c| matches!(c, Utf8Component::CurDir | Utf8Component::Normal(_))),
+            "invalid whiteout {path}"
+        );
+        let parent = dest.join(parent);
+        if name == OPAQUE_WHITEOUT {
+            let entries = match parent.read_dir_utf8() {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).with_context(|| format!("reading {parent}")),
+            };
+            for child in entries {
+                let child = child.with_context(|| format!("reading {parent}"))?;
+                remove_path(child.path())?;
+            }
+        } else {
+            remove_path(&parent.join(target))?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove the file or directory tree at `path`, if it exists.
+fn remove_path(path: &Utf8Path) -> Result<()> {
+    let result = match path.symlink_metadata() {
+        Ok(meta) if meta.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => Err(e),
+    };
+    result.with_context(|| format!("removing {path}"))
+}
+
+/// Returns the component name of a layer built by chunkah.
+pub fn layer_component(layer: &oci_image::Descriptor) -> Option<&str> {
+    layer
+        .annotations()
+        .as_ref()?
+        .get(COMPONENT_ANNOTATION)
+        .map(String::as_str)
+}
+
+/// Returns the stability a layer built by chunkah was built with.
+pub fn layer_stability(layer: &oci_image::Descriptor) -> Option<f64> {
+    layer
+        .annotations()
+        .as_ref()?
+        .get(STABILITY_ANNOTATION)?
+        .parse()
+        .ok()
+}
+
+/// Component names moved out of an oversized manifest.
+struct MovedComponents {
+    /// The image manifest the components artifact refers to.
+    subject: oci_image::Descriptor,
+    /// Platform of the image.
+    platform: oci_image::Platform,
+    /// Mapping from layer digest to component name.
+    components: BTreeMap<String, String>,
+}
+
+/// Returns the size of the manifest once serialized.
+fn manifest_size(manifest: &oci_image::ImageManifest) -> Result<u64> {
+    let json = serde_json::to_vec(manifest).context("serializing manifest")?;
+    Ok(json.len() as u64)
+}
+
+/// Remove the component names from the layer annotations, returning the
+/// mapping from layer digest to component name.
+fn take_layer_components(manifest: &mut oci_image::ImageManifest) -> BTreeMap<String, String> {
+    let mut components = BTreeMap::new();
+    for layer in manifest.layers_mut() {
+        let digest = layer.digest().to_string();
+        let mut annotations = layer.annotations().clone().unwrap_or_default();
+        if let Some(name) = annotations.remove(COMPONENT_ANNOTATION) {
+            components.insert(digest, name);
+        }
+        layer.set_annotations((!annotations.is_empty()).then_some(annotations));
+    }
+    components
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use camino::Utf8PathBuf;
     use cap_std_ext::cap_std::ambient_authority;
     use cap_std_ext::cap_std::fs::PermissionsExt;
     use cap_std_ext::dirext::CapStdExtDirExt;
@@ -261,6 +1302,19 @@ mod tests {
     fn build_and_extract<F>(rootfs_setup: F, specs: Vec<ComponentSpec>) -> TestOciResult
     where
         F: FnOnce(&Dir),
+    {
+        build_and_extract_with(rootfs_setup, specs, |builder| builder)
+    }
+
+    /// Same as `build_and_extract`, but allows further configuring the builder.
+    fn build_and_extract_with<F, B>(
+        rootfs_setup: F,
+        specs: Vec<ComponentSpec>,
+        configure: B,
+    ) -> TestOciResult
+    where
+        F: FnOnce(&Dir),
+        B: FnOnce(Builder) -> Builder,
     {
         // Create temp rootfs and run setup
         let rootfs_dir = tempfile::tempdir().unwrap();
@@ -284,6 +1338,7 @@ mod tests {
                         mtime_clamp,
                         stability: 0.0,
                         files,
+                        packages: Default::default(),
                     },
                 )
             })
@@ -307,7 +1362,9 @@ mod tests {
         let builder = Builder::new(&rootfs, components)
             .unwrap()
             .compression(Compression::None)
-            .config(config);
+            .config(config)
+            .validate(true);
+        let builder = configure(builder);
         let mut output = Vec::new();
         builder.build(&mut output).unwrap();
 
@@ -607,4 +1664,468 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_components_json_layer() {
+        let result = build_and_extract_with(
+            |rootfs| {
+                rootfs.create_dir_all("usr/share").unwrap();
+                rootfs
+                    .set_permissions(
+                        "usr/share",
+                        cap_std_ext::cap_std::fs::Permissions::from_mode(0o750),
+                    )
+                    .unwrap();
+                rootfs.write("file_a", "content a").unwrap();
+            },
+            vec![(
+                "component_a",
+                btreeset! { Utf8PathBuf::from("/file_a") },
+                1000,
+            )],
+            |builder| builder.components_json(b"{}".to_vec(), 500),
+        );
+
+        let layers = result.manifest.layers();
+        assert_eq!(layers.len(), 2);
+        let metadata_layer = layers.last().unwrap();
+        assert_eq!(
+            metadata_layer
+                .annotations()
+                .as_ref()
+                .and_then(|a| a.get("org.chunkah.component"))
+                .map(|s| s.as_str()),
+            Some(METADATA_COMPONENT)
+        );
+
+        let mut layer_tar = result.read_layer_tar(metadata_layer);
+        let mut paths = Vec::new();
+        for entry in layer_tar.entries().unwrap() {
+            let entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().to_string();
+            let mode = entry.header().mode().unwrap() & 0o777;
+            match path.as_str() {
+                // existing directories keep their metadata from the rootfs
+                "usr/share/" => assert_eq!(mode, 0o750),
+                "usr/share/chunkah/" => assert_eq!(mode, 0o755),
+                "usr/share/chunkah/components.json" => {
+                    assert_eq!(mode, 0o644);
+                    assert_eq!(entry.header().mtime().unwrap(), 500);
+                }
+                _ => {}
+            }
+            paths.push(path);
+        }
+        assert_eq!(
+            paths,
+            [
+                "usr/",
+                "usr/share/",
+                "usr/share/chunkah/",
+                "usr/share/chunkah/components.json"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_rootfs_tar() {
+        use fs_set_times::{SetTimes, SystemTimeSpec};
+
+        let rootfs_dir = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(rootfs_dir.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("usr/bin").unwrap();
+        rootfs.write("usr/bin/a", "a").unwrap();
+        rootfs.write("usr/bin/b", "b").unwrap();
+        let mtime = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(2000);
+        for path in ["usr", "usr/bin", "usr/bin/a", "usr/bin/b"] {
+            rootfs
+                .open(path)
+                .unwrap()
+                .set_times(None, Some(SystemTimeSpec::Absolute(mtime)))
+                .unwrap();
+        }
+        let all_files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+
+        // both components have /usr/bin, and the second one gets /usr as a
+        // parent directory
+        let component = |paths: &[&str], mtime_clamp| Component {
+            mtime_clamp,
+            stability: 0.0,
+            files: paths
+                .iter()
+                .map(|p| (Utf8PathBuf::from(*p), all_files[Utf8Path::new(p)].clone()))
+                .collect(),
+            packages: Default::default(),
+        };
+        let components = vec![
+            (
+                "a".to_string(),
+                component(&["/usr", "/usr/bin", "/usr/bin/a"], 500),
+            ),
+            (
+                "b".to_string(),
+                component(&["/usr/bin", "/usr/bin/b"], 1000),
+            ),
+        ];
+        let builder = Builder::new(&rootfs, components).unwrap();
+        let mut output = Vec::new();
+        builder.write_rootfs_tar(&mut output).unwrap();
+
+        let mut archive = tar::Archive::new(output.as_slice());
+        let entries: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|e| {
+                let entry = e.unwrap();
+                let path = entry.path().unwrap().to_string_lossy().to_string();
+                (path, entry.header().mtime().unwrap())
+            })
+            .collect();
+        // the topmost layer a path is in decides its clamp
+        assert_eq!(
+            entries,
+            [
+                ("usr/".to_string(), 1000),
+                ("usr/bin/".to_string(), 1000),
+                ("usr/bin/a".to_string(), 500),
+                ("usr/bin/b".to_string(), 1000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_inputs_digests() {
+        let result = build_and_extract_with(
+            |rootfs| {
+                rootfs.write("file_a", "content a").unwrap();
+            },
+            vec![(
+                "component_a",
+                btreeset! { Utf8PathBuf::from("/file_a") },
+                1000,
+            )],
+            |builder| builder.inputs_digests(true),
+        );
+
+        let layer = result.first_layer();
+        let digest = layer
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get(crate::attest::INPUTS_DIGEST_ANNOTATION))
+            .expect("layer should have an inputs digest");
+        assert_eq!(
+            digest,
+            &crate::attest::layer_inputs_digest(&result.oci_dir, layer).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_docker_layer_media_type() {
+        let result = build_and_extract_with(
+            |rootfs| {
+                rootfs.write("file_a", "content a").unwrap();
+            },
+            vec![(
+                "component_a",
+                btreeset! { Utf8PathBuf::from("/file_a") },
+                1000,
+            )],
+            |builder| builder.layer_media_type(LayerMediaType::Docker),
+        );
+
+        let layer = result.first_layer();
+        assert_eq!(
+            layer.media_type().to_string(),
+            crate::tar::DOCKER_LAYER_MEDIA_TYPE
+        );
+        let mut archive =
+            tar::Archive::new(crate::tar::read_layer(&result.oci_dir, layer).unwrap());
+        assert!(
+            archive
+                .entries()
+                .unwrap()
+                .any(|e| e.unwrap().path().unwrap().ends_with("file_a"))
+        );
+    }
+
+    #[test]
+    fn test_adaptive_compression() {
+        let result = build_and_extract_with(
+            |rootfs| {
+                let mut jar = b"PK\x03\x04".to_vec();
+                jar.resize(1000, 0);
+                rootfs.write("app.jar", jar).unwrap();
+                rootfs.write("app.conf", "key = value").unwrap();
+            },
+            vec![
+                ("jar", btreeset! { Utf8PathBuf::from("/app.jar") }, 1000),
+                ("conf", btreeset! { Utf8PathBuf::from("/app.conf") }, 1000),
+            ],
+            |builder| {
+                builder
+                    .compression(Compression::Gzip(6))
+                    .archive_compression(crate::tar::ArchiveCompression::None)
+                    .adaptive_compression(true)
+            },
+        );
+
+        let media_types: Vec<_> = result
+            .manifest
+            .layers()
+            .iter()
+            .map(|layer| layer.media_type().clone())
+            .collect();
+        assert_eq!(
+            media_types,
+            [
+                oci_image::MediaType::ImageLayer,
+                oci_image::MediaType::ImageLayerGzip
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_threads() {
+        let build = |threads| {
+            build_and_extract_with(
+                |rootfs| {
+                    for i in 0..8 {
+                        rootfs
+                            .write(format!("file{i}"), vec![b'a' + i; 1000])
+                            .unwrap();
+                    }
+                },
+                (0..8)
+                    .map(|i| {
+                        (
+                            ["a", "b", "c", "d", "e", "f", "g", "h"][i],
+                            btreeset! { Utf8PathBuf::from(format!("/file{i}")) },
+                            1000,
+                        )
+                    })
+                    .collect(),
+                |builder| {
+                    builder
+                        .compression(Compression::Gzip(6))
+                        .archive_compression(crate::tar::ArchiveCompression::None)
+                        .build_threads(threads)
+                },
+            )
+        };
+
+        let sequential = build(1);
+        let parallel = build(3);
+        assert_eq!(sequential.manifest.layers().len(), 8);
+        assert_eq!(parallel.manifest, sequential.manifest);
+        assert_eq!(parallel.image_config, sequential.image_config);
+    }
+
+    #[test]
+    fn test_oversized_manifest() {
+        // a component name too big for the manifest, as can happen when
+        // packing merges a lot of components together
+        let name: &'static str = "x".repeat(MAX_MANIFEST_SIZE as usize).leak();
+        let result = build_and_extract(
+            |rootfs| {
+                rootfs.write("file_a", "content a").unwrap();
+            },
+            vec![(name, btreeset! { Utf8PathBuf::from("/file_a") }, 1000)],
+        );
+
+        // the name moved out of the layer annotations
+        let layer = result.first_layer();
+        assert!(
+            layer
+                .annotations()
+                .as_ref()
+                .unwrap()
+                .contains_key("org.chunkah.stability")
+        );
+        assert!(
+            !layer
+                .annotations()
+                .as_ref()
+                .unwrap()
+                .contains_key(COMPONENT_ANNOTATION)
+        );
+
+        // and into an artifact referring to the image
+        let index = result.oci_dir.read_index().unwrap();
+        assert_eq!(index.manifests().len(), 2);
+        let artifact: oci_image::ImageManifest = result
+            .oci_dir
+            .read_json_blob(&index.manifests()[1])
+            .unwrap();
+        assert_eq!(
+            artifact.artifact_type().as_ref().unwrap().to_string(),
+            LAYER_COMPONENTS_ARTIFACT_TYPE
+        );
+        assert_eq!(
+            artifact.subject().as_ref().unwrap().digest(),
+            index.manifests()[0].digest()
+        );
+        let components: BTreeMap<String, String> = result
+            .oci_dir
+            .read_json_blob(&artifact.layers()[0])
+            .unwrap();
+        assert_eq!(components.get(&layer.digest().to_string()).unwrap(), name);
+    }
+
+    #[test]
+    fn test_rebuild() {
+        let base = build_and_extract(
+            |rootfs| {
+                rootfs.write("file_a", "content a").unwrap();
+                rootfs.write("file_b", "content b").unwrap();
+            },
+            vec![
+                ("a", btreeset! { Utf8PathBuf::from("/file_a") }, 1000),
+                ("b", btreeset! { Utf8PathBuf::from("/file_b") }, 1000),
+            ],
+        );
+        let base_path = Utf8Path::from_path(base._oci_tempdir.path()).unwrap();
+        let base_image = BaseImage::open(base_path).unwrap();
+        assert_eq!(base_image.layer_names(), [Some("a"), Some("b")]);
+
+        let rootfs_dir = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(rootfs_dir.path(), ambient_authority()).unwrap();
+        rootfs.write("file_b", "new content b").unwrap();
+        let all_files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let component = Component {
+            mtime_clamp: 1000,
+            stability: 0.0,
+            files: all_files
+                .into_iter()
+                .filter(|(p, _)| p == "/file_b")
+                .collect(),
+            packages: Default::default(),
+        };
+        let mut output = Vec::new();
+        Builder::new(&rootfs, vec![("b".to_string(), component)])
+            .unwrap()
+            .compression(Compression::None)
+            .validate(true)
+            .rebuild(&base_image, &mut output)
+            .unwrap();
+
+        let oci_tempdir = tempfile::tempdir().unwrap();
+        tar::Archive::new(output.as_slice())
+            .unpack(oci_tempdir.path())
+            .unwrap();
+        let rebuilt = BaseImage::open(Utf8Path::from_path(oci_tempdir.path()).unwrap()).unwrap();
+        let (old_layers, new_layers) = (base.manifest.layers(), rebuilt.manifest.layers());
+        assert_eq!(rebuilt.layer_names(), [Some("a"), Some("b")]);
+        // untouched layers are reused as is
+        assert_eq!(new_layers[0], old_layers[0]);
+        assert_ne!(new_layers[1].digest(), old_layers[1].digest());
+        let diff_ids = rebuilt.config.rootfs().diff_ids();
+        assert_eq!(diff_ids[0], base.image_config.rootfs().diff_ids()[0]);
+        assert_eq!(diff_ids[1], new_layers[1].digest().to_string());
+        assert_eq!(
+            rebuilt.config.architecture(),
+            base.image_config.architecture()
+        );
+    }
+
+    #[test]
+    fn test_build_on() {
+        let base = build_and_extract(
+            |rootfs| {
+                rootfs.write("file_a", "content a").unwrap();
+            },
+            vec![("a", btreeset! { Utf8PathBuf::from("/file_a") }, 1000)],
+        );
+        let base_path = Utf8Path::from_path(base._oci_tempdir.path()).unwrap();
+        let base_image = BaseImage::open(base_path).unwrap();
+
+        let rootfs_dir = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(rootfs_dir.path(), ambient_authority()).unwrap();
+        rootfs.write("app", "app content").unwrap();
+        let component = Component {
+            mtime_clamp: 1000,
+            stability: 0.0,
+            files: crate::scan::Scanner::new(&rootfs).scan().unwrap(),
+            packages: Default::default(),
+        };
+        let mut output = Vec::new();
+        Builder::new(&rootfs, vec![("app".to_string(), component)])
+            .unwrap()
+            .compression(Compression::None)
+            .config(base_image.config().clone())
+            .build_on(&base_image, &mut output)
+            .unwrap();
+
+        let oci_tempdir = tempfile::tempdir().unwrap();
+        tar::Archive::new(output.as_slice())
+            .unpack(oci_tempdir.path())
+            .unwrap();
+        let app = BaseImage::open(Utf8Path::from_path(oci_tempdir.path()).unwrap()).unwrap();
+        assert_eq!(app.layer_names(), [Some("a"), Some("app")]);
+        let base_layer = &base.manifest.layers()[0];
+        assert_eq!(&app.manifest.layers()[0], base_layer);
+        // the base layer is referenced, not copied
+        let blob = format!("blobs/sha256/{}", base_layer.digest().digest());
+        assert!(!oci_tempdir.path().join(&blob).exists());
+        assert!(base._oci_tempdir.path().join(&blob).exists());
+        let diff_ids = app.config.rootfs().diff_ids();
+        assert_eq!(diff_ids.len(), 2);
+        assert_eq!(diff_ids[0], base.image_config.rootfs().diff_ids()[0]);
+        assert_eq!(app.config.history().as_ref().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_unpack() {
+        let base = build_and_extract(
+            |rootfs| {
+                rootfs.create_dir_all("dir/sub").unwrap();
+                rootfs.write("dir/sub/file_a", "content a").unwrap();
+                rootfs.write("file_b", "content b").unwrap();
+            },
+            vec![
+                (
+                    "a",
+                    btreeset! {
+                        Utf8PathBuf::from("/dir"),
+                        Utf8PathBuf::from("/dir/sub"),
+                        Utf8PathBuf::from("/dir/sub/file_a"),
+                    },
+                    1000,
+                ),
+                ("b", btreeset! { Utf8PathBuf::from("/file_b") }, 1000),
+            ],
+        );
+        let base_path = Utf8Path::from_path(base._oci_tempdir.path()).unwrap();
+        let dest_dir = tempfile::tempdir().unwrap();
+        let dest = Utf8Path::from_path(dest_dir.path()).unwrap();
+        BaseImage::open(base_path).unwrap().unpack(dest).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dest.join("dir/sub/file_a")).unwrap(),
+            "content a"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dest.join("file_b")).unwrap(),
+            "content b"
+        );
+
+        let whiteouts = |paths: &[&str]| {
+            let mut builder = tar::Builder::new(Vec::new());
+            for path in paths {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(0);
+                builder.append_data(&mut header, path, &[][..]).unwrap();
+            }
+            builder.into_inner().unwrap()
+        };
+        apply_whiteouts(whiteouts(&["./.wh.file_b"]).as_slice(), dest).unwrap();
+        assert!(!dest.join("file_b").exists());
+        apply_whiteouts(whiteouts(&["dir/.wh..wh..opq"]).as_slice(), dest).unwrap();
+        assert!(dest.join("dir").is_dir());
+        assert!(!dest.join("dir/sub").exists());
+        // whiting out missing paths is fine
+        apply_whiteouts(
+            whiteouts(&["nope/.wh..wh..opq", ".wh.nope"]).as_slice(),
+            dest,
+        )
+        .unwrap();
+    }
 }
@@ -0,0 +1,1062 @@
+//! Tarballs applied on top of the rootfs without extracting them.
+//!
+//! Entries of the tarballs are merged into the scanned [`FileMap`], and their
+//! content is read straight from the tarballs when writing the layers. Files
+//! and symlinks replace those of the rootfs at the same path, but directories
+//! which exist in the rootfs keep their metadata there, so that e.g. `/usr`
+//! doesn't move into the layer of the tarball. Tarballs must be uncompressed,
+//! so that contents can be read at their offset.
+//!
+//! Files added with `--add` are entries of the overlay too, which are kept in
+//! memory and go into a component of their own.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+
+use crate::components::layers::WHITEOUT_PREFIX;
+use crate::components::{Component, FileInfo, FileMap, FileType, XattrInterner};
+
+/// Prefix of the names of the components of tarballs, followed by their file
+/// stem.
+const OVERLAY_COMPONENT_PREFIX: &str = "overlay/";
+
+/// Name of the component of the files added with `--add`, after the prefix.
+const ADDED_COMPONENT_NAME: &str = "added";
+
+/// Where the content of a file added with `--add` comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddedSource {
+    /// A file of the host.
+    Path(Utf8PathBuf),
+    /// The given content.
+    Inline(String),
+}
+
+/// A file added with `--add`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddedFile {
+    pub source: AddedSource,
+    pub dest: Utf8PathBuf,
+    pub mode: u32,
+}
+
+impl AddedFile {
+    /// Parse `SRC:DEST[:MODE]`, where SRC is a host path or `inline:TEXT`,
+    /// DEST an absolute path and MODE octal (0644 by default). Since DEST and
+    /// MODE are split off the end, SRC may contain colons.
+    pub fn parse(s: &str) -> Result<Self> {
+        let (rest, mode) = match s.rsplit_once(':') {
+            Some((rest, mode))
+                if !mode.is_empty()
+                    && mode.bytes().all(|b| b.is_ascii_digit())
+                    && rest
+                        .rsplit_once(':')
+                        .is_some_and(|(_, d)| d.starts_with('/')) =>
+            {
+                let mode =
+                    u32::from_str_radix(mode, 8).with_context(|| format!("invalid mode in {s}"))?;
+                anyhow::ensure!(mode <= 0o7777, "mode in {s} is more than permission bits");
+                (rest, mode)
+            }
+            _ => (s, 0o644),
+        };
+        let Some((source, dest)) = rest.rsplit_once(':') else {
+            anyhow::bail!("{s} is not SRC:DEST or SRC:DEST:MODE");
+        };
+        anyhow::ensure!(!source.is_empty(), "missing source in {s}");
+        let dest = Utf8Path::new(dest);
+        anyhow::ensure!(
+            dest.is_absolute() && dest.file_name().is_some(),
+            "destination must be an absolute file path: {dest}"
+        );
+        anyhow::ensure!(
+            !dest
+                .components()
+                .any(|c| matches!(c, Utf8Component::ParentDir)),
+            "destination can't go up: {dest}"
+        );
+        let source = match source.strip_prefix("inline:") {
+            Some(content) => AddedSource::Inline(content.to_string()),
+            None => AddedSource::Path(source.into()),
+        };
+        Ok(Self {
+            source,
+            dest: dest.components().collect(),
+            mode,
+        })
+    }
+}
+
+/// Tarballs applied on top of the rootfs, in order.
+#[derive(Debug)]
+pub struct Overlay {
+    /// Component names and opened tarballs, or `None` for added files.
+    archives: Vec<(String, Option<std::fs::File>)>,
+    entries: BTreeMap<Utf8PathBuf, Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// Index of the tarball in `archives`.
+    archive: usize,
+    info: FileInfo,
+    content: Content,
+}
+
+#[derive(Debug)]
+enum Content {
+    Directory,
+    /// Offset of the content in the tarball; the size is in the file info.
+    File(u64),
+    /// Content of an added file.
+    Data(Vec<u8>),
+    Symlink(Utf8PathBuf),
+    /// A device or FIFO; the device number is in the file info.
+    Special,
+}
+
+impl Overlay {
+    /// Read the entries of the tarballs at `paths`. Entries of later tarballs
+    /// replace those of earlier ones.
+    pub fn load(paths: &[Utf8PathBuf]) -> Result<Self> {
+        let mut overlay = Self {
+            archives: Vec::new(),
+            entries: BTreeMap::new(),
+        };
+        for path in paths {
+            overlay
+                .load_archive(path)
+                .with_context(|| format!("reading {path}"))?;
+        }
+        Ok(overlay)
+    }
+
+    fn load_archive(&mut self, path: &Utf8Path) -> Result<()> {
+        let name = format!(
+            "{OVERLAY_COMPONENT_PREFIX}{}",
+            path.file_stem().unwrap_or(path.as_str())
+        );
+        anyhow::ensure!(
+            !self.archives.iter().any(|(n, _)| *n == name),
+            "another tarball is also named {name}"
+        );
+        let file = std::fs::File::open(path).with_context(|| format!("opening {path}"))?;
+        let mut magic = [0u8; 2];
+        if file.read_exact_at(&mut magic, 0).is_ok() && magic == [0x1f, 0x8b] {
+            anyhow::bail!("compressed tarballs aren't supported; decompress it first");
+        }
+        let archive = self.archives.len();
+
+        let mut xattrs = XattrInterner::default();
+        let mut tar = tar::Archive::new(&file);
+        for entry in tar_entries(&mut tar)? {
+            let entry = entry?;
+            let path = entry_path(entry.path())?;
+            anyhow::ensure!(
+                !path
+                    .file_name()
+                    .is_some_and(|name| name.starts_with(WHITEOUT_PREFIX)),
+                "{path}: whiteouts aren't supported"
+            );
+            let content = match entry.header().entry_type() {
+                tar::EntryType::Link => {
+                    // hardlinks become copies of their target
+                    let target = entry
+                        .link_name()
+                        .with_context(|| format!("hardlink {path} has no target"))?;
+                    let target = entry_path(target)?;
+                    let entry = self
+                        .entries
+                        .get(&target)
+                        .filter(|e| e.archive == archive)
+                        .with_context(|| format!("hardlink {path} target {target} not found"))?;
+                    let content = match &entry.content {
+                        Content::File(offset) => Content::File(*offset),
+                        Content::Data(data) => Content::Data(data.clone()),
+                        Content::Symlink(target) => Content::Symlink(target.clone()),
+                        Content::Special => Content::Special,
+                        Content::Directory => anyhow::bail!("hardlink {path} to a directory"),
+                    };
+                    let info = entry.info.clone();
+                    self.insert(path, archive, info, content);
+                    continue;
+                }
+                tar::EntryType::Directory => Content::Directory,
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    Content::File(entry.raw_file_position())
+                }
+                tar::EntryType::Symlink => Content::Symlink(symlink_target(&entry, &path)?),
+                _ => Content::Special,
+            };
+            let info = entry_info(&entry, &path, &mut xattrs)?;
+            self.insert(path, archive, info, content);
+        }
+
+        self.archives.push((name, Some(file)));
+        Ok(())
+    }
+
+    /// Add `files` on top of the tarballs, root-owned with an mtime of
+    /// `mtime`, in a component of their own.
+    pub fn add_files(&mut self, files: &[AddedFile], mtime: u64) -> Result<()> {
+        let name = format!("{OVERLAY_COMPONENT_PREFIX}{ADDED_COMPONENT_NAME}");
+        anyhow::ensure!(
+            !self.archives.iter().any(|(n, _)| *n == name),
+            "a tarball is also named {name}"
+        );
+        let archive = self.archives.len();
+        for file in files {
+            let content = match &file.source {
+                AddedSource::Path(path) => {
+                    std::fs::read(path).with_context(|| format!("reading {path}"))?
+                }
+                AddedSource::Inline(content) => content.as_bytes().to_vec(),
+            };
+            let info = FileInfo {
+                file_type: FileType::File,
+                mode: libc::S_IFREG | file.mode,
+                size: content.len() as u64,
+                uid: 0,
+                gid: 0,
+                mtime,
+                ino: 0,
+                nlink: 1,
+                xattrs: Default::default(),
+                extra: None,
+            };
+            self.insert(file.dest.clone(), archive, info, Content::Data(content));
+        }
+        self.archives.push((name, None));
+        Ok(())
+    }
+
+    fn insert(&mut self, path: Utf8PathBuf, archive: usize, info: FileInfo, content: Content) {
+        if info.file_type != FileType::Directory {
+            // a file replacing a directory replaces its contents too
+            let children: Vec<_> = self
+                .entries
+                .range::<Utf8Path, _>((
+                    std::ops::Bound::Excluded(path.as_path()),
+                    std::ops::Bound::Unbounded,
+                ))
+                .map(|(p, _)| p)
+                .take_while(|p| p.starts_with(&path))
+                .cloned()
+                .collect();
+            for child in children {
+                self.entries.remove(&child);
+            }
+        }
+        self.entries.insert(
+            path,
+            Entry {
+                archive,
+                info,
+                content,
+            },
+        );
+    }
+
+    /// Merge the entries into the rootfs `files`.
+    ///
+    /// Paths replaced by entries are removed from `files`, along with the
+    /// contents of replaced directories, and returned as the files of the
+    /// components of each tarball (see [`Overlay::components`]). Missing
+    /// parent directories are added as root-owned with mode 0755.
+    pub fn apply(&mut self, files: &mut FileMap) -> Result<()> {
+        let mut kept_dirs = Vec::new();
+        for (path, entry) in &self.entries {
+            // anything below a file or symlink of the rootfs would be lost
+            if let Some(ancestor) = path.ancestors().skip(1).find(|a| {
+                files
+                    .get(*a)
+                    .is_some_and(|i| i.file_type != FileType::Directory)
+            }) && !self.entries.contains_key(ancestor)
+            {
+                anyhow::bail!("{path} is below {ancestor}, which isn't a directory in the rootfs");
+            }
+            match files.get(path) {
+                Some(info)
+                    if info.file_type == FileType::Directory
+                        && entry.info.file_type == FileType::Directory =>
+                {
+                    kept_dirs.push(path.clone());
+                }
+                Some(info) => {
+                    if info.file_type == FileType::Directory {
+                        let children: Vec<_> = files
+                            .keys()
+                            .filter(|p| *p != path && p.starts_with(path))
+                            .cloned()
+                            .collect();
+                        for child in children {
+                            files.remove(&child);
+                        }
+                    }
+                    files.remove(path);
+                }
+                None => {}
+            }
+        }
+        for path in kept_dirs {
+            self.entries.remove(&path);
+        }
+
+        // parent directories go with the first tarball needing them; the root
+        // isn't in the rootfs files either
+        let mut missing: BTreeMap<Utf8PathBuf, (usize, u64)> = BTreeMap::new();
+        for (path, entry) in &self.entries {
+            for ancestor in path.ancestors().skip(1) {
+                if ancestor == "/"
+                    || files.contains_key(ancestor)
+                    || self.entries.contains_key(ancestor)
+                {
+                    break;
+                }
+                let (archive, mtime) = missing
+                    .entry(ancestor.to_path_buf())
+                    .or_insert((entry.archive, entry.info.mtime));
+                *archive = (*archive).min(entry.archive);
+                *mtime = (*mtime).max(entry.info.mtime);
+            }
+        }
+        for (path, (archive, mtime)) in missing {
+            let info = FileInfo {
+                file_type: FileType::Directory,
+                mode: libc::S_IFDIR | 0o755,
+                size: 0,
+                uid: 0,
+                gid: 0,
+                mtime,
+                ino: 0,
+                nlink: 1,
+                xattrs: Default::default(),
+                extra: None,
+            };
+            self.entries.insert(
+                path,
+                Entry {
+                    archive,
+                    info,
+                    content: Content::Directory,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns a component for each tarball with its entries, clamped to
+    /// `mtime_clamp`. Since tarballs carry no information about how often
+    /// they change, their stability is 0.
+    pub fn components(&self, mtime_clamp: u64) -> Vec<(String, Component)> {
+        let mut components: Vec<_> = self
+            .archives
+            .iter()
+            .map(|(name, _)| {
+                (
+                    name.clone(),
+                    Component {
+                        mtime_clamp,
+                        stability: 0.0,
+                        files: FileMap::new(),
+                        packages: Default::default(),
+                    },
+                )
+            })
+            .collect();
+        for (path, entry) in &self.entries {
+            components[entry.archive]
+                .1
+                .files
+                .insert(path.clone(), entry.info.clone());
+        }
+        components.retain(|(_, c)| !c.files.is_empty());
+        components
+    }
+
+    /// Returns the metadata of the entry at `path`.
+    pub fn info(&self, path: &Utf8Path) -> Option<&FileInfo> {
+        self.entries.get(path).map(|e| &e.info)
+    }
+
+    /// Returns the content of the file at `path`, or `None` if it isn't a
+    /// file of the overlay.
+    pub fn read(&self, path: &Utf8Path) -> Result<Option<Vec<u8>>> {
+        self.read_prefix(path, usize::MAX)
+    }
+
+    /// Returns the first `len` bytes of the file at `path` (or all of them if
+    /// it's shorter), or `None` if it isn't a file of the overlay.
+    pub fn read_prefix(&self, path: &Utf8Path, len: usize) -> Result<Option<Vec<u8>>> {
+        let Some(Entry {
+            archive,
+            info,
+            content,
+        }) = self.entries.get(path)
+        else {
+            return Ok(None);
+        };
+        let offset = match content {
+            Content::File(offset) => *offset,
+            Content::Data(data) => return Ok(Some(data[..data.len().min(len)].to_vec())),
+            _ => return Ok(None),
+        };
+        let (name, file) = &self.archives[*archive];
+        let file = file
+            .as_ref()
+            .with_context(|| format!("{name} has no tarball to read {path} from"))?;
+        let size = usize::try_from(info.size)
+            .context("file too large")?
+            .min(len);
+        let mut content = vec![0u8; size];
+        file.read_exact_at(&mut content, offset)
+            .with_context(|| format!("reading {path} from {name}"))?;
+        Ok(Some(content))
+    }
+
+    /// Returns the target of the symlink at `path`, or `None` if it isn't a
+    /// symlink of the overlay.
+    pub fn read_link(&self, path: &Utf8Path) -> Option<&Utf8Path> {
+        match self.entries.get(path) {
+            Some(Entry {
+                content: Content::Symlink(target),
+                ..
+            }) => Some(target),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the metadata of the tar `entry` at `path` from its headers, which
+/// may name owners, devices and xattrs that couldn't be created where we run.
+/// Hardlinks are the caller's to resolve, and the xattrs are interned with
+/// `interner`.
+pub(crate) fn entry_info<R: Read>(
+    entry: &TarEntry<R>,
+    path: &Utf8Path,
+    interner: &mut XattrInterner,
+) -> Result<FileInfo> {
+    let size = entry.size();
+    let header = entry.header();
+    let (file_type, type_bits) = match header.entry_type() {
+        tar::EntryType::Directory => (FileType::Directory, libc::S_IFDIR),
+        tar::EntryType::Regular | tar::EntryType::Continuous => (FileType::File, libc::S_IFREG),
+        tar::EntryType::Symlink => (FileType::Symlink, libc::S_IFLNK),
+        tar::EntryType::Block => (FileType::BlockDevice, libc::S_IFBLK),
+        tar::EntryType::Char => (FileType::CharDevice, libc::S_IFCHR),
+        tar::EntryType::Fifo => (FileType::Fifo, libc::S_IFIFO),
+        other => anyhow::bail!("{path}: unsupported entry type {other:?}"),
+    };
+    let rdev = match file_type {
+        FileType::BlockDevice | FileType::CharDevice => {
+            let major = header.device_major().context("reading device major")?;
+            let minor = header.device_minor().context("reading device minor")?;
+            libc::makedev(major.unwrap_or(0), minor.unwrap_or(0))
+        }
+        _ => 0,
+    };
+    // the content isn't where it'd be read from
+    anyhow::ensure!(
+        !entry
+            .records()
+            .iter()
+            .any(|(key, _)| key.starts_with("GNU.sparse.")),
+        "{path}: sparse files aren't supported"
+    );
+    let mut info = FileInfo {
+        file_type,
+        mode: type_bits | (header.mode().context("reading mode")? & 0o7777),
+        size,
+        uid: entry.uid()?,
+        gid: entry.gid()?,
+        mtime: header.mtime().context("reading mtime")?,
+        ino: 0,
+        nlink: 1,
+        xattrs: interner.intern(entry.xattrs()),
+        extra: None,
+    };
+    info.set_rdev(rdev);
+    Ok(info)
+}
+
+/// Returns the target of the symlink `entry` at `path`.
+pub(crate) fn symlink_target<R: Read>(entry: &TarEntry<R>, path: &Utf8Path) -> Result<Utf8PathBuf> {
+    let target = entry
+        .link_name()
+        .with_context(|| format!("symlink {path} has no target"))?;
+    Utf8Path::from_path(target)
+        .map(Utf8Path::to_path_buf)
+        .with_context(|| format!("symlink {path} target is not UTF-8"))
+}
+
+/// Returns the entries of `archive`, with their paths, link names and PAX
+/// records read from the headers before them.
+///
+/// The tar crate splits PAX records at newlines, so it can't read back xattr
+/// values containing one, which e.g. `security.capability` blobs may. Here
+/// the records are parsed by their length instead, which means reading the
+/// headers of the archive raw and resolving GNU long names and PAX records
+/// ourselves. Sizes in PAX records and GNU sparse files aren't supported.
+pub(crate) fn tar_entries<R: Read>(archive: &mut tar::Archive<R>) -> Result<TarEntries<'_, R>> {
+    let entries = archive.entries().context("reading entries")?.raw(true);
+    Ok(TarEntries(entries))
+}
+
+/// Iterator over the entries of a tarball, see [`tar_entries`].
+pub(crate) struct TarEntries<'a, R: Read>(tar::Entries<'a, R>);
+
+impl<'a, R: Read> Iterator for TarEntries<'a, R> {
+    type Item = Result<TarEntry<'a, R>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+impl<'a, R: Read> TarEntries<'a, R> {
+    fn next_entry(&mut self) -> Result<Option<TarEntry<'a, R>>> {
+        let mut long_path = None;
+        let mut long_link = None;
+        let mut records = None;
+        loop {
+            let Some(entry) = self.0.next() else {
+                anyhow::ensure!(
+                    long_path.is_none() && long_link.is_none() && records.is_none(),
+                    "tarball ends with headers describing a missing entry"
+                );
+                return Ok(None);
+            };
+            let mut entry = entry.context("reading entry")?;
+            let slot = match entry.header().entry_type() {
+                tar::EntryType::GNULongName => &mut long_path,
+                tar::EntryType::GNULongLink => &mut long_link,
+                tar::EntryType::XHeader => {
+                    anyhow::ensure!(records.is_none(), "two PAX headers for the same entry");
+                    let mut data = Vec::new();
+                    entry.read_to_end(&mut data).context("reading PAX header")?;
+                    records = Some(pax_records(&data)?);
+                    continue;
+                }
+                tar::EntryType::GNUSparse => anyhow::bail!("sparse files aren't supported"),
+                _ => {
+                    return TarEntry::new(entry, long_path, long_link, records.unwrap_or_default())
+                        .map(Some);
+                }
+            };
+            anyhow::ensure!(slot.is_none(), "two long names for the same entry");
+            let mut name = Vec::new();
+            entry
+                .read_to_end(&mut name)
+                .context("reading GNU long name")?;
+            // the names are NUL-terminated
+            if name.last() == Some(&0) {
+                name.pop();
+            }
+            *slot = Some(name);
+        }
+    }
+}
+
+/// An entry of a tarball, with the path, link name and PAX records from the
+/// headers before it.
+pub(crate) struct TarEntry<'a, R: Read> {
+    entry: tar::Entry<'a, R>,
+    path: Vec<u8>,
+    link_name: Option<Vec<u8>>,
+    records: Vec<(String, Vec<u8>)>,
+}
+
+impl<'a, R: Read> TarEntry<'a, R> {
+    fn new(
+        entry: tar::Entry<'a, R>,
+        long_path: Option<Vec<u8>>,
+        long_link: Option<Vec<u8>>,
+        records: Vec<(String, Vec<u8>)>,
+    ) -> Result<Self> {
+        let record = |key: &str| {
+            records
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.clone())
+        };
+        let header = entry.header();
+        let path = record("path")
+            .or(long_path)
+            .unwrap_or_else(|| header.path_bytes().into_owned());
+        let link_name = record("linkpath")
+            .or(long_link)
+            .or_else(|| header.link_name_bytes().map(|l| l.into_owned()));
+        // the content was read with the size of the header
+        if let Some(size) = record("size") {
+            anyhow::ensure!(
+                String::from_utf8_lossy(&size).parse::<u64>().ok() == Some(entry.size()),
+                "{}: sizes in PAX records aren't supported",
+                String::from_utf8_lossy(&path)
+            );
+        }
+        Ok(Self {
+            entry,
+            path,
+            link_name,
+            records,
+        })
+    }
+
+    /// Returns the path of the entry.
+    pub(crate) fn path(&self) -> &std::path::Path {
+        std::path::Path::new(std::ffi::OsStr::from_bytes(&self.path))
+    }
+
+    /// Returns the target of the hardlink or symlink entry, if any.
+    pub(crate) fn link_name(&self) -> Option<&std::path::Path> {
+        self.link_name
+            .as_deref()
+            .filter(|l| !l.is_empty())
+            .map(|l| std::path::Path::new(std::ffi::OsStr::from_bytes(l)))
+    }
+
+    /// Returns the PAX records of the entry.
+    pub(crate) fn records(&self) -> &[(String, Vec<u8>)] {
+        &self.records
+    }
+
+    /// Returns the xattrs of the entry, from its `SCHILY.xattr.` PAX records.
+    pub(crate) fn xattrs(&self) -> Vec<(String, Vec<u8>)> {
+        self.records
+            .iter()
+            .filter_map(|(key, value)| {
+                key.strip_prefix("SCHILY.xattr.")
+                    .map(|name| (name.to_string(), value.clone()))
+            })
+            .collect()
+    }
+
+    /// Returns the owner of the entry, from its PAX records or header.
+    pub(crate) fn uid(&self) -> Result<u32> {
+        self.id("uid", self.entry.header().uid())
+    }
+
+    /// Returns the group of the entry, from its PAX records or header.
+    pub(crate) fn gid(&self) -> Result<u32> {
+        self.id("gid", self.entry.header().gid())
+    }
+
+    fn id(&self, key: &str, header: std::io::Result<u64>) -> Result<u32> {
+        let id = match self.records.iter().find(|(k, _)| k == key) {
+            Some((_, value)) => std::str::from_utf8(value)
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .with_context(|| format!("invalid {key} PAX record"))?,
+            None => header.with_context(|| format!("reading {key}"))?,
+        };
+        u32::try_from(id).with_context(|| format!("{key} {id} out of range"))
+    }
+
+    /// Returns the header of the entry.
+    pub(crate) fn header(&self) -> &tar::Header {
+        self.entry.header()
+    }
+
+    /// Returns the size of the content of the entry.
+    pub(crate) fn size(&self) -> u64 {
+        self.entry.size()
+    }
+
+    /// Returns the offset of the content of the entry in the tarball.
+    pub(crate) fn raw_file_position(&self) -> u64 {
+        self.entry.raw_file_position()
+    }
+}
+
+impl<R: Read> Read for TarEntry<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.entry.read(buf)
+    }
+}
+
+/// Parse the `<length> <key>=<value>\n` records of a PAX header. They're
+/// split by their length, since values may contain any byte.
+pub(crate) fn pax_records(mut data: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut records = Vec::new();
+    // some writers pad the header with NULs
+    while data.first().is_some_and(|&b| b != 0) {
+        let len = data.iter().position(|&b| b == b' ').and_then(|space| {
+            let len = std::str::from_utf8(&data[..space])
+                .ok()?
+                .parse::<usize>()
+                .ok()?;
+            (len > space + 1 && len <= data.len() && data[len - 1] == b'\n').then_some((space, len))
+        });
+        let Some((space, len)) = len else {
+            anyhow::bail!("malformed PAX record");
+        };
+        let record = &data[space + 1..len - 1];
+        let equals = record
+            .iter()
+            .position(|&b| b == b'=')
+            .context("PAX record has no '='")?;
+        let key = std::str::from_utf8(&record[..equals]).context("PAX record key is not UTF-8")?;
+        records.push((key.to_string(), record[equals + 1..].to_vec()));
+        data = &data[len..];
+    }
+    Ok(records)
+}
+
+/// Convert the path of a tar entry to an absolute path like in [`FileMap`]s.
+pub(crate) fn entry_path(path: &std::path::Path) -> Result<Utf8PathBuf> {
+    let path = Utf8Path::from_path(path).context("entry path is not UTF-8")?;
+    let mut abs = Utf8PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Utf8Component::Normal(name) => abs.push(name),
+            Utf8Component::ParentDir => anyhow::bail!("{path}: entry path has '..'"),
+            _ => {}
+        }
+    }
+    Ok(abs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn append(builder: &mut tar::Builder<std::fs::File>, path: &str, kind: tar::EntryType) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_mode(0o644);
+        header.set_mtime(1000);
+        header.set_uid(1000);
+        header.set_gid(1000);
+        match kind {
+            tar::EntryType::Regular => {
+                let content = format!("content of {path}");
+                header.set_size(content.len() as u64);
+                builder
+                    .append_data(&mut header, path, content.as_bytes())
+                    .unwrap();
+            }
+            tar::EntryType::Symlink => {
+                header.set_size(0);
+                builder.append_link(&mut header, path, "target").unwrap();
+            }
+            _ => {
+                header.set_mode(0o700);
+                header.set_size(0);
+                builder
+                    .append_data(&mut header, path, std::io::empty())
+                    .unwrap();
+            }
+        }
+    }
+
+    fn dir_info() -> FileInfo {
+        FileInfo {
+            mode: libc::S_IFDIR | 0o755,
+            ino: 1,
+            ..FileInfo::test_file(FileType::Directory, 0, 1)
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(tmp.path()).unwrap();
+        let app = dir.join("app.tar");
+        let mut builder = tar::Builder::new(std::fs::File::create(&app).unwrap());
+        append(&mut builder, "./usr/", tar::EntryType::Directory);
+        append(&mut builder, "./usr/bin/app", tar::EntryType::Regular);
+        append(&mut builder, "./etc/os-release", tar::EntryType::Symlink);
+        append(&mut builder, "./var", tar::EntryType::Regular);
+        builder.finish().unwrap();
+        let config = dir.join("config.tar");
+        let mut builder = tar::Builder::new(std::fs::File::create(&config).unwrap());
+        append(&mut builder, "srv/app.conf", tar::EntryType::Regular);
+        builder.finish().unwrap();
+
+        let mut files: FileMap = ["/", "/usr", "/usr/bin", "/etc", "/var", "/var/lib"]
+            .into_iter()
+            .map(|p| (Utf8PathBuf::from(p), dir_info()))
+            .collect();
+        let mut file = dir_info();
+        file.file_type = FileType::File;
+        files.insert("/etc/os-release".into(), file);
+
+        let mut overlay = Overlay::load(&[app, config]).unwrap();
+        overlay.apply(&mut files).unwrap();
+
+        // replaced paths, and what was in replaced directories, are gone
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            ["/", "/etc", "/usr", "/usr/bin"]
+        );
+        let components = overlay.components(500);
+        let names: Vec<_> = components
+            .iter()
+            .map(|(name, c)| {
+                let paths = c.files.keys().map(|p| p.as_str()).collect::<Vec<_>>();
+                (name.as_str(), paths)
+            })
+            .collect();
+        // /usr exists in the rootfs, so it's kept from there, while /srv is
+        // added
+        assert_eq!(
+            names,
+            [
+                (
+                    "overlay/app",
+                    vec!["/etc/os-release", "/usr/bin/app", "/var"]
+                ),
+                ("overlay/config", vec!["/srv", "/srv/app.conf"]),
+            ]
+        );
+        assert_eq!(
+            components[0].1.files[Utf8Path::new("/usr/bin/app")].uid,
+            1000
+        );
+        assert_eq!(
+            components[1].1.files[Utf8Path::new("/srv")].mode,
+            libc::S_IFDIR | 0o755
+        );
+
+        assert_eq!(
+            overlay.read(Utf8Path::new("/usr/bin/app")).unwrap(),
+            Some(b"content of ./usr/bin/app".to_vec())
+        );
+        assert_eq!(
+            overlay.read_link(Utf8Path::new("/etc/os-release")),
+            Some(Utf8Path::new("target"))
+        );
+        assert_eq!(overlay.read(Utf8Path::new("/usr/bin")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_added_file() {
+        assert_eq!(
+            AddedFile::parse("out/version.txt:/etc/app-version").unwrap(),
+            AddedFile {
+                source: AddedSource::Path("out/version.txt".into()),
+                dest: "/etc/app-version".into(),
+                mode: 0o644,
+            }
+        );
+        assert_eq!(
+            AddedFile::parse("inline:a:b:/etc/entitlement/:0600").unwrap(),
+            AddedFile {
+                source: AddedSource::Inline("a:b".to_string()),
+                dest: "/etc/entitlement".into(),
+                mode: 0o600,
+            }
+        );
+        for invalid in [
+            "/etc/app-version",
+            ":/etc/app-version",
+            "src:etc/app-version",
+            "src:/",
+            "src:/etc/../app",
+            "src:/etc/app:0999",
+            "src:/etc/app:10000",
+        ] {
+            assert!(AddedFile::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_add_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(tmp.path()).unwrap();
+        let app = dir.join("app.tar");
+        let mut builder = tar::Builder::new(std::fs::File::create(&app).unwrap());
+        append(&mut builder, "etc/app.conf", tar::EntryType::Regular);
+        builder.finish().unwrap();
+        std::fs::write(dir.join("stamp"), "v1.2.3\n").unwrap();
+
+        let mut files: FileMap = [("/".into(), dir_info())].into();
+        let mut overlay = Overlay::load(&[app]).unwrap();
+        overlay
+            .add_files(
+                &[
+                    AddedFile::parse(&format!("{dir}/stamp:/etc/app-version")).unwrap(),
+                    AddedFile::parse("inline:key=value:/etc/app.conf:600").unwrap(),
+                ],
+                42,
+            )
+            .unwrap();
+        overlay.apply(&mut files).unwrap();
+
+        let components = overlay.components(500);
+        let names: Vec<_> = components
+            .iter()
+            .map(|(name, c)| {
+                let paths = c.files.keys().map(|p| p.as_str()).collect::<Vec<_>>();
+                (name.as_str(), paths)
+            })
+            .collect();
+        // added files replace those of the tarballs, which leaves nothing of
+        // app.tar
+        assert_eq!(
+            names,
+            [(
+                "overlay/added",
+                vec!["/etc", "/etc/app-version", "/etc/app.conf"]
+            )]
+        );
+        let conf = &components[0].1.files[Utf8Path::new("/etc/app.conf")];
+        assert_eq!(
+            (conf.mode, conf.uid, conf.mtime, conf.size),
+            (libc::S_IFREG | 0o600, 0, 42, 9)
+        );
+        assert_eq!(
+            overlay.read(Utf8Path::new("/etc/app-version")).unwrap(),
+            Some(b"v1.2.3\n".to_vec())
+        );
+        assert_eq!(
+            overlay
+                .read_prefix(Utf8Path::new("/etc/app.conf"), 3)
+                .unwrap(),
+            Some(b"key".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_below_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(tmp.path()).unwrap();
+        let app = dir.join("app.tar");
+        let mut builder = tar::Builder::new(std::fs::File::create(&app).unwrap());
+        append(&mut builder, "bin/app", tar::EntryType::Regular);
+        builder.finish().unwrap();
+
+        // e.g. /bin -> usr/bin
+        let mut link = dir_info();
+        link.file_type = FileType::Symlink;
+        let mut files: FileMap = [("/".into(), dir_info()), ("/bin".into(), link)].into();
+        let mut overlay = Overlay::load(&[app]).unwrap();
+        assert!(overlay.apply(&mut files).is_err());
+    }
+
+    #[test]
+    fn test_tar_entries() {
+        let long = format!("usr/{}", "a".repeat(150));
+        let mut builder = tar::Builder::new(Vec::new());
+        // the capability blob has a newline, which the tar crate can't read
+        builder
+            .append_pax_extensions([
+                ("path", long.as_bytes()),
+                ("SCHILY.xattr.security.capability", b"\x01\n\x02".as_slice()),
+            ])
+            .unwrap();
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(0o755);
+        header.set_size(3);
+        builder
+            .append_data(&mut header, "usr/short", b"app".as_slice())
+            .unwrap();
+        // GNU long names
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_mode(0o777);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, format!("{long}-link"), &long)
+            .unwrap();
+        let data = builder.into_inner().unwrap();
+
+        let mut archive = tar::Archive::new(data.as_slice());
+        let mut entries = tar_entries(&mut archive).unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+        assert_eq!(entry.path(), Utf8Path::new(&long).as_std_path());
+        assert_eq!(
+            entry.xattrs(),
+            [("security.capability".to_string(), b"\x01\n\x02".to_vec())]
+        );
+        let mut content = String::new();
+        entry.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "app");
+        let entry = entries.next().unwrap().unwrap();
+        assert_eq!(
+            entry.path(),
+            Utf8Path::new(&format!("{long}-link")).as_std_path()
+        );
+        assert_eq!(entry.link_name(), Some(Utf8Path::new(&long).as_std_path()));
+        assert!(entry.xattrs().is_empty());
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn test_pax_records() {
+        assert_eq!(
+            pax_records(b"11 a=b\nc\nd\n7 e=\n\n\n\0\0").unwrap(),
+            [
+                ("a".to_string(), b"b\nc\nd".to_vec()),
+                ("e".to_string(), b"\n\n".to_vec())
+            ]
+        );
+        assert!(pax_records(b"7 a=b\n").is_err());
+        assert!(pax_records(b"5 ab\n").is_err());
+        assert!(pax_records(b"x a=b\n").is_err());
+    }
+
+    #[test]
+    fn test_write_layer() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(tmp.path()).unwrap();
+        let app = dir.join("app.tar");
+        let mut builder = tar::Builder::new(std::fs::File::create(&app).unwrap());
+        append(&mut builder, "app/bin/app", tar::EntryType::Regular);
+        append(&mut builder, "app/bin/link", tar::EntryType::Symlink);
+        builder.finish().unwrap();
+
+        // the rootfs is empty
+        let rootfs_path = dir.join("rootfs");
+        std::fs::create_dir(&rootfs_path).unwrap();
+        let rootfs = cap_std_ext::cap_std::fs::Dir::open_ambient_dir(
+            &rootfs_path,
+            cap_std_ext::cap_std::ambient_authority(),
+        )
+        .unwrap();
+        let mut files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let mut overlay = Overlay::load(&[app]).unwrap();
+        overlay.apply(&mut files).unwrap();
+        let (_, component) = overlay.components(2000).pop().unwrap();
+
+        let options = crate::tar::TarOptions {
+            overlay: Some(std::sync::Arc::new(overlay)),
+            ..Default::default()
+        };
+        let mut tar_builder = tar::Builder::new(Vec::new());
+        crate::tar::write_files_to_tar(&mut tar_builder, &rootfs, &component.files, 2000, &options)
+            .unwrap();
+        let layer = tar_builder.into_inner().unwrap();
+
+        let mut archive = tar::Archive::new(layer.as_slice());
+        let mut entries = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            let link = entry
+                .link_name()
+                .unwrap()
+                .map(|l| l.to_string_lossy().into_owned());
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+            entries.push((path, link, content));
+        }
+        assert_eq!(
+            entries,
+            [
+                ("app/".into(), None, String::new()),
+                ("app/bin/".into(), None, String::new()),
+                ("app/bin/app".into(), None, "content of app/bin/app".into()),
+                ("app/bin/link".into(), Some("target".into()), String::new()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,130 @@
+//! User and group names of the rootfs, for the `uname` and `gname` fields of
+//! tar headers.
+//!
+//! Names are resolved against the rootfs's own `/etc/passwd` and `/etc/group`,
+//! never against the host's, so that layers are the same wherever they're
+//! built.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+
+use crate::overlay::Overlay;
+
+const PASSWD_PATH: &str = "/etc/passwd";
+const GROUP_PATH: &str = "/etc/group";
+
+/// Maximum length of the names in tar headers.
+const MAX_NAME_LEN: usize = 32;
+
+/// User and group names by ID.
+#[derive(Debug, Default)]
+pub struct OwnerNames {
+    users: HashMap<u32, String>,
+    groups: HashMap<u32, String>,
+}
+
+impl OwnerNames {
+    /// Load the names from `/etc/passwd` and `/etc/group` of the rootfs, or
+    /// of `overlay` if it has them. A missing file has no names.
+    pub fn load(rootfs: &Dir, overlay: Option<&Overlay>) -> Result<Self> {
+        let read = |path: &str| -> Result<HashMap<u32, String>> {
+            let from_overlay = match overlay {
+                Some(overlay) => overlay
+                    .read(Utf8Path::new(path))
+                    .with_context(|| format!("reading {path} from overlay"))?,
+                None => None,
+            };
+            let content = match from_overlay {
+                Some(content) => Some(String::from_utf8_lossy(&content).into_owned()),
+                None => rootfs
+                    .read_to_string_optional(path.trim_start_matches('/'))
+                    .with_context(|| format!("reading {path}"))?,
+            };
+            Ok(content.as_deref().map(parse_db).unwrap_or_default())
+        };
+        Ok(Self {
+            users: read(PASSWD_PATH)?,
+            groups: read(GROUP_PATH)?,
+        })
+    }
+
+    /// The name of user `uid`, if it has one short enough for tar headers.
+    pub fn user(&self, uid: u32) -> Option<&str> {
+        self.users.get(&uid).map(String::as_str)
+    }
+
+    /// The name of group `gid`, if it has one short enough for tar headers.
+    pub fn group(&self, gid: u32) -> Option<&str> {
+        self.groups.get(&gid).map(String::as_str)
+    }
+}
+
+/// Parse a passwd or group file, which both have the name in the first field
+/// and the ID in the third. Like getpwuid(3), the first entry of an ID wins.
+/// Comments, NIS entries (`+`/`-`) and malformed lines are ignored.
+fn parse_db(content: &str) -> HashMap<u32, String> {
+    let mut names = HashMap::new();
+    for line in content.lines() {
+        let mut fields = line.split(':');
+        let (Some(name), Some(_), Some(id)) = (fields.next(), fields.next(), fields.next()) else {
+            continue;
+        };
+        if name.is_empty()
+            || name.len() > MAX_NAME_LEN
+            || name.starts_with(['#', '+', '-'])
+            || name.contains(char::is_whitespace)
+        {
+            continue;
+        }
+        let Ok(id) = id.parse::<u32>() else {
+            continue;
+        };
+        names.entry(id).or_insert_with(|| name.to_string());
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std::ambient_authority;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_db() {
+        let names = parse_db(
+            "root:x:0:0:root:/root:/bin/bash\n\
+             # comment\n\
+             toor:x:0:0::/root:/bin/sh\n\
+             +nisuser::::::\n\
+             broken:x:notanumber:0::/:/bin/false\n\
+             short\n\
+             \n\
+             nobody:x:65534:65534:Kernel Overflow User:/:/sbin/nologin\n",
+        );
+        assert_eq!(
+            names,
+            HashMap::from([(0, "root".to_string()), (65534, "nobody".to_string())])
+        );
+    }
+
+    #[test]
+    fn test_load() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir("etc").unwrap();
+        rootfs
+            .write("etc/passwd", "root:x:0:0:root:/root:/bin/bash\n")
+            .unwrap();
+
+        let names = OwnerNames::load(&rootfs, None).unwrap();
+        assert_eq!(names.user(0), Some("root"));
+        assert_eq!(names.user(1000), None);
+        // no /etc/group
+        assert_eq!(names.group(0), None);
+    }
+}
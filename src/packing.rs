@@ -34,9 +34,64 @@
 //!    losses for this new merged group vs all the remaining groups and insert
 //!    into the heap.
 //! 4. Keep doing 3. until we get to K groups.
+//!
+//! Pinned components are never considered for merges. If there are too many
+//! of them for K to be reachable, see `pins_fit()` and `relax_pins()`.
+//!
+//! Neither are merges which would break the constraints of the `PackOptions`,
+//! i.e. exceed the maximum group size or put items which must stay apart
+//! together, so K may not be reachable then too.
+//!
+//! ## Layer overhead
+//!
+//! Each group also has a fixed cost, e.g. the requests and round trips to
+//! fetch a blob, which registries penalizing many small blobs make
+//! significant. With an overhead of O bytes per group, the TEV of an
+//! arrangement is reduced by O for each of its groups, so a merge saves O. In
+//! step 4., merges then carry on past K groups as long as they lose less than
+//! O, which folds tiny groups together.
+//!
+//! ## Previous packing
+//!
+//! A layer only keeps its digest across builds if it's packed with the same
+//! components as before. Given the group each item was in in a previous
+//! packing, merges of groups whose items all come from the same previous
+//! group count as losing `PREVIOUS_LOSS_FACTOR` times their loss, so that the
+//! previous groups are favored over merges which are about as good.
+//!
+//! ## Exact search
+//!
+//! The greedy merges aren't guaranteed to find the arrangement with the best
+//! TEV. For small N, `calculate_packing_exact()` instead searches all the
+//! arrangements into K groups with branch and bound: items are placed one at
+//! a time, largest expected value first, into each existing group or a new
+//! one, and a branch is abandoned as soon as even keeping all its remaining
+//! items separate couldn't beat the best arrangement found so far. Merging
+//! never adds expected value, so that's an upper bound. The search starts
+//! from the greedy arrangement and gives up after `EXACT_MAX_STEPS` placements,
+//! which N of up to a few dozen usually stays well under.
 
 use std::cmp::Ordering;
-use std::collections::BinaryHeap;
+use std::collections::{BTreeSet, BinaryHeap};
+
+/// Factor applied to the loss of merges which reproduce a previous group.
+const PREVIOUS_LOSS_FACTOR: f64 = 0.5;
+
+/// Maximum number of placements the exact search tries before settling for the
+/// best arrangement found so far.
+const EXACT_MAX_STEPS: u64 = 20_000_000;
+
+/// Algorithm used to pack items into groups
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PackingAlgorithm {
+    /// Merge the groups whose merge loses the least, until there are few
+    /// enough (fast, but not always optimal)
+    #[default]
+    Greedy,
+    /// Search for the optimal packing, starting from the greedy one (for
+    /// small numbers of components)
+    Exact,
+}
 
 /// Input item for packing
 #[derive(Debug, Clone)]
@@ -45,6 +100,23 @@ pub struct PackItem {
     pub size: u64,
     /// Probability the component doesn't change between updates (0.0 to 1.0)
     pub stability: f64,
+    /// Whether this item must get a group of its own
+    pub pinned: bool,
+}
+
+/// Options for the groups, besides their number
+#[derive(Debug, Clone, Default)]
+pub struct PackOptions {
+    /// Fixed cost in bytes of each group, which merging two groups saves
+    pub overhead: u64,
+    /// Maximum total size in bytes of a group
+    pub max_size: Option<u64>,
+    /// Sets of items which must each be in a different group, as indices into
+    /// the input slice
+    pub apart: Vec<Vec<usize>>,
+    /// The group of each item in a previous packing, if known; either empty
+    /// or as long as the input slice
+    pub previous: Vec<Option<usize>>,
 }
 
 /// Output group from packing
@@ -103,15 +175,24 @@ impl Eq for MergeCandidate {}
 ///
 /// Returns groups sorted by stability descending (most stable first). Each
 /// group contains indices into the original input slice.
-pub fn calculate_packing(items: &[PackItem], max_groups: usize) -> Vec<PackGroup> {
+///
+/// Pinned items are never merged, and neither are groups which would break
+/// `options`. If that leaves too many groups (see `pins_fit()`), more than
+/// `max_groups` groups are returned.
+pub fn calculate_packing(
+    items: &[PackItem],
+    max_groups: usize,
+    options: &PackOptions,
+) -> Vec<PackGroup> {
     if items.is_empty() || max_groups == 0 {
         return Vec::new();
     }
 
     let n = items.len();
 
-    // if we already have fewer items than max_groups, no packing is needed
-    if n <= max_groups {
+    // if we already have fewer items than max_groups, no packing is needed,
+    // unless merges can save overhead
+    if n <= max_groups && options.overhead == 0 {
         let mut result: Vec<PackGroup> = items
             .iter()
             .enumerate()
@@ -139,15 +220,48 @@ pub fn calculate_packing(items: &[PackItem], max_groups: usize) -> Vec<PackGroup
         .collect();
     let mut active_count = n;
     let mut merge_candidates = BinaryHeap::new();
+    // whether each group (by id) is pinned
+    let mut pinned: Vec<bool> = items.iter().map(|item| item.pinned).collect();
+    // the apart sets each group (by id) has an item of
+    let mut apart: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); n];
+    for (set_idx, set) in options.apart.iter().enumerate() {
+        for &i in set {
+            apart[i].insert(set_idx);
+        }
+    }
+    // the previous group all the items of each group (by id) come from, if any
+    let mut previous: Vec<Option<usize>> = (0..n)
+        .map(|i| options.previous.get(i).copied().flatten())
+        .collect();
+    let merge_loss =
+        |a: &PackGroup, b: &PackGroup, prev_a: Option<usize>, prev_b: Option<usize>| {
+            let loss = calculate_merge_loss(a, b);
+            if prev_a.is_some() && prev_a == prev_b {
+                loss * PREVIOUS_LOSS_FACTOR
+            } else {
+                loss
+            }
+        };
+    let can_merge =
+        |a: &PackGroup, b: &PackGroup, apart_a: &BTreeSet<usize>, apart_b: &BTreeSet<usize>| {
+            options.max_size.is_none_or(|max| a.size + b.size <= max)
+                && apart_a.is_disjoint(apart_b)
+        };
 
     // pre-calculate merge losses for all initial pairs
     for i in 0..n {
         for j in (i + 1)..n {
+            if items[i].pinned || items[j].pinned {
+                continue;
+            }
             // SAFETY: we just created these groups above
             let g_a = groups[i].as_ref().unwrap();
             let g_b = groups[j].as_ref().unwrap();
+            if !can_merge(g_a, g_b, &apart[i], &apart[j]) {
+                continue;
+            }
 
-            let loss = calculate_merge_loss(g_a, g_b);
+            let loss = merge_loss(g_a, g_b, previous[i], previous[j]);
             merge_candidates.push(MergeCandidate {
                 loss,
                 group_a_id: i,
@@ -156,11 +270,13 @@ pub fn calculate_packing(items: &[PackItem], max_groups: usize) -> Vec<PackGroup
         }
     }
 
-    // do the next best merge until we're within the constraint
-    while active_count > max_groups {
-        let Some(merge_op) = merge_candidates.pop() else {
+    // do the next best merge until we're within the constraint, and as long
+    // as merges save more overhead than they lose
+    let overhead = options.overhead as f64;
+    while let Some(merge_op) = merge_candidates.pop() {
+        if active_count <= max_groups && merge_op.loss >= overhead {
             break;
-        };
+        }
 
         // skip stale candidates (groups already merged)
         if groups[merge_op.group_a_id].is_none() || groups[merge_op.group_b_id].is_none() {
@@ -181,6 +297,15 @@ pub fn calculate_packing(items: &[PackItem], max_groups: usize) -> Vec<PackGroup
             size: g_a.size + g_b.size,
             stability: g_a.stability * g_b.stability,
         }));
+        // merged groups only ever contain unpinned items
+        pinned.push(false);
+        let merged_apart = apart[merge_op.group_a_id]
+            .union(&apart[merge_op.group_b_id])
+            .copied()
+            .collect();
+        apart.push(merged_apart);
+        let (prev_a, prev_b) = (previous[merge_op.group_a_id], previous[merge_op.group_b_id]);
+        previous.push(if prev_a == prev_b { prev_a } else { None });
         active_count -= 1;
 
         // calculate losses between new group and all remaining groups
@@ -190,8 +315,16 @@ pub fn calculate_packing(items: &[PackItem], max_groups: usize) -> Vec<PackGroup
                 continue;
             }
             // is this still an active group?
-            if let Some(other_group) = other_group_opt {
-                let loss = calculate_merge_loss(created_group, other_group);
+            if let Some(other_group) = other_group_opt
+                && !pinned[other_id]
+                && can_merge(created_group, other_group, &apart[new_id], &apart[other_id])
+            {
+                let loss = merge_loss(
+                    created_group,
+                    other_group,
+                    previous[new_id],
+                    previous[other_id],
+                );
                 merge_candidates.push(MergeCandidate {
                     loss,
                     group_a_id: new_id,
@@ -207,6 +340,192 @@ pub fn calculate_packing(items: &[PackItem], max_groups: usize) -> Vec<PackGroup
     result
 }
 
+/// Like `calculate_packing()`, but searches for the arrangement with the best
+/// TEV. See the module docstring for the search.
+///
+/// Also returns whether the search completed, i.e. the arrangement is optimal.
+/// Otherwise, it's the best found so far, which is never worse than the greedy
+/// one.
+pub fn calculate_packing_exact(
+    items: &[PackItem],
+    max_groups: usize,
+    options: &PackOptions,
+) -> (Vec<PackGroup>, bool) {
+    let greedy = calculate_packing(items, max_groups, options);
+    let pinned = items.iter().filter(|item| item.pinned).count();
+    // without overhead, keeping every item separate is optimal, and if the
+    // greedy packing needs too many groups, there's nothing to improve on
+    if (items.len() <= max_groups && options.overhead == 0) || greedy.len() > max_groups {
+        return (greedy, true);
+    }
+
+    let mut free: Vec<usize> = (0..items.len()).filter(|&i| !items[i].pinned).collect();
+    let ev = |i: usize| items[i].size as f64 * items[i].stability;
+    free.sort_by(|&a, &b| ev(b).partial_cmp(&ev(a)).unwrap_or(Ordering::Equal));
+    // remaining[k] is the most the items from free[k] on can add
+    let mut remaining = vec![0.0; free.len() + 1];
+    for k in (0..free.len()).rev() {
+        remaining[k] = remaining[k + 1] + ev(free[k]);
+    }
+    let mut apart_of: Vec<Vec<usize>> = vec![Vec::new(); items.len()];
+    for (set_idx, set) in options.apart.iter().enumerate() {
+        for &i in set {
+            apart_of[i].push(set_idx);
+        }
+    }
+
+    let overhead = options.overhead as f64;
+    let best_value = greedy
+        .iter()
+        .filter(|g| !items[g.indices[0]].pinned)
+        .map(|g| g.expected_value() - overhead)
+        .sum();
+    let mut search = ExactSearch {
+        items,
+        free: &free,
+        remaining: &remaining,
+        apart_of: &apart_of,
+        max_size: options.max_size,
+        overhead,
+        max_groups: max_groups - pinned,
+        groups: Vec::new(),
+        best_value,
+        best: None,
+        steps: 0,
+    };
+    search.place(0, 0.0);
+    let complete = search.steps <= EXACT_MAX_STEPS;
+
+    let Some(best) = search.best else {
+        return (greedy, complete);
+    };
+    let mut result: Vec<PackGroup> = items
+        .iter()
+        .enumerate()
+        .filter(|(_, item)| item.pinned)
+        .map(|(i, item)| PackGroup {
+            indices: vec![i],
+            size: item.size,
+            stability: item.stability,
+        })
+        .chain(best.into_iter().map(|group| group.group))
+        .collect();
+    sort_by_stability_desc(&mut result);
+    (result, complete)
+}
+
+/// A group being filled by the exact search, with the apart sets of its items.
+#[derive(Debug, Clone)]
+struct SearchGroup {
+    group: PackGroup,
+    apart: Vec<usize>,
+}
+
+/// State of the exact search.
+struct ExactSearch<'a> {
+    items: &'a [PackItem],
+    /// The unpinned items, in the order they're placed.
+    free: &'a [usize],
+    remaining: &'a [f64],
+    apart_of: &'a [Vec<usize>],
+    max_size: Option<u64>,
+    overhead: f64,
+    /// Maximum number of groups for the unpinned items.
+    max_groups: usize,
+    groups: Vec<SearchGroup>,
+    best_value: f64,
+    /// The best arrangement found, if better than the greedy one.
+    best: Option<Vec<SearchGroup>>,
+    steps: u64,
+}
+
+impl ExactSearch<'_> {
+    /// Place the items from `free[k]` on, with `value` the TEV of the groups
+    /// so far, minus their overhead.
+    fn place(&mut self, k: usize, value: f64) {
+        if self.steps > EXACT_MAX_STEPS || value + self.remaining[k] <= self.best_value {
+            return;
+        }
+        if k == self.free.len() {
+            self.best_value = value;
+            self.best = Some(self.groups.clone());
+            return;
+        }
+        self.steps += 1;
+
+        let idx = self.free[k];
+        let items = self.items;
+        let item = &items[idx];
+        for g in 0..self.groups.len() {
+            let group = &self.groups[g];
+            if self
+                .max_size
+                .is_some_and(|max| group.group.size + item.size > max)
+                || self.apart_of[idx]
+                    .iter()
+                    .any(|set| group.apart.contains(set))
+            {
+                continue;
+            }
+            let old_value = group.group.expected_value();
+            let saved = group.clone();
+            let group = &mut self.groups[g];
+            group.group.indices.push(idx);
+            group.group.size += item.size;
+            group.group.stability *= item.stability;
+            group.apart.extend(&self.apart_of[idx]);
+            let new_value = value - old_value + group.group.expected_value();
+            self.place(k + 1, new_value);
+            self.groups[g] = saved;
+        }
+
+        if self.groups.len() < self.max_groups {
+            self.groups.push(SearchGroup {
+                group: PackGroup {
+                    indices: vec![idx],
+                    size: item.size,
+                    stability: item.stability,
+                },
+                apart: self.apart_of[idx].clone(),
+            });
+            let new_value =
+                value + self.groups.last().unwrap().group.expected_value() - self.overhead;
+            self.place(k + 1, new_value);
+            self.groups.pop();
+        }
+    }
+}
+
+/// Returns true if the pinned items fit in `max_groups`, leaving room for at
+/// least one group for the unpinned items, if any.
+pub fn pins_fit(items: &[PackItem], max_groups: usize) -> bool {
+    let pinned = items.iter().filter(|item| item.pinned).count();
+    let has_unpinned = items.iter().any(|item| !item.pinned);
+    pinned + usize::from(has_unpinned) <= max_groups
+}
+
+/// Unpin items until the pins fit in `max_groups`.
+///
+/// Items with the lowest expected value are unpinned first since keeping them
+/// separate is worth the least. Returns the indices of the unpinned items.
+pub fn relax_pins(items: &mut [PackItem], max_groups: usize) -> Vec<usize> {
+    let mut candidates: Vec<usize> = (0..items.len()).filter(|&i| items[i].pinned).collect();
+    candidates.sort_by(|&a, &b| {
+        let ev = |i: usize| items[i].size as f64 * items[i].stability;
+        ev(a).partial_cmp(&ev(b)).unwrap_or(Ordering::Equal)
+    });
+
+    let mut unpinned = Vec::new();
+    for idx in candidates {
+        if pins_fit(items, max_groups) {
+            break;
+        }
+        items[idx].pinned = false;
+        unpinned.push(idx);
+    }
+    unpinned
+}
+
 fn sort_by_stability_desc(items: &mut [PackGroup]) {
     items.sort_by(|a, b| {
         b.stability
@@ -283,21 +602,23 @@ mod tests {
     #[test]
     fn test_edge_cases() {
         // empty input
-        assert!(calculate_packing(&[], 5).is_empty());
+        assert!(calculate_packing(&[], 5, &PackOptions::default()).is_empty());
 
         // max_groups = 0
         let items = vec![PackItem {
             size: 100,
             stability: 0.5,
+            pinned: false,
         }];
-        assert!(calculate_packing(&items, 0).is_empty());
+        assert!(calculate_packing(&items, 0, &PackOptions::default()).is_empty());
 
         // single item
         let items = vec![PackItem {
             size: 100,
             stability: 0.5,
+            pinned: false,
         }];
-        let result = calculate_packing(&items, 5);
+        let result = calculate_packing(&items, 5, &PackOptions::default());
         assert_eq!(result.len(), 1);
         assert_eq!(result[0].indices, vec![0]);
         verify_packing_result(&items, &result, 5);
@@ -310,17 +631,20 @@ mod tests {
             PackItem {
                 size: 100,
                 stability: 0.9,
+                pinned: false,
             },
             PackItem {
                 size: 200,
                 stability: 0.8,
+                pinned: false,
             },
             PackItem {
                 size: 300,
                 stability: 0.7,
+                pinned: false,
             },
         ];
-        let result = calculate_packing(&items, 5);
+        let result = calculate_packing(&items, 5, &PackOptions::default());
         assert_eq!(result.len(), 3);
         // Should be sorted by stability descending
         assert_eq!(result[0].indices, vec![0]); // 0.9
@@ -335,17 +659,20 @@ mod tests {
             PackItem {
                 size: 100,
                 stability: 0.5,
+                pinned: false,
             },
             PackItem {
                 size: 200,
                 stability: 0.5,
+                pinned: false,
             },
             PackItem {
                 size: 300,
                 stability: 0.5,
+                pinned: false,
             },
         ];
-        let result = calculate_packing(&items, 1);
+        let result = calculate_packing(&items, 1, &PackOptions::default());
         assert_eq!(result.len(), 1);
         assert_eq!(result[0].indices.len(), 3);
         // All items should be in the single group
@@ -363,17 +690,20 @@ mod tests {
             PackItem {
                 size: 1000,
                 stability: 0.99,
+                pinned: false,
             },
             PackItem {
                 size: 1000,
                 stability: 0.99,
+                pinned: false,
             },
             PackItem {
                 size: 1000,
                 stability: 0.3,
+                pinned: false,
             },
         ];
-        let result = calculate_packing(&items, 2);
+        let result = calculate_packing(&items, 2, &PackOptions::default());
         assert_eq!(result.len(), 2);
 
         // the two stable items (indices 0 and 1) should be merged together
@@ -394,17 +724,20 @@ mod tests {
             PackItem {
                 size: 10000,
                 stability: 0.5,
+                pinned: false,
             },
             PackItem {
                 size: 10,
                 stability: 0.5,
+                pinned: false,
             },
             PackItem {
                 size: 10,
                 stability: 0.5,
+                pinned: false,
             },
         ];
-        let result = calculate_packing(&items, 2);
+        let result = calculate_packing(&items, 2, &PackOptions::default());
         assert_eq!(result.len(), 2);
 
         // The two small items (indices 1 and 2) should be merged together (least loss)
@@ -418,4 +751,297 @@ mod tests {
         assert!(small_group.unwrap().indices.contains(&2));
         verify_packing_result(&items, &result, 2);
     }
+
+    #[test]
+    fn test_pinned_not_merged() {
+        // merging the two small items would be cheapest, but one is pinned
+        let items = vec![
+            PackItem {
+                size: 10000,
+                stability: 0.5,
+                pinned: false,
+            },
+            PackItem {
+                size: 10,
+                stability: 0.5,
+                pinned: true,
+            },
+            PackItem {
+                size: 10,
+                stability: 0.5,
+                pinned: false,
+            },
+        ];
+        assert!(pins_fit(&items, 2));
+        let result = calculate_packing(&items, 2, &PackOptions::default());
+        assert_eq!(result.len(), 2);
+
+        let pinned_group = result.iter().find(|g| g.indices.contains(&1)).unwrap();
+        assert_eq!(pinned_group.indices, vec![1]);
+        verify_packing_result(&items, &result, 2);
+    }
+
+    #[test]
+    fn test_relax_pins() {
+        let items = vec![
+            PackItem {
+                size: 1000,
+                stability: 0.9,
+                pinned: true,
+            },
+            PackItem {
+                size: 10,
+                stability: 0.9,
+                pinned: true,
+            },
+            PackItem {
+                size: 100,
+                stability: 0.9,
+                pinned: true,
+            },
+            PackItem {
+                size: 500,
+                stability: 0.5,
+                pinned: false,
+            },
+        ];
+        // 3 pins + 1 group for the unpinned item
+        assert!(pins_fit(&items, 4));
+        assert!(!pins_fit(&items, 2));
+
+        // without relaxing, we get more groups than requested
+        assert_eq!(
+            calculate_packing(&items, 2, &PackOptions::default()).len(),
+            4
+        );
+
+        // the pins with the lowest expected value go first
+        let mut relaxed = items.clone();
+        assert_eq!(relax_pins(&mut relaxed, 2), vec![1, 2]);
+        assert!(pins_fit(&relaxed, 2));
+        let result = calculate_packing(&relaxed, 2, &PackOptions::default());
+        verify_packing_result(&relaxed, &result, 2);
+    }
+
+    #[test]
+    fn test_max_size() {
+        let item = |size| PackItem {
+            size,
+            stability: 0.5,
+            pinned: false,
+        };
+        let items = vec![item(60), item(50), item(40), item(10)];
+        let max_size = PackOptions {
+            max_size: Some(100),
+            ..Default::default()
+        };
+
+        let result = calculate_packing(&items, 2, &max_size);
+        verify_packing_result(&items, &result, 2);
+        assert!(result.iter().all(|g| g.size <= 100));
+
+        // 160 bytes don't fit in one group of at most 100 bytes
+        let result = calculate_packing(&items, 1, &max_size);
+        assert_eq!(result.len(), 2);
+        assert!(result.iter().all(|g| g.size <= 100));
+    }
+
+    /// Returns the best TEV of packing `items` into at most `max_groups`
+    /// groups with `overhead` bytes per group by trying all the arrangements.
+    fn brute_force(items: &[PackItem], max_groups: usize, overhead: u64) -> f64 {
+        fn go(
+            items: &[PackItem],
+            groups: &mut Vec<(u64, f64)>,
+            k: usize,
+            max: usize,
+            o: f64,
+        ) -> f64 {
+            if k == items.len() {
+                return groups.iter().map(|(s, p)| *s as f64 * p - o).sum();
+            }
+            let mut best = f64::MIN;
+            for g in 0..groups.len() {
+                let saved = groups[g];
+                groups[g] = (saved.0 + items[k].size, saved.1 * items[k].stability);
+                best = best.max(go(items, groups, k + 1, max, o));
+                groups[g] = saved;
+            }
+            if groups.len() < max {
+                groups.push((items[k].size, items[k].stability));
+                best = best.max(go(items, groups, k + 1, max, o));
+                groups.pop();
+            }
+            best
+        }
+        go(items, &mut Vec::new(), 0, max_groups, overhead as f64)
+    }
+
+    fn total_value(result: &[PackGroup], overhead: u64) -> f64 {
+        result
+            .iter()
+            .map(|g| g.expected_value() - overhead as f64)
+            .sum()
+    }
+
+    #[test]
+    fn test_exact() {
+        let item = |size, stability| PackItem {
+            size,
+            stability,
+            pinned: false,
+        };
+        let items = vec![
+            item(1000, 0.99),
+            item(900, 0.5),
+            item(800, 0.95),
+            item(300, 0.2),
+            item(200, 0.9),
+            item(100, 0.7),
+            item(50, 0.99),
+        ];
+        for max_groups in 1..=items.len() {
+            let (result, optimal) =
+                calculate_packing_exact(&items, max_groups, &PackOptions::default());
+            assert!(optimal);
+            verify_packing_result(&items, &result, max_groups);
+            let greedy = calculate_packing(&items, max_groups, &PackOptions::default());
+            assert!(total_value(&result, 0) >= total_value(&greedy, 0) - 1e-6);
+            let best = brute_force(&items, max_groups, 0);
+            assert!((total_value(&result, 0) - best).abs() < 1e-6 * best);
+        }
+
+        // pins and constraints are honored
+        let mut items = items;
+        items[0].pinned = true;
+        let constraints = PackOptions {
+            max_size: Some(1500),
+            apart: vec![vec![1, 2]],
+            ..Default::default()
+        };
+        let (result, optimal) = calculate_packing_exact(&items, 3, &constraints);
+        assert!(optimal);
+        verify_packing_result(&items, &result, 3);
+        assert!(result.iter().any(|g| g.indices == [0]));
+        assert!(result.iter().all(|g| g.indices == [0] || g.size <= 1500));
+        assert!(
+            !result
+                .iter()
+                .any(|g| g.indices.contains(&1) && g.indices.contains(&2))
+        );
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_exact_matches_brute_force(
+            items in proptest::collection::vec((1u64..10_000, 0.01f64..1.0), 1..8),
+            max_groups in 1usize..5,
+            overhead in proptest::prop_oneof![proptest::strategy::Just(0u64), 1u64..5000],
+        ) {
+            let items: Vec<PackItem> = items
+                .into_iter()
+                .map(|(size, stability)| PackItem { size, stability, pinned: false })
+                .collect();
+            let options = PackOptions {
+                overhead,
+                ..Default::default()
+            };
+            let (result, optimal) = calculate_packing_exact(&items, max_groups, &options);
+            proptest::prop_assert!(optimal);
+            verify_packing_result(&items, &result, max_groups);
+            let best = brute_force(&items, max_groups, overhead);
+            let value = total_value(&result, overhead);
+            proptest::prop_assert!((value - best).abs() <= 1e-6 * best.abs().max(1.0));
+        }
+    }
+
+    #[test]
+    fn test_overhead() {
+        let item = |size, stability| PackItem {
+            size,
+            stability,
+            pinned: false,
+        };
+        let mut items = vec![item(1_000_000, 0.99); 3];
+        items.extend(vec![item(100, 0.5); 5]);
+
+        let result = calculate_packing(&items, 10, &PackOptions::default());
+        assert_eq!(result.len(), 8);
+
+        // the tiny items aren't worth a layer each, but the big ones are
+        let options = PackOptions {
+            overhead: 10_000,
+            ..Default::default()
+        };
+        let result = calculate_packing(&items, 10, &options);
+        verify_packing_result(&items, &result, 10);
+        assert_eq!(result.len(), 4);
+        assert!(result.iter().any(|g| g.indices.len() == 5));
+
+        let (exact, optimal) = calculate_packing_exact(&items, 10, &options);
+        assert!(optimal);
+        assert!(total_value(&exact, 10_000) >= total_value(&result, 10_000) - 1e-6);
+    }
+
+    #[test]
+    fn test_previous() {
+        let items = vec![
+            PackItem {
+                size: 100,
+                stability: 0.5,
+                pinned: false,
+            };
+            4
+        ];
+        // all merges are equally good, so the previous groups win
+        let options = PackOptions {
+            previous: vec![Some(0), Some(1), Some(0), Some(1)],
+            ..Default::default()
+        };
+        let result = calculate_packing(&items, 2, &options);
+        verify_packing_result(&items, &result, 2);
+        let mut groups: Vec<Vec<usize>> = result
+            .into_iter()
+            .map(|mut g| {
+                g.indices.sort();
+                g.indices
+            })
+            .collect();
+        groups.sort();
+        assert_eq!(groups, [vec![0, 2], vec![1, 3]]);
+
+        // previous groups which are much worse aren't reproduced
+        let mut items = items;
+        items[2].size = 1_000_000;
+        items[2].stability = 0.99;
+        let result = calculate_packing(&items, 2, &options);
+        let big = result.iter().find(|g| g.indices.contains(&2)).unwrap();
+        assert_eq!(big.indices, [2]);
+    }
+
+    #[test]
+    fn test_apart() {
+        let item = |size| PackItem {
+            size,
+            stability: 0.5,
+            pinned: false,
+        };
+        // merging the two small items would be cheapest
+        let items = vec![item(10), item(10), item(1000), item(2000)];
+        let constraints = PackOptions {
+            apart: vec![vec![0, 1]],
+            ..Default::default()
+        };
+
+        let result = calculate_packing(&items, 2, &constraints);
+        verify_packing_result(&items, &result, 2);
+        assert!(
+            !result
+                .iter()
+                .any(|g| g.indices.contains(&0) && g.indices.contains(&1))
+        );
+
+        // the two can't share the only group
+        let result = calculate_packing(&items, 1, &constraints);
+        assert_eq!(result.len(), 2);
+    }
 }
@@ -0,0 +1,194 @@
+//! A gzip encoder compressing blocks of its input on several threads, like
+//! pigz, so that a single huge layer doesn't bottleneck on one core.
+//!
+//! The input is cut into blocks of [`BLOCK_SIZE`] bytes, each deflated on its
+//! own and ended with a sync flush, which byte-aligns it so the blocks can be
+//! concatenated into a single deflate stream. The output only depends on the
+//! input and the compression level, not on the number of threads, but it
+//! differs from that of [`flate2::write::GzEncoder`].
+
+use std::io::{self, Write};
+
+use flate2::{Compress, FlushCompress, Status};
+use ocidir::{BlobWriter, WriteComplete};
+
+/// Size of the blocks compressed independently. Larger blocks compress a bit
+/// better, smaller ones spread small layers over more threads.
+const BLOCK_SIZE: usize = 1 << 20;
+
+pub(crate) struct ParallelGzEncoder<W: Write> {
+    inner: W,
+    level: flate2::Compression,
+    threads: usize,
+    /// Full blocks waiting to be compressed, one per thread.
+    pending: Vec<Vec<u8>>,
+    /// The block being filled.
+    current: Vec<u8>,
+    crc: flate2::Crc,
+    header_written: bool,
+}
+
+impl<W: Write> ParallelGzEncoder<W> {
+    pub(crate) fn new(inner: W, level: flate2::Compression, threads: usize) -> Self {
+        Self {
+            inner,
+            level,
+            threads: threads.max(1),
+            pending: Vec::new(),
+            current: Vec::with_capacity(BLOCK_SIZE),
+            crc: flate2::Crc::new(),
+            header_written: false,
+        }
+    }
+
+    /// Compress the rest of the input, write the gzip trailer and return the
+    /// inner writer.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        let last = std::mem::take(&mut self.current);
+        self.write_blocks(Some(&last))?;
+        self.inner.write_all(&self.crc.sum().to_le_bytes())?;
+        self.inner.write_all(&self.crc.amount().to_le_bytes())?;
+        Ok(self.inner)
+    }
+
+    /// Compress the pending blocks and `last`, the final block if set, in
+    /// parallel and write them out in order.
+    fn write_blocks(&mut self, last: Option<&[u8]>) -> io::Result<()> {
+        if !self.header_written {
+            self.inner.write_all(&gzip_header(self.level))?;
+            self.header_written = true;
+        }
+        let level = self.level;
+        let blocks = std::mem::take(&mut self.pending);
+        let compressed: Vec<io::Result<Vec<u8>>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = blocks
+                .iter()
+                .map(|block| (block.as_slice(), false))
+                .chain(last.map(|block| (block, true)))
+                .map(|(block, last)| scope.spawn(move || deflate_block(block, level, last)))
+                .collect();
+            handles
+                .into_iter()
+                // SAFETY: deflate_block doesn't panic
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        for block in compressed {
+            self.inner.write_all(&block?)?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ParallelGzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.crc.update(buf);
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let n = (BLOCK_SIZE - self.current.len()).min(rest.len());
+            self.current.extend_from_slice(&rest[..n]);
+            rest = &rest[n..];
+            if self.current.len() == BLOCK_SIZE {
+                let block = std::mem::replace(&mut self.current, Vec::with_capacity(BLOCK_SIZE));
+                self.pending.push(block);
+                if self.pending.len() == self.threads {
+                    self.write_blocks(None)?;
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // compressing a partial block would make the output depend on when
+        // we're flushed
+        self.inner.flush()
+    }
+}
+
+impl<'a> WriteComplete<BlobWriter<'a>> for ParallelGzEncoder<BlobWriter<'a>> {
+    fn complete(self) -> io::Result<BlobWriter<'a>> {
+        self.finish()
+    }
+}
+
+/// The gzip header, the same as the one of [`flate2::GzBuilder`] without
+/// mtime.
+fn gzip_header(level: flate2::Compression) -> [u8; 10] {
+    let extra_flags = if level.level() >= flate2::Compression::best().level() {
+        2
+    } else if level.level() <= flate2::Compression::fast().level() {
+        4
+    } else {
+        0
+    };
+    [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, extra_flags, 255]
+}
+
+/// Deflate `block` on its own, ending the stream if it's the `last` one and
+/// with a sync flush otherwise.
+fn deflate_block(block: &[u8], level: flate2::Compression, last: bool) -> io::Result<Vec<u8>> {
+    let mut compress = Compress::new(level, false);
+    let flush = if last {
+        FlushCompress::Finish
+    } else {
+        FlushCompress::Sync
+    };
+    let mut out = Vec::with_capacity(block.len() / 2 + 64);
+    loop {
+        if out.len() == out.capacity() {
+            out.reserve(out.capacity());
+        }
+        let consumed = usize::try_from(compress.total_in()).unwrap_or(usize::MAX);
+        let status = compress
+            .compress_vec(&block[consumed..], &mut out, flush)
+            .map_err(io::Error::other)?;
+        let done = if last {
+            status == Status::StreamEnd
+        } else {
+            // the flush is complete once there's room left in the output
+            compress.total_in() == block.len() as u64 && out.len() < out.capacity()
+        };
+        if done {
+            return Ok(out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn compress(data: &[u8], threads: usize) -> Vec<u8> {
+        let mut encoder = ParallelGzEncoder::new(Vec::new(), flate2::Compression::new(6), threads);
+        // odd write sizes to cross block boundaries
+        for chunk in data.chunks(100_003) {
+            encoder.write_all(chunk).unwrap();
+        }
+        encoder.finish().unwrap()
+    }
+
+    fn decompress(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        flate2::read::GzDecoder::new(data)
+            .read_to_end(&mut out)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_parallel_gzip() {
+        let text: Vec<u8> = (0..BLOCK_SIZE * 5 / 2)
+            .map(|i| b"chunkah layers "[i % 15])
+            .collect();
+        for data in [&[][..], b"hello", &text[..BLOCK_SIZE], &text] {
+            let compressed = compress(data, 3);
+            assert_eq!(decompress(&compressed), data);
+            // the output doesn't depend on the number of threads
+            assert_eq!(compress(data, 1), compressed);
+        }
+        assert!(compress(&text, 3).len() < text.len() / 100);
+    }
+}
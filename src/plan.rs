@@ -0,0 +1,200 @@
+//! Build plans: the layers of an image and the paths they hold.
+//!
+//! Claiming files and packing components depend on e.g. the time of the
+//! build, the stability state and the available repos, while writing layers
+//! from a given assignment of paths is reproducible. `--plan-out` records the
+//! outcome of the former, and `--plan` builds the exact same layers from it
+//! later without claiming or packing again.
+
+use std::collections::BTreeSet;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::components::{Component, FileMap};
+
+/// Version of the plan format.
+const PLAN_VERSION: u32 = 1;
+
+/// Maximum number of paths of each kind listed in the error.
+const MAX_REPORTED_PATHS: usize = 20;
+
+/// The layers of an image, in order.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Plan {
+    version: u32,
+    layers: Vec<PlannedLayer>,
+    /// The components JSON embedded with `--embed-components`, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    components_json: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct PlannedLayer {
+    name: String,
+    stability: f64,
+    mtime_clamp: u64,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    packages: BTreeSet<String>,
+    paths: Vec<Utf8PathBuf>,
+}
+
+impl Plan {
+    /// Record the packed `layers`, and the components JSON to embed, if any.
+    pub fn new(layers: &[(String, Component)], components_json: Option<&[u8]>) -> Result<Self> {
+        let components_json = components_json
+            .map(|json| String::from_utf8(json.to_vec()))
+            .transpose()
+            .context("components JSON is not UTF-8")?;
+        Ok(Self {
+            version: PLAN_VERSION,
+            layers: layers
+                .iter()
+                .map(|(name, component)| PlannedLayer {
+                    name: name.clone(),
+                    stability: component.stability,
+                    mtime_clamp: component.mtime_clamp,
+                    packages: component.packages.clone(),
+                    paths: component.files.keys().cloned().collect(),
+                })
+                .collect(),
+            components_json,
+        })
+    }
+
+    /// Load a plan written by [`Plan::write`].
+    pub fn load(path: &Utf8Path) -> Result<Self> {
+        let content = std::fs::read(path).with_context(|| format!("reading {path}"))?;
+        let plan: Self =
+            serde_json::from_slice(&content).with_context(|| format!("parsing {path}"))?;
+        anyhow::ensure!(
+            plan.version == PLAN_VERSION,
+            "{path}: unsupported version {}",
+            plan.version
+        );
+        Ok(plan)
+    }
+
+    /// Write the plan to `path` as JSON.
+    pub fn write(&self, path: &Utf8Path) -> Result<()> {
+        let content = serde_json::to_vec_pretty(self).context("serializing plan")?;
+        std::fs::write(path, content).with_context(|| format!("writing {path}"))
+    }
+
+    /// Returns the components JSON to embed, if the plan has one.
+    pub fn components_json(&self) -> Option<&[u8]> {
+        self.components_json.as_deref().map(str::as_bytes)
+    }
+
+    /// Assign `files` to the layers of the plan.
+    ///
+    /// Fails unless the files are exactly those of the plan.
+    pub fn layers(&self, mut files: FileMap) -> Result<Vec<(String, Component)>> {
+        let mut missing = Vec::new();
+        let layers = self
+            .layers
+            .iter()
+            .map(|layer| {
+                let mut layer_files = FileMap::new();
+                for path in &layer.paths {
+                    match files.remove_entry(path) {
+                        Some((path, info)) => {
+                            layer_files.insert(path, info);
+                        }
+                        None => missing.push(path.as_path()),
+                    }
+                }
+                let component = Component {
+                    mtime_clamp: layer.mtime_clamp,
+                    stability: layer.stability,
+                    files: layer_files,
+                    packages: layer.packages.clone(),
+                };
+                (layer.name.clone(), component)
+            })
+            .collect();
+
+        if missing.is_empty() && files.is_empty() {
+            return Ok(layers);
+        }
+        let mut report = String::from("rootfs doesn't match the plan");
+        let unplanned: Vec<&Utf8Path> = files.keys().map(Utf8PathBuf::as_path).collect();
+        for (kind, paths) in [("missing", &missing), ("not in the plan", &unplanned)] {
+            if paths.is_empty() {
+                continue;
+            }
+            report.push_str(&format!("\n{} path(s) {kind}:", paths.len()));
+            for path in paths.iter().take(MAX_REPORTED_PATHS) {
+                report.push_str(&format!("\n  {path}"));
+            }
+            if paths.len() > MAX_REPORTED_PATHS {
+                report.push_str("\n  ...");
+            }
+        }
+        anyhow::bail!(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{FileInfo, FileType};
+
+    fn file_map(paths: &[&str]) -> FileMap {
+        paths
+            .iter()
+            .map(|p| {
+                let info = FileInfo::test_file(FileType::File, 1, 1);
+                (Utf8PathBuf::from(*p), info)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_plan_roundtrip() {
+        let layer = |paths: &[&str], stability| Component {
+            mtime_clamp: 100,
+            stability,
+            files: file_map(paths),
+            packages: BTreeSet::from(["bash".to_string()]),
+        };
+        let layers = vec![
+            (
+                "rpm/bash".to_string(),
+                layer(&["/usr/bin/bash"], 0.123456789),
+            ),
+            (
+                "rpm/coreutils rpm/glibc".to_string(),
+                layer(&["/usr/bin/ls", "/usr/lib64/libc.so.6"], 0.5),
+            ),
+        ];
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = Utf8Path::from_path(tmp.path()).unwrap().join("plan.json");
+        Plan::new(&layers, Some(b"{}"))
+            .unwrap()
+            .write(&path)
+            .unwrap();
+        let plan = Plan::load(&path).unwrap();
+        assert_eq!(plan.components_json(), Some(&b"{}"[..]));
+
+        let files = file_map(&["/usr/bin/bash", "/usr/bin/ls", "/usr/lib64/libc.so.6"]);
+        let planned = plan.layers(files).unwrap();
+        assert_eq!(planned.len(), 2);
+        for ((name, component), (planned_name, planned_component)) in layers.iter().zip(&planned) {
+            assert_eq!(name, planned_name);
+            assert_eq!(component.stability, planned_component.stability);
+            assert_eq!(component.mtime_clamp, planned_component.mtime_clamp);
+            assert_eq!(component.packages, planned_component.packages);
+            assert!(component.files.keys().eq(planned_component.files.keys()));
+        }
+
+        let files = file_map(&["/usr/bin/bash", "/usr/bin/ls", "/usr/bin/new"]);
+        let err = plan.layers(files).unwrap_err().to_string();
+        assert!(err.contains("1 path(s) missing:\n  /usr/lib64/libc.so.6"));
+        assert!(err.contains("1 path(s) not in the plan:\n  /usr/bin/new"));
+    }
+}
@@ -0,0 +1,101 @@
+use crate::packing::PackingAlgorithm;
+use crate::tar::EntryOrder;
+
+/// Presets of build options for common classes of images.
+///
+/// Options set explicitly on the command line always take precedence over the
+/// profile's defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    /// Bootable container images; large OS images with many packages.
+    Bootc,
+    /// Minimal images with few components.
+    Distroless,
+    /// Images shipping AI/ML models; dominated by huge, incompressible files.
+    Ml,
+    /// Application images on top of a distro base.
+    Webapp,
+}
+
+/// Build option defaults provided by a profile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileDefaults {
+    /// Maximum number of layers to output.
+    pub max_layers: usize,
+    /// Whether to compress layers and the archive.
+    pub compressed: bool,
+    /// Order of entries within each layer.
+    pub entry_order: EntryOrder,
+    /// Paths to prune from the rootfs, in addition to those from the CLI.
+    pub prune: &'static [&'static str],
+    /// Minimum size of unclaimed files getting a component of their own, if
+    /// not the `bigfiles` repo's.
+    pub bigfile_threshold: Option<u64>,
+    /// Xattr to read the component of paths from, if not `user.component`.
+    pub component_xattr: Option<&'static str>,
+    /// Algorithm used to pack components into layers.
+    pub packing: PackingAlgorithm,
+}
+
+impl Default for ProfileDefaults {
+    /// The defaults when no profile is selected.
+    fn default() -> Self {
+        Self {
+            max_layers: 64,
+            compressed: false,
+            entry_order: EntryOrder::Path,
+            prune: &[],
+            bigfile_threshold: None,
+            component_xattr: None,
+            packing: PackingAlgorithm::Greedy,
+        }
+    }
+}
+
+/// Runtime state directories whose contents should never be shipped.
+const RUNTIME_DIRS: &[&str] = &["/run/", "/tmp/", "/var/tmp/"];
+
+/// Same as RUNTIME_DIRS, plus the physical root of bootable images, which
+/// must be empty in the container image.
+const BOOTC_PRUNE: &[&str] = &["/run/", "/tmp/", "/var/tmp/", "/sysroot/"];
+
+/// Minimum size of the unclaimed files of ML images getting a component of
+/// their own: 64 MiB.
+const ML_BIGFILE_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+impl Profile {
+    /// Returns the build option defaults for this profile.
+    pub fn defaults(self) -> ProfileDefaults {
+        match self {
+            // Lots of packages; more layers means better splitting. The
+            // component xattr stays rpm-ostree's `user.component`.
+            Profile::Bootc => ProfileDefaults {
+                max_layers: 96,
+                prune: BOOTC_PRUNE,
+                ..Default::default()
+            },
+            // Few components, so there's no point in many layers, and few
+            // enough for the exact packing.
+            Profile::Distroless => ProfileDefaults {
+                max_layers: 32,
+                compressed: true,
+                packing: PackingAlgorithm::Exact,
+                ..Default::default()
+            },
+            // Weights don't compress well and small files (configs,
+            // tokenizers) are useful to have first when streaming. Only the
+            // weights themselves are worth layers of their own.
+            Profile::Ml => ProfileDefaults {
+                entry_order: EntryOrder::Size,
+                bigfile_threshold: Some(ML_BIGFILE_THRESHOLD),
+                ..Default::default()
+            },
+            Profile::Webapp => ProfileDefaults {
+                compressed: true,
+                prune: RUNTIME_DIRS,
+                ..Default::default()
+            },
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Status lines on stderr for the long-running steps of a build, enabled with
+//! `--progress`.
+
+use std::time::{Duration, Instant};
+
+use crate::trace::{self, LogFormat};
+
+/// Minimum time between two status lines of the same step.
+const INTERVAL: Duration = Duration::from_secs(2);
+
+/// Counts the files and bytes processed by a step, and prints how far it got
+/// at most once per [`INTERVAL`].
+pub(crate) struct Progress {
+    step: &'static str,
+    files: u64,
+    bytes: u64,
+    started: Instant,
+    last_report: Instant,
+}
+
+impl Progress {
+    pub(crate) fn new(step: &'static str) -> Self {
+        let now = Instant::now();
+        Self {
+            step,
+            files: 0,
+            bytes: 0,
+            started: now,
+            last_report: now,
+        }
+    }
+
+    /// Count a file of `size` bytes.
+    pub(crate) fn add(&mut self, size: u64) {
+        self.files += 1;
+        self.bytes += size;
+        if self.last_report.elapsed() >= INTERVAL {
+            self.last_report = Instant::now();
+            self.report(None);
+        }
+    }
+
+    /// Print the totals of the step.
+    pub(crate) fn finish(&self) {
+        self.report(Some(self.started.elapsed()));
+    }
+
+    fn report(&self, done_in: Option<Duration>) {
+        match trace::format() {
+            LogFormat::Text => eprintln!("{}", self.status(done_in)),
+            LogFormat::Json => {
+                let mut record = serde_json::json!({
+                    "step": self.step,
+                    "files": self.files,
+                    "bytes": self.bytes,
+                });
+                if let Some(elapsed) = done_in {
+                    record["duration_ms"] = u64::try_from(elapsed.as_millis())
+                        .unwrap_or(u64::MAX)
+                        .into();
+                }
+                eprintln!("{}", trace::json_line("progress", record));
+            }
+        }
+    }
+
+    fn status(&self, done_in: Option<Duration>) -> String {
+        let mut status = format!(
+            "progress: {}: {} files, {} bytes",
+            self.step, self.files, self.bytes
+        );
+        if let Some(elapsed) = done_in {
+            status.push_str(&format!(", done in {:.1}s", elapsed.as_secs_f64()));
+        }
+        status
+    }
+}
+
+/// Print that layer `index` (from 0) of `count`, named `name`, was written.
+pub(crate) fn layer_written(index: usize, count: usize, name: &str, files: usize, size: u64) {
+    match trace::format() {
+        LogFormat::Text => eprintln!("{}", layer_status(index, count, name, files, size)),
+        LogFormat::Json => {
+            let record = serde_json::json!({
+                "step": "layer",
+                "layer": index + 1,
+                "layers": count,
+                "name": name,
+                "files": files,
+                "bytes": size,
+            });
+            eprintln!("{}", trace::json_line("progress", record));
+        }
+    }
+}
+
+fn layer_status(index: usize, count: usize, name: &str, files: usize, size: u64) -> String {
+    format!(
+        "progress: layer {}/{count} {name}: {files} files, {size} bytes written",
+        index + 1
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let mut progress = Progress::new("scanning");
+        progress.add(100);
+        progress.add(0);
+        assert_eq!(
+            progress.status(None),
+            "progress: scanning: 2 files, 100 bytes"
+        );
+        assert_eq!(
+            progress.status(Some(Duration::from_millis(1500))),
+            "progress: scanning: 2 files, 100 bytes, done in 1.5s"
+        );
+        assert_eq!(
+            layer_status(2, 64, "rpm/glibc", 890, 9876543),
+            "progress: layer 3/64 rpm/glibc: 890 files, 9876543 bytes written"
+        );
+    }
+}
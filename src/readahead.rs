@@ -0,0 +1,198 @@
+//! Reading the files of a layer ahead of the tar writer on a few threads, so
+//! that waiting on the storage (e.g. an NFS build root) overlaps with
+//! compressing what was already read, enabled with `--read-ahead`.
+//!
+//! The files are claimed in the order they're written, and only while the
+//! bytes read but not yet written fit in [`BUDGET`], so memory stays bounded
+//! and the file the writer waits for is always either claimed or claimable.
+
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::Scope;
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use cap_std_ext::cap_std::fs::Dir;
+
+use crate::components::FileInfo;
+use crate::overlay::Overlay;
+
+/// Maximum number of bytes read ahead of the writer. A larger file is still
+/// read, once nothing else is in flight.
+const BUDGET: u64 = 64 << 20;
+
+#[derive(Default)]
+struct State {
+    /// Index of the next file to claim.
+    next: usize,
+    /// Bytes claimed but not yet taken by the writer.
+    in_flight: u64,
+    /// Set once the writer is gone.
+    stop: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+/// The contents of `files`, read ahead by threads of a [`Scope`] and handed
+/// out in order by [`ReadAhead::next`].
+pub(crate) struct ReadAhead<'a> {
+    files: &'a [(&'a Utf8Path, &'a FileInfo)],
+    next: usize,
+    shared: Arc<Shared>,
+    receiver: Receiver<(usize, Result<Vec<u8>>)>,
+    /// Contents received before the ones of the files before them.
+    pending: HashMap<usize, Result<Vec<u8>>>,
+}
+
+impl<'a> ReadAhead<'a> {
+    /// Start reading `files`, in the order they'll be asked for, on
+    /// `threads` threads of `scope`.
+    pub(crate) fn start<'scope>(
+        scope: &'scope Scope<'scope, 'a>,
+        rootfs: &'a Dir,
+        overlay: Option<&'a Overlay>,
+        files: &'a [(&'a Utf8Path, &'a FileInfo)],
+        threads: usize,
+    ) -> Self {
+        let shared = Arc::new(Shared::default());
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..threads.max(1).min(files.len()) {
+            let shared = Arc::clone(&shared);
+            let sender = sender.clone();
+            scope.spawn(move || {
+                while let Some(index) = claim(&shared, files) {
+                    let (path, info) = files[index];
+                    let content = crate::tar::read_file_content(rootfs, path, info, overlay);
+                    // the receiver is only gone once the writer is
+                    if sender.send((index, content)).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        Self {
+            files,
+            next: 0,
+            shared,
+            receiver,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Returns the content of the next file, which must be `path`.
+    pub(crate) fn next(&mut self, path: &Utf8Path) -> Result<Vec<u8>> {
+        let &(expected, info) = self
+            .files
+            .get(self.next)
+            .with_context(|| format!("{path} wasn't read ahead"))?;
+        anyhow::ensure!(
+            expected == path,
+            "{path} read out of order, expected {expected}"
+        );
+        let content = loop {
+            if let Some(content) = self.pending.remove(&self.next) {
+                break content;
+            }
+            let (index, content) = self
+                .receiver
+                .recv()
+                .context("read-ahead threads stopped early")?;
+            self.pending.insert(index, content);
+        };
+        self.next += 1;
+        {
+            // SAFETY: we never panic while holding the lock
+            let mut state = self.shared.state.lock().unwrap();
+            state.in_flight -= info.size;
+        }
+        self.shared.changed.notify_all();
+        content
+    }
+}
+
+impl Drop for ReadAhead<'_> {
+    fn drop(&mut self) {
+        {
+            // SAFETY: we never panic while holding the lock
+            let mut state = self.shared.state.lock().unwrap();
+            state.stop = true;
+        }
+        self.shared.changed.notify_all();
+    }
+}
+
+/// Claim the next file once it fits in the budget, or return `None` once
+/// there's nothing left to read.
+fn claim(shared: &Shared, files: &[(&Utf8Path, &FileInfo)]) -> Option<usize> {
+    // SAFETY: we never panic while holding the lock
+    let mut state = shared.state.lock().unwrap();
+    loop {
+        if state.stop || state.next == files.len() {
+            return None;
+        }
+        let size = files[state.next].1.size;
+        if state.in_flight == 0 || state.in_flight + size <= BUDGET {
+            let index = state.next;
+            state.next += 1;
+            state.in_flight += size;
+            return Some(index);
+        }
+        state = shared.changed.wait(state).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std::ambient_authority;
+
+    use super::*;
+    use crate::components::FileType;
+
+    #[test]
+    fn test_read_ahead() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        let paths: Vec<String> = (0..50).map(|i| format!("/file{i}")).collect();
+        for (i, path) in paths.iter().enumerate() {
+            rootfs.write(&path[1..], vec![b'x'; i]).unwrap();
+        }
+        // one file bigger than the budget
+        let infos: Vec<FileInfo> = paths
+            .iter()
+            .enumerate()
+            .map(|(i, path)| {
+                let metadata = rootfs.symlink_metadata(&path[1..]).unwrap();
+                let mut info =
+                    FileInfo::from_metadata(&metadata, FileType::File, Default::default());
+                if i == 10 {
+                    info.size = BUDGET + 1;
+                }
+                info
+            })
+            .collect();
+        let files: Vec<(&Utf8Path, &FileInfo)> = paths
+            .iter()
+            .zip(&infos)
+            .map(|(path, info)| (Utf8Path::new(path), info))
+            .collect();
+
+        std::thread::scope(|scope| {
+            let mut read_ahead = ReadAhead::start(scope, &rootfs, None, &files, 4);
+            for (i, (path, _)) in files.iter().enumerate() {
+                assert_eq!(read_ahead.next(path).unwrap(), vec![b'x'; i]);
+            }
+            assert!(read_ahead.next(Utf8Path::new("/file0")).is_err());
+        });
+
+        // stopping early doesn't hang the threads
+        std::thread::scope(|scope| {
+            let mut read_ahead = ReadAhead::start(scope, &rootfs, None, &files, 4);
+            assert!(read_ahead.next(Utf8Path::new("/file1")).is_err());
+        });
+    }
+}
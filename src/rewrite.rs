@@ -0,0 +1,324 @@
+//! Rewriting of path prefixes, e.g. to ship an app staged under
+//! `/build/stage` as `/opt/app` without copying it.
+//!
+//! Rewritten paths replace the original ones in the scanned [`FileMap`], and
+//! their files are still read from their original path (see
+//! [`FileInfo::fs_path`]). Absolute symlink targets are rewritten as they're
+//! written to the layers. The first rule matching a path applies.
+
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+
+use crate::components::{FileInfo, FileMap, FileType};
+
+/// A rewrite of the paths under `from` to the same paths under `to`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathRewrite {
+    from: Utf8PathBuf,
+    to: Utf8PathBuf,
+}
+
+impl PathRewrite {
+    /// Parse a rule as `FROM=TO`, with absolute paths.
+    pub fn parse(s: &str) -> Result<Self> {
+        let Some((from, to)) = s.split_once('=') else {
+            anyhow::bail!("{s} is not FROM=TO");
+        };
+        let normalize = |path: &str| -> Result<Utf8PathBuf> {
+            let path = Utf8Path::new(path);
+            anyhow::ensure!(path.is_absolute(), "path must be absolute: {path}");
+            anyhow::ensure!(
+                path.components()
+                    .all(|c| matches!(c, Utf8Component::RootDir | Utf8Component::Normal(_))),
+                "path must be normalized: {path}"
+            );
+            anyhow::ensure!(path != "/", "can't rewrite the root directory");
+            // this also drops trailing slashes
+            Ok(path.components().collect())
+        };
+        Ok(Self {
+            from: normalize(from).with_context(|| format!("invalid rewrite {s}"))?,
+            to: normalize(to).with_context(|| format!("invalid rewrite {s}"))?,
+        })
+    }
+
+    fn rewrite(&self, path: &Utf8Path) -> Option<Utf8PathBuf> {
+        let rest = path.strip_prefix(&self.from).ok()?;
+        Some(match rest.as_str() {
+            "" => self.to.clone(),
+            rest => self.to.join(rest),
+        })
+    }
+}
+
+/// The rewrite rules, and the directories at and above their targets, for
+/// parent directories of entries which aren't in the layer.
+#[derive(Debug)]
+pub struct PathRewrites {
+    rules: Vec<PathRewrite>,
+    dirs: FileMap,
+}
+
+impl PathRewrites {
+    pub fn new(rules: Vec<PathRewrite>) -> Self {
+        Self {
+            rules,
+            dirs: FileMap::new(),
+        }
+    }
+
+    /// Returns the rewritten `path`, if a rule matches it.
+    fn rewrite(&self, path: &Utf8Path) -> Option<Utf8PathBuf> {
+        self.rules.iter().find_map(|rule| rule.rewrite(path))
+    }
+
+    /// Rewrite the paths of `files`.
+    ///
+    /// Parent directories of the targets which don't exist are added as
+    /// root-owned 0755 directories, and parent directories of the sources
+    /// which are left empty are dropped, as if the sources had been moved.
+    pub fn apply(&mut self, files: &mut FileMap) -> Result<()> {
+        for rule in &self.rules {
+            anyhow::ensure!(
+                files.contains_key(&rule.from),
+                "{} to rewrite isn't in the rootfs",
+                rule.from
+            );
+            anyhow::ensure!(
+                self.rewrite(&rule.from).as_ref() == Some(&rule.to),
+                "{} is already rewritten by an earlier rule",
+                rule.from
+            );
+        }
+
+        let mut rewritten = Vec::new();
+        files.retain(|path, info| match self.rewrite(path) {
+            Some(new_path) => {
+                let mut info = info.clone();
+                info.set_disk_path(Some(info.fs_path(path).into()));
+                rewritten.push((path.clone(), new_path, info));
+                false
+            }
+            None => true,
+        });
+        for (path, new_path, info) in rewritten {
+            anyhow::ensure!(
+                !files.contains_key(&new_path),
+                "rewriting {path} to {new_path} collides with an existing path"
+            );
+            files.insert(new_path, info);
+        }
+
+        for rule in &self.rules {
+            // SAFETY: we checked that `from` is in the map and rewritten by
+            // this rule
+            let mtime = files[&rule.to].mtime;
+            for ancestor in rule.to.ancestors().skip(1) {
+                if ancestor == "/" || files.contains_key(ancestor) {
+                    continue;
+                }
+                files.insert(
+                    ancestor.to_owned(),
+                    FileInfo {
+                        file_type: FileType::Directory,
+                        mode: libc::S_IFDIR | 0o755,
+                        size: 0,
+                        uid: 0,
+                        gid: 0,
+                        mtime,
+                        ino: 0,
+                        nlink: 1,
+                        xattrs: Default::default(),
+                        extra: None,
+                    },
+                );
+            }
+            for ancestor in rule.from.ancestors().skip(1) {
+                if ancestor == "/" || has_children(files, ancestor) {
+                    break;
+                }
+                files.remove(ancestor);
+            }
+        }
+
+        self.dirs = files
+            .iter()
+            .filter(|(path, info)| {
+                info.file_type == FileType::Directory
+                    && self
+                        .rules
+                        .iter()
+                        .any(|rule| path.starts_with(&rule.to) || rule.to.starts_with(path))
+            })
+            .map(|(path, info)| (path.clone(), info.clone()))
+            .collect();
+        Ok(())
+    }
+
+    /// Returns the metadata of the directory at `path`, if it's at or above
+    /// the target of a rule, where the rootfs doesn't have it.
+    pub fn info(&self, path: &Utf8Path) -> Option<&FileInfo> {
+        self.dirs.get(path)
+    }
+
+    /// Returns the rewritten symlink target `target`, if it's absolute and a
+    /// rule matches it.
+    pub fn rewrite_target(&self, target: &Path) -> Option<PathBuf> {
+        let target = Utf8Path::from_path(target)?;
+        if !target.is_absolute() {
+            return None;
+        }
+        self.rewrite(target).map(Utf8PathBuf::into_std_path_buf)
+    }
+}
+
+/// Whether `dir` has any entry below it in `files`.
+fn has_children(files: &FileMap, dir: &Utf8Path) -> bool {
+    files
+        .range::<Utf8Path, _>((Bound::Excluded(dir), Bound::Unbounded))
+        .next()
+        .is_some_and(|(path, _)| path.starts_with(dir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(mtime: u64) -> FileInfo {
+        FileInfo {
+            mode: libc::S_IFDIR | 0o750,
+            uid: 1000,
+            gid: 1000,
+            ..FileInfo::test_file(FileType::Directory, 0, mtime)
+        }
+    }
+
+    fn file() -> FileInfo {
+        FileInfo {
+            file_type: FileType::File,
+            mode: libc::S_IFREG | 0o644,
+            ..dir(0)
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            PathRewrite::parse("/build/stage/=/opt/app").unwrap(),
+            PathRewrite {
+                from: Utf8PathBuf::from("/build/stage"),
+                to: Utf8PathBuf::from("/opt/app"),
+            }
+        );
+        for invalid in [
+            "/build/stage",
+            "build=/opt",
+            "/build=opt",
+            "/=/opt",
+            "/build=/",
+            "/build/../etc=/opt",
+        ] {
+            assert!(PathRewrite::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut files: FileMap = [
+            ("/build", dir(1)),
+            ("/build/stage", dir(2)),
+            ("/build/stage/bin", dir(3)),
+            ("/build/stage/bin/app", file()),
+            ("/build/stageX", file()),
+            ("/usr", dir(4)),
+        ]
+        .into_iter()
+        .map(|(path, info)| (Utf8PathBuf::from(path), info))
+        .collect();
+        let mut rewrites = PathRewrites::new(vec![
+            PathRewrite::parse("/build/stage=/opt/vendor/app").unwrap(),
+        ]);
+        rewrites.apply(&mut files).unwrap();
+
+        assert_eq!(
+            files.keys().map(|p| p.as_str()).collect::<Vec<_>>(),
+            [
+                // /build isn't empty because of /build/stageX
+                "/build",
+                "/build/stageX",
+                "/opt",
+                "/opt/vendor",
+                "/opt/vendor/app",
+                "/opt/vendor/app/bin",
+                "/opt/vendor/app/bin/app",
+                "/usr",
+            ]
+        );
+        let app = &files[Utf8Path::new("/opt/vendor/app/bin/app")];
+        assert_eq!(
+            app.fs_path(Utf8Path::new("/opt/vendor/app/bin/app")),
+            Path::new("build/stage/bin/app")
+        );
+        // missing parents are added, with the mtime of the target
+        let opt = &files[Utf8Path::new("/opt")];
+        assert_eq!((opt.uid, opt.mode & 0o7777, opt.mtime), (0, 0o755, 2));
+        assert_eq!(
+            rewrites.info(Utf8Path::new("/opt/vendor")).unwrap().mtime,
+            2
+        );
+        assert_eq!(
+            rewrites
+                .info(Utf8Path::new("/opt/vendor/app"))
+                .unwrap()
+                .mtime,
+            2
+        );
+        assert!(rewrites.info(Utf8Path::new("/usr")).is_none());
+
+        assert_eq!(
+            rewrites.rewrite_target(Path::new("/build/stage/bin/app")),
+            Some(PathBuf::from("/opt/vendor/app/bin/app"))
+        );
+        assert_eq!(rewrites.rewrite_target(Path::new("bin/app")), None);
+        assert_eq!(rewrites.rewrite_target(Path::new("/build/stageX")), None);
+    }
+
+    #[test]
+    fn test_apply_drops_empty_sources() {
+        let mut files: FileMap = [("/build", dir(1)), ("/build/stage", dir(2))]
+            .into_iter()
+            .map(|(path, info)| (Utf8PathBuf::from(path), info))
+            .collect();
+        PathRewrites::new(vec![PathRewrite::parse("/build/stage=/app").unwrap()])
+            .apply(&mut files)
+            .unwrap();
+        assert_eq!(
+            files.keys().map(|p| p.as_str()).collect::<Vec<_>>(),
+            ["/app"]
+        );
+    }
+
+    #[test]
+    fn test_apply_errors() {
+        let files: FileMap = [("/build", dir(1)), ("/opt", dir(2))]
+            .into_iter()
+            .map(|(path, info)| (Utf8PathBuf::from(path), info))
+            .collect();
+        let apply = |rule: &str| {
+            PathRewrites::new(vec![PathRewrite::parse(rule).unwrap()]).apply(&mut files.clone())
+        };
+        assert!(apply("/missing=/opt/app").is_err());
+        assert!(apply("/build=/opt").is_err());
+        assert!(
+            PathRewrites::new(vec![
+                PathRewrite::parse("/build=/app").unwrap(),
+                PathRewrite::parse("/build/stage=/opt/app").unwrap(),
+            ])
+            .apply(&mut files.clone())
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,834 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::ambient_authority;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+
+use crate::components::{FileInfo, FileMap, FileType, XattrInterner};
+use crate::overlay::{entry_info, entry_path, symlink_target, tar_entries};
+
+/// Magic number at the start of a squashfs image.
+const SQUASHFS_MAGIC: [u8; 4] = *b"hsqs";
+
+/// Offset of the erofs superblock.
+const EROFS_SUPER_OFFSET: u64 = 1024;
+
+/// Magic number at the start of the erofs superblock.
+const EROFS_MAGIC: [u8; 4] = 0xE0F5_E1E2u32.to_le_bytes();
+
+/// Offset of the magic number of POSIX and GNU tar headers.
+const TAR_MAGIC_OFFSET: u64 = 257;
+
+/// Magic number of POSIX and GNU tar headers, without the version which
+/// differs between them.
+const TAR_MAGIC: [u8; 5] = *b"ustar";
+
+/// Magic numbers at the start of the compressed tarballs we can read.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const XZ_MAGIC: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const BZIP2_MAGIC: [u8; 3] = *b"BZh";
+
+/// The `--rootfs-tar` value to read the tarball from stdin.
+pub(crate) const STDIN_PATH: &str = "-";
+
+/// Format of a filesystem image given as the rootfs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ImageFormat {
+    Squashfs,
+    Erofs,
+}
+
+impl std::fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Squashfs => write!(f, "squashfs"),
+            Self::Erofs => write!(f, "erofs"),
+        }
+    }
+}
+
+impl ImageFormat {
+    /// Detect the format of the filesystem image at `path` from its magic
+    /// number.
+    ///
+    /// Returns `Ok(None)` if it's not a filesystem image we know of.
+    pub(crate) fn detect(path: &Utf8Path) -> Result<Option<Self>> {
+        let file = std::fs::File::open(path).with_context(|| format!("opening {path}"))?;
+        if read_magic(&file, 0)? == Some(SQUASHFS_MAGIC) {
+            return Ok(Some(Self::Squashfs));
+        }
+        if read_magic(&file, EROFS_SUPER_OFFSET)? == Some(EROFS_MAGIC) {
+            return Ok(Some(Self::Erofs));
+        }
+        Ok(None)
+    }
+}
+
+/// Whether the file at `path` looks like a tarball, possibly compressed with
+/// gzip, xz or bzip2.
+pub(crate) fn is_tarball(path: &Utf8Path) -> Result<bool> {
+    let file = std::fs::File::open(path).with_context(|| format!("opening {path}"))?;
+    if read_magic(&file, TAR_MAGIC_OFFSET)? == Some(TAR_MAGIC) {
+        return Ok(true);
+    }
+    let mut head = Vec::new();
+    (&file)
+        .take(XZ_MAGIC.len() as u64)
+        .read_to_end(&mut head)
+        .with_context(|| format!("reading {path}"))?;
+    Ok(compression(&head).is_some())
+}
+
+/// Compression of a tarball.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    Gzip,
+    Xz,
+    Bzip2,
+}
+
+/// Detect the compression of a tarball from its first bytes.
+fn compression(head: &[u8]) -> Option<Compression> {
+    if head.starts_with(&GZIP_MAGIC) {
+        Some(Compression::Gzip)
+    } else if head.starts_with(&XZ_MAGIC) {
+        Some(Compression::Xz)
+    } else if head.starts_with(&BZIP2_MAGIC) {
+        Some(Compression::Bzip2)
+    } else {
+        None
+    }
+}
+
+/// Read the `N` bytes at `offset`, or `None` if the file is too short.
+fn read_magic<const N: usize>(file: &std::fs::File, offset: u64) -> Result<Option<[u8; N]>> {
+    let mut magic = [0u8; N];
+    match file.read_exact_at(&mut magic, offset) {
+        Ok(()) => Ok(Some(magic)),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e).context("reading magic number"),
+    }
+}
+
+/// A tarball or filesystem image unpacked in a temporary directory, which is
+/// deleted on drop.
+pub(crate) struct UnpackedImage {
+    _tmpdir: tempfile::TempDir,
+    path: Utf8PathBuf,
+    /// The metadata of the entries of the image, which its extracted files
+    /// don't carry.
+    metadata: Option<Arc<FileMap>>,
+}
+
+impl UnpackedImage {
+    /// Path to the unpacked rootfs.
+    pub(crate) fn path(&self) -> &Utf8Path {
+        &self.path
+    }
+
+    /// The metadata of the entries of the image, including the parent
+    /// directories a tarball doesn't have entries for.
+    pub(crate) fn metadata(&self) -> Option<&Arc<FileMap>> {
+        self.metadata.as_ref()
+    }
+
+    /// Replace the metadata of the scanned `files` with that of the entries of
+    /// the image, keeping the inodes of the extracted files so that hardlinks
+    /// are still found, and drop its special files if `skip_special_files`.
+    pub(crate) fn restore_metadata(
+        &self,
+        files: &mut FileMap,
+        skip_special_files: bool,
+    ) -> Result<()> {
+        let Some(metadata) = &self.metadata else {
+            return Ok(());
+        };
+        if skip_special_files {
+            files.retain(|path, _| {
+                metadata
+                    .get(path)
+                    .is_none_or(|info| !info.file_type.is_special())
+            });
+        }
+        for (path, info) in files.iter_mut() {
+            let entry = metadata
+                .get(path)
+                .with_context(|| format!("{path} has no entry in the image"))?;
+            // special files are extracted as empty files, whose digest would
+            // be meaningless
+            let sha256 = match entry.file_type {
+                FileType::File => info.sha256().copied(),
+                _ => None,
+            };
+            let disk_path = info.disk_path().map(Box::from);
+            *info = FileInfo {
+                ino: info.ino,
+                nlink: info.nlink,
+                ..entry.clone()
+            };
+            info.set_sha256(sha256);
+            info.set_disk_path(disk_path);
+        }
+        Ok(())
+    }
+}
+
+/// Create a temporary directory in `workdir`, or in the system temporary
+/// directory if `None`, and return it with the path of the rootfs in it.
+fn rootfs_tempdir(workdir: Option<&Utf8Path>) -> Result<(tempfile::TempDir, Utf8PathBuf)> {
+    let mut builder = tempfile::Builder::new();
+    builder.prefix("chunkah-rootfs-");
+    let tmpdir = match workdir {
+        Some(dir) => builder.tempdir_in(dir),
+        None => builder.tempdir(),
+    }
+    .context("creating temporary directory")?;
+    let tmp_path =
+        Utf8Path::from_path(tmpdir.path()).context("temporary directory path is not UTF-8")?;
+    let path = tmp_path.join("rootfs");
+    Ok((tmpdir, path))
+}
+
+/// Extract the tarball at `image`, or on stdin if `image` is [`STDIN_PATH`],
+/// in a temporary directory in `workdir`, or in the system temporary directory
+/// if `None`.
+///
+/// Only the content of the entries is extracted, so that the repos can read
+/// their databases; their metadata is read from the headers in the same pass
+/// (see [`UnpackedImage::restore_metadata`]). So ownership, device nodes and
+/// xattrs such as `security.capability` are kept without running as root, and
+/// special files are extracted as empty files.
+pub(crate) fn unpack_tar(image: &Utf8Path, workdir: Option<&Utf8Path>) -> Result<UnpackedImage> {
+    let reader: Box<dyn Read> = if image == STDIN_PATH {
+        Box::new(std::io::stdin().lock())
+    } else {
+        Box::new(std::fs::File::open(image).with_context(|| format!("opening {image}"))?)
+    };
+    let (tmpdir, path) = rootfs_tempdir(workdir)?;
+    std::fs::create_dir(&path).with_context(|| format!("creating {path}"))?;
+    let dir = Dir::open_ambient_dir(path.as_std_path(), ambient_authority())
+        .with_context(|| format!("opening {path}"))?;
+
+    let mut metadata = FileMap::new();
+    let mut xattrs = XattrInterner::default();
+    let mut archive = tar::Archive::new(decompress(reader)?);
+    for entry in tar_entries(&mut archive)? {
+        let mut entry = entry?;
+        let path = entry_path(entry.path())?;
+        let info = if entry.header().entry_type() == tar::EntryType::Link {
+            let target = entry
+                .link_name()
+                .with_context(|| format!("hardlink {path} has no target"))?;
+            let target = entry_path(target)?;
+            let info = metadata
+                .get(&target)
+                .with_context(|| format!("hardlink {path} target {target} not found"))?
+                .clone();
+            anyhow::ensure!(
+                info.file_type != FileType::Directory,
+                "hardlink {path} to a directory"
+            );
+            let rel_path = prepare_entry(&dir, &path, FileType::File, &mut metadata)?;
+            dir.hard_link(strip_root(&target), &dir, rel_path)
+                .with_context(|| format!("linking {path} to {target}"))?;
+            info
+        } else {
+            let info = entry_info(&entry, &path, &mut xattrs)?;
+            let target = (info.file_type == FileType::Symlink)
+                .then(|| symlink_target(&entry, &path))
+                .transpose()?;
+            let rel_path = prepare_entry(&dir, &path, info.file_type, &mut metadata)?;
+            extract_entry(&dir, rel_path, &path, &info, target.as_deref(), &mut entry)?;
+            info
+        };
+        metadata.insert(path, info);
+    }
+
+    add_parent_dirs(&mut metadata);
+    Ok(UnpackedImage {
+        _tmpdir: tmpdir,
+        path,
+        metadata: Some(Arc::new(metadata)),
+    })
+}
+
+/// An entry of a filesystem image, as read by [`crate::squashfs`] and
+/// [`crate::erofs`].
+pub(crate) struct ImageEntry {
+    pub(crate) path: Utf8PathBuf,
+    pub(crate) info: FileInfo,
+    /// The number of its inode in the image, which hardlinks share.
+    pub(crate) inode: u64,
+    pub(crate) symlink_target: Option<Utf8PathBuf>,
+}
+
+/// Called by the readers of filesystem images for each of their entries,
+/// parents first, with the content of regular files.
+pub(crate) type VisitEntry<'a> = dyn FnMut(ImageEntry, &mut dyn Read) -> Result<()> + 'a;
+
+/// Extract the squashfs or erofs image at `image` in a temporary directory in
+/// `workdir`, or in the system temporary directory if `None`.
+///
+/// The image is read in-process, so no privileges are needed to mount it. Like
+/// for tarballs (see [`unpack_tar`]), only the content of the entries is
+/// extracted and their metadata is read from the image in the same pass.
+pub(crate) fn unpack_image(
+    image: &Utf8Path,
+    format: ImageFormat,
+    workdir: Option<&Utf8Path>,
+) -> Result<UnpackedImage> {
+    let file = std::fs::File::open(image).with_context(|| format!("opening {image}"))?;
+    let (tmpdir, path) = rootfs_tempdir(workdir)?;
+    std::fs::create_dir(&path).with_context(|| format!("creating {path}"))?;
+    let dir = Dir::open_ambient_dir(path.as_std_path(), ambient_authority())
+        .with_context(|| format!("opening {path}"))?;
+
+    let mut metadata = FileMap::new();
+    let mut xattrs = XattrInterner::default();
+    // the first path of each inode, which later ones are hardlinked to
+    let mut inodes: HashMap<u64, Utf8PathBuf> = HashMap::new();
+    let mut visit = |entry: ImageEntry, content: &mut dyn Read| -> Result<()> {
+        let ImageEntry {
+            path,
+            info,
+            inode,
+            symlink_target,
+        } = entry;
+        let rel_path = prepare_entry(&dir, &path, info.file_type, &mut metadata)?;
+        if let Some(target) = inodes.get(&inode) {
+            dir.hard_link(strip_root(target), &dir, rel_path)
+                .with_context(|| format!("linking {path} to {target}"))?;
+        } else {
+            extract_entry(
+                &dir,
+                rel_path,
+                &path,
+                &info,
+                symlink_target.as_deref(),
+                content,
+            )?;
+            inodes.insert(inode, path.clone());
+        }
+        metadata.insert(path, info);
+        Ok(())
+    };
+    match format {
+        ImageFormat::Squashfs => crate::squashfs::read_image(&file, &mut xattrs, &mut visit)?,
+        ImageFormat::Erofs => crate::erofs::read_image(&file, &mut xattrs, &mut visit)?,
+    }
+
+    Ok(UnpackedImage {
+        _tmpdir: tmpdir,
+        path,
+        metadata: Some(Arc::new(metadata)),
+    })
+}
+
+/// Returns the name of an entry of the directory at `parent` in a filesystem
+/// image, checking that it's a single path component.
+pub(crate) fn entry_name<'a>(parent: &Utf8Path, name: &'a [u8]) -> Result<&'a str> {
+    let name = std::str::from_utf8(name)
+        .with_context(|| format!("{parent}: entry name {} is not UTF-8", name.escape_ascii()))?;
+    anyhow::ensure!(
+        !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\0']),
+        "{parent}: invalid entry name {name:?}"
+    );
+    Ok(name)
+}
+
+/// Little-endian fields of the on-disk structures of a filesystem image, read
+/// in order.
+pub(crate) struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self(data)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        anyhow::ensure!(len <= self.0.len(), "truncated metadata");
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16> {
+        self.array().map(u16::from_le_bytes)
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64> {
+        self.array().map(u64::from_le_bytes)
+    }
+}
+
+/// Returns the device number encoded in a 32-bit field of a filesystem image,
+/// like the kernel's `new_decode_dev()`.
+pub(crate) fn decode_dev(dev: u32) -> u64 {
+    let major = (dev & 0xfff00) >> 8;
+    let minor = (dev & 0xff) | ((dev >> 12) & 0xfff00);
+    libc::makedev(major, minor)
+}
+
+/// Returns `path` relative to the root.
+fn strip_root(path: &Utf8Path) -> &Utf8Path {
+    path.strip_prefix("/").unwrap_or(path)
+}
+
+/// Make room in `dir` for the entry of type `file_type` at `path` and return
+/// its path relative to `dir`. Like when extracting with `tar`, later entries
+/// replace earlier ones, except that directories are merged.
+fn prepare_entry<'a>(
+    dir: &Dir,
+    path: &'a Utf8Path,
+    file_type: FileType,
+    metadata: &mut FileMap,
+) -> Result<&'a Utf8Path> {
+    let rel_path = strip_root(path);
+    if rel_path.as_str().is_empty() {
+        anyhow::ensure!(
+            file_type == FileType::Directory,
+            "the root of the image isn't a directory"
+        );
+        return Ok(rel_path);
+    }
+    if let Some(parent) = rel_path.parent()
+        && !parent.as_str().is_empty()
+    {
+        dir.create_dir_all(parent)
+            .with_context(|| format!("creating parent directories of {path}"))?;
+    }
+    let existing = dir
+        .symlink_metadata_optional(rel_path)
+        .with_context(|| format!("getting metadata for {path}"))?;
+    match existing {
+        Some(existing) if existing.is_dir() && file_type == FileType::Directory => {}
+        Some(existing) if existing.is_dir() => {
+            dir.remove_dir_all(rel_path)
+                .with_context(|| format!("removing {path}"))?;
+            metadata.retain(|p, _| !p.starts_with(path));
+        }
+        Some(_) => dir
+            .remove_file(rel_path)
+            .with_context(|| format!("removing {path}"))?,
+        None => {}
+    }
+    Ok(rel_path)
+}
+
+/// Extract the entry at `path`, described by `info`, with `content` if it's a
+/// regular file or `symlink_target` if it's a symlink, to `rel_path` in `dir`.
+fn extract_entry(
+    dir: &Dir,
+    rel_path: &Utf8Path,
+    path: &Utf8Path,
+    info: &FileInfo,
+    symlink_target: Option<&Utf8Path>,
+    content: &mut dyn Read,
+) -> Result<()> {
+    match info.file_type {
+        FileType::Directory => {
+            if !rel_path.as_str().is_empty() && !dir.try_exists(rel_path)? {
+                dir.create_dir(rel_path)
+                    .with_context(|| format!("creating {path}"))?;
+            }
+        }
+        FileType::File => {
+            let mut file = dir
+                .create(rel_path)
+                .with_context(|| format!("creating {path}"))?;
+            std::io::copy(content, &mut file).with_context(|| format!("extracting {path}"))?;
+        }
+        FileType::Symlink => {
+            let target = symlink_target.with_context(|| format!("symlink {path} has no target"))?;
+            dir.symlink_contents(target, rel_path)
+                .with_context(|| format!("creating symlink {path}"))?;
+        }
+        // creating these needs privileges; the headers are enough to write them
+        FileType::BlockDevice | FileType::CharDevice | FileType::Fifo => {
+            dir.create(rel_path)
+                .with_context(|| format!("creating {path}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Add the parent directories of the entries of `metadata` which have no entry
+/// of their own, including the root, root-owned with the newest mtime of their
+/// children.
+fn add_parent_dirs(metadata: &mut FileMap) {
+    let mut missing: BTreeMap<Utf8PathBuf, u64> = BTreeMap::new();
+    for (path, info) in metadata.iter() {
+        for ancestor in path.ancestors().skip(1) {
+            if metadata.contains_key(ancestor) {
+                break;
+            }
+            let mtime = missing.entry(ancestor.to_path_buf()).or_insert(info.mtime);
+            *mtime = (*mtime).max(info.mtime);
+        }
+    }
+    for (path, mtime) in missing {
+        let info = FileInfo {
+            file_type: FileType::Directory,
+            mode: libc::S_IFDIR | 0o755,
+            size: 0,
+            uid: 0,
+            gid: 0,
+            mtime,
+            ino: 0,
+            nlink: 1,
+            xattrs: Default::default(),
+            extra: None,
+        };
+        metadata.insert(path, info);
+    }
+}
+
+/// Returns `reader` decompressed according to the magic number of its
+/// content. It doesn't need to be seekable, so that it can be a pipe.
+fn decompress(mut reader: Box<dyn Read>) -> Result<Box<dyn Read>> {
+    let mut head = Vec::new();
+    reader
+        .by_ref()
+        .take(XZ_MAGIC.len() as u64)
+        .read_to_end(&mut head)
+        .context("reading tarball")?;
+    let compression = compression(&head);
+    let reader = std::io::Cursor::new(head).chain(reader);
+    Ok(match compression {
+        Some(Compression::Gzip) => Box::new(flate2::read::GzDecoder::new(reader)),
+        Some(Compression::Xz) => Box::new(xz2::read::XzDecoder::new(reader)),
+        Some(Compression::Bzip2) => Box::new(bzip2::read::BzDecoder::new(reader)),
+        None => Box::new(reader),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(tmp.path()).unwrap();
+
+        let squashfs = dir.join("rootfs.squashfs");
+        std::fs::write(&squashfs, b"hsqs\x10\x00\x00\x00").unwrap();
+        assert_eq!(
+            ImageFormat::detect(&squashfs).unwrap(),
+            Some(ImageFormat::Squashfs)
+        );
+        assert!(!is_tarball(&squashfs).unwrap());
+
+        let erofs = dir.join("rootfs.erofs");
+        let mut content = vec![0u8; 2048];
+        content[1024..1028].copy_from_slice(&[0xe2, 0xe1, 0xf5, 0xe0]);
+        std::fs::write(&erofs, content).unwrap();
+        assert_eq!(
+            ImageFormat::detect(&erofs).unwrap(),
+            Some(ImageFormat::Erofs)
+        );
+
+        // tarballs are passed with --rootfs-tar instead
+        let tar = dir.join("rootfs.tar");
+        let mut builder = tar::Builder::new(std::fs::File::create(&tar).unwrap());
+        let mut header = tar::Header::new_ustar();
+        header.set_size(0);
+        builder
+            .append_data(&mut header, "etc/hostname", std::io::empty())
+            .unwrap();
+        builder.finish().unwrap();
+        assert_eq!(ImageFormat::detect(&tar).unwrap(), None);
+        assert!(is_tarball(&tar).unwrap());
+
+        let gz = dir.join("rootfs.tar.gz");
+        std::fs::write(&gz, b"\x1f\x8b\x08\x00").unwrap();
+        assert!(is_tarball(&gz).unwrap());
+
+        // too short for any magic number
+        let other = dir.join("rootfs.img");
+        std::fs::write(&other, b"hs").unwrap();
+        assert_eq!(ImageFormat::detect(&other).unwrap(), None);
+        assert!(!is_tarball(&other).unwrap());
+    }
+
+    fn header(kind: tar::EntryType, mode: u32, uid: u64) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_mode(mode);
+        header.set_uid(uid);
+        header.set_gid(uid);
+        header.set_mtime(1000);
+        header.set_size(0);
+        header
+    }
+
+    #[test]
+    fn test_unpack_tar() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(tmp.path()).unwrap();
+        let tar = dir.join("rootfs.tar.gz");
+        let encoder = flate2::write::GzEncoder::new(
+            std::fs::File::create(&tar).unwrap(),
+            flate2::Compression::fast(),
+        );
+        let mut builder = tar::Builder::new(encoder);
+        let mut root = header(tar::EntryType::Directory, 0o555, 0);
+        builder
+            .append_data(&mut root, "./", std::io::empty())
+            .unwrap();
+        // owned by someone else than us, with file capabilities
+        builder
+            .append_pax_extensions([("SCHILY.xattr.security.capability", b"\x01\x02".as_slice())])
+            .unwrap();
+        let mut ping = header(tar::EntryType::Regular, 0o4755, 1000);
+        ping.set_size(5);
+        builder
+            .append_data(&mut ping, "usr/bin/ping", "ping\n".as_bytes())
+            .unwrap();
+        let mut link = header(tar::EntryType::Link, 0, 0);
+        builder
+            .append_link(&mut link, "usr/bin/ping6", "usr/bin/ping")
+            .unwrap();
+        let mut null = header(tar::EntryType::Char, 0o666, 0);
+        null.set_device_major(1).unwrap();
+        null.set_device_minor(3).unwrap();
+        builder
+            .append_data(&mut null, "dev/null", std::io::empty())
+            .unwrap();
+        let mut symlink = header(tar::EntryType::Symlink, 0o777, 0);
+        builder
+            .append_link(&mut symlink, "etc/localtime", "/usr/share/zoneinfo/UTC")
+            .unwrap();
+        // replaced by the next entry
+        let mut hostname = header(tar::EntryType::Regular, 0o644, 0);
+        hostname.set_size(4);
+        builder
+            .append_data(&mut hostname, "etc/hostname", "old\n".as_bytes())
+            .unwrap();
+        let mut hostname = header(tar::EntryType::Regular, 0o600, 0);
+        hostname.set_size(5);
+        builder
+            .append_data(&mut hostname, "etc/hostname", "host\n".as_bytes())
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let unpacked = unpack_tar(&tar, Some(dir)).unwrap();
+        let rootfs = unpacked.path();
+        assert_eq!(
+            std::fs::read_to_string(rootfs.join("etc/hostname")).unwrap(),
+            "host\n"
+        );
+        assert_eq!(
+            std::fs::read_link(rootfs.join("etc/localtime")).unwrap(),
+            std::path::Path::new("/usr/share/zoneinfo/UTC")
+        );
+
+        let rootfs_dir = Dir::open_ambient_dir(rootfs.as_std_path(), ambient_authority()).unwrap();
+        let mut files = crate::scan::Scanner::new(&rootfs_dir)
+            .hash_contents(true)
+            .scan()
+            .unwrap();
+        unpacked.restore_metadata(&mut files, false).unwrap();
+
+        let ping = &files[Utf8Path::new("/usr/bin/ping")];
+        assert_eq!(
+            (ping.mode, ping.uid, ping.gid),
+            (libc::S_IFREG | 0o4755, 1000, 1000)
+        );
+        assert_eq!(
+            *ping.xattrs,
+            [("security.capability".to_string(), b"\x01\x02".to_vec())]
+        );
+        assert!(ping.sha256().is_some());
+        // still a hardlink on disk
+        let ping6 = &files[Utf8Path::new("/usr/bin/ping6")];
+        assert_eq!((ping6.ino, ping6.nlink, ping6.uid), (ping.ino, 2, 1000));
+        let null = &files[Utf8Path::new("/dev/null")];
+        assert_eq!(null.file_type, FileType::CharDevice);
+        assert_eq!(null.rdev(), libc::makedev(1, 3));
+        assert_eq!(null.sha256(), None);
+        let hostname = &files[Utf8Path::new("/etc/hostname")];
+        assert_eq!((hostname.mode, hostname.size), (libc::S_IFREG | 0o600, 5));
+        // parent directories without entries
+        let usr = &files[Utf8Path::new("/usr")];
+        assert_eq!(
+            (usr.mode, usr.uid, usr.mtime),
+            (libc::S_IFDIR | 0o755, 0, 1000)
+        );
+
+        let metadata = unpacked.metadata().unwrap();
+        assert_eq!(metadata[Utf8Path::new("/")].mode, libc::S_IFDIR | 0o555);
+
+        unpacked.restore_metadata(&mut files, true).unwrap();
+        assert!(!files.contains_key(Utf8Path::new("/dev/null")));
+        assert!(files.contains_key(Utf8Path::new("/dev")));
+    }
+
+    /// The content of `/usr/bin/ping` in the image fixtures.
+    fn ping() -> Vec<u8> {
+        let mut state = 1u32;
+        (0..10_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                b"abcdefgh"[(state >> 16) as usize % 8]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_unpack_image() {
+        let fixtures = Utf8Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        for name in ["rootfs-gzip.squashfs", "rootfs-xz.squashfs", "rootfs.erofs"] {
+            let image = fixtures.join(name);
+            let format = ImageFormat::detect(&image).unwrap().unwrap();
+            let unpacked = unpack_image(&image, format, None).unwrap();
+            let rootfs = unpacked.path();
+            assert_eq!(
+                std::fs::read_to_string(rootfs.join("etc/hostname")).unwrap(),
+                "chunkah\n",
+                "{name}"
+            );
+            assert_eq!(
+                std::fs::read_link(rootfs.join("etc/localtime")).unwrap(),
+                std::path::Path::new("/usr/share/zoneinfo/UTC"),
+                "{name}"
+            );
+            // spans several blocks, the last one in a fragment for squashfs
+            // and inline for erofs
+            assert!(
+                std::fs::read(rootfs.join("usr/bin/ping")).unwrap() == ping(),
+                "{name}"
+            );
+
+            let rootfs_dir =
+                Dir::open_ambient_dir(rootfs.as_std_path(), ambient_authority()).unwrap();
+            let mut files = crate::scan::Scanner::new(&rootfs_dir).scan().unwrap();
+            unpacked.restore_metadata(&mut files, false).unwrap();
+            let stat = |path: &str| {
+                let info = &files[Utf8Path::new(path)];
+                (info.file_type, info.mode, info.uid, info.gid, info.mtime)
+            };
+            assert_eq!(
+                stat("/usr/bin/ping"),
+                (FileType::File, libc::S_IFREG | 0o4755, 0, 0, 1_700_000_003),
+                "{name}"
+            );
+            assert_eq!(
+                stat("/home/user"),
+                (
+                    FileType::Directory,
+                    libc::S_IFDIR | 0o700,
+                    1000,
+                    1000,
+                    1_700_000_002
+                ),
+                "{name}"
+            );
+            assert_eq!(
+                stat("/run/initctl"),
+                (FileType::Fifo, libc::S_IFIFO | 0o600, 0, 0, 1_700_000_000),
+                "{name}"
+            );
+            let null = &files[Utf8Path::new("/dev/null")];
+            assert_eq!(null.file_type, FileType::CharDevice, "{name}");
+            assert_eq!(null.rdev(), libc::makedev(1, 3), "{name}");
+            // the major and minor don't fit in 8 bits
+            let nvme = &files[Utf8Path::new("/dev/nvme")];
+            assert_eq!(nvme.file_type, FileType::BlockDevice, "{name}");
+            assert_eq!(nvme.rdev(), libc::makedev(259, 300), "{name}");
+            assert_eq!(nvme.gid, 6, "{name}");
+        }
+    }
+
+    #[test]
+    fn test_unpack_erofs() {
+        let image = Utf8Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/rootfs.erofs");
+        let unpacked = unpack_image(&image, ImageFormat::Erofs, None).unwrap();
+        let rootfs = unpacked.path();
+        // stored as a chunk with no block, as composefs does for the files
+        // whose content is elsewhere
+        assert!(std::fs::read(rootfs.join("usr/bin/sparse")).unwrap() == [0; 10_000]);
+
+        let rootfs_dir = Dir::open_ambient_dir(rootfs.as_std_path(), ambient_authority()).unwrap();
+        let mut files = crate::scan::Scanner::new(&rootfs_dir).scan().unwrap();
+        unpacked.restore_metadata(&mut files, false).unwrap();
+        // shared with other files, and inline
+        let ping = &files[Utf8Path::new("/usr/bin/ping")];
+        assert_eq!(
+            *ping.xattrs,
+            [
+                (
+                    "security.selinux".to_string(),
+                    b"system_u:object_r:bin_t:s0\0".to_vec()
+                ),
+                (
+                    "security.capability".to_string(),
+                    b"\x01\x00\x00\x02\n\x00".to_vec()
+                ),
+            ]
+        );
+        let ping6 = &files[Utf8Path::new("/usr/bin/ping6")];
+        assert_eq!((ping6.ino, ping6.nlink), (ping.ino, 2));
+        let bashrc = &files[Utf8Path::new("/home/user/.bashrc")];
+        assert_eq!(
+            *bashrc.xattrs,
+            [("user.comment".to_string(), b"a\nb".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_unpack_image_unsupported() {
+        let fixtures = Utf8Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(tmp.path()).unwrap();
+        let unpack_err = |image: &Utf8Path, format| match unpack_image(image, format, Some(dir)) {
+            Ok(_) => panic!("{image} unpacked"),
+            Err(err) => format!("{err:#}"),
+        };
+
+        let mut squashfs = std::fs::read(fixtures.join("rootfs-gzip.squashfs")).unwrap();
+        // the compression id
+        squashfs[20..22].copy_from_slice(&6u16.to_le_bytes());
+        let image = dir.join("rootfs.squashfs");
+        std::fs::write(&image, &squashfs).unwrap();
+        let err = unpack_err(&image, ImageFormat::Squashfs);
+        assert!(
+            err.contains("compressed with zstd aren't supported"),
+            "{err}"
+        );
+
+        let mut erofs = std::fs::read(fixtures.join("rootfs.erofs")).unwrap();
+        // the incompatible features
+        erofs[1104..1108].copy_from_slice(&0x100u32.to_le_bytes());
+        let image = dir.join("rootfs.erofs");
+        std::fs::write(&image, &erofs).unwrap();
+        let err = unpack_err(&image, ImageFormat::Erofs);
+        assert!(err.contains("unsupported features 0x100"), "{err}");
+
+        std::fs::write(&image, &erofs[..2048]).unwrap();
+        unpack_err(&image, ImageFormat::Erofs);
+    }
+}
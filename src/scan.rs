@@ -1,19 +1,47 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
 use std::ops::ControlFlow;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
 
 use anyhow::{Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
-use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::cap_std::fs::{Dir, MetadataExt};
 use cap_std_ext::dirext::{CapStdExtDirExt, WalkConfiguration};
+use openssl::hash::{Hasher, MessageDigest};
 
-use crate::components::{FileInfo, FileMap, FileType};
+use crate::components::{FileInfo, FileMap, FileType, XattrInterner};
+use crate::diagnostics::{self, Diagnostic};
+use crate::ignore::IgnoreRules;
+use crate::progress::Progress;
+use crate::utils::GLOB_MATCH_OPTIONS;
+
+/// What to do with the paths of the rootfs which aren't valid UTF-8.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum NonUtf8Paths {
+    /// Fail the scan.
+    #[default]
+    Error,
+    /// Leave them out, along with their contents if they're directories.
+    Skip,
+    /// Keep them, with invalid sequences replaced by U+FFFD in the layers.
+    Lossy,
+}
 
 /// Builder for scanning a rootfs directory.
 pub struct Scanner<'a> {
     rootfs: &'a Dir,
     skip_special_files: bool,
     prune_paths: Vec<PrunePath>,
+    exclude_patterns: Vec<glob::Pattern>,
+    ignore: Option<&'a IgnoreRules>,
+    progress: bool,
+    hash_contents: bool,
+    threads: usize,
+    non_utf8_paths: NonUtf8Paths,
+    /// The device of the rootfs, if the scan stays on it.
+    root_dev: Option<u64>,
+    ignore_errors: bool,
 }
 
 impl<'a> Scanner<'a> {
@@ -23,13 +51,22 @@ impl<'a> Scanner<'a> {
             rootfs,
             skip_special_files: false,
             prune_paths: Vec::new(),
+            exclude_patterns: Vec::new(),
+            ignore: None,
+            progress: false,
+            hash_contents: false,
+            threads: 1,
+            non_utf8_paths: NonUtf8Paths::default(),
+            root_dev: None,
+            ignore_errors: false,
         }
     }
 
     /// Skip special file types (sockets, FIFOs, block/char devices).
     ///
-    /// By default, encountering special files causes an error.
-    /// With this enabled, they are silently skipped instead.
+    /// By default, FIFOs and devices are captured along with their device
+    /// numbers, and sockets, which tar can't represent, cause an error.
+    /// With this enabled, they are all silently skipped instead.
     pub fn skip_special_files(mut self, skip: bool) -> Self {
         self.skip_special_files = skip;
         self
@@ -47,81 +84,577 @@ impl<'a> Scanner<'a> {
         Ok(self)
     }
 
+    /// Set glob patterns of paths to exclude from the scan, e.g. `**/*.pyc`.
+    ///
+    /// Patterns match paths relative to the rootfs, so a leading `/` doesn't
+    /// matter. Excluded directories are left out along with their contents.
+    pub fn exclude(mut self, patterns: &[String]) -> Result<Self> {
+        self.exclude_patterns = patterns
+            .iter()
+            .map(|p| parse_exclude_pattern(p))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(self)
+    }
+
+    /// Skip paths matched by the patterns of an ignore file.
+    ///
+    /// Ignored directories are kept if some of their contents are re-included,
+    /// since those need them.
+    pub fn ignore(mut self, rules: &'a IgnoreRules) -> Self {
+        self.ignore = Some(rules);
+        self
+    }
+
+    /// Print the number of files scanned so far to stderr every few seconds.
+    pub fn progress(mut self, enabled: bool) -> Self {
+        self.progress = enabled;
+        self
+    }
+
+    /// Compute the SHA-256 of regular files while scanning, into
+    /// [`FileInfo::sha256`]. This reads every file, but only once.
+    pub fn hash_contents(mut self, enabled: bool) -> Self {
+        self.hash_contents = enabled;
+        self
+    }
+
+    /// List directories on `threads` threads. The files found are the same
+    /// as with one, which walks the rootfs in order.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Set what to do with paths which aren't valid UTF-8. A warning lists
+    /// the ones skipped or made valid.
+    pub fn non_utf8_paths(mut self, policy: NonUtf8Paths) -> Self {
+        self.non_utf8_paths = policy;
+        self
+    }
+
+    /// Don't descend into directories on other filesystems than the rootfs,
+    /// like `find -xdev`. Mount points are kept as empty directories.
+    pub fn one_file_system(mut self, enabled: bool) -> Result<Self> {
+        self.root_dev = if enabled {
+            let metadata = self
+                .rootfs
+                .dir_metadata()
+                .context("getting metadata for rootfs")?;
+            Some(metadata.dev())
+        } else {
+            None
+        };
+        Ok(self)
+    }
+
+    /// Skip the paths which can't be read, e.g. because of permissions or
+    /// because they vanished during the scan, instead of failing. A warning
+    /// lists them at the end. Directories which can't be listed are kept
+    /// empty.
+    pub fn ignore_errors(mut self, enabled: bool) -> Self {
+        self.ignore_errors = enabled;
+        self
+    }
+
     /// Scan the rootfs and return a map of file paths to their metadata.
     ///
-    /// We use cap-std-ext's walk here, which doesn't follow symlinks.
+    /// We use cap-std-ext's walk here, which doesn't follow symlinks, unless
+    /// several threads were requested or errors are ignored, since its walk
+    /// can't carry on after failing to list a directory.
     pub fn scan(self) -> Result<FileMap> {
+        let state = Mutex::new(VisitState {
+            progress: self.progress.then(|| Progress::new("scanning")),
+            inode_digests: HashMap::new(),
+            non_utf8: Vec::new(),
+            xattrs: XattrInterner::default(),
+            errors: Vec::new(),
+        });
+        let visited = if self.threads > 1 || self.ignore_errors {
+            self.walk_parallel(&state)?
+        } else {
+            self.walk(&state)?
+        };
+        // SAFETY: we never panic while holding the lock
+        let state = state.into_inner().unwrap();
+        if let Some(progress) = &state.progress {
+            progress.finish();
+        }
+        self.report_non_utf8(state.non_utf8);
+        report_errors(state.errors);
+
         let mut files = BTreeMap::new();
+        // ignored directories we still walk, in case their contents are
+        // re-included
+        let mut ignored_dirs = BTreeMap::new();
+        for (path, visited) in visited {
+            if files.contains_key(&path) || ignored_dirs.contains_key(&path) {
+                // only lossy paths can collide
+                anyhow::bail!("several paths are {path} once made valid UTF-8");
+            }
+            if visited.ignored {
+                ignored_dirs.insert(path, visited.info);
+            } else {
+                files.insert(path, visited.info);
+            }
+        }
 
-        let config = WalkConfiguration::default().path_base(Path::new("/"));
+        if !ignored_dirs.is_empty() {
+            let parents: Vec<Utf8PathBuf> = files
+                .keys()
+                .flat_map(|path| path.ancestors().skip(1))
+                .filter(|parent| ignored_dirs.contains_key(*parent))
+                .map(Utf8Path::to_owned)
+                .collect();
+            for parent in parents {
+                if let Some(info) = ignored_dirs.remove(&parent) {
+                    files.insert(parent, info);
+                }
+            }
+        }
 
+        Ok(files)
+    }
+
+    /// Visit the rootfs on the current thread.
+    fn walk(&self, state: &Mutex<VisitState>) -> Result<Vec<(Utf8PathBuf, Visited)>> {
+        let mut visited = Vec::new();
+        let config = WalkConfiguration::default().path_base(Path::new("/"));
         self.rootfs
             .walk(&config, |component| {
-                let path: &Utf8Path = component
-                    .path
-                    .try_into()
-                    .map_err(|_| anyhow::anyhow!("path is not valid UTF-8"))?;
-
-                let rel_path = path.strip_prefix("/").unwrap_or(path);
-                let fs_path = if rel_path.as_str().is_empty() {
-                    "."
-                } else {
-                    rel_path.as_str()
+                let Some(path) = self.utf8_path(component.path, state)? else {
+                    return anyhow::Ok(if component.file_type.is_dir() {
+                        ControlFlow::Break(())
+                    } else {
+                        ControlFlow::Continue(())
+                    });
                 };
-
-                let metadata = self
-                    .rootfs
-                    .symlink_metadata(fs_path)
-                    .with_context(|| format!("getting metadata for {}", path))?;
-
-                // Check file type early, before reading xattrs
-                let file_type = match FileType::from_cap_std(&metadata.file_type()) {
-                    Some(ft) => ft,
-                    None => {
-                        if self.skip_special_files {
-                            return Ok(ControlFlow::Continue(()));
-                        } else {
-                            anyhow::bail!("special file type not supported: {}", path);
-                        }
+                let descend = match self.visit(&path, component.path, state)? {
+                    Visit::Skip { dir } => !dir,
+                    Visit::Keep(entry) => {
+                        let descend = entry.descend;
+                        visited.push((path, entry));
+                        descend
                     }
                 };
+                if descend {
+                    anyhow::Ok(ControlFlow::Continue(()))
+                } else {
+                    // don't bother recursing into this directory
+                    Ok(ControlFlow::Break(()))
+                }
+            })
+            .context("failed to walk rootfs")?;
+        Ok(visited)
+    }
 
-                let prune_action = check_prune(path, &self.prune_paths);
-                if prune_action == PruneAction::SkipEntirely {
-                    if file_type == FileType::Directory {
-                        // don't bother recursing into this directory
-                        return Ok(ControlFlow::Break(()));
-                    }
-                    return Ok(ControlFlow::Continue(()));
+    /// Visit the rootfs on `threads` threads, each listing the directories
+    /// found by all of them.
+    fn walk_parallel(&self, state: &Mutex<VisitState>) -> Result<Vec<(Utf8PathBuf, Visited)>> {
+        let queue = DirQueue::new(PathBuf::from("/"));
+        let results: Vec<Result<Vec<(Utf8PathBuf, Visited)>>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..self.threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut visited = Vec::new();
+                        while let Some(dir) = queue.pop() {
+                            let result = self.visit_dir(&dir, state, &queue, &mut visited);
+                            queue.done();
+                            if let Err(e) = result {
+                                queue.stop();
+                                return Err(e);
+                            }
+                        }
+                        Ok(visited)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                // SAFETY: the threads don't panic
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        let mut visited = Vec::new();
+        for result in results {
+            visited.extend(result.context("failed to walk rootfs")?);
+        }
+        Ok(visited)
+    }
+
+    /// Visit the entries of the directory `dir`, queueing its subdirectories.
+    fn visit_dir(
+        &self,
+        dir: &Path,
+        state: &Mutex<VisitState>,
+        queue: &DirQueue,
+        visited: &mut Vec<(Utf8PathBuf, Visited)>,
+    ) -> Result<()> {
+        let entries = match self
+            .rootfs
+            .read_dir(fs_path(dir))
+            .with_context(|| format!("reading directory {}", dir.display()))
+        {
+            Ok(entries) => entries,
+            Err(e) => return self.tolerate(e, dir, state),
+        };
+        for entry in entries {
+            let entry = match entry.with_context(|| format!("reading directory {}", dir.display()))
+            {
+                Ok(entry) => entry,
+                Err(e) => return self.tolerate(e, dir, state),
+            };
+            let actual_path = dir.join(entry.file_name());
+            let Some(path) = self.utf8_path(&actual_path, state)? else {
+                continue;
+            };
+            let visit = match self.visit(&path, &actual_path, state) {
+                Ok(visit) => visit,
+                Err(e) => {
+                    self.tolerate(e, &actual_path, state)?;
+                    continue;
+                }
+            };
+            if let Visit::Keep(entry) = visit {
+                if entry.descend && entry.info.file_type == FileType::Directory {
+                    queue.push(actual_path);
                 }
+                visited.push((path, entry));
+            }
+        }
+        Ok(())
+    }
 
-                let xattrs = read_xattrs(self.rootfs, fs_path)
-                    .with_context(|| format!("reading xattrs for {}", path))?;
+    /// Returns the path `actual_path` goes by in the file map, or `None` if
+    /// it isn't valid UTF-8 and is skipped.
+    fn utf8_path(
+        &self,
+        actual_path: &Path,
+        state: &Mutex<VisitState>,
+    ) -> Result<Option<Utf8PathBuf>> {
+        if let Some(path) = Utf8Path::from_path(actual_path) {
+            return Ok(Some(path.to_owned()));
+        }
+        if self.non_utf8_paths == NonUtf8Paths::Error {
+            anyhow::bail!(
+                "path is not valid UTF-8: {} (see --non-utf8-paths)",
+                actual_path.display()
+            );
+        }
+        // the contents of directories with such names aren't worth listing too
+        if actual_path.file_name().and_then(OsStr::to_str).is_none() {
+            // SAFETY: we never panic while holding the lock
+            let mut state = state.lock().unwrap();
+            state.non_utf8.push(actual_path.to_owned());
+        }
+        Ok(match self.non_utf8_paths {
+            NonUtf8Paths::Lossy => Some(Utf8PathBuf::from(
+                actual_path.to_string_lossy().into_owned(),
+            )),
+            _ => None,
+        })
+    }
 
-                let file_info = FileInfo::from_metadata(&metadata, file_type, xattrs);
+    /// Record the error `err` on `actual_path` if errors are ignored and it
+    /// comes from the filesystem, or return it.
+    fn tolerate(
+        &self,
+        err: anyhow::Error,
+        actual_path: &Path,
+        state: &Mutex<VisitState>,
+    ) -> Result<()> {
+        if !self.ignore_errors {
+            return Err(err);
+        }
+        let Some(kind) = err
+            .root_cause()
+            .downcast_ref::<std::io::Error>()
+            .map(std::io::Error::kind)
+        else {
+            return Err(err);
+        };
+        // SAFETY: we never panic while holding the lock
+        let mut state = state.lock().unwrap();
+        state
+            .errors
+            .push((actual_path.to_owned(), kind, format!("{err:#}")));
+        Ok(())
+    }
 
-                files.insert(path.to_owned(), file_info);
+    /// Warn about the paths which aren't valid UTF-8, if any.
+    fn report_non_utf8(&self, mut paths: Vec<PathBuf>) {
+        if paths.is_empty() {
+            return;
+        }
+        paths.sort();
+        let action = match self.non_utf8_paths {
+            NonUtf8Paths::Lossy => "kept with invalid sequences replaced",
+            _ => "skipped",
+        };
+        let list: Vec<String> = paths
+            .iter()
+            .map(|path| format!("\n  {}", path.display()))
+            .collect();
+        diagnostics::report(&[Diagnostic::warning(format!(
+            "{} paths aren't valid UTF-8 and were {action}:{}",
+            paths.len(),
+            list.concat()
+        ))]);
+    }
 
-                if prune_action == PruneAction::SkipChildren && file_type == FileType::Directory {
-                    // don't bother recursing into this directory
-                    Ok(ControlFlow::Break(()))
+    /// Visit the entry at `path`, which is `actual_path` on disk.
+    fn visit(
+        &self,
+        path: &Utf8Path,
+        actual_path: &Path,
+        state: &Mutex<VisitState>,
+    ) -> Result<Visit> {
+        let fs_path = fs_path(actual_path);
+
+        let metadata = self
+            .rootfs
+            .symlink_metadata(fs_path)
+            .with_context(|| format!("getting metadata for {}", path))?;
+
+        // Check file type early, before reading xattrs
+        let file_type = match FileType::from_cap_std(&metadata.file_type()) {
+            Some(ft) if ft.is_special() && self.skip_special_files => {
+                return Ok(Visit::Skip { dir: false });
+            }
+            Some(ft) => ft,
+            None => {
+                if self.skip_special_files {
+                    return Ok(Visit::Skip { dir: false });
                 } else {
-                    Ok(ControlFlow::Continue(()))
+                    anyhow::bail!("special file type not supported: {}", path);
                 }
-            })
-            .context("failed to walk rootfs")?;
+            }
+        };
 
-        Ok(files)
+        let prune_action = check_prune(path, &self.prune_paths);
+        let dir = file_type == FileType::Directory;
+        if prune_action == PruneAction::SkipEntirely || is_excluded(path, &self.exclude_patterns) {
+            return Ok(Visit::Skip { dir });
+        }
+
+        let ignored = self.ignore.is_some_and(|rules| rules.is_ignored(path));
+        if ignored && (!dir || !self.ignore.is_some_and(IgnoreRules::has_exceptions)) {
+            return Ok(Visit::Skip { dir });
+        }
+
+        let xattrs = read_xattrs(self.rootfs, fs_path)
+            .with_context(|| format!("reading xattrs for {}", path))?;
+        // SAFETY: we never panic while holding the lock
+        let xattrs = state.lock().unwrap().xattrs.intern(xattrs);
+
+        let mut info = FileInfo::from_metadata(&metadata, file_type, xattrs);
+        if path.as_std_path() != actual_path {
+            info.set_disk_path(Some(fs_path.into()));
+        }
+        if self.hash_contents && file_type == FileType::File {
+            // inode numbers are only unique within a filesystem
+            let inode = (metadata.dev(), info.ino);
+            let known = if info.nlink > 1 {
+                // SAFETY: we never panic while holding the lock
+                state.lock().unwrap().inode_digests.get(&inode).copied()
+            } else {
+                None
+            };
+            let digest = match known {
+                Some(digest) => digest,
+                None => {
+                    let digest = hash_file(self.rootfs, fs_path)
+                        .with_context(|| format!("hashing {}", path))?;
+                    if info.nlink > 1 {
+                        // SAFETY: we never panic while holding the lock
+                        state.lock().unwrap().inode_digests.insert(inode, digest);
+                    }
+                    digest
+                }
+            };
+            info.set_sha256(Some(digest));
+        }
+
+        if !ignored {
+            // SAFETY: we never panic while holding the lock
+            if let Some(progress) = &mut state.lock().unwrap().progress {
+                progress.add(info.size);
+            }
+        }
+
+        Ok(Visit::Keep(Visited {
+            info,
+            ignored,
+            descend: !(dir
+                && (prune_action == PruneAction::SkipChildren
+                    || self.root_dev.is_some_and(|dev| metadata.dev() != dev))),
+        }))
     }
 }
 
-/// Read all xattrs for a path.
-pub fn read_xattrs(rootfs: &Dir, fs_path: &str) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
-    use std::ffi::OsStr;
+/// State shared by the threads visiting the rootfs.
+struct VisitState {
+    progress: Option<Progress>,
+    /// Digests of hardlinked files by device and inode number, which are only
+    /// read once.
+    inode_digests: HashMap<(u64, u64), [u8; 32]>,
+    /// Paths whose file name isn't valid UTF-8.
+    non_utf8: Vec<PathBuf>,
+    /// The xattrs of the files visited, shared by the files with the same.
+    xattrs: XattrInterner,
+    /// Paths skipped because of an ignored error, with its kind and message.
+    errors: Vec<(PathBuf, std::io::ErrorKind, String)>,
+}
+
+enum Visit {
+    /// Left out, along with its contents if it's a directory.
+    Skip {
+        dir: bool,
+    },
+    Keep(Visited),
+}
+
+/// An entry of the rootfs which was kept.
+struct Visited {
+    info: FileInfo,
+    /// Whether it's an ignored directory, kept in case some of its contents
+    /// are re-included.
+    ignored: bool,
+    /// Whether to visit its contents, if it's a directory.
+    descend: bool,
+}
+
+/// Warn about the paths skipped because of ignored errors, if any, with the
+/// number of errors of each kind.
+fn report_errors(mut errors: Vec<(PathBuf, std::io::ErrorKind, String)>) {
+    if errors.is_empty() {
+        return;
+    }
+    errors.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for (_, kind, _) in &errors {
+        *counts.entry(kind.to_string()).or_default() += 1;
+    }
+    let counts: Vec<String> = counts
+        .iter()
+        .map(|(kind, count)| format!("{count} {kind}"))
+        .collect();
+    let list: Vec<String> = errors
+        .iter()
+        .map(|(_, _, message)| format!("\n  {message}"))
+        .collect();
+    diagnostics::report(&[Diagnostic::warning(format!(
+        "{} paths couldn't be scanned and were skipped ({}):{}",
+        errors.len(),
+        counts.join(", "),
+        list.concat()
+    ))]);
+}
+
+/// The directories left to list by the threads of a parallel walk.
+struct DirQueue {
+    state: Mutex<DirQueueState>,
+    changed: Condvar,
+}
 
+struct DirQueueState {
+    dirs: Vec<PathBuf>,
+    /// Number of directories being listed, which may queue more.
+    active: usize,
+    stopped: bool,
+}
+
+impl DirQueue {
+    fn new(root: PathBuf) -> Self {
+        Self {
+            state: Mutex::new(DirQueueState {
+                dirs: vec![root],
+                active: 0,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Wait for a directory to list, or return `None` once they've all been
+    /// listed or the walk was stopped.
+    fn pop(&self) -> Option<PathBuf> {
+        // SAFETY: we never panic while holding the lock
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.stopped {
+                return None;
+            }
+            if let Some(dir) = state.dirs.pop() {
+                state.active += 1;
+                return Some(dir);
+            }
+            if state.active == 0 {
+                return None;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    fn push(&self, dir: PathBuf) {
+        // SAFETY: we never panic while holding the lock
+        self.state.lock().unwrap().dirs.push(dir);
+        self.changed.notify_one();
+    }
+
+    /// Mark a directory returned by [`DirQueue::pop`] as listed.
+    fn done(&self) {
+        // SAFETY: we never panic while holding the lock
+        let mut state = self.state.lock().unwrap();
+        state.active -= 1;
+        if state.active == 0 && state.dirs.is_empty() {
+            self.changed.notify_all();
+        }
+    }
+
+    fn stop(&self) {
+        // SAFETY: we never panic while holding the lock
+        self.state.lock().unwrap().stopped = true;
+        self.changed.notify_all();
+    }
+}
+
+/// Returns the path of `path` relative to the rootfs directory.
+fn fs_path(path: &Path) -> &Path {
+    let rel_path = path.strip_prefix("/").unwrap_or(path);
+    if rel_path.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        rel_path
+    }
+}
+
+/// Returns the SHA-256 of the content of a regular file.
+fn hash_file(rootfs: &Dir, fs_path: &Path) -> Result<[u8; 32]> {
+    let mut file = rootfs.open(fs_path)?;
+    let mut hasher = Hasher::new(MessageDigest::sha256())?;
+    std::io::copy(&mut file, &mut hasher)?;
+    let digest = hasher.finish()?;
+    // SAFETY: SHA-256 digests are 32 bytes
+    Ok(digest.as_ref().try_into().unwrap())
+}
+
+/// Read the metadata of the root directory of the rootfs, which scans leave
+/// out.
+pub fn root_info(rootfs: &Dir) -> Result<FileInfo> {
+    let metadata = rootfs.dir_metadata().context("getting metadata for /")?;
+    let xattrs = read_xattrs(rootfs, Path::new(".")).context("reading xattrs for /")?;
+    Ok(FileInfo::from_metadata(
+        &metadata,
+        FileType::Directory,
+        xattrs.into(),
+    ))
+}
+
+/// Read all xattrs for a path.
+pub fn read_xattrs(rootfs: &Dir, fs_path: &Path) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
     let xattr_list = rootfs
         .listxattrs(fs_path)
-        .with_context(|| format!("listing xattrs for {}", fs_path))?;
+        .with_context(|| format!("listing xattrs for {}", fs_path.display()))?;
 
     let mut xattrs = Vec::new();
     for key in xattr_list.iter() {
@@ -136,16 +669,20 @@ pub fn read_xattrs(rootfs: &Dir, fs_path: &str) -> anyhow::Result<Vec<(String, V
 
         if let Some(value) = rootfs
             .getxattr(fs_path, key)
-            .with_context(|| format!("reading xattr {} for {}", key.display(), fs_path))?
+            .with_context(|| format!("reading xattr {} for {}", key.display(), fs_path.display()))?
         {
             // Technically, keeping the key as OsStr would be more correct,
             // but we'll need UTF-8 to shove it in a PAX header anyway so might
             // as well error now. Note libarchive and GNU tar differ here.
             // libarchive does urlencoding, GNU tar just writes the key as is
             // anyway. We'll cross that bridge when/if we get to it.
-            let key_str = key
-                .to_str()
-                .with_context(|| format!("non-UTF8 xattr key {} on {}", key.display(), fs_path))?;
+            let key_str = key.to_str().with_context(|| {
+                format!(
+                    "non-UTF8 xattr key {} on {}",
+                    key.display(),
+                    fs_path.display()
+                )
+            })?;
             xattrs.push((key_str.to_string(), value));
         }
     }
@@ -155,7 +692,7 @@ pub fn read_xattrs(rootfs: &Dir, fs_path: &str) -> anyhow::Result<Vec<(String, V
 
 /// Represents a path to prune during scanning.
 #[derive(Debug, Clone, PartialEq)]
-enum PrunePath {
+pub(crate) enum PrunePath {
     /// Prune the path and all its descendants (e.g., --prune /foo)
     Exact(Utf8PathBuf),
     /// Prune only the children, keeping the directory itself (e.g., --prune /foo/)
@@ -163,7 +700,7 @@ enum PrunePath {
 }
 
 /// Parse a prune path string into a PrunePath.
-fn parse_prune_path(path: &Utf8PathBuf) -> Result<PrunePath> {
+pub(crate) fn parse_prune_path(path: &Utf8PathBuf) -> Result<PrunePath> {
     if path == "/" {
         anyhow::bail!("cannot prune root directory");
     }
@@ -182,7 +719,7 @@ fn parse_prune_path(path: &Utf8PathBuf) -> Result<PrunePath> {
 
 /// Result of checking if a path should be pruned.
 #[derive(Debug, Clone, Copy, PartialEq)]
-enum PruneAction {
+pub(crate) enum PruneAction {
     /// Keep the path and recurse into it
     Keep,
     /// Keep the path but don't recurse into it
@@ -191,8 +728,27 @@ enum PruneAction {
     SkipEntirely,
 }
 
+/// Parse an exclude pattern, relative to the rootfs.
+fn parse_exclude_pattern(pattern: &str) -> Result<glob::Pattern> {
+    let rel_pattern = pattern.trim_start_matches('/');
+    anyhow::ensure!(
+        !rel_pattern.is_empty(),
+        "cannot exclude root directory: {pattern}"
+    );
+    glob::Pattern::new(rel_pattern).with_context(|| format!("invalid exclude pattern {pattern}"))
+}
+
+/// Whether the absolute path `path` matches one of the exclude `patterns`.
+fn is_excluded(path: &Utf8Path, patterns: &[glob::Pattern]) -> bool {
+    let rel_path = path.as_str().trim_start_matches('/');
+    !rel_path.is_empty()
+        && patterns
+            .iter()
+            .any(|p| p.matches_with(rel_path, GLOB_MATCH_OPTIONS))
+}
+
 /// Check if a path should be pruned.
-fn check_prune(path: &Utf8Path, prune_paths: &[PrunePath]) -> PruneAction {
+pub(crate) fn check_prune(path: &Utf8Path, prune_paths: &[PrunePath]) -> PruneAction {
     for prune in prune_paths {
         match prune {
             PrunePath::Exact(prune_path) => {
@@ -249,6 +805,61 @@ mod tests {
         assert_eq!(get_file_type(&files, "/escape"), Some(FileType::Symlink));
     }
 
+    #[test]
+    fn test_scanner_hash_contents() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir("dir").unwrap();
+        rootfs.write("dir/hello", "hello").unwrap();
+        std::fs::hard_link(tmp.path().join("dir/hello"), tmp.path().join("link")).unwrap();
+        rootfs.symlink("dir/hello", "symlink").unwrap();
+
+        let sha256 = |files: &FileMap, path: &str| {
+            files
+                .get(Utf8Path::new(path))
+                .unwrap()
+                .sha256()
+                .map(hex::encode)
+        };
+        let files = Scanner::new(&rootfs).scan().unwrap();
+        assert_eq!(sha256(&files, "/dir/hello"), None);
+
+        let files = Scanner::new(&rootfs).hash_contents(true).scan().unwrap();
+        let hello = hex::encode(openssl::sha::sha256(b"hello"));
+        assert_eq!(sha256(&files, "/dir/hello").unwrap(), hello);
+        assert_eq!(sha256(&files, "/link").unwrap(), hello);
+        assert_eq!(sha256(&files, "/dir"), None);
+        assert_eq!(sha256(&files, "/symlink"), None);
+    }
+
+    #[test]
+    fn test_scanner_hash_contents_other_device() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.write("hello", "hello").unwrap();
+        std::fs::hard_link(tmp.path().join("hello"), tmp.path().join("link")).unwrap();
+        let metadata = rootfs.symlink_metadata("hello").unwrap();
+
+        // a hardlinked file with the same inode number on another filesystem,
+        // e.g. a bind mount, was already hashed
+        let state = Mutex::new(VisitState {
+            progress: None,
+            inode_digests: HashMap::from([((metadata.dev() + 1, metadata.ino()), [0u8; 32])]),
+            non_utf8: Vec::new(),
+            xattrs: XattrInterner::default(),
+            errors: Vec::new(),
+        });
+        let scanner = Scanner::new(&rootfs).hash_contents(true);
+        let Visit::Keep(visited) = scanner
+            .visit(Utf8Path::new("/hello"), Path::new("/hello"), &state)
+            .unwrap()
+        else {
+            panic!("/hello was skipped");
+        };
+        assert_eq!(visited.info.sha256(), Some(&openssl::sha::sha256(b"hello")));
+        assert_eq!(state.into_inner().unwrap().inode_digests.len(), 2);
+    }
+
     #[test]
     fn test_scanner_empty() {
         let tmp = tempfile::tempdir().unwrap();
@@ -262,6 +873,7 @@ mod tests {
         // nor what we emit will be read. Bootable containers and other
         // OCI-but-not-container-runtime users could make use of them, but we'll
         // probably want to make it opt in if the use case shows up.
+        // --include-root does that, see root_info().
         assert_eq!(files.len(), 0);
     }
 
@@ -312,7 +924,56 @@ mod tests {
         assert_eq!(get_file_type(&files, "/regular.txt"), Some(FileType::File));
 
         // Socket should be skipped (not in the map)
-        assert!(files.get(Utf8Path::new("/test.sock")).is_none());
+        assert!(!files.contains_key(Utf8Path::new("/test.sock")));
+    }
+
+    #[test]
+    fn test_scanner_ignore_errors() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.write("regular.txt", "content").unwrap();
+        rootfs.create_dir("locked").unwrap();
+        rootfs.write("locked/secret", "").unwrap();
+        let locked = tmp.path().join("locked");
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+        // root can list it anyway
+        let denied = std::fs::read_dir(&locked).is_err();
+
+        assert_eq!(Scanner::new(&rootfs).threads(2).scan().is_err(), denied);
+        let files = Scanner::new(&rootfs).ignore_errors(true).scan().unwrap();
+        assert_eq!(get_file_type(&files, "/regular.txt"), Some(FileType::File));
+        assert_eq!(get_file_type(&files, "/locked"), Some(FileType::Directory));
+        assert_eq!(files.contains_key(Utf8Path::new("/locked/secret")), !denied);
+
+        // other errors still fail the scan
+        let _socket = std::os::unix::net::UnixListener::bind(tmp.path().join("test.sock")).unwrap();
+        assert!(Scanner::new(&rootfs).ignore_errors(true).scan().is_err());
+
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn test_scanner_fifo() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+
+        let fifo_path = std::ffi::CString::new(tmp.path().join("fifo").to_str().unwrap()).unwrap();
+        // SAFETY: the path is a valid C string
+        assert_eq!(unsafe { libc::mkfifo(fifo_path.as_ptr(), 0o644) }, 0);
+
+        // FIFOs are captured by default
+        let files = Scanner::new(&rootfs).scan().unwrap();
+        assert_eq!(get_file_type(&files, "/fifo"), Some(FileType::Fifo));
+        assert_eq!(files[Utf8Path::new("/fifo")].rdev(), 0);
+
+        // and skipped along with the other special files
+        let files = Scanner::new(&rootfs)
+            .skip_special_files(true)
+            .scan()
+            .unwrap();
+        assert!(!files.contains_key(Utf8Path::new("/fifo")));
     }
 
     #[test]
@@ -351,4 +1012,232 @@ mod tests {
         assert!(files.contains_key(Utf8Path::new("/zkeep/nested")));
         assert!(files.contains_key(Utf8Path::new("/zkeep/nested/file.txt")));
     }
+
+    #[test]
+    fn test_scanner_with_exclude() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+
+        rootfs.create_dir_all("usr/lib/app/__pycache__").unwrap();
+        rootfs.write("usr/lib/app/__pycache__/mod.pyc", "").unwrap();
+        rootfs.write("usr/lib/app/mod.py", "").unwrap();
+        rootfs.write("usr/lib/app/old.pyc", "").unwrap();
+        rootfs.write("top.pyc", "").unwrap();
+        rootfs.create_dir_all("srv/cache").unwrap();
+        rootfs.write("srv/cache/data", "").unwrap();
+
+        let exclude = ["**/*.pyc", "**/__pycache__", "/srv/*/data"].map(String::from);
+        let files = Scanner::new(&rootfs)
+            .exclude(&exclude)
+            .unwrap()
+            .scan()
+            .unwrap();
+        let paths: Vec<&str> = files.keys().map(|p| p.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "/srv",
+                "/srv/cache",
+                "/usr",
+                "/usr/lib",
+                "/usr/lib/app",
+                "/usr/lib/app/mod.py"
+            ]
+        );
+
+        for invalid in ["/", "**/[", ""] {
+            assert!(
+                Scanner::new(&rootfs)
+                    .exclude(&[invalid.to_string()])
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_scanner_one_file_system() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("usr/bin").unwrap();
+        rootfs.write("usr/bin/app", "").unwrap();
+        let files = Scanner::new(&rootfs)
+            .one_file_system(true)
+            .unwrap()
+            .scan()
+            .unwrap();
+        assert!(files.contains_key(Utf8Path::new("/usr/bin/app")));
+
+        // /proc is a mount point on any host running the tests
+        let host = Dir::open_ambient_dir("/", ambient_authority()).unwrap();
+        let (Ok(root), Ok(proc)) = (host.dir_metadata(), host.symlink_metadata("proc")) else {
+            return;
+        };
+        if root.dev() == proc.dev() {
+            return;
+        }
+        let prune: Vec<Utf8PathBuf> = host
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| name != "proc")
+            .map(|name| Utf8PathBuf::from(format!("/{}", name.to_str().unwrap())))
+            .collect();
+        let files = Scanner::new(&host)
+            .prune(&prune)
+            .unwrap()
+            .one_file_system(true)
+            .unwrap()
+            .scan()
+            .unwrap();
+        let paths: Vec<&str> = files.keys().map(|p| p.as_str()).collect();
+        assert_eq!(paths, ["/proc"]);
+    }
+
+    #[test]
+    fn test_scanner_with_ignore() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+
+        rootfs.create_dir_all("src").unwrap();
+        rootfs.write("src/main.rs", "").unwrap();
+        rootfs.create_dir_all("target/debug").unwrap();
+        rootfs.write("target/debug/app", "").unwrap();
+        rootfs.create_dir_all("docs/api").unwrap();
+        rootfs.write("docs/api/index.md", "").unwrap();
+        rootfs.write("docs/api/README.md", "").unwrap();
+        rootfs.write("build.log", "").unwrap();
+
+        let rules = IgnoreRules::parse("target\n*.log\ndocs\n!docs/**/README.md\n").unwrap();
+        let files = Scanner::new(&rootfs).ignore(&rules).scan().unwrap();
+        let paths: Vec<&str> = files.keys().map(|p| p.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "/docs",
+                "/docs/api",
+                "/docs/api/README.md",
+                "/src",
+                "/src/main.rs"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scanner_threads() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        for i in 0..10 {
+            rootfs.create_dir_all(format!("usr/lib{i}/sub")).unwrap();
+            rootfs
+                .write(format!("usr/lib{i}/sub/file"), "x".repeat(i))
+                .unwrap();
+            rootfs.symlink("sub", format!("usr/lib{i}/link")).unwrap();
+        }
+        rootfs.create_dir_all("var/cache/dnf").unwrap();
+        rootfs.write("var/cache/dnf/metadata", "").unwrap();
+        rootfs.create_dir_all("tmp/build").unwrap();
+        rootfs.write("build.log", "").unwrap();
+
+        let rules = IgnoreRules::parse("*.log\n").unwrap();
+        let scan = |threads| {
+            let files = Scanner::new(&rootfs)
+                .prune(&["/tmp".into(), "/var/cache/".into()])
+                .unwrap()
+                .ignore(&rules)
+                .threads(threads)
+                .scan()
+                .unwrap();
+            files
+                .into_iter()
+                .map(|(path, info)| (path, info.file_type, info.size))
+                .collect::<Vec<_>>()
+        };
+
+        let sequential = scan(1);
+        assert!(sequential.iter().any(|(p, _, _)| p == "/var/cache"));
+        assert!(!sequential.iter().any(|(p, _, _)| p.starts_with("/tmp")));
+        assert!(!sequential.iter().any(|(p, _, _)| p == "/var/cache/dnf"));
+        assert!(!sequential.iter().any(|(p, _, _)| p == "/build.log"));
+        assert_eq!(scan(4), sequential);
+    }
+
+    #[test]
+    fn test_scanner_non_utf8_paths() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.write("ok", "content").unwrap();
+        rootfs
+            .write(OsStr::from_bytes(b"bad\xff"), "content")
+            .unwrap();
+        rootfs.create_dir(OsStr::from_bytes(b"dir\xfe")).unwrap();
+        rootfs
+            .write(OsStr::from_bytes(b"dir\xfe/file"), "content")
+            .unwrap();
+
+        let scan = |policy, threads| {
+            Scanner::new(&rootfs)
+                .non_utf8_paths(policy)
+                .threads(threads)
+                .scan()
+        };
+        for threads in [1, 4] {
+            let err = format!("{:#}", scan(NonUtf8Paths::Error, threads).unwrap_err());
+            assert!(err.contains("not valid UTF-8"), "{err}");
+
+            let files = scan(NonUtf8Paths::Skip, threads).unwrap();
+            assert_eq!(files.keys().collect::<Vec<_>>(), ["/ok"]);
+
+            let files = scan(NonUtf8Paths::Lossy, threads).unwrap();
+            assert_eq!(
+                files.keys().collect::<Vec<_>>(),
+                ["/bad\u{fffd}", "/dir\u{fffd}", "/dir\u{fffd}/file", "/ok"]
+            );
+            let file = Utf8Path::new("/dir\u{fffd}/file");
+            assert_eq!(
+                files[file].fs_path(file).as_os_str().as_bytes(),
+                b"dir\xfe/file"
+            );
+            assert!(files[Utf8Path::new("/ok")].disk_path().is_none());
+        }
+
+        // names which only differ by their invalid sequences collide
+        rootfs
+            .write(OsStr::from_bytes(b"bad\xfe"), "content")
+            .unwrap();
+        assert!(scan(NonUtf8Paths::Lossy, 1).is_err());
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_prune_properties(
+            components in proptest::collection::vec("[a-z.]{1,8}", 1..5),
+            child in "[a-z]{1,8}",
+            children_only in proptest::bool::ANY,
+        ) {
+            let base = format!("/{}", components.join("/"));
+            proptest::prop_assume!(base.split('/').all(|c| c != "." && c != ".."));
+            let arg = if children_only { format!("{base}/") } else { base.clone() };
+            let prune = [parse_prune_path(&Utf8PathBuf::from(arg)).unwrap()];
+
+            let expected = if children_only {
+                PruneAction::SkipChildren
+            } else {
+                PruneAction::SkipEntirely
+            };
+            proptest::prop_assert_eq!(check_prune(Utf8Path::new(&base), &prune), expected);
+            let child_path = format!("{base}/{child}");
+            proptest::prop_assert_eq!(
+                check_prune(Utf8Path::new(&child_path), &prune),
+                PruneAction::SkipEntirely
+            );
+            // a sibling sharing the name as a prefix isn't pruned
+            let sibling = format!("{base}{child}");
+            proptest::prop_assert_eq!(
+                check_prune(Utf8Path::new(&sibling), &prune),
+                PruneAction::Keep
+            );
+        }
+    }
 }
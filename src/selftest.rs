@@ -0,0 +1,635 @@
+//! Post-build self-test of the layers.
+//!
+//! Each layer is checked in two ways. First, its tar headers are compared
+//! with the metadata of the files that went into it, which catches header
+//! bugs regardless of who runs the build. Then, it's extracted into a scratch
+//! directory with each [`Extractor`] and the result is scanned like a rootfs
+//! and compared again, which catches entries that extractors interpret
+//! differently from us (e.g. mode masking or PAX quirks).
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::process::{Command, Stdio};
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::ambient_authority;
+use cap_std_ext::cap_std::fs::Dir;
+use ocidir::oci_spec::image as oci_image;
+
+use crate::components::{FileInfo, FileMap, FileType};
+use crate::overlay::{entry_path, tar_entries};
+use crate::owners::OwnerNames;
+use crate::tar::TarOptions;
+
+/// A tool layers get extracted with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extractor {
+    /// The `tar` crate, which we also write layers with.
+    Builtin,
+    /// GNU tar, with `--numeric-owner` like container runtimes.
+    GnuTar,
+    /// libarchive's bsdtar, with `--numeric-owner`.
+    Bsdtar,
+}
+
+impl std::fmt::Display for Extractor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Builtin => "tar crate",
+            Self::GnuTar => "GNU tar",
+            Self::Bsdtar => "bsdtar",
+        })
+    }
+}
+
+impl Extractor {
+    /// Returns the extractors to self-test with: the builtin one, plus GNU
+    /// tar and bsdtar if `system_tar` is set. System tools which aren't
+    /// installed are skipped with a warning.
+    pub fn available(system_tar: bool) -> Vec<Self> {
+        let mut extractors = vec![Self::Builtin];
+        if !system_tar {
+            return extractors;
+        }
+        for (extractor, program, version_prefix) in [
+            (Self::GnuTar, "tar", "tar (GNU tar)"),
+            (Self::Bsdtar, "bsdtar", "bsdtar"),
+        ] {
+            let found = Command::new(program)
+                .arg("--version")
+                .output()
+                .is_ok_and(|out| {
+                    out.status.success()
+                        && String::from_utf8_lossy(&out.stdout).starts_with(version_prefix)
+                });
+            if found {
+                extractors.push(extractor);
+            } else {
+                crate::diagnostics::report(&[crate::diagnostics::Diagnostic::warning(format!(
+                    "{extractor} not found; skipping it in the self-test"
+                ))]);
+            }
+        }
+        extractors
+    }
+}
+
+/// Self-test settings.
+#[derive(Debug, Clone)]
+pub struct SelfTest {
+    /// Extractors to extract each layer with.
+    pub extractors: Vec<Extractor>,
+    /// Directory for the scratch directories, or `None` for the system
+    /// temporary directory.
+    pub workdir: Option<Utf8PathBuf>,
+}
+
+/// Metadata of an entry as we expect to find it in a layer.
+#[derive(Debug, PartialEq)]
+struct Expected {
+    file_type: FileType,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: u64,
+}
+
+impl Expected {
+    fn new(info: &FileInfo, mtime_clamp: u64) -> Self {
+        Self {
+            file_type: info.file_type,
+            mode: info.mode & 0o7777,
+            uid: info.uid,
+            gid: info.gid,
+            mtime: info.mtime.min(mtime_clamp),
+        }
+    }
+}
+
+/// Check that the layer `layer`, written from `files` with `mtime_clamp`
+/// and `options`, has the same metadata once extracted. All problems found
+/// are reported together.
+pub fn check_layer(
+    oci_dir: &ocidir::OciDir,
+    layer: &oci_image::Descriptor,
+    files: &FileMap,
+    mtime_clamp: u64,
+    options: &TarOptions,
+    self_test: &SelfTest,
+) -> Result<()> {
+    // the layer has the owners and modes of the image rather than those of
+    // the rootfs
+    let transformed_files;
+    let files = if options.uid_map.is_identity()
+        && options.gid_map.is_identity()
+        && options.chown.is_empty()
+        && options.chmod.is_empty()
+    {
+        files
+    } else {
+        transformed_files = transform_files(files, options);
+        &transformed_files
+    };
+
+    let mut problems = Vec::new();
+    let reader = crate::tar::read_layer(oci_dir, layer)?;
+    check_headers(
+        reader,
+        files,
+        mtime_clamp,
+        options.owner_names.as_deref(),
+        &mut problems,
+    )
+    .context("reading layer headers")?;
+
+    for &extractor in &self_test.extractors {
+        let mut builder = tempfile::Builder::new();
+        builder.prefix("chunkah-selftest-");
+        let scratch = match &self_test.workdir {
+            Some(dir) => builder.tempdir_in(dir),
+            None => builder.tempdir(),
+        }
+        .context("creating scratch directory")?;
+        let path =
+            Utf8Path::from_path(scratch.path()).context("scratch directory path is not UTF-8")?;
+        // like the scratch directory, extracted files are only owned by the
+        // original owners when running as root
+        let as_root = path
+            .metadata()
+            .with_context(|| format!("querying {path}"))?
+            .uid()
+            == 0;
+        // the tar crate extracts devices and FIFOs as regular files, and
+        // creating devices needs root
+        let skip = match extractor {
+            Extractor::Builtin => files.values().any(|info| info.file_type.is_special()),
+            _ => !as_root && files.values().any(|info| info.rdev() != 0),
+        };
+        if skip {
+            continue;
+        }
+
+        let reader = crate::tar::read_layer(oci_dir, layer)?;
+        extract(extractor, reader, path, as_root)
+            .with_context(|| format!("extracting layer with {extractor}"))?;
+        let dir = Dir::open_ambient_dir(path, ambient_authority())
+            .with_context(|| format!("opening {path}"))?;
+        let extracted = crate::scan::Scanner::new(&dir)
+            .scan()
+            .context("scanning extracted layer")?;
+        check_extracted(
+            files,
+            mtime_clamp,
+            &extracted,
+            as_root,
+            &extractor.to_string(),
+            &mut problems,
+        );
+        make_removable(path, &extracted)?;
+    }
+
+    if !problems.is_empty() {
+        anyhow::bail!(
+            "self-test failed:\n{}",
+            problems
+                .iter()
+                .map(|p| format!("  - {p}"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+    }
+    Ok(())
+}
+
+/// Returns `files` with the owners and modes of their entries.
+fn transform_files(files: &FileMap, options: &TarOptions) -> FileMap {
+    files
+        .iter()
+        .map(|(path, info)| {
+            let mut info = info.clone();
+            (info.uid, info.gid, info.mode) =
+                crate::tar::entry_owner_and_mode(path, &info, options);
+            (path.clone(), info)
+        })
+        .collect()
+}
+
+/// Compare the tar headers of the layer with `files`.
+fn check_headers<R: Read>(
+    reader: R,
+    files: &FileMap,
+    mtime_clamp: u64,
+    owner_names: Option<&OwnerNames>,
+    problems: &mut Vec<String>,
+) -> Result<()> {
+    let mut seen: HashMap<Utf8PathBuf, usize> = HashMap::new();
+    let mut archive = tar::Archive::new(reader);
+    for entry in tar_entries(&mut archive)? {
+        let entry = entry?;
+        let path = entry_path(entry.path())?;
+        *seen.entry(path.clone()).or_default() += 1;
+        let Some(info) = files.get(&path) else {
+            // parent directories written for the files
+            continue;
+        };
+        let header = entry.header();
+        let entry_type = header.entry_type();
+
+        let file_type = match entry_type {
+            tar::EntryType::Directory => Some(FileType::Directory),
+            tar::EntryType::Regular => Some(FileType::File),
+            tar::EntryType::Symlink => Some(FileType::Symlink),
+            tar::EntryType::Block => Some(FileType::BlockDevice),
+            tar::EntryType::Char => Some(FileType::CharDevice),
+            tar::EntryType::Fifo => Some(FileType::Fifo),
+            // hardlinks get the type of their target
+            tar::EntryType::Link => Some(info.file_type),
+            _ => None,
+        };
+        let Some(file_type) = file_type else {
+            problems.push(format!("{path}: unexpected entry type {entry_type:?}"));
+            continue;
+        };
+        let actual = Expected {
+            file_type,
+            mode: header.mode().context("reading mode")? & 0o7777,
+            uid: entry.uid()?,
+            gid: entry.gid()?,
+            mtime: header.mtime().context("reading mtime")?,
+        };
+        compare(
+            &path,
+            "header",
+            &Expected::new(info, mtime_clamp),
+            &actual,
+            true,
+            problems,
+        );
+        if matches!(entry_type, tar::EntryType::Block | tar::EntryType::Char) {
+            let major = header.device_major().context("reading device major")?;
+            let minor = header.device_minor().context("reading device minor")?;
+            let device = (major.unwrap_or(0), minor.unwrap_or(0));
+            if device != (libc::major(info.rdev()), libc::minor(info.rdev())) {
+                problems.push(format!("{path}: header device number doesn't match"));
+            }
+        }
+
+        // extractors without --numeric-owner would map names to IDs of the
+        // host, so there mustn't be any unless they're the rootfs's own
+        let name = |name: Option<&[u8]>| {
+            name.filter(|n| !n.is_empty())
+                .map(|n| String::from_utf8_lossy(n).into_owned())
+        };
+        let expected_names = (
+            owner_names
+                .and_then(|n| n.user(info.uid))
+                .map(str::to_string),
+            owner_names
+                .and_then(|n| n.group(info.gid))
+                .map(str::to_string),
+        );
+        let names = (
+            name(header.username_bytes()),
+            name(header.groupname_bytes()),
+        );
+        if names != expected_names {
+            problems.push(format!(
+                "{path}: header user and group names {names:?} instead of {expected_names:?}"
+            ));
+        }
+
+        let mut xattrs = entry.xattrs();
+        let mut expected_xattrs = info.xattrs.to_vec();
+        xattrs.sort();
+        expected_xattrs.sort();
+        // hardlinks don't carry xattrs; they're on the first entry
+        if entry_type != tar::EntryType::Link && xattrs != expected_xattrs {
+            problems.push(format!("{path}: header xattrs don't match"));
+        }
+    }
+
+    for path in files.keys() {
+        match seen.get(path) {
+            None => problems.push(format!("{path}: missing from layer")),
+            Some(&n) if n > 1 => problems.push(format!("{path}: {n} entries in layer")),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Compare the scan of an extracted layer with `files`.
+///
+/// Without root, extractors can't restore ownership nor setuid/setgid bits, so
+/// those are only compared when `as_root` is set. Symlinks are compared by
+/// type only, since not all extractors restore their mtime, and the root
+/// directory not at all since it's the scratch directory.
+fn check_extracted(
+    files: &FileMap,
+    mtime_clamp: u64,
+    extracted: &FileMap,
+    as_root: bool,
+    extractor: &str,
+    problems: &mut Vec<String>,
+) {
+    // inode in the rootfs -> inode once extracted, to check hardlinks
+    let mut inodes: HashMap<u64, u64> = HashMap::new();
+    for (path, info) in files {
+        if path == "/" {
+            continue;
+        }
+        let Some(extracted_info) = extracted.get(path) else {
+            problems.push(format!("{path}: missing once extracted with {extractor}"));
+            continue;
+        };
+        if info.file_type == FileType::Symlink && extracted_info.file_type == FileType::Symlink {
+            continue;
+        }
+        let mut expected = Expected::new(info, mtime_clamp);
+        let mut actual = Expected::new(extracted_info, u64::MAX);
+        if !as_root {
+            expected.mode &= 0o1777;
+            actual.mode &= 0o1777;
+        }
+        compare(path, extractor, &expected, &actual, as_root, problems);
+        if info.rdev() != extracted_info.rdev() {
+            problems.push(format!("{path}: device number changed by {extractor}"));
+        }
+
+        if info.file_type != FileType::Directory && info.nlink > 1 {
+            let extracted_ino = extracted_info.ino;
+            if *inodes.entry(info.ino).or_insert(extracted_ino) != extracted_ino {
+                problems.push(format!("{path}: hardlink broken by {extractor}"));
+            }
+        }
+    }
+}
+
+fn compare(
+    path: &Utf8Path,
+    ctx: &str,
+    expected: &Expected,
+    actual: &Expected,
+    check_owner: bool,
+    problems: &mut Vec<String>,
+) {
+    if expected.file_type != actual.file_type {
+        problems.push(format!(
+            "{path}: {ctx}: type {:?} instead of {:?}",
+            actual.file_type, expected.file_type
+        ));
+        return;
+    }
+    if expected.mode != actual.mode {
+        problems.push(format!(
+            "{path}: {ctx}: mode {:o} instead of {:o}",
+            actual.mode, expected.mode
+        ));
+    }
+    if check_owner && (expected.uid, expected.gid) != (actual.uid, actual.gid) {
+        problems.push(format!(
+            "{path}: {ctx}: owner {}:{} instead of {}:{}",
+            actual.uid, actual.gid, expected.uid, expected.gid
+        ));
+    }
+    if expected.mtime != actual.mtime {
+        problems.push(format!(
+            "{path}: {ctx}: mtime {} instead of {}",
+            actual.mtime, expected.mtime
+        ));
+    }
+}
+
+/// Extract the uncompressed layer `reader` into `dest` with the tar crate.
+///
+/// Like [`tar::Archive::unpack`], directories are unpacked last so that their
+/// modes don't get in the way of their contents, but their mtimes are restored
+/// too, which the tar crate only does for files.
+fn extract_builtin<R: Read>(reader: R, dest: &Utf8Path, as_root: bool) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.set_preserve_ownerships(as_root);
+    let mut dirs = Vec::new();
+    for entry in archive.entries().context("reading entries")? {
+        let mut entry = entry.context("reading entry")?;
+        if entry.header().entry_type() == tar::EntryType::Directory {
+            dirs.push(entry);
+        } else {
+            entry.unpack_in(dest).context("unpacking")?;
+        }
+    }
+    // children first, so that creating them doesn't change the mtime of their
+    // parent once restored
+    dirs.sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
+    for mut dir in dirs {
+        if !dir.unpack_in(dest).context("unpacking")? {
+            continue;
+        }
+        let path = dir.path().context("reading path")?;
+        let mtime = dir.header().mtime().context("reading mtime")?;
+        std::fs::File::open(dest.as_std_path().join(&path))
+            .and_then(|f| f.set_modified(UNIX_EPOCH + Duration::from_secs(mtime)))
+            .with_context(|| format!("setting mtime of {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Extract the uncompressed layer `reader` into `dest` with `extractor`.
+fn extract<R: Read>(
+    extractor: Extractor,
+    mut reader: R,
+    dest: &Utf8Path,
+    as_root: bool,
+) -> Result<()> {
+    let mut cmd = match extractor {
+        Extractor::Builtin => return extract_builtin(reader, dest, as_root),
+        Extractor::GnuTar => {
+            let mut cmd = Command::new("tar");
+            cmd.args(["--numeric-owner", "--same-permissions"]);
+            if as_root {
+                // without root, restoring e.g. security.* xattrs fails
+                cmd.args(["--xattrs", "--xattrs-include=*"]);
+            }
+            cmd
+        }
+        Extractor::Bsdtar => {
+            let mut cmd = Command::new("bsdtar");
+            cmd.args(["--numeric-owner", "-p"]);
+            if !as_root {
+                cmd.arg("--no-xattrs");
+            }
+            cmd
+        }
+    };
+    let mut child = cmd
+        .arg("-xf")
+        .arg("-")
+        .arg("-C")
+        .arg(dest)
+        .stdin(Stdio::piped())
+        // the OCI archive may be going to our stdout
+        .stdout(std::io::stderr())
+        .spawn()
+        .with_context(|| format!("spawning {extractor}"))?;
+    // SAFETY: we asked for a piped stdin
+    let mut stdin = child.stdin.take().unwrap();
+    let copied = std::io::copy(&mut reader, &mut stdin);
+    drop(stdin);
+    let status = child
+        .wait()
+        .with_context(|| format!("waiting for {extractor}"))?;
+    copied.context("writing layer")?;
+    anyhow::ensure!(status.success(), "{extractor} failed: {status}");
+    Ok(())
+}
+
+/// Make the directories extracted in `path` writable, so that the scratch
+/// directory can be removed without root.
+fn make_removable(path: &Utf8Path, extracted: &FileMap) -> Result<()> {
+    for (dir, info) in extracted {
+        if info.file_type != FileType::Directory || info.mode & 0o700 == 0o700 {
+            continue;
+        }
+        let fs_path = path.join(dir.strip_prefix("/").unwrap_or(dir));
+        std::fs::set_permissions(&fs_path, std::fs::Permissions::from_mode(info.mode | 0o700))
+            .with_context(|| format!("making {fs_path} writable"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(mode: u32, mtime: u64) -> FileInfo {
+        FileInfo {
+            mode,
+            ino: 1,
+            ..FileInfo::test_file(FileType::File, 5, mtime)
+        }
+    }
+
+    #[test]
+    fn test_check_headers() {
+        let files: FileMap = [
+            (Utf8PathBuf::from("/usr/bin/app"), file(0o100755, 2000)),
+            (Utf8PathBuf::from("/etc/app.conf"), file(0o100644, 500)),
+        ]
+        .into();
+
+        let mut tar_builder = tar::Builder::new(Vec::new());
+        for (path, mode, mtime) in [
+            // parent directories aren't in the files
+            ("usr/", 0o755, 1000),
+            ("usr/bin/app", 0o755, 1000),
+            ("etc/app.conf", 0o600, 500),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(mode);
+            header.set_mtime(mtime);
+            header.set_uid(0);
+            header.set_gid(0);
+            if path.ends_with('/') {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_size(0);
+                tar_builder
+                    .append_data(&mut header, path, std::io::empty())
+                    .unwrap();
+            } else {
+                header.set_size(5);
+                tar_builder
+                    .append_data(&mut header, path, &b"hello"[..])
+                    .unwrap();
+            }
+        }
+        let layer = tar_builder.into_inner().unwrap();
+
+        let mut problems = Vec::new();
+        check_headers(layer.as_slice(), &files, 1000, None, &mut problems).unwrap();
+        // the mtime of /usr/bin/app was clamped, but the mode of /etc/app.conf
+        // is wrong
+        assert_eq!(problems, ["/etc/app.conf: header: mode 600 instead of 644"]);
+    }
+
+    #[test]
+    fn test_check_layer() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs_path = tmp.path().join("rootfs");
+        std::fs::create_dir_all(rootfs_path.join("usr/bin")).unwrap();
+        std::fs::write(rootfs_path.join("usr/bin/app"), b"hello").unwrap();
+        std::fs::hard_link(
+            rootfs_path.join("usr/bin/app"),
+            rootfs_path.join("usr/bin/app2"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("app", rootfs_path.join("usr/bin/link")).unwrap();
+        std::fs::create_dir(rootfs_path.join("readonly")).unwrap();
+        std::fs::write(rootfs_path.join("readonly/file"), b"x").unwrap();
+        // not the time of extraction, which the directories would get if
+        // their mtime wasn't restored
+        for dir in ["usr", "usr/bin", "readonly"] {
+            std::fs::File::open(rootfs_path.join(dir))
+                .unwrap()
+                .set_modified(UNIX_EPOCH + Duration::from_secs(1_000_000_000))
+                .unwrap();
+        }
+        std::fs::set_permissions(
+            rootfs_path.join("readonly"),
+            std::fs::Permissions::from_mode(0o555),
+        )
+        .unwrap();
+        let rootfs = Dir::open_ambient_dir(&rootfs_path, ambient_authority()).unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+
+        let oci_path = tmp.path().join("oci");
+        std::fs::create_dir(&oci_path).unwrap();
+        let oci_dir =
+            ocidir::OciDir::ensure(Dir::open_ambient_dir(&oci_path, ambient_authority()).unwrap())
+                .unwrap();
+        let mut tar_builder = crate::tar::create_layer(
+            &oci_dir,
+            crate::ocibuilder::Compression::Gzip(6),
+            crate::ocibuilder::LayerMediaType::Oci,
+        )
+        .unwrap();
+        crate::tar::write_files_to_tar(
+            &mut tar_builder,
+            &rootfs,
+            &files,
+            u64::MAX,
+            &Default::default(),
+        )
+        .unwrap();
+        tar_builder.finish().unwrap();
+        let layer = tar_builder.into_inner().unwrap().complete().unwrap();
+        let mut manifest = oci_dir.new_empty_manifest().unwrap().build().unwrap();
+        let mut config = oci_image::ImageConfiguration::default();
+        oci_dir.push_layer(&mut manifest, &mut config, layer, "test", None);
+        let desc = &manifest.layers()[0];
+
+        let self_test = SelfTest {
+            extractors: Extractor::available(true),
+            workdir: Some(Utf8PathBuf::from_path_buf(tmp.path().to_path_buf()).unwrap()),
+        };
+        check_layer(
+            &oci_dir,
+            desc,
+            &files,
+            u64::MAX,
+            &Default::default(),
+            &self_test,
+        )
+        .unwrap();
+
+        // so that the temporary directory can be removed
+        std::fs::set_permissions(
+            rootfs_path.join("readonly"),
+            std::fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+    }
+}
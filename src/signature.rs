@@ -0,0 +1,417 @@
+//! Signatures of images, in the format cosign uses for OCI 1.1 referrers.
+//!
+//! Each signature is an artifact of type [`SIGNATURE_ARTIFACT_TYPE`] referring
+//! to the image manifest. Its single blob is a [simple signing] payload naming
+//! the digest of the image manifest, and the layer descriptor of that blob
+//! holds the base64 signature. An image can carry signatures by several keys,
+//! e.g. while rotating keys. Since the manifest digest covers the config and
+//! all layers, signing never requires rebuilding the image.
+//!
+//! Keys are PEM encoded. Ed25519 keys sign the payload directly; RSA and ECDSA
+//! keys sign its SHA-256 digest, like cosign does.
+//!
+//! [simple signing]: https://github.com/containers/image/blob/main/docs/containers-signature.5.md
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use ocidir::oci_spec::image as oci_image;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{HasPublic, Id, PKey, PKeyRef, Private};
+use openssl::sign::{Signer, Verifier};
+use serde::{Deserialize, Serialize};
+
+/// Artifact type of cosign signatures.
+pub const SIGNATURE_ARTIFACT_TYPE: &str = "application/vnd.dev.cosign.artifact.sig.v1+json";
+
+/// Media type of the simple signing payload.
+const PAYLOAD_MEDIA_TYPE: &str = "application/vnd.dev.cosign.simplesigning.v1+json";
+
+/// Payload annotation holding the base64 signature.
+const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+
+/// Type of the payload, so that it can't be confused with other data signed
+/// with the same key.
+const PAYLOAD_TYPE: &str = "cosign container image signature";
+
+/// The signed simple signing payload.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Payload {
+    critical: Critical,
+    optional: Option<serde_json::Value>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Critical {
+    identity: Identity,
+    image: Image,
+    #[serde(rename = "type")]
+    typ: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Identity {
+    #[serde(rename = "docker-reference")]
+    docker_reference: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Image {
+    #[serde(rename = "docker-manifest-digest")]
+    docker_manifest_digest: String,
+}
+
+/// A signature attached to an image.
+#[derive(Debug)]
+pub struct Signature {
+    /// Digest of the signature artifact manifest.
+    artifact_digest: String,
+    payload: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl Signature {
+    /// Whether this is a valid signature of the image manifest `image` by
+    /// `key`, naming the image reference `reference`.
+    pub fn verify<T: HasPublic>(
+        &self,
+        image: &oci_image::Descriptor,
+        reference: &str,
+        key: &PKeyRef<T>,
+    ) -> Result<bool> {
+        let mut verifier = match key.id() {
+            Id::ED25519 => Verifier::new_without_digest(key),
+            _ => Verifier::new(MessageDigest::sha256(), key),
+        }
+        .context("creating verifier")?;
+        // verifying with a key of another type fails, which just means it
+        // isn't the signing key
+        if !verifier
+            .verify_oneshot(&self.signature, &self.payload)
+            .unwrap_or(false)
+        {
+            return Ok(false);
+        }
+        let payload: Payload =
+            serde_json::from_slice(&self.payload).context("parsing signed payload")?;
+        Ok(payload.critical.typ == PAYLOAD_TYPE
+            && payload.critical.image.docker_manifest_digest == image.digest().to_string()
+            && payload.critical.identity.docker_reference == reference)
+    }
+}
+
+/// Returns the signatures attached to the image manifest `image`.
+pub fn read_signatures(
+    oci_dir: &ocidir::OciDir,
+    image: &oci_image::Descriptor,
+) -> Result<Vec<Signature>> {
+    let index = oci_dir.read_index().context("reading index")?;
+    let mut signatures = Vec::new();
+    for desc in index.manifests() {
+        if desc.media_type() != &oci_image::MediaType::ImageManifest {
+            continue;
+        }
+        let manifest: oci_image::ImageManifest =
+            oci_dir.read_json_blob(desc).context("reading manifest")?;
+        let is_signature = manifest
+            .artifact_type()
+            .as_ref()
+            .is_some_and(|t| t.to_string() == SIGNATURE_ARTIFACT_TYPE);
+        let refers_to_image = manifest
+            .subject()
+            .as_ref()
+            .is_some_and(|subject| subject.digest() == image.digest());
+        if !is_signature || !refers_to_image {
+            continue;
+        }
+        let [layer] = manifest.layers().as_slice() else {
+            anyhow::bail!("signature {} doesn't have a single blob", desc.digest());
+        };
+        let encoded = layer
+            .annotations()
+            .as_ref()
+            .and_then(|a| a.get(SIGNATURE_ANNOTATION))
+            .with_context(|| {
+                format!(
+                    "signature {} has no {SIGNATURE_ANNOTATION} annotation",
+                    desc.digest()
+                )
+            })?;
+        let signature = openssl::base64::decode_block(encoded)
+            .with_context(|| format!("decoding signature {}", desc.digest()))?;
+        let mut payload = Vec::new();
+        oci_dir
+            .read_blob(layer)
+            .context("opening payload")?
+            .read_to_end(&mut payload)
+            .context("reading payload")?;
+        signatures.push(Signature {
+            artifact_digest: desc.digest().to_string(),
+            payload,
+            signature,
+        });
+    }
+    Ok(signatures)
+}
+
+/// Sign the image manifest `image` with `key`, attaching the signature to it.
+///
+/// `reference` is the image reference recorded in the payload, e.g.
+/// `quay.io/org/image`, which containers-policy.json(5) can check.
+pub fn sign(
+    oci_dir: &ocidir::OciDir,
+    image: &oci_image::Descriptor,
+    platform: oci_image::Platform,
+    reference: &str,
+    key: &PKey<Private>,
+) -> Result<()> {
+    let payload = serde_json::to_vec(&Payload {
+        critical: Critical {
+            identity: Identity {
+                docker_reference: reference.to_string(),
+            },
+            image: Image {
+                docker_manifest_digest: image.digest().to_string(),
+            },
+            typ: PAYLOAD_TYPE.to_string(),
+        },
+        optional: None,
+    })
+    .context("serializing payload")?;
+    let mut signer = match key.id() {
+        Id::ED25519 => Signer::new_without_digest(key),
+        Id::RSA | Id::EC => Signer::new(MessageDigest::sha256(), key),
+        _ => anyhow::bail!("unsupported key type; use an RSA, ECDSA or Ed25519 key"),
+    }
+    .context("creating signer")?;
+    let signature = signer
+        .sign_oneshot_to_vec(&payload)
+        .context("signing payload")?;
+
+    let empty_config = oci_dir
+        .write_json_blob(&serde_json::json!({}), oci_image::MediaType::EmptyJSON)
+        .context("writing empty config")?
+        .build()
+        .context("building empty config descriptor")?;
+    let mut blob = oci_dir.create_blob().context("creating payload blob")?;
+    blob.write_all(&payload).context("writing payload")?;
+    let annotations = HashMap::from([(
+        SIGNATURE_ANNOTATION.to_string(),
+        openssl::base64::encode_block(&signature),
+    )]);
+    let layer = blob
+        .complete()
+        .context("completing payload blob")?
+        .descriptor()
+        .media_type(oci_image::MediaType::Other(PAYLOAD_MEDIA_TYPE.into()))
+        .annotations(annotations)
+        .build()
+        .context("building payload descriptor")?;
+    let subject = oci_image::DescriptorBuilder::default()
+        .media_type(image.media_type().clone())
+        .digest(image.digest().clone())
+        .size(image.size())
+        .build()
+        .context("building subject descriptor")?;
+
+    let artifact = oci_image::ImageManifestBuilder::default()
+        .schema_version(oci_image::SCHEMA_VERSION)
+        .media_type(oci_image::MediaType::ImageManifest)
+        .artifact_type(oci_image::MediaType::Other(SIGNATURE_ARTIFACT_TYPE.into()))
+        .config(empty_config)
+        .layers(vec![layer])
+        .subject(subject)
+        .build()
+        .context("building signature manifest")?;
+    oci_dir
+        .insert_manifest(artifact, None, platform)
+        .context("inserting signature manifest")?;
+    Ok(())
+}
+
+/// Detach `signatures` from the image in the OCI image layout `dir`.
+///
+/// Their blobs are left behind, like when the image itself is replaced.
+pub fn remove_signatures(dir: &Dir, signatures: &[&Signature]) -> Result<()> {
+    let oci_dir = ocidir::OciDir::open(dir.try_clone().context("cloning directory")?)
+        .context("opening OCI image layout")?;
+    let mut index = oci_dir.read_index().context("reading index")?;
+    let manifests = index
+        .manifests()
+        .iter()
+        .filter(|desc| {
+            !signatures
+                .iter()
+                .any(|sig| sig.artifact_digest == desc.digest().to_string())
+        })
+        .cloned()
+        .collect();
+    index.set_manifests(manifests);
+    let content = serde_json::to_vec(&index).context("serializing index")?;
+    dir.atomic_write("index.json", content)
+        .context("writing index.json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::cap_std::ambient_authority;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+
+    /// Create an OCI image layout with an empty image in it.
+    fn empty_image(tmp: &tempfile::TempDir) -> (Dir, oci_image::Descriptor) {
+        let dir = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        let oci_dir = ocidir::OciDir::ensure(dir.try_clone().unwrap()).unwrap();
+        let manifest = oci_dir.new_empty_manifest().unwrap().build().unwrap();
+        let config = oci_image::ImageConfiguration::default();
+        let image = oci_dir
+            .insert_manifest_and_config(manifest, config, None, platform())
+            .unwrap();
+        (dir, image)
+    }
+
+    fn platform() -> oci_image::Platform {
+        oci_image::PlatformBuilder::default()
+            .os("linux")
+            .architecture("amd64")
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (dir, image) = empty_image(&tmp);
+        let oci_dir = ocidir::OciDir::open(dir.try_clone().unwrap()).unwrap();
+
+        let ec_key = PKey::from_ec_key(
+            EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap(),
+        )
+        .unwrap();
+        let ed_key = PKey::generate_ed25519().unwrap();
+        sign(&oci_dir, &image, platform(), "quay.io/org/image", &ec_key).unwrap();
+        sign(&oci_dir, &image, platform(), "quay.io/org/image", &ed_key).unwrap();
+
+        // signatures don't count as images
+        let (desc, _) = crate::utils::read_image_manifest(&oci_dir).unwrap();
+        assert_eq!(desc.digest(), image.digest());
+
+        let signatures = read_signatures(&oci_dir, &image).unwrap();
+        assert_eq!(signatures.len(), 2);
+        assert!(
+            signatures[0]
+                .verify(&image, "quay.io/org/image", &ec_key)
+                .unwrap()
+        );
+        assert!(
+            signatures[1]
+                .verify(&image, "quay.io/org/image", &ed_key)
+                .unwrap()
+        );
+        assert!(
+            !signatures[0]
+                .verify(&image, "quay.io/org/image", &ed_key)
+                .unwrap()
+        );
+        assert!(
+            !signatures[1]
+                .verify(&image, "quay.io/org/image", &ec_key)
+                .unwrap()
+        );
+
+        // a signature of another image isn't valid for this one
+        let other = oci_image::DescriptorBuilder::default()
+            .media_type(oci_image::MediaType::ImageManifest)
+            .digest(
+                "sha256:0000000000000000000000000000000000000000000000000000000000000000"
+                    .parse::<oci_image::Digest>()
+                    .unwrap(),
+            )
+            .size(image.size())
+            .build()
+            .unwrap();
+        assert!(
+            !signatures[0]
+                .verify(&other, "quay.io/org/image", &ec_key)
+                .unwrap()
+        );
+
+        // nor is one naming another repository
+        assert!(
+            !signatures[0]
+                .verify(&image, "quay.io/org/other", &ec_key)
+                .unwrap()
+        );
+        assert!(!signatures[0].verify(&image, "", &ec_key).unwrap());
+
+        remove_signatures(&dir, &[&signatures[0]]).unwrap();
+        let signatures = read_signatures(&oci_dir, &image).unwrap();
+        assert_eq!(signatures.len(), 1);
+        assert!(
+            signatures[0]
+                .verify(&image, "quay.io/org/image", &ed_key)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_cosign_format() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (dir, image) = empty_image(&tmp);
+        let oci_dir = ocidir::OciDir::open(dir.try_clone().unwrap()).unwrap();
+        let key = PKey::generate_ed25519().unwrap();
+        sign(&oci_dir, &image, platform(), "quay.io/org/image", &key).unwrap();
+
+        let index = oci_dir.read_index().unwrap();
+        let desc = &index.manifests()[1];
+        let manifest: oci_image::ImageManifest = oci_dir.read_json_blob(desc).unwrap();
+        assert_eq!(
+            manifest.artifact_type().as_ref().unwrap().to_string(),
+            "application/vnd.dev.cosign.artifact.sig.v1+json"
+        );
+        assert_eq!(
+            manifest.subject().as_ref().unwrap().digest(),
+            image.digest()
+        );
+        let layer = &manifest.layers()[0];
+        assert_eq!(
+            layer.media_type().to_string(),
+            "application/vnd.dev.cosign.simplesigning.v1+json"
+        );
+        assert!(
+            layer
+                .annotations()
+                .as_ref()
+                .unwrap()
+                .contains_key("dev.cosignproject.cosign/signature")
+        );
+
+        let mut payload = String::new();
+        oci_dir
+            .read_blob(layer)
+            .unwrap()
+            .read_to_string(&mut payload)
+            .unwrap();
+        assert_eq!(
+            payload,
+            format!(
+                r#"{{"critical":{{"identity":{{"docker-reference":"quay.io/org/image"}},"image":{{"docker-manifest-digest":"{}"}},"type":"cosign container image signature"}},"optional":null}}"#,
+                image.digest()
+            )
+        );
+    }
+
+    #[test]
+    fn test_unsupported_key() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (dir, image) = empty_image(&tmp);
+        let oci_dir = ocidir::OciDir::open(dir).unwrap();
+        let key = PKey::generate_ed448().unwrap();
+        let err = sign(&oci_dir, &image, platform(), "", &key).unwrap_err();
+        assert!(format!("{err:#}").contains("unsupported key type"));
+    }
+}
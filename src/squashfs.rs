@@ -0,0 +1,1201 @@
+//! Reading squashfs images given as the rootfs, without mounting them.
+//!
+//! Only what's needed to extract a rootfs is read: the inodes, directories,
+//! ids, fragments and xattrs of version 4.0 images compressed with gzip, xz or
+//! lzma, which covers what `mksquashfs` writes by default. The on-disk format
+//! is described in the kernel's `Documentation/filesystems/squashfs.rst`.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+use std::rc::Rc;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::components::{FileInfo, FileType, XattrInterner, Xattrs};
+use crate::rootfs_image::{Fields, ImageEntry, VisitEntry, decode_dev, entry_name};
+
+const SUPERBLOCK_SIZE: usize = 96;
+
+/// Maximum size of a metadata block, uncompressed.
+const METADATA_SIZE: usize = 8192;
+
+/// Flag of the header of metadata blocks stored uncompressed.
+const METADATA_UNCOMPRESSED: u16 = 1 << 15;
+
+/// Flag of the size of data blocks stored uncompressed.
+const DATA_UNCOMPRESSED: u32 = 1 << 24;
+
+/// Fragment and xattr index of the inodes which have none.
+const NO_INDEX: u32 = u32::MAX;
+
+/// Xattr type flag of values stored out of line, i.e. shared.
+const XATTR_VALUE_OOL: u16 = 0x100;
+
+/// Inode types, the extended ones having more fields.
+const DIR: u16 = 1;
+const FILE: u16 = 2;
+const SYMLINK: u16 = 3;
+const BLOCK_DEVICE: u16 = 4;
+const CHAR_DEVICE: u16 = 5;
+const FIFO: u16 = 6;
+const SOCKET: u16 = 7;
+const EXTENDED: u16 = 7;
+
+/// Read the squashfs image `file`, calling `visit` for each of its entries.
+pub(crate) fn read_image(
+    file: &File,
+    interner: &mut XattrInterner,
+    visit: &mut VisitEntry,
+) -> Result<()> {
+    let mut image = Image::open(file).context("reading squashfs superblock")?;
+    let root = image
+        .inode(image.superblock.root_inode)
+        .context("reading root inode")?;
+    anyhow::ensure!(
+        matches!(root.data, InodeData::Dir { .. }),
+        "the root of the image isn't a directory"
+    );
+    image.walk(root, interner, visit)
+}
+
+/// Compression of the blocks of an image.
+#[derive(Debug, Clone, Copy)]
+enum Compression {
+    Gzip,
+    Lzma,
+    Xz,
+}
+
+impl Compression {
+    fn from_id(id: u16) -> Result<Self> {
+        let name = match id {
+            1 => return Ok(Self::Gzip),
+            2 => return Ok(Self::Lzma),
+            4 => return Ok(Self::Xz),
+            3 => "lzo",
+            5 => "lz4",
+            6 => "zstd",
+            _ => anyhow::bail!("unknown compression {id}"),
+        };
+        anyhow::bail!("images compressed with {name} aren't supported; use gzip or xz")
+    }
+
+    /// Decompress a block of at most `max_size` bytes.
+    fn decompress(self, data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+        let decoder: Box<dyn Read + '_> = match self {
+            Self::Gzip => Box::new(flate2::read::ZlibDecoder::new(data)),
+            Self::Lzma => {
+                let stream = xz2::stream::Stream::new_lzma_decoder(u64::MAX)
+                    .context("creating lzma decoder")?;
+                Box::new(xz2::read::XzDecoder::new_stream(data, stream))
+            }
+            Self::Xz => Box::new(xz2::read::XzDecoder::new(data)),
+        };
+        let mut block = Vec::new();
+        decoder
+            .take(max_size as u64 + 1)
+            .read_to_end(&mut block)
+            .context("decompressing block")?;
+        anyhow::ensure!(block.len() <= max_size, "decompressed block is too large");
+        Ok(block)
+    }
+}
+
+/// The fields of the superblock we need.
+struct Superblock {
+    block_size: u32,
+    compression: Compression,
+    root_inode: u64,
+    id_count: u16,
+    fragment_count: u32,
+    id_table: u64,
+    xattr_id_table: u64,
+    inode_table: u64,
+    directory_table: u64,
+    fragment_table: u64,
+}
+
+impl Superblock {
+    fn parse(data: &[u8]) -> Result<Self> {
+        let mut fields = Fields::new(data);
+        anyhow::ensure!(fields.bytes(4)? == b"hsqs", "not a squashfs image");
+        let _inode_count = fields.u32()?;
+        let _mod_time = fields.u32()?;
+        let block_size = fields.u32()?;
+        let fragment_count = fields.u32()?;
+        let compression = fields.u16()?;
+        let _block_log = fields.u16()?;
+        let _flags = fields.u16()?;
+        let id_count = fields.u16()?;
+        let version = (fields.u16()?, fields.u16()?);
+        anyhow::ensure!(
+            version == (4, 0),
+            "unsupported version {}.{}",
+            version.0,
+            version.1
+        );
+        anyhow::ensure!(
+            block_size.is_power_of_two() && (4096..=1 << 20).contains(&block_size),
+            "invalid block size {block_size}"
+        );
+        let root_inode = fields.u64()?;
+        let _bytes_used = fields.u64()?;
+        Ok(Self {
+            block_size,
+            compression: Compression::from_id(compression)?,
+            root_inode,
+            id_count,
+            fragment_count,
+            id_table: fields.u64()?,
+            xattr_id_table: fields.u64()?,
+            inode_table: fields.u64()?,
+            directory_table: fields.u64()?,
+            fragment_table: fields.u64()?,
+        })
+    }
+}
+
+/// Read the metadata block at `position`, and return it with the position of
+/// the next one.
+fn read_metadata_block(
+    file: &File,
+    compression: Compression,
+    position: u64,
+) -> Result<(Vec<u8>, u64)> {
+    let mut header = [0u8; 2];
+    file.read_exact_at(&mut header, position)
+        .with_context(|| format!("reading metadata block at {position}"))?;
+    let header = u16::from_le_bytes(header);
+    let size = header & !METADATA_UNCOMPRESSED;
+    let mut data = vec![0u8; size.into()];
+    file.read_exact_at(&mut data, position + 2)
+        .with_context(|| format!("reading metadata block at {position}"))?;
+    if header & METADATA_UNCOMPRESSED == 0 {
+        data = compression
+            .decompress(&data, METADATA_SIZE)
+            .with_context(|| format!("reading metadata block at {position}"))?;
+    }
+    Ok((data, position + 2 + u64::from(size)))
+}
+
+/// Read the `count` entries of `size` bytes of the lookup table whose block
+/// positions are listed at `start`.
+fn read_lookup_table(
+    file: &File,
+    compression: Compression,
+    start: u64,
+    count: usize,
+    size: usize,
+) -> Result<Vec<u8>> {
+    let len = count * size;
+    let mut positions = vec![0u8; len.div_ceil(METADATA_SIZE) * 8];
+    file.read_exact_at(&mut positions, start)
+        .with_context(|| format!("reading lookup table at {start}"))?;
+    let mut table = Vec::new();
+    for position in positions.chunks_exact(8) {
+        let position = u64::from_le_bytes(position.try_into().unwrap());
+        table.extend(read_metadata_block(file, compression, position)?.0);
+    }
+    anyhow::ensure!(table.len() >= len, "truncated lookup table at {start}");
+    table.truncate(len);
+    Ok(table)
+}
+
+/// Consecutive metadata blocks starting at `start`, which inodes and
+/// directory listings may span, decompressed as they're read.
+struct MetadataTable<'a> {
+    file: &'a File,
+    compression: Compression,
+    start: u64,
+    /// The blocks read so far, by their offset from `start`, with the offset
+    /// of the next one.
+    blocks: HashMap<u64, Rc<(Vec<u8>, u64)>>,
+}
+
+impl<'a> MetadataTable<'a> {
+    fn new(file: &'a File, compression: Compression, start: u64) -> Self {
+        Self {
+            file,
+            compression,
+            start,
+            blocks: HashMap::new(),
+        }
+    }
+
+    fn block(&mut self, offset: u64) -> Result<Rc<(Vec<u8>, u64)>> {
+        if let Some(block) = self.blocks.get(&offset) {
+            return Ok(block.clone());
+        }
+        let position = self
+            .start
+            .checked_add(offset)
+            .context("invalid reference")?;
+        let (data, next) = read_metadata_block(self.file, self.compression, position)?;
+        let block = Rc::new((data, next - self.start));
+        self.blocks.insert(offset, block.clone());
+        Ok(block)
+    }
+
+    /// Read `len` bytes from `offset` in the block at `block`, continuing in
+    /// the next blocks if needed, and return them with where they end.
+    fn read(
+        &mut self,
+        mut block: u64,
+        mut offset: usize,
+        len: usize,
+    ) -> Result<(Vec<u8>, (u64, usize))> {
+        let mut data = Vec::new();
+        while data.len() < len {
+            let current = self.block(block)?;
+            let (content, next) = &*current;
+            if offset >= content.len() {
+                offset -= content.len();
+                block = *next;
+                continue;
+            }
+            let n = (len - data.len()).min(content.len() - offset);
+            data.extend_from_slice(&content[offset..offset + n]);
+            offset += n;
+        }
+        Ok((data, (block, offset)))
+    }
+
+    /// Returns a reader of the metadata at `reference`, which has the offset
+    /// of the block in the high bits and the offset in it in the low 16 bits.
+    fn reader(&mut self, reference: u64) -> MetadataReader<'_, 'a> {
+        MetadataReader {
+            table: self,
+            position: (reference >> 16, (reference & 0xffff) as usize),
+        }
+    }
+}
+
+/// Reads metadata in order from a [`MetadataTable`].
+struct MetadataReader<'t, 'a> {
+    table: &'t mut MetadataTable<'a>,
+    position: (u64, usize),
+}
+
+impl MetadataReader<'_, '_> {
+    fn bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let (data, position) = self.table.read(self.position.0, self.position.1, len)?;
+        self.position = position;
+        Ok(data)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Fields::new(&self.bytes(4)?).u32()
+    }
+}
+
+/// An inode, with the fields we need.
+struct Inode {
+    /// The type of the inode, basic or not.
+    kind: u16,
+    permissions: u16,
+    uid_index: u16,
+    gid_index: u16,
+    mtime: u32,
+    number: u32,
+    xattr_index: u32,
+    data: InodeData,
+}
+
+enum InodeData {
+    Dir {
+        block: u32,
+        offset: u16,
+        size: u32,
+    },
+    File {
+        blocks_start: u64,
+        size: u64,
+        block_sizes: Vec<u32>,
+        /// The index of the fragment with the tail of the file, and the offset
+        /// of the tail in it.
+        fragment: Option<(u32, u32)>,
+    },
+    Symlink(Vec<u8>),
+    Device(u32),
+    Ipc,
+}
+
+/// An opened image, with its lookup tables.
+struct Image<'a> {
+    file: &'a File,
+    superblock: Superblock,
+    ids: Vec<u32>,
+    /// The position and size of each fragment block.
+    fragments: Vec<(u64, u32)>,
+    /// The position and count of the xattrs of each xattr index.
+    xattr_ids: Vec<(u64, u32)>,
+    inodes: MetadataTable<'a>,
+    directories: MetadataTable<'a>,
+    xattrs: MetadataTable<'a>,
+    /// Xattrs are shared by many inodes.
+    xattr_cache: HashMap<u32, Xattrs>,
+    /// Files are mostly read in the order of their fragments, so the last one
+    /// is kept.
+    fragment_cache: Option<(u32, Vec<u8>)>,
+}
+
+impl<'a> Image<'a> {
+    fn open(file: &'a File) -> Result<Self> {
+        let mut data = [0u8; SUPERBLOCK_SIZE];
+        file.read_exact_at(&mut data, 0)?;
+        let superblock = Superblock::parse(&data)?;
+        let compression = superblock.compression;
+
+        let ids = read_lookup_table(
+            file,
+            compression,
+            superblock.id_table,
+            superblock.id_count.into(),
+            4,
+        )
+        .context("reading id table")?;
+        let ids = ids
+            .chunks_exact(4)
+            .map(|id| u32::from_le_bytes(id.try_into().unwrap()))
+            .collect();
+
+        let fragments = if superblock.fragment_count == 0 {
+            Vec::new()
+        } else {
+            read_lookup_table(
+                file,
+                compression,
+                superblock.fragment_table,
+                superblock.fragment_count as usize,
+                16,
+            )
+            .context("reading fragment table")?
+            .chunks_exact(16)
+            .map(|entry| {
+                let mut fields = Fields::new(entry);
+                Ok((fields.u64()?, fields.u32()?))
+            })
+            .collect::<Result<_>>()?
+        };
+
+        let (xattr_ids, xattr_table) = if superblock.xattr_id_table == u64::MAX {
+            (Vec::new(), 0)
+        } else {
+            let mut header = [0u8; 16];
+            file.read_exact_at(&mut header, superblock.xattr_id_table)
+                .context("reading xattr id table")?;
+            let mut fields = Fields::new(&header);
+            let xattr_table = fields.u64()?;
+            let count = fields.u32()?;
+            let xattr_ids = read_lookup_table(
+                file,
+                compression,
+                superblock.xattr_id_table + 16,
+                count as usize,
+                16,
+            )
+            .context("reading xattr id table")?
+            .chunks_exact(16)
+            .map(|entry| {
+                let mut fields = Fields::new(entry);
+                Ok((fields.u64()?, fields.u32()?))
+            })
+            .collect::<Result<_>>()?;
+            (xattr_ids, xattr_table)
+        };
+
+        Ok(Self {
+            file,
+            inodes: MetadataTable::new(file, compression, superblock.inode_table),
+            directories: MetadataTable::new(file, compression, superblock.directory_table),
+            xattrs: MetadataTable::new(file, compression, xattr_table),
+            superblock,
+            ids,
+            fragments,
+            xattr_ids,
+            xattr_cache: HashMap::new(),
+            fragment_cache: None,
+        })
+    }
+
+    /// Read the inode at `reference`.
+    fn inode(&mut self, reference: u64) -> Result<Inode> {
+        let block_size = u64::from(self.superblock.block_size);
+        let mut reader = self.inodes.reader(reference);
+        let header = reader.bytes(16)?;
+        let mut fields = Fields::new(&header);
+        let kind = fields.u16()?;
+        let permissions = fields.u16()?;
+        let uid_index = fields.u16()?;
+        let gid_index = fields.u16()?;
+        let mtime = fields.u32()?;
+        let number = fields.u32()?;
+        let extended = kind > EXTENDED;
+        let base_kind = if extended { kind - EXTENDED } else { kind };
+
+        let mut xattr_index = NO_INDEX;
+        let data = match base_kind {
+            DIR if !extended => {
+                let data = reader.bytes(16)?;
+                let mut fields = Fields::new(&data);
+                let block = fields.u32()?;
+                let _link_count = fields.u32()?;
+                let size = fields.u16()?.into();
+                let offset = fields.u16()?;
+                InodeData::Dir {
+                    block,
+                    offset,
+                    size,
+                }
+            }
+            DIR => {
+                let data = reader.bytes(24)?;
+                let mut fields = Fields::new(&data);
+                let _link_count = fields.u32()?;
+                let size = fields.u32()?;
+                let block = fields.u32()?;
+                let _parent = fields.u32()?;
+                let _index_count = fields.u16()?;
+                let offset = fields.u16()?;
+                xattr_index = fields.u32()?;
+                InodeData::Dir {
+                    block,
+                    offset,
+                    size,
+                }
+            }
+            FILE => {
+                let (blocks_start, size, fragment, fragment_offset) = if extended {
+                    let data = reader.bytes(40)?;
+                    let mut fields = Fields::new(&data);
+                    let blocks_start = fields.u64()?;
+                    let size = fields.u64()?;
+                    let _sparse = fields.u64()?;
+                    let _link_count = fields.u32()?;
+                    let fragment = fields.u32()?;
+                    let fragment_offset = fields.u32()?;
+                    xattr_index = fields.u32()?;
+                    (blocks_start, size, fragment, fragment_offset)
+                } else {
+                    let data = reader.bytes(16)?;
+                    let mut fields = Fields::new(&data);
+                    let blocks_start = fields.u32()?.into();
+                    let fragment = fields.u32()?;
+                    let fragment_offset = fields.u32()?;
+                    let size = fields.u32()?.into();
+                    (blocks_start, size, fragment, fragment_offset)
+                };
+                // the tail of the file is in a fragment if it has one
+                let block_count = if fragment == NO_INDEX {
+                    size.div_ceil(block_size)
+                } else {
+                    size / block_size
+                };
+                let block_sizes = reader.bytes(usize::try_from(block_count * 4)?)?;
+                InodeData::File {
+                    blocks_start,
+                    size,
+                    block_sizes: block_sizes
+                        .chunks_exact(4)
+                        .map(|size| u32::from_le_bytes(size.try_into().unwrap()))
+                        .collect(),
+                    fragment: (fragment != NO_INDEX).then_some((fragment, fragment_offset)),
+                }
+            }
+            SYMLINK => {
+                let _link_count = reader.u32()?;
+                let size = reader.u32()?;
+                anyhow::ensure!(size <= 4096, "symlink target is too long");
+                let target = reader.bytes(size as usize)?;
+                if extended {
+                    xattr_index = reader.u32()?;
+                }
+                InodeData::Symlink(target)
+            }
+            BLOCK_DEVICE | CHAR_DEVICE => {
+                let _link_count = reader.u32()?;
+                let dev = reader.u32()?;
+                if extended {
+                    xattr_index = reader.u32()?;
+                }
+                InodeData::Device(dev)
+            }
+            FIFO | SOCKET => {
+                let _link_count = reader.u32()?;
+                if extended {
+                    xattr_index = reader.u32()?;
+                }
+                InodeData::Ipc
+            }
+            _ => anyhow::bail!("unknown inode type {kind}"),
+        };
+        Ok(Inode {
+            kind: base_kind,
+            permissions,
+            uid_index,
+            gid_index,
+            mtime,
+            number,
+            xattr_index,
+            data,
+        })
+    }
+
+    /// Returns the names of the entries of a directory, with the references
+    /// of their inodes.
+    fn dir_entries(&mut self, block: u32, offset: u16, size: u32) -> Result<Vec<(Vec<u8>, u64)>> {
+        // the size counts the `.` and `..` entries, which aren't stored
+        let mut remaining = size.saturating_sub(3) as usize;
+        let mut reader = self
+            .directories
+            .reader((u64::from(block) << 16) | u64::from(offset));
+        let mut entries = Vec::new();
+        while remaining > 0 {
+            let header = reader.bytes(12)?;
+            let mut fields = Fields::new(&header);
+            // stored minus one
+            let count = fields.u32()?;
+            let start = fields.u32()?;
+            anyhow::ensure!(count < 256, "invalid directory header");
+            remaining = remaining
+                .checked_sub(12)
+                .context("truncated directory listing")?;
+            for _ in 0..=count {
+                let entry = reader.bytes(8)?;
+                let mut fields = Fields::new(&entry);
+                let offset = fields.u16()?;
+                let _inode_offset = fields.u16()?;
+                let _kind = fields.u16()?;
+                let name_size = usize::from(fields.u16()?) + 1;
+                let name = reader.bytes(name_size)?;
+                remaining = remaining
+                    .checked_sub(8 + name_size)
+                    .context("truncated directory listing")?;
+                entries.push((name, (u64::from(start) << 16) | u64::from(offset)));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn id(&self, index: u16) -> Result<u32> {
+        self.ids
+            .get(usize::from(index))
+            .copied()
+            .with_context(|| format!("invalid id index {index}"))
+    }
+
+    fn xattrs(&mut self, index: u32, interner: &mut XattrInterner) -> Result<Xattrs> {
+        if index == NO_INDEX {
+            return Ok(Xattrs::default());
+        }
+        if let Some(xattrs) = self.xattr_cache.get(&index) {
+            return Ok(xattrs.clone());
+        }
+        let &(reference, count) = self
+            .xattr_ids
+            .get(index as usize)
+            .with_context(|| format!("invalid xattr index {index}"))?;
+        let mut reader = self.xattrs.reader(reference);
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let header = reader.bytes(4)?;
+            let mut fields = Fields::new(&header);
+            let kind = fields.u16()?;
+            let name_size = fields.u16()?;
+            let prefix = match kind & 0xff {
+                0 => "user.",
+                1 => "trusted.",
+                2 => "security.",
+                other => anyhow::bail!("unknown xattr prefix {other}"),
+            };
+            let name = reader.bytes(name_size.into())?;
+            let name = String::from_utf8([prefix.as_bytes(), &name].concat())
+                .context("xattr name is not UTF-8")?;
+            let value_size = reader.u32()?;
+            anyhow::ensure!(value_size <= 1 << 16, "{name}: xattr value is too large");
+            let value = reader.bytes(value_size as usize)?;
+            entries.push((name, kind & XATTR_VALUE_OOL != 0, value));
+        }
+        let mut xattrs = Vec::new();
+        for (name, out_of_line, mut value) in entries {
+            // shared values are stored once, elsewhere
+            if out_of_line {
+                let reference = u64::from_le_bytes(
+                    value
+                        .as_slice()
+                        .try_into()
+                        .with_context(|| format!("{name}: invalid xattr value reference"))?,
+                );
+                let mut reader = self.xattrs.reader(reference);
+                let value_size = reader.u32()?;
+                anyhow::ensure!(value_size <= 1 << 16, "{name}: xattr value is too large");
+                value = reader.bytes(value_size as usize)?;
+            }
+            xattrs.push((name, value));
+        }
+        let xattrs = interner.intern(xattrs);
+        self.xattr_cache.insert(index, xattrs.clone());
+        Ok(xattrs)
+    }
+
+    /// Returns the decompressed fragment block `index`.
+    fn fragment(&mut self, index: u32) -> Result<&[u8]> {
+        if self
+            .fragment_cache
+            .as_ref()
+            .is_none_or(|(i, _)| *i != index)
+        {
+            let &(position, size) = self
+                .fragments
+                .get(index as usize)
+                .with_context(|| format!("invalid fragment index {index}"))?;
+            let block = read_block(
+                self.file,
+                self.superblock.compression,
+                position,
+                size,
+                self.superblock.block_size as usize,
+            )
+            .with_context(|| format!("reading fragment {index}"))?;
+            self.fragment_cache = Some((index, block));
+        }
+        Ok(&self.fragment_cache.as_ref().unwrap().1)
+    }
+
+    /// Visit the root directory, described by `root`, and everything under
+    /// it, parents first.
+    ///
+    /// The directories to visit are kept on the heap rather than recursing,
+    /// so that deeply nested images can't overflow the stack, and the inode
+    /// numbers of those visited are kept to stop on loops in corrupted images.
+    fn walk(
+        &mut self,
+        root: Inode,
+        interner: &mut XattrInterner,
+        visit: &mut VisitEntry,
+    ) -> Result<()> {
+        let mut dirs = HashSet::from([root.number]);
+        let mut stack = vec![(Utf8PathBuf::from("/"), root)];
+        while let Some((path, inode)) = stack.pop() {
+            let InodeData::Dir {
+                block,
+                offset,
+                size,
+            } = inode.data
+            else {
+                unreachable!("not a directory");
+            };
+            let entries = self
+                .dir_entries(block, offset, size)
+                .with_context(|| format!("reading directory {path}"))?;
+            self.emit(&path, inode, interner, visit)?;
+            let mut subdirs = Vec::new();
+            for (name, reference) in entries {
+                let child_path = path.join(entry_name(&path, &name)?);
+                let child = self
+                    .inode(reference)
+                    .with_context(|| format!("reading inode of {child_path}"))?;
+                if matches!(child.data, InodeData::Dir { .. }) {
+                    anyhow::ensure!(dirs.insert(child.number), "{child_path}: directory loop");
+                    subdirs.push((child_path, child));
+                } else {
+                    self.emit(&child_path, child, interner, visit)?;
+                }
+            }
+            // visit them in order
+            stack.extend(subdirs.into_iter().rev());
+        }
+        Ok(())
+    }
+
+    /// Call `visit` for the entry at `path`, described by `inode`.
+    fn emit(
+        &mut self,
+        path: &Utf8Path,
+        inode: Inode,
+        interner: &mut XattrInterner,
+        visit: &mut VisitEntry,
+    ) -> Result<()> {
+        let (file_type, type_bits) = match inode.kind {
+            DIR => (FileType::Directory, libc::S_IFDIR),
+            FILE => (FileType::File, libc::S_IFREG),
+            SYMLINK => (FileType::Symlink, libc::S_IFLNK),
+            BLOCK_DEVICE => (FileType::BlockDevice, libc::S_IFBLK),
+            CHAR_DEVICE => (FileType::CharDevice, libc::S_IFCHR),
+            FIFO => (FileType::Fifo, libc::S_IFIFO),
+            // sockets, which tar can't represent
+            _ => return Ok(()),
+        };
+        let mut info = FileInfo {
+            file_type,
+            mode: type_bits | u32::from(inode.permissions & 0o7777),
+            size: 0,
+            uid: self.id(inode.uid_index)?,
+            gid: self.id(inode.gid_index)?,
+            mtime: inode.mtime.into(),
+            ino: 0,
+            nlink: 1,
+            xattrs: self
+                .xattrs(inode.xattr_index, interner)
+                .with_context(|| format!("reading xattrs of {path}"))?,
+            extra: None,
+        };
+        let mut symlink_target = None;
+        let mut content: Box<dyn Read + '_> = Box::new(std::io::empty());
+        match inode.data {
+            InodeData::File {
+                blocks_start,
+                size,
+                block_sizes,
+                fragment,
+            } => {
+                info.size = size;
+                let block_size = u64::from(self.superblock.block_size);
+                let tail = match fragment {
+                    Some((index, offset)) => {
+                        let fragment = self
+                            .fragment(index)
+                            .with_context(|| format!("reading {path}"))?;
+                        let start = offset as usize;
+                        let end = start + (size % block_size) as usize;
+                        fragment
+                            .get(start..end)
+                            .with_context(|| format!("{path}: invalid fragment offset"))?
+                            .to_vec()
+                    }
+                    None => Vec::new(),
+                };
+                content = Box::new(Content {
+                    file: self.file,
+                    compression: self.superblock.compression,
+                    block_size,
+                    position: blocks_start,
+                    block_sizes: block_sizes.into_iter(),
+                    remaining: size - tail.len() as u64,
+                    tail: Some(tail),
+                    buffer: Vec::new(),
+                    consumed: 0,
+                });
+            }
+            InodeData::Symlink(target) => {
+                info.size = target.len() as u64;
+                let target = String::from_utf8(target)
+                    .with_context(|| format!("symlink {path} target is not UTF-8"))?;
+                symlink_target = Some(Utf8PathBuf::from(target));
+            }
+            InodeData::Device(dev) => info.set_rdev(decode_dev(dev)),
+            InodeData::Dir { .. } | InodeData::Ipc => {}
+        }
+        let entry = ImageEntry {
+            path: path.to_owned(),
+            info,
+            inode: inode.number.into(),
+            symlink_target,
+        };
+        visit(entry, &mut content)
+    }
+}
+
+/// Read the data block of `size`, as found in block lists and the fragment
+/// table, at `position`.
+fn read_block(
+    file: &File,
+    compression: Compression,
+    position: u64,
+    size: u32,
+    block_size: usize,
+) -> Result<Vec<u8>> {
+    let on_disk = size & !DATA_UNCOMPRESSED;
+    anyhow::ensure!(
+        on_disk as usize <= block_size,
+        "invalid block size {on_disk}"
+    );
+    let mut data = vec![0u8; on_disk as usize];
+    file.read_exact_at(&mut data, position)
+        .with_context(|| format!("reading block at {position}"))?;
+    if size & DATA_UNCOMPRESSED == 0 {
+        data = compression.decompress(&data, block_size)?;
+    }
+    Ok(data)
+}
+
+/// The content of a file, read block by block.
+struct Content<'a> {
+    file: &'a File,
+    compression: Compression,
+    block_size: u64,
+    /// Position of the next block.
+    position: u64,
+    block_sizes: std::vec::IntoIter<u32>,
+    /// Size of the content still to read from the blocks.
+    remaining: u64,
+    /// The tail of the file from its fragment, read after the blocks.
+    tail: Option<Vec<u8>>,
+    buffer: Vec<u8>,
+    consumed: usize,
+}
+
+impl Content<'_> {
+    /// Read the next block into the buffer.
+    fn fill(&mut self) -> Result<()> {
+        self.consumed = 0;
+        self.buffer = match self.block_sizes.next() {
+            Some(size) => {
+                let len = self.block_size.min(self.remaining);
+                let block = if size & !DATA_UNCOMPRESSED == 0 {
+                    // sparse block
+                    vec![0u8; len as usize]
+                } else {
+                    read_block(
+                        self.file,
+                        self.compression,
+                        self.position,
+                        size,
+                        self.block_size as usize,
+                    )?
+                };
+                anyhow::ensure!(block.len() as u64 == len, "invalid block size");
+                self.position += u64::from(size & !DATA_UNCOMPRESSED);
+                self.remaining -= len;
+                block
+            }
+            None => self.tail.take().unwrap_or_default(),
+        };
+        Ok(())
+    }
+}
+
+impl Read for Content<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.consumed == self.buffer.len() {
+            self.fill().map_err(std::io::Error::other)?;
+        }
+        let n = buf.len().min(self.buffer.len() - self.consumed);
+        buf[..n].copy_from_slice(&self.buffer[self.consumed..self.consumed + n]);
+        self.consumed += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    /// Uncompressed metadata blocks holding `data`.
+    fn metadata_blocks(data: &[u8]) -> Vec<u8> {
+        let mut blocks = Vec::new();
+        for chunk in data.chunks(METADATA_SIZE) {
+            blocks.extend((chunk.len() as u16 | METADATA_UNCOMPRESSED).to_le_bytes());
+            blocks.extend(chunk);
+        }
+        blocks
+    }
+
+    /// The reference of the metadata at `offset` in [`metadata_blocks`].
+    fn reference(offset: usize) -> u64 {
+        let block = offset / METADATA_SIZE * (METADATA_SIZE + 2);
+        ((block as u64) << 16) | (offset % METADATA_SIZE) as u64
+    }
+
+    /// The inode and directory tables of an image, with basic inodes of
+    /// empty files and directories only.
+    #[derive(Default)]
+    struct Tables {
+        inodes: Vec<u8>,
+        directories: Vec<u8>,
+        count: u32,
+    }
+
+    impl Tables {
+        /// Returns the reference of the next inode, of `skip` bytes after the
+        /// end of the table.
+        fn next(&self, skip: usize) -> u64 {
+            reference(self.inodes.len() + skip)
+        }
+
+        fn header(&mut self, kind: u16, permissions: u16) -> u64 {
+            let reference = self.next(0);
+            self.count += 1;
+            self.inodes.extend(kind.to_le_bytes());
+            self.inodes.extend(permissions.to_le_bytes());
+            // uid and gid indexes, and mtime
+            self.inodes.extend([0u8; 8]);
+            self.inodes.extend(self.count.to_le_bytes());
+            reference
+        }
+
+        fn file(&mut self) -> u64 {
+            let reference = self.header(FILE, 0o644);
+            // blocks start, fragment, offset in it and size
+            self.inodes.extend(0u32.to_le_bytes());
+            self.inodes.extend(NO_INDEX.to_le_bytes());
+            self.inodes.extend([0u8; 8]);
+            reference
+        }
+
+        /// Add a directory listing `entries`, with the references of their
+        /// inodes. Its inode takes 32 bytes.
+        fn dir(&mut self, entries: &[(&str, u64)]) -> u64 {
+            let start = self.directories.len();
+            for (name, inode) in entries {
+                // a header per entry, which is valid if wasteful
+                self.directories.extend(0u32.to_le_bytes());
+                self.directories
+                    .extend(((inode >> 16) as u32).to_le_bytes());
+                self.directories.extend(0u32.to_le_bytes());
+                self.directories
+                    .extend(((inode & 0xffff) as u16).to_le_bytes());
+                // inode number offset and type
+                self.directories.extend([0u8; 4]);
+                self.directories
+                    .extend((name.len() as u16).wrapping_sub(1).to_le_bytes());
+                self.directories.extend(name.as_bytes());
+            }
+            let size = self.directories.len() - start + 3;
+            let listing = reference(start);
+            let reference = self.header(DIR, 0o755);
+            self.inodes.extend(((listing >> 16) as u32).to_le_bytes());
+            // link count
+            self.inodes.extend(2u32.to_le_bytes());
+            self.inodes.extend((size as u16).to_le_bytes());
+            self.inodes
+                .extend(((listing & 0xffff) as u16).to_le_bytes());
+            // parent inode number
+            self.inodes.extend(0u32.to_le_bytes());
+            reference
+        }
+
+        /// Returns an image with the directory at `root` as its root, and
+        /// the position of its directory table.
+        fn image(&self, root: u64) -> (Vec<u8>, usize) {
+            let mut data = vec![0u8; SUPERBLOCK_SIZE];
+            let inode_table = data.len();
+            data.extend(metadata_blocks(&self.inodes));
+            let directory_table = data.len();
+            data.extend(metadata_blocks(&self.directories));
+            let ids = data.len();
+            data.extend(metadata_blocks(&0u32.to_le_bytes()));
+            let id_table = data.len();
+            data.extend((ids as u64).to_le_bytes());
+
+            let superblock = &mut data[..SUPERBLOCK_SIZE];
+            superblock[..4].copy_from_slice(b"hsqs");
+            superblock[4..8].copy_from_slice(&self.count.to_le_bytes());
+            superblock[12..16].copy_from_slice(&4096u32.to_le_bytes());
+            // gzip
+            superblock[20..22].copy_from_slice(&1u16.to_le_bytes());
+            superblock[22..24].copy_from_slice(&12u16.to_le_bytes());
+            // one id
+            superblock[26..28].copy_from_slice(&1u16.to_le_bytes());
+            superblock[28..30].copy_from_slice(&4u16.to_le_bytes());
+            superblock[32..40].copy_from_slice(&root.to_le_bytes());
+            for (i, position) in [
+                id_table as u64,
+                u64::MAX,
+                inode_table as u64,
+                directory_table as u64,
+                u64::MAX,
+                u64::MAX,
+            ]
+            .into_iter()
+            .enumerate()
+            {
+                superblock[48 + i * 8..56 + i * 8].copy_from_slice(&position.to_le_bytes());
+            }
+            (data, directory_table)
+        }
+    }
+
+    /// Read the image `data` and return the paths of its entries.
+    fn read(data: &[u8]) -> Result<Vec<Utf8PathBuf>> {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(data).unwrap();
+        let mut paths = Vec::new();
+        read_image(
+            &file,
+            &mut XattrInterner::default(),
+            &mut |entry, content| {
+                std::io::copy(content, &mut std::io::sink())?;
+                paths.push(entry.path);
+                Ok(())
+            },
+        )?;
+        Ok(paths)
+    }
+
+    fn read_err(data: &[u8]) -> String {
+        match read(data) {
+            Ok(paths) => panic!("read {paths:?}"),
+            Err(err) => format!("{err:#}"),
+        }
+    }
+
+    #[test]
+    fn test_read_image() {
+        let mut tables = Tables::default();
+        let file = tables.file();
+        let empty = tables.dir(&[]);
+        let root = tables.dir(&[("file", file), ("sub", empty)]);
+        let (image, _) = tables.image(root);
+        assert_eq!(read(&image).unwrap(), ["/", "/file", "/sub"]);
+
+        let patched = |offset: usize, value: &[u8]| {
+            let mut image = image.clone();
+            image[offset..offset + value.len()].copy_from_slice(value);
+            read_err(&image)
+        };
+        let err = patched(28, &[3, 0, 1, 0]);
+        assert!(err.contains("unsupported version 3.1"), "{err}");
+        let err = patched(20, &6u16.to_le_bytes());
+        assert!(
+            err.contains("compressed with zstd aren't supported"),
+            "{err}"
+        );
+        let err = patched(20, &99u16.to_le_bytes());
+        assert!(err.contains("unknown compression 99"), "{err}");
+        let err = patched(12, &1000u32.to_le_bytes());
+        assert!(err.contains("invalid block size 1000"), "{err}");
+        // the type of the file's inode
+        let err = patched(SUPERBLOCK_SIZE + 2, &99u16.to_le_bytes());
+        assert!(err.contains("unknown inode type 99"), "{err}");
+        // the uid index of the file's inode
+        let err = patched(SUPERBLOCK_SIZE + 2 + 4, &1u16.to_le_bytes());
+        assert!(err.contains("invalid id index 1"), "{err}");
+    }
+
+    #[test]
+    fn test_read_image_invalid_dirs() {
+        let mut tables = Tables::default();
+        let root = tables.dir(&[("file", 1 << 40)]);
+        assert!(read(&tables.image(root).0).is_err());
+
+        let mut tables = Tables::default();
+        let file = tables.file();
+        let root = tables.dir(&[("file", file)]);
+        let (mut image, directory_table) = tables.image(root);
+        // the entry count of the first directory header
+        image[directory_table + 2..directory_table + 6].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = read_err(&image);
+        assert!(err.contains("invalid directory header"), "{err}");
+
+        let mut tables = Tables::default();
+        let sub = tables.next(0);
+        let sub = tables.dir(&[("sub", sub)]);
+        let root = tables.dir(&[("sub", sub)]);
+        let err = read_err(&tables.image(root).0);
+        assert!(err.contains("/sub/sub: directory loop"), "{err}");
+
+        let mut tables = Tables::default();
+        // the root comes right after
+        let root = tables.next(32);
+        let sub = tables.dir(&[("root", root)]);
+        let root = tables.dir(&[("sub", sub)]);
+        let err = read_err(&tables.image(root).0);
+        assert!(err.contains("/sub/root: directory loop"), "{err}");
+
+        for name in ["", ".", "a/b"] {
+            let mut tables = Tables::default();
+            let file = tables.file();
+            let root = tables.dir(&[(name, file)]);
+            assert!(read(&tables.image(root).0).is_err(), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn test_read_image_deep() {
+        // walked without recursing, however deep
+        let mut tables = Tables::default();
+        let mut dir = tables.dir(&[]);
+        for _ in 0..10_000 {
+            dir = tables.dir(&[("a", dir)]);
+        }
+        assert_eq!(read(&tables.image(dir).0).unwrap().len(), 10_001);
+    }
+
+    #[test]
+    fn test_read_image_truncated() {
+        for name in ["rootfs-gzip.squashfs", "rootfs-xz.squashfs"] {
+            let image = std::fs::read(
+                Utf8Path::new(env!("CARGO_MANIFEST_DIR"))
+                    .join("tests/fixtures")
+                    .join(name),
+            )
+            .unwrap();
+            // images are padded after the bytes used
+            let used = u64::from_le_bytes(image[40..48].try_into().unwrap());
+            for len in (0..used as usize).step_by(61) {
+                assert!(read(&image[..len]).is_err(), "{name} truncated to {len}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_xattrs() {
+        // an xattr with its value inline, then one with its value out of line
+        let mut kvs = Vec::new();
+        kvs.extend(2u16.to_le_bytes());
+        kvs.extend(10u16.to_le_bytes());
+        kvs.extend(b"capability");
+        kvs.extend(6u32.to_le_bytes());
+        kvs.extend(b"\x01\x00\x00\x02\n\x00");
+        kvs.extend((XATTR_VALUE_OOL).to_le_bytes());
+        kvs.extend(7u16.to_le_bytes());
+        kvs.extend(b"comment");
+        kvs.extend(8u32.to_le_bytes());
+        let value_offset = kvs.len() as u64 + 8;
+        kvs.extend(value_offset.to_le_bytes());
+        kvs.extend(3u32.to_le_bytes());
+        kvs.extend(b"a\nb");
+        let mut data = (kvs.len() as u16 | METADATA_UNCOMPRESSED)
+            .to_le_bytes()
+            .to_vec();
+        data.extend(kvs);
+        let tmp = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(tmp.path(), data).unwrap();
+        let file = tmp.as_file();
+
+        let compression = Compression::Gzip;
+        let mut image = Image {
+            file,
+            superblock: Superblock {
+                block_size: 4096,
+                compression,
+                root_inode: 0,
+                id_count: 0,
+                fragment_count: 0,
+                id_table: 0,
+                xattr_id_table: 0,
+                inode_table: 0,
+                directory_table: 0,
+                fragment_table: 0,
+            },
+            ids: Vec::new(),
+            fragments: Vec::new(),
+            xattr_ids: vec![(0, 2)],
+            inodes: MetadataTable::new(file, compression, 0),
+            directories: MetadataTable::new(file, compression, 0),
+            xattrs: MetadataTable::new(file, compression, 0),
+            xattr_cache: HashMap::new(),
+            fragment_cache: None,
+        };
+        let mut interner = XattrInterner::default();
+        let xattrs = image.xattrs(0, &mut interner).unwrap();
+        assert_eq!(
+            *xattrs,
+            [
+                (
+                    "security.capability".to_string(),
+                    b"\x01\x00\x00\x02\n\x00".to_vec()
+                ),
+                ("user.comment".to_string(), b"a\nb".to_vec()),
+            ]
+        );
+        assert!(image.xattrs(NO_INDEX, &mut interner).unwrap().is_empty());
+        assert!(image.xattrs(1, &mut interner).is_err());
+    }
+}
@@ -1,20 +1,44 @@
-use std::collections::HashMap;
-use std::io::Write;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Arc;
 
 use anyhow::{Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
 use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
 use ocidir::oci_spec::image as oci_image;
 use ocidir::{BlobWriter, WriteComplete};
 
 use crate::components::{FileInfo, FileMap, FileType};
+use crate::idmap::IdMap;
+use crate::normalize::Normalizer;
+use crate::overlay::Overlay;
+use crate::owners::OwnerNames;
+use crate::parallel_gzip::ParallelGzEncoder;
+use crate::readahead::ReadAhead;
+use crate::rewrite::PathRewrites;
+use crate::transform::{Chmod, Chown};
+
+/// Docker media type of uncompressed layers. This isn't part of the Docker
+/// image spec, but is understood by containerd and Docker.
+pub const DOCKER_LAYER_MEDIA_TYPE: &str = "application/vnd.docker.image.rootfs.diff.tar";
+
+/// Docker media type of gzip-compressed layers.
+pub const DOCKER_LAYER_GZIP_MEDIA_TYPE: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
 
 /// Compression options for OCI archives.
+#[derive(Debug, Clone, Copy)]
 pub enum ArchiveCompression {
     /// No compression.
     None,
     /// Gzip compression with the specified level.
     Gzip(flate2::Compression),
+    /// Xz compression with the specified preset (0-9).
+    Xz(u32),
+    /// Bzip2 compression with the specified level.
+    Bzip2(bzip2::Compression),
 }
 
 /// A passthrough writer that performs no compression.
@@ -41,6 +65,7 @@ impl<'a> WriteComplete<BlobWriter<'a>> for NoCompression<'a> {
 pub enum LayerWriter<'a> {
     Uncompressed(ocidir::LayerWriter<'a, NoCompression<'a>>),
     Gzip(ocidir::LayerWriter<'a, flate2::write::GzEncoder<BlobWriter<'a>>>),
+    ParallelGzip(ocidir::LayerWriter<'a, ParallelGzEncoder<BlobWriter<'a>>>),
 }
 
 impl<'a> Write for LayerWriter<'a> {
@@ -48,6 +73,7 @@ impl<'a> Write for LayerWriter<'a> {
         match self {
             LayerWriter::Uncompressed(w) => w.write(buf),
             LayerWriter::Gzip(w) => w.write(buf),
+            LayerWriter::ParallelGzip(w) => w.write(buf),
         }
     }
 
@@ -55,6 +81,7 @@ impl<'a> Write for LayerWriter<'a> {
         match self {
             LayerWriter::Uncompressed(w) => w.flush(),
             LayerWriter::Gzip(w) => w.flush(),
+            LayerWriter::ParallelGzip(w) => w.flush(),
         }
     }
 }
@@ -65,28 +92,53 @@ impl<'a> LayerWriter<'a> {
         match self {
             LayerWriter::Uncompressed(w) => w.complete().context("completing uncompressed layer"),
             LayerWriter::Gzip(w) => w.complete().context("completing gzip layer"),
+            LayerWriter::ParallelGzip(w) => w.complete().context("completing gzip layer"),
         }
     }
 }
 
 /// Create a tar builder for a new layer in an OCI directory.
+#[cfg(test)]
 pub fn create_layer(
     oci_dir: &ocidir::OciDir,
     compression: crate::ocibuilder::Compression,
+    media_type: crate::ocibuilder::LayerMediaType,
+) -> Result<tar::Builder<LayerWriter<'_>>> {
+    create_layer_with_threads(oci_dir, compression, media_type, 1)
+}
+
+/// Same as [`create_layer`], but gzip layers are compressed on `threads`
+/// threads if there's more than one (see [`crate::parallel_gzip`]).
+pub fn create_layer_with_threads(
+    oci_dir: &ocidir::OciDir,
+    compression: crate::ocibuilder::Compression,
+    media_type: crate::ocibuilder::LayerMediaType,
+    threads: usize,
 ) -> Result<tar::Builder<LayerWriter<'_>>> {
+    let media_type = media_type.media_type(compression);
     let layer_writer = match compression {
         crate::ocibuilder::Compression::None => {
             let layer_writer = oci_dir
-                .create_custom_layer(|bw| Ok(NoCompression(bw)), oci_image::MediaType::ImageLayer)
+                .create_custom_layer(|bw| Ok(NoCompression(bw)), media_type)
                 .context("creating uncompressed layer writer")?;
             LayerWriter::Uncompressed(layer_writer)
         }
+        crate::ocibuilder::Compression::Gzip(level) if threads > 1 => {
+            let level = flate2::Compression::new(level);
+            let layer_writer = oci_dir
+                .create_custom_layer(
+                    |bw| Ok(ParallelGzEncoder::new(bw, level, threads)),
+                    media_type,
+                )
+                .context("creating parallel gzip layer writer")?;
+            LayerWriter::ParallelGzip(layer_writer)
+        }
         crate::ocibuilder::Compression::Gzip(level) => {
             let level = flate2::Compression::new(level);
             let layer_writer = oci_dir
                 .create_custom_layer(
                     |bw| Ok(flate2::write::GzEncoder::new(bw, level)),
-                    oci_image::MediaType::ImageLayerGzip,
+                    media_type,
                 )
                 .context("creating gzip layer writer")?;
             LayerWriter::Gzip(layer_writer)
@@ -95,65 +147,227 @@ pub fn create_layer(
     Ok(tar::Builder::new(layer_writer))
 }
 
+/// Returns whether layers of the given media type are gzip-compressed, or
+/// `None` if it isn't a tar layer media type we know about.
+pub fn layer_is_gzip(media_type: &oci_image::MediaType) -> Option<bool> {
+    match media_type {
+        oci_image::MediaType::ImageLayer => Some(false),
+        oci_image::MediaType::ImageLayerGzip => Some(true),
+        // per the OCI compatibility matrix, these are interchangeable
+        oci_image::MediaType::Other(m) if m == DOCKER_LAYER_MEDIA_TYPE => Some(false),
+        oci_image::MediaType::Other(m) if m == DOCKER_LAYER_GZIP_MEDIA_TYPE => Some(true),
+        _ => None,
+    }
+}
+
+/// Open a layer blob for reading as an uncompressed tarball.
+pub fn read_layer(
+    oci_dir: &ocidir::OciDir,
+    layer: &oci_image::Descriptor,
+) -> Result<Box<dyn Read>> {
+    let blob = oci_dir.read_blob(layer).context("opening blob")?;
+    Ok(match layer_is_gzip(layer.media_type()) {
+        Some(true) => Box::new(flate2::read::GzDecoder::new(blob)),
+        _ => Box::new(blob),
+    })
+}
+
+/// Order in which entries are written within a layer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EntryOrder {
+    /// Sorted by path.
+    #[default]
+    Path,
+    /// Directories first (sorted by path), then everything else sorted by
+    /// size ascending. This puts metadata and small files at the front of the
+    /// layer, which helps streaming extractors and lazy pullers get to a
+    /// usable state sooner.
+    Size,
+}
+
+/// Format of the headers of layer entries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TarFormat {
+    /// GNU headers, with GNU long name entries for paths and link names too
+    /// long for them.
+    #[default]
+    Gnu,
+    /// POSIX ustar headers, with PAX extended headers for paths and link
+    /// names too long for them.
+    Pax,
+}
+
+/// Options controlling how files are written to layers.
+#[derive(Debug, Clone, Default)]
+pub struct TarOptions {
+    /// Order of entries within each layer.
+    pub entry_order: EntryOrder,
+    /// Normalizers to run on file content.
+    pub normalizers: Vec<Normalizer>,
+    /// Tarballs applied on top of the rootfs, which their files are read from.
+    pub overlay: Option<Arc<Overlay>>,
+    /// Rewrites of the paths of the rootfs.
+    pub rewrites: Option<Arc<PathRewrites>>,
+    /// Metadata of the files of the tarball the rootfs was extracted from,
+    /// which the extracted files don't carry.
+    pub rootfs_metadata: Option<Arc<FileMap>>,
+    /// Number of threads reading files ahead of the writer, or 0 to read each
+    /// file when it's written.
+    pub read_ahead: usize,
+    /// Format of the entry headers.
+    pub format: TarFormat,
+    /// Names of the owners of entries, if headers carry them.
+    pub owner_names: Option<Arc<OwnerNames>>,
+    /// Mapping of the user IDs of the rootfs to those of the layers.
+    pub uid_map: IdMap,
+    /// Mapping of the group IDs of the rootfs to those of the layers.
+    pub gid_map: IdMap,
+    /// Owners overriding those of the rootfs, after the ID maps.
+    pub chown: Vec<Chown>,
+    /// Permission bits overriding those of the rootfs.
+    pub chmod: Vec<Chmod>,
+}
+
 /// Build a tar layer from a list of files and return the completed layer.
 ///
 /// Parent directories are automatically created as needed using metadata from
-/// the files map. This uses a stack-based approach that leverages the sorted order
-/// of the input BTreeMap for efficiency.
+/// the files map. Regardless of the entry order, directories are always written
+/// before their contents.
 pub fn write_files_to_tar<W: Write>(
     tar_builder: &mut tar::Builder<W>,
     rootfs: &Dir,
     files: &FileMap,
     mtime_clamp: u64,
+    options: &TarOptions,
 ) -> Result<()> {
-    // Stack of written directory paths - leverages sorted iteration order
-    let mut dir_stack: Vec<&Utf8Path> = Vec::new();
+    write_files_to_tar_with_clamps(tar_builder, rootfs, files, |_| mtime_clamp, options)
+}
+
+/// Same as [`write_files_to_tar`], but with the mtime clamp of each entry
+/// (including the parent directories written for it) given by `mtime_clamp`.
+pub fn write_files_to_tar_with_clamps<W, F>(
+    tar_builder: &mut tar::Builder<W>,
+    rootfs: &Dir,
+    files: &FileMap,
+    mtime_clamp: F,
+    options: &TarOptions,
+) -> Result<()>
+where
+    W: Write,
+    F: Fn(&Utf8Path) -> u64,
+{
+    let entries = ordered_entries(files, options.entry_order);
+    let overlay = options.overlay.as_deref();
+    if options.read_ahead == 0 {
+        return write_entries(
+            tar_builder,
+            rootfs,
+            files,
+            &entries,
+            mtime_clamp,
+            options,
+            |path, info| read_file_content(rootfs, path, info, overlay),
+        );
+    }
+    let to_read = files_to_read(&entries);
+    std::thread::scope(|scope| {
+        let mut read_ahead = ReadAhead::start(scope, rootfs, overlay, &to_read, options.read_ahead);
+        write_entries(
+            tar_builder,
+            rootfs,
+            files,
+            &entries,
+            mtime_clamp,
+            options,
+            |path, _| read_ahead.next(path),
+        )
+    })
+}
+
+/// Write `entries`, the ordered `files`, with the content of regular files
+/// returned by `read_content`.
+fn write_entries<W, F, R>(
+    tar_builder: &mut tar::Builder<W>,
+    rootfs: &Dir,
+    files: &FileMap,
+    entries: &[(&Utf8PathBuf, &FileInfo)],
+    mtime_clamp: F,
+    options: &TarOptions,
+    mut read_content: R,
+) -> Result<()>
+where
+    W: Write,
+    F: Fn(&Utf8Path) -> u64,
+    R: FnMut(&Utf8Path, &FileInfo) -> Result<Vec<u8>>,
+{
+    // Set of written directory paths
+    let mut written_dirs: HashSet<&Utf8Path> = HashSet::new();
     // Track inode -> first path written for hardlink detection.
     let mut inode_to_path: HashMap<u64, Utf8PathBuf> = HashMap::new();
 
-    for (path, file_info) in files {
-        // Pop directories that are not ancestors of current path
-        while let Some(top) = dir_stack.last() {
-            if path.starts_with(top) && path.as_path() != *top {
-                break;
-            }
-            dir_stack.pop();
-        }
-
-        // Collect ancestors that need to be written (between stack top and
-        // current path's parent)
+    for &(path, file_info) in entries {
+        // Collect ancestors that need to be written (between the closest
+        // written ancestor and current path's parent)
         let ancestors: Vec<_> = path
             .ancestors()
             .skip(1) // skip self
             .filter(|p| !p.as_str().is_empty() && *p != "/")
-            .take_while(|p| dir_stack.last().is_none_or(|top| *p != *top))
+            .take_while(|p| !written_dirs.contains(p))
             .collect();
 
-        // Write ancestors in reverse order (shallowest first) and push to stack
+        // Write ancestors in reverse order (shallowest first)
         for ancestor in ancestors.into_iter().rev() {
             let ancestor_path = Utf8PathBuf::from(ancestor);
             // XXX: somehow reuse existing FileInfos for that dir, which may
             // live in other components
             let ancestor_info = if let Some(info) = files.get(&ancestor_path) {
                 info.clone()
+            } else if let Some(info) = options.overlay.as_ref().and_then(|o| o.info(ancestor)) {
+                info.clone()
+            } else if let Some(info) = options.rewrites.as_ref().and_then(|r| r.info(ancestor)) {
+                info.clone()
+            } else if let Some(info) = options
+                .rootfs_metadata
+                .as_ref()
+                .and_then(|m| m.get(ancestor))
+            {
+                info.clone()
             } else {
-                let rel_path = ancestor.strip_prefix("/").unwrap_or(ancestor);
+                // on disk, the ancestor is as many levels up, even if the
+                // path of the file isn't valid UTF-8
+                let levels = path.components().count() - ancestor.components().count();
+                // SAFETY: the ancestor has fewer components than the path
+                let rel_path = file_info.fs_path(path).ancestors().nth(levels).unwrap();
                 let metadata = rootfs
                     .symlink_metadata(rel_path)
                     .with_context(|| format!("getting metadata for {}", ancestor))?;
-                let xattrs = crate::scan::read_xattrs(rootfs, rel_path.as_str())
+                let xattrs = crate::scan::read_xattrs(rootfs, rel_path)
                     .with_context(|| format!("reading xattrs for {}", ancestor))?;
-                FileInfo::from_metadata(&metadata, FileType::Directory, xattrs)
+                FileInfo::from_metadata(&metadata, FileType::Directory, xattrs.into())
             };
-            write_dir_entry(tar_builder, ancestor, mtime_clamp, &ancestor_info)
-                .with_context(|| format!("writing parent directory {}", ancestor))?;
-            dir_stack.push(ancestor);
+            write_dir_entry(
+                tar_builder,
+                ancestor,
+                mtime_clamp(ancestor),
+                &ancestor_info,
+                options,
+            )
+            .with_context(|| format!("writing parent directory {}", ancestor))?;
+            written_dirs.insert(ancestor);
         }
 
         // Handle hardlinks up front
         if file_info.file_type != FileType::Directory && file_info.nlink > 1 {
             if let Some(first_path) = inode_to_path.get(&file_info.ino) {
-                write_hardlink_entry(tar_builder, path, first_path, mtime_clamp, file_info)?;
+                write_hardlink_entry(
+                    tar_builder,
+                    path,
+                    first_path,
+                    mtime_clamp(path),
+                    file_info,
+                    options,
+                )?;
                 continue;
             }
             // First occurrence of this hardlinked file/symlink
@@ -162,21 +376,114 @@ pub fn write_files_to_tar<W: Write>(
 
         match file_info.file_type {
             FileType::Directory => {
-                write_dir_entry(tar_builder, path, mtime_clamp, file_info)?;
-                // We might enter this directory in the next iteration; push it
-                dir_stack.push(path.as_path());
+                write_dir_entry(tar_builder, path, mtime_clamp(path), file_info, options)?;
+                written_dirs.insert(path.as_path());
             }
             FileType::File => {
-                write_file_entry(tar_builder, rootfs, path, mtime_clamp, file_info)?;
+                let content = read_content(path, file_info)?;
+                write_file_entry(
+                    tar_builder,
+                    path,
+                    content,
+                    mtime_clamp(path),
+                    file_info,
+                    options,
+                )?;
             }
             FileType::Symlink => {
-                write_symlink_entry(tar_builder, rootfs, path, mtime_clamp, file_info)?;
+                write_symlink_entry(
+                    tar_builder,
+                    rootfs,
+                    path,
+                    mtime_clamp(path),
+                    file_info,
+                    options,
+                )?;
+            }
+            FileType::BlockDevice | FileType::CharDevice | FileType::Fifo => {
+                write_special_entry(tar_builder, path, mtime_clamp(path), file_info, options)?;
             }
         }
     }
     Ok(())
 }
 
+/// Write a file that doesn't exist in the rootfs with the given content.
+///
+/// Parent directories are written using their metadata from the rootfs (or its
+/// tarball) if they exist there, or as root-owned 0755 directories otherwise. The file itself
+/// is root-owned with mode 0644.
+pub fn write_generated_file<W: Write>(
+    tar_builder: &mut tar::Builder<W>,
+    rootfs: &Dir,
+    path: &Utf8Path,
+    content: &[u8],
+    mtime: u64,
+    options: &TarOptions,
+) -> Result<()> {
+    let ancestors: Vec<_> = path
+        .ancestors()
+        .skip(1)
+        .filter(|p| !p.as_str().is_empty() && *p != "/")
+        .collect();
+    for ancestor in ancestors.into_iter().rev() {
+        let ancestor_info = if let Some(info) = options
+            .rootfs_metadata
+            .as_ref()
+            .and_then(|m| m.get(ancestor))
+        {
+            info.clone()
+        } else {
+            let rel_path = strip_root_prefix(ancestor);
+            match rootfs
+                .symlink_metadata_optional(rel_path)
+                .with_context(|| format!("getting metadata for {}", ancestor))?
+            {
+                Some(metadata) => {
+                    let xattrs = crate::scan::read_xattrs(rootfs, rel_path.as_std_path())
+                        .with_context(|| format!("reading xattrs for {}", ancestor))?;
+                    FileInfo::from_metadata(&metadata, FileType::Directory, xattrs.into())
+                }
+                None => FileInfo {
+                    file_type: FileType::Directory,
+                    mode: libc::S_IFDIR | 0o755,
+                    size: 0,
+                    uid: 0,
+                    gid: 0,
+                    mtime,
+                    ino: 0,
+                    nlink: 1,
+                    xattrs: Default::default(),
+                    extra: None,
+                },
+            }
+        };
+        write_dir_entry(tar_builder, ancestor, mtime, &ancestor_info, options)
+            .with_context(|| format!("writing parent directory {}", ancestor))?;
+    }
+
+    let mut header = new_header(options.format);
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(content.len() as u64);
+    header.set_mtime(mtime);
+    header.set_uid(0);
+    header.set_gid(0);
+    set_owner_names(&mut header, 0, 0, options)?;
+    header.set_mode(0o644);
+    append_entry(
+        tar_builder,
+        &mut header,
+        strip_root_prefix(path).as_str(),
+        None,
+        &[],
+        options.format,
+        content,
+    )
+    .with_context(|| format!("appending generated file {}", path))?;
+
+    Ok(())
+}
+
 /// Write the OCI directory as a tar archive to a writer.
 // XXX: Consider upstreaming this to ocidir-rs.
 pub fn write_oci_archive<W: Write>(
@@ -184,13 +491,77 @@ pub fn write_oci_archive<W: Write>(
     writer: W,
     compression: ArchiveCompression,
 ) -> Result<()> {
+    // finish the encoders explicitly; dropping them would swallow errors
     match compression {
-        ArchiveCompression::None => write_oci_archive_to(oci_dir, writer),
+        ArchiveCompression::None => {
+            write_oci_archive_to(oci_dir, writer)?;
+        }
         ArchiveCompression::Gzip(level) => {
             let gzip_writer = flate2::write::GzEncoder::new(writer, level);
-            write_oci_archive_to(oci_dir, gzip_writer)
+            write_oci_archive_to(oci_dir, gzip_writer)?
+                .finish()
+                .context("finishing gzip stream")?;
+        }
+        ArchiveCompression::Xz(preset) => {
+            let xz_writer = xz2::write::XzEncoder::new(writer, preset);
+            write_oci_archive_to(oci_dir, xz_writer)?
+                .finish()
+                .context("finishing xz stream")?;
+        }
+        ArchiveCompression::Bzip2(level) => {
+            let bzip2_writer = bzip2::write::BzEncoder::new(writer, level);
+            write_oci_archive_to(oci_dir, bzip2_writer)?
+                .finish()
+                .context("finishing bzip2 stream")?;
         }
     }
+    Ok(())
+}
+
+/// Return the entries of `files` in the order they should be written.
+fn ordered_entries(files: &FileMap, order: EntryOrder) -> Vec<(&Utf8PathBuf, &FileInfo)> {
+    match order {
+        EntryOrder::Path => files.iter().collect(),
+        EntryOrder::Size => {
+            let (mut entries, mut rest): (Vec<_>, Vec<_>) = files
+                .iter()
+                .partition(|(_, info)| info.file_type == FileType::Directory);
+            // stable sort, so equal sizes stay in path order
+            rest.sort_by_key(|(_, info)| info.size);
+            entries.extend(rest);
+            entries
+        }
+    }
+}
+
+/// Returns the regular files whose content is read when writing `entries`, in
+/// order. Hardlinks to files written before aren't read again.
+fn files_to_read<'a>(
+    entries: &[(&'a Utf8PathBuf, &'a FileInfo)],
+) -> Vec<(&'a Utf8Path, &'a FileInfo)> {
+    let mut inodes = HashSet::new();
+    entries
+        .iter()
+        .filter(|(_, info)| {
+            info.file_type == FileType::File && (info.nlink <= 1 || inodes.insert(info.ino))
+        })
+        .map(|&(path, info)| (path.as_path(), info))
+        .collect()
+}
+
+/// Read the content of a regular file, from the overlay if it's there.
+pub(crate) fn read_file_content(
+    rootfs: &Dir,
+    path: &Utf8Path,
+    info: &FileInfo,
+    overlay: Option<&Overlay>,
+) -> Result<Vec<u8>> {
+    if let Some(content) = overlay.map(|o| o.read(path)).transpose()?.flatten() {
+        return Ok(content);
+    }
+    rootfs
+        .read(info.fs_path(path))
+        .with_context(|| format!("reading {}", path))
 }
 
 /// Strip leading "/" from a path, returning the path unchanged if no prefix.
@@ -198,68 +569,179 @@ fn strip_root_prefix(path: &Utf8Path) -> &Utf8Path {
     path.strip_prefix("/").unwrap_or(path)
 }
 
+/// Returns an empty header of the given format.
+fn new_header(format: TarFormat) -> tar::Header {
+    match format {
+        TarFormat::Gnu => tar::Header::new_gnu(),
+        TarFormat::Pax => tar::Header::new_ustar(),
+    }
+}
+
 /// Prepare a tar header with common metadata from FileInfo.
-fn write_header_from_file_info(header: &mut tar::Header, file_info: &FileInfo, mtime_clamp: u64) {
+fn write_header_from_file_info(
+    header: &mut tar::Header,
+    path: &Utf8Path,
+    file_info: &FileInfo,
+    mtime_clamp: u64,
+    options: &TarOptions,
+) -> Result<()> {
     let mtime = std::cmp::min(file_info.mtime, mtime_clamp);
+    let (uid, gid, mode) = entry_owner_and_mode(path, file_info, options);
     header.set_mtime(mtime);
-    header.set_uid(file_info.uid as u64);
-    header.set_gid(file_info.gid as u64);
-    header.set_mode(file_info.mode);
+    header.set_uid(uid as u64);
+    header.set_gid(gid as u64);
+    set_owner_names(header, uid, gid, options)?;
+    header.set_mode(mode);
+    Ok(())
 }
 
-/// Append xattrs as PAX extensions to the tar stream.
+/// Returns the uid, gid and mode of the entry of `path` in the layers, once
+/// the ID maps and the `--chown` and `--chmod` transformations are applied.
+pub(crate) fn entry_owner_and_mode(
+    path: &Utf8Path,
+    file_info: &FileInfo,
+    options: &TarOptions,
+) -> (u32, u32, u32) {
+    let mut uid = options.uid_map.map(file_info.uid);
+    let mut gid = options.gid_map.map(file_info.gid);
+    if let Some(chown) = options.chown.iter().rev().find(|c| c.matches(path)) {
+        (uid, gid) = (chown.uid, chown.gid);
+    }
+    let mut mode = file_info.mode;
+    // symlinks don't have permissions of their own
+    if file_info.file_type != FileType::Symlink
+        && let Some(chmod) = options.chmod.iter().rev().find(|c| c.matches(path))
+    {
+        mode = (mode & !0o7777) | chmod.mode;
+    }
+    (uid, gid, mode)
+}
+
+/// Set the user and group names of `header` from the owner names of the
+/// rootfs, if enabled. IDs without a name are left numeric only.
+fn set_owner_names(
+    header: &mut tar::Header,
+    uid: u32,
+    gid: u32,
+    options: &TarOptions,
+) -> Result<()> {
+    let Some(names) = &options.owner_names else {
+        return Ok(());
+    };
+    if let Some(user) = names.user(uid) {
+        header
+            .set_username(user)
+            .with_context(|| format!("setting user name {user}"))?;
+    }
+    if let Some(group) = names.group(gid) {
+        header
+            .set_groupname(group)
+            .with_context(|| format!("setting group name {group}"))?;
+    }
+    Ok(())
+}
+
+/// Append an entry at `path` with `header` and `data`, and the link name of
+/// links.
 ///
-/// This must be called before appending the actual file entry.
-/// Uses the SCHILY.xattr.{key} format that tools like tar understand.
-fn append_xattrs<W: Write>(
+/// Xattrs go in a PAX header before the entry, using the SCHILY.xattr.{key}
+/// format that tools like tar understand. Paths and link names too long for
+/// the header go in GNU long name entries with [`TarFormat::Gnu`], and in that
+/// same PAX header with [`TarFormat::Pax`].
+fn append_entry<W: Write, R: Read>(
     tar_builder: &mut tar::Builder<W>,
-    xattrs: &[(String, Vec<u8>)],
+    header: &mut tar::Header,
     path: &str,
+    link_name: Option<&Path>,
+    xattrs: &[(String, Vec<u8>)],
+    format: TarFormat,
+    data: R,
 ) -> Result<()> {
-    if xattrs.is_empty() {
-        return Ok(());
+    let mut pax_extensions: Vec<(String, Vec<u8>)> = Vec::new();
+    if format == TarFormat::Pax {
+        if header.set_path(path).is_err() {
+            pax_extensions.push(("path".to_string(), path.as_bytes().to_vec()));
+            header
+                .set_path(truncate_name(path, 100))
+                .with_context(|| format!("setting truncated path of {path}"))?;
+        }
+        if let Some(link_name) = link_name
+            && header.set_link_name(link_name).is_err()
+        {
+            let link_name = link_name.as_os_str().as_bytes();
+            pax_extensions.push(("linkpath".to_string(), link_name.to_vec()));
+            let truncated = String::from_utf8_lossy(link_name);
+            header
+                .set_link_name(truncate_name(&truncated, 100))
+                .with_context(|| format!("setting truncated link name of {path}"))?;
+        }
     }
-
-    let pax_extensions: Vec<_> = xattrs
-        .iter()
-        .map(|(k, v)| (format!("SCHILY.xattr.{k}"), v.clone()))
-        .collect();
-
+    pax_extensions.extend(
+        xattrs
+            .iter()
+            .map(|(k, v)| (format!("SCHILY.xattr.{k}"), v.clone())),
+    );
     tar_builder
         .append_pax_extensions(
             pax_extensions
                 .iter()
                 .map(|(k, v)| (k.as_str(), v.as_slice())),
         )
-        .with_context(|| format!("appending xattrs for {}", path))?;
+        .context("appending PAX extensions")?;
 
+    match (format, link_name) {
+        (TarFormat::Pax, _) => {
+            header.set_cksum();
+            tar_builder.append(header, data)?;
+        }
+        (TarFormat::Gnu, Some(link_name)) => tar_builder.append_link(header, path, link_name)?,
+        (TarFormat::Gnu, None) => tar_builder.append_data(header, path, data)?,
+    }
     Ok(())
 }
 
+/// Returns the longest prefix of `name` of at most `max` bytes which doesn't
+/// split a character, for the header of an entry whose name is in a PAX
+/// header. Trailing dots are dropped so that the prefix never ends with a
+/// `..` component, which the tar crate rejects.
+fn truncate_name(name: &str, max: usize) -> &str {
+    let mut end = name.len().min(max);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    name[..end].trim_end_matches('.')
+}
+
 /// Write a directory entry to the tar archive.
-fn write_dir_entry<W: Write>(
+pub(crate) fn write_dir_entry<W: Write>(
     tar_builder: &mut tar::Builder<W>,
     path: &Utf8Path,
     mtime_clamp: u64,
     file_info: &FileInfo,
+    options: &TarOptions,
 ) -> Result<()> {
     let rel_path = strip_root_prefix(path);
 
-    let mut header = tar::Header::new_gnu();
+    let mut header = new_header(options.format);
     header.set_entry_type(tar::EntryType::Directory);
     header.set_size(0);
-    write_header_from_file_info(&mut header, file_info, mtime_clamp);
-    append_xattrs(tar_builder, &file_info.xattrs, path.as_str())
-        .with_context(|| format!("appending xattrs for {}", path))?;
+    write_header_from_file_info(&mut header, path, file_info, mtime_clamp, options)?;
 
     let tar_dir_path = if rel_path.as_str().is_empty() {
         "./".to_string()
     } else {
         format!("{}/", rel_path)
     };
-    tar_builder
-        .append_data(&mut header, &tar_dir_path, std::io::empty())
-        .with_context(|| format!("appending directory {}", path))?;
+    append_entry(
+        tar_builder,
+        &mut header,
+        &tar_dir_path,
+        None,
+        &file_info.xattrs,
+        options.format,
+        std::io::empty(),
+    )
+    .with_context(|| format!("appending directory {}", path))?;
 
     Ok(())
 }
@@ -271,23 +753,33 @@ fn write_hardlink_entry<W: Write>(
     link_target: &Utf8Path,
     mtime_clamp: u64,
     file_info: &FileInfo,
+    options: &TarOptions,
 ) -> Result<()> {
     let rel_path = strip_root_prefix(path);
     let rel_target = strip_root_prefix(link_target);
 
-    let mut header = tar::Header::new_gnu();
+    let mut header = new_header(options.format);
     header.set_entry_type(tar::EntryType::Link);
     header.set_size(0);
-    write_header_from_file_info(&mut header, file_info, mtime_clamp);
+    write_header_from_file_info(&mut header, path, file_info, mtime_clamp, options)?;
     // Mask out file type bits; it's harmless but it matches what GNU tar
     // and Python's tarfile do as well. Not doing this does though result in
     // libarchive's strmode not showing the file as 'h' which shows up in diffs
     // pre vs post-chunkah.
-    header.set_mode(file_info.mode & 0o7777);
-
-    tar_builder
-        .append_link(&mut header, rel_path.as_str(), rel_target.as_str())
-        .with_context(|| format!("appending hardlink {} -> {}", path, link_target))?;
+    let (_, _, mode) = entry_owner_and_mode(path, file_info, options);
+    header.set_mode(mode & 0o7777);
+
+    // hardlinks don't carry xattrs; they're on the first entry
+    append_entry(
+        tar_builder,
+        &mut header,
+        rel_path.as_str(),
+        Some(rel_target.as_std_path()),
+        &[],
+        options.format,
+        std::io::empty(),
+    )
+    .with_context(|| format!("appending hardlink {} -> {}", path, link_target))?;
 
     Ok(())
 }
@@ -295,27 +787,30 @@ fn write_hardlink_entry<W: Write>(
 /// Write a regular file entry to the tar archive.
 fn write_file_entry<W: Write>(
     tar_builder: &mut tar::Builder<W>,
-    rootfs: &Dir,
     path: &Utf8Path,
+    mut content: Vec<u8>,
     mtime_clamp: u64,
     file_info: &FileInfo,
+    options: &TarOptions,
 ) -> Result<()> {
     let rel_path = strip_root_prefix(path);
+    crate::normalize::normalize(path, &mut content, &options.normalizers, mtime_clamp);
 
-    let content = rootfs
-        .read(rel_path)
-        .with_context(|| format!("reading {}", path))?;
-
-    let mut header = tar::Header::new_gnu();
+    let mut header = new_header(options.format);
     header.set_entry_type(tar::EntryType::Regular);
     header.set_size(content.len() as u64);
-    write_header_from_file_info(&mut header, file_info, mtime_clamp);
-    append_xattrs(tar_builder, &file_info.xattrs, path.as_str())
-        .with_context(|| format!("appending xattrs for {}", path))?;
-
-    tar_builder
-        .append_data(&mut header, rel_path.as_str(), content.as_slice())
-        .with_context(|| format!("appending file {}", path))?;
+    write_header_from_file_info(&mut header, path, file_info, mtime_clamp, options)?;
+
+    append_entry(
+        tar_builder,
+        &mut header,
+        rel_path.as_str(),
+        None,
+        &file_info.xattrs,
+        options.format,
+        content.as_slice(),
+    )
+    .with_context(|| format!("appending file {}", path))?;
 
     Ok(())
 }
@@ -327,30 +822,83 @@ fn write_symlink_entry<W: Write>(
     path: &Utf8Path,
     mtime_clamp: u64,
     file_info: &FileInfo,
+    options: &TarOptions,
 ) -> Result<()> {
     let rel_path = strip_root_prefix(path);
 
-    let target = rootfs
-        .read_link_contents(rel_path)
-        .with_context(|| format!("reading symlink {}", path))?;
+    let target = match options.overlay.as_ref().and_then(|o| o.read_link(path)) {
+        Some(target) => target.as_std_path().to_path_buf(),
+        None => {
+            let target = rootfs
+                .read_link_contents(file_info.fs_path(path))
+                .with_context(|| format!("reading symlink {}", path))?;
+            match options.rewrites.as_ref() {
+                Some(rewrites) => rewrites.rewrite_target(&target).unwrap_or(target),
+                None => target,
+            }
+        }
+    };
 
-    let mut header = tar::Header::new_gnu();
+    let mut header = new_header(options.format);
     header.set_entry_type(tar::EntryType::Symlink);
     header.set_size(0);
-    write_header_from_file_info(&mut header, file_info, mtime_clamp);
-    append_xattrs(tar_builder, &file_info.xattrs, path.as_str())
-        .with_context(|| format!("appending xattrs for {}", path))?;
+    write_header_from_file_info(&mut header, path, file_info, mtime_clamp, options)?;
+
+    append_entry(
+        tar_builder,
+        &mut header,
+        rel_path.as_str(),
+        Some(&target),
+        &file_info.xattrs,
+        options.format,
+        std::io::empty(),
+    )
+    .with_context(|| format!("appending symlink {}", path))?;
 
-    tar_builder
-        .append_link(&mut header, rel_path.as_str(), target)
-        .with_context(|| format!("appending symlink {}", path))?;
+    Ok(())
+}
+
+/// Write a device or FIFO entry to the tar archive.
+fn write_special_entry<W: Write>(
+    tar_builder: &mut tar::Builder<W>,
+    path: &Utf8Path,
+    mtime_clamp: u64,
+    file_info: &FileInfo,
+    options: &TarOptions,
+) -> Result<()> {
+    let rel_path = strip_root_prefix(path);
+
+    let mut header = new_header(options.format);
+    header.set_entry_type(match file_info.file_type {
+        FileType::BlockDevice => tar::EntryType::Block,
+        FileType::CharDevice => tar::EntryType::Char,
+        _ => tar::EntryType::Fifo,
+    });
+    header.set_size(0);
+    write_header_from_file_info(&mut header, path, file_info, mtime_clamp, options)?;
+    header
+        .set_device_major(libc::major(file_info.rdev()))
+        .with_context(|| format!("setting device major for {}", path))?;
+    header
+        .set_device_minor(libc::minor(file_info.rdev()))
+        .with_context(|| format!("setting device minor for {}", path))?;
+
+    append_entry(
+        tar_builder,
+        &mut header,
+        rel_path.as_str(),
+        None,
+        &file_info.xattrs,
+        options.format,
+        std::io::empty(),
+    )
+    .with_context(|| format!("appending special file {}", path))?;
 
     Ok(())
 }
 
-fn write_oci_archive_to<W: Write>(oci_dir: &Dir, writer: W) -> Result<()> {
+fn write_oci_archive_to<W: Write>(oci_dir: &Dir, writer: W) -> Result<W> {
     use cap_std_ext::cap_std::fs::FileType as CapFileType;
-    use cap_std_ext::dirext::CapStdExtDirExt;
     use std::ops::ControlFlow;
 
     // Template headers for directories and files - cloned and modified as needed
@@ -418,8 +966,7 @@ fn write_oci_archive_to<W: Write>(oci_dir: &Dir, writer: W) -> Result<()> {
         })
         .context("walking OCI directory")?;
 
-    tar.finish().context("finishing tar archive")?;
-    Ok(())
+    tar.into_inner().context("finishing tar archive")
 }
 
 #[cfg(test)]
@@ -447,7 +994,14 @@ mod tests {
         let mut output = Vec::new();
         {
             let mut tar_builder = tar::Builder::new(&mut output);
-            write_files_to_tar(&mut tar_builder, &rootfs, &files, mtime_clamp).unwrap();
+            write_files_to_tar(
+                &mut tar_builder,
+                &rootfs,
+                &files,
+                mtime_clamp,
+                &TarOptions::default(),
+            )
+            .unwrap();
             tar_builder.finish().unwrap();
         }
         output
@@ -522,6 +1076,378 @@ mod tests {
         assert!(found_link, "symlink should be in tar");
     }
 
+    #[test]
+    fn test_write_files_to_tar_special_files() {
+        // creating devices needs root, so they're only faked in the file map
+        let output = write_tar_bytes(
+            |rootfs| rootfs.write("file", "content").unwrap(),
+            Some(|files: &mut FileMap| {
+                let file = files.remove(Utf8Path::new("/file")).unwrap();
+                for (path, file_type, rdev) in [
+                    ("/null", FileType::CharDevice, libc::makedev(1, 3)),
+                    ("/sda", FileType::BlockDevice, libc::makedev(8, 0)),
+                    ("/fifo", FileType::Fifo, 0),
+                ] {
+                    let mut info = file.clone();
+                    info.file_type = file_type;
+                    info.size = 0;
+                    info.set_rdev(rdev);
+                    files.insert(path.into(), info);
+                }
+            }),
+            1000,
+        );
+
+        let mut archive = tar::Archive::new(output.as_slice());
+        let mut found = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let entry = entry.unwrap();
+            let header = entry.header();
+            found.push((
+                entry.path().unwrap().to_string_lossy().into_owned(),
+                header.entry_type(),
+                header.device_major().unwrap(),
+                header.device_minor().unwrap(),
+                header.size().unwrap(),
+            ));
+        }
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            found,
+            vec![
+                ("fifo".into(), tar::EntryType::Fifo, Some(0), Some(0), 0),
+                ("null".into(), tar::EntryType::Char, Some(1), Some(3), 0),
+                ("sda".into(), tar::EntryType::Block, Some(8), Some(0), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_files_to_tar_owner_names() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir("etc").unwrap();
+        rootfs
+            .write("etc/passwd", "root:x:0:0:root:/root:/bin/bash\n")
+            .unwrap();
+        rootfs
+            .write("etc/group", "root:x:0:\nwheel:x:10:\n")
+            .unwrap();
+        let mut files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        for (path, uid, gid) in [
+            ("/etc", 1000, 1000),
+            ("/etc/passwd", 0, 10),
+            ("/etc/group", 1000, 0),
+        ] {
+            let info = files.get_mut(Utf8Path::new(path)).unwrap();
+            info.uid = uid;
+            info.gid = gid;
+        }
+
+        let mut output = Vec::new();
+        {
+            let mut tar_builder = tar::Builder::new(&mut output);
+            let options = TarOptions {
+                owner_names: Some(Arc::new(OwnerNames::load(&rootfs, None).unwrap())),
+                ..Default::default()
+            };
+            write_files_to_tar(&mut tar_builder, &rootfs, &files, 1000, &options).unwrap();
+            tar_builder.finish().unwrap();
+        }
+
+        let mut archive = tar::Archive::new(output.as_slice());
+        let names: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let header = entry.header();
+                // unset names read back as empty
+                let name = |n: Option<&str>| n.filter(|n| !n.is_empty()).map(str::to_string);
+                (
+                    entry.path().unwrap().to_string_lossy().into_owned(),
+                    name(header.username().unwrap()),
+                    name(header.groupname().unwrap()),
+                )
+            })
+            .collect();
+        let name = |n: &str| Some(n.to_string());
+        assert_eq!(
+            names,
+            [
+                ("etc/".to_string(), None, None),
+                ("etc/group".to_string(), None, name("root")),
+                ("etc/passwd".to_string(), name("root"), name("wheel")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_files_to_tar_id_maps() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.write("file", "content").unwrap();
+        let mut files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let info = files.get_mut(Utf8Path::new("/file")).unwrap();
+        info.uid = 100000;
+        info.gid = 100005;
+
+        let map = |range| IdMap::new(vec![crate::idmap::IdRange::parse(range).unwrap()]);
+        let mut output = Vec::new();
+        {
+            let mut tar_builder = tar::Builder::new(&mut output);
+            let options = TarOptions {
+                uid_map: map("100000:0:65536"),
+                gid_map: map("100000:0"),
+                ..Default::default()
+            };
+            write_files_to_tar(&mut tar_builder, &rootfs, &files, 1000, &options).unwrap();
+            tar_builder.finish().unwrap();
+        }
+
+        let mut archive = tar::Archive::new(output.as_slice());
+        let entry = archive.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(entry.header().uid().unwrap(), 0);
+        assert_eq!(entry.header().gid().unwrap(), 5);
+    }
+
+    #[test]
+    fn test_write_files_to_tar_transforms() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir("app").unwrap();
+        rootfs.write("app/run", "content").unwrap();
+        rootfs.write("app/app.conf", "content").unwrap();
+        rootfs.symlink("run", "app/link").unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+
+        let mut output = Vec::new();
+        {
+            let mut tar_builder = tar::Builder::new(&mut output);
+            let options = TarOptions {
+                chown: vec![
+                    Chown::parse("0:0").unwrap(),
+                    Chown::parse("1000:100:/app/*.conf").unwrap(),
+                ],
+                chmod: vec![
+                    Chmod::parse("0700:/app/*").unwrap(),
+                    Chmod::parse("0755:/app/run").unwrap(),
+                ],
+                ..Default::default()
+            };
+            write_files_to_tar(&mut tar_builder, &rootfs, &files, 1000, &options).unwrap();
+            tar_builder.finish().unwrap();
+        }
+
+        let mut archive = tar::Archive::new(output.as_slice());
+        let entries: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let header = entry.header();
+                (
+                    entry.path().unwrap().to_string_lossy().into_owned(),
+                    header.uid().unwrap(),
+                    header.gid().unwrap(),
+                    header.mode().unwrap() & 0o7777,
+                )
+            })
+            .collect();
+        let symlink_mode = files[Utf8Path::new("/app/link")].mode & 0o7777;
+        let dir_mode = files[Utf8Path::new("/app")].mode & 0o7777;
+        assert_eq!(
+            entries,
+            [
+                ("app/".to_string(), 0, 0, dir_mode),
+                ("app/app.conf".to_string(), 1000, 100, 0o700),
+                ("app/link".to_string(), 0, 0, symlink_mode),
+                ("app/run".to_string(), 0, 0, 0o755),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_files_to_tar_rewrites() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("build/stage/bin").unwrap();
+        rootfs.write("build/stage/bin/app", "content").unwrap();
+        // cap-std refuses absolute targets
+        std::os::unix::fs::symlink("/build/stage/bin/app", tmp.path().join("link")).unwrap();
+        let mut files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        let mut rewrites = PathRewrites::new(vec![
+            crate::rewrite::PathRewrite::parse("/build/stage=/opt/app").unwrap(),
+        ]);
+        rewrites.apply(&mut files).unwrap();
+        // the parent directories are in another layer
+        files.retain(|path, _| !path.starts_with("/opt") || path == "/opt/app/bin/app");
+
+        let mut output = Vec::new();
+        {
+            let mut tar_builder = tar::Builder::new(&mut output);
+            let options = TarOptions {
+                rewrites: Some(Arc::new(rewrites)),
+                ..Default::default()
+            };
+            write_files_to_tar(&mut tar_builder, &rootfs, &files, 1000, &options).unwrap();
+            tar_builder.finish().unwrap();
+        }
+
+        let mut archive = tar::Archive::new(output.as_slice());
+        let mut entries = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            let link = entry
+                .link_name()
+                .unwrap()
+                .map(|l| l.to_string_lossy().into_owned());
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+            entries.push((path, link, content));
+        }
+        assert_eq!(
+            entries,
+            [
+                (
+                    "link".to_string(),
+                    Some("/opt/app/bin/app".to_string()),
+                    String::new()
+                ),
+                ("opt/".to_string(), None, String::new()),
+                ("opt/app/".to_string(), None, String::new()),
+                ("opt/app/bin/".to_string(), None, String::new()),
+                ("opt/app/bin/app".to_string(), None, "content".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_files_to_tar_long_names() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        let name = "f".repeat(120);
+        let target = "t".repeat(120);
+        rootfs.write(&name, "content").unwrap();
+        rootfs.symlink(&target, "link").unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+
+        let write = |format| {
+            let mut output = Vec::new();
+            let mut tar_builder = tar::Builder::new(&mut output);
+            let options = TarOptions {
+                format,
+                ..Default::default()
+            };
+            write_files_to_tar(&mut tar_builder, &rootfs, &files, 1000, &options).unwrap();
+            tar_builder.finish().unwrap();
+            drop(tar_builder);
+            output
+        };
+        let entry_types = |output: &[u8]| {
+            let mut archive = tar::Archive::new(output);
+            let entries = archive.entries().unwrap().raw(true);
+            entries
+                .map(|e| e.unwrap().header().entry_type())
+                .collect::<Vec<_>>()
+        };
+
+        let pax = write(TarFormat::Pax);
+        assert_eq!(pax, write(TarFormat::Pax));
+        assert!(
+            !entry_types(&pax)
+                .iter()
+                .any(|t| t.is_gnu_longname() || t.is_gnu_longlink())
+        );
+        let gnu = write(TarFormat::Gnu);
+        assert!(entry_types(&gnu).iter().any(|t| t.is_gnu_longname()));
+        assert!(entry_types(&gnu).iter().any(|t| t.is_gnu_longlink()));
+
+        for output in [pax, gnu] {
+            let mut archive = tar::Archive::new(output.as_slice());
+            let mut entries = Vec::new();
+            for entry in archive.entries().unwrap() {
+                let entry = entry.unwrap();
+                let path = entry.path().unwrap().to_string_lossy().into_owned();
+                let link = entry
+                    .link_name()
+                    .unwrap()
+                    .map(|l| l.to_string_lossy().into_owned());
+                entries.push((path, link));
+            }
+            assert_eq!(
+                entries,
+                [
+                    (name.clone(), None),
+                    ("link".to_string(), Some(target.clone())),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn test_write_files_to_tar_non_utf8_paths() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir(OsStr::from_bytes(b"dir\xff")).unwrap();
+        rootfs
+            .write(OsStr::from_bytes(b"dir\xff/file"), "content")
+            .unwrap();
+        rootfs
+            .symlink("file", OsStr::from_bytes(b"dir\xff/link"))
+            .unwrap();
+        let mut files = crate::scan::Scanner::new(&rootfs)
+            .non_utf8_paths(crate::scan::NonUtf8Paths::Lossy)
+            .scan()
+            .unwrap();
+        // the parent directory comes from the rootfs
+        files.remove(Utf8Path::new("/dir\u{fffd}"));
+
+        let mut output = Vec::new();
+        {
+            let mut tar_builder = tar::Builder::new(&mut output);
+            write_files_to_tar(
+                &mut tar_builder,
+                &rootfs,
+                &files,
+                1000,
+                &TarOptions::default(),
+            )
+            .unwrap();
+            tar_builder.finish().unwrap();
+        }
+
+        let mut archive = tar::Archive::new(output.as_slice());
+        let mut entries = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            let link = entry
+                .link_name()
+                .unwrap()
+                .map(|l| l.to_string_lossy().into_owned());
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+            entries.push((path, link, content));
+        }
+        assert_eq!(
+            entries,
+            [
+                ("dir\u{fffd}/".to_string(), None, String::new()),
+                ("dir\u{fffd}/file".to_string(), None, "content".to_string()),
+                (
+                    "dir\u{fffd}/link".to_string(),
+                    Some("file".to_string()),
+                    String::new()
+                ),
+            ]
+        );
+    }
+
     #[test]
     fn test_write_files_to_tar_creates_parent_dirs() {
         // Parent directories not in files are created via symlink_metadata() fallback
@@ -573,6 +1499,40 @@ mod tests {
         assert!(abc_pos < file_pos, "a/b/c/ should come before a/b/c/file");
     }
 
+    #[test]
+    fn test_write_files_to_tar_size_order() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir_all("a/b").unwrap();
+        rootfs.write("a/big", "x".repeat(100)).unwrap();
+        rootfs.write("a/b/small", "x").unwrap();
+        rootfs.write("medium", "x".repeat(10)).unwrap();
+        rootfs.create_dir("z").unwrap();
+
+        let mut files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+        // remove a parent dir to check it's still emitted before its contents
+        files.remove(Utf8Path::new("/a/b"));
+
+        let mut output = Vec::new();
+        {
+            let mut tar_builder = tar::Builder::new(&mut output);
+            let options = TarOptions {
+                entry_order: EntryOrder::Size,
+                ..Default::default()
+            };
+            write_files_to_tar(&mut tar_builder, &rootfs, &files, 1000, &options).unwrap();
+            tar_builder.finish().unwrap();
+        }
+
+        let mut archive = tar::Archive::new(output.as_slice());
+        let paths: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(paths, ["a/", "z/", "a/b/", "a/b/small", "medium", "a/big"]);
+    }
+
     #[test]
     fn test_write_oci_archive_uncompressed() {
         let (_tmp, oci_dir) = create_minimal_oci_dir();
@@ -609,6 +1569,86 @@ mod tests {
         assert!(!entries.is_empty());
     }
 
+    #[test]
+    fn test_write_oci_archive_xz() {
+        let (_tmp, oci_dir) = create_minimal_oci_dir();
+
+        let mut output = Vec::new();
+        write_oci_archive(&oci_dir, &mut output, ArchiveCompression::Xz(0)).unwrap();
+
+        // Verify it's xz compressed (magic bytes)
+        assert_eq!(&output[..6], b"\xfd7zXZ\0");
+
+        // Decompress and verify it's a valid tar
+        let decoder = xz2::read::XzDecoder::new(output.as_slice());
+        let mut archive = tar::Archive::new(decoder);
+        let entries: Vec<_> = archive.entries().unwrap().map(|e| e.unwrap()).collect();
+        assert!(!entries.is_empty());
+    }
+
+    #[test]
+    fn test_write_oci_archive_bzip2() {
+        let (_tmp, oci_dir) = create_minimal_oci_dir();
+
+        let mut output = Vec::new();
+        write_oci_archive(
+            &oci_dir,
+            &mut output,
+            ArchiveCompression::Bzip2(bzip2::Compression::fast()),
+        )
+        .unwrap();
+
+        // Verify it's bzip2 compressed (magic bytes)
+        assert_eq!(&output[..3], b"BZh");
+
+        // Decompress and verify it's a valid tar
+        let decoder = bzip2::read::BzDecoder::new(output.as_slice());
+        let mut archive = tar::Archive::new(decoder);
+        let entries: Vec<_> = archive.entries().unwrap().map(|e| e.unwrap()).collect();
+        assert!(!entries.is_empty());
+    }
+
+    #[test]
+    fn test_write_files_to_tar_read_ahead() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rootfs = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        rootfs.create_dir("usr").unwrap();
+        for i in 0..20 {
+            rootfs
+                .write(format!("usr/file{i}"), vec![b'a' + i; 100 * i as usize])
+                .unwrap();
+        }
+        std::fs::hard_link(tmp.path().join("usr/file3"), tmp.path().join("usr/link")).unwrap();
+        rootfs.symlink("file1", "usr/symlink").unwrap();
+        let files = crate::scan::Scanner::new(&rootfs).scan().unwrap();
+
+        let write = |options: &TarOptions| {
+            let mut tar_builder = tar::Builder::new(Vec::new());
+            write_files_to_tar(&mut tar_builder, &rootfs, &files, 1000, options).unwrap();
+            tar_builder.into_inner().unwrap()
+        };
+        let expected = write(&TarOptions::default());
+        for entry_order in [EntryOrder::Path, EntryOrder::Size] {
+            let options = TarOptions {
+                entry_order,
+                read_ahead: 3,
+                ..Default::default()
+            };
+            let sequential = TarOptions {
+                read_ahead: 0,
+                ..options.clone()
+            };
+            assert_eq!(write(&options), write(&sequential));
+        }
+        assert_eq!(
+            write(&TarOptions {
+                read_ahead: 1,
+                ..Default::default()
+            }),
+            expected
+        );
+    }
+
     #[test]
     fn test_write_files_to_tar_hardlinks() {
         let tmp = tempfile::tempdir().unwrap();
@@ -643,7 +1683,14 @@ mod tests {
         let mut output = Vec::new();
         {
             let mut tar_builder = tar::Builder::new(&mut output);
-            write_files_to_tar(&mut tar_builder, &rootfs, &files, 1000).unwrap();
+            write_files_to_tar(
+                &mut tar_builder,
+                &rootfs,
+                &files,
+                1000,
+                &TarOptions::default(),
+            )
+            .unwrap();
             tar_builder.finish().unwrap();
         }
 
@@ -718,4 +1765,25 @@ mod tests {
             "extracted symlinks should have same inode"
         );
     }
+
+    proptest::proptest! {
+        #[test]
+        fn test_dir_header_roundtrip(
+            components in proptest::collection::vec("[^/\\x00]{1,40}", 1..10),
+            mode in 0u32..0o7777,
+            uid in proptest::num::u32::ANY,
+            gid in proptest::num::u32::ANY,
+            mtime in proptest::num::u64::ANY,
+            mtime_clamp in proptest::num::u64::ANY,
+            xattrs in proptest::collection::vec(
+                ("user\\.[a-z.]{1,10}", proptest::collection::vec(proptest::num::u8::ANY, 0..20)),
+                0..3,
+            ),
+        ) {
+            proptest::prop_assume!(components.iter().all(|c| c != "." && c != ".."));
+            let path = format!("/{}", components.join("/"));
+            // this asserts that everything reads back the same
+            crate::fuzzing::tar_dir_header(&path, mode, uid, gid, mtime, mtime_clamp, &xattrs);
+        }
+    }
 }
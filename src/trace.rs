@@ -0,0 +1,447 @@
+//! Logging of the steps of a build with [`tracing`].
+//!
+//! The steps of a build (scan, claim, pack and write) are `info` spans, each
+//! layer is a `debug` span, and the claim and packing decisions are `trace`
+//! events with the [`CLAIM`] and [`PACK`] targets. A `tracing-subscriber` fmt
+//! layer writes them to stderr or `--trace-out` in the `--log-format`, and the
+//! durations of the steps go to the `--metrics`. The subscriber is only
+//! installed for the duration of the build, so that builds can run one after
+//! the other in a process which has a subscriber of its own.
+
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use tracing::level_filters::LevelFilter;
+use tracing::span::{Attributes, Id};
+use tracing::subscriber::Interest;
+use tracing::{Dispatch, Event, Level, Metadata, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::writer::MutexGuardWriter;
+use tracing_subscriber::layer::{self, Filter, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::metrics::Metrics;
+
+/// Target of the events telling which repo and component claimed a path.
+pub const CLAIM: &str = "claim";
+
+/// Target of the events telling how components were packed into layers.
+pub const PACK: &str = "pack";
+
+/// Maximum number of `trace` events per target written to stderr. There can
+/// be hundreds of thousands of claim decisions, which would drown the
+/// terminal. There is no limit when writing to a file.
+const STDERR_EVENT_LIMIT: u64 = 1000;
+
+/// Format of the log lines and diagnostics on stderr.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Lines for humans
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+static FORMAT: OnceLock<LogFormat> = OnceLock::new();
+
+/// The subscriber of the spans and events of a build.
+pub struct Tracing {
+    dispatch: Dispatch,
+    /// The `--trace-out` file, if any.
+    file: Option<TraceFile>,
+}
+
+impl Tracing {
+    /// Returns the subscriber of the spans and events of a build, recording
+    /// the durations of its steps in `metrics`.
+    ///
+    /// Those enabled by `verbosity` (the number of `-v`) go to stderr, or all
+    /// of them to `out` if provided, in `format`. Only every `sample`th
+    /// `trace` event of each target is written.
+    pub fn new(
+        verbosity: u8,
+        format: LogFormat,
+        out: Option<&Utf8Path>,
+        sample: u64,
+        metrics: Metrics,
+    ) -> Result<Self> {
+        // diagnostics follow the format even without tracing
+        let _ = FORMAT.set(format);
+        let (subscriber, file) = match out {
+            Some(path) => {
+                let file = std::fs::File::create(path)
+                    .with_context(|| format!("creating trace file {path}"))?;
+                let file = TraceFile(Arc::new(Mutex::new(BufWriter::new(file))));
+                let subscriber = subscriber(
+                    file.clone(),
+                    format,
+                    LevelFilter::TRACE,
+                    sample,
+                    None,
+                    metrics,
+                );
+                (subscriber, Some(file))
+            }
+            None => {
+                let max_level = match verbosity {
+                    0 => LevelFilter::OFF,
+                    1 => LevelFilter::INFO,
+                    2 => LevelFilter::DEBUG,
+                    _ => LevelFilter::TRACE,
+                };
+                let subscriber = subscriber(
+                    std::io::stderr,
+                    format,
+                    max_level,
+                    sample,
+                    Some(STDERR_EVENT_LIMIT),
+                    metrics,
+                );
+                (subscriber, None)
+            }
+        };
+        Ok(Self {
+            dispatch: Dispatch::new(subscriber),
+            file,
+        })
+    }
+
+    /// Run `f` with the spans and events of the current thread going to this
+    /// subscriber. Threads spawned by `f` need to install it themselves, e.g.
+    /// with the [`tracing::dispatcher::get_default`] of their parent.
+    pub fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        tracing::dispatcher::with_default(&self.dispatch, f)
+    }
+
+    /// Flush the events buffered for `--trace-out`.
+    pub fn flush(&self) -> Result<()> {
+        if let Some(file) = &self.file {
+            // SAFETY: we never panic while holding the lock
+            file.0
+                .lock()
+                .unwrap()
+                .flush()
+                .context("flushing trace output")?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the subscriber writing the spans and events up to `max_level` to
+/// `writer` in `format`, and recording the durations of the steps in
+/// `metrics`.
+fn subscriber<W>(
+    writer: W,
+    format: LogFormat,
+    max_level: LevelFilter,
+    sample: u64,
+    limit: Option<u64>,
+    metrics: Metrics,
+) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(false)
+        .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE);
+    let fmt = match format {
+        LogFormat::Text => fmt.boxed(),
+        LogFormat::Json => fmt.json().with_span_list(true).boxed(),
+    };
+    let filter = LogFilter {
+        max_level,
+        sample: sample.max(1),
+        limit,
+        counts: Mutex::default(),
+    };
+    Box::new(
+        tracing_subscriber::registry()
+            .with(fmt.with_filter(filter))
+            .with(StepTimer(metrics).with_filter(SpansOnly)),
+    )
+}
+
+/// Returns the format of log lines and diagnostics.
+pub fn format() -> LogFormat {
+    FORMAT.get().copied().unwrap_or_default()
+}
+
+/// Format a record as a JSON log line of type `kind`, with a timestamp.
+pub fn json_line(kind: &str, record: serde_json::Value) -> String {
+    let mut line = serde_json::Map::new();
+    line.insert(
+        "timestamp".into(),
+        chrono::Utc::now()
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+            .into(),
+    );
+    line.insert("type".into(), kind.into());
+    if let serde_json::Value::Object(record) = record {
+        line.extend(record);
+    }
+    serde_json::Value::Object(line).to_string()
+}
+
+/// The `--trace-out` file, buffered and flushed at the end of the build.
+#[derive(Clone)]
+struct TraceFile(Arc<Mutex<BufWriter<std::fs::File>>>);
+
+impl<'a> MakeWriter<'a> for TraceFile {
+    type Writer = MutexGuardWriter<'a, BufWriter<std::fs::File>>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.0.make_writer()
+    }
+}
+
+/// Enables the spans and events up to a level, and samples and rate-limits
+/// `trace` events.
+struct LogFilter {
+    max_level: LevelFilter,
+    /// Write one out of every `sample` `trace` events of each target.
+    sample: u64,
+    /// Maximum number of `trace` events to write per target.
+    limit: Option<u64>,
+    /// Number of `trace` events seen per target.
+    counts: Mutex<HashMap<&'static str, u64>>,
+}
+
+impl LogFilter {
+    /// Returns whether to write the `n`th event of `target`.
+    fn sampled(&self, target: &str, n: u64) -> bool {
+        if !n.is_multiple_of(self.sample) {
+            return false;
+        }
+        let written = n / self.sample;
+        match self.limit {
+            Some(limit) if written == limit => {
+                crate::diagnostics::report(&[crate::diagnostics::Diagnostic::warning(format!(
+                    "{target}: limit of {limit} trace events reached; \
+                     further events suppressed (use --trace-out)"
+                ))]);
+                false
+            }
+            Some(limit) => written < limit,
+            None => true,
+        }
+    }
+}
+
+impl<S> Filter<S> for LogFilter {
+    fn enabled(&self, meta: &Metadata<'_>, _: &layer::Context<'_, S>) -> bool {
+        *meta.level() <= self.max_level
+    }
+
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        if *meta.level() > self.max_level {
+            Interest::never()
+        } else if meta.is_event() && *meta.level() == Level::TRACE {
+            // sampled in event_enabled()
+            Interest::sometimes()
+        } else {
+            Interest::always()
+        }
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, _: &layer::Context<'_, S>) -> bool {
+        let meta = event.metadata();
+        if *meta.level() != Level::TRACE {
+            return true;
+        }
+        let n = {
+            // SAFETY: we never panic while holding the lock
+            let mut counts = self.counts.lock().unwrap();
+            let seen = counts.entry(meta.target()).or_default();
+            *seen += 1;
+            *seen - 1
+        };
+        self.sampled(meta.target(), n)
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.max_level)
+    }
+}
+
+/// Enables spans only, so that events aren't built just for [`StepTimer`].
+struct SpansOnly;
+
+impl<S> Filter<S> for SpansOnly {
+    fn enabled(&self, meta: &Metadata<'_>, _: &layer::Context<'_, S>) -> bool {
+        meta.is_span()
+    }
+
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        if meta.is_span() {
+            Interest::always()
+        } else {
+            Interest::never()
+        }
+    }
+}
+
+/// When a span was created.
+struct Started(Instant);
+
+/// Records the durations of the `info` spans, the steps of the build, in the
+/// metrics.
+struct StepTimer(Metrics);
+
+impl<S> Layer<S> for StepTimer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _: &Attributes<'_>, id: &Id, ctx: layer::Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(Started(Instant::now()));
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: layer::Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        if *span.metadata().level() == Level::INFO
+            && let Some(Started(started)) = span.extensions().get::<Started>()
+        {
+            self.0.step(span.name(), started.elapsed());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A writer that can be inspected after being moved into the subscriber.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuf {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(|s| s.to_string())
+                .collect()
+        }
+    }
+
+    /// Run `f` with the subscriber writing to the returned buffer.
+    fn capture(
+        format: LogFormat,
+        max_level: LevelFilter,
+        sample: u64,
+        limit: Option<u64>,
+        f: impl FnOnce(),
+    ) -> Vec<String> {
+        let buf = SharedBuf::default();
+        let writer = buf.clone();
+        let subscriber = subscriber(
+            move || writer.clone(),
+            format,
+            max_level,
+            sample,
+            limit,
+            Metrics::default(),
+        );
+        tracing::subscriber::with_default(subscriber, f);
+        buf.lines()
+    }
+
+    #[test]
+    fn test_sampling_and_limit() {
+        let lines = capture(LogFormat::Text, LevelFilter::TRACE, 2, Some(2), || {
+            for i in 0..10 {
+                tracing::trace!(target: CLAIM, "event {i}");
+            }
+            tracing::trace!(target: PACK, "packed");
+        });
+        let messages: Vec<_> = lines
+            .iter()
+            .map(|line| line.split_once("TRACE ").unwrap().1)
+            .collect();
+        assert_eq!(
+            messages,
+            ["claim: event 0", "claim: event 2", "pack: packed"]
+        );
+    }
+
+    #[test]
+    fn test_no_limit() {
+        let mut built = 0;
+        let lines = capture(LogFormat::Text, LevelFilter::TRACE, 1, None, || {
+            for _ in 0..5 {
+                tracing::trace!(target: CLAIM, "{}", {
+                    built += 1;
+                    "claimed"
+                });
+            }
+        });
+        assert_eq!(lines.len(), 5);
+        assert_eq!(built, 5);
+    }
+
+    #[test]
+    fn test_levels() {
+        let mut built = 0;
+        let lines = capture(LogFormat::Text, LevelFilter::INFO, 1, None, || {
+            let _write = tracing::info_span!("write").entered();
+            let _layer = tracing::debug_span!("layer", component = "rpm/glibc").entered();
+            // events need -vvv, and aren't even built without it
+            tracing::trace!(target: PACK, "{}", {
+                built += 1;
+                "packed"
+            });
+        });
+        assert_eq!(built, 0);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(" INFO write: "), "{}", lines[0]);
+        assert!(lines[0].ends_with(": new"), "{}", lines[0]);
+        assert!(lines[1].contains(": close time.busy="), "{}", lines[1]);
+
+        let lines = capture(LogFormat::Text, LevelFilter::OFF, 1, None, || {
+            let _write = tracing::info_span!("write").entered();
+        });
+        assert!(lines.is_empty());
+    }
+
+    #[test]
+    fn test_json() {
+        let lines = capture(LogFormat::Json, LevelFilter::TRACE, 1, None, || {
+            let _layer = tracing::debug_span!("layer", component = "rpm/glibc").entered();
+            tracing::trace!(target: PACK, "packed");
+        });
+        let records: Vec<serde_json::Value> = lines
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0]["fields"]["message"], "new");
+        assert_eq!(records[0]["span"]["name"], "layer");
+        assert_eq!(records[0]["span"]["component"], "rpm/glibc");
+        assert_eq!(records[1]["level"], "TRACE");
+        assert_eq!(records[1]["target"], "pack");
+        assert_eq!(records[1]["fields"]["message"], "packed");
+        assert_eq!(records[1]["spans"][0]["name"], "layer");
+        assert_eq!(records[2]["fields"]["message"], "close");
+        assert!(records[2]["timestamp"].is_string());
+    }
+}
@@ -0,0 +1,113 @@
+//! Ownership and permission transformations applied when writing layers.
+//!
+//! Build hosts often leave ownership or modes on generated files that
+//! shouldn't ship in the image, and the rootfs can't always be fixed in place.
+//! `--chown` and `--chmod` override them in the tar headers instead. Patterns
+//! match absolute paths, `*` and `?` don't match `/` but `**` does, and
+//! transformations apply in order, so the last matching one wins.
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+
+use crate::utils::GLOB_MATCH_OPTIONS;
+
+/// Parse a pattern of absolute paths.
+fn parse_pattern(pattern: &str) -> Result<glob::Pattern> {
+    anyhow::ensure!(
+        pattern.starts_with('/'),
+        "pattern must be absolute: {pattern}"
+    );
+    glob::Pattern::new(pattern).with_context(|| format!("invalid pattern {pattern}"))
+}
+
+/// A `--chown` transformation: the owner of matching paths, or of all paths
+/// without a pattern.
+#[derive(Debug, Clone)]
+pub struct Chown {
+    pub uid: u32,
+    pub gid: u32,
+    pattern: Option<glob::Pattern>,
+}
+
+impl Chown {
+    /// Parse `UID:GID[:PATHGLOB]`.
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut fields = s.splitn(3, ':');
+        let (Some(uid), Some(gid)) = (fields.next(), fields.next()) else {
+            anyhow::bail!("{s} is not UID:GID or UID:GID:PATHGLOB");
+        };
+        Ok(Self {
+            uid: uid.parse().with_context(|| format!("invalid UID in {s}"))?,
+            gid: gid.parse().with_context(|| format!("invalid GID in {s}"))?,
+            pattern: fields.next().map(parse_pattern).transpose()?,
+        })
+    }
+
+    pub fn matches(&self, path: &Utf8Path) -> bool {
+        self.pattern
+            .as_ref()
+            .is_none_or(|p| p.matches_with(path.as_str(), GLOB_MATCH_OPTIONS))
+    }
+}
+
+/// A `--chmod` transformation: the permission bits of matching paths.
+#[derive(Debug, Clone)]
+pub struct Chmod {
+    pub mode: u32,
+    pattern: glob::Pattern,
+}
+
+impl Chmod {
+    /// Parse `MODE:PATHGLOB`, with an octal `MODE`.
+    pub fn parse(s: &str) -> Result<Self> {
+        let Some((mode, pattern)) = s.split_once(':') else {
+            anyhow::bail!("{s} is not MODE:PATHGLOB");
+        };
+        let mode = u32::from_str_radix(mode, 8).with_context(|| format!("invalid mode in {s}"))?;
+        anyhow::ensure!(mode <= 0o7777, "mode in {s} is more than permission bits");
+        Ok(Self {
+            mode,
+            pattern: parse_pattern(pattern)?,
+        })
+    }
+
+    pub fn matches(&self, path: &Utf8Path) -> bool {
+        self.pattern.matches_with(path.as_str(), GLOB_MATCH_OPTIONS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chown() {
+        let chown = Chown::parse("0:10").unwrap();
+        assert_eq!((chown.uid, chown.gid), (0, 10));
+        assert!(chown.matches(Utf8Path::new("/anything")));
+
+        let chown = Chown::parse("1000:1000:/home/user/**").unwrap();
+        assert!(chown.matches(Utf8Path::new("/home/user/.config/app")));
+        assert!(!chown.matches(Utf8Path::new("/home/other")));
+        // patterns can have colons
+        let chown = Chown::parse("0:0:/srv/a:b").unwrap();
+        assert!(chown.matches(Utf8Path::new("/srv/a:b")));
+
+        for invalid in ["", "0", "a:0", "0:b", "0:0:relative", "0:0:/["] {
+            assert!(Chown::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_chmod() {
+        let chmod = Chmod::parse("0644:/etc/*.conf").unwrap();
+        assert_eq!(chmod.mode, 0o644);
+        assert!(chmod.matches(Utf8Path::new("/etc/app.conf")));
+        assert!(!chmod.matches(Utf8Path::new("/etc/app/app.conf")));
+        assert_eq!(Chmod::parse("4755:/usr/bin/su").unwrap().mode, 0o4755);
+
+        for invalid in ["", "644", "u+w:/etc", "0999:/etc", "10000:/etc", "644:etc"] {
+            assert!(Chmod::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+}
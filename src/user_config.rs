@@ -0,0 +1,126 @@
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Deserialize;
+
+use crate::ocibuilder::ArchiveFormat;
+use crate::profile::Profile;
+use crate::tar::EntryOrder;
+
+/// Per-user defaults for build options.
+///
+/// Read from `$XDG_CONFIG_HOME/chunkah/config.toml` (`~/.config/...` by
+/// default). Options set here act as if they were passed on the command line,
+/// except that the command line and environment variables take precedence.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct UserConfig {
+    pub(crate) profile: Option<Profile>,
+    pub(crate) max_layers: Option<usize>,
+    pub(crate) compressed: Option<bool>,
+    pub(crate) compression_level: Option<u32>,
+    pub(crate) archive_compression: Option<ArchiveFormat>,
+    pub(crate) entry_order: Option<EntryOrder>,
+    pub(crate) claim_cache: Option<Utf8PathBuf>,
+    pub(crate) workdir: Option<Utf8PathBuf>,
+    pub(crate) registry_auth_file: Option<Utf8PathBuf>,
+    /// Default for `--scan-threads` and `--build-threads`.
+    pub(crate) jobs: Option<usize>,
+}
+
+impl UserConfig {
+    /// Returns the path of the user config, or `None` if neither
+    /// `$XDG_CONFIG_HOME` nor `$HOME` is set.
+    pub(crate) fn path() -> Option<Utf8PathBuf> {
+        let config_home = match std::env::var("XDG_CONFIG_HOME") {
+            Ok(dir) if !dir.is_empty() => Utf8PathBuf::from(dir),
+            _ => {
+                let home = std::env::var("HOME").ok().filter(|home| !home.is_empty())?;
+                Utf8PathBuf::from(home).join(".config")
+            }
+        };
+        Some(config_home.join("chunkah/config.toml"))
+    }
+
+    /// Load the user config at `path`, if it exists.
+    pub(crate) fn load_from(path: &Utf8Path) -> Result<Option<Self>> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("reading {path}")),
+        };
+        Self::parse(&content)
+            .with_context(|| format!("parsing {path}"))
+            .map(Some)
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let config: Self = toml::from_str(content)?;
+        if let Some(level) = config.compression_level {
+            anyhow::ensure!(
+                level <= 9,
+                "compression-level must be between 0 and 9, got {level}"
+            );
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = UserConfig::parse(
+            r#"
+            profile = "webapp"
+            max-layers = 48
+            compression-level = 9
+            archive-compression = "xz"
+            entry-order = "size"
+            claim-cache = "/var/cache/chunkah"
+            workdir = "/var/tmp"
+            registry-auth-file = "/run/user/1000/containers/auth.json"
+            jobs = 8
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            UserConfig {
+                profile: Some(Profile::Webapp),
+                max_layers: Some(48),
+                compressed: None,
+                compression_level: Some(9),
+                archive_compression: Some(ArchiveFormat::Xz),
+                entry_order: Some(EntryOrder::Size),
+                claim_cache: Some("/var/cache/chunkah".into()),
+                workdir: Some("/var/tmp".into()),
+                registry_auth_file: Some("/run/user/1000/containers/auth.json".into()),
+                jobs: Some(8),
+            }
+        );
+
+        assert_eq!(UserConfig::parse("").unwrap(), UserConfig::default());
+        // typos shouldn't be silently ignored
+        assert!(UserConfig::parse("max_layers = 48").is_err());
+        assert!(UserConfig::parse("compression-level = 10").is_err());
+        assert!(UserConfig::parse("profile = \"nope\"").is_err());
+    }
+
+    #[test]
+    fn test_load_from() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(tmp.path()).unwrap();
+        let path = dir.join("config.toml");
+        assert_eq!(UserConfig::load_from(&path).unwrap(), None);
+
+        std::fs::write(&path, "compressed = true\n").unwrap();
+        let config = UserConfig::load_from(&path).unwrap().unwrap();
+        assert_eq!(config.compressed, Some(true));
+
+        std::fs::write(&path, "compressed = \"yes\"\n").unwrap();
+        let err = UserConfig::load_from(&path).unwrap_err();
+        assert!(format!("{err:#}").starts_with(&format!("parsing {path}")));
+    }
+}
@@ -6,9 +6,46 @@ use std::{
 use anyhow::{Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
 use ocidir::cap_std::fs::Dir;
+use ocidir::oci_spec::image as oci_image;
 
 use crate::components::{FileMap, FileType};
 
+/// Options for matching paths and component names against glob patterns:
+/// `*` and `?` don't match `/`, but `**` does.
+pub(crate) const GLOB_MATCH_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Returns the descriptor and manifest of the image in an OCI image layout.
+///
+/// Artifacts referring to the image (e.g. signatures) are skipped; there must
+/// be a single image otherwise.
+pub fn read_image_manifest(
+    oci_dir: &ocidir::OciDir,
+) -> Result<(oci_image::Descriptor, oci_image::ImageManifest)> {
+    let index = oci_dir.read_index().context("reading index")?;
+    let mut images = Vec::new();
+    for desc in index.manifests() {
+        if desc.media_type() != &oci_image::MediaType::ImageManifest {
+            continue;
+        }
+        let manifest: oci_image::ImageManifest =
+            oci_dir.read_json_blob(desc).context("reading manifest")?;
+        if manifest.subject().is_none() {
+            images.push((desc.clone(), manifest));
+        }
+    }
+    anyhow::ensure!(
+        images.len() == 1,
+        "expected a single image manifest, found {}",
+        images.len()
+    );
+    // SAFETY: we just checked there's one
+    Ok(images.pop().unwrap())
+}
+
 pub fn get_current_epoch() -> Result<u64> {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -138,7 +175,7 @@ fn canonicalize_dir_path(
 }
 
 /// Normalize a path by resolving `.` and `..` components.
-fn normalize_path(path: &Utf8Path) -> Result<Utf8PathBuf> {
+pub fn normalize_path(path: &Utf8Path) -> Result<Utf8PathBuf> {
     let mut result = Utf8PathBuf::new();
     for component in path.components() {
         use camino::Utf8Component;
@@ -0,0 +1,317 @@
+use anyhow::{Context, Result};
+use ocidir::oci_spec::image as oci_image;
+use openssl::hash::{Hasher, MessageDigest};
+
+/// Registries are only required to accept manifests up to this size (cf. the
+/// OCI distribution spec). Annotations are the main thing that can make ours
+/// grow, so this effectively limits the total size of annotations.
+pub const MAX_MANIFEST_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Validate an OCI directory against the image-spec.
+///
+/// This checks required fields, blob digests and sizes, media type
+/// consistency, that the config matches the layers (diff_ids and history)
+/// and that the manifest stays within the size registries accept. All
+/// problems found are reported together.
+pub fn validate_oci_dir(oci_dir: &ocidir::OciDir) -> Result<()> {
+    let mut problems = Vec::new();
+
+    // this checks the index and that all blobs match their digests
+    oci_dir.fsck().context("checking blob digests")?;
+
+    let index = oci_dir.read_index().context("reading index")?;
+    if index.schema_version() != 2 {
+        problems.push(format!(
+            "index: unsupported schemaVersion {}",
+            index.schema_version()
+        ));
+    }
+    if index.manifests().is_empty() {
+        problems.push("index: no manifests".to_string());
+    }
+
+    for desc in index.manifests() {
+        let ctx = format!("manifest {}", desc.digest());
+        if desc.media_type() != &oci_image::MediaType::ImageManifest {
+            problems.push(format!(
+                "{ctx}: unexpected media type {}",
+                desc.media_type()
+            ));
+            continue;
+        }
+        check_blob_size(oci_dir, desc, &ctx, &mut problems)?;
+        if desc.size() > MAX_MANIFEST_SIZE {
+            problems.push(format!(
+                "{ctx}: size {} exceeds {MAX_MANIFEST_SIZE} bytes; reduce annotations",
+                desc.size()
+            ));
+        }
+
+        let manifest: oci_image::ImageManifest = oci_dir
+            .read_json_blob(desc)
+            .with_context(|| format!("reading {ctx}"))?;
+        validate_manifest(oci_dir, &manifest, &ctx, &mut problems)
+            .with_context(|| format!("validating {ctx}"))?;
+    }
+
+    if !problems.is_empty() {
+        anyhow::bail!(
+            "OCI validation failed:\n{}",
+            problems
+                .iter()
+                .map(|p| format!("  - {p}"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+    }
+    Ok(())
+}
+
+fn validate_manifest(
+    oci_dir: &ocidir::OciDir,
+    manifest: &oci_image::ImageManifest,
+    ctx: &str,
+    problems: &mut Vec<String>,
+) -> Result<()> {
+    if manifest.schema_version() != 2 {
+        problems.push(format!(
+            "{ctx}: unsupported schemaVersion {}",
+            manifest.schema_version()
+        ));
+    }
+    // mediaType is optional in the manifest itself, but must match if set
+    if let Some(media_type) = manifest.media_type()
+        && media_type != &oci_image::MediaType::ImageManifest
+    {
+        problems.push(format!("{ctx}: unexpected media type {media_type}"));
+    }
+    if let Some(annotations) = manifest.annotations() {
+        check_annotations(annotations, ctx, problems);
+    }
+
+    let config_desc = manifest.config();
+    let config_ctx = format!("{ctx}: config");
+    if config_desc.media_type() != &oci_image::MediaType::ImageConfig {
+        problems.push(format!(
+            "{config_ctx}: unexpected media type {}",
+            config_desc.media_type()
+        ));
+    }
+    check_blob_size(oci_dir, config_desc, &config_ctx, problems)?;
+
+    let mut diff_ids = Vec::new();
+    for (i, layer) in manifest.layers().iter().enumerate() {
+        let layer_ctx = format!("{ctx}: layer {i}");
+        if crate::tar::layer_is_gzip(layer.media_type()).is_none() {
+            problems.push(format!(
+                "{layer_ctx}: unexpected media type {}",
+                layer.media_type()
+            ));
+            continue;
+        }
+        if let Some(annotations) = layer.annotations() {
+            check_annotations(annotations, &layer_ctx, problems);
+        }
+        check_blob_size(oci_dir, layer, &layer_ctx, problems)?;
+        diff_ids
+            .push(compute_diff_id(oci_dir, layer).with_context(|| format!("hashing {layer_ctx}"))?);
+    }
+
+    let config: oci_image::ImageConfiguration = oci_dir
+        .read_json_blob(config_desc)
+        .with_context(|| format!("reading {config_ctx}"))?;
+    if config.os().to_string().is_empty() || config.architecture().to_string().is_empty() {
+        problems.push(format!("{config_ctx}: missing os or architecture"));
+    }
+    if let Some(created) = config.created()
+        && chrono::DateTime::parse_from_rfc3339(created).is_err()
+    {
+        problems.push(format!("{config_ctx}: created is not RFC 3339: {created}"));
+    }
+    if config.rootfs().typ() != "layers" {
+        problems.push(format!(
+            "{config_ctx}: unexpected rootfs type {}",
+            config.rootfs().typ()
+        ));
+    }
+    if config.rootfs().diff_ids() != &diff_ids {
+        problems.push(format!(
+            "{config_ctx}: diff_ids don't match layers ({} diff_ids, {} layers)",
+            config.rootfs().diff_ids().len(),
+            manifest.layers().len()
+        ));
+    }
+    if let Some(history) = config.history() {
+        let non_empty = history
+            .iter()
+            .filter(|h| !h.empty_layer().unwrap_or(false))
+            .count();
+        if non_empty != manifest.layers().len() {
+            problems.push(format!(
+                "{config_ctx}: {non_empty} non-empty history entries for {} layers",
+                manifest.layers().len()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn check_annotations(
+    annotations: &std::collections::HashMap<String, String>,
+    ctx: &str,
+    problems: &mut Vec<String>,
+) {
+    if annotations.keys().any(|k| k.is_empty()) {
+        problems.push(format!("{ctx}: empty annotation key"));
+    }
+}
+
+/// Check that the blob exists and matches the size in its descriptor.
+fn check_blob_size(
+    oci_dir: &ocidir::OciDir,
+    desc: &oci_image::Descriptor,
+    ctx: &str,
+    problems: &mut Vec<String>,
+) -> Result<()> {
+    let blob = oci_dir
+        .read_blob(desc)
+        .with_context(|| format!("opening blob for {ctx}"))?;
+    let size = blob.metadata().context("getting blob metadata")?.len();
+    if size != desc.size() {
+        problems.push(format!(
+            "{ctx}: descriptor size {} doesn't match blob size {size}",
+            desc.size()
+        ));
+    }
+    Ok(())
+}
+
+/// Compute the diff_id (digest of the uncompressed tarball) of a layer.
+fn compute_diff_id(oci_dir: &ocidir::OciDir, layer: &oci_image::Descriptor) -> Result<String> {
+    let mut reader = crate::tar::read_layer(oci_dir, layer)?;
+    let mut hasher = Hasher::new(MessageDigest::sha256())?;
+    std::io::copy(&mut reader, &mut hasher).context("reading layer")?;
+    Ok(format!("sha256:{}", hex::encode(hasher.finish()?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std::ambient_authority;
+    use cap_std_ext::cap_std::fs::Dir;
+
+    use super::*;
+
+    /// Build a minimal valid OCI dir with a single layer.
+    fn build_oci_dir(
+        tmp: &tempfile::TempDir,
+        compression: crate::ocibuilder::Compression,
+        media_type: crate::ocibuilder::LayerMediaType,
+    ) -> ocidir::OciDir {
+        let dir = Dir::open_ambient_dir(tmp.path(), ambient_authority()).unwrap();
+        let oci_dir = ocidir::OciDir::ensure(dir).unwrap();
+        let mut manifest = oci_dir.new_empty_manifest().unwrap().build().unwrap();
+        let mut config = oci_image::ImageConfiguration::default();
+
+        let mut tar_builder = crate::tar::create_layer(&oci_dir, compression, media_type).unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        tar_builder
+            .append_data(&mut header, "hello", &b"hello"[..])
+            .unwrap();
+        tar_builder.finish().unwrap();
+        let layer = tar_builder.into_inner().unwrap().complete().unwrap();
+        let history = oci_image::HistoryBuilder::default()
+            .created_by("test".to_string())
+            .build()
+            .unwrap();
+        oci_dir.push_layer_with_history_annotated(
+            &mut manifest,
+            &mut config,
+            layer,
+            None::<std::collections::HashMap<String, String>>,
+            Some(history),
+        );
+
+        let platform = oci_image::PlatformBuilder::default()
+            .os("linux")
+            .architecture("amd64")
+            .build()
+            .unwrap();
+        oci_dir
+            .insert_manifest_and_config(manifest, config, None, platform)
+            .unwrap();
+        oci_dir
+    }
+
+    #[test]
+    fn test_validate_ok() {
+        for compression in [
+            crate::ocibuilder::Compression::None,
+            crate::ocibuilder::Compression::Gzip(6),
+        ] {
+            for media_type in [
+                crate::ocibuilder::LayerMediaType::Oci,
+                crate::ocibuilder::LayerMediaType::Docker,
+            ] {
+                let tmp = tempfile::tempdir().unwrap();
+                let oci_dir = build_oci_dir(&tmp, compression, media_type);
+                validate_oci_dir(&oci_dir).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_validate_corrupt_blob() {
+        let tmp = tempfile::tempdir().unwrap();
+        let oci_dir = build_oci_dir(
+            &tmp,
+            crate::ocibuilder::Compression::None,
+            crate::ocibuilder::LayerMediaType::Oci,
+        );
+        let index = oci_dir.read_index().unwrap();
+        let manifest: oci_image::ImageManifest =
+            oci_dir.read_json_blob(&index.manifests()[0]).unwrap();
+        let digest = manifest.layers()[0].digest().digest().to_string();
+
+        let blobs = tmp.path().join("blobs/sha256");
+        std::fs::write(blobs.join(digest), b"garbage").unwrap();
+        assert!(validate_oci_dir(&oci_dir).is_err());
+    }
+
+    #[test]
+    fn test_validate_diff_id_mismatch() {
+        let tmp = tempfile::tempdir().unwrap();
+        let oci_dir = build_oci_dir(
+            &tmp,
+            crate::ocibuilder::Compression::Gzip(6),
+            crate::ocibuilder::LayerMediaType::Oci,
+        );
+        let index = oci_dir.read_index().unwrap();
+        let mut manifest: oci_image::ImageManifest =
+            oci_dir.read_json_blob(&index.manifests()[0]).unwrap();
+        let mut config: oci_image::ImageConfiguration =
+            oci_dir.read_json_blob(manifest.config()).unwrap();
+
+        // corrupt the diff_id and add an invalid annotation
+        let mut rootfs = config.rootfs().clone();
+        rootfs.set_diff_ids(vec![format!("sha256:{}", "0".repeat(64))]);
+        config.set_rootfs(rootfs);
+        let platform = oci_image::PlatformBuilder::default()
+            .os("linux")
+            .architecture("amd64")
+            .build()
+            .unwrap();
+        manifest.set_annotations(Some([("".to_string(), "x".to_string())].into()));
+        let config_desc = oci_dir.write_config(config).unwrap();
+        manifest.set_config(config_desc);
+        oci_dir
+            .replace_with_single_manifest(manifest, platform)
+            .unwrap();
+
+        let err = format!("{:#}", validate_oci_dir(&oci_dir).unwrap_err());
+        assert!(err.contains("diff_ids don't match layers"), "{err}");
+        assert!(err.contains("empty annotation key"), "{err}");
+    }
+}
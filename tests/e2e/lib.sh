@@ -75,6 +75,65 @@ assert_no_diff() {
     just -f "${repo_root}/Justfile" diff "$@"
 }
 
+# Assert that the components JSON embedded in an image (--embed-components)
+# matches a golden file. Each line of the golden file is a component which
+# must exist, optionally followed by paths which must belong to it. Empty lines
+# and lines starting with '#' are ignored.
+assert_golden_components() {
+    local image="${1}"; shift
+    local golden="${1}"; shift
+    local json component path
+    local -a fields
+    json=$(podman run --rm "${image}" cat /usr/share/chunkah/components.json)
+    while read -r -a fields; do
+        if [[ ${#fields[@]} -eq 0 || "${fields[0]}" == \#* ]]; then
+            continue
+        fi
+        component="${fields[0]}"
+        if ! jq -e --arg c "${component}" '.components | has($c)' <<< "${json}" >/dev/null; then
+            echo "ERROR: Expected component '${component}' not found in ${image}"
+            return 1
+        fi
+        for path in "${fields[@]:1}"; do
+            if ! jq -e --arg c "${component}" --arg p "${path}" \
+                    '.components[$c].paths | index($p) != null' <<< "${json}" >/dev/null; then
+                echo "ERROR: Expected ${path} in component '${component}' in ${image}"
+                return 1
+            fi
+        done
+    done < "${golden}"
+}
+
+# Build the golden fixture in tests/e2e/golden/<name>, split it with
+# --max-layers, and check the result against the fixture's golden components,
+# the layer count, and the source filesystem.
+run_golden_test() {
+    local name="${1}"; shift
+    local source_image="${1}"; shift
+    local chunked_image="${1}"; shift
+    local max_layers="${1}"; shift
+    local fixture config_str iid
+    fixture="$(cd "$(dirname "${BASH_SOURCE[0]}")" && pwd)/golden/${name}"
+
+    ${BUILDAH:-buildah} build -t "${source_image}" "${fixture}"
+    config_str=$(podman inspect "${source_image}")
+
+    podman run --rm --mount=type=image,src="${source_image}",target=/chunkah \
+        -e CHUNKAH_CONFIG_STR="${config_str}" \
+        "${CHUNKAH_IMG:?}" build --embed-components --max-layers "${max_layers}" \
+        > out.ociarchive
+
+    # XXX: need to fix 'podman load' to only print image ID on its stdout, like 'podman pull'
+    iid=$(podman load -i out.ociarchive)
+    iid=${iid#*sha256:}
+    podman tag "${iid}" "${chunked_image}"
+
+    assert_golden_components "${chunked_image}" "${fixture}/components.txt"
+    assert_layer_count "${chunked_image}" "${max_layers}"
+    # the embedded components JSON is the only addition
+    assert_no_diff "${source_image}" "${chunked_image}" --skip /usr/share/chunkah
+}
+
 # Remove images, ignoring errors.
 cleanup_images() {
     for image in "$@"; do
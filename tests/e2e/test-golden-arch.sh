@@ -0,0 +1,18 @@
+#!/bin/bash
+# Golden test: split a minimal Arch Linux image and check it against tests/e2e/golden/arch.
+set -xeuo pipefail
+shopt -s inherit_errexit
+
+SCRIPT_DIR="$(cd "$(dirname "${BASH_SOURCE[0]}")" && pwd)"
+# shellcheck source=SCRIPTDIR/lib.sh
+. "${SCRIPT_DIR}/lib.sh"
+
+SOURCE_IMAGE="localhost/golden-arch:test"
+CHUNKED_IMAGE="localhost/golden-arch-chunked:test"
+
+cleanup() {
+    cleanup_images "${SOURCE_IMAGE}" "${CHUNKED_IMAGE}"
+}
+trap cleanup EXIT
+
+run_golden_test arch "${SOURCE_IMAGE}" "${CHUNKED_IMAGE}" 8
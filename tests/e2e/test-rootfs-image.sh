@@ -0,0 +1,56 @@
+#!/bin/bash
+# Test splitting a rootfs given as a tarball or as a squashfs image.
+set -xeuo pipefail
+shopt -s inherit_errexit
+
+SCRIPT_DIR="$(cd "$(dirname "${BASH_SOURCE[0]}")" && pwd)"
+# shellcheck source=SCRIPTDIR/lib.sh
+. "${SCRIPT_DIR}/lib.sh"
+
+SOURCE_IMAGE="quay.io/fedora/fedora-minimal:latest"
+CHUNKED_IMAGE="localhost/fedora-minimal-chunked:test"
+
+cleanup() {
+    cleanup_images "${CHUNKED_IMAGE}"
+    rm -f rootfs.squashfs rootfs.tar
+}
+trap cleanup EXIT
+
+podman pull "${SOURCE_IMAGE}"
+CHUNKAH_CONFIG_STR=$(podman inspect "${SOURCE_IMAGE}")
+
+ctr=$(podman create "${SOURCE_IMAGE}")
+podman export -o rootfs.tar "${ctr}"
+podman rm "${ctr}"
+
+# the metadata comes from the tar headers, so no privileges are needed
+podman run --rm -v "${PWD}:/run/src:z" -e CHUNKAH_CONFIG_STR="${CHUNKAH_CONFIG_STR}" \
+    --user 1000:1000 "${CHUNKAH_IMG:?}" build --rootfs-tar /run/src/rootfs.tar > out.ociarchive
+
+iid=$(podman load -i out.ociarchive)
+iid=${iid#*sha256:}
+podman tag "${iid}" "${CHUNKED_IMAGE}"
+
+# the package database is read from the unpacked tarball
+assert_has_components "${CHUNKED_IMAGE}" "rpm/filesystem" "rpm/setup" "rpm/glibc"
+
+# ownership, modes and xattrs survive the round-trip through the tarball
+assert_no_diff "${SOURCE_IMAGE}" "${CHUNKED_IMAGE}"
+
+# the same rootfs as a squashfs image, read without mounting it
+podman run --rm -v "${PWD}:/run/src:z" "${SOURCE_IMAGE}" bash -c '
+    microdnf install -y squashfs-tools tar > /dev/null
+    mkdir /rootfs
+    tar -C /rootfs --xattrs --xattrs-include="*" -xpf /run/src/rootfs.tar
+    mksquashfs /rootfs /run/src/rootfs.squashfs -comp xz -noappend -quiet
+'
+cleanup_images "${CHUNKED_IMAGE}"
+podman run --rm -v "${PWD}:/run/src:z" -e CHUNKAH_CONFIG_STR="${CHUNKAH_CONFIG_STR}" \
+    --user 1000:1000 "${CHUNKAH_IMG:?}" build --rootfs /run/src/rootfs.squashfs > out.ociarchive
+
+iid=$(podman load -i out.ociarchive)
+iid=${iid#*sha256:}
+podman tag "${iid}" "${CHUNKED_IMAGE}"
+
+assert_has_components "${CHUNKED_IMAGE}" "rpm/filesystem" "rpm/setup" "rpm/glibc"
+assert_no_diff "${SOURCE_IMAGE}" "${CHUNKED_IMAGE}"
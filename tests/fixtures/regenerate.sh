@@ -50,3 +50,138 @@ podman run --rm quay.io/archlinux/archlinux:latest bash -c '
     tar -C /mnt -cf - var/lib/pacman/local
     ' | tar xf -
 popd
+
+echo ">>> REGENERATING: rootfs-gzip.squashfs rootfs-xz.squashfs rootfs.erofs" >&2
+# Written with the backhand and composefs crates rather than mksquashfs and
+# mkfs.erofs, so that the readers are checked against independent writers
+gen=$(mktemp -d)
+trap 'rm -rf "${gen}"' EXIT
+mkdir "${gen}/src"
+cat > "${gen}/Cargo.toml" << 'TOML'
+[package]
+name = "fixgen"
+version = "0.1.0"
+edition = "2024"
+
+[dependencies]
+backhand = { version = "0.25", default-features = false, features = ["gzip", "xz"] }
+composefs = "0.9"
+TOML
+cat > "${gen}/src/main.rs" << 'RUST'
+//! Writes the squashfs and erofs fixtures of chunkah's rootfs image tests.
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::io::Cursor;
+
+use backhand::compression::Compressor;
+use backhand::{FilesystemCompressor, FilesystemWriter, NodeHeader};
+use composefs::erofs::format::FormatVersion;
+use composefs::erofs::writer::{ValidatedFileSystem, mkfs_erofs_versioned};
+use composefs::fsverity::Sha256HashValue;
+use composefs::generic_tree::{Inode, LeafContent, Stat};
+use composefs::tree::{Directory, FileSystem, RegularFile};
+
+const MTIME: u32 = 1_700_000_000;
+
+/// Not too compressible content spanning a few 4 KiB blocks and a fragment.
+fn ping() -> Vec<u8> {
+    let mut state = 1u32;
+    (0..10_000)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            b"abcdefgh"[(state >> 16) as usize % 8]
+        })
+        .collect()
+}
+
+fn squashfs(compressor: Compressor, out: &str) {
+    let mut fs = FilesystemWriter::default();
+    fs.set_block_size(4096);
+    fs.set_time(MTIME);
+    fs.set_root_mode(0o755);
+    fs.set_compressor(FilesystemCompressor::new(compressor, None).unwrap());
+    let h = |mode, uid, mtime| NodeHeader::new(mode, uid, uid, mtime);
+    fs.push_dir("dev", h(0o755, 0, MTIME)).unwrap();
+    fs.push_char_device((1 << 8) | 3, "dev/null", h(0o666, 0, MTIME)).unwrap();
+    // major 259, minor 300 needs the extended encoding
+    fs.push_block_device((300 & 0xff) | (259 << 8) | ((300 & !0xff) << 12), "dev/nvme", h(0o660, 6, MTIME)).unwrap();
+    fs.push_dir("etc", h(0o755, 0, MTIME)).unwrap();
+    fs.push_file(Cursor::new(b"chunkah\n".to_vec()), "etc/hostname", h(0o644, 0, MTIME + 1)).unwrap();
+    fs.push_symlink("/usr/share/zoneinfo/UTC", "etc/localtime", h(0o777, 0, MTIME)).unwrap();
+    fs.push_dir("home", h(0o755, 0, MTIME)).unwrap();
+    fs.push_dir("home/user", h(0o700, 1000, MTIME + 2)).unwrap();
+    fs.push_file(Cursor::new(Vec::new()), "home/user/.bashrc", h(0o644, 1000, MTIME)).unwrap();
+    fs.push_dir("run", h(0o755, 0, MTIME)).unwrap();
+    fs.push_fifo("run/initctl", h(0o600, 0, MTIME)).unwrap();
+    fs.push_dir("usr", h(0o755, 0, MTIME)).unwrap();
+    fs.push_dir("usr/bin", h(0o755, 0, MTIME)).unwrap();
+    fs.push_file(Cursor::new(ping()), "usr/bin/ping", h(0o4755, 0, MTIME + 3)).unwrap();
+    fs.push_file(Cursor::new(vec![0u8; 8192]), "usr/bin/zeros", h(0o644, 0, MTIME)).unwrap();
+    let mut file = std::fs::File::create(out).unwrap();
+    fs.write(&mut file).unwrap();
+}
+
+fn stat(mode: u32, uid: u32, mtime: u32, xattrs: &[(&str, &[u8])]) -> Stat {
+    Stat {
+        st_mode: mode,
+        st_uid: uid,
+        st_gid: uid,
+        st_mtim_sec: mtime.into(),
+        st_mtim_nsec: 0,
+        xattrs: xattrs
+            .iter()
+            .map(|(k, v)| (Box::from(OsStr::new(k)), Box::from(*v)))
+            .collect::<BTreeMap<_, _>>(),
+    }
+}
+
+fn erofs(version: FormatVersion, out: &str) {
+    type Fs = FileSystem<Sha256HashValue>;
+    let mut fs = Fs::new(stat(0o755, 0, MTIME, &[]));
+    let dir = |mode, uid, mtime| Directory::<Sha256HashValue>::new(stat(mode, uid, mtime, &[]));
+    let leaf = |fs: &mut Fs, st, content| Inode::leaf(fs.push_leaf(st, content));
+    let selinux: (&str, &[u8]) = ("security.selinux", b"system_u:object_r:bin_t:s0\0");
+
+    let mut dev = dir(0o755, 0, MTIME);
+    dev.insert(OsStr::new("null"), leaf(&mut fs, stat(0o666, 0, MTIME, &[]), LeafContent::CharacterDevice(0x103)));
+    dev.insert(OsStr::new("nvme"), leaf(&mut fs, stat(0o660, 6, MTIME, &[]), LeafContent::BlockDevice((300 & 0xff) | (259 << 8) | ((300 & !0xff) << 12))));
+    let mut etc = dir(0o755, 0, MTIME);
+    etc.insert(OsStr::new("hostname"), leaf(&mut fs, stat(0o644, 0, MTIME + 1, &[]), LeafContent::Regular(RegularFile::Inline(Box::from(&b"chunkah\n"[..])))));
+    etc.insert(OsStr::new("localtime"), leaf(&mut fs, stat(0o777, 0, MTIME, &[]), LeafContent::Symlink(Box::from(OsStr::new("/usr/share/zoneinfo/UTC")))));
+    let mut user = dir(0o700, 1000, MTIME + 2);
+    user.insert(OsStr::new(".bashrc"), leaf(&mut fs, stat(0o644, 1000, MTIME, &[("user.comment", b"a\nb")]), LeafContent::Regular(RegularFile::Inline(Box::default()))));
+    let mut home = dir(0o755, 0, MTIME);
+    home.insert(OsStr::new("user"), Inode::Directory(Box::new(user)));
+    let mut run = dir(0o755, 0, MTIME);
+    run.insert(OsStr::new("initctl"), leaf(&mut fs, stat(0o600, 0, MTIME, &[]), LeafContent::Fifo));
+    let mut bin = dir(0o755, 0, MTIME);
+    let ping = fs.push_leaf(
+        stat(0o4755, 0, MTIME + 3, &[("security.capability", b"\x01\x00\x00\x02\n\x00"), selinux]),
+        LeafContent::Regular(RegularFile::Inline(ping().into())),
+    );
+    bin.insert(OsStr::new("ping"), Inode::leaf(ping));
+    bin.insert(OsStr::new("ping6"), Inode::leaf(ping));
+    bin.insert(OsStr::new("sparse"), leaf(&mut fs, stat(0o644, 0, MTIME, &[selinux]), LeafContent::Regular(RegularFile::Sparse(10_000))));
+    let mut usr = dir(0o755, 0, MTIME);
+    usr.insert(OsStr::new("bin"), Inode::Directory(Box::new(bin)));
+    for (name, d) in [("dev", dev), ("etc", etc), ("home", home), ("run", run), ("usr", usr)] {
+        fs.root.insert(OsStr::new(name), Inode::Directory(Box::new(d)));
+    }
+    let image = mkfs_erofs_versioned(&ValidatedFileSystem::new(fs).unwrap(), version);
+    std::fs::write(out, image).unwrap();
+}
+
+fn main() {
+    let out = std::env::args().nth(1).unwrap();
+    squashfs(Compressor::Gzip, &format!("{out}/rootfs-gzip.squashfs"));
+    squashfs(Compressor::Xz, &format!("{out}/rootfs-xz.squashfs"));
+    // V1 is what composefs writes by default, with both compact and extended
+    // inodes, and whiteouts for each of /00 to /ff
+    erofs(FormatVersion::V1, &format!("{out}/rootfs.erofs"));
+}
+RUST
+cargo run --quiet --manifest-path "${gen}/Cargo.toml" -- "${PWD}"
+# they also seed the fuzz targets of the readers
+cp rootfs-gzip.squashfs rootfs-xz.squashfs ../../fuzz/corpus/squashfs_image/
+cp rootfs.erofs ../../fuzz/corpus/erofs_image/